@@ -0,0 +1,96 @@
+//! Separate listener for the operational endpoints
+//!
+//! With `ADMIN_ADDR`, the admin and debug endpoints leave the data-plane
+//! ports for a listener of their own, on an address (`127.0.0.1:9090`) or a
+//! Unix socket (a path, `/run/xet-proxy/admin.sock`):
+//!
+//! - `/metrics` and `/slo`, unless `METRICS_ADDR` places them (see
+//!   [`crate::ports`]);
+//! - `/config`, `/upstream/:request_id` and `/events`;
+//! - the `/cache` management routes, `/admin/drain-status` and
+//!   `/admin/limits`.
+//!
+//! The data-plane ports answer them with 404, so they can be exposed more
+//! broadly. The health probes answer on both. `ADMIN_ADDR=off` serves them
+//! nowhere.
+//!
+//! The admin listener has its own authentication, whatever `AUTH_PROVIDERS`
+//! says: a key of `ADMIN_API_KEYS` (comma-separated) in `X-API-Key`, which
+//! makes the caller an admin. Without `ADMIN_API_KEYS` it is open, for
+//! addresses and sockets only operators reach.
+
+use crate::auth::{Grant, API_KEY_HEADER};
+use crate::ports::{Bind, Listener};
+use crate::AppError;
+use axum::extract::{Request, State};
+use axum::middleware::Next;
+use axum::response::Response;
+use std::sync::Arc;
+
+#[derive(Clone)]
+pub struct Admin {
+    bind: Bind,
+    keys: Arc<[String]>,
+}
+
+impl Admin {
+    /// Load `ADMIN_API_KEYS`, for the listener on `bind`
+    pub fn new(bind: Bind) -> Self {
+        let keys: Vec<String> = std::env::var("ADMIN_API_KEYS")
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|key| !key.is_empty())
+            .map(str::to_string)
+            .collect();
+        Self {
+            bind,
+            keys: keys.into(),
+        }
+    }
+
+    /// Where the listener is, for the startup banner
+    pub fn describe(&self) -> String {
+        self.bind.describe()
+    }
+
+    /// Whether callers need a key
+    pub fn authenticated(&self) -> bool {
+        !self.keys.is_empty()
+    }
+
+    pub async fn bind(&self) -> Listener {
+        self.bind.listen("ADMIN_ADDR").await
+    }
+}
+
+/// Middleware admitting holders of an `ADMIN_API_KEYS` key, as admins
+pub async fn require_key(
+    State(admin): State<Admin>,
+    mut request: Request,
+    next: Next,
+) -> Result<Response, AppError> {
+    let grant = if admin.keys.is_empty() {
+        Grant::admin("admin listener")
+    } else {
+        let key = request
+            .headers()
+            .get(API_KEY_HEADER)
+            .and_then(|key| key.to_str().ok())
+            .map(str::trim)
+            .ok_or_else(|| {
+                AppError::Unauthorized(format!(
+                    "Admin endpoints need a key of ADMIN_API_KEYS in {}",
+                    API_KEY_HEADER
+                ))
+            })?;
+        let i = admin
+            .keys
+            .iter()
+            .position(|known| known == key)
+            .ok_or_else(|| AppError::Unauthorized("Invalid admin key".to_string()))?;
+        Grant::admin(&format!("ADMIN_API_KEYS[{}]", i))
+    };
+    request.extensions_mut().insert(grant);
+    Ok(next.run(request).await)
+}
@@ -0,0 +1,106 @@
+//! Vanity model aliases
+//!
+//! `ALIASES_FILE` names a JSON file mapping stable names to a pinned file or
+//! a bundle of files in a repository:
+//!
+//! ```json
+//! {
+//!   "prod-chat-v3": { "repo": "jedisct1/MiMo-7B-RL-GGUF", "file": "MiMo-7B-RL-Q8_0.gguf" },
+//!   "prod-embed":   { "repo": "org/embedder", "files": ["model.onnx", "tokenizer.json"] },
+//!   "eval-set":     { "repo": "org/evals", "type": "dataset", "revision": "v2", "file": "test.parquet" }
+//! }
+//! ```
+//!
+//! `type` (`model`, `dataset` or `space`) defaults to `model` and `revision`
+//! to `main`.
+//!
+//! Applications reference `/models/<name>`; operators repoint a name by
+//! editing the file and restarting, without touching client configs.
+
+use crate::repo::{RepoRef, RepoType, DEFAULT_REVISION};
+use serde::Deserialize;
+use std::collections::HashMap;
+use tracing::info;
+
+/// What an alias resolves to
+#[derive(Clone, Debug, Deserialize)]
+pub struct Alias {
+    /// Repository as `owner/repo`
+    pub repo: String,
+    #[serde(default, rename = "type")]
+    pub repo_type: RepoType,
+    #[serde(default = "default_revision")]
+    pub revision: String,
+    #[serde(flatten)]
+    pub target: AliasTarget,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(untagged)]
+pub enum AliasTarget {
+    File { file: String },
+    Bundle { files: Vec<String> },
+}
+
+fn default_revision() -> String {
+    DEFAULT_REVISION.to_string()
+}
+
+impl Alias {
+    /// The repository and revision the alias points at
+    pub fn repo_ref(&self) -> RepoRef {
+        // Validated at load time
+        let (owner, name) = self.repo.split_once('/').unwrap();
+        RepoRef {
+            repo_type: self.repo_type,
+            owner: owner.to_string(),
+            name: name.to_string(),
+            revision: self.revision.clone(),
+        }
+    }
+
+    /// Whether `path` is served under this alias
+    pub fn contains(&self, path: &str) -> bool {
+        match &self.target {
+            AliasTarget::File { file } => file == path,
+            AliasTarget::Bundle { files } => files.iter().any(|f| f == path),
+        }
+    }
+}
+
+/// Alias table loaded at startup
+#[derive(Clone, Default)]
+pub struct Aliases {
+    aliases: HashMap<String, Alias>,
+}
+
+impl Aliases {
+    /// Load aliases from `ALIASES_FILE`, if set
+    pub fn from_env() -> Self {
+        let Ok(path) = std::env::var("ALIASES_FILE") else {
+            return Self::default();
+        };
+        let text = std::fs::read_to_string(&path)
+            .unwrap_or_else(|e| panic!("Failed to read ALIASES_FILE {}: {}", path, e));
+        let aliases: HashMap<String, Alias> = serde_json::from_str(&text)
+            .unwrap_or_else(|e| panic!("Invalid ALIASES_FILE {}: {}", path, e));
+
+        for (name, alias) in &aliases {
+            let valid_repo = alias.repo.split_once('/').is_some_and(|(owner, repo)| {
+                !owner.is_empty() && !repo.is_empty() && !repo.contains('/')
+            });
+            assert!(valid_repo, "Alias '{}': repo must be 'owner/repo'", name);
+            assert!(
+                !alias.revision.is_empty(),
+                "Alias '{}': revision must not be empty",
+                name
+            );
+        }
+        info!("Loaded {} model aliases from {}", aliases.len(), path);
+        Self { aliases }
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Alias> {
+        self.aliases.get(name)
+    }
+}
@@ -0,0 +1,243 @@
+//! Guard against requests multiplying upstream work
+//!
+//! A path download costs a listing of the repository on top of the download
+//! itself, so a client repeating cheap requests can make the proxy do much
+//! more upstream. Three safeguards keep that in check:
+//!
+//! - concurrent listings of one repository, revision, hub and token are
+//!   made once upstream, the other requests waiting for that listing; the
+//!   listing cache (see [`crate::listing_cache`]) then answers those that
+//!   follow. A waiting request lists for itself if the shared listing fails;
+//! - `LISTING_RATE_PER_MINUTE` caps the upstream listings each client makes
+//!   a minute (unset: no cap). A request over it gets 429 with
+//!   `Retry-After`; listings served from the cache or shared with another
+//!   request don't count. Clients are told apart by their authenticated
+//!   identity, else by IP address;
+//! - `xet_proxy_listing_requests_total{outcome}` counts the requests needing
+//!   a listing by how they got it (`cached`, `shared`, `upstream`,
+//!   `refused`), and `xet_proxy_listing_amplification` is the upstream
+//!   listings made per request needing one, 1 when nothing is saved.
+
+use crate::auth::Grant;
+use crate::listing::ListedFile;
+use crate::repo::RepoRef;
+use crate::AppError;
+use axum::extract::{ConnectInfo, Request};
+use axum::middleware::Next;
+use axum::response::Response;
+use std::collections::HashMap;
+use std::future::Future;
+use std::hash::{BuildHasher, RandomState};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
+use tracing::{debug, info, warn};
+
+/// Client buckets beyond which idle ones are dropped
+const PRUNE_AT: usize = 10_000;
+
+tokio::task_local! {
+    static CLIENT: Arc<str>;
+}
+
+/// Repository, revision, hub and token fingerprint of a listing
+type FlightKey = (String, String, Arc<str>, u64);
+
+/// A listing being made upstream, published once it succeeds
+type Flight = watch::Receiver<Option<Arc<[ListedFile]>>>;
+
+/// Token bucket refilling the per-minute cap evenly
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// How a request got its listing
+#[derive(Default)]
+struct Outcomes {
+    cached: AtomicU64,
+    shared: AtomicU64,
+    upstream: AtomicU64,
+    refused: AtomicU64,
+}
+
+#[derive(Clone)]
+pub struct Amplification {
+    per_minute: Option<u32>,
+    /// Fingerprints tokens, so they aren't kept in memory
+    hasher: RandomState,
+    flights: Arc<Mutex<HashMap<FlightKey, Flight>>>,
+    buckets: Arc<Mutex<HashMap<Arc<str>, Bucket>>>,
+    outcomes: Arc<Outcomes>,
+}
+
+impl Amplification {
+    /// Load `LISTING_RATE_PER_MINUTE`
+    pub fn from_env() -> Self {
+        let per_minute = crate::config::positive::<u32>("LISTING_RATE_PER_MINUTE");
+        if let Some(n) = per_minute {
+            info!("Upstream listings capped at {} a minute per client", n);
+        }
+        Self {
+            per_minute,
+            hasher: RandomState::new(),
+            flights: Arc::default(),
+            buckets: Arc::default(),
+            outcomes: Arc::default(),
+        }
+    }
+
+    /// Count a request whose listing came from the cache
+    pub fn cached(&self) {
+        self.outcomes.cached.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Take one upstream listing from the current client's allowance,
+    /// counting it when allowed
+    pub fn admit(&self) -> Result<(), AppError> {
+        let Some(limit) = self.per_minute else {
+            self.outcomes.upstream.fetch_add(1, Ordering::Relaxed);
+            return Ok(());
+        };
+        let client = current_client();
+        let limit = f64::from(limit);
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() >= PRUNE_AT {
+            // Idle a minute means full again, as good as new
+            buckets
+                .retain(|_, bucket| now.duration_since(bucket.updated) < Duration::from_secs(60));
+        }
+        let bucket = buckets.entry(client.clone()).or_insert(Bucket {
+            tokens: limit,
+            updated: now,
+        });
+        let refill = now.duration_since(bucket.updated).as_secs_f64() * limit / 60.0;
+        bucket.tokens = (bucket.tokens + refill).min(limit);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            self.outcomes.upstream.fetch_add(1, Ordering::Relaxed);
+            return Ok(());
+        }
+        self.outcomes.refused.fetch_add(1, Ordering::Relaxed);
+        let wait = Duration::from_secs_f64((1.0 - bucket.tokens) * 60.0 / limit);
+        warn!(
+            "Refused an upstream listing to {}: over {} a minute",
+            client, limit
+        );
+        Err(AppError::RateLimited {
+            message: format!("Over {} upstream repository listings per minute", limit),
+            retry_after: Some(wait),
+        })
+    }
+
+    /// The listing of `repo` made by `list`, unless the same listing is
+    /// already being made, in which case its result; a listing made here is
+    /// charged to the current client
+    pub async fn coalesce<F, Fut>(
+        &self,
+        repo: &RepoRef,
+        hf_token: &str,
+        list: F,
+    ) -> Result<Vec<ListedFile>, AppError>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<Vec<ListedFile>, AppError>>,
+    {
+        let key = (
+            repo.to_string(),
+            repo.revision.clone(),
+            crate::hub::endpoint(),
+            self.hasher.hash_one(hf_token),
+        );
+        let (publish, flight) = watch::channel(None);
+        let joined = {
+            let mut flights = self.flights.lock().unwrap();
+            match flights.get(&key) {
+                Some(flight) => Some(flight.clone()),
+                None => {
+                    flights.insert(key.clone(), flight);
+                    None
+                }
+            }
+        };
+        let Some(mut flight) = joined else {
+            // Made here, shared with those asking meanwhile
+            let _landing = Landing {
+                flights: &self.flights,
+                key,
+            };
+            self.admit()?;
+            let files = list().await?;
+            publish.send_replace(Some(files.as_slice().into()));
+            return Ok(files);
+        };
+        if let Ok(files) = flight.wait_for(Option::is_some).await {
+            if let Some(files) = files.as_ref() {
+                debug!(
+                    "Listing of {}@{} shared with another request",
+                    repo, repo.revision
+                );
+                self.outcomes.shared.fetch_add(1, Ordering::Relaxed);
+                return Ok(files.to_vec());
+            }
+        }
+        // The shared listing failed: this request lists for itself
+        self.admit()?;
+        list().await
+    }
+
+    /// `(outcome, count)` of the requests needing a listing
+    pub fn outcomes(&self) -> [(&'static str, u64); 4] {
+        let outcomes = &self.outcomes;
+        [
+            ("cached", outcomes.cached.load(Ordering::Relaxed)),
+            ("shared", outcomes.shared.load(Ordering::Relaxed)),
+            ("upstream", outcomes.upstream.load(Ordering::Relaxed)),
+            ("refused", outcomes.refused.load(Ordering::Relaxed)),
+        ]
+    }
+
+    /// Upstream listings per request needing one, once there was one
+    pub fn factor(&self) -> Option<f64> {
+        let outcomes = self.outcomes();
+        let requests: u64 = outcomes.iter().map(|(_, n)| n).sum();
+        let upstream = outcomes[2].1;
+        (requests > 0).then(|| upstream as f64 / requests as f64)
+    }
+}
+
+/// Removes a listing from those in flight once it lands, or is abandoned
+struct Landing<'a> {
+    flights: &'a Mutex<HashMap<FlightKey, Flight>>,
+    key: FlightKey,
+}
+
+impl Drop for Landing<'_> {
+    fn drop(&mut self) {
+        self.flights.lock().unwrap().remove(&self.key);
+    }
+}
+
+/// Client of the current request, `unknown` outside one
+fn current_client() -> Arc<str> {
+    CLIENT
+        .try_with(Arc::clone)
+        .unwrap_or_else(|_| "unknown".into())
+}
+
+/// Middleware noting who the request is from, for the listing cap; inside
+/// authentication, so it sees the client's identity
+pub async fn scope(request: Request, next: Next) -> Response {
+    let client: Arc<str> = match request.extensions().get::<Grant>() {
+        Some(grant) => format!("id:{}", grant.name).into(),
+        None => match request.extensions().get::<ConnectInfo<SocketAddr>>() {
+            Some(ConnectInfo(peer)) => format!("ip:{}", peer.ip()).into(),
+            None => "ip:unknown".into(),
+        },
+    };
+    CLIENT.scope(client, next.run(request)).await
+}
@@ -0,0 +1,695 @@
+//! Multi-file downloads as a streamed tar archive
+//!
+//! `GET /download-archive/:owner/:repo?prefix=` packs every listed file under
+//! the prefix into one uncompressed tar, e.g. all shards of a sharded
+//! safetensors checkpoint. Entries are named `<repo>/<path>` and written in
+//! listing order. Since every size is known from the listing, the archive's
+//! length is known before the first byte is sent.
+//!
+//! Up to `ARCHIVE_PARALLELISM` files (default 4) are downloaded at once: the
+//! one being streamed and the next few, each reading ahead into a bounded
+//! buffer. A file that ends short or fails aborts the archive, so a client
+//! never mistakes a truncated archive for a complete one.
+//!
+//! `?on_error=` sets what happens to a file whose download can't start:
+//! `fail` (the default) aborts the archive, `skip` leaves it out and
+//! `retry` retries it up to the request's retries (see
+//! [`crate::overrides`]) before leaving it out. Files left out are listed,
+//! with their errors, in a last `<repo>/.archive-errors.json` entry, and
+//! counted in the `X-Archive-Skipped` trailer. A file that fails once its
+//! bytes are being sent still aborts the archive. Since the archive's length
+//! then depends on what is left out, it is sent without `Content-Length`.
+//!
+//! Each archive is a job in [`crate::progress`], under the id the response
+//! carries in `X-Job-Id`.
+//!
+//! An archive has no upstream hash to check it against, so its SHA-256 is
+//! computed as it streams. Once the last byte is out it is logged, reported
+//! as `sha256` by the job, and sent as the `X-Archive-Sha256` trailer (hex).
+//! HTTP/1.1 only carries trailers in chunked responses to clients that ask
+//! for them, so a request with `TE: trailers` gets the archive without its
+//! `Content-Length`.
+
+use crate::downloader::{DownloadRequest, Downloader};
+use crate::listing::ListedFile;
+use crate::overrides::RequestOptions;
+use crate::progress::Job;
+use crate::repo::RepoRef;
+use crate::slots::Priority;
+use crate::transfer::Source;
+use axum::body::Bytes;
+use axum::http::{HeaderMap, HeaderName, HeaderValue};
+use http_body::{Body, Frame};
+use ring::digest;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tokio::sync::{mpsc, oneshot};
+use tokio_stream::StreamExt;
+use tracing::{info, warn};
+
+const BLOCK: usize = 512;
+/// Chunks each file reads ahead of the archive
+const FILE_BUFFER: usize = 16;
+/// Largest size the octal `size` field holds; larger files get a PAX record
+const MAX_OCTAL_SIZE: u64 = 0o77777777777;
+/// Trailer carrying the archive's SHA-256
+pub const SHA256_TRAILER: &str = "x-archive-sha256";
+/// Trailer counting the files left out of the archive
+pub const SKIPPED_TRAILER: &str = "x-archive-skipped";
+/// Name of the entry listing the files left out, under the repository's
+const ERRORS_ENTRY: &str = ".archive-errors.json";
+
+/// What an archive does with a file whose download can't start
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize, utoipa::ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum OnError {
+    /// Abort the archive
+    #[default]
+    Fail,
+    /// Leave the file out
+    Skip,
+    /// Retry the file up to the request's retries, then leave it out
+    Retry,
+}
+
+/// A file left out of the archive, as listed in its errors entry
+#[derive(Serialize)]
+struct Skipped {
+    path: String,
+    xet_hash: String,
+    size: u64,
+    error: String,
+}
+
+/// Contents of the errors entry
+#[derive(Serialize)]
+struct ErrorsManifest<'a> {
+    on_error: OnError,
+    skipped: &'a [Skipped],
+}
+
+/// Load `ARCHIVE_PARALLELISM` (default 4)
+pub fn parallelism_from_env() -> usize {
+    crate::config::positive("ARCHIVE_PARALLELISM").unwrap_or(4)
+}
+
+/// Header blocks of one regular file entry
+fn entry_header(path: &str, size: u64) -> Vec<u8> {
+    let mut out = Vec::new();
+    let mut records = String::new();
+    if path.len() > 100 {
+        records.push_str(&pax_record("path", path));
+    }
+    if size > MAX_OCTAL_SIZE {
+        records.push_str(&pax_record("size", &size.to_string()));
+    }
+    if !records.is_empty() {
+        out.extend(ustar_block("././@PaxHeader", records.len() as u64, b'x'));
+        out.extend(records.as_bytes());
+        out.resize(out.len() + padding(records.len() as u64), 0);
+    }
+    // A PAX record overrides the truncated name and the clamped size
+    let name = truncate(path, 100);
+    out.extend(ustar_block(name, size.min(MAX_OCTAL_SIZE), b'0'));
+    out
+}
+
+/// One ustar header block
+fn ustar_block(name: &str, size: u64, kind: u8) -> [u8; BLOCK] {
+    let mut block = [0u8; BLOCK];
+    let mut field = |offset: usize, value: &[u8]| {
+        block[offset..offset + value.len()].copy_from_slice(value);
+    };
+    field(0, name.as_bytes());
+    field(100, b"0000644\0");
+    field(108, b"0000000\0");
+    field(116, b"0000000\0");
+    field(124, format!("{:011o}\0", size).as_bytes());
+    field(136, b"00000000000\0");
+    field(156, &[kind]);
+    field(257, b"ustar\0");
+    field(263, b"00");
+    // The checksum is computed with its own field set to spaces
+    field(148, b"        ");
+    let checksum: u32 = block.iter().map(|&b| b as u32).sum();
+    block[148..156].copy_from_slice(format!("{:06o}\0 ", checksum).as_bytes());
+    block
+}
+
+/// `<length> <key>=<value>\n`, where the length counts itself
+fn pax_record(key: &str, value: &str) -> String {
+    let body = format!(" {}={}\n", key, value);
+    let mut length = body.len() + 1;
+    while (length.to_string().len() + body.len()) != length {
+        length = length.to_string().len() + body.len();
+    }
+    format!("{}{}", length, body)
+}
+
+fn truncate(path: &str, max: usize) -> &str {
+    let mut end = path.len().min(max);
+    while !path.is_char_boundary(end) {
+        end -= 1;
+    }
+    &path[..end]
+}
+
+/// Zero bytes after `size` bytes of data, up to the next block boundary
+fn padding(size: u64) -> usize {
+    (BLOCK - (size % BLOCK as u64) as usize) % BLOCK
+}
+
+/// Exact length of the archive of `files`
+pub fn archive_size(repo: &RepoRef, files: &[ListedFile]) -> u64 {
+    let entries: u64 = files
+        .iter()
+        .map(|f| {
+            let header = entry_header(&entry_name(repo, &f.path), f.size).len() as u64;
+            header + f.size + padding(f.size) as u64
+        })
+        .sum();
+    // Two zero blocks end the archive
+    entries + 2 * BLOCK as u64
+}
+
+fn entry_name(repo: &RepoRef, path: &str) -> String {
+    format!("{}/{}", repo.name, path)
+}
+
+/// Stream the tar archive of `files`, downloading up to `parallelism` at once
+#[allow(clippy::too_many_arguments)]
+pub fn stream(
+    downloader: Arc<dyn Downloader>,
+    repo: RepoRef,
+    files: Vec<ListedFile>,
+    hf_token: String,
+    parallelism: usize,
+    options: RequestOptions,
+    on_error: OnError,
+    priority: Priority,
+    job: Arc<Job>,
+) -> ArchiveBody {
+    let (sender, body) = mpsc::channel(FILE_BUFFER);
+    let retries = job.retries();
+    let archive = async move {
+        let count = files.len();
+        job.add_total(archive_size(&repo, &files));
+        let mut archive = Output {
+            sender,
+            job,
+            digest: digest::Context::new(&digest::SHA256),
+        };
+        let member = Member {
+            downloader,
+            repo: &repo,
+            hf_token: hf_token.into(),
+            options,
+            on_error,
+            priority,
+        };
+        let written = write(&mut archive, &member, files, parallelism);
+        match written.await {
+            Ok(skipped) => {
+                let sha256 = hex::encode(archive.digest.clone().finish());
+                info!(
+                    "Archive of {} complete ({} files, {} skipped, sha256 {})",
+                    repo,
+                    count - skipped,
+                    skipped,
+                    sha256
+                );
+                archive.job.set_sha256(sha256.clone());
+                archive.job.finish(Ok(()));
+                let mut trailers = HeaderMap::new();
+                if let Ok(value) = HeaderValue::from_str(&sha256) {
+                    trailers.insert(HeaderName::from_static(SHA256_TRAILER), value);
+                }
+                if on_error != OnError::Fail {
+                    trailers.insert(HeaderName::from_static(SKIPPED_TRAILER), skipped.into());
+                }
+                let _ = archive.sender.send(Ok(Frame::trailers(trailers))).await;
+            }
+            Err(e) => {
+                warn!("Archive of {} aborted: {}", repo, e);
+                archive.job.finish(Err(e.to_string()));
+                let _ = archive.sender.send(Err(e)).await;
+            }
+        }
+    };
+    tokio::spawn(crate::upstream::inherit(crate::retry::counted(
+        retries, archive,
+    )));
+    ArchiveBody { frames: body }
+}
+
+/// The archive as it streams, ending with its SHA-256 trailer
+pub struct ArchiveBody {
+    frames: mpsc::Receiver<io::Result<Frame<Bytes>>>,
+}
+
+impl Body for ArchiveBody {
+    type Data = Bytes;
+    type Error = io::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<io::Result<Frame<Bytes>>>> {
+        self.frames.poll_recv(cx)
+    }
+}
+
+/// The archive body, counting what is sent into its job and its digest
+struct Output {
+    sender: mpsc::Sender<io::Result<Frame<Bytes>>>,
+    job: Arc<Job>,
+    digest: digest::Context,
+}
+
+impl Output {
+    async fn send(&mut self, bytes: Bytes) -> io::Result<()> {
+        let len = bytes.len() as u64;
+        self.digest.update(&bytes);
+        self.sender
+            .send(Ok(Frame::data(bytes)))
+            .await
+            .map_err(|_| io::Error::other("client went away"))?;
+        self.job.add_bytes(len);
+        Ok(())
+    }
+}
+
+/// How the archive's files are fetched
+struct Member<'a> {
+    downloader: Arc<dyn Downloader>,
+    repo: &'a RepoRef,
+    hf_token: Arc<str>,
+    options: RequestOptions,
+    on_error: OnError,
+    priority: Priority,
+}
+
+/// Write the archive, returning how many files were left out
+async fn write(
+    archive: &mut Output,
+    member: &Member<'_>,
+    files: Vec<ListedFile>,
+    parallelism: usize,
+) -> io::Result<usize> {
+    let repo = member.repo;
+    let mut pending = files.into_iter();
+    let mut started = VecDeque::new();
+    let mut skipped = Vec::new();
+    loop {
+        while started.len() < parallelism {
+            let Some(file) = pending.next() else {
+                break;
+            };
+            let download = fetch(member, &file, archive.job.clone());
+            started.push_back((file, download));
+        }
+        let Some((file, download)) = started.pop_front() else {
+            break;
+        };
+
+        let header = entry_header(&entry_name(repo, &file.path), file.size);
+        let mut chunks = match download.await {
+            Ok(Ok(chunks)) => chunks,
+            Ok(Err(error)) if member.on_error == OnError::Fail => {
+                return Err(io::Error::other(error));
+            }
+            Ok(Err(error)) => {
+                warn!(
+                    "Leaving {} out of the archive of {}: {}",
+                    file.path, repo, error
+                );
+                let entry = header.len() as u64 + file.size + padding(file.size) as u64;
+                archive.job.sub_total(entry);
+                archive.job.file_done();
+                skipped.push(Skipped {
+                    path: file.path,
+                    xet_hash: file.xet_hash,
+                    size: file.size,
+                    error,
+                });
+                continue;
+            }
+            Err(_) => return Err(io::Error::other(format!("{} was not fetched", file.path))),
+        };
+        archive.send(header.into()).await?;
+        let mut written = 0u64;
+        while let Some(chunk) = chunks.recv().await {
+            let chunk = chunk?;
+            written += chunk.len() as u64;
+            if written > file.size {
+                break;
+            }
+            archive.send(chunk).await?;
+        }
+        if written != file.size {
+            return Err(io::Error::other(format!(
+                "{} delivered {} of {} bytes",
+                file.path, written, file.size
+            )));
+        }
+        let padding = vec![0u8; padding(file.size)];
+        archive.send(padding.into()).await?;
+        archive.job.file_done();
+    }
+    if !skipped.is_empty() {
+        let manifest = ErrorsManifest {
+            on_error: member.on_error,
+            skipped: &skipped,
+        };
+        let json = serde_json::to_vec_pretty(&manifest).map_err(io::Error::other)?;
+        let size = json.len() as u64;
+        let header = entry_header(&entry_name(repo, ERRORS_ENTRY), size);
+        archive
+            .job
+            .add_total(header.len() as u64 + size + padding(size) as u64);
+        archive.send(header.into()).await?;
+        archive.send(json.into()).await?;
+        archive.send(vec![0u8; padding(size)].into()).await?;
+    }
+    let trailer = vec![0u8; 2 * BLOCK];
+    archive.send(trailer.into()).await?;
+    Ok(skipped.len())
+}
+
+/// Start downloading one file into a bounded buffer, resolving to the
+/// buffer once the download started or to why it couldn't
+fn fetch(
+    member: &Member<'_>,
+    file: &ListedFile,
+    job: Arc<Job>,
+) -> oneshot::Receiver<Result<mpsc::Receiver<io::Result<Bytes>>, String>> {
+    let (started, receiver) = oneshot::channel();
+    let (downloader, repo) = (member.downloader.clone(), member.repo.clone());
+    let (hf_token, options, on_error) = (
+        member.hf_token.clone(),
+        member.options.clone(),
+        member.on_error,
+    );
+    let priority = member.priority;
+    let (path, hash, size) = (file.path.clone(), file.xet_hash.clone(), file.size);
+    tokio::spawn(crate::upstream::inherit(async move {
+        let start = || {
+            downloader.download(DownloadRequest {
+                repo: &repo,
+                hash: &hash,
+                hf_token: &hf_token,
+                range: None,
+                length: Some(size),
+                deadline: options.deadline,
+                priority,
+                spool: false,
+            })
+        };
+        let download = match on_error {
+            OnError::Retry => options.run("Archive file download", start).await,
+            OnError::Fail | OnError::Skip => start().await,
+        };
+        let mut body = match download {
+            Ok(download) => {
+                job.attribute(Source::of(&download), download.upstream_bytes.clone());
+                download.body
+            }
+            Err(e) => {
+                let _ = started.send(Err(format!("{}: {}", path, e.message())));
+                return;
+            }
+        };
+        let (chunks, buffer) = mpsc::channel(FILE_BUFFER);
+        if started.send(Ok(buffer)).is_err() {
+            return;
+        }
+        // Stops (dropping the download) once the archive no longer wants it
+        while let Some(chunk) = body.next().await {
+            if chunks.send(chunk).await.is_err() {
+                return;
+            }
+        }
+    }));
+    receiver
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dev::DevDownloader;
+    use crate::overrides::OverrideLimits;
+    use crate::progress::Progress;
+
+    /// Value of an octal header field
+    fn octal(field: &[u8]) -> u64 {
+        let digits = std::str::from_utf8(field).unwrap();
+        u64::from_str_radix(digits.trim_matches(|c| c == '\0' || c == ' '), 8).unwrap()
+    }
+
+    fn name(block: &[u8]) -> &str {
+        let end = block[..100].iter().position(|&b| b == 0).unwrap_or(100);
+        std::str::from_utf8(&block[..end]).unwrap()
+    }
+
+    /// Entries of a tar archive, with PAX paths applied, checking every
+    /// header's checksum and the end-of-archive blocks
+    fn entries(mut tar: &[u8]) -> Vec<(String, Vec<u8>)> {
+        let mut entries = Vec::new();
+        let mut pax_path = None;
+        loop {
+            let block = &tar[..BLOCK];
+            if block.iter().all(|&b| b == 0) {
+                assert_eq!(tar, &[0u8; 2 * BLOCK][..], "end of archive");
+                return entries;
+            }
+            let mut unsummed = block.to_vec();
+            unsummed[148..156].copy_from_slice(b"        ");
+            let sum: u64 = unsummed.iter().map(|&b| b as u64).sum();
+            assert_eq!(octal(&block[148..155]), sum, "checksum of {}", name(block));
+            assert_eq!(&block[257..265], b"ustar\x0000");
+
+            let size = octal(&block[124..136]) as usize;
+            let data = &tar[BLOCK..BLOCK + size];
+            match block[156] {
+                b'x' => {
+                    let records = std::str::from_utf8(data).unwrap();
+                    pax_path = records
+                        .lines()
+                        .find_map(|line| line.split_once(" path="))
+                        .map(|(_, path)| path.to_string());
+                }
+                b'0' => {
+                    let path = pax_path.take().unwrap_or_else(|| name(block).to_string());
+                    entries.push((path, data.to_vec()));
+                }
+                kind => panic!("unexpected entry type {}", kind),
+            }
+            tar = &tar[BLOCK + size + padding(size as u64)..];
+        }
+    }
+
+    #[test]
+    fn padding_rounds_up_to_a_block() {
+        assert_eq!(padding(0), 0);
+        assert_eq!(padding(1), 511);
+        assert_eq!(padding(511), 1);
+        assert_eq!(padding(512), 0);
+        assert_eq!(padding(513), 511);
+    }
+
+    #[test]
+    fn short_entries_have_one_ustar_header() {
+        let header = entry_header("repo/config.json", 1234);
+        assert_eq!(header.len(), BLOCK);
+        assert_eq!(name(&header), "repo/config.json");
+        assert_eq!(&header[124..136], b"00000002322\0");
+        assert_eq!(header[156], b'0');
+
+        let mut tar = header;
+        tar.extend(vec![7u8; 1234]);
+        tar.resize(tar.len() + padding(1234) + 2 * BLOCK, 0);
+        assert_eq!(
+            entries(&tar),
+            [("repo/config.json".to_string(), vec![7u8; 1234])]
+        );
+    }
+
+    #[test]
+    fn pax_record_length_counts_itself() {
+        // Lengths around where the length gains a digit
+        for len in 0..1100 {
+            let record = pax_record("path", &"a".repeat(len));
+            let (length, rest) = record.split_once(' ').unwrap();
+            assert_eq!(length.parse::<usize>().unwrap(), record.len(), "{}", len);
+            assert_eq!(rest, format!("path={}\n", "a".repeat(len)));
+        }
+    }
+
+    #[test]
+    fn long_paths_get_a_pax_record() {
+        let path = format!("repo/{}/model.safetensors", "shard".repeat(30));
+        let header = entry_header(&path, 10);
+        let records = pax_record("path", &path);
+        assert_eq!(header[156], b'x');
+        assert_eq!(octal(&header[124..136]), records.len() as u64);
+        assert_eq!(&header[BLOCK..BLOCK + records.len()], records.as_bytes());
+        assert_eq!(header.len(), 3 * BLOCK);
+
+        // The ustar name is the path cut to 100 bytes, for readers without PAX
+        let ustar = &header[2 * BLOCK..];
+        assert_eq!(name(ustar), &path[..100]);
+        assert_eq!(ustar[156], b'0');
+
+        let mut tar = header;
+        tar.extend(b"0123456789");
+        tar.resize(tar.len() + padding(10) + 2 * BLOCK, 0);
+        assert_eq!(entries(&tar), [(path, b"0123456789".to_vec())]);
+    }
+
+    #[test]
+    fn long_paths_are_cut_on_a_char_boundary() {
+        let path = format!("r/{}", "é".repeat(60));
+        let header = entry_header(&path, 0);
+        let ustar = &header[header.len() - BLOCK..];
+        assert_eq!(name(ustar), &path[..100]);
+
+        let path = format!("r/a{}", "é".repeat(60));
+        let header = entry_header(&path, 0);
+        let ustar = &header[header.len() - BLOCK..];
+        assert_eq!(name(ustar), &path[..99]);
+    }
+
+    #[test]
+    fn huge_files_get_a_pax_size() {
+        let size = MAX_OCTAL_SIZE + 1;
+        let header = entry_header("repo/huge.bin", size);
+        let records = pax_record("size", &size.to_string());
+        assert_eq!(&header[BLOCK..BLOCK + records.len()], records.as_bytes());
+        // The ustar size is clamped, overridden by the record
+        let ustar = &header[2 * BLOCK..];
+        assert_eq!(octal(&ustar[124..136]), MAX_OCTAL_SIZE);
+    }
+
+    struct Archive {
+        data: Vec<u8>,
+        trailers: Option<HeaderMap>,
+        error: Option<io::Error>,
+    }
+
+    async fn archive(files: Vec<ListedFile>, on_error: OnError) -> Archive {
+        let options =
+            RequestOptions::from_headers(&HeaderMap::new(), &OverrideLimits::from_env()).unwrap();
+        let job = Progress::from_env().start("test", "archive", files.len());
+        let mut body = stream(
+            Arc::new(DevDownloader::from_env()),
+            RepoRef::parse(crate::dev::REPO, None).unwrap(),
+            files,
+            crate::dev::TOKEN.to_string(),
+            2,
+            options,
+            on_error,
+            Priority::Normal,
+            job,
+        );
+        let mut archive = Archive {
+            data: Vec::new(),
+            trailers: None,
+            error: None,
+        };
+        while let Some(frame) = std::future::poll_fn(|cx| Pin::new(&mut body).poll_frame(cx)).await
+        {
+            match frame {
+                Ok(frame) => match frame.into_data() {
+                    Ok(data) => archive.data.extend(data),
+                    Err(frame) => archive.trailers = frame.into_trailers().ok(),
+                },
+                Err(e) => archive.error = Some(e),
+            }
+        }
+        archive
+    }
+
+    async fn samples() -> Vec<ListedFile> {
+        let repo = RepoRef::parse(crate::dev::REPO, None).unwrap();
+        DevDownloader::from_env()
+            .list(&repo, crate::dev::TOKEN)
+            .await
+            .unwrap()
+    }
+
+    fn missing() -> ListedFile {
+        ListedFile {
+            path: "missing.bin".to_string(),
+            size: 100,
+            xet_hash: "0".repeat(64),
+        }
+    }
+
+    #[tokio::test]
+    async fn archive_holds_every_file_in_listing_order() {
+        let files = samples().await;
+        let repo = RepoRef::parse(crate::dev::REPO, None).unwrap();
+        let size = archive_size(&repo, &files);
+        let archive = archive(files.clone(), OnError::Fail).await;
+        assert!(archive.error.is_none());
+        assert_eq!(archive.data.len() as u64, size);
+
+        let entries = entries(&archive.data);
+        let names: Vec<_> = entries.iter().map(|(name, _)| name.clone()).collect();
+        let listed: Vec<_> = files
+            .iter()
+            .map(|f| format!("dev-samples/{}", f.path))
+            .collect();
+        assert_eq!(names, listed);
+        for ((_, data), file) in entries.iter().zip(&files) {
+            assert_eq!(data.len() as u64, file.size);
+        }
+
+        let trailers = archive.trailers.unwrap();
+        let sha256 = hex::encode(digest::digest(&digest::SHA256, &archive.data));
+        assert_eq!(trailers[SHA256_TRAILER], sha256.as_str());
+        assert!(!trailers.contains_key(SKIPPED_TRAILER));
+    }
+
+    #[tokio::test]
+    async fn failing_file_aborts_the_archive_by_default() {
+        let mut files = samples().await;
+        files.insert(1, missing());
+        let archive = archive(files, OnError::Fail).await;
+        assert!(archive.error.is_some());
+        assert!(archive.trailers.is_none());
+    }
+
+    #[tokio::test]
+    async fn skipped_files_are_listed_in_the_errors_entry() {
+        let mut files = samples().await;
+        let kept: Vec<_> = files
+            .iter()
+            .map(|f| format!("dev-samples/{}", f.path))
+            .collect();
+        files.insert(1, missing());
+        let archive = archive(files, OnError::Skip).await;
+        assert!(archive.error.is_none());
+
+        let mut entries = entries(&archive.data);
+        let (name, errors) = entries.pop().unwrap();
+        assert_eq!(name, "dev-samples/.archive-errors.json");
+        let names: Vec<_> = entries.into_iter().map(|(name, _)| name).collect();
+        assert_eq!(names, kept);
+
+        let errors: serde_json::Value = serde_json::from_slice(&errors).unwrap();
+        assert_eq!(errors["on_error"], "skip");
+        assert_eq!(errors["skipped"].as_array().unwrap().len(), 1);
+        assert_eq!(errors["skipped"][0]["path"], "missing.bin");
+        assert_eq!(errors["skipped"][0]["size"], 100);
+
+        let trailers = archive.trailers.unwrap();
+        assert_eq!(trailers[SKIPPED_TRAILER], "1");
+        let sha256 = hex::encode(digest::digest(&digest::SHA256, &archive.data));
+        assert_eq!(trailers[SHA256_TRAILER], sha256.as_str());
+    }
+}
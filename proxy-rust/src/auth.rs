@@ -0,0 +1,738 @@
+//! Authentication of proxy clients
+//!
+//! Clients are authenticated by a chain of [`AuthProvider`]s, named in
+//! `AUTH_PROVIDERS` in the order they are tried:
+//!
+//! - `api_keys`: keys in `X-API-Key`, from `API_KEYS` and `API_KEYS_FILE`;
+//! - `jwt`: signed tokens in `X-Proxy-Authorization: Bearer`;
+//! - `mtls`: the subject of the client certificate, verified by the proxy's
+//!   own HTTPS listener or by a TLS-terminating front proxy passing it in a
+//!   header;
+//! - `external`: an HTTP authorizer asked about every request.
+//!
+//! Without `AUTH_PROVIDERS`, every provider that is configured is used, in
+//! that order; with none configured the proxy is open. The HuggingFace
+//! token still goes in `Authorization`.
+//!
+//! Every request except `/` and the health probes must then be authenticated; the
+//! first provider recognizing credentials in the request decides, and a
+//! request none of them recognizes is refused with 401. The resulting
+//! [`Grant`] may restrict the repositories the client reaches (`owners`,
+//! `repos`: 403 for any other; endpoints not tied to a repository are open
+//! to every client) and bound its request rate (`requests_per_minute`: 429
+//! with `Retry-After` beyond it). Admins (`admin`) may also change other
+//! clients' limits at runtime (see [`crate::tenants`]).
+//!
+//! Browsers log in once with their credentials and follow download links
+//! with a cookie instead (see [`login`]).
+//!
+//! `SIGHUP` reloads the providers' files. A file that no longer loads is
+//! logged and the previous contents stay in force; rate limit state carries
+//! over.
+
+mod external;
+mod jwt;
+mod keys;
+mod login;
+mod mtls;
+
+pub use keys::API_KEY_HEADER;
+pub use login::set_cookie;
+
+use crate::repo::RepoRef;
+use crate::repo::RepoType;
+use crate::tenants::Tenants;
+use crate::{AppError, ROUTE_DOWNLOAD, ROUTE_UPLOAD};
+use async_trait::async_trait;
+use axum::extract::{MatchedPath, Query, RawPathParams, Request, State};
+use axum::http::{HeaderMap, Method, Uri};
+use axum::middleware::Next;
+use axum::response::Response;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::{error, info, warn};
+
+/// Routes reachable without credentials
+const OPEN_ROUTES: [&str; 6] = ["/", "/health", "/healthz", "/readyz", "/login", "/logout"];
+const PROVIDERS: [&str; 4] = ["api_keys", "jwt", "mtls", "external"];
+
+/// The parts of a request providers authenticate
+pub struct Credentials<'a> {
+    pub method: &'a Method,
+    pub uri: &'a Uri,
+    pub headers: &'a HeaderMap,
+    /// Subject of the certificate the client presented to [`crate::tls`]
+    pub client_cert: Option<&'a str>,
+}
+
+impl Credentials<'_> {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .get(name)
+            .and_then(|h| h.to_str().ok())
+            .map(str::trim)
+    }
+}
+
+/// One way of authenticating clients
+#[async_trait]
+pub trait AuthProvider: Send + Sync {
+    /// Name in `AUTH_PROVIDERS`
+    fn name(&self) -> &'static str;
+
+    /// Where the provider looks for credentials, for the 401 message
+    fn expects(&self) -> &'static str;
+
+    /// The grant for the request's credentials, `Ok(None)` if it carries
+    /// none this provider recognizes, or the refusal of the ones it carries
+    async fn authenticate(&self, credentials: &Credentials<'_>) -> Result<Option<Grant>, AppError>;
+
+    /// Reread the provider's files, on `SIGHUP`
+    fn reload(&self) {}
+}
+
+/// Restrictions of a client, as written in the providers' files and claims
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct Restrictions {
+    owners: Vec<String>,
+    repos: Vec<String>,
+    requests_per_minute: Option<u32>,
+    admin: bool,
+}
+
+impl Restrictions {
+    fn grant(self, name: &str) -> Result<Grant, String> {
+        if self.requests_per_minute == Some(0) {
+            return Err("requests_per_minute must be positive".to_string());
+        }
+        let repos = self
+            .repos
+            .iter()
+            .map(|spec| RepoRef::parse(spec, None))
+            .collect::<Option<Vec<_>>>()
+            .ok_or_else(|| "repos must be 'owner/repo' or '<type>s/owner/repo'".to_string())?;
+        Ok(Grant {
+            name: name.into(),
+            owners: self.owners,
+            repos,
+            requests_per_minute: self.requests_per_minute,
+            admin: self.admin,
+        })
+    }
+}
+
+/// What a client may do; handlers check it for repositories they resolve
+/// themselves
+#[derive(Clone, Debug)]
+pub struct Grant {
+    pub name: Arc<str>,
+    owners: Vec<String>,
+    repos: Vec<RepoRef>,
+    requests_per_minute: Option<u32>,
+    /// May use the admin endpoints
+    admin: bool,
+}
+
+impl Grant {
+    /// A grant reaching every repository
+    fn unrestricted(name: &str) -> Self {
+        Self {
+            name: name.into(),
+            owners: Vec::new(),
+            repos: Vec::new(),
+            requests_per_minute: None,
+            admin: false,
+        }
+    }
+
+    /// An unrestricted grant of an operator
+    pub fn admin(name: &str) -> Self {
+        Self {
+            admin: true,
+            ..Self::unrestricted(name)
+        }
+    }
+
+    /// Refuse with 403 unless the client is an admin
+    pub fn check_admin(&self) -> Result<(), AppError> {
+        if self.admin {
+            Ok(())
+        } else {
+            Err(AppError::Forbidden(format!(
+                "'{}' is not an admin",
+                self.name
+            )))
+        }
+    }
+
+    /// Refuse with 403 unless the client may reach `repo`
+    pub fn check(&self, repo: &RepoRef) -> Result<(), AppError> {
+        if self.owners.is_empty() && self.repos.is_empty() {
+            return Ok(());
+        }
+        let allowed = self.owners.contains(&repo.owner)
+            || self.repos.iter().any(|allowed| {
+                allowed.repo_type == repo.repo_type
+                    && allowed.owner == repo.owner
+                    && allowed.name == repo.name
+            });
+        if allowed {
+            Ok(())
+        } else {
+            Err(AppError::Forbidden(format!(
+                "'{}' is not allowed to access {}",
+                self.name, repo
+            )))
+        }
+    }
+}
+
+/// The configured providers, with the clients' rate limit state
+#[derive(Clone)]
+pub struct Authenticator {
+    providers: Arc<[Box<dyn AuthProvider>]>,
+    buckets: Arc<Mutex<HashMap<Arc<str>, Bucket>>>,
+    /// Limits set at runtime, overriding the grants'
+    tenants: Tenants,
+    logins: login::Logins,
+}
+
+/// Token bucket refilling `requests_per_minute` a minute
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Authenticator {
+    /// Load `AUTH_PROVIDERS` and the providers' settings; `None` if no
+    /// provider is configured
+    pub fn from_env() -> Option<Self> {
+        let listed = std::env::var("AUTH_PROVIDERS").ok().map(|names| {
+            let names: Vec<String> = names
+                .split(',')
+                .map(|name| name.trim().to_string())
+                .filter(|name| !name.is_empty())
+                .collect();
+            assert!(
+                !names.is_empty(),
+                "AUTH_PROVIDERS must list at least one provider"
+            );
+            for (i, name) in names.iter().enumerate() {
+                assert!(
+                    PROVIDERS.contains(&name.as_str()),
+                    "AUTH_PROVIDERS: unknown provider '{}' (expected one of: {})",
+                    name,
+                    PROVIDERS.join(", ")
+                );
+                assert!(
+                    !names[..i].contains(name),
+                    "AUTH_PROVIDERS lists '{}' twice",
+                    name
+                );
+            }
+            names
+        });
+        let names = listed
+            .clone()
+            .unwrap_or_else(|| PROVIDERS.iter().map(|name| name.to_string()).collect());
+
+        let mut providers: Vec<Box<dyn AuthProvider>> = Vec::new();
+        for name in &names {
+            let provider: Option<Box<dyn AuthProvider>> = match name.as_str() {
+                "api_keys" => keys::StaticKeys::from_env().map(|p| Box::new(p) as _),
+                "jwt" => jwt::Jwt::from_env().map(|p| Box::new(p) as _),
+                "mtls" => mtls::ClientCert::from_env().map(|p| Box::new(p) as _),
+                _ => external::External::from_env().map(|p| Box::new(p) as _),
+            };
+            match provider {
+                Some(provider) => providers.push(provider),
+                None if listed.is_some() => {
+                    panic!("AUTH_PROVIDERS lists '{}', which is not configured", name)
+                }
+                None => {}
+            }
+        }
+        if providers.is_empty() {
+            return None;
+        }
+        info!(
+            "Authentication by {}",
+            providers
+                .iter()
+                .map(|p| p.name())
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+        Some(Self {
+            providers: providers.into(),
+            buckets: Arc::default(),
+            tenants: Tenants::from_env(),
+            logins: login::Logins::from_env(),
+        })
+    }
+
+    /// Per-client limits set at runtime
+    pub fn tenants(&self) -> &Tenants {
+        &self.tenants
+    }
+
+    /// Names of the providers, in order
+    pub fn providers(&self) -> Vec<&'static str> {
+        self.providers.iter().map(|p| p.name()).collect()
+    }
+
+    /// Reload the providers' files on every `SIGHUP`
+    pub fn start(&self) {
+        let auth = self.clone();
+        tokio::spawn(async move {
+            let mut hangups =
+                match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup()) {
+                    Ok(hangups) => hangups,
+                    Err(e) => {
+                        error!(
+                            "Cannot watch SIGHUP, authentication files won't reload: {}",
+                            e
+                        );
+                        return;
+                    }
+                };
+            while hangups.recv().await.is_some() {
+                for provider in auth.providers.iter() {
+                    provider.reload();
+                }
+                auth.tenants.reload();
+                auth.logins.clear();
+            }
+        });
+    }
+
+    /// How long a browser login lasts
+    pub fn login_ttl(&self) -> Duration {
+        self.logins.ttl()
+    }
+
+    /// Log in with the request's credentials, returning the login cookie's
+    /// token and the grant it stands for
+    pub async fn login(&self, credentials: &Credentials<'_>) -> Result<(String, Grant), AppError> {
+        let grant = self.authenticate(credentials).await?;
+        Ok((self.logins.start(grant.clone()), grant))
+    }
+
+    /// End the login of the request's cookie, if any
+    pub fn logout(&self, headers: &HeaderMap) {
+        self.logins.end(headers);
+    }
+
+    /// The grant of the request's login cookie, for reads, else of the
+    /// first provider recognizing its credentials, after charging its rate
+    /// limit
+    async fn authenticate(&self, credentials: &Credentials<'_>) -> Result<Grant, AppError> {
+        let read = matches!(*credentials.method, Method::GET | Method::HEAD);
+        let mut grant = self.logins.grant(credentials.headers).filter(|_| read);
+        for provider in self.providers.iter() {
+            if grant.is_some() {
+                break;
+            }
+            grant = provider.authenticate(credentials).await?;
+        }
+        let Some(grant) = grant else {
+            let expected: Vec<&str> = self.providers.iter().map(|p| p.expects()).collect();
+            return Err(AppError::Unauthorized(format!(
+                "Authentication required: {}",
+                expected.join(", or ")
+            )));
+        };
+        let limit = self
+            .tenants
+            .get(&grant.name)
+            .and_then(|limits| limits.requests_per_minute)
+            .or(grant.requests_per_minute);
+        if let Some(limit) = limit {
+            self.charge(&grant.name, limit)?;
+        }
+        Ok(grant)
+    }
+
+    fn charge(&self, name: &Arc<str>, limit: u32) -> Result<(), AppError> {
+        let limit = f64::from(limit);
+        let mut buckets = self.buckets.lock().unwrap();
+        let now = Instant::now();
+        let bucket = buckets.entry(name.clone()).or_insert(Bucket {
+            tokens: limit,
+            updated: now,
+        });
+        let refill = now.duration_since(bucket.updated).as_secs_f64() * limit / 60.0;
+        bucket.tokens = (bucket.tokens + refill).min(limit);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            return Ok(());
+        }
+        let wait = Duration::from_secs_f64((1.0 - bucket.tokens) * 60.0 / limit);
+        Err(AppError::RateLimited {
+            message: format!("'{}' exceeded {} requests per minute", name, limit),
+            retry_after: Some(wait.max(Duration::from_secs(1))),
+        })
+    }
+}
+
+/// The `?type=` of a request, where the route takes one
+#[derive(Deserialize)]
+struct TypeQuery {
+    #[serde(default, rename = "type")]
+    repo_type: RepoType,
+}
+
+/// Middleware authenticating the request and checking the repository named
+/// by the path; the [`Grant`] is passed on as an extension
+pub async fn require_auth(
+    State(auth): State<Authenticator>,
+    params: Option<RawPathParams>,
+    mut request: Request,
+    next: Next,
+) -> Result<Response, AppError> {
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|path| path.as_str().to_string());
+    if route
+        .as_deref()
+        .is_some_and(|route| OPEN_ROUTES.contains(&route))
+    {
+        return Ok(next.run(request).await);
+    }
+    let credentials = Credentials {
+        method: request.method(),
+        uri: request.uri(),
+        headers: request.headers(),
+        client_cert: request
+            .extensions()
+            .get::<crate::tls::ClientIdentity>()
+            .map(|identity| &*identity.0),
+    };
+    let grant = auth
+        .authenticate(&credentials)
+        .await
+        .inspect_err(|e| warn!("Refused {}: {}", request.uri().path(), e.message()))?;
+
+    let param = |name: &str| {
+        params
+            .iter()
+            .flatten()
+            .find(|(key, _)| *key == name)
+            .map(|(_, value)| value)
+    };
+    if let (Some(owner), Some(repo)) = (param("owner"), param("repo")) {
+        // Typed downloads put the repository type where the owner would be
+        let raw_rest = request
+            .uri()
+            .path()
+            .splitn(4, '/')
+            .nth(3)
+            .unwrap_or_default();
+        let typed = match route.as_deref() {
+            Some(ROUTE_DOWNLOAD) => RepoRef::from_typed_path(owner, raw_rest),
+            _ => None,
+        };
+        let mut repo = match typed {
+            Some(typed) => typed?.0,
+            None => RepoRef::model(owner.to_string(), repo.to_string()),
+        };
+        // Uploads take the repository type from the query
+        if route.as_deref() == Some(ROUTE_UPLOAD) {
+            if let Ok(Query(query)) = Query::<TypeQuery>::try_from_uri(request.uri()) {
+                repo.repo_type = query.repo_type;
+            }
+        }
+        grant.check(&repo)?;
+    }
+
+    request.extensions_mut().insert(grant);
+    Ok(next.run(request).await)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::Body;
+    use axum::http::StatusCode;
+    use axum::routing::{any, get};
+    use axum::Router;
+    use tower::ServiceExt;
+
+    /// Grants every request the same grant
+    struct Granting(Grant);
+
+    #[async_trait]
+    impl AuthProvider for Granting {
+        fn name(&self) -> &'static str {
+            "granting"
+        }
+
+        fn expects(&self) -> &'static str {
+            "nothing"
+        }
+
+        async fn authenticate(&self, _: &Credentials<'_>) -> Result<Option<Grant>, AppError> {
+            Ok(Some(self.0.clone()))
+        }
+    }
+
+    fn scoped(name: &str, repos: &[&str]) -> Grant {
+        Restrictions {
+            repos: repos.iter().map(|repo| repo.to_string()).collect(),
+            ..Restrictions::default()
+        }
+        .grant(name)
+        .unwrap()
+    }
+
+    fn authenticator(providers: Vec<Box<dyn AuthProvider>>) -> Authenticator {
+        Authenticator {
+            providers: providers.into(),
+            buckets: Arc::default(),
+            tenants: Tenants::from_env(),
+            logins: login::Logins::from_env(),
+        }
+    }
+
+    /// The routes of the proxy the middleware tells apart, answering 200
+    fn app(auth: Authenticator) -> Router {
+        let app = OPEN_ROUTES.iter().fold(Router::new(), |app, route| {
+            app.route(route, any(|| async {}))
+        });
+        app.route(ROUTE_DOWNLOAD, get(|| async {}))
+            .route(ROUTE_UPLOAD, any(|| async {}))
+            .route("/list/:owner/:repo", get(|| async {}))
+            .route("/download-archive/:owner/:repo", get(|| async {}))
+            .route_layer(axum::middleware::from_fn(crate::logging::debug))
+            .route_layer(axum::middleware::from_fn_with_state(auth, require_auth))
+    }
+
+    async fn status(app: &Router, method: Method, uri: &str) -> StatusCode {
+        let request = Request::builder()
+            .method(method)
+            .uri(uri)
+            .body(Body::empty())
+            .unwrap();
+        app.clone().oneshot(request).await.unwrap().status()
+    }
+
+    #[tokio::test]
+    async fn uploads_are_checked_against_their_repository_type() {
+        let model = app(authenticator(vec![Box::new(Granting(scoped(
+            "model",
+            &["org/x"],
+        )))]));
+        assert_eq!(
+            status(&model, Method::PUT, "/upload/org/x/f.bin").await,
+            StatusCode::OK
+        );
+        for repo_type in ["dataset", "space"] {
+            let uri = format!("/upload/org/x/f.bin?type={}", repo_type);
+            assert_eq!(
+                status(&model, Method::PUT, &uri).await,
+                StatusCode::FORBIDDEN
+            );
+            assert_eq!(
+                status(&model, Method::POST, &uri).await,
+                StatusCode::FORBIDDEN
+            );
+        }
+
+        let dataset = app(authenticator(vec![Box::new(Granting(scoped(
+            "dataset",
+            &["datasets/org/x"],
+        )))]));
+        assert_eq!(
+            status(&dataset, Method::PUT, "/upload/org/x/f.bin?type=dataset").await,
+            StatusCode::OK
+        );
+        assert_eq!(
+            status(&dataset, Method::PUT, "/upload/org/x/f.bin").await,
+            StatusCode::FORBIDDEN
+        );
+    }
+
+    /// `X-API-Key` keys: `pk_org` reaching the repositories of `org`,
+    /// `pk_admin` an operator's
+    fn api_keys(dir: &tempfile::TempDir) -> keys::StaticKeys {
+        let file = dir.path().join("keys.json");
+        let keys = r#"{ "org": { "key": "pk_org", "owners": ["org"] } }"#;
+        std::fs::write(&file, keys).unwrap();
+        keys::StaticKeys::new(
+            vec!["pk_admin".to_string()],
+            Some(file.display().to_string()),
+        )
+        .unwrap()
+    }
+
+    async fn status_with(app: &Router, uri: &str, headers: &[(&str, &str)]) -> StatusCode {
+        let mut request = Request::builder().uri(uri);
+        for (name, value) in headers {
+            request = request.header(*name, *value);
+        }
+        let request = request.body(Body::empty()).unwrap();
+        app.clone().oneshot(request).await.unwrap().status()
+    }
+
+    #[tokio::test]
+    async fn archives_are_checked_against_their_repository() {
+        let app = app(authenticator(vec![Box::new(Granting(scoped(
+            "model",
+            &["org/x"],
+        )))]));
+        assert_eq!(
+            status(&app, Method::GET, "/download-archive/org/x?prefix=model-").await,
+            StatusCode::OK
+        );
+        for uri in [
+            "/download-archive/org/y",
+            "/download-archive/other/x?prefix=model-",
+        ] {
+            assert_eq!(
+                status(&app, Method::GET, uri).await,
+                StatusCode::FORBIDDEN,
+                "{}",
+                uri
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn only_admins_may_debug_a_request() {
+        let dir = tempfile::tempdir().unwrap();
+        let app = app(authenticator(vec![Box::new(api_keys(&dir))]));
+        let list = "/list/org/x";
+        assert_eq!(
+            status_with(&app, list, &[("x-api-key", "pk_org"), ("x-debug", "true")]).await,
+            StatusCode::FORBIDDEN
+        );
+        assert_eq!(
+            status_with(&app, list, &[("x-api-key", "pk_org")]).await,
+            StatusCode::OK
+        );
+        assert_eq!(
+            status_with(
+                &app,
+                list,
+                &[("x-api-key", "pk_admin"), ("x-debug", "true")]
+            )
+            .await,
+            StatusCode::OK
+        );
+    }
+
+    #[tokio::test]
+    async fn invalid_api_key_does_not_fall_through() {
+        let dir = tempfile::tempdir().unwrap();
+        // A provider after the keys would let anyone in
+        let app = app(authenticator(vec![
+            Box::new(api_keys(&dir)),
+            Box::new(Granting(Grant::unrestricted("anyone"))),
+        ]));
+        let list = "/list/other/x";
+        assert_eq!(
+            status_with(&app, list, &[("x-api-key", "pk_wrong")]).await,
+            StatusCode::UNAUTHORIZED
+        );
+        // A key the first provider knows decides too, even when refused
+        assert_eq!(
+            status_with(&app, list, &[("x-api-key", "pk_org")]).await,
+            StatusCode::FORBIDDEN
+        );
+        assert_eq!(
+            status_with(&app, "/list/org/x", &[("x-api-key", "pk_org")]).await,
+            StatusCode::OK
+        );
+        assert_eq!(
+            status_with(&app, list, &[("x-api-key", "pk_admin")]).await,
+            StatusCode::OK
+        );
+        // Without a key, the next provider decides
+        assert_eq!(status_with(&app, list, &[]).await, StatusCode::OK);
+
+        let keys_only = self::app(authenticator(vec![Box::new(api_keys(&dir))]));
+        assert_eq!(
+            status_with(&keys_only, list, &[]).await,
+            StatusCode::UNAUTHORIZED
+        );
+    }
+
+    #[tokio::test]
+    async fn open_routes_need_no_credentials() {
+        let dir = tempfile::tempdir().unwrap();
+        let app = app(authenticator(vec![Box::new(api_keys(&dir))]));
+        for route in OPEN_ROUTES {
+            assert_eq!(
+                status_with(&app, route, &[]).await,
+                StatusCode::OK,
+                "{}",
+                route
+            );
+            // Not even a wrong key is looked at
+            assert_eq!(
+                status_with(&app, route, &[("x-api-key", "pk_wrong")]).await,
+                StatusCode::OK,
+                "{}",
+                route
+            );
+        }
+        for uri in [
+            "/list/org/x",
+            "/download/org/x/f.bin",
+            "/upload/org/x/f.bin",
+        ] {
+            assert_eq!(
+                status_with(&app, uri, &[]).await,
+                StatusCode::UNAUTHORIZED,
+                "{}",
+                uri
+            );
+        }
+    }
+
+    #[test]
+    fn grant_checks_type_owner_and_name() {
+        let grant = Restrictions {
+            owners: vec!["org".to_string()],
+            repos: vec![
+                "datasets/other/evals".to_string(),
+                "other/model".to_string(),
+            ],
+            ..Restrictions::default()
+        }
+        .grant("team")
+        .unwrap();
+        let check = |spec: &str| grant.check(&RepoRef::parse(spec, None).unwrap()).is_ok();
+
+        // Every repository of an owner, whatever its type
+        assert!(check("org/x"));
+        assert!(check("datasets/org/x"));
+        assert!(check("spaces/org/x"));
+        // Listed repositories, of their type only
+        assert!(check("datasets/other/evals"));
+        assert!(check("other/model"));
+        assert!(!check("other/evals"));
+        assert!(!check("spaces/other/evals"));
+        assert!(!check("datasets/other/model"));
+        assert!(!check("datasets/other/evals2"));
+        assert!(!check("datasets/another/evals"));
+        assert!(!check("organization/x"));
+        assert!(matches!(
+            grant.check(&RepoRef::parse("other/x", None).unwrap()),
+            Err(AppError::Forbidden(_))
+        ));
+
+        let unrestricted = Grant::unrestricted("anyone");
+        assert!(unrestricted
+            .check(&RepoRef::parse("spaces/any/thing", None).unwrap())
+            .is_ok());
+        assert!(unrestricted.check_admin().is_err());
+        assert!(Grant::admin("ops").check_admin().is_ok());
+        assert!(grant.check_admin().is_err());
+    }
+}
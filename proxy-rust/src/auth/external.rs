@@ -0,0 +1,129 @@
+//! `external`: an HTTP authorizer
+//!
+//! Every request is described to `AUTH_URL` in a JSON `POST`:
+//!
+//! ```json
+//! {"method": "GET", "path": "/download/owner/repo/model.gguf", "query": "", "headers": {"cookie": "..."}}
+//! ```
+//!
+//! Headers are passed on except `Authorization` and `X-HF-Token`, which
+//! carry the HuggingFace token. A 2xx answer authenticates the client; its
+//! body may name it and restrict it (`{"name": "...", "owners": [...],
+//! "repos": [...], "requests_per_minute": 60}`, all optional). 401 means
+//! the authorizer doesn't recognize the request, so the next provider is
+//! tried; 403 refuses it. Anything else, or no answer within
+//! `AUTH_URL_TIMEOUT_MS` (default 2000), is answered with 503.
+
+use super::{AuthProvider, Credentials, Grant, Restrictions};
+use crate::AppError;
+use async_trait::async_trait;
+use reqwest::StatusCode;
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+use std::time::Duration;
+
+/// Headers never sent to the authorizer
+const WITHHELD: [&str; 2] = ["authorization", "x-hf-token"];
+
+#[derive(Default, Deserialize)]
+#[serde(default)]
+struct Decision {
+    name: Option<String>,
+    owners: Vec<String>,
+    repos: Vec<String>,
+    requests_per_minute: Option<u32>,
+    admin: bool,
+}
+
+pub struct External {
+    url: String,
+    http: reqwest::Client,
+}
+
+impl External {
+    /// Load `AUTH_URL` and `AUTH_URL_TIMEOUT_MS`; `None` without a URL
+    pub fn from_env() -> Option<Self> {
+        let url = std::env::var("AUTH_URL").ok()?;
+        assert!(
+            url.starts_with("http://") || url.starts_with("https://"),
+            "AUTH_URL must be an http:// or https:// URL"
+        );
+        let millis = crate::config::positive("AUTH_URL_TIMEOUT_MS").unwrap_or(2000);
+        let http = reqwest::Client::builder()
+            .timeout(Duration::from_millis(millis))
+            .user_agent(concat!("xet-proxy/", env!("CARGO_PKG_VERSION")))
+            .build()
+            .expect("Failed to build HTTP client");
+        Some(Self { url, http })
+    }
+}
+
+#[async_trait]
+impl AuthProvider for External {
+    fn name(&self) -> &'static str {
+        "external"
+    }
+
+    fn expects(&self) -> &'static str {
+        "credentials the authorizer accepts"
+    }
+
+    async fn authenticate(&self, credentials: &Credentials<'_>) -> Result<Option<Grant>, AppError> {
+        let mut headers = Map::new();
+        for (name, value) in credentials.headers {
+            if WITHHELD.contains(&name.as_str()) {
+                continue;
+            }
+            if let Ok(value) = value.to_str() {
+                headers.insert(name.to_string(), Value::from(value));
+            }
+        }
+        let summary = json!({
+            "method": credentials.method.as_str(),
+            "path": credentials.uri.path(),
+            "query": credentials.uri.query().unwrap_or_default(),
+            "headers": headers,
+        });
+        let unavailable = |what: String| AppError::Unavailable {
+            message: format!("Authorizer {}", what),
+            retry_after: None,
+        };
+        let response = self
+            .http
+            .post(&self.url)
+            .json(&summary)
+            .send()
+            .await
+            .map_err(|e| unavailable(format!("unreachable: {}", e)))?;
+        match response.status() {
+            status if status.is_success() => {
+                let body = response
+                    .bytes()
+                    .await
+                    .map_err(|e| unavailable(format!("answer unreadable: {}", e)))?;
+                let decision: Decision = if body.iter().all(u8::is_ascii_whitespace) {
+                    Decision::default()
+                } else {
+                    serde_json::from_slice(&body)
+                        .map_err(|e| unavailable(format!("answered an invalid decision: {}", e)))?
+                };
+                let name = decision.name.unwrap_or_else(|| "external".to_string());
+                let restrictions = Restrictions {
+                    owners: decision.owners,
+                    repos: decision.repos,
+                    requests_per_minute: decision.requests_per_minute,
+                    admin: decision.admin,
+                };
+                restrictions
+                    .grant(&name)
+                    .map(Some)
+                    .map_err(|e| unavailable(format!("answered an invalid decision: {}", e)))
+            }
+            StatusCode::UNAUTHORIZED => Ok(None),
+            StatusCode::FORBIDDEN => {
+                Err(AppError::Forbidden("Refused by the authorizer".to_string()))
+            }
+            status => Err(unavailable(format!("answered HTTP {}", status.as_u16()))),
+        }
+    }
+}
@@ -0,0 +1,335 @@
+//! `jwt`: signed bearer tokens
+//!
+//! Clients send `X-Proxy-Authorization: Bearer <token>`. Tokens are checked
+//! against `JWT_SECRET` (HMAC) or the PEM public key in
+//! `JWT_PUBLIC_KEY_FILE` (RSA, EC or Ed25519), with `JWT_ALGORITHM`
+//! (default `HS256` with a secret, `RS256` with a key file). They must not
+//! be expired and, when `JWT_ISSUER` and `JWT_AUDIENCE` are set, must carry
+//! that `iss` and `aud`. The `sub` claim names the client; optional
+//! `owners`, `repos` and `requests_per_minute` claims restrict it like an
+//! API key, and `admin: true` lets it use the admin endpoints. `SIGHUP` rereads the key file, for key rotation.
+
+use super::{AuthProvider, Credentials, Grant, Restrictions};
+use crate::AppError;
+use async_trait::async_trait;
+use jsonwebtoken::{Algorithm, DecodingKey, Validation};
+use serde::Deserialize;
+use std::str::FromStr;
+use std::sync::RwLock;
+use tracing::{error, info};
+
+const HEADER: &str = "x-proxy-authorization";
+
+#[derive(Deserialize)]
+struct Claims {
+    sub: String,
+    #[serde(default)]
+    owners: Vec<String>,
+    #[serde(default)]
+    repos: Vec<String>,
+    requests_per_minute: Option<u32>,
+    #[serde(default)]
+    admin: bool,
+}
+
+pub struct Jwt {
+    key_file: Option<String>,
+    algorithm: Algorithm,
+    key: RwLock<DecodingKey>,
+    validation: Validation,
+}
+
+impl Jwt {
+    /// Load `JWT_SECRET` or `JWT_PUBLIC_KEY_FILE`, `JWT_ALGORITHM`,
+    /// `JWT_ISSUER` and `JWT_AUDIENCE`; `None` without a secret or key
+    pub fn from_env() -> Option<Self> {
+        let secret = std::env::var("JWT_SECRET").ok();
+        let key_file = std::env::var("JWT_PUBLIC_KEY_FILE").ok();
+        let default = match (&secret, &key_file) {
+            (None, None) => return None,
+            (Some(_), Some(_)) => panic!("Set either JWT_SECRET or JWT_PUBLIC_KEY_FILE, not both"),
+            (Some(_), None) => Algorithm::HS256,
+            (None, Some(_)) => Algorithm::RS256,
+        };
+        let algorithm = std::env::var("JWT_ALGORITHM").map_or(default, |v| {
+            Algorithm::from_str(&v)
+                .unwrap_or_else(|_| panic!("JWT_ALGORITHM '{}' is not a JWT algorithm", v))
+        });
+        let hmac = matches!(
+            algorithm,
+            Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512
+        );
+        assert!(
+            hmac == secret.is_some(),
+            "JWT_ALGORITHM {:?} needs {}",
+            algorithm,
+            if hmac {
+                "JWT_SECRET"
+            } else {
+                "JWT_PUBLIC_KEY_FILE"
+            }
+        );
+        let key = match &secret {
+            Some(secret) => {
+                assert!(!secret.is_empty(), "JWT_SECRET must not be empty");
+                DecodingKey::from_secret(secret.as_bytes())
+            }
+            None => load_key(key_file.as_deref().unwrap_or_default(), algorithm)
+                .unwrap_or_else(|e| panic!("{}", e)),
+        };
+
+        let validation = validation(
+            algorithm,
+            std::env::var("JWT_ISSUER").ok(),
+            std::env::var("JWT_AUDIENCE").ok(),
+        );
+        Some(Self {
+            key_file,
+            algorithm,
+            key: RwLock::new(key),
+            validation,
+        })
+    }
+}
+
+/// Checks of `algorithm` tokens, which must be unexpired and carry `issuer`
+/// and `audience` if set
+fn validation(
+    algorithm: Algorithm,
+    issuer: Option<String>,
+    audience: Option<String>,
+) -> Validation {
+    let mut validation = Validation::new(algorithm);
+    // The claims are only compared when present: required, they must be
+    let mut required = vec!["exp"];
+    if let Some(issuer) = issuer {
+        validation.set_issuer(&[issuer]);
+        required.push("iss");
+    }
+    match audience {
+        Some(audience) => {
+            validation.set_audience(&[audience]);
+            required.push("aud");
+        }
+        None => validation.validate_aud = false,
+    }
+    validation.set_required_spec_claims(&required);
+    validation
+}
+
+#[async_trait]
+impl AuthProvider for Jwt {
+    fn name(&self) -> &'static str {
+        "jwt"
+    }
+
+    fn expects(&self) -> &'static str {
+        "a JWT in X-Proxy-Authorization"
+    }
+
+    async fn authenticate(&self, credentials: &Credentials<'_>) -> Result<Option<Grant>, AppError> {
+        let Some(value) = credentials.header(HEADER) else {
+            return Ok(None);
+        };
+        let token = value.strip_prefix("Bearer ").ok_or_else(|| {
+            AppError::Unauthorized(format!("{} must be 'Bearer <token>'", HEADER))
+        })?;
+        let claims = jsonwebtoken::decode::<Claims>(
+            token.trim(),
+            &self.key.read().unwrap(),
+            &self.validation,
+        )
+        .map_err(|e| AppError::Unauthorized(format!("Invalid JWT: {}", e)))?
+        .claims;
+        let restrictions = Restrictions {
+            owners: claims.owners,
+            repos: claims.repos,
+            requests_per_minute: claims.requests_per_minute,
+            admin: claims.admin,
+        };
+        restrictions
+            .grant(&format!("jwt:{}", claims.sub))
+            .map(Some)
+            .map_err(|e| AppError::Unauthorized(format!("Invalid JWT claims: {}", e)))
+    }
+
+    fn reload(&self) {
+        let Some(path) = &self.key_file else {
+            return;
+        };
+        match load_key(path, self.algorithm) {
+            Ok(key) => {
+                info!("Reloaded JWT_PUBLIC_KEY_FILE {}", path);
+                *self.key.write().unwrap() = key;
+            }
+            Err(e) => error!("Keeping the previous JWT key: {}", e),
+        }
+    }
+}
+
+fn load_key(path: &str, algorithm: Algorithm) -> Result<DecodingKey, String> {
+    let pem = std::fs::read(path)
+        .map_err(|e| format!("Failed to read JWT_PUBLIC_KEY_FILE {}: {}", path, e))?;
+    let key = match algorithm {
+        Algorithm::ES256 | Algorithm::ES384 => DecodingKey::from_ec_pem(&pem),
+        Algorithm::EdDSA => DecodingKey::from_ed_pem(&pem),
+        _ => DecodingKey::from_rsa_pem(&pem),
+    };
+    key.map_err(|e| format!("Invalid JWT_PUBLIC_KEY_FILE {}: {}", path, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::{HeaderMap, HeaderValue, Method, Uri};
+    use jsonwebtoken::{EncodingKey, Header};
+    use serde_json::{json, Value};
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    const SECRET: &[u8] = b"jwt secret";
+
+    /// HS256 tokens of `SECRET`, for the proxy's issuer and audience
+    fn jwt() -> Jwt {
+        Jwt {
+            key_file: None,
+            algorithm: Algorithm::HS256,
+            key: RwLock::new(DecodingKey::from_secret(SECRET)),
+            validation: validation(
+                Algorithm::HS256,
+                Some("issuer".to_string()),
+                Some("xet-proxy".to_string()),
+            ),
+        }
+    }
+
+    fn now() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+    }
+
+    /// Claims the proxy takes, with `changes` on top; a `null` drops one
+    fn claims(changes: Value) -> Value {
+        let mut claims = json!({
+            "sub": "ci",
+            "iss": "issuer",
+            "aud": "xet-proxy",
+            "exp": now() + 600,
+            "repos": ["datasets/org/evals"],
+        });
+        for (name, value) in changes.as_object().unwrap() {
+            match value {
+                Value::Null => claims.as_object_mut().unwrap().remove(name),
+                value => claims
+                    .as_object_mut()
+                    .unwrap()
+                    .insert(name.clone(), value.clone()),
+            };
+        }
+        claims
+    }
+
+    fn token(algorithm: Algorithm, claims: &Value) -> String {
+        jsonwebtoken::encode(
+            &Header::new(algorithm),
+            claims,
+            &EncodingKey::from_secret(SECRET),
+        )
+        .unwrap()
+    }
+
+    async fn authenticate(value: &str) -> Result<Option<Grant>, AppError> {
+        let mut headers = HeaderMap::new();
+        headers.insert(HEADER, HeaderValue::from_str(value).unwrap());
+        let credentials = Credentials {
+            method: &Method::GET,
+            uri: &Uri::from_static("/list/org/evals"),
+            headers: &headers,
+            client_cert: None,
+        };
+        jwt().authenticate(&credentials).await
+    }
+
+    async fn refused(value: &str) -> bool {
+        matches!(authenticate(value).await, Err(AppError::Unauthorized(_)))
+    }
+
+    #[tokio::test]
+    async fn valid_token_grants_its_claims() {
+        let bearer = format!("Bearer {}", token(Algorithm::HS256, &claims(json!({}))));
+        let grant = authenticate(&bearer).await.unwrap().unwrap();
+        assert_eq!(&*grant.name, "jwt:ci");
+        let evals = crate::repo::RepoRef::parse("datasets/org/evals", None).unwrap();
+        assert!(grant.check(&evals).is_ok());
+        let model = crate::repo::RepoRef::parse("org/evals", None).unwrap();
+        assert!(grant.check(&model).is_err());
+        assert!(grant.check_admin().is_err());
+
+        let none = Credentials {
+            method: &Method::GET,
+            uri: &Uri::from_static("/"),
+            headers: &HeaderMap::new(),
+            client_cert: None,
+        };
+        assert!(jwt().authenticate(&none).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn other_algorithms_are_refused() {
+        for algorithm in [Algorithm::HS384, Algorithm::HS512] {
+            let bearer = format!("Bearer {}", token(algorithm, &claims(json!({}))));
+            assert!(refused(&bearer).await, "{:?}", algorithm);
+        }
+        // Unsigned, as `alg: none`
+        let signed = token(Algorithm::HS256, &claims(json!({})));
+        let payload = signed.split('.').nth(1).unwrap();
+        assert!(refused(&format!("Bearer eyJhbGciOiJub25lIn0.{}.", payload)).await);
+        // Signed with another secret
+        let forged = jsonwebtoken::encode(
+            &Header::new(Algorithm::HS256),
+            &claims(json!({})),
+            &EncodingKey::from_secret(b"another secret"),
+        )
+        .unwrap();
+        assert!(refused(&format!("Bearer {}", forged)).await);
+    }
+
+    #[tokio::test]
+    async fn expired_or_misaddressed_tokens_are_refused() {
+        let refusals = [
+            json!({ "exp": now() - 3600 }),
+            json!({ "exp": null }),
+            json!({ "aud": "another-service" }),
+            json!({ "aud": null }),
+            json!({ "iss": "another-issuer" }),
+            json!({ "iss": null }),
+            json!({ "sub": null }),
+            json!({ "repos": ["not a repository"] }),
+            json!({ "requests_per_minute": 0 }),
+        ];
+        for changes in refusals {
+            let bearer = format!(
+                "Bearer {}",
+                token(Algorithm::HS256, &claims(changes.clone()))
+            );
+            assert!(refused(&bearer).await, "{}", changes);
+        }
+        let token = token(Algorithm::HS256, &claims(json!({})));
+        assert!(refused(&format!("Basic {}", token)).await);
+        assert!(refused("Bearer not.a.token").await);
+    }
+
+    #[test]
+    fn issuer_and_audience_are_only_required_when_set() {
+        let lenient = validation(Algorithm::HS256, None, None);
+        let token = token(
+            Algorithm::HS256,
+            &claims(json!({ "iss": null, "aud": null })),
+        );
+        let key = DecodingKey::from_secret(SECRET);
+        assert!(jsonwebtoken::decode::<Claims>(&token, &key, &lenient).is_ok());
+        let strict = validation(Algorithm::HS256, Some("issuer".into()), None);
+        assert!(jsonwebtoken::decode::<Claims>(&token, &key, &strict).is_err());
+    }
+}
@@ -0,0 +1,156 @@
+//! `api_keys`: static API keys
+//!
+//! `API_KEYS` is a comma-separated list of unrestricted keys.
+//! `API_KEYS_FILE` names a JSON file of named keys:
+//!
+//! ```json
+//! {
+//!   "team-a": { "key": "pk_...", "owners": ["org"], "repos": ["datasets/other/evals"] },
+//!   "ci":     { "key": "pk_...", "requests_per_minute": 120 },
+//!   "ops":    { "key": "pk_...", "admin": true }
+//! }
+//! ```
+//!
+//! Clients present a key in `X-API-Key`; an unknown key is refused. Keys of
+//! `API_KEYS` are admins.
+
+use super::{AuthProvider, Credentials, Grant, Restrictions};
+use crate::AppError;
+use async_trait::async_trait;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::sync::RwLock;
+use tracing::{error, info};
+
+pub const API_KEY_HEADER: &str = "x-api-key";
+
+/// One key as written in `API_KEYS_FILE`
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct KeySpec {
+    key: String,
+    #[serde(default)]
+    owners: Vec<String>,
+    #[serde(default)]
+    repos: Vec<String>,
+    requests_per_minute: Option<u32>,
+    #[serde(default)]
+    admin: bool,
+}
+
+/// Configured keys, reloadable
+pub struct StaticKeys {
+    file: Option<String>,
+    static_keys: Vec<String>,
+    keys: RwLock<HashMap<String, Grant>>,
+}
+
+impl StaticKeys {
+    /// Load `API_KEYS` and `API_KEYS_FILE`; `None` if neither is set
+    pub fn from_env() -> Option<Self> {
+        let static_keys: Vec<String> = std::env::var("API_KEYS")
+            .ok()
+            .map(|keys| {
+                keys.split(',')
+                    .map(str::trim)
+                    .filter(|k| !k.is_empty())
+                    .map(String::from)
+                    .collect()
+            })
+            .unwrap_or_default();
+        let file = std::env::var("API_KEYS_FILE").ok();
+        if static_keys.is_empty() && file.is_none() {
+            assert!(
+                std::env::var("API_KEYS").is_err(),
+                "API_KEYS must list at least one key"
+            );
+            return None;
+        }
+        let keys = Self::new(static_keys, file).unwrap_or_else(|e| panic!("{}", e));
+        info!("Loaded {} API keys", keys.keys.read().unwrap().len());
+        Some(keys)
+    }
+
+    /// The unrestricted `static_keys` and the keys of `file`
+    pub(super) fn new(static_keys: Vec<String>, file: Option<String>) -> Result<Self, String> {
+        let keys = load(&static_keys, file.as_deref())?;
+        Ok(Self {
+            file,
+            static_keys,
+            keys: RwLock::new(keys),
+        })
+    }
+}
+
+#[async_trait]
+impl AuthProvider for StaticKeys {
+    fn name(&self) -> &'static str {
+        "api_keys"
+    }
+
+    fn expects(&self) -> &'static str {
+        "an API key in X-API-Key"
+    }
+
+    async fn authenticate(&self, credentials: &Credentials<'_>) -> Result<Option<Grant>, AppError> {
+        let Some(key) = credentials.header(API_KEY_HEADER) else {
+            return Ok(None);
+        };
+        self.keys
+            .read()
+            .unwrap()
+            .get(key)
+            .cloned()
+            .map(Some)
+            .ok_or_else(|| AppError::Unauthorized("Invalid API key".to_string()))
+    }
+
+    fn reload(&self) {
+        if self.file.is_none() {
+            return;
+        }
+        match load(&self.static_keys, self.file.as_deref()) {
+            Ok(keys) => {
+                info!("Reloaded {} API keys", keys.len());
+                *self.keys.write().unwrap() = keys;
+            }
+            Err(e) => error!("Keeping the previous API keys: {}", e),
+        }
+    }
+}
+
+/// Keys by secret: the static ones, then those of `file`
+fn load(static_keys: &[String], file: Option<&str>) -> Result<HashMap<String, Grant>, String> {
+    let mut keys: HashMap<String, Grant> = static_keys
+        .iter()
+        .enumerate()
+        .map(|(i, key)| {
+            // Operator keys, so they are admins too
+            (key.clone(), Grant::admin(&format!("API_KEYS[{}]", i)))
+        })
+        .collect();
+    let Some(path) = file else {
+        return Ok(keys);
+    };
+    let text = std::fs::read_to_string(path)
+        .map_err(|e| format!("Failed to read API_KEYS_FILE {}: {}", path, e))?;
+    let specs: HashMap<String, KeySpec> = serde_json::from_str(&text)
+        .map_err(|e| format!("Invalid API_KEYS_FILE {}: {}", path, e))?;
+    for (name, spec) in specs {
+        let invalid = |what: &str| format!("API key '{}': {}", name, what);
+        if spec.key.trim().is_empty() || spec.key.contains(char::is_whitespace) {
+            return Err(invalid("key must be non-empty and contain no whitespace"));
+        }
+        let restrictions = Restrictions {
+            owners: spec.owners,
+            repos: spec.repos,
+            requests_per_minute: spec.requests_per_minute,
+            admin: spec.admin,
+        };
+        let grant = restrictions.grant(&name).map_err(|e| invalid(&e))?;
+        if keys.insert(spec.key, grant).is_some() {
+            return Err(invalid("key is used more than once"));
+        }
+    }
+    Ok(keys)
+}
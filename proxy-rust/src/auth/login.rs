@@ -0,0 +1,133 @@
+//! Browser logins, so download links work without a key in the URL
+//!
+//! A browser can't put an API key in `X-API-Key` when following a link.
+//! `POST /login` with the key (in `X-API-Key`, any credentials the providers
+//! recognize, or an `api_key` form field) sets a `xet_proxy_login` cookie
+//! standing for the grant they earned, for `LOGIN_TTL_SECS` (default 1800).
+//! The cookie authenticates `GET` and `HEAD` requests only, so another site
+//! can't make the browser change anything with it. `POST /logout` ends the
+//! login; a `SIGHUP` ends them all, so a revoked key's logins don't outlive
+//! it.
+
+use super::Grant;
+use axum::http::HeaderMap;
+use ring::rand::{SecureRandom, SystemRandom};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::info;
+
+pub const LOGIN_COOKIE: &str = "xet_proxy_login";
+/// Logins kept at most; the oldest goes first
+const MAX_LOGINS: usize = 10_000;
+
+struct Login {
+    grant: Grant,
+    expires: Instant,
+}
+
+/// Live logins, by token
+#[derive(Clone)]
+pub struct Logins {
+    ttl: Duration,
+    logins: Arc<Mutex<HashMap<String, Login>>>,
+}
+
+impl Logins {
+    /// Load `LOGIN_TTL_SECS`
+    pub fn from_env() -> Self {
+        let secs = crate::config::positive("LOGIN_TTL_SECS").unwrap_or(1800);
+        Self {
+            ttl: Duration::from_secs(secs),
+            logins: Arc::default(),
+        }
+    }
+
+    pub fn ttl(&self) -> Duration {
+        self.ttl
+    }
+
+    /// A new login for `grant`, as its token
+    pub fn start(&self, grant: Grant) -> String {
+        let token = token();
+        let now = Instant::now();
+        let mut logins = self.logins.lock().unwrap();
+        logins.retain(|_, login| login.expires > now);
+        if logins.len() >= MAX_LOGINS {
+            let oldest = logins
+                .iter()
+                .min_by_key(|(_, login)| login.expires)
+                .map(|(token, _)| token.clone());
+            if let Some(oldest) = oldest {
+                logins.remove(&oldest);
+            }
+        }
+        info!("'{}' logged in for {}s", grant.name, self.ttl.as_secs());
+        logins.insert(
+            token.clone(),
+            Login {
+                grant,
+                expires: now + self.ttl,
+            },
+        );
+        token
+    }
+
+    /// The grant of the login cookie in `headers`, while it lasts
+    pub fn grant(&self, headers: &HeaderMap) -> Option<Grant> {
+        let token = cookie(headers)?;
+        let mut logins = self.logins.lock().unwrap();
+        match logins.get(token) {
+            Some(login) if login.expires > Instant::now() => Some(login.grant.clone()),
+            Some(_) => {
+                logins.remove(token);
+                None
+            }
+            None => None,
+        }
+    }
+
+    /// End the login of the cookie in `headers`, if any
+    pub fn end(&self, headers: &HeaderMap) {
+        if let Some(token) = cookie(headers) {
+            self.logins.lock().unwrap().remove(token);
+        }
+    }
+
+    /// End every login
+    pub fn clear(&self) {
+        self.logins.lock().unwrap().clear();
+    }
+}
+
+/// The login token among the request's cookies
+fn cookie(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get_all(axum::http::header::COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .filter_map(|pair| pair.trim().split_once('='))
+        .find(|(name, _)| *name == LOGIN_COOKIE)
+        .map(|(_, token)| token)
+}
+
+/// Unguessable login token
+fn token() -> String {
+    let mut bytes = [0u8; 32];
+    SystemRandom::new()
+        .fill(&mut bytes)
+        .expect("The system random generator works");
+    hex::encode(bytes)
+}
+
+/// `Set-Cookie` value for `token`, good for `max_age`; `secure` over HTTPS
+pub fn set_cookie(token: &str, max_age: Duration, secure: bool) -> String {
+    format!(
+        "{}={}; Path=/; Max-Age={}; HttpOnly; SameSite=Lax{}",
+        LOGIN_COOKIE,
+        token,
+        max_age.as_secs(),
+        if secure { "; Secure" } else { "" }
+    )
+}
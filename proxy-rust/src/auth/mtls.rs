@@ -0,0 +1,117 @@
+//! `mtls`: client certificate identities
+//!
+//! The identity of a client is the subject of its certificate. With
+//! `TLS_CLIENT_CA_FILE`, the proxy's own HTTPS listener verifies it (see
+//! [`crate::tls`]). Otherwise the front proxy terminating TLS verifies the
+//! client certificate and passes its identity (typically the subject DN)
+//! in `MTLS_IDENTITY_HEADER`, e.g. with nginx
+//! `proxy_set_header X-Client-Cert-Subject $ssl_client_s_dn;`. The front
+//! proxy must always set (or clear) the header, or clients could send their
+//! own; a certificate verified by the proxy itself wins over the header.
+//! With `MTLS_IDENTITIES_FILE`, a JSON file of identities and their
+//! restrictions (`owners`, `repos`, `requests_per_minute`, as for API keys),
+//! only listed identities are accepted; without it, every identity is.
+
+use super::{AuthProvider, Credentials, Grant, Restrictions};
+use crate::AppError;
+use async_trait::async_trait;
+use std::collections::HashMap;
+use std::sync::RwLock;
+use tracing::{error, info};
+
+pub struct ClientCert {
+    header: Option<String>,
+    file: Option<String>,
+    /// Accepted identities, when restricted by the file
+    identities: RwLock<Option<HashMap<String, Grant>>>,
+}
+
+impl ClientCert {
+    /// Load `MTLS_IDENTITY_HEADER` and `MTLS_IDENTITIES_FILE`; `None`
+    /// without a header or `TLS_CLIENT_CA_FILE`
+    pub fn from_env() -> Option<Self> {
+        let header = std::env::var("MTLS_IDENTITY_HEADER")
+            .ok()
+            .map(|header| header.trim().to_ascii_lowercase());
+        if header.is_none() && std::env::var("TLS_CLIENT_CA_FILE").is_err() {
+            return None;
+        }
+        if let Some(header) = &header {
+            assert!(
+                axum::http::HeaderName::from_bytes(header.as_bytes()).is_ok(),
+                "MTLS_IDENTITY_HEADER must be a header name"
+            );
+        }
+        let file = std::env::var("MTLS_IDENTITIES_FILE").ok();
+        let identities = file
+            .as_deref()
+            .map(|path| load(path).unwrap_or_else(|e| panic!("{}", e)));
+        if let Some(identities) = &identities {
+            info!("Loaded {} client certificate identities", identities.len());
+        }
+        Some(Self {
+            header,
+            file,
+            identities: RwLock::new(identities),
+        })
+    }
+}
+
+#[async_trait]
+impl AuthProvider for ClientCert {
+    fn name(&self) -> &'static str {
+        "mtls"
+    }
+
+    fn expects(&self) -> &'static str {
+        "a client certificate"
+    }
+
+    async fn authenticate(&self, credentials: &Credentials<'_>) -> Result<Option<Grant>, AppError> {
+        let identity = credentials.client_cert.or_else(|| {
+            let header = self.header.as_deref()?;
+            credentials.header(header).filter(|id| !id.is_empty())
+        });
+        let Some(identity) = identity else {
+            return Ok(None);
+        };
+        match &*self.identities.read().unwrap() {
+            Some(identities) => identities.get(identity).cloned().map(Some).ok_or_else(|| {
+                AppError::Unauthorized(format!("Client certificate '{}' is not allowed", identity))
+            }),
+            None => Ok(Some(Grant::unrestricted(identity))),
+        }
+    }
+
+    fn reload(&self) {
+        let Some(path) = &self.file else {
+            return;
+        };
+        match load(path) {
+            Ok(identities) => {
+                info!(
+                    "Reloaded {} client certificate identities",
+                    identities.len()
+                );
+                *self.identities.write().unwrap() = Some(identities);
+            }
+            Err(e) => error!("Keeping the previous client certificate identities: {}", e),
+        }
+    }
+}
+
+fn load(path: &str) -> Result<HashMap<String, Grant>, String> {
+    let text = std::fs::read_to_string(path)
+        .map_err(|e| format!("Failed to read MTLS_IDENTITIES_FILE {}: {}", path, e))?;
+    let specs: HashMap<String, Restrictions> = serde_json::from_str(&text)
+        .map_err(|e| format!("Invalid MTLS_IDENTITIES_FILE {}: {}", path, e))?;
+    specs
+        .into_iter()
+        .map(|(identity, restrictions)| {
+            let grant = restrictions
+                .grant(&identity)
+                .map_err(|e| format!("Identity '{}': {}", identity, e))?;
+            Ok((identity, grant))
+        })
+        .collect()
+}
@@ -0,0 +1,116 @@
+//! Per-repository upstream rate-limit backoff
+//!
+//! When the Hub or CAS answers 429 for a repository, requests for that
+//! repository are refused locally for a cooldown period that doubles with
+//! each consecutive rate-limited attempt (up to a cap) and resets on the
+//! next success. Other repositories are unaffected, so one hot repo being
+//! throttled never causes a global backoff.
+
+use crate::AppError;
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::warn;
+
+/// Zig failure signature for an upstream 429
+pub const RATE_LIMITED_SIGNATURE: &str = "error: TooManyRequests";
+
+#[derive(Clone, Copy, Debug)]
+struct Cooldown {
+    until: Instant,
+    consecutive: u32,
+}
+
+/// Cooldown tracker keyed by repository
+#[derive(Clone)]
+pub struct UpstreamBackoff {
+    base: Duration,
+    max: Duration,
+    cooldowns: Arc<Mutex<HashMap<String, Cooldown>>>,
+}
+
+impl UpstreamBackoff {
+    pub fn new(base: Duration, max: Duration) -> Self {
+        Self {
+            base,
+            max,
+            cooldowns: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Load `BACKOFF_BASE_SECS` (default 5) and `BACKOFF_MAX_SECS` (default 300)
+    pub fn from_env() -> Self {
+        let secs = |name: &str, default| crate::config::number(name).unwrap_or(default);
+        Self::new(
+            Duration::from_secs(secs("BACKOFF_BASE_SECS", 5)),
+            Duration::from_secs(secs("BACKOFF_MAX_SECS", 300)),
+        )
+    }
+
+    /// Remaining cooldown for `key`, if any
+    pub fn remaining(&self, key: &str) -> Option<Duration> {
+        let cooldowns = self.cooldowns.lock().unwrap();
+        let cooldown = cooldowns.get(key)?;
+        cooldown.until.checked_duration_since(Instant::now())
+    }
+
+    /// Register a 429 for `key` and return the new cooldown
+    pub fn record_rate_limited(&self, key: &str) -> Duration {
+        let mut cooldowns = self.cooldowns.lock().unwrap();
+        let consecutive = cooldowns.get(key).map_or(0, |c| c.consecutive) + 1;
+        let wait = self
+            .base
+            .saturating_mul(1 << (consecutive - 1).min(16))
+            .min(self.max);
+        cooldowns.insert(
+            key.to_string(),
+            Cooldown {
+                until: Instant::now() + wait,
+                consecutive,
+            },
+        );
+        warn!(
+            "Upstream rate limited {} ({} in a row), cooling down for {}s",
+            key,
+            consecutive,
+            wait.as_secs()
+        );
+        wait
+    }
+
+    pub fn record_success(&self, key: &str) {
+        self.cooldowns.lock().unwrap().remove(key);
+    }
+
+    /// Refuse work for `key` while it is cooling down
+    pub fn check(&self, key: &str) -> Result<(), AppError> {
+        match self.remaining(key) {
+            Some(wait) => Err(AppError::RateLimited {
+                message: format!("Upstream is rate limiting '{}', retry later", key),
+                retry_after: Some(wait),
+            }),
+            None => Ok(()),
+        }
+    }
+
+    /// Run an upstream operation for `key`, refusing it while cooling down
+    pub async fn guard<T, Fut>(&self, key: &str, op: Fut) -> Result<T, AppError>
+    where
+        Fut: Future<Output = Result<T, AppError>>,
+    {
+        self.check(key)?;
+
+        match op.await {
+            Ok(value) => {
+                self.record_success(key);
+                Ok(value)
+            }
+            Err(AppError::RateLimited { message, .. }) => Err(AppError::RateLimited {
+                message,
+                retry_after: Some(self.record_rate_limited(key)),
+            }),
+            Err(e) => Err(e),
+        }
+    }
+}
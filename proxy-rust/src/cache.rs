@@ -0,0 +1,1770 @@
+//! Content-addressed file cache
+//!
+//! With `CACHE_DIR` set, downloaded files are kept on disk keyed by XET hash
+//! and [`CachingDownloader`] serves later requests for them (including byte
+//! ranges) from there. With `CACHE_S3_BUCKET` instead, they are kept in an
+//! S3-compatible bucket that several proxies can share (see [`s3`]). A cold
+//! request for a whole file is teed to the store while it streams to the
+//! client; the file is committed to the cache only if the transfer ended
+//! cleanly with the announced length, so a failed or aborted download never
+//! leaves a truncated entry behind. If the store falls behind the client,
+//! the fill is abandoned rather than slowing the transfer down.
+//!
+//! A file has one writer at a time. A request for a file that is being
+//! filled into `CACHE_DIR` follows the fill: it reads the growing file,
+//! waiting for the bytes it needs as they are written, rather than fetching
+//! it a second time. Should the client of the fill go away, the rest of the
+//! file is still fetched for its followers. A fill in an S3 or encrypted
+//! store can't be followed, so such a request streams the file from upstream
+//! without writing it again. Spooling (or prefetching) a file being filled
+//! waits for the fill to end. Followers and waiters give up on a fill that
+//! writes nothing for 30 seconds.
+//!
+//! The cache is bounded by `CACHE_MAX_BYTES`; least recently used entries
+//! are evicted once a commit takes it over the limit. Files with a transfer
+//! reading or filling them are counted and spared: the cache can stay over
+//! the limit while they are in use, and evicts once the last such
+//! transfer ends. Entries found in the
+//! store at startup are indexed with their modification time as last use.
+//! With a shared bucket, a file missing from the index is looked up in the
+//! bucket before going upstream, so a file one proxy fetched serves them
+//! all.
+//!
+//! Operators can attach metadata to a cached file (owning team, retention
+//! class, free-form labels) with `PUT /cache/:hash/metadata`. It is kept in
+//! a `<hash>.meta.json` sidecar next to the file, survives restarts, goes
+//! away with the file, and is reported by `/cache` for retention and
+//! chargeback tooling.
+//!
+//! `DELETE /cache` and `DELETE /cache/:hash` only mark files deleted: they
+//! stop being served and counted at once, but stay in the store (as
+//! `<hash>.deleted`) for `CACHE_PURGE_GRACE_SECS` (default 86400) and can be
+//! brought back with `POST /cache/restore` or `POST /cache/:hash/restore`.
+//! A sweep removes them for good once the grace period is over. `0`, or
+//! `?immediate=true` on the request, deletes right away. Deleted files found
+//! at startup start a new grace period.
+//!
+//! On disk, files are kept in subdirectories by hash prefix; see [`layout`]
+//! for how directories of earlier releases are upgraded. With
+//! `CACHE_ENCRYPTION_KEY`, either store only ever holds files encrypted
+//! under per-file keys the operator's key wraps (see [`encrypted`]).
+
+mod disk;
+mod encrypted;
+mod layout;
+mod s3;
+mod store;
+
+use crate::downloader::{ByteStream, Download, DownloadRequest, Downloader};
+use crate::listing::{ListPage, ListedFile};
+use crate::manifest::ManifestChunk;
+use crate::range::ByteRange;
+use crate::repo::RepoRef;
+use crate::upload::{UploadRequest, UploadResult};
+use crate::AppError;
+use async_trait::async_trait;
+use axum::body::Bytes;
+use futures_core::Stream;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::future::Future;
+use std::io::{self, SeekFrom};
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::task::{Context, Poll};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use tokio::sync::{mpsc, oneshot, watch};
+use tokio_stream::wrappers::ReceiverStream;
+use tokio_stream::StreamExt;
+use tracing::{debug, info, warn};
+
+/// Chunks buffered between a cold transfer and its cache writer
+const FILL_BUFFER: usize = 64;
+const PARTIAL_SUFFIX: &str = ".partial";
+const METADATA_SUFFIX: &str = ".meta.json";
+const DELETED_SUFFIX: &str = ".deleted";
+/// Longest pause between sweeps of deleted files
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+/// Longest a request waiting on another's fill of a file waits for it to
+/// write more
+const FILL_STALL: Duration = Duration::from_secs(30);
+/// Largest read of a followed fill
+const FOLLOW_CHUNK: u64 = 64 * 1024;
+
+#[derive(Clone)]
+struct Entry {
+    size: u64,
+    hits: u64,
+    last_used: SystemTime,
+    /// When the file was written to the store
+    fetched: SystemTime,
+    /// Found in the shared store, written there by another proxy
+    peer: bool,
+    metadata: Option<CacheMetadata>,
+}
+
+/// Operator-supplied metadata of a cached file
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CacheMetadata {
+    /// Team owning the file, for chargeback
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub team: Option<String>,
+    /// Retention class, as understood by the operator's policies
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retention: Option<String>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub labels: BTreeMap<String, String>,
+}
+
+impl CacheMetadata {
+    fn is_empty(&self) -> bool {
+        self.team.is_none() && self.retention.is_none() && self.labels.is_empty()
+    }
+}
+
+/// A file marked deleted, restorable until its grace period is over
+struct Deleted {
+    entry: Entry,
+    deleted_at: SystemTime,
+}
+
+#[derive(Default)]
+struct Index {
+    entries: HashMap<String, Entry>,
+    total: u64,
+    deleted: HashMap<String, Deleted>,
+    /// Transfers reading or filling each file in use, spared from eviction
+    active: HashMap<String, usize>,
+    /// Fills in progress, by file
+    filling: HashMap<String, Arc<Filling>>,
+}
+
+/// How far the fill of a file has got
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum FillState {
+    /// Waiting for the transfer to start
+    Starting,
+    /// Bytes written so far
+    Writing(u64),
+    /// Committed with this size
+    Committed(u64),
+    Failed,
+}
+
+/// A fill in progress, watched by requests waiting on it
+struct Filling {
+    state: watch::Sender<FillState>,
+    /// File being written and its expected length, if it can be followed;
+    /// set before the fill starts writing
+    followable: OnceLock<(PathBuf, u64)>,
+}
+
+#[derive(Default)]
+struct Lookups {
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+/// Cache of complete files, in a [`store::CacheStore`]
+#[derive(Clone)]
+pub struct Cache {
+    store: Arc<dyn store::CacheStore>,
+    max_bytes: u64,
+    /// How long deleted files stay restorable; zero deletes at once
+    grace: Duration,
+    index: Arc<Mutex<Index>>,
+    lookups: Arc<Lookups>,
+}
+
+/// One cached file, as reported by the admin endpoint
+#[derive(Serialize)]
+pub struct CacheEntry {
+    pub hash: String,
+    pub size: u64,
+    pub hits: u64,
+    /// Unix time of the last hit or fill
+    pub last_used: u64,
+    /// Unix time the file was fetched into the cache
+    pub fetched_at: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<CacheMetadata>,
+}
+
+/// A deleted file still in the store, as reported by the admin endpoint
+#[derive(Serialize)]
+pub struct DeletedEntry {
+    pub hash: String,
+    pub size: u64,
+    /// Unix time of the deletion
+    pub deleted_at: u64,
+    /// Unix time after which it can no longer be restored
+    pub purge_at: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<CacheMetadata>,
+}
+
+/// Cache contents and usage
+#[derive(Serialize)]
+pub struct CacheReport {
+    /// Where the files are: the directory, or the bucket and prefix
+    pub dir: String,
+    pub max_bytes: u64,
+    pub used_bytes: u64,
+    /// Downloads served from the cache
+    pub hits: u64,
+    /// Downloads that had to go upstream
+    pub misses: u64,
+    /// Most recently used first
+    pub entries: Vec<CacheEntry>,
+    /// Bytes of deleted files awaiting removal, not part of `used_bytes`
+    pub deleted_bytes: u64,
+    /// Restorable files, most recently deleted first
+    pub deleted: Vec<DeletedEntry>,
+}
+
+/// Whether caching is on: `CACHE_DIR` or `CACHE_S3_BUCKET` is set
+pub fn configured() -> bool {
+    std::env::var_os("CACHE_DIR").is_some() || std::env::var_os("CACHE_S3_BUCKET").is_some()
+}
+
+impl Cache {
+    /// Load `CACHE_DIR` or `CACHE_S3_*`, `CACHE_MAX_BYTES` (default 10 GiB),
+    /// `CACHE_PURGE_GRACE_SECS` and `CACHE_ENCRYPTION_KEY[_FILE]`; `None` if
+    /// caching is off
+    pub fn from_env() -> Option<Self> {
+        let store: Arc<dyn store::CacheStore> = match (
+            std::env::var("CACHE_DIR").ok(),
+            std::env::var("CACHE_S3_BUCKET").ok(),
+        ) {
+            (None, None) => return None,
+            (Some(dir), None) => Arc::new(disk::DiskStore::open(PathBuf::from(dir))),
+            (None, Some(bucket)) => Arc::new(s3::S3Store::from_env(bucket)),
+            (Some(_), Some(_)) => panic!("CACHE_DIR and CACHE_S3_BUCKET are exclusive; set one"),
+        };
+        let store: Arc<dyn store::CacheStore> = match encrypted::MasterKey::from_env() {
+            Some(key) => Arc::new(encrypted::EncryptedStore::new(store, key)),
+            None => store,
+        };
+        let max_bytes = crate::config::positive("CACHE_MAX_BYTES").unwrap_or(10 << 30);
+        let grace = crate::config::number("CACHE_PURGE_GRACE_SECS").unwrap_or(86400);
+
+        Some(Self {
+            store,
+            max_bytes,
+            grace: Duration::from_secs(grace),
+            index: Arc::new(Mutex::new(Index::default())),
+            lookups: Arc::default(),
+        })
+    }
+
+    /// Index the files already in the store, at startup
+    pub async fn load(&self) {
+        let contents = self.store.scan().await.unwrap_or_else(|e| {
+            panic!(
+                "Failed to read the cache at {}: {}",
+                self.store.describe(),
+                e
+            )
+        });
+        let mut metadata = Vec::new();
+        for hash in contents.metadata {
+            match self.store.read_metadata(&hash).await.and_then(|bytes| {
+                serde_json::from_slice::<CacheMetadata>(&bytes).map_err(io::Error::other)
+            }) {
+                Ok(read) => metadata.push((hash, read)),
+                Err(e) => warn!("Ignoring unreadable metadata of cached {}: {}", hash, e),
+            }
+        }
+        let mut orphans = Vec::new();
+        {
+            let mut index = self.index.lock().unwrap();
+            for file in contents.files {
+                let entry = Entry {
+                    size: file.size,
+                    hits: 0,
+                    last_used: file.modified,
+                    fetched: file.modified,
+                    peer: false,
+                    metadata: None,
+                };
+                if file.deleted {
+                    let deleted = Deleted {
+                        entry,
+                        deleted_at: SystemTime::now(),
+                    };
+                    index.deleted.insert(file.hash, deleted);
+                } else {
+                    index.total += file.size;
+                    index.entries.insert(file.hash, entry);
+                }
+            }
+            for (hash, read) in metadata {
+                let index = &mut *index;
+                let entry = index.entries.get_mut(&hash);
+                match entry.or_else(|| index.deleted.get_mut(&hash).map(|d| &mut d.entry)) {
+                    Some(entry) => entry.metadata = Some(read),
+                    // The file itself is gone
+                    None => orphans.push(hash),
+                }
+            }
+            info!(
+                "Cache at {}: {} files, {} of {} bytes used, {} deleted files restorable",
+                self.store.describe(),
+                index.entries.len(),
+                index.total,
+                self.max_bytes,
+                index.deleted.len()
+            );
+        }
+        for hash in orphans {
+            let _ = self.store.write_metadata(&hash, None).await;
+        }
+        self.evict(None).await;
+    }
+
+    /// Size of a cached file
+    pub fn size(&self, hash: &str) -> Option<u64> {
+        self.index.lock().unwrap().entries.get(hash).map(|e| e.size)
+    }
+
+    /// Size of a cached file, looking in the store for files other proxies
+    /// sharing it have added
+    pub async fn lookup(&self, hash: &str) -> Option<u64> {
+        if let Some(size) = self.size(hash) {
+            return Some(size);
+        }
+        if !self.store.shared() {
+            return None;
+        }
+        match self.store.stat(hash).await {
+            Ok(Some((size, modified))) => {
+                debug!("Found {} in the shared cache", hash);
+                self.insert(hash, size, modified, true).await;
+                Some(size)
+            }
+            Ok(None) => None,
+            Err(e) => {
+                warn!("Failed to look {} up in the cache: {}", hash, e);
+                None
+            }
+        }
+    }
+
+    /// When a cached file was fetched, looking in the store like
+    /// [`Self::lookup`]
+    pub async fn fetched(&self, hash: &str) -> Option<SystemTime> {
+        self.lookup(hash).await?;
+        self.index
+            .lock()
+            .unwrap()
+            .entries
+            .get(hash)
+            .map(|e| e.fetched)
+    }
+
+    /// Serve a cached file (or a range of it) from the store
+    async fn open(&self, hash: &str, range: Option<ByteRange>) -> Option<Download> {
+        self.lookup(hash).await?;
+        // In use from the moment it is found, so eviction can't pick it
+        let (size, fetched, peer, active) = {
+            let mut index = self.index.lock().unwrap();
+            let entry = index.entries.get_mut(hash)?;
+            entry.hits += 1;
+            entry.last_used = SystemTime::now();
+            let (size, fetched, peer) = (entry.size, entry.fetched, entry.peer);
+            (size, fetched, peer, self.activate(&mut index, hash))
+        };
+
+        let (start, length) = range.map_or((0, size), |r| (r.start, r.len()));
+        match self.store.open(hash, start, length).await {
+            Ok(download) => {
+                debug!("Serving {} from cache", hash);
+                Some(Download {
+                    body: Box::pin(Reading {
+                        inner: download.body,
+                        _active: active,
+                    }),
+                    cached: Some(fetched),
+                    peer,
+                    ..download
+                })
+            }
+            Err(e) => {
+                warn!("Cached file {} is unreadable, dropping it: {}", hash, e);
+                self.remove(hash).await;
+                None
+            }
+        }
+    }
+
+    /// Become the only writer of a file; `None` while another fills it
+    fn claim(&self, hash: &str) -> Option<Writer> {
+        let mut index = self.index.lock().unwrap();
+        if index.filling.contains_key(hash) {
+            return None;
+        }
+        let filling = Arc::new(Filling {
+            state: watch::Sender::new(FillState::Starting),
+            followable: OnceLock::new(),
+        });
+        index.filling.insert(hash.to_string(), filling.clone());
+        Some(Writer {
+            cache: self.clone(),
+            hash: hash.to_string(),
+            filling,
+        })
+    }
+
+    /// Wait for the fill of a file in progress to end, as long as it keeps
+    /// writing; `None` if there is none, otherwise how far it got
+    async fn fill_outcome(&self, hash: &str) -> Option<FillState> {
+        let filling = self.index.lock().unwrap().filling.get(hash)?.clone();
+        let mut state = filling.state.subscribe();
+        loop {
+            let current = *state.borrow_and_update();
+            if matches!(current, FillState::Committed(_) | FillState::Failed) {
+                return Some(current);
+            }
+            // The sender lives as long as `filling`
+            if tokio::time::timeout(FILL_STALL, state.changed())
+                .await
+                .is_err()
+            {
+                return Some(current);
+            }
+        }
+    }
+
+    /// Serve a file (or a range of it) being filled, reading the fill's
+    /// file as it grows; `None` if it is not being filled into a file that
+    /// can be followed
+    async fn follow(&self, hash: &str, range: Option<ByteRange>) -> Option<Download> {
+        let (filling, active) = {
+            let mut index = self.index.lock().unwrap();
+            let filling = index.filling.get(hash)?.clone();
+            (filling, self.activate(&mut index, hash))
+        };
+        // Whether the fill can be followed is known once its transfer started
+        let mut state = filling.state.subscribe();
+        let started = state.wait_for(|state| *state != FillState::Starting);
+        tokio::time::timeout(FILL_STALL, started).await.ok()?.ok()?;
+        let (partial, size) = filling.followable.get()?.clone();
+        let (start, length) = range.map_or((0, size), |r| (r.start, r.len()));
+        if start + length > size {
+            return None;
+        }
+        let mut file = match tokio::fs::File::open(&partial).await {
+            Ok(file) => file,
+            // Committed meanwhile, or failed
+            Err(_) => {
+                drop(active);
+                return self.open(hash, range).await;
+            }
+        };
+        file.seek(SeekFrom::Start(start)).await.ok()?;
+
+        debug!("Following the fill of {}", hash);
+        let (sender, receiver) = mpsc::channel(4);
+        let read = Arc::new(AtomicU64::new(0));
+        tokio::spawn(follow_fill(
+            filling,
+            state,
+            file,
+            start..start + length,
+            sender,
+            read.clone(),
+        ));
+        Some(Download {
+            body: Box::pin(Reading {
+                inner: Box::pin(ReceiverStream::new(receiver)),
+                _active: active,
+            }),
+            length: Some(length),
+            upstream_bytes: read,
+            cached: Some(SystemTime::now()),
+            peer: false,
+        })
+    }
+
+    /// Tee a whole-file download into the cache while it streams
+    fn fill(&self, writer: Writer, download: Download) -> Download {
+        if download
+            .length
+            .is_some_and(|length| length > self.max_bytes)
+        {
+            return download;
+        }
+        let (chunks, chunk_receiver) = mpsc::channel(FILL_BUFFER);
+        let (finished, finished_receiver) = oneshot::channel();
+        let filling = writer.filling.clone();
+        tokio::spawn(self.clone().write_fill(
+            writer,
+            download.length,
+            chunk_receiver,
+            finished_receiver,
+        ));
+        Download {
+            body: Box::pin(TeeStream {
+                inner: download.body,
+                fill: Some((chunks, finished)),
+                filling,
+                length: download.length,
+                bytes: 0,
+            }),
+            ..download
+        }
+    }
+
+    async fn write_fill(
+        self,
+        writer: Writer,
+        expected: Option<u64>,
+        chunks: mpsc::Receiver<Bytes>,
+        finished: oneshot::Receiver<()>,
+    ) {
+        let hash = writer.hash.clone();
+        let body = ReceiverStream::new(chunks).map(Ok);
+        let completed = async { finished.await.is_ok() };
+        match self.write_entry(writer, expected, body, completed).await {
+            Ok(size) => info!("Cached {} ({} bytes)", hash, size),
+            Err(e) => debug!("Not caching {}: {}", hash, e),
+        }
+    }
+
+    /// Write a whole-file download straight into the cache, at the pace of
+    /// the store rather than of a client; a file another transfer is
+    /// filling is left to it
+    pub async fn store(&self, hash: &str, download: Download) -> io::Result<u64> {
+        if download
+            .length
+            .is_some_and(|length| length > self.max_bytes)
+        {
+            return Err(io::Error::other("file is larger than the cache"));
+        }
+        let writer = loop {
+            if let Some(writer) = self.claim(hash) {
+                break writer;
+            }
+            match self.fill_outcome(hash).await {
+                Some(FillState::Committed(size)) => return Ok(size),
+                // Ended meanwhile, or failed: try to write it here
+                Some(FillState::Failed) | None => continue,
+                Some(_) => return Err(io::Error::other("another fill of the file stalled")),
+            }
+        };
+        let size = self
+            .write_entry(
+                writer,
+                download.length,
+                download.body,
+                std::future::ready(true),
+            )
+            .await?;
+        info!("Cached {} ({} bytes)", hash, size);
+        Ok(size)
+    }
+
+    /// Write `body` to a fill and commit it once `completed` confirms the
+    /// transfer ended cleanly with the expected length
+    async fn write_entry<S>(
+        &self,
+        writer: Writer,
+        expected: Option<u64>,
+        mut body: S,
+        completed: impl Future<Output = bool>,
+    ) -> io::Result<u64>
+    where
+        S: Stream<Item = io::Result<Bytes>> + Unpin,
+    {
+        let hash = writer.hash.as_str();
+        let mut active = self.activate(&mut self.index.lock().unwrap(), hash);
+        let mut fill = self.store.create(hash).await?;
+        if let (Some(partial), Some(expected)) = (fill.partial(), expected) {
+            let _ = writer.filling.followable.set((partial, expected));
+        }
+        writer.progress(FillState::Writing(0));
+        let written = async {
+            let mut written = 0u64;
+            while let Some(chunk) = body.next().await {
+                let chunk = chunk?;
+                written += chunk.len() as u64;
+                if written > self.max_bytes {
+                    return Err(io::Error::other("file is larger than the cache"));
+                }
+                fill.write(chunk).await?;
+                writer.progress(FillState::Writing(written));
+            }
+            if !completed.await {
+                return Err(io::Error::other("transfer did not complete"));
+            }
+            if expected.is_some_and(|expected| expected != written) {
+                return Err(io::Error::other("transfer length mismatch"));
+            }
+            Ok(written)
+        }
+        .await;
+
+        let size = match written {
+            Ok(size) => size,
+            Err(e) => {
+                fill.abort().await;
+                return Err(e);
+            }
+        };
+        fill.commit().await?;
+        self.insert(hash, size, SystemTime::now(), false).await;
+        writer.progress(FillState::Committed(size));
+        // The commit evicted already, sparing this file: it shouldn't go the
+        // moment its fill lets go of it
+        active.catch_up = false;
+        Ok(size)
+    }
+
+    async fn insert(&self, hash: &str, size: u64, fetched: SystemTime, peer: bool) {
+        let superseded = {
+            let mut index = self.index.lock().unwrap();
+            let entry = Entry {
+                size,
+                hits: 0,
+                last_used: SystemTime::now(),
+                fetched,
+                peer,
+                metadata: None,
+            };
+            // Another proxy sharing the store may have committed it first
+            if let Some(previous) = index.entries.insert(hash.to_string(), entry) {
+                index.total -= previous.size;
+                if let Some(entry) = index.entries.get_mut(hash) {
+                    entry.metadata = previous.metadata;
+                }
+            }
+            index.total += size;
+            index.deleted.remove(hash).is_some()
+        };
+        // A fresh copy supersedes a deleted one
+        if superseded {
+            let _ = self.store.supersede(hash).await;
+        }
+        self.evict(Some(hash)).await;
+    }
+
+    /// Mark a file in use until the returned guard is dropped
+    fn activate(&self, index: &mut Index, hash: &str) -> Active {
+        *index.active.entry(hash.to_string()).or_default() += 1;
+        Active {
+            cache: self.clone(),
+            hash: hash.to_string(),
+            catch_up: true,
+        }
+    }
+
+    /// Evict least recently used entries until the cache fits, sparing `keep`
+    /// and files in use
+    async fn evict(&self, keep: Option<&str>) {
+        let mut evicted = Vec::new();
+        {
+            let mut index = self.index.lock().unwrap();
+            while index.total > self.max_bytes {
+                let Some(oldest) = index
+                    .entries
+                    .iter()
+                    .filter(|(hash, _)| Some(hash.as_str()) != keep)
+                    .filter(|(hash, _)| !index.active.contains_key(*hash))
+                    .min_by_key(|(_, entry)| entry.last_used)
+                    .map(|(hash, _)| hash.clone())
+                else {
+                    debug!(
+                        "Cache {} bytes over its limit, in files in use",
+                        index.total - self.max_bytes
+                    );
+                    break;
+                };
+                let entry = index.entries.remove(&oldest).unwrap();
+                index.total -= entry.size;
+                evicted.push((oldest, entry.size));
+            }
+        }
+        for (hash, size) in evicted {
+            if let Err(e) = self.store.remove(&hash).await {
+                warn!("Failed to evict cached file {}: {}", hash, e);
+            }
+            debug!("Evicted {} ({} bytes) from cache", hash, size);
+        }
+    }
+
+    /// Remove one file from the cache; returns its size if it was cached
+    pub async fn remove(&self, hash: &str) -> Option<u64> {
+        let entry = {
+            let mut index = self.index.lock().unwrap();
+            let entry = index.entries.remove(hash)?;
+            index.total -= entry.size;
+            entry
+        };
+        let _ = self.store.remove(hash).await;
+        Some(entry.size)
+    }
+
+    /// Write a scratch entry, read it back and remove it again, for
+    /// `--self-test`
+    pub async fn self_test(&self) -> Result<String, String> {
+        const SCRATCH: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+        let content = Bytes::from(format!("xet-proxy self-test {:?}", SystemTime::now()));
+        let length = content.len() as u64;
+        let download = Download {
+            body: Box::pin(tokio_stream::once(Ok(content.clone()))),
+            length: Some(length),
+            upstream_bytes: Arc::default(),
+            cached: None,
+            peer: false,
+        };
+        self.store(SCRATCH, download)
+            .await
+            .map_err(|e| format!("write to {}: {}", self.store.describe(), e))?;
+
+        let read = async {
+            let mut body = self.open(SCRATCH, None).await?.body;
+            let mut read = Vec::new();
+            while let Some(chunk) = body.next().await {
+                read.extend_from_slice(&chunk.ok()?);
+            }
+            Some(read)
+        };
+        let read = read.await;
+        let removed = self.remove(SCRATCH).await;
+        if read.as_deref() != Some(&content[..]) {
+            return Err("read back different content than written".to_string());
+        }
+        let stored = self.store.stat(SCRATCH).await;
+        if removed.is_none() || self.size(SCRATCH).is_some() || !matches!(stored, Ok(None)) {
+            return Err("scratch entry was not removed".to_string());
+        }
+        Ok(format!(
+            "wrote, read back and removed {} bytes in {}",
+            length,
+            self.store.describe()
+        ))
+    }
+
+    /// Create and remove a probe in the store, for `/readyz`
+    pub async fn check_writable(&self) -> Result<String, String> {
+        self.store.check_writable().await
+    }
+
+    /// Replace the metadata of a cached file; `None` if it is not cached
+    pub async fn set_metadata(
+        &self,
+        hash: &str,
+        metadata: CacheMetadata,
+    ) -> io::Result<Option<CacheEntry>> {
+        if self.size(hash).is_none() {
+            return Ok(None);
+        }
+        let metadata = Some(metadata).filter(|metadata| !metadata.is_empty());
+        let bytes = metadata.as_ref().map(serde_json::to_vec).transpose()?;
+        self.store.write_metadata(hash, bytes).await?;
+        let mut index = self.index.lock().unwrap();
+        let Some(entry) = index.entries.get_mut(hash) else {
+            return Ok(None);
+        };
+        entry.metadata = metadata;
+        Ok(Some(report_entry(hash, entry)))
+    }
+
+    /// One cached file
+    pub fn entry(&self, hash: &str) -> Option<CacheEntry> {
+        let index = self.index.lock().unwrap();
+        index
+            .entries
+            .get(hash)
+            .map(|entry| report_entry(hash, entry))
+    }
+
+    /// How long deleted files stay restorable
+    pub fn grace(&self) -> Duration {
+        self.grace
+    }
+
+    /// Delete one file, restorable during the grace period unless
+    /// `immediate`; returns its size if it was cached
+    pub async fn delete(&self, hash: &str, immediate: bool) -> Option<u64> {
+        if immediate || self.grace.is_zero() {
+            let discarded = self.discard(hash).await;
+            return self.remove(hash).await.or(discarded);
+        }
+        let entry = {
+            let mut index = self.index.lock().unwrap();
+            let entry = index.entries.remove(hash)?;
+            index.total -= entry.size;
+            entry
+        };
+        let size = entry.size;
+        self.mark_deleted(hash, entry).await;
+        Some(size)
+    }
+
+    /// Delete every cached file, restorable during the grace period unless
+    /// `immediate`; returns the number of files and bytes taken out
+    pub async fn purge(&self, immediate: bool) -> (usize, u64) {
+        let (purged, entries, deleted) = {
+            let mut index = self.index.lock().unwrap();
+            let purged = (index.entries.len(), index.total);
+            let entries = std::mem::take(&mut index.entries);
+            index.total = 0;
+            let deleted: Vec<String> = index.deleted.keys().cloned().collect();
+            (purged, entries, deleted)
+        };
+        if immediate || self.grace.is_zero() {
+            for hash in entries.keys() {
+                let _ = self.store.remove(hash).await;
+            }
+            for hash in deleted {
+                self.discard(&hash).await;
+            }
+        } else {
+            for (hash, entry) in entries {
+                self.mark_deleted(&hash, entry).await;
+            }
+        }
+        purged
+    }
+
+    async fn mark_deleted(&self, hash: &str, entry: Entry) {
+        if let Err(e) = self.store.set_aside(hash).await {
+            warn!("Failed to set aside deleted {}, removing it: {}", hash, e);
+            let _ = self.store.remove(hash).await;
+            return;
+        }
+        let deleted = Deleted {
+            entry,
+            deleted_at: SystemTime::now(),
+        };
+        self.index
+            .lock()
+            .unwrap()
+            .deleted
+            .insert(hash.to_string(), deleted);
+    }
+
+    /// Bring a deleted file back; `None` if there is none to restore
+    pub async fn restore(&self, hash: &str) -> io::Result<Option<CacheEntry>> {
+        if !self.restore_entry(hash).await? {
+            return Ok(None);
+        }
+        let entry = self.entry(hash);
+        self.evict(Some(hash)).await;
+        Ok(entry)
+    }
+
+    /// Bring every deleted file back; returns the number of files and bytes
+    pub async fn restore_all(&self) -> io::Result<(usize, u64)> {
+        let deleted: Vec<(String, u64)> = {
+            let index = self.index.lock().unwrap();
+            let deleted = index.deleted.iter();
+            deleted
+                .map(|(hash, d)| (hash.clone(), d.entry.size))
+                .collect()
+        };
+        let mut restored = (0, 0);
+        for (hash, size) in deleted {
+            if self.restore_entry(&hash).await? {
+                restored.0 += 1;
+                restored.1 += size;
+            }
+        }
+        self.evict(None).await;
+        Ok(restored)
+    }
+
+    /// Move a deleted file back into the cache, as just used
+    async fn restore_entry(&self, hash: &str) -> io::Result<bool> {
+        if !self.index.lock().unwrap().deleted.contains_key(hash) {
+            return Ok(false);
+        }
+        self.store.bring_back(hash).await?;
+        let mut index = self.index.lock().unwrap();
+        // Swept or restored meanwhile
+        let Some(deleted) = index.deleted.remove(hash) else {
+            return Ok(false);
+        };
+        let mut entry = deleted.entry;
+        entry.last_used = SystemTime::now();
+        index.total += entry.size;
+        index.entries.insert(hash.to_string(), entry);
+        info!("Restored deleted {} to the cache", hash);
+        Ok(true)
+    }
+
+    /// Remove a deleted file for good; returns its size if there was one
+    async fn discard(&self, hash: &str) -> Option<u64> {
+        let deleted = self.index.lock().unwrap().deleted.remove(hash)?;
+        if let Err(e) = self.store.discard(hash).await {
+            warn!("Failed to remove deleted {}: {}", hash, e);
+        }
+        Some(deleted.entry.size)
+    }
+
+    /// Remove deleted files whose grace period is over
+    async fn sweep(&self) {
+        let expired: Vec<String> = {
+            let index = self.index.lock().unwrap();
+            let deleted = index.deleted.iter();
+            deleted
+                .filter(|(_, deleted)| {
+                    deleted.deleted_at.elapsed().unwrap_or_default() >= self.grace
+                })
+                .map(|(hash, _)| hash.clone())
+                .collect()
+        };
+        for hash in expired {
+            self.discard(&hash).await;
+            debug!("Removed deleted {} after its grace period", hash);
+        }
+    }
+
+    /// Sweep deleted files in the background
+    pub fn start(&self) {
+        if self.grace.is_zero() {
+            return;
+        }
+        let cache = self.clone();
+        let period = self.grace.min(SWEEP_INTERVAL);
+        tokio::spawn(async move {
+            let mut ticks = tokio::time::interval(period);
+            loop {
+                ticks.tick().await;
+                cache.sweep().await;
+            }
+        });
+    }
+
+    pub fn report(&self) -> CacheReport {
+        let index = self.index.lock().unwrap();
+        let mut entries: Vec<_> = index
+            .entries
+            .iter()
+            .map(|(hash, entry)| report_entry(hash, entry))
+            .collect();
+        entries.sort_by_key(|entry| std::cmp::Reverse(entry.last_used));
+        let mut deleted: Vec<_> = index
+            .deleted
+            .iter()
+            .map(|(hash, deleted)| {
+                let deleted_at = unix_secs(deleted.deleted_at);
+                DeletedEntry {
+                    hash: hash.clone(),
+                    size: deleted.entry.size,
+                    deleted_at,
+                    purge_at: deleted_at + self.grace.as_secs(),
+                    metadata: deleted.entry.metadata.clone(),
+                }
+            })
+            .collect();
+        deleted.sort_by_key(|entry| std::cmp::Reverse(entry.deleted_at));
+        CacheReport {
+            dir: self.store.describe(),
+            max_bytes: self.max_bytes,
+            used_bytes: index.total,
+            hits: self.lookups.hits.load(Ordering::Relaxed),
+            misses: self.lookups.misses.load(Ordering::Relaxed),
+            entries,
+            deleted_bytes: index.deleted.values().map(|d| d.entry.size).sum(),
+            deleted,
+        }
+    }
+}
+
+fn report_entry(hash: &str, entry: &Entry) -> CacheEntry {
+    CacheEntry {
+        hash: hash.to_string(),
+        size: entry.size,
+        hits: entry.hits,
+        last_used: unix_secs(entry.last_used),
+        fetched_at: unix_secs(entry.fetched),
+        metadata: entry.metadata.clone(),
+    }
+}
+
+/// `xet-proxy migrate-cache`: upgrade `CACHE_DIR` to the current layout
+/// and return the process exit code
+pub fn migrate_command() -> i32 {
+    let Ok(dir) = std::env::var("CACHE_DIR") else {
+        eprintln!("CACHE_DIR is not set, there is nothing to migrate");
+        return 2;
+    };
+    let dir = PathBuf::from(dir);
+    match layout::migrate(&dir) {
+        Ok(migration) if migration.from == layout::CURRENT => {
+            println!("{} already has layout {}", dir.display(), layout::CURRENT);
+            0
+        }
+        Ok(migration) => {
+            println!(
+                "{} migrated from layout {} to {}: {} files moved",
+                dir.display(),
+                migration.from,
+                layout::CURRENT,
+                migration.moved
+            );
+            0
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            1
+        }
+    }
+}
+
+fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs())
+}
+
+fn is_hash(name: &str) -> bool {
+    name.len() == 64 && name.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+/// A file in use by a transfer, spared from eviction while held
+struct Active {
+    cache: Cache,
+    hash: String,
+    /// Evict once released if the cache is over its limit
+    catch_up: bool,
+}
+
+impl Drop for Active {
+    fn drop(&mut self) {
+        let over = {
+            let mut index = self.cache.index.lock().unwrap();
+            if let Some(count) = index.active.get_mut(&self.hash) {
+                *count -= 1;
+                if *count == 0 {
+                    index.active.remove(&self.hash);
+                }
+            }
+            self.catch_up
+                && !index.active.contains_key(&self.hash)
+                && index.total > self.cache.max_bytes
+        };
+        // Catch up on an eviction the transfer held off
+        if over {
+            if let Ok(runtime) = tokio::runtime::Handle::try_current() {
+                let cache = self.cache.clone();
+                runtime.spawn(async move { cache.evict(None).await });
+            }
+        }
+    }
+}
+
+/// The right to fill a file, held by its only writer; a fill dropped before
+/// it committed failed
+struct Writer {
+    cache: Cache,
+    hash: String,
+    filling: Arc<Filling>,
+}
+
+impl Writer {
+    fn progress(&self, state: FillState) {
+        self.filling.state.send_replace(state);
+    }
+}
+
+impl Drop for Writer {
+    fn drop(&mut self) {
+        self.cache.index.lock().unwrap().filling.remove(&self.hash);
+        self.filling.state.send_if_modified(|state| {
+            let failed = !matches!(state, FillState::Committed(_));
+            if failed {
+                *state = FillState::Failed;
+            }
+            failed
+        });
+    }
+}
+
+/// Send bytes `range` of a file being filled as the fill writes them
+async fn follow_fill(
+    // Keeps the sender of `state` alive
+    _filling: Arc<Filling>,
+    mut state: watch::Receiver<FillState>,
+    mut file: tokio::fs::File,
+    range: std::ops::Range<u64>,
+    sender: mpsc::Sender<io::Result<Bytes>>,
+    read: Arc<AtomicU64>,
+) {
+    let mut position = range.start;
+    let followed = async {
+        while position < range.end {
+            let written = match *state.borrow_and_update() {
+                FillState::Starting => 0,
+                FillState::Writing(written) | FillState::Committed(written) => written,
+                FillState::Failed => {
+                    return Err(io::Error::other("the fill being followed failed"))
+                }
+            };
+            let available = written.min(range.end);
+            if available <= position {
+                if tokio::time::timeout(FILL_STALL, state.changed())
+                    .await
+                    .is_err()
+                {
+                    return Err(io::Error::other("the fill being followed stalled"));
+                }
+                continue;
+            }
+            let mut chunk = vec![0; (available - position).min(FOLLOW_CHUNK) as usize];
+            file.read_exact(&mut chunk).await?;
+            position += chunk.len() as u64;
+            read.fetch_add(chunk.len() as u64, Ordering::Relaxed);
+            if sender.send(Ok(chunk.into())).await.is_err() {
+                return Ok(());
+            }
+        }
+        Ok(())
+    };
+    if let Err(e) = followed.await {
+        let _ = sender.send(Err(e)).await;
+    }
+}
+
+/// Body of a cached file, holding it in use until dropped
+struct Reading {
+    inner: ByteStream,
+    _active: Active,
+}
+
+impl Stream for Reading {
+    type Item = io::Result<Bytes>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.inner.as_mut().poll_next(cx)
+    }
+}
+
+/// Body wrapper copying chunks to a cache fill as the client reads them,
+/// so a cold file is fetched once. The client never waits on the store: a
+/// fill that can't keep up, an upstream error or the client going away
+/// before the end abandons the fill, which then leaves nothing behind;
+/// unless, in the last case, other requests are waiting on the fill
+struct TeeStream {
+    inner: ByteStream,
+    /// Chunk channel and completion signal; dropped to abandon the fill
+    fill: Option<(mpsc::Sender<Bytes>, oneshot::Sender<()>)>,
+    filling: Arc<Filling>,
+    length: Option<u64>,
+    bytes: u64,
+}
+
+impl Drop for TeeStream {
+    fn drop(&mut self) {
+        let Some((chunks, finished)) = self.fill.take() else {
+            return;
+        };
+        if self.filling.state.receiver_count() == 0 {
+            return;
+        }
+        let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+            return;
+        };
+        // Fetch the rest for the requests following or waiting on the fill,
+        // at the pace of the store
+        debug!("Client of a followed fill went away, finishing the fill");
+        let mut inner = std::mem::replace(&mut self.inner, Box::pin(tokio_stream::empty()));
+        let (length, mut bytes) = (self.length, self.bytes);
+        runtime.spawn(async move {
+            while let Some(chunk) = inner.next().await {
+                let Ok(chunk) = chunk else {
+                    return;
+                };
+                bytes += chunk.len() as u64;
+                if chunks.send(chunk).await.is_err() {
+                    return;
+                }
+                if length == Some(bytes) {
+                    break;
+                }
+            }
+            let _ = finished.send(());
+        });
+    }
+}
+
+impl TeeStream {
+    fn complete(&mut self) {
+        if let Some((_, finished)) = self.fill.take() {
+            let _ = finished.send(());
+        }
+    }
+}
+
+impl Stream for TeeStream {
+    type Item = io::Result<Bytes>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let poll = self.inner.as_mut().poll_next(cx);
+        match &poll {
+            Poll::Ready(Some(Ok(chunk))) => {
+                let abandoned = self
+                    .fill
+                    .as_ref()
+                    .is_some_and(|(chunks, _)| chunks.try_send(chunk.clone()).is_err());
+                if abandoned {
+                    debug!("Cache writer fell behind, abandoning fill");
+                    self.fill = None;
+                }
+                // A body with a known length is dropped after its last byte
+                // rather than polled to its end
+                self.bytes += chunk.len() as u64;
+                if self.length == Some(self.bytes) {
+                    self.complete();
+                }
+            }
+            Poll::Ready(Some(Err(_))) => self.fill = None,
+            Poll::Ready(None) => self.complete(),
+            Poll::Pending => {}
+        }
+        poll
+    }
+}
+
+/// Downloader serving from the cache when warm and filling it when cold
+pub struct CachingDownloader {
+    inner: Arc<dyn Downloader>,
+    cache: Cache,
+}
+
+impl CachingDownloader {
+    pub fn new(inner: Arc<dyn Downloader>, cache: Cache) -> Self {
+        Self { inner, cache }
+    }
+}
+
+#[async_trait]
+impl Downloader for CachingDownloader {
+    async fn list(&self, repo: &RepoRef, hf_token: &str) -> Result<Vec<ListedFile>, AppError> {
+        self.inner.list(repo, hf_token).await
+    }
+
+    async fn list_page(
+        &self,
+        repo: &RepoRef,
+        hf_token: &str,
+        cursor: Option<&str>,
+    ) -> Result<ListPage, AppError> {
+        self.inner.list_page(repo, hf_token, cursor).await
+    }
+
+    async fn commit(&self, repo: &RepoRef, hf_token: &str) -> Result<Option<String>, AppError> {
+        self.inner.commit(repo, hf_token).await
+    }
+
+    async fn download(&self, request: DownloadRequest<'_>) -> Result<Download, AppError> {
+        let (hash, range) = (request.hash, request.range);
+        if let Some(download) = self.cache.open(hash, range).await {
+            self.cache.lookups.hits.fetch_add(1, Ordering::Relaxed);
+            return Ok(download);
+        }
+        if !request.spool {
+            if let Some(download) = self.cache.follow(hash, range).await {
+                self.cache.lookups.hits.fetch_add(1, Ordering::Relaxed);
+                return Ok(download);
+            }
+        }
+        self.cache.lookups.misses.fetch_add(1, Ordering::Relaxed);
+        if request.spool {
+            // A fill in progress fetches the file for this request too
+            if self.cache.fill_outcome(hash).await.is_some() {
+                if let Some(download) = self.cache.open(hash, range).await {
+                    return Ok(Download {
+                        cached: None,
+                        ..download
+                    });
+                }
+            }
+            // The whole file, whatever range the client wants
+            let download = self.inner.download(DownloadRequest {
+                range: None,
+                length: request.length.filter(|_| range.is_none()),
+                ..request
+            });
+            let stored = self.cache.store(hash, download.await?).await;
+            match stored {
+                Ok(_) => {
+                    if let Some(download) = self.cache.open(hash, range).await {
+                        // Fetched for this request: a miss
+                        return Ok(Download {
+                            cached: None,
+                            ..download
+                        });
+                    }
+                }
+                Err(e) => warn!("Failed to spool {}, streaming it instead: {}", hash, e),
+            }
+            return self
+                .inner
+                .download(DownloadRequest {
+                    spool: false,
+                    ..request
+                })
+                .await;
+        }
+        // Only whole files are cached, by one writer at a time, claimed
+        // before going upstream so concurrent misses don't both fill
+        let writer = range.is_none().then(|| self.cache.claim(hash)).flatten();
+        let download = self.inner.download(request).await?;
+        match writer {
+            Some(writer) => Ok(self.cache.fill(writer, download)),
+            None => Ok(download),
+        }
+    }
+
+    async fn file_size(
+        &self,
+        repo: &RepoRef,
+        hash: &str,
+        hf_token: &str,
+    ) -> Result<Option<u64>, AppError> {
+        match self.cache.lookup(hash).await {
+            Some(size) => Ok(Some(size)),
+            None => self.inner.file_size(repo, hash, hf_token).await,
+        }
+    }
+
+    async fn manifest(
+        &self,
+        repo: &RepoRef,
+        hash: &str,
+        hf_token: &str,
+    ) -> Result<Vec<ManifestChunk>, AppError> {
+        self.inner.manifest(repo, hash, hf_token).await
+    }
+
+    async fn upload(&self, request: UploadRequest<'_>) -> Result<UploadResult, AppError> {
+        self.inner.upload(request).await
+    }
+
+    fn failure_counts(&self) -> BTreeMap<String, u64> {
+        self.inner.failure_counts()
+    }
+
+    async fn check(&self) -> Result<String, String> {
+        self.inner.check().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Room for one 60-byte file, not two
+    const MAX_BYTES: u64 = 100;
+
+    fn cache(dir: &tempfile::TempDir) -> Cache {
+        Cache {
+            store: Arc::new(disk::DiskStore::open(dir.path().to_path_buf())),
+            max_bytes: MAX_BYTES,
+            grace: Duration::ZERO,
+            index: Arc::default(),
+            lookups: Arc::default(),
+        }
+    }
+
+    fn hash(c: char) -> String {
+        c.to_string().repeat(64)
+    }
+
+    fn download(content: &'static [u8]) -> Download {
+        Download {
+            body: Box::pin(tokio_stream::once(Ok(Bytes::from_static(content)))),
+            length: Some(content.len() as u64),
+            upstream_bytes: Arc::default(),
+            cached: None,
+            peer: false,
+        }
+    }
+
+    async fn read(download: Download) -> Vec<u8> {
+        let mut body = download.body;
+        let mut read = Vec::new();
+        while let Some(chunk) = body.next().await {
+            read.extend_from_slice(&chunk.unwrap());
+        }
+        read
+    }
+
+    /// Wait for spawned work on the cache until `done` holds
+    async fn settle(done: impl Fn() -> bool) {
+        for _ in 0..100 {
+            if done() {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("cache did not settle");
+    }
+
+    #[tokio::test]
+    async fn open_stream_is_not_evicted() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = cache(&dir);
+        let (a, b) = (hash('a'), hash('b'));
+        cache.store(&a, download(&[1; 60])).await.unwrap();
+
+        let reading = cache.open(&a, None).await.unwrap();
+        cache.store(&b, download(&[2; 60])).await.unwrap();
+
+        assert_eq!(cache.size(&a), Some(60));
+        assert_eq!(cache.size(&b), Some(60));
+        assert_eq!(cache.report().used_bytes, 120);
+        assert_eq!(read(reading).await, [1; 60]);
+    }
+
+    #[tokio::test]
+    async fn last_reader_ending_catches_up_on_eviction() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = cache(&dir);
+        let (a, b) = (hash('a'), hash('b'));
+        cache.store(&a, download(&[1; 60])).await.unwrap();
+
+        let first = cache.open(&a, None).await.unwrap();
+        let second = cache.open(&a, None).await.unwrap();
+        cache.store(&b, download(&[2; 60])).await.unwrap();
+
+        // Neither the commit's own release nor a reader of two evicts
+        drop(first);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(cache.size(&a), Some(60));
+        assert_eq!(cache.size(&b), Some(60));
+
+        drop(second);
+        settle(|| cache.size(&a).is_none()).await;
+        assert_eq!(cache.size(&b), Some(60));
+        assert_eq!(cache.report().used_bytes, 60);
+        assert!(matches!(cache.store.stat(&a).await, Ok(None)));
+    }
+
+    #[tokio::test]
+    async fn file_being_filled_is_not_evicted() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = cache(&dir);
+        let (a, b) = (hash('a'), hash('b'));
+        cache.store(&a, download(&[1; 60])).await.unwrap();
+
+        // A second fill of the same file, whose transfer hasn't started
+        let filling = cache.fill(cache.claim(&a).unwrap(), download(&[1; 60]));
+        settle(|| cache.index.lock().unwrap().active.contains_key(&a)).await;
+        cache.store(&b, download(&[2; 60])).await.unwrap();
+
+        assert_eq!(cache.size(&a), Some(60));
+        assert_eq!(cache.size(&b), Some(60));
+
+        // Once the fill commits, the cache fits again
+        assert_eq!(read(filling).await, [1; 60]);
+        settle(|| cache.report().used_bytes <= MAX_BYTES).await;
+        assert_eq!(cache.size(&a), Some(60));
+        assert!(cache.size(&b).is_none());
+    }
+
+    /// A download whose body the test sends, chunk by chunk
+    fn upstream(length: u64) -> (mpsc::Sender<io::Result<Bytes>>, Download) {
+        let (sender, receiver) = mpsc::channel(2 * FILL_BUFFER);
+        let download = Download {
+            body: Box::pin(ReceiverStream::new(receiver)),
+            length: Some(length),
+            upstream_bytes: Arc::default(),
+            cached: None,
+            peer: false,
+        };
+        (sender, download)
+    }
+
+    #[tokio::test]
+    async fn file_has_one_writer_at_a_time() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = cache(&dir);
+        let a = hash('a');
+
+        let writer = cache.claim(&a).unwrap();
+        assert!(cache.claim(&a).is_none());
+        assert!(cache.claim(&hash('b')).is_some());
+        // A writer giving up lets the next one in
+        drop(writer);
+        let writer = cache.claim(&a).unwrap();
+
+        let (sender, upstream) = upstream(60);
+        let mut filling = cache.fill(writer, upstream).body;
+        assert!(cache.claim(&a).is_none());
+        sender.send(Ok(Bytes::from_static(&[1; 60]))).await.unwrap();
+        assert_eq!(&filling.next().await.unwrap().unwrap()[..], [1; 60]);
+        drop(filling);
+        settle(|| cache.size(&a).is_some()).await;
+        settle(|| cache.claim(&a).is_some()).await;
+    }
+
+    #[tokio::test]
+    async fn store_leaves_a_file_to_its_fill() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = cache(&dir);
+        let a = hash('a');
+
+        let (sender, upstream) = upstream(60);
+        let mut filling = cache.fill(cache.claim(&a).unwrap(), upstream).body;
+        // A second copy with other content would show if it were written
+        let stored = tokio::spawn({
+            let cache = cache.clone();
+            let a = a.clone();
+            async move { cache.store(&a, download(&[2; 60])).await }
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!stored.is_finished());
+
+        sender.send(Ok(Bytes::from_static(&[1; 60]))).await.unwrap();
+        drop(sender);
+        assert_eq!(&filling.next().await.unwrap().unwrap()[..], [1; 60]);
+        assert_eq!(stored.await.unwrap().unwrap(), 60);
+        assert_eq!(read(cache.open(&a, None).await.unwrap()).await, [1; 60]);
+    }
+
+    #[tokio::test]
+    async fn store_writes_a_file_whose_fill_failed() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = cache(&dir);
+        let a = hash('a');
+
+        let (sender, upstream) = upstream(60);
+        let filling = cache.fill(cache.claim(&a).unwrap(), upstream);
+        let stored = tokio::spawn({
+            let cache = cache.clone();
+            let a = a.clone();
+            async move { cache.store(&a, download(&[2; 60])).await }
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        sender.send(Ok(Bytes::from_static(&[1; 30]))).await.unwrap();
+        sender.send(Err(io::Error::other("reset"))).await.unwrap();
+        assert_eq!(read_until_error(filling.body).await, [1; 30]);
+        assert_eq!(stored.await.unwrap().unwrap(), 60);
+        assert_eq!(read(cache.open(&a, None).await.unwrap()).await, [2; 60]);
+    }
+
+    /// Files left in the cache directory, fills included
+    fn files(dir: &tempfile::TempDir) -> Vec<String> {
+        let mut files = Vec::new();
+        let mut dirs = vec![dir.path().to_path_buf()];
+        while let Some(dir) = dirs.pop() {
+            for entry in std::fs::read_dir(dir).unwrap().flatten() {
+                if entry.file_type().unwrap().is_dir() {
+                    dirs.push(entry.path());
+                } else {
+                    files.push(entry.file_name().to_string_lossy().into_owned());
+                }
+            }
+        }
+        files.retain(|name| name != "LAYOUT_VERSION");
+        files
+    }
+
+    #[tokio::test]
+    async fn cold_file_is_cached_as_it_streams() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = cache(&dir);
+        let a = hash('a');
+
+        let (sender, upstream) = upstream(60);
+        let mut body = cache.fill(cache.claim(&a).unwrap(), upstream).body;
+        for chunk in [[1u8; 20], [2; 20], [3; 20]] {
+            sender
+                .send(Ok(Bytes::copy_from_slice(&chunk)))
+                .await
+                .unwrap();
+            assert_eq!(&body.next().await.unwrap().unwrap()[..], chunk);
+        }
+        // Committed after the last byte, without the body being polled to its end
+        settle(|| cache.size(&a).is_some()).await;
+        let cached = read(cache.open(&a, None).await.unwrap()).await;
+        assert_eq!(cached, [[1u8; 20], [2; 20], [3; 20]].concat());
+        assert_eq!(files(&dir), [a]);
+    }
+
+    #[tokio::test]
+    async fn failed_transfers_leave_nothing_behind() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = cache(&dir);
+        let a = hash('a');
+        let gone = || cache.claim(&a).is_some() && files(&dir).is_empty();
+
+        // The client goes away
+        let (sender, transfer) = upstream(60);
+        let mut body = cache.fill(cache.claim(&a).unwrap(), transfer).body;
+        sender.send(Ok(Bytes::from_static(&[1; 30]))).await.unwrap();
+        body.next().await.unwrap().unwrap();
+        drop(body);
+        settle(gone).await;
+
+        // Upstream fails
+        let (sender, transfer) = upstream(60);
+        let mut body = cache.fill(cache.claim(&a).unwrap(), transfer).body;
+        sender.send(Ok(Bytes::from_static(&[1; 30]))).await.unwrap();
+        sender.send(Err(io::Error::other("reset"))).await.unwrap();
+        body.next().await.unwrap().unwrap();
+        assert!(body.next().await.unwrap().is_err());
+        settle(gone).await;
+
+        // Upstream ends short
+        let (sender, transfer) = upstream(60);
+        let mut body = cache.fill(cache.claim(&a).unwrap(), transfer).body;
+        sender.send(Ok(Bytes::from_static(&[1; 30]))).await.unwrap();
+        drop(sender);
+        body.next().await.unwrap().unwrap();
+        assert!(body.next().await.is_none());
+        settle(gone).await;
+        assert!(cache.size(&a).is_none());
+    }
+
+    #[tokio::test]
+    async fn client_does_not_wait_for_a_slow_fill() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = cache(&dir);
+        let a = hash('a');
+
+        // More chunks than the fill buffers, read before its writer runs
+        let chunks = FILL_BUFFER + 1;
+        let (sender, upstream) = upstream(chunks as u64);
+        for _ in 0..chunks {
+            sender.try_send(Ok(Bytes::from_static(&[1]))).unwrap();
+        }
+        let body = cache.fill(cache.claim(&a).unwrap(), upstream).body;
+        let received: Vec<_> = body.take(chunks).collect().await;
+        assert!(received.iter().all(|chunk| chunk.is_ok()));
+
+        settle(|| cache.claim(&a).is_some() && files(&dir).is_empty()).await;
+        assert!(cache.size(&a).is_none());
+    }
+
+    /// A fill of `a` whose 60 bytes the test sends, and the client's body
+    fn fill_of(cache: &Cache, a: &str) -> (mpsc::Sender<io::Result<Bytes>>, ByteStream) {
+        let (sender, transfer) = upstream(60);
+        (sender, cache.fill(cache.claim(a).unwrap(), transfer).body)
+    }
+
+    /// What a body delivers before it fails, panicking if it doesn't
+    async fn read_until_error(mut body: ByteStream) -> Vec<u8> {
+        let mut read = Vec::new();
+        loop {
+            match body.next().await.expect("body should fail") {
+                Ok(chunk) => read.extend_from_slice(&chunk),
+                Err(_) => return read,
+            }
+        }
+    }
+
+    async fn next(body: &mut ByteStream) -> Vec<u8> {
+        body.next().await.unwrap().unwrap().to_vec()
+    }
+
+    #[tokio::test]
+    async fn follower_reads_a_fill_as_it_grows() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = cache(&dir);
+        let a = hash('a');
+
+        // Following from before the transfer started
+        let writer = cache.claim(&a).unwrap();
+        let follower = tokio::spawn({
+            let cache = cache.clone();
+            let a = a.clone();
+            async move { cache.follow(&a, None).await.unwrap() }
+        });
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        let (sender, transfer) = upstream(60);
+        let mut body = cache.fill(writer, transfer).body;
+        let follower = follower.await.unwrap();
+        assert_eq!(follower.length, Some(60));
+        let mut followed = follower.body;
+
+        sender.send(Ok(Bytes::from_static(&[1; 20]))).await.unwrap();
+        assert_eq!(next(&mut body).await, [1; 20]);
+        assert_eq!(next(&mut followed).await, [1; 20]);
+
+        // A range, from the middle of what is written to past it
+        let range = ByteRange {
+            start: 10,
+            end: 49,
+            size: 60,
+        };
+        let ranged = cache.follow(&a, Some(range)).await.unwrap();
+        assert_eq!(ranged.length, Some(40));
+
+        sender.send(Ok(Bytes::from_static(&[2; 40]))).await.unwrap();
+        assert_eq!(next(&mut body).await, [2; 40]);
+        assert_eq!(
+            read(Download {
+                body: followed,
+                ..download(b"")
+            })
+            .await,
+            [2; 40]
+        );
+        assert_eq!(read(ranged).await, [[1; 10].as_slice(), &[2; 30]].concat());
+        settle(|| cache.size(&a).is_some()).await;
+        assert!(cache.follow(&a, None).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn followers_keep_a_fill_going_without_its_client() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = cache(&dir);
+        let a = hash('a');
+
+        let (sender, mut body) = fill_of(&cache, &a);
+        sender.send(Ok(Bytes::from_static(&[1; 20]))).await.unwrap();
+        next(&mut body).await;
+        let follower = cache.follow(&a, None).await.unwrap();
+        drop(body);
+
+        sender.send(Ok(Bytes::from_static(&[2; 40]))).await.unwrap();
+        let followed = read(follower).await;
+        assert_eq!(followed, [[1; 20].as_slice(), &[2; 40]].concat());
+        settle(|| cache.size(&a).is_some()).await;
+        assert_eq!(read(cache.open(&a, None).await.unwrap()).await, followed);
+    }
+
+    #[tokio::test]
+    async fn follower_of_a_failed_fill_fails() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = cache(&dir);
+        let a = hash('a');
+
+        let (sender, body) = fill_of(&cache, &a);
+        let followed = cache.follow(&a, None).await.unwrap().body;
+        sender.send(Ok(Bytes::from_static(&[1; 20]))).await.unwrap();
+        sender.send(Err(io::Error::other("reset"))).await.unwrap();
+        assert_eq!(read_until_error(body).await, [1; 20]);
+
+        // Some of what was written, then the error
+        let followed = read_until_error(followed).await;
+        assert!(followed.len() <= 20 && followed.iter().all(|&b| b == 1));
+        settle(|| files(&dir).is_empty()).await;
+    }
+
+    #[tokio::test]
+    async fn fill_of_unknown_length_is_not_followed() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = cache(&dir);
+        let a = hash('a');
+
+        let (sender, transfer) = upstream(60);
+        let transfer = Download {
+            length: None,
+            ..transfer
+        };
+        let mut body = cache.fill(cache.claim(&a).unwrap(), transfer).body;
+        sender.send(Ok(Bytes::from_static(&[1; 20]))).await.unwrap();
+        next(&mut body).await;
+        assert!(cache.follow(&a, None).await.is_none());
+        assert!(cache.follow(&hash('b'), None).await.is_none());
+    }
+}
@@ -0,0 +1,249 @@
+//! Cache store in a local directory, `CACHE_DIR`
+//!
+//! Files live in shard subdirectories (see [`super::layout`]), next to
+//! their `.meta.json` sidecars. A fill goes to a `.partial` file renamed
+//! into place once complete; a deletion renames the file to `.deleted`.
+
+use super::store::{CacheStore, Contents, Fill, Stored};
+use super::{is_hash, layout, DELETED_SUFFIX, METADATA_SUFFIX, PARTIAL_SUFFIX};
+use crate::downloader::Download;
+use crate::transfer::CountingReader;
+use async_trait::async_trait;
+use axum::body::Bytes;
+use std::io::{self, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use tokio_util::io::ReaderStream;
+use tracing::warn;
+
+pub struct DiskStore {
+    dir: PathBuf,
+}
+
+impl DiskStore {
+    /// Create `dir` if needed and upgrade its layout
+    pub fn open(dir: PathBuf) -> Self {
+        std::fs::create_dir_all(&dir)
+            .unwrap_or_else(|e| panic!("Failed to create CACHE_DIR {}: {}", dir.display(), e));
+        layout::prepare(&dir);
+        Self { dir }
+    }
+
+    fn path(&self, hash: &str) -> PathBuf {
+        layout::shard_dir(&self.dir, hash).join(hash)
+    }
+
+    fn deleted_path(&self, hash: &str) -> PathBuf {
+        layout::shard_dir(&self.dir, hash).join(format!("{}{}", hash, DELETED_SUFFIX))
+    }
+
+    fn metadata_path(&self, hash: &str) -> PathBuf {
+        layout::shard_dir(&self.dir, hash).join(format!("{}{}", hash, METADATA_SUFFIX))
+    }
+}
+
+#[async_trait]
+impl CacheStore for DiskStore {
+    fn describe(&self) -> String {
+        self.dir.display().to_string()
+    }
+
+    async fn scan(&self) -> io::Result<Contents> {
+        let dir = self.dir.clone();
+        tokio::task::spawn_blocking(move || scan(&dir))
+            .await
+            .map_err(io::Error::other)?
+    }
+
+    async fn stat(&self, hash: &str) -> io::Result<Option<(u64, SystemTime)>> {
+        match tokio::fs::metadata(self.path(hash)).await {
+            Ok(metadata) => Ok(Some((
+                metadata.len(),
+                metadata.modified().unwrap_or(UNIX_EPOCH),
+            ))),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn open(&self, hash: &str, start: u64, length: u64) -> io::Result<Download> {
+        let mut file = tokio::fs::File::open(self.path(hash)).await?;
+        if start > 0 {
+            file.seek(SeekFrom::Start(start)).await?;
+        }
+        let reader = CountingReader::new(file.take(length));
+        let upstream_bytes = reader.counter();
+        Ok(Download {
+            body: Box::pin(ReaderStream::new(reader)),
+            length: Some(length),
+            upstream_bytes,
+            cached: None,
+            peer: false,
+        })
+    }
+
+    async fn create(&self, hash: &str) -> io::Result<Box<dyn Fill>> {
+        static FILL_ID: AtomicU64 = AtomicU64::new(0);
+        // Next to the file, so committing it is a rename within a directory
+        let shard = layout::shard_dir(&self.dir, hash);
+        let partial = shard.join(format!(
+            "{}.{}.{}{}",
+            hash,
+            std::process::id(),
+            FILL_ID.fetch_add(1, Ordering::Relaxed),
+            PARTIAL_SUFFIX
+        ));
+        tokio::fs::create_dir_all(&shard).await?;
+        let file = match tokio::fs::File::create(&partial).await {
+            Ok(file) => file,
+            Err(e) => {
+                let _ = tokio::fs::remove_file(&partial).await;
+                return Err(e);
+            }
+        };
+        Ok(Box::new(DiskFill {
+            file,
+            partial,
+            path: self.path(hash),
+        }))
+    }
+
+    async fn remove(&self, hash: &str) -> io::Result<()> {
+        let _ = tokio::fs::remove_file(self.metadata_path(hash)).await;
+        tokio::fs::remove_file(self.path(hash)).await
+    }
+
+    async fn set_aside(&self, hash: &str) -> io::Result<()> {
+        tokio::fs::rename(self.path(hash), self.deleted_path(hash)).await
+    }
+
+    async fn bring_back(&self, hash: &str) -> io::Result<()> {
+        tokio::fs::rename(self.deleted_path(hash), self.path(hash)).await
+    }
+
+    async fn discard(&self, hash: &str) -> io::Result<()> {
+        let _ = tokio::fs::remove_file(self.metadata_path(hash)).await;
+        tokio::fs::remove_file(self.deleted_path(hash)).await
+    }
+
+    async fn supersede(&self, hash: &str) -> io::Result<()> {
+        tokio::fs::remove_file(self.deleted_path(hash)).await
+    }
+
+    async fn read_metadata(&self, hash: &str) -> io::Result<Vec<u8>> {
+        tokio::fs::read(self.metadata_path(hash)).await
+    }
+
+    async fn write_metadata(&self, hash: &str, metadata: Option<Vec<u8>>) -> io::Result<()> {
+        let path = self.metadata_path(hash);
+        let Some(metadata) = metadata else {
+            return match tokio::fs::remove_file(&path).await {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+                _ => Ok(()),
+            };
+        };
+        // Written aside and renamed, so a crash never leaves half a sidecar
+        let partial = layout::shard_dir(&self.dir, hash)
+            .join(format!("{}{}{}", hash, METADATA_SUFFIX, PARTIAL_SUFFIX));
+        tokio::fs::write(&partial, metadata).await?;
+        tokio::fs::rename(&partial, &path).await
+    }
+
+    async fn check_writable(&self) -> Result<String, String> {
+        static PROBES: AtomicU64 = AtomicU64::new(0);
+        // A partial file, so a crash leaves nothing the index would pick up
+        let probe = self.dir.join(format!(
+            "readyz-{}-{}{}",
+            std::process::id(),
+            PROBES.fetch_add(1, Ordering::Relaxed),
+            PARTIAL_SUFFIX
+        ));
+        tokio::fs::write(&probe, b"ready")
+            .await
+            .map_err(|e| format!("{} is not writable: {}", self.dir.display(), e))?;
+        tokio::fs::remove_file(&probe)
+            .await
+            .map_err(|e| format!("cannot remove {}: {}", probe.display(), e))?;
+        Ok(format!("{} is writable", self.dir.display()))
+    }
+}
+
+/// Index the files of the cache directory and drop leftover partial fills
+fn scan(dir: &Path) -> io::Result<Contents> {
+    let mut dir_entries = Vec::new();
+    for dir_entry in std::fs::read_dir(dir)?.flatten() {
+        let name = dir_entry.file_name().to_string_lossy().into_owned();
+        if layout::is_shard(&name) {
+            match std::fs::read_dir(dir_entry.path()) {
+                Ok(shard) => dir_entries.extend(shard.flatten()),
+                Err(e) => warn!("Skipping unreadable cache shard {}: {}", name, e),
+            }
+        } else if name.ends_with(PARTIAL_SUFFIX) {
+            let _ = std::fs::remove_file(dir_entry.path());
+        }
+    }
+    let mut contents = Contents::default();
+    for dir_entry in dir_entries {
+        let name = dir_entry.file_name().to_string_lossy().into_owned();
+        let Ok(metadata) = dir_entry.metadata() else {
+            continue;
+        };
+        let (hash, deleted) = if name.ends_with(PARTIAL_SUFFIX) {
+            let _ = std::fs::remove_file(dir_entry.path());
+            continue;
+        } else if let Some(hash) = name.strip_suffix(DELETED_SUFFIX).filter(|h| is_hash(h)) {
+            (hash.to_string(), true)
+        } else if let Some(hash) = name.strip_suffix(METADATA_SUFFIX) {
+            contents.metadata.push(hash.to_string());
+            continue;
+        } else if metadata.is_file() && is_hash(&name) {
+            (name, false)
+        } else {
+            continue;
+        };
+        contents.files.push(Stored {
+            hash,
+            size: metadata.len(),
+            modified: metadata.modified().unwrap_or(UNIX_EPOCH),
+            deleted,
+        });
+    }
+    Ok(contents)
+}
+
+struct DiskFill {
+    file: tokio::fs::File,
+    partial: PathBuf,
+    path: PathBuf,
+}
+
+#[async_trait]
+impl Fill for DiskFill {
+    async fn write(&mut self, chunk: Bytes) -> io::Result<()> {
+        self.file.write_all(&chunk).await?;
+        // Tokio writes in the background; wait until it is in the file
+        self.file.flush().await
+    }
+
+    fn partial(&self) -> Option<PathBuf> {
+        Some(self.partial.clone())
+    }
+
+    async fn commit(self: Box<Self>) -> io::Result<()> {
+        let result = async {
+            self.file.sync_all().await?;
+            tokio::fs::rename(&self.partial, &self.path).await
+        }
+        .await;
+        if result.is_err() {
+            let _ = tokio::fs::remove_file(&self.partial).await;
+        }
+        result
+    }
+
+    async fn abort(self: Box<Self>) {
+        let _ = tokio::fs::remove_file(&self.partial).await;
+    }
+}
@@ -0,0 +1,620 @@
+//! Encryption at rest of cached files, `CACHE_ENCRYPTION_KEY`
+//!
+//! [`EncryptedStore`] wraps the disk or S3 store so that no file reaches it
+//! in the clear. Each file gets its own random 256-bit data key, wrapped
+//! with AES-256-GCM under the operator's master key and kept in the file's
+//! header, bound to the file's hash so headers can't be swapped between
+//! files. The body is sealed in segments of 64 KiB, each with AES-256-GCM
+//! under the data key and its number as nonce; the last one is flagged, so
+//! a file cut at a segment boundary doesn't pass for a shorter one. A range
+//! only decrypts the segments it covers.
+//!
+//! ```text
+//! "XPE1" | wrapping nonce (12) | wrapped data key (32 + 16) | segment 0 + tag | segment 1 + tag | ...
+//! ```
+//!
+//! The store reports the size of what it holds; sizes are mapped back to
+//! those of the files. Metadata sidecars are kept in the clear. Files the
+//! master key doesn't open (a plaintext cache, another key) are dropped
+//! when first read, like any unreadable file.
+
+use super::store::{CacheStore, Contents, Fill};
+use crate::downloader::Download;
+use async_trait::async_trait;
+use axum::body::Bytes;
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM};
+use ring::rand::{SecureRandom, SystemRandom};
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::SystemTime;
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use tokio_stream::StreamExt;
+use tracing::warn;
+
+const MAGIC: &[u8; 4] = b"XPE1";
+const NONCE_LEN: usize = 12;
+const KEY_LEN: usize = 32;
+const TAG: u64 = 16;
+/// Magic, wrapping nonce and wrapped data key with its tag
+const HEADER: u64 = MAGIC.len() as u64 + NONCE_LEN as u64 + KEY_LEN as u64 + TAG;
+/// Bytes of a file sealed together
+const SEGMENT: u64 = 64 << 10;
+const SEALED_SEGMENT: u64 = SEGMENT + TAG;
+
+/// Operator key wrapping the data keys
+#[derive(Clone)]
+pub struct MasterKey(Arc<LessSafeKey>);
+
+impl MasterKey {
+    /// Load `CACHE_ENCRYPTION_KEY` or `CACHE_ENCRYPTION_KEY_FILE` (64 hex
+    /// characters); `None` if neither is set
+    pub fn from_env() -> Option<Self> {
+        let encoded = match (
+            std::env::var("CACHE_ENCRYPTION_KEY").ok(),
+            std::env::var("CACHE_ENCRYPTION_KEY_FILE").ok(),
+        ) {
+            (None, None) => return None,
+            (Some(key), None) => key,
+            (None, Some(path)) => std::fs::read_to_string(&path).unwrap_or_else(|e| {
+                panic!("Failed to read CACHE_ENCRYPTION_KEY_FILE {}: {}", path, e)
+            }),
+            (Some(_), Some(_)) => {
+                panic!("CACHE_ENCRYPTION_KEY and CACHE_ENCRYPTION_KEY_FILE are exclusive; set one")
+            }
+        };
+        let key = hex::decode(encoded.trim())
+            .ok()
+            .filter(|key| key.len() == KEY_LEN)
+            .expect("CACHE_ENCRYPTION_KEY must be 64 hex characters (a 256-bit key)");
+        Some(Self(Arc::new(aes_key(&key))))
+    }
+}
+
+/// Cache store sealing the files of another
+pub struct EncryptedStore {
+    inner: Arc<dyn CacheStore>,
+    master: MasterKey,
+    random: SystemRandom,
+}
+
+impl EncryptedStore {
+    pub fn new(inner: Arc<dyn CacheStore>, master: MasterKey) -> Self {
+        Self {
+            inner,
+            master,
+            random: SystemRandom::new(),
+        }
+    }
+
+    /// Unwrap the data key in the header of a stored file
+    async fn data_key(&self, hash: &str) -> io::Result<LessSafeKey> {
+        let header = collect(self.inner.open(hash, 0, HEADER).await?).await?;
+        if header.len() != HEADER as usize || !header.starts_with(MAGIC) {
+            return Err(invalid("not an encrypted cache file"));
+        }
+        let (nonce, wrapped) = header[MAGIC.len()..].split_at(NONCE_LEN);
+        let nonce = Nonce::try_assume_unique_for_key(nonce).map_err(|_| invalid("bad nonce"))?;
+        let mut wrapped = wrapped.to_vec();
+        let key = self
+            .master
+            .0
+            .open_in_place(nonce, Aad::from(hash.as_bytes()), &mut wrapped)
+            .map_err(|_| invalid("the data key doesn't unwrap with CACHE_ENCRYPTION_KEY"))?;
+        Ok(aes_key(key))
+    }
+}
+
+#[async_trait]
+impl CacheStore for EncryptedStore {
+    fn describe(&self) -> String {
+        format!("{} (encrypted)", self.inner.describe())
+    }
+
+    fn shared(&self) -> bool {
+        self.inner.shared()
+    }
+
+    async fn scan(&self) -> io::Result<Contents> {
+        let mut contents = self.inner.scan().await?;
+        let found = contents.files.len();
+        contents
+            .files
+            .retain_mut(|file| match plain_size(file.size) {
+                Some(size) => {
+                    file.size = size;
+                    true
+                }
+                None => false,
+            });
+        let ignored = found - contents.files.len();
+        if ignored > 0 {
+            warn!(
+                "Ignoring {} cached files at {} of a size no encrypted file has",
+                ignored,
+                self.inner.describe()
+            );
+        }
+        Ok(contents)
+    }
+
+    async fn stat(&self, hash: &str) -> io::Result<Option<(u64, SystemTime)>> {
+        match self.inner.stat(hash).await? {
+            Some((sealed, modified)) => match plain_size(sealed) {
+                Some(size) => Ok(Some((size, modified))),
+                None => Err(invalid("not an encrypted cache file")),
+            },
+            None => Ok(None),
+        }
+    }
+
+    async fn open(&self, hash: &str, start: u64, length: u64) -> io::Result<Download> {
+        let (size, _) = self
+            .stat(hash)
+            .await?
+            .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))?;
+        if start.saturating_add(length) > size {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "range beyond the file",
+            ));
+        }
+        let key = self.data_key(hash).await?;
+        let plain_bytes = Arc::new(AtomicU64::new(0));
+        let (sender, receiver) = mpsc::channel(4);
+        if length > 0 {
+            let first = start / SEGMENT;
+            let last = (start + length - 1) / SEGMENT;
+            let sealed_start = HEADER + first * SEALED_SEGMENT;
+            let sealed_end = (HEADER + (last + 1) * SEALED_SEGMENT).min(sealed_size(size));
+            let sealed = self
+                .inner
+                .open(hash, sealed_start, sealed_end - sealed_start)
+                .await?;
+            let reader = Reader {
+                store: self.inner.clone(),
+                hash: hash.to_string(),
+                key,
+                index: first,
+                final_index: segments(size) - 1,
+                skip: start - first * SEGMENT,
+                remaining: length,
+            };
+            tokio::spawn(reader.run(sealed, sender, plain_bytes.clone()));
+        }
+        Ok(Download {
+            body: Box::pin(ReceiverStream::new(receiver)),
+            length: Some(length),
+            upstream_bytes: plain_bytes,
+            cached: None,
+            peer: false,
+        })
+    }
+
+    async fn create(&self, hash: &str) -> io::Result<Box<dyn Fill>> {
+        let mut data_key = [0u8; KEY_LEN];
+        let mut nonce = [0u8; NONCE_LEN];
+        self.random
+            .fill(&mut data_key)
+            .map_err(|_| io::Error::other("no randomness"))?;
+        self.random
+            .fill(&mut nonce)
+            .map_err(|_| io::Error::other("no randomness"))?;
+        let mut wrapped = data_key.to_vec();
+        self.master
+            .0
+            .seal_in_place_append_tag(
+                Nonce::assume_unique_for_key(nonce),
+                Aad::from(hash.as_bytes()),
+                &mut wrapped,
+            )
+            .map_err(|_| io::Error::other("failed to wrap the data key"))?;
+        let mut header = Vec::with_capacity(HEADER as usize);
+        header.extend_from_slice(MAGIC);
+        header.extend_from_slice(&nonce);
+        header.extend_from_slice(&wrapped);
+
+        let mut inner = self.inner.create(hash).await?;
+        if let Err(e) = inner.write(Bytes::from(header)).await {
+            inner.abort().await;
+            return Err(e);
+        }
+        Ok(Box::new(EncryptedFill {
+            inner,
+            key: aes_key(&data_key),
+            index: 0,
+            pending: Vec::with_capacity(2 * SEGMENT as usize),
+        }))
+    }
+
+    async fn remove(&self, hash: &str) -> io::Result<()> {
+        self.inner.remove(hash).await
+    }
+
+    async fn set_aside(&self, hash: &str) -> io::Result<()> {
+        self.inner.set_aside(hash).await
+    }
+
+    async fn bring_back(&self, hash: &str) -> io::Result<()> {
+        self.inner.bring_back(hash).await
+    }
+
+    async fn discard(&self, hash: &str) -> io::Result<()> {
+        self.inner.discard(hash).await
+    }
+
+    async fn supersede(&self, hash: &str) -> io::Result<()> {
+        self.inner.supersede(hash).await
+    }
+
+    async fn read_metadata(&self, hash: &str) -> io::Result<Vec<u8>> {
+        self.inner.read_metadata(hash).await
+    }
+
+    async fn write_metadata(&self, hash: &str, metadata: Option<Vec<u8>>) -> io::Result<()> {
+        self.inner.write_metadata(hash, metadata).await
+    }
+
+    async fn check_writable(&self) -> Result<String, String> {
+        self.inner.check_writable().await
+    }
+}
+
+/// A file being sealed, one segment behind the writes: the last segment is
+/// only known at commit
+struct EncryptedFill {
+    inner: Box<dyn Fill>,
+    key: LessSafeKey,
+    index: u64,
+    pending: Vec<u8>,
+}
+
+impl EncryptedFill {
+    async fn seal_segment(&mut self, len: usize, last: bool) -> io::Result<()> {
+        let mut segment: Vec<u8> = self.pending.drain(..len).collect();
+        self.key
+            .seal_in_place_append_tag(nonce(self.index, last), Aad::empty(), &mut segment)
+            .map_err(|_| io::Error::other("failed to seal a segment"))?;
+        self.index += 1;
+        self.inner.write(Bytes::from(segment)).await
+    }
+}
+
+#[async_trait]
+impl Fill for EncryptedFill {
+    async fn write(&mut self, chunk: Bytes) -> io::Result<()> {
+        self.pending.extend_from_slice(&chunk);
+        while self.pending.len() as u64 > SEGMENT {
+            self.seal_segment(SEGMENT as usize, false).await?;
+        }
+        Ok(())
+    }
+
+    async fn commit(mut self: Box<Self>) -> io::Result<()> {
+        let len = self.pending.len();
+        if let Err(e) = self.seal_segment(len, true).await {
+            self.inner.abort().await;
+            return Err(e);
+        }
+        self.inner.commit().await
+    }
+
+    async fn abort(self: Box<Self>) {
+        self.inner.abort().await
+    }
+}
+
+/// Decryption of the segments a range covers
+struct Reader {
+    /// Where a file failing to decrypt is removed from, so the next request
+    /// fetches it again
+    store: Arc<dyn CacheStore>,
+    hash: String,
+    key: LessSafeKey,
+    /// Number of the next segment
+    index: u64,
+    /// Number of the file's last segment
+    final_index: u64,
+    /// Bytes of the first segment before the range
+    skip: u64,
+    remaining: u64,
+}
+
+impl Reader {
+    async fn run(
+        mut self,
+        mut sealed: Download,
+        sender: mpsc::Sender<io::Result<Bytes>>,
+        plain_bytes: Arc<AtomicU64>,
+    ) {
+        let mut pending = Vec::new();
+        let mut ended = false;
+        while self.remaining > 0 {
+            let full = pending.len() as u64 >= SEALED_SEGMENT;
+            if !full && !ended {
+                match sealed.body.next().await {
+                    Some(Ok(chunk)) => pending.extend_from_slice(&chunk),
+                    Some(Err(e)) => {
+                        let _ = sender.send(Err(e)).await;
+                        return;
+                    }
+                    None => ended = true,
+                }
+                continue;
+            }
+            let len = pending.len().min(SEALED_SEGMENT as usize);
+            let mut segment: Vec<u8> = pending.drain(..len).collect();
+            let last = self.index == self.final_index;
+            let opened =
+                self.key
+                    .open_in_place(nonce(self.index, last), Aad::empty(), &mut segment);
+            let plain = match opened {
+                Ok(plain) => &*plain,
+                Err(_) => {
+                    warn!(
+                        "Segment {} of cached {} failed to decrypt (damaged or truncated), removing it",
+                        self.index, self.hash
+                    );
+                    let _ = self.store.remove(&self.hash).await;
+                    let _ = sender
+                        .send(Err(invalid("cached file failed to decrypt")))
+                        .await;
+                    return;
+                }
+            };
+            self.index += 1;
+            let skipped = self.skip.min(plain.len() as u64) as usize;
+            self.skip -= skipped as u64;
+            let take = self.remaining.min((plain.len() - skipped) as u64) as usize;
+            self.remaining -= take as u64;
+            if take == 0 {
+                continue;
+            }
+            plain_bytes.fetch_add(take as u64, Ordering::Relaxed);
+            let bytes = Bytes::copy_from_slice(&plain[skipped..skipped + take]);
+            if sender.send(Ok(bytes)).await.is_err() {
+                return;
+            }
+        }
+    }
+}
+
+/// Nonce of a segment: its number, and whether it is the last
+fn nonce(index: u64, last: bool) -> Nonce {
+    let mut nonce = [0u8; NONCE_LEN];
+    nonce[..8].copy_from_slice(&index.to_be_bytes());
+    nonce[NONCE_LEN - 1] = u8::from(last);
+    Nonce::assume_unique_for_key(nonce)
+}
+
+fn aes_key(key: &[u8]) -> LessSafeKey {
+    LessSafeKey::new(UnboundKey::new(&AES_256_GCM, key).expect("AES-256 keys are 32 bytes"))
+}
+
+/// Segments of a file of `size` bytes; an empty file has one, empty
+fn segments(size: u64) -> u64 {
+    size.div_ceil(SEGMENT).max(1)
+}
+
+/// Bytes stored for a file of `size` bytes
+fn sealed_size(size: u64) -> u64 {
+    HEADER + size + segments(size) * TAG
+}
+
+/// Size of the file stored as `sealed` bytes, if any file is
+fn plain_size(sealed: u64) -> Option<u64> {
+    let body = sealed.checked_sub(HEADER)?;
+    let full = body / SEALED_SEGMENT;
+    let size = match body % SEALED_SEGMENT {
+        0 if full > 0 => full * SEGMENT,
+        // Only an empty file ends with an empty segment
+        TAG if full == 0 => 0,
+        rest if rest > TAG => full * SEGMENT + rest - TAG,
+        _ => return None,
+    };
+    Some(size)
+}
+
+async fn collect(download: Download) -> io::Result<Vec<u8>> {
+    let mut body = download.body;
+    let mut bytes = Vec::new();
+    while let Some(chunk) = body.next().await {
+        bytes.extend_from_slice(&chunk?);
+    }
+    Ok(bytes)
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::disk::DiskStore;
+
+    const HASH: &str = "aa00000000000000000000000000000000000000000000000000000000000000";
+    const OTHER_HASH: &str = "bb00000000000000000000000000000000000000000000000000000000000000";
+    /// Three full segments and a short last one
+    const SIZE: u64 = 3 * SEGMENT + 5;
+
+    fn master(byte: u8) -> MasterKey {
+        MasterKey(Arc::new(aes_key(&[byte; KEY_LEN])))
+    }
+
+    /// An encrypted store over a disk store, returned too
+    fn stores(dir: &tempfile::TempDir) -> (EncryptedStore, Arc<dyn CacheStore>) {
+        let inner: Arc<dyn CacheStore> = Arc::new(DiskStore::open(dir.path().to_path_buf()));
+        (EncryptedStore::new(inner.clone(), master(1)), inner)
+    }
+
+    fn content(size: u64) -> Vec<u8> {
+        (0..size).map(|i| (i * 31 % 251) as u8).collect()
+    }
+
+    /// Store `data` as `hash`, in writes of odd sizes
+    async fn put(store: &dyn CacheStore, hash: &str, data: &[u8]) {
+        let mut fill = store.create(hash).await.unwrap();
+        for chunk in data.chunks(10_007) {
+            fill.write(Bytes::copy_from_slice(chunk)).await.unwrap();
+        }
+        fill.commit().await.unwrap();
+    }
+
+    async fn read(
+        store: &dyn CacheStore,
+        hash: &str,
+        start: u64,
+        length: u64,
+    ) -> io::Result<Vec<u8>> {
+        collect(store.open(hash, start, length).await?).await
+    }
+
+    #[tokio::test]
+    async fn round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let (store, inner) = stores(&dir);
+        for size in [0, 1, SEGMENT - 1, SEGMENT, SEGMENT + 1, SIZE] {
+            let data = content(size);
+            put(&store, HASH, &data).await;
+            let (sealed, _) = inner.stat(HASH).await.unwrap().unwrap();
+            assert_eq!(sealed, sealed_size(size));
+            assert_eq!(store.stat(HASH).await.unwrap().unwrap().0, size);
+            assert_eq!(
+                read(&store, HASH, 0, size).await.unwrap(),
+                data,
+                "{} bytes",
+                size
+            );
+            // Nothing of the file is stored in the clear
+            let raw = read(&*inner, HASH, 0, sealed).await.unwrap();
+            assert!(size < 16 || !raw.windows(16).any(|w| w == &data[..16]));
+        }
+    }
+
+    #[tokio::test]
+    async fn ranges_across_segment_boundaries() {
+        let dir = tempfile::tempdir().unwrap();
+        let (store, _) = stores(&dir);
+        let data = content(SIZE);
+        put(&store, HASH, &data).await;
+
+        let ranges = [
+            (0, 1),
+            (SEGMENT - 1, 1),
+            (SEGMENT - 1, 2),
+            (SEGMENT, SEGMENT),
+            (SEGMENT - 3, SEGMENT + 6),
+            (SEGMENT + 3, 2 * SEGMENT),
+            (3 * SEGMENT, 5),
+            (SIZE - 1, 1),
+            (SIZE, 0),
+        ];
+        for (start, length) in ranges {
+            let range = (start as usize)..(start + length) as usize;
+            assert_eq!(
+                read(&store, HASH, start, length).await.unwrap(),
+                data[range],
+                "{} bytes from {}",
+                length,
+                start
+            );
+        }
+        let beyond = read(&store, HASH, SIZE - 1, 2).await.unwrap_err();
+        assert_eq!(beyond.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[tokio::test]
+    async fn tampered_segment_fails_without_its_plaintext() {
+        let dir = tempfile::tempdir().unwrap();
+        let (store, inner) = stores(&dir);
+        let data = content(SIZE);
+        put(&store, HASH, &data).await;
+
+        // Flip a bit of the second segment
+        let mut raw = read(&*inner, HASH, 0, sealed_size(SIZE)).await.unwrap();
+        raw[(HEADER + SEALED_SEGMENT + 100) as usize] ^= 1;
+        put(&*inner, HASH, &raw).await;
+
+        let mut body = store.open(HASH, 0, SIZE).await.unwrap().body;
+        let mut received = Vec::new();
+        let error = loop {
+            match body.next().await {
+                Some(Ok(chunk)) => received.extend_from_slice(&chunk),
+                Some(Err(e)) => break e,
+                None => panic!("a tampered file read to its end"),
+            }
+        };
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        // Only the segment before the damage was delivered
+        assert_eq!(received, data[..SEGMENT as usize]);
+        // And the file is dropped, to be fetched again
+        assert!(inner.stat(HASH).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn file_cut_at_a_segment_boundary_fails() {
+        let dir = tempfile::tempdir().unwrap();
+        let (store, inner) = stores(&dir);
+        put(&store, HASH, &content(SIZE)).await;
+
+        // Passes for a file of two full segments, none flagged last
+        let cut = HEADER + 2 * SEALED_SEGMENT;
+        let raw = read(&*inner, HASH, 0, cut).await.unwrap();
+        put(&*inner, HASH, &raw).await;
+        assert_eq!(store.stat(HASH).await.unwrap().unwrap().0, 2 * SEGMENT);
+        let error = read(&store, HASH, SEGMENT, SEGMENT).await.unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn other_key_or_hash_does_not_open() {
+        let dir = tempfile::tempdir().unwrap();
+        let (store, inner) = stores(&dir);
+        let data = content(SIZE);
+        put(&store, HASH, &data).await;
+
+        let stranger = EncryptedStore::new(inner.clone(), master(2));
+        let error = read(&stranger, HASH, 0, SIZE).await.unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+
+        // The data key is bound to the hash: a file copied under another
+        // hash doesn't open either
+        let raw = read(&*inner, HASH, 0, sealed_size(SIZE)).await.unwrap();
+        put(&*inner, OTHER_HASH, &raw).await;
+        let error = read(&store, OTHER_HASH, 0, SIZE).await.unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+
+        // A plaintext file, as an unencrypted cache left it
+        put(&*inner, OTHER_HASH, &data).await;
+        assert!(read(&store, OTHER_HASH, 0, SIZE).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn every_file_has_its_own_key() {
+        let dir = tempfile::tempdir().unwrap();
+        let (store, inner) = stores(&dir);
+        let data = content(SEGMENT + 1);
+        let sealed = sealed_size(SEGMENT + 1);
+
+        put(&store, HASH, &data).await;
+        let first = read(&*inner, HASH, 0, sealed).await.unwrap();
+        put(&store, HASH, &data).await;
+        let second = read(&*inner, HASH, 0, sealed).await.unwrap();
+        put(&store, OTHER_HASH, &data).await;
+        let third = read(&*inner, OTHER_HASH, 0, sealed).await.unwrap();
+
+        let header = |raw: &[u8]| raw[MAGIC.len()..HEADER as usize].to_vec();
+        let body = |raw: &[u8]| raw[HEADER as usize..].to_vec();
+        for (a, b) in [(&first, &second), (&first, &third), (&second, &third)] {
+            // Wrapping nonce and wrapped key differ...
+            assert_ne!(header(a)[..NONCE_LEN], header(b)[..NONCE_LEN]);
+            assert_ne!(header(a)[NONCE_LEN..], header(b)[NONCE_LEN..]);
+            // ...and so does the same content sealed under the same segment
+            // nonces: the data keys are not the same
+            assert_ne!(body(a), body(b));
+        }
+        assert_eq!(read(&store, HASH, 0, SEGMENT + 1).await.unwrap(), data);
+    }
+}
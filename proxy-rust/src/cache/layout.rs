@@ -0,0 +1,156 @@
+//! Versioned on-disk layout of the cache
+//!
+//! `CACHE_DIR` records the version of its layout in a `LAYOUT_VERSION` file.
+//! A directory without one is version 1, the flat layout of earlier
+//! releases. Version 2 shards files into subdirectories named by the first
+//! two hex digits of their hash (`ab/abcd…`, with their `.meta.json`
+//! sidecars and `.deleted` copies next to them), so no directory lists
+//! hundreds of thousands of files.
+//!
+//! An older layout is upgraded in place at startup, one rename per file:
+//! nothing is copied, so a warm cache of any size migrates in moments and
+//! keeps every entry. The version is recorded only once every file has
+//! moved, so an interrupted migration resumes on the next start.
+//! `CACHE_AUTO_MIGRATE=false` refuses to start on an older layout instead,
+//! for operators who run `xet-proxy migrate-cache` themselves. A layout newer
+//! than this release knows is refused, rather than served as an empty cache.
+
+use super::{is_hash, DELETED_SUFFIX, METADATA_SUFFIX, PARTIAL_SUFFIX};
+use std::io;
+use std::path::{Path, PathBuf};
+use tracing::info;
+
+/// Layout written by this release
+pub const CURRENT: u32 = 2;
+const VERSION_FILE: &str = "LAYOUT_VERSION";
+
+/// Upgrade of a directory from one version to the next; returns how many
+/// files it moved
+type Step = fn(&Path) -> io::Result<usize>;
+
+/// Upgrades by the version they start from
+const MIGRATIONS: &[(u32, Step)] = &[(1, shard_flat_files)];
+
+/// Outcome of [`migrate`]
+pub struct Migration {
+    pub from: u32,
+    pub moved: usize,
+}
+
+/// Subdirectory of `dir` holding the files of `hash`
+pub fn shard_dir(dir: &Path, hash: &str) -> PathBuf {
+    let shard = hash
+        .get(..2)
+        .filter(|shard| shard.bytes().all(|b| b.is_ascii_hexdigit()))
+        .unwrap_or("_");
+    dir.join(shard)
+}
+
+/// Whether a directory entry named `name` is a shard
+pub fn is_shard(name: &str) -> bool {
+    name.len() == 2 && name.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+/// Layout version of `dir`
+pub fn version(dir: &Path) -> Result<u32, String> {
+    let path = dir.join(VERSION_FILE);
+    match std::fs::read_to_string(&path) {
+        Ok(text) => text
+            .trim()
+            .parse()
+            .map_err(|_| format!("{} is not a layout version", path.display())),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(1),
+        Err(e) => Err(format!("Failed to read {}: {}", path.display(), e)),
+    }
+}
+
+/// Upgrade `dir` to the current layout, if needed
+pub fn migrate(dir: &Path) -> Result<Migration, String> {
+    let from = version(dir)?;
+    if from > CURRENT {
+        return Err(format!(
+            "CACHE_DIR {} has layout {}, newer than the {} this release knows; upgrade the proxy or use another directory",
+            dir.display(),
+            from,
+            CURRENT
+        ));
+    }
+    let mut moved = 0;
+    for (version, step) in MIGRATIONS.iter().filter(|(version, _)| *version >= from) {
+        info!(
+            "Migrating CACHE_DIR {} from layout {} to {}",
+            dir.display(),
+            version,
+            version + 1
+        );
+        moved += step(dir).map_err(|e| {
+            format!(
+                "Migrating CACHE_DIR {} from layout {} failed, rerun to resume: {}",
+                dir.display(),
+                version,
+                e
+            )
+        })?;
+        record(dir, version + 1)
+            .map_err(|e| format!("Failed to record the layout of {}: {}", dir.display(), e))?;
+    }
+    Ok(Migration { from, moved })
+}
+
+/// Migrate if allowed by `CACHE_AUTO_MIGRATE` (default true), at startup
+pub fn prepare(dir: &Path) {
+    let auto = crate::config::flag("CACHE_AUTO_MIGRATE").unwrap_or(true);
+    let version = version(dir).unwrap_or_else(|e| panic!("{}", e));
+    if version < CURRENT && !auto {
+        panic!(
+            "CACHE_DIR {} has layout {} (current: {}) and CACHE_AUTO_MIGRATE is off; run `xet-proxy migrate-cache` first",
+            dir.display(),
+            version,
+            CURRENT
+        );
+    }
+    let migration = migrate(dir).unwrap_or_else(|e| panic!("{}", e));
+    if migration.from < CURRENT {
+        info!(
+            "CACHE_DIR {} migrated to layout {}: {} files moved",
+            dir.display(),
+            CURRENT,
+            migration.moved
+        );
+    }
+}
+
+/// Write the version aside and rename it, so it's never half written
+fn record(dir: &Path, version: u32) -> io::Result<()> {
+    let temp = dir.join(format!("{}{}", VERSION_FILE, PARTIAL_SUFFIX));
+    std::fs::write(&temp, format!("{}\n", version))?;
+    std::fs::rename(&temp, dir.join(VERSION_FILE))
+}
+
+/// 1 to 2: move flat files into their shards, and drop leftover partial
+/// fills
+fn shard_flat_files(dir: &Path) -> io::Result<usize> {
+    let mut moved = 0;
+    for dir_entry in std::fs::read_dir(dir)? {
+        let dir_entry = dir_entry?;
+        let name = dir_entry.file_name().to_string_lossy().into_owned();
+        if !dir_entry.file_type()?.is_file() {
+            continue;
+        }
+        if name.ends_with(PARTIAL_SUFFIX) {
+            let _ = std::fs::remove_file(dir_entry.path());
+            continue;
+        }
+        let Some((hash, suffix)) = name.split_at_checked(64) else {
+            continue;
+        };
+        if !is_hash(hash) || ![DELETED_SUFFIX, METADATA_SUFFIX, ""].contains(&suffix) {
+            continue;
+        }
+        let shard = shard_dir(dir, hash);
+        std::fs::create_dir_all(&shard)?;
+        std::fs::rename(dir_entry.path(), shard.join(&name))?;
+        moved += 1;
+    }
+    Ok(moved)
+}
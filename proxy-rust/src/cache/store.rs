@@ -0,0 +1,100 @@
+//! Where cached files are kept
+//!
+//! [`Cache`](super::Cache) indexes, evicts and reports files; a
+//! [`CacheStore`] holds their bytes. Each file is stored under its XET hash,
+//! with an optional metadata sidecar, and can be set aside as deleted and
+//! brought back. Fills are committed whole or not at all: nothing a reader
+//! can open is ever half written.
+
+use crate::downloader::Download;
+use async_trait::async_trait;
+use axum::body::Bytes;
+use std::io;
+use std::path::PathBuf;
+use std::time::SystemTime;
+
+/// A file found in the store
+pub struct Stored {
+    pub hash: String,
+    pub size: u64,
+    /// Last write: when the file was fetched, and its last use if found at
+    /// startup
+    pub modified: SystemTime,
+    /// Set aside by a deletion, restorable
+    pub deleted: bool,
+}
+
+/// What the store holds, as scanned at startup
+#[derive(Default)]
+pub struct Contents {
+    pub files: Vec<Stored>,
+    /// Hashes with a metadata sidecar
+    pub metadata: Vec<String>,
+}
+
+#[async_trait]
+pub trait CacheStore: Send + Sync {
+    /// Where the files are, for logs and `/cache`
+    fn describe(&self) -> String;
+
+    /// Whether other proxies write to the store too, so a file missing from
+    /// the index may still be there
+    fn shared(&self) -> bool {
+        false
+    }
+
+    /// List the files and sidecars, dropping leftovers of interrupted fills
+    async fn scan(&self) -> io::Result<Contents>;
+
+    /// Size and last write of a live (not deleted) file, if the store has it
+    async fn stat(&self, hash: &str) -> io::Result<Option<(u64, SystemTime)>>;
+
+    /// Read `length` bytes of a file from `start`
+    async fn open(&self, hash: &str, start: u64, length: u64) -> io::Result<Download>;
+
+    /// Start writing a file, invisible until committed
+    async fn create(&self, hash: &str) -> io::Result<Box<dyn Fill>>;
+
+    /// Delete a file and its metadata
+    async fn remove(&self, hash: &str) -> io::Result<()>;
+
+    /// Set a file aside as deleted
+    async fn set_aside(&self, hash: &str) -> io::Result<()>;
+
+    /// Make a file set aside live again
+    async fn bring_back(&self, hash: &str) -> io::Result<()>;
+
+    /// Delete a file set aside, with its metadata
+    async fn discard(&self, hash: &str) -> io::Result<()>;
+
+    /// Forget the deleted copy of a file that was just committed again,
+    /// keeping the new one
+    async fn supersede(&self, hash: &str) -> io::Result<()>;
+
+    async fn read_metadata(&self, hash: &str) -> io::Result<Vec<u8>>;
+
+    /// Replace a file's metadata sidecar; `None` removes it
+    async fn write_metadata(&self, hash: &str, metadata: Option<Vec<u8>>) -> io::Result<()>;
+
+    /// Write and remove a probe, for `/readyz`
+    async fn check_writable(&self) -> Result<String, String>;
+}
+
+/// A file being written
+#[async_trait]
+pub trait Fill: Send {
+    /// Write a chunk; once it returns, a reader of [`Self::partial`] sees it
+    async fn write(&mut self, chunk: Bytes) -> io::Result<()>;
+
+    /// Local file holding the bytes written so far, as written, if readers
+    /// may follow the fill there
+    fn partial(&self) -> Option<PathBuf> {
+        None
+    }
+
+    /// Make the file visible, replacing any live copy
+    async fn commit(self: Box<Self>) -> io::Result<()>;
+
+    /// Give up, leaving nothing behind
+    async fn abort(self: Box<Self>);
+}
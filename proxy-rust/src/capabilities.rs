@@ -0,0 +1,113 @@
+//! Upstream capabilities, as detected per endpoint
+//!
+//! Mirrors, internal hubs and CAS deployments don't all speak the same
+//! version of the APIs the native engine uses. Rather than assume, it
+//! records what each endpoint turned out to support the first times it
+//! was used, and adapts:
+//!
+//! - `ranged_reconstructions` (CAS): whether a reconstruction query honors
+//!   `Range`. The first query for a range not starting at 0 is compared
+//!   with the whole file's; a CAS that ignores `Range` gets whole-file
+//!   queries from then on, with the range cut out of the stream.
+//! - `reconstruction_api` (CAS): the reconstruction answer format, `v1` for
+//!   terms with presigned `fetch_info`. An answer in any other shape is
+//!   recorded as `unsupported` and refused with a clear error instead of
+//!   failing term by term.
+//! - `ranged_fetches` (xorb storage): whether presigned xorb URLs answer
+//!   `Range` with `206`. A store answering the whole object gets its range
+//!   cut out of the body.
+//! - `xet_hashes` (Hub): whether its tree API reports `xetHash`, `false`
+//!   until one is seen in a listing with files. A hub too old to report them
+//!   has nothing XET-enabled to list.
+//!
+//! `GET /version` reports them with the proxy's version and engine, for
+//! debugging mixed-version environments. The cli engine talks to CAS from
+//! the CLI, so with it nothing is detected.
+
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::info;
+use utoipa::ToSchema;
+
+/// Kinds of upstream endpoints
+pub const HUB: &str = "hub";
+pub const CAS: &str = "cas";
+pub const XORB_STORAGE: &str = "xorb_storage";
+
+/// What an endpoint was found to support; `None` until known
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, ToSchema)]
+pub struct EndpointCapabilities {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ranged_reconstructions: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reconstruction_api: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ranged_fetches: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub xet_hashes: Option<bool>,
+    /// Unix time a capability last changed
+    pub detected_at: u64,
+}
+
+/// Capabilities of every endpoint used so far, by kind and URL
+#[derive(Clone, Default)]
+pub struct Capabilities {
+    endpoints: Arc<Mutex<BTreeMap<(&'static str, String), EndpointCapabilities>>>,
+}
+
+impl Capabilities {
+    /// What is known about `endpoint` as a `kind` endpoint
+    pub fn get(&self, kind: &'static str, endpoint: &str) -> EndpointCapabilities {
+        let endpoints = self.endpoints.lock().unwrap();
+        let key = (kind, endpoint.to_string());
+        endpoints.get(&key).cloned().unwrap_or_default()
+    }
+
+    /// Record what `endpoint` was found to support as a `kind` endpoint,
+    /// logging any change
+    pub fn record(
+        &self,
+        kind: &'static str,
+        endpoint: &str,
+        detect: impl FnOnce(&mut EndpointCapabilities),
+    ) {
+        let mut endpoints = self.endpoints.lock().unwrap();
+        let known = endpoints.entry((kind, endpoint.to_string())).or_default();
+        let mut updated = known.clone();
+        detect(&mut updated);
+        if updated == *known {
+            return;
+        }
+        updated.detected_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+        info!(
+            "Upstream capabilities of {} {}: {:?}",
+            kind, endpoint, updated
+        );
+        *known = updated;
+    }
+
+    /// Capabilities by kind, then URL
+    pub fn report(&self) -> BTreeMap<&'static str, BTreeMap<String, EndpointCapabilities>> {
+        let mut report: BTreeMap<_, BTreeMap<_, _>> = BTreeMap::new();
+        for ((kind, endpoint), capabilities) in self.endpoints.lock().unwrap().iter() {
+            report
+                .entry(*kind)
+                .or_default()
+                .insert(endpoint.clone(), capabilities.clone());
+        }
+        report
+    }
+}
+
+/// `scheme://host[:port]` of a URL, the endpoint presigned URLs belong to
+pub fn origin(url: &str) -> &str {
+    let start = url.find("://").map_or(0, |i| i + 3);
+    let end = url[start..]
+        .find(['/', '?'])
+        .map_or(url.len(), |i| start + i);
+    &url[..end]
+}
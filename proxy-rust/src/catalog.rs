@@ -0,0 +1,130 @@
+//! Catalog of files seen in listings
+//!
+//! A hash download carries nothing but the hash, so on its own it is served
+//! as `<hash8>.bin` of unknown size. Every repository listing and upload
+//! records the files' hashes with their repository, path and size, so a
+//! later hash download of a known file gets the filename the template
+//! renders for it (with all repository fields), its `Content-Length`,
+//! `Range` support and a MIME type derived from its extension.
+//!
+//! Hashes name content, so an entry never goes stale; when the same content
+//! is listed under several paths, the latest sighting names it. At most
+//! `CATALOG_MAX_ENTRIES` (default 100000, `0` disables the catalog) hashes
+//! are remembered, the least recently recorded are forgotten first.
+
+use crate::listing::ListedFile;
+use crate::repo::RepoRef;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+
+const DEFAULT_MAX_ENTRIES: usize = 100_000;
+
+/// Where a hash was last seen
+#[derive(Clone, Debug)]
+pub struct CatalogEntry {
+    pub repo: RepoRef,
+    pub path: String,
+    pub size: u64,
+}
+
+#[derive(Default)]
+struct Entries {
+    by_hash: HashMap<String, (CatalogEntry, u64)>,
+    /// Hashes by the sequence number of their latest recording
+    order: BTreeMap<u64, String>,
+    next: u64,
+}
+
+#[derive(Clone)]
+pub struct Catalog {
+    max_entries: usize,
+    entries: Arc<Mutex<Entries>>,
+}
+
+impl Catalog {
+    /// Load `CATALOG_MAX_ENTRIES`
+    pub fn from_env() -> Self {
+        let max_entries =
+            crate::config::number("CATALOG_MAX_ENTRIES").unwrap_or(DEFAULT_MAX_ENTRIES);
+        Self {
+            max_entries,
+            entries: Arc::new(Mutex::new(Entries::default())),
+        }
+    }
+
+    /// Remember the files of a repository listing
+    pub fn record_listing(&self, repo: &RepoRef, files: &[ListedFile]) {
+        for file in files {
+            self.record(repo, &file.path, file.size, &file.xet_hash);
+        }
+    }
+
+    /// Remember one file
+    pub fn record(&self, repo: &RepoRef, path: &str, size: u64, hash: &str) {
+        if self.max_entries == 0 {
+            return;
+        }
+        let mut entries = self.entries.lock().unwrap();
+        let seq = entries.next;
+        entries.next += 1;
+        let entry = CatalogEntry {
+            repo: repo.clone(),
+            path: path.to_string(),
+            size,
+        };
+        if let Some((_, previous)) = entries.by_hash.insert(hash.to_string(), (entry, seq)) {
+            entries.order.remove(&previous);
+        }
+        entries.order.insert(seq, hash.to_string());
+        while entries.by_hash.len() > self.max_entries {
+            let Some((_, oldest)) = entries.order.pop_first() else {
+                break;
+            };
+            entries.by_hash.remove(&oldest);
+        }
+    }
+
+    /// Where a hash was last seen, if anywhere
+    pub fn get(&self, hash: &str) -> Option<CatalogEntry> {
+        let entries = self.entries.lock().unwrap();
+        entries.by_hash.get(hash).map(|(entry, _)| entry.clone())
+    }
+}
+
+/// MIME type of a file, from the extension of its path
+pub fn content_type(path: &str) -> &'static str {
+    let basename = path.rsplit('/').next().unwrap_or(path);
+    let extension = basename
+        .rsplit_once('.')
+        .map(|(_, extension)| extension.to_ascii_lowercase())
+        .unwrap_or_default();
+    match extension.as_str() {
+        "json" => "application/json",
+        "jsonl" => "application/jsonl",
+        "txt" => "text/plain; charset=utf-8",
+        "md" => "text/markdown; charset=utf-8",
+        "csv" => "text/csv; charset=utf-8",
+        "tsv" => "text/tab-separated-values; charset=utf-8",
+        "yaml" | "yml" => "application/yaml",
+        "py" => "text/x-python; charset=utf-8",
+        "html" | "htm" => "text/html; charset=utf-8",
+        "xml" => "application/xml",
+        "pdf" => "application/pdf",
+        "svg" => "image/svg+xml",
+        "parquet" => "application/vnd.apache.parquet",
+        "arrow" => "application/vnd.apache.arrow.file",
+        "zip" => "application/zip",
+        "gz" => "application/gzip",
+        "tar" => "application/x-tar",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "wav" => "audio/wav",
+        "mp3" => "audio/mpeg",
+        "flac" => "audio/flac",
+        "mp4" => "video/mp4",
+        // Weights (gguf, safetensors, bin, onnx, ...) and anything unknown
+        _ => "application/octet-stream",
+    }
+}
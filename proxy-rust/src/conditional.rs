@@ -0,0 +1,178 @@
+//! Conditional requests
+//!
+//! File responses carry a strong `ETag`: the quoted XET hash, which names the
+//! content itself, so it is also what `/list` reports. A request whose
+//! `If-None-Match` lists it (or `*`) gets `304 Not Modified` without anything
+//! being fetched; only `GET` and `HEAD` are answered that way. `If-Range` keeps a `Range` only while it names the current
+//! ETag; a changed file, a weak tag or a date (there is no `Last-Modified` to
+//! compare it to) gets the whole file instead, so a resumed download never
+//! splices bytes of two versions together. Hash URLs name fixed content and
+//! are additionally marked `immutable`, so clients can keep them.
+
+use axum::body::Body;
+use axum::http::{header, HeaderMap, Method, StatusCode};
+use axum::response::Response;
+
+/// Strong entity tag of a file with XET hash `hash`
+pub fn etag(hash: &str) -> String {
+    format!("\"{}\"", hash)
+}
+
+/// Validators a request is conditional on
+#[derive(Clone, Debug, Default)]
+pub struct Conditions {
+    if_none_match: Option<String>,
+    if_range: Option<String>,
+}
+
+impl Conditions {
+    pub fn from_headers(headers: &HeaderMap) -> Self {
+        let value = |name| {
+            headers
+                .get(name)
+                .and_then(|v| v.to_str().ok())
+                .map(|v| v.trim().to_string())
+        };
+        Self {
+            if_none_match: value(header::IF_NONE_MATCH),
+            if_range: value(header::IF_RANGE),
+        }
+    }
+
+    /// Whether a `method` request can be answered with `304 Not Modified`,
+    /// the client already having the version tagged `etag`
+    pub fn not_modified(&self, method: &Method, etag: &str) -> bool {
+        if method != Method::GET && method != Method::HEAD {
+            return false;
+        }
+        // If-None-Match uses the weak comparison
+        self.if_none_match.as_deref().is_some_and(|tags| {
+            tags == "*"
+                || tags
+                    .split(',')
+                    .map(|tag| tag.trim())
+                    .any(|tag| tag.strip_prefix("W/").unwrap_or(tag) == etag)
+        })
+    }
+
+    /// Whether a `Range` may be served for the version tagged `etag`
+    pub fn range_applies(&self, etag: &str) -> bool {
+        // If-Range uses the strong comparison, so weak tags never match
+        self.if_range.as_deref().is_none_or(|tag| tag == etag)
+    }
+}
+
+/// `304 Not Modified` for a file tagged `etag`
+pub fn not_modified_response(etag: &str) -> Response {
+    Response::builder()
+        .status(StatusCode::NOT_MODIFIED)
+        .header(header::ETAG, etag)
+        .body(Body::empty())
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HASH: &str = "4f3c2b1a";
+
+    fn conditions(headers: &[(header::HeaderName, &str)]) -> Conditions {
+        let mut map = HeaderMap::new();
+        for (name, value) in headers {
+            map.insert(name, value.parse().unwrap());
+        }
+        Conditions::from_headers(&map)
+    }
+
+    fn if_none_match(value: &str) -> Conditions {
+        conditions(&[(header::IF_NONE_MATCH, value)])
+    }
+
+    fn if_range(value: &str) -> Conditions {
+        conditions(&[(header::IF_RANGE, value)])
+    }
+
+    #[test]
+    fn etag_is_the_quoted_hash() {
+        assert_eq!(etag(HASH), "\"4f3c2b1a\"");
+    }
+
+    #[test]
+    fn if_none_match_uses_the_weak_comparison() {
+        let tag = etag(HASH);
+        assert!(if_none_match("\"4f3c2b1a\"").not_modified(&Method::GET, &tag));
+        assert!(if_none_match("W/\"4f3c2b1a\"").not_modified(&Method::GET, &tag));
+        assert!(!if_none_match("\"other\"").not_modified(&Method::GET, &tag));
+        assert!(!if_none_match("W/\"other\"").not_modified(&Method::GET, &tag));
+        // The tag is compared quoted
+        assert!(!if_none_match("4f3c2b1a").not_modified(&Method::GET, &tag));
+        assert!(!Conditions::default().not_modified(&Method::GET, &tag));
+    }
+
+    #[test]
+    fn if_none_match_accepts_star_and_lists() {
+        let tag = etag(HASH);
+        assert!(if_none_match("*").not_modified(&Method::GET, &tag));
+        assert!(if_none_match(" * ").not_modified(&Method::GET, &tag));
+        assert!(if_none_match("\"a\", \"4f3c2b1a\"").not_modified(&Method::GET, &tag));
+        assert!(if_none_match("\"a\",W/\"4f3c2b1a\" , \"b\"").not_modified(&Method::GET, &tag));
+        assert!(!if_none_match("\"a\", \"b\"").not_modified(&Method::GET, &tag));
+    }
+
+    #[test]
+    fn only_get_and_head_are_not_modified() {
+        let tag = etag(HASH);
+        for (method, expected) in [
+            (Method::GET, true),
+            (Method::HEAD, true),
+            (Method::POST, false),
+            (Method::PUT, false),
+            (Method::PATCH, false),
+            (Method::DELETE, false),
+        ] {
+            assert_eq!(
+                if_none_match("*").not_modified(&method, &tag),
+                expected,
+                "{method}"
+            );
+            assert_eq!(
+                if_none_match(&tag).not_modified(&method, &tag),
+                expected,
+                "{method}"
+            );
+        }
+    }
+
+    #[test]
+    fn not_modified_response_carries_the_etag() {
+        let tag = etag(HASH);
+        let response = not_modified_response(&tag);
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(response.headers()[header::ETAG], tag.as_str());
+    }
+
+    #[test]
+    fn if_range_uses_the_strong_comparison() {
+        let tag = etag(HASH);
+        assert!(Conditions::default().range_applies(&tag));
+        assert!(if_range("\"4f3c2b1a\"").range_applies(&tag));
+        assert!(if_range(" \"4f3c2b1a\" ").range_applies(&tag));
+        // A weak tag never matches, even for the same hash
+        assert!(!if_range("W/\"4f3c2b1a\"").range_applies(&tag));
+    }
+
+    #[test]
+    fn mismatched_if_range_falls_back_to_the_whole_file() {
+        let tag = etag(HASH);
+        // Another version, a list (not allowed in If-Range), or a date
+        for value in [
+            "\"other\"",
+            "\"4f3c2b1a\", \"other\"",
+            "*",
+            "Wed, 21 Oct 2015 07:28:00 GMT",
+        ] {
+            assert!(!if_range(value).range_applies(&tag), "{value}");
+        }
+    }
+}
@@ -0,0 +1,718 @@
+//! Configuration file
+//!
+//! Every setting is an environment variable, read by the subsystem that owns
+//! it. `--config <file>` (or `PROXY_CONFIG`) names a TOML or YAML file
+//! (picked by extension) grouping them into sections:
+//!
+//! ```toml
+//! [server]
+//! port = 8080
+//!
+//! [engine]
+//! bin_path = "/usr/local/bin/xet-download"
+//! workers = 4
+//!
+//! [cache]
+//! dir = "/var/cache/xet-proxy"
+//! max_bytes = 100_000_000_000
+//!
+//! [limits]
+//! max_concurrent_downloads = 16
+//!
+//! [env]
+//! RUST_LOG = "info"
+//! ```
+//!
+//! Each key stands for one variable (see [`SETTINGS`]); `[env]` sets any
+//! other variable by name. The file is loaded before anything else and fills
+//! in the variables that are not already set, so the environment overrides
+//! the file. Keys are typed and unknown ones are refused, with the line at
+//! fault; values are then validated by their loaders as if they came from
+//! the environment. Loaders read booleans with [`flag`] and integers with
+//! [`number`] or [`positive`], so a bad value is refused the same way
+//! whichever subsystem owns it.
+//!
+//! `GET /config` returns the effective configuration, in the same shape,
+//! with secrets redacted. `xet-proxy config-schema` prints a JSON Schema of
+//! the file, generated from its types, to validate or template configs
+//! with; each key's description names the variable it sets.
+
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::OnceLock;
+use utoipa::{PartialSchema, ToSchema};
+
+/// `(section, key, variable)` of every typed setting
+const SETTINGS: &[(&str, &str, &str)] = &[
+    ("server", "port", "PORT"),
+    ("server", "filename_template", "FILENAME_TEMPLATE"),
+    ("server", "verify_downloads", "VERIFY_DOWNLOADS"),
+    ("server", "hook_script", "HOOK_SCRIPT"),
+    ("server", "upstream_traces", "UPSTREAM_TRACES"),
+    ("server", "progress_interval_ms", "PROGRESS_INTERVAL_MS"),
+    ("server", "shutdown_drain_secs", "SHUTDOWN_DRAIN_SECS"),
+    ("server", "readiness_timeout_ms", "READINESS_TIMEOUT_MS"),
+    ("server", "readiness_cache_secs", "READINESS_CACHE_SECS"),
+    ("server", "log_format", "LOG_FORMAT"),
+    ("server", "read_only", "READ_ONLY"),
+    ("tls", "cert_file", "TLS_CERT_FILE"),
+    ("tls", "key_file", "TLS_KEY_FILE"),
+    ("tls", "port", "TLS_PORT"),
+    ("tls", "only", "TLS_ONLY"),
+    ("tls", "client_ca_file", "TLS_CLIENT_CA_FILE"),
+    ("tls", "client_auth", "TLS_CLIENT_AUTH"),
+    ("admin", "addr", "ADMIN_ADDR"),
+    ("admin", "api_keys", "ADMIN_API_KEYS"),
+    ("admin", "metrics_addr", "METRICS_ADDR"),
+    ("hub", "token", "HF_TOKEN"),
+    ("hub", "token_fallback", "HF_TOKEN_FALLBACK"),
+    ("hub", "token_scope_policy", "TOKEN_SCOPE_POLICY"),
+    ("hub", "token_scope_check_secs", "TOKEN_SCOPE_CHECK_SECS"),
+    ("hub", "cas_token_repo", "CAS_TOKEN_REPO"),
+    ("hub", "endpoint", "HF_ENDPOINT"),
+    ("hub", "endpoint_allowlist", "HF_ENDPOINT_ALLOWLIST"),
+    ("hub", "ca_file", "UPSTREAM_CA_FILE"),
+    ("hub", "ca_only", "UPSTREAM_CA_ONLY"),
+    ("hub", "pinned_certs", "UPSTREAM_PINNED_CERTS"),
+    ("engine", "kind", "XET_ENGINE"),
+    ("engine", "bin_path", "ZIG_BIN_PATH"),
+    ("engine", "workers", "CLI_WORKERS"),
+    ("engine", "rlimit_cpu_secs", "CLI_RLIMIT_CPU_SECS"),
+    ("engine", "rlimit_memory_mb", "CLI_RLIMIT_MEMORY_MB"),
+    ("engine", "backoff_base_secs", "BACKOFF_BASE_SECS"),
+    ("engine", "backoff_max_secs", "BACKOFF_MAX_SECS"),
+    ("engine", "dev_latency_ms", "DEV_LATENCY_MS"),
+    ("cache", "dir", "CACHE_DIR"),
+    ("cache", "max_bytes", "CACHE_MAX_BYTES"),
+    ("cache", "purge_grace_secs", "CACHE_PURGE_GRACE_SECS"),
+    ("cache", "encryption_key", "CACHE_ENCRYPTION_KEY"),
+    ("cache", "encryption_key_file", "CACHE_ENCRYPTION_KEY_FILE"),
+    ("cache", "auto_migrate", "CACHE_AUTO_MIGRATE"),
+    ("cache", "s3_bucket", "CACHE_S3_BUCKET"),
+    ("cache", "s3_prefix", "CACHE_S3_PREFIX"),
+    ("cache", "s3_endpoint", "CACHE_S3_ENDPOINT"),
+    ("cache", "s3_region", "CACHE_S3_REGION"),
+    ("cache", "s3_virtual_hosted", "CACHE_S3_VIRTUAL_HOSTED"),
+    ("cache", "s3_access_key_id", "CACHE_S3_ACCESS_KEY_ID"),
+    (
+        "cache",
+        "s3_secret_access_key",
+        "CACHE_S3_SECRET_ACCESS_KEY",
+    ),
+    ("cache", "s3_session_token", "CACHE_S3_SESSION_TOKEN"),
+    ("cache", "catalog_max_entries", "CATALOG_MAX_ENTRIES"),
+    ("cache", "listing_ttl_secs", "LISTING_CACHE_TTL_SECS"),
+    ("cache", "listing_max_entries", "LISTING_CACHE_MAX_ENTRIES"),
+    ("cache", "reverify_after_secs", "CACHE_REVERIFY_AFTER_SECS"),
+    ("cache", "head_max_bytes", "HEAD_CACHE_MAX_BYTES"),
+    ("cache", "head_prefix_bytes", "HEAD_CACHE_PREFIX_BYTES"),
+    (
+        "cache",
+        "prefetch_sync_interval_secs",
+        "PREFETCH_SYNC_INTERVAL_SECS",
+    ),
+    ("cache", "prefetch_sync_nice", "PREFETCH_SYNC_NICE"),
+    (
+        "limits",
+        "max_concurrent_downloads",
+        "MAX_CONCURRENT_DOWNLOADS",
+    ),
+    ("limits", "download_queue_size", "DOWNLOAD_QUEUE_SIZE"),
+    ("limits", "archive_parallelism", "ARCHIVE_PARALLELISM"),
+    ("limits", "resume_priority_secs", "RESUME_PRIORITY_SECS"),
+    ("limits", "resume_secret", "RESUME_SECRET"),
+    ("limits", "shed_max_fds", "SHED_MAX_FDS"),
+    ("limits", "shed_max_rss_mb", "SHED_MAX_RSS_MB"),
+    ("limits", "upload_expiry_secs", "UPLOAD_EXPIRY_SECS"),
+    (
+        "limits",
+        "listing_rate_per_minute",
+        "LISTING_RATE_PER_MINUTE",
+    ),
+    ("requests", "timeout_secs", "PROXY_TIMEOUT_SECS"),
+    ("requests", "max_timeout_secs", "PROXY_MAX_TIMEOUT_SECS"),
+    ("requests", "default_retries", "PROXY_DEFAULT_RETRIES"),
+    ("requests", "max_retries", "PROXY_MAX_RETRIES"),
+    ("requests", "allow_redirect", "PROXY_ALLOW_REDIRECT"),
+    ("requests", "allow_spool", "PROXY_ALLOW_SPOOL"),
+    ("requests", "list_budget_ms", "PROXY_LIST_BUDGET_MS"),
+    ("requests", "retry_base_ms", "PROXY_RETRY_BASE_MS"),
+    ("requests", "retry_max_ms", "PROXY_RETRY_MAX_MS"),
+    ("requests", "retry_jitter", "PROXY_RETRY_JITTER"),
+    ("requests", "chunk_retries", "PROXY_CHUNK_RETRIES"),
+    ("auth", "providers", "AUTH_PROVIDERS"),
+    ("auth", "api_keys", "API_KEYS"),
+    ("auth", "api_keys_file", "API_KEYS_FILE"),
+    ("auth", "jwt_secret", "JWT_SECRET"),
+    ("auth", "jwt_public_key_file", "JWT_PUBLIC_KEY_FILE"),
+    ("auth", "jwt_algorithm", "JWT_ALGORITHM"),
+    ("auth", "jwt_issuer", "JWT_ISSUER"),
+    ("auth", "jwt_audience", "JWT_AUDIENCE"),
+    ("auth", "mtls_identity_header", "MTLS_IDENTITY_HEADER"),
+    ("auth", "mtls_identities_file", "MTLS_IDENTITIES_FILE"),
+    ("auth", "url", "AUTH_URL"),
+    ("auth", "url_timeout_ms", "AUTH_URL_TIMEOUT_MS"),
+    ("auth", "session_ttl_secs", "SESSION_TTL_SECS"),
+    ("auth", "login_ttl_secs", "LOGIN_TTL_SECS"),
+    ("auth", "tenant_limits_file", "TENANT_LIMITS_FILE"),
+    ("policy", "url", "POLICY_URL"),
+    ("policy", "timeout_ms", "POLICY_TIMEOUT_MS"),
+    ("policy", "cache_secs", "POLICY_CACHE_SECS"),
+    ("policy", "fail_open", "POLICY_FAIL_OPEN"),
+    ("slo", "target", "SLO_TARGET"),
+    ("slo", "ttfb_ms", "SLO_TTFB_MS"),
+    ("slo", "total_secs", "SLO_TOTAL_SECS"),
+    ("slo", "window_secs", "SLO_WINDOW_SECS"),
+    ("files", "aliases", "ALIASES_FILE"),
+    ("files", "artifact_rules", "ARTIFACT_RULES_FILE"),
+    ("files", "rate_limits", "RATE_LIMITS_FILE"),
+    ("files", "prefetch_sync", "PREFETCH_SYNC_FILE"),
+    ("files", "upload_dir", "UPLOAD_DIR"),
+    ("nats", "url", "NATS_URL"),
+    ("nats", "subject_prefix", "NATS_SUBJECT_PREFIX"),
+];
+
+/// Variables whose values `GET /config` doesn't reveal
+const SECRETS: &[&str] = &[
+    "HF_TOKEN",
+    "API_KEYS",
+    "ADMIN_API_KEYS",
+    "JWT_SECRET",
+    "RESUME_SECRET",
+    "NATS_URL",
+    "CACHE_S3_SECRET_ACCESS_KEY",
+    "CACHE_S3_SESSION_TOKEN",
+    "CACHE_ENCRYPTION_KEY",
+];
+const REDACTED: &str = "<redacted>";
+
+#[derive(Default, Deserialize, Serialize, ToSchema)]
+#[serde(default, deny_unknown_fields)]
+struct Config {
+    server: Server,
+    tls: TlsSettings,
+    admin: AdminSettings,
+    hub: Hub,
+    engine: Engine,
+    cache: CacheSettings,
+    limits: Limits,
+    requests: Requests,
+    auth: Auth,
+    policy: PolicySettings,
+    slo: Slo,
+    files: Files,
+    nats: Nats,
+    /// Any other variable, by name
+    env: BTreeMap<String, Scalar>,
+}
+
+#[derive(Default, Deserialize, Serialize, ToSchema)]
+#[serde(default, deny_unknown_fields)]
+struct Server {
+    port: Option<Port>,
+    filename_template: Option<String>,
+    verify_downloads: Option<String>,
+    hook_script: Option<String>,
+    upstream_traces: Option<usize>,
+    progress_interval_ms: Option<u64>,
+    shutdown_drain_secs: Option<u64>,
+    readiness_timeout_ms: Option<u64>,
+    readiness_cache_secs: Option<u64>,
+    log_format: Option<String>,
+    read_only: Option<bool>,
+}
+
+#[derive(Default, Deserialize, Serialize, ToSchema)]
+#[serde(default, deny_unknown_fields)]
+struct TlsSettings {
+    cert_file: Option<String>,
+    key_file: Option<String>,
+    port: Option<u16>,
+    only: Option<bool>,
+    client_ca_file: Option<String>,
+    client_auth: Option<String>,
+}
+
+#[derive(Default, Deserialize, Serialize, ToSchema)]
+#[serde(default, deny_unknown_fields)]
+struct AdminSettings {
+    addr: Option<String>,
+    /// Joined with commas into `ADMIN_API_KEYS`
+    api_keys: Option<Vec<String>>,
+    metrics_addr: Option<String>,
+}
+
+#[derive(Default, Deserialize, Serialize, ToSchema)]
+#[serde(default, deny_unknown_fields)]
+struct Hub {
+    token: Option<String>,
+    token_fallback: Option<bool>,
+    token_scope_policy: Option<String>,
+    token_scope_check_secs: Option<u64>,
+    cas_token_repo: Option<String>,
+    endpoint: Option<String>,
+    endpoint_allowlist: Option<String>,
+    ca_file: Option<String>,
+    ca_only: Option<bool>,
+    pinned_certs: Option<String>,
+}
+
+#[derive(Default, Deserialize, Serialize, ToSchema)]
+#[serde(default, deny_unknown_fields)]
+struct Engine {
+    kind: Option<String>,
+    bin_path: Option<String>,
+    workers: Option<u64>,
+    rlimit_cpu_secs: Option<u64>,
+    rlimit_memory_mb: Option<u64>,
+    backoff_base_secs: Option<u64>,
+    backoff_max_secs: Option<u64>,
+    dev_latency_ms: Option<u64>,
+}
+
+#[derive(Default, Deserialize, Serialize, ToSchema)]
+#[serde(default, deny_unknown_fields)]
+struct CacheSettings {
+    dir: Option<String>,
+    max_bytes: Option<u64>,
+    purge_grace_secs: Option<u64>,
+    encryption_key: Option<String>,
+    encryption_key_file: Option<String>,
+    auto_migrate: Option<bool>,
+    s3_bucket: Option<String>,
+    s3_prefix: Option<String>,
+    s3_endpoint: Option<String>,
+    s3_region: Option<String>,
+    s3_virtual_hosted: Option<bool>,
+    s3_access_key_id: Option<String>,
+    s3_secret_access_key: Option<String>,
+    s3_session_token: Option<String>,
+    catalog_max_entries: Option<u64>,
+    listing_ttl_secs: Option<u64>,
+    listing_max_entries: Option<u64>,
+    reverify_after_secs: Option<u64>,
+    head_max_bytes: Option<u64>,
+    head_prefix_bytes: Option<u64>,
+    prefetch_sync_interval_secs: Option<u64>,
+    prefetch_sync_nice: Option<u64>,
+}
+
+#[derive(Default, Deserialize, Serialize, ToSchema)]
+#[serde(default, deny_unknown_fields)]
+struct Limits {
+    max_concurrent_downloads: Option<u64>,
+    download_queue_size: Option<u64>,
+    archive_parallelism: Option<u64>,
+    resume_priority_secs: Option<u64>,
+    resume_secret: Option<String>,
+    shed_max_fds: Option<u64>,
+    shed_max_rss_mb: Option<u64>,
+    upload_expiry_secs: Option<u64>,
+    listing_rate_per_minute: Option<u64>,
+}
+
+#[derive(Default, Deserialize, Serialize, ToSchema)]
+#[serde(default, deny_unknown_fields)]
+struct Requests {
+    timeout_secs: Option<u64>,
+    max_timeout_secs: Option<u64>,
+    default_retries: Option<u64>,
+    max_retries: Option<u64>,
+    allow_redirect: Option<bool>,
+    allow_spool: Option<bool>,
+    list_budget_ms: Option<u64>,
+    retry_base_ms: Option<u64>,
+    retry_max_ms: Option<u64>,
+    retry_jitter: Option<f64>,
+    chunk_retries: Option<u64>,
+}
+
+#[derive(Default, Deserialize, Serialize, ToSchema)]
+#[serde(default, deny_unknown_fields)]
+struct Auth {
+    /// Joined with commas into `AUTH_PROVIDERS`
+    providers: Option<Vec<String>>,
+    /// Joined with commas into `API_KEYS`
+    api_keys: Option<Vec<String>>,
+    api_keys_file: Option<String>,
+    jwt_secret: Option<String>,
+    jwt_public_key_file: Option<String>,
+    jwt_algorithm: Option<String>,
+    jwt_issuer: Option<String>,
+    jwt_audience: Option<String>,
+    mtls_identity_header: Option<String>,
+    mtls_identities_file: Option<String>,
+    url: Option<String>,
+    url_timeout_ms: Option<u64>,
+    session_ttl_secs: Option<u64>,
+    login_ttl_secs: Option<u64>,
+    tenant_limits_file: Option<String>,
+}
+
+#[derive(Default, Deserialize, Serialize, ToSchema)]
+#[serde(default, deny_unknown_fields)]
+struct PolicySettings {
+    url: Option<String>,
+    timeout_ms: Option<u64>,
+    cache_secs: Option<u64>,
+    fail_open: Option<bool>,
+}
+
+#[derive(Default, Deserialize, Serialize, ToSchema)]
+#[serde(default, deny_unknown_fields)]
+struct Slo {
+    target: Option<f64>,
+    ttfb_ms: Option<u64>,
+    total_secs: Option<u64>,
+    window_secs: Option<u64>,
+}
+
+#[derive(Default, Deserialize, Serialize, ToSchema)]
+#[serde(default, deny_unknown_fields)]
+struct Files {
+    aliases: Option<String>,
+    artifact_rules: Option<String>,
+    rate_limits: Option<String>,
+    prefetch_sync: Option<String>,
+    upload_dir: Option<String>,
+}
+
+#[derive(Default, Deserialize, Serialize, ToSchema)]
+#[serde(default, deny_unknown_fields)]
+struct Nats {
+    url: Option<String>,
+    subject_prefix: Option<String>,
+}
+
+/// A port number, or `off`
+#[derive(Deserialize, Serialize, ToSchema)]
+#[serde(untagged)]
+enum Port {
+    Number(u16),
+    Off(String),
+}
+
+/// Value of an `[env]` entry
+#[derive(Deserialize, Serialize, ToSchema)]
+#[serde(untagged)]
+enum Scalar {
+    Bool(bool),
+    Integer(i64),
+    Float(f64),
+    String(String),
+}
+
+/// Outcome of loading the file, kept for `check-config` and `GET /config`
+struct Loaded {
+    path: Option<String>,
+    /// Variables named in the file, with whether the environment overrode it
+    vars: BTreeMap<String, bool>,
+}
+
+static LOADED: OnceLock<Result<Loaded, String>> = OnceLock::new();
+
+/// Load the file named by `path` or else `PROXY_CONFIG`, if any, into the
+/// environment; called once, before any other setting is read
+pub fn init(path: Option<String>) -> Result<(), String> {
+    let path = path.or_else(|| std::env::var("PROXY_CONFIG").ok());
+    let loaded = match path {
+        Some(path) => load(&path).map_err(|e| format!("Config file {}: {}", path, e)),
+        None => Ok(Loaded {
+            path: None,
+            vars: BTreeMap::new(),
+        }),
+    };
+    match LOADED.get_or_init(|| loaded) {
+        Ok(_) => Ok(()),
+        Err(e) => Err(e.clone()),
+    }
+}
+
+/// The file settings were loaded from, if any
+pub fn file() -> Option<&'static str> {
+    LOADED.get()?.as_ref().ok()?.path.as_deref()
+}
+
+/// Whether the file loaded, for `check-config`
+pub fn status() -> Result<Option<String>, String> {
+    match LOADED.get() {
+        Some(Err(e)) => Err(e.clone()),
+        _ => Ok(None),
+    }
+}
+
+fn load(path: &str) -> Result<Loaded, String> {
+    let text = std::fs::read_to_string(path).map_err(|e| e.to_string())?;
+    let config: Config = match path.rsplit_once('.').map(|(_, ext)| ext) {
+        Some("toml") => toml::from_str(&text).map_err(|e| e.to_string())?,
+        Some("yaml" | "yml") => serde_yaml::from_str(&text).map_err(|e| e.to_string())?,
+        _ => return Err("must end in .toml, .yaml or .yml".to_string()),
+    };
+    let vars = config.vars()?;
+    let mut loaded = BTreeMap::new();
+    for (var, value) in vars {
+        let overridden = std::env::var_os(&var).is_some();
+        if !overridden {
+            std::env::set_var(&var, value);
+        }
+        loaded.insert(var, overridden);
+    }
+    Ok(Loaded {
+        path: Some(path.to_string()),
+        vars: loaded,
+    })
+}
+
+impl Config {
+    /// Variables set by the file and their values
+    fn vars(&self) -> Result<Vec<(String, String)>, String> {
+        let tree = serde_json::to_value(self).map_err(|e| e.to_string())?;
+        let mut vars = Vec::new();
+        for &(section, key, var) in SETTINGS {
+            match &tree[section][key] {
+                Value::Null => {}
+                Value::String(s) => vars.push((var.to_string(), s.clone())),
+                Value::Array(items) => {
+                    let items: Vec<&str> = items.iter().filter_map(Value::as_str).collect();
+                    vars.push((var.to_string(), items.join(",")));
+                }
+                value => vars.push((var.to_string(), value.to_string())),
+            }
+        }
+        for (var, value) in &self.env {
+            let valid = !var.is_empty()
+                && var
+                    .bytes()
+                    .all(|b| matches!(b, b'A'..=b'Z' | b'0'..=b'9' | b'_'));
+            if !valid {
+                return Err(format!(
+                    "[env] key '{}' is not an UPPER_CASE variable name",
+                    var
+                ));
+            }
+            if let Some((section, key, _)) = SETTINGS.iter().find(|(_, _, v)| v == var) {
+                return Err(format!(
+                    "[env] {} is set as `{}.{}` instead",
+                    var, section, key
+                ));
+            }
+            if var == "PROXY_CONFIG" {
+                return Err("[env] cannot set PROXY_CONFIG".to_string());
+            }
+            let value = match value {
+                Scalar::Bool(b) => b.to_string(),
+                Scalar::Integer(n) => n.to_string(),
+                Scalar::Float(x) => x.to_string(),
+                Scalar::String(s) => s.clone(),
+            };
+            vars.push((var.clone(), value));
+        }
+        Ok(vars)
+    }
+}
+
+/// JSON Schema (draft 2020-12) of the file, with the sections under `$defs`
+pub fn schema() -> Value {
+    let mut sections = Vec::new();
+    <Config as ToSchema>::schemas(&mut sections);
+    let mut defs = Map::new();
+    for (name, section) in sections {
+        defs.insert(name, serde_json::to_value(section).unwrap_or_default());
+    }
+    let mut root = serde_json::to_value(Config::schema()).unwrap_or_default();
+    // Each typed key is described by the variable it stands for
+    for &(section, key, var) in SETTINGS {
+        let section = &root["properties"][section];
+        let Some(reference) = section
+            .pointer("/$ref")
+            .or_else(|| section.pointer("/oneOf/0/$ref"))
+            .and_then(Value::as_str)
+        else {
+            continue;
+        };
+        let name = reference.rsplit('/').next().unwrap_or_default();
+        let Some(property) = defs
+            .get_mut(name)
+            .and_then(|def| def.pointer_mut(&format!("/properties/{}", key)))
+        else {
+            continue;
+        };
+        if property.get("description").is_none() {
+            let secret = if SECRETS.contains(&var) {
+                " (a secret)"
+            } else {
+                ""
+            };
+            property["description"] = Value::String(format!("Sets `{}`{}", var, secret));
+        }
+    }
+    let mut schema = json!({
+        "$schema": "https://json-schema.org/draft/2020-12/schema",
+        "title": "xet-proxy configuration file",
+    });
+    if let (Value::Object(schema), Value::Object(root)) = (&mut schema, root.take()) {
+        schema.extend(root);
+        schema.insert("$defs".to_string(), Value::Object(defs));
+    }
+    rewrite_refs(&mut schema);
+    schema
+}
+
+/// Point OpenAPI component references at `$defs`
+fn rewrite_refs(value: &mut Value) {
+    match value {
+        Value::Object(map) => {
+            for (key, value) in map.iter_mut() {
+                match value {
+                    Value::String(reference) if key == "$ref" => {
+                        *reference = reference.replace("#/components/schemas/", "#/$defs/");
+                    }
+                    value => rewrite_refs(value),
+                }
+            }
+        }
+        Value::Array(items) => items.iter_mut().for_each(rewrite_refs),
+        _ => {}
+    }
+}
+
+/// Effective configuration for `GET /config`: each typed setting and each
+/// `[env]` variable of the file, as currently set, secrets redacted
+pub fn effective() -> Value {
+    let loaded = LOADED.get().and_then(|loaded| loaded.as_ref().ok());
+    let mut config = Map::new();
+    for &(section, key, var) in SETTINGS {
+        if let Some(value) = current(var, SECRETS.contains(&var)) {
+            let section = config
+                .entry(section)
+                .or_insert_with(|| Value::Object(Map::new()));
+            section[key] = value;
+        }
+    }
+    let mut env = Map::new();
+    for var in loaded.iter().flat_map(|loaded| loaded.vars.keys()) {
+        if SETTINGS.iter().all(|(_, _, v)| v != var) {
+            if let Some(value) = current(var, looks_secret(var)) {
+                env.insert(var.clone(), value);
+            }
+        }
+    }
+    if !env.is_empty() {
+        config.insert("env".to_string(), Value::Object(env));
+    }
+    let overridden: Vec<&String> = loaded
+        .iter()
+        .flat_map(|loaded| loaded.vars.iter())
+        .filter(|(_, &overridden)| overridden)
+        .map(|(var, _)| var)
+        .collect();
+    json!({
+        "file": loaded.and_then(|loaded| loaded.path.as_deref()),
+        "config": config,
+        "overridden_by_env": overridden,
+    })
+}
+
+/// Boolean variable `var`, `true` or `1` for on and `false` or `0` for off;
+/// `None` if unset
+pub fn flag(var: &str) -> Option<bool> {
+    let value = std::env::var(var).ok()?;
+    match value.as_str() {
+        "true" | "1" => Some(true),
+        "false" | "0" => Some(false),
+        _ => panic!(
+            "{} must be 'true', 'false', '1' or '0', got '{}'",
+            var, value
+        ),
+    }
+}
+
+/// Non-negative integer variable `var`; `None` if unset
+pub fn number<T>(var: &str) -> Option<T>
+where
+    T: FromStr,
+    T::Err: fmt::Display,
+{
+    let value = std::env::var(var).ok()?;
+    Some(value.parse().unwrap_or_else(|e| {
+        panic!(
+            "{} must be a non-negative integer, got '{}' ({})",
+            var, value, e
+        )
+    }))
+}
+
+/// Positive integer variable `var`; `None` if unset
+pub fn positive<T>(var: &str) -> Option<T>
+where
+    T: FromStr + Default + PartialOrd,
+{
+    let value = std::env::var(var).ok()?;
+    let number = value.parse().ok().filter(|n| *n > T::default());
+    Some(number.unwrap_or_else(|| panic!("{} must be a positive integer, got '{}'", var, value)))
+}
+
+/// Value of `var`, typed as in the file where it reads as a number or boolean
+fn current(var: &str, secret: bool) -> Option<Value> {
+    let value = std::env::var(var).ok()?;
+    if secret {
+        return Some(Value::String(REDACTED.to_string()));
+    }
+    Some(match serde_json::from_str::<Value>(&value) {
+        Ok(typed @ (Value::Number(_) | Value::Bool(_))) => typed,
+        _ => Value::String(value),
+    })
+}
+
+/// Whether an `[env]` variable likely holds a credential
+fn looks_secret(var: &str) -> bool {
+    !var.ends_with("_FILE")
+        && ["TOKEN", "KEY", "SECRET", "PASSWORD"]
+            .iter()
+            .any(|word| var.contains(word))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Panic message of `load`, if it panics
+    fn failure<T>(load: impl FnOnce() -> T + std::panic::UnwindSafe) -> Option<String> {
+        let payload = std::panic::catch_unwind(load).err()?;
+        payload.downcast_ref::<String>().cloned()
+    }
+
+    #[test]
+    fn numbers_are_parsed_or_refused_with_the_value() {
+        // Names of their own: tests share the environment
+        std::env::set_var("CONFIG_TEST_NUMBER", "42");
+        std::env::set_var("CONFIG_TEST_NEGATIVE", "-1");
+        std::env::set_var("CONFIG_TEST_PORT", "70000");
+        assert_eq!(number::<u64>("CONFIG_TEST_NUMBER"), Some(42));
+        assert_eq!(number::<u64>("CONFIG_TEST_UNSET"), None);
+        let message = failure(|| number::<u64>("CONFIG_TEST_NEGATIVE")).unwrap();
+        assert!(
+            message.starts_with("CONFIG_TEST_NEGATIVE must be a non-negative integer, got '-1'")
+        );
+        let message = failure(|| number::<u16>("CONFIG_TEST_PORT")).unwrap();
+        assert!(
+            message.contains("got '70000' (number too large"),
+            "{message}"
+        );
+    }
+
+    #[test]
+    fn positive_numbers_refuse_zero() {
+        std::env::set_var("CONFIG_TEST_POSITIVE", "3");
+        std::env::set_var("CONFIG_TEST_ZERO", "0");
+        assert_eq!(positive::<usize>("CONFIG_TEST_POSITIVE"), Some(3));
+        assert_eq!(positive::<usize>("CONFIG_TEST_UNSET"), None);
+        assert_eq!(
+            failure(|| positive::<usize>("CONFIG_TEST_ZERO")).as_deref(),
+            Some("CONFIG_TEST_ZERO must be a positive integer, got '0'")
+        );
+    }
+}
@@ -0,0 +1,173 @@
+//! `xet-proxy check-config`
+//!
+//! Validates the environment configuration without starting the server, for
+//! CI/CD pipelines. Every loader the server runs at startup is executed (they
+//! panic on invalid values, and the panic message is reported), followed by
+//! checks that only make sense up front, such as referenced files existing.
+//! Exits non-zero if anything failed.
+
+use crate::aliases::Aliases;
+use crate::backoff::UpstreamBackoff;
+use crate::cache::Cache;
+use crate::head_cache::HeadCache;
+use crate::overrides::OverrideLimits;
+use crate::select::SelectionRules;
+use crate::shedding::ShedLimits;
+use crate::slo::SloConfig;
+use crate::subprocess::ResourceLimits;
+use std::os::unix::fs::PermissionsExt;
+use std::panic::{self, AssertUnwindSafe};
+
+#[derive(Default)]
+struct Report {
+    failures: usize,
+}
+
+impl Report {
+    fn check(&mut self, name: &str, check: impl FnOnce() -> Result<Option<String>, String>) {
+        match check() {
+            Ok(None) => println!("ok    {}", name),
+            Ok(Some(warning)) => println!("warn  {}: {}", name, warning),
+            Err(e) => {
+                self.failures += 1;
+                println!("FAIL  {}: {}", name, e);
+            }
+        }
+    }
+
+    /// Run a startup loader, reporting its panic message as the failure
+    fn load<T>(&mut self, name: &str, loader: impl FnOnce() -> T) {
+        self.check(name, || {
+            panic::catch_unwind(AssertUnwindSafe(loader))
+                .map(|_| None)
+                .map_err(|payload| {
+                    payload
+                        .downcast_ref::<String>()
+                        .cloned()
+                        .or_else(|| payload.downcast_ref::<&str>().map(|s| s.to_string()))
+                        .unwrap_or_else(|| "invalid".to_string())
+                })
+        });
+    }
+}
+
+/// Validate the configuration and return the process exit code
+pub fn run() -> i32 {
+    let failures = check();
+    if failures == 0 {
+        println!("Configuration OK");
+        0
+    } else {
+        println!("{} problem(s) found", failures);
+        1
+    }
+}
+
+/// Print a line per setting; returns the number of failures
+pub fn check() -> usize {
+    // Loader panics are reported, not printed with a backtrace note
+    let default_hook = panic::take_hook();
+    panic::set_hook(Box::new(|_| {}));
+
+    let mut report = Report::default();
+    report.check("--config / PROXY_CONFIG", crate::config::status);
+    report.load(
+        "PORT, ADMIN_ADDR, METRICS_ADDR",
+        crate::ports::PortMap::from_env,
+    );
+    report.load("LOG_FORMAT", crate::logging::format_from_env);
+    report.load("TLS_*", crate::tls::Tls::from_env);
+    report.load("FILENAME_TEMPLATE", crate::filename_template_from_env);
+    report.load("PROXY_* overrides", OverrideLimits::from_env);
+    report.load("CLI_RLIMIT_*", ResourceLimits::from_env);
+    report.load("CLI_WORKERS", crate::workers::pool_size_from_env);
+    report.load("RESUME_*", crate::resume::AbortedTransfers::from_env);
+    report.load("BACKOFF_*", UpstreamBackoff::from_env);
+    report.load("SLO_*", SloConfig::from_env);
+    report.load("ARTIFACT_RULES_FILE", SelectionRules::from_env);
+    report.load("RATE_LIMITS_FILE", crate::throttle::Throttle::from_env);
+    report.load("ALIASES_FILE", Aliases::from_env);
+    report.load("ARCHIVE_PARALLELISM", crate::archive::parallelism_from_env);
+    report.load("PROGRESS_INTERVAL_MS", crate::progress::Progress::from_env);
+    report.load("PREFETCH_SYNC_*", crate::prefetch::Schedule::from_env);
+    report.load("SHUTDOWN_DRAIN_SECS", crate::shutdown::Shutdown::from_env);
+    report.load("READINESS_*", crate::readiness::Readiness::from_env);
+    report.load("CACHE_*", Cache::from_env);
+    report.load(
+        "CACHE_REVERIFY_AFTER_SECS",
+        crate::reverify::Reverify::from_env,
+    );
+    report.load("HEAD_CACHE_*", HeadCache::from_env);
+    report.load("CATALOG_MAX_ENTRIES", crate::catalog::Catalog::from_env);
+    report.load(
+        "LISTING_CACHE_*",
+        crate::listing_cache::ListingCache::from_env,
+    );
+    report.load(
+        "LISTING_RATE_PER_MINUTE",
+        crate::amplification::Amplification::from_env,
+    );
+    report.load("SHED_*", ShedLimits::from_env);
+    report.load(
+        "MAX_CONCURRENT_DOWNLOADS, DOWNLOAD_QUEUE_SIZE",
+        crate::limiter::DownloadLimiter::from_env,
+    );
+    report.load(
+        "AUTH_PROVIDERS, provider settings, TENANT_LIMITS_FILE and LOGIN_TTL_SECS",
+        crate::auth::Authenticator::from_env,
+    );
+    report.load("POLICY_*", crate::policy::Policy::from_env);
+    report.load("SESSION_TTL_SECS", crate::sessions::Sessions::from_env);
+    report.load(
+        "UPLOAD_DIR, UPLOAD_EXPIRY_SECS",
+        crate::tus::Uploads::from_env,
+    );
+    report.load("VERIFY_DOWNLOADS", crate::integrity::VerifyMode::from_env);
+    report.load("UPSTREAM_TRACES", crate::upstream::Traces::from_env);
+    report.load("SELF_TEST_TIMEOUT_SECS", crate::self_test::timeout_from_env);
+    report.load("XET_ENGINE", || {
+        crate::downloader_from_env(
+            UpstreamBackoff::from_env(),
+            crate::retry::RetryPolicy::from_env(),
+            crate::capabilities::Capabilities::default(),
+        )
+    });
+    #[cfg(feature = "hooks")]
+    report.load("HOOK_SCRIPT", crate::hooks::Hooks::from_env);
+
+    if std::env::var("XET_ENGINE").map_or(true, |engine| engine == "cli") {
+        report.check("ZIG_BIN_PATH", || {
+            let path = crate::zig_bin_path();
+            let metadata = std::fs::metadata(&path).map_err(|e| format!("{}: {}", path, e))?;
+            if !metadata.is_file() || metadata.permissions().mode() & 0o111 == 0 {
+                return Err(format!("{} is not an executable file", path));
+            }
+            Ok(None)
+        });
+    }
+
+    report.load("HF_TOKEN_FALLBACK", crate::fallback_token_from_env);
+    report.load("READ_ONLY", crate::read_only_from_env);
+    report.load("TOKEN_SCOPE_POLICY, TOKEN_SCOPE_CHECK_SECS", || {
+        crate::token_scopes::TokenScopes::from_env(false)
+    });
+    report.load("CAS_TOKEN_REPO", crate::cas_token_repo_from_env);
+    report.load("HF_ENDPOINT", crate::hub::Hub::from_env);
+    report.load(
+        "UPSTREAM_CA_FILE, UPSTREAM_CA_ONLY, UPSTREAM_PINNED_CERTS",
+        crate::trust::Trust::from_env,
+    );
+    report.check("HF_TOKEN", || match std::env::var("HF_TOKEN") {
+        Err(_) => Ok(None),
+        Ok(token) if token.is_empty() || token.contains(char::is_whitespace) => {
+            Err("must be non-empty and contain no whitespace".to_string())
+        }
+        Ok(token) if !token.starts_with("hf_") => Ok(Some(
+            "does not look like a HuggingFace token (hf_...)".to_string(),
+        )),
+        Ok(_) => Ok(None),
+    });
+
+    panic::set_hook(default_hook);
+    report.failures
+}
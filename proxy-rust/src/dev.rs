@@ -0,0 +1,208 @@
+//! Developer mode: bundled sample files, no credentials or network
+//!
+//! `xet-proxy --dev` (or `XET_ENGINE=dev`) replaces the download engine with
+//! one serving a few small files built into the binary, as the model
+//! repository `xet-proxy/dev-samples`:
+//!
+//! - `README.md` and `config.json`, text;
+//! - `model.safetensors`, a 1 MiB tensor of deterministic weights.
+//!
+//! Everything else runs as usual, so resolution by path, hash downloads,
+//! ranges, archives, the cache and verification behave as they do against
+//! the Hub: the files carry their real XET hashes. Requests need no
+//! HuggingFace token. Each listing and download first waits
+//! `DEV_LATENCY_MS` (default 50), and files stream in 64 KiB chunks, so
+//! clients see first-byte latency and progress as they would upstream.
+//!
+//! `--dev` also keeps the cache in `xet-proxy-dev` under the temporary
+//! directory, unless a cache is configured.
+
+use crate::downloader::{Download, DownloadRequest, Downloader};
+use crate::integrity::FileHasher;
+use crate::listing::ListedFile;
+use crate::repo::RepoRef;
+use crate::AppError;
+use async_trait::async_trait;
+use axum::body::Bytes;
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+
+/// Repository the samples are listed in
+pub const REPO: &str = "xet-proxy/dev-samples";
+/// Token requests fall back to, since the samples need none
+pub const TOKEN: &str = "dev";
+const CHUNK: usize = 64 * 1024;
+
+const README: &str = "\
+# dev-samples
+
+Sample files served by `xet-proxy --dev`, for integrating against the proxy
+without a HuggingFace account or network access.
+";
+
+const CONFIG: &str = r#"{
+  "architectures": ["SampleModel"],
+  "hidden_size": 512,
+  "num_hidden_layers": 1,
+  "torch_dtype": "float32"
+}
+"#;
+
+struct Sample {
+    path: &'static str,
+    hash: String,
+    data: Bytes,
+}
+
+pub struct DevDownloader {
+    samples: Arc<[Sample]>,
+    latency: Duration,
+}
+
+impl DevDownloader {
+    /// Load `DEV_LATENCY_MS` and build the samples
+    pub fn from_env() -> Self {
+        let millis = crate::config::number("DEV_LATENCY_MS").unwrap_or(50);
+        let files = [
+            ("README.md", Bytes::from_static(README.as_bytes())),
+            ("config.json", Bytes::from_static(CONFIG.as_bytes())),
+            ("model.safetensors", safetensors(512, 512)),
+        ];
+        let samples = files
+            .into_iter()
+            .map(|(path, data)| {
+                let mut hasher = FileHasher::default();
+                hasher.update(&data);
+                Sample {
+                    path,
+                    hash: hasher.finish(),
+                    data,
+                }
+            })
+            .collect();
+        Self {
+            samples,
+            latency: Duration::from_millis(millis),
+        }
+    }
+
+    fn sample(&self, hash: &str) -> Result<&Sample, AppError> {
+        self.samples
+            .iter()
+            .find(|sample| sample.hash == hash)
+            .ok_or_else(|| AppError::NotFound(format!("No sample file has hash {}", hash)))
+    }
+}
+
+#[async_trait]
+impl Downloader for DevDownloader {
+    async fn list(&self, repo: &RepoRef, _hf_token: &str) -> Result<Vec<ListedFile>, AppError> {
+        tokio::time::sleep(self.latency).await;
+        if repo.id() != REPO {
+            return Err(AppError::NotFound(format!(
+                "Repository {} not found; developer mode serves only {}",
+                repo, REPO
+            )));
+        }
+        Ok(self
+            .samples
+            .iter()
+            .map(|sample| ListedFile {
+                path: sample.path.to_string(),
+                size: sample.data.len() as u64,
+                xet_hash: sample.hash.clone(),
+            })
+            .collect())
+    }
+
+    async fn download(&self, request: DownloadRequest<'_>) -> Result<Download, AppError> {
+        let data = self.sample(request.hash)?.data.clone();
+        let data = match request.range {
+            Some(range) => data.slice(range.start as usize..=range.end as usize),
+            None => data,
+        };
+        let length = data.len() as u64;
+        let upstream_bytes = Arc::new(AtomicU64::new(0));
+        let (sender, receiver) = mpsc::channel(4);
+        let (latency, counter) = (self.latency, upstream_bytes.clone());
+        tokio::spawn(async move {
+            tokio::time::sleep(latency).await;
+            let mut rest = data;
+            while !rest.is_empty() {
+                let chunk = rest.split_to(CHUNK.min(rest.len()));
+                counter.fetch_add(chunk.len() as u64, Ordering::Relaxed);
+                if sender.send(Ok::<_, io::Error>(chunk)).await.is_err() {
+                    return;
+                }
+            }
+        });
+        Ok(Download {
+            body: Box::pin(ReceiverStream::new(receiver)),
+            length: Some(length),
+            upstream_bytes,
+            cached: None,
+            peer: false,
+        })
+    }
+
+    async fn file_size(
+        &self,
+        _repo: &RepoRef,
+        hash: &str,
+        _hf_token: &str,
+    ) -> Result<Option<u64>, AppError> {
+        Ok(Some(self.sample(hash)?.data.len() as u64))
+    }
+
+    async fn check(&self) -> Result<String, String> {
+        Ok(format!(
+            "developer mode, {} sample files, {:?} latency",
+            self.samples.len(),
+            self.latency
+        ))
+    }
+}
+
+/// Safetensors file of one F32 `rows` x `cols` tensor of pseudo-random
+/// weights, the same every time
+fn safetensors(rows: usize, cols: usize) -> Bytes {
+    let size = rows * cols * 4;
+    let mut header = format!(
+        r#"{{"weight":{{"dtype":"F32","shape":[{},{}],"data_offsets":[0,{}]}}}}"#,
+        rows, cols, size
+    );
+    // The data starts 8-byte aligned
+    while header.len() % 8 != 0 {
+        header.push(' ');
+    }
+    let mut out = Vec::with_capacity(8 + header.len() + size);
+    out.extend((header.len() as u64).to_le_bytes());
+    out.extend(header.as_bytes());
+    let mut state = 0x2545_f491_4f6c_dd1du64;
+    for _ in 0..rows * cols {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        let weight = ((state >> 40) as f32 / (1u64 << 24) as f32 - 0.5) / 10.0;
+        out.extend(weight.to_le_bytes());
+    }
+    out.into()
+}
+
+/// Set up `--dev`: the dev engine, and a cache somewhere to put it
+pub fn enable() {
+    std::env::set_var("XET_ENGINE", "dev");
+    if !crate::cache::configured() {
+        let dir = std::env::temp_dir().join("xet-proxy-dev");
+        std::env::set_var("CACHE_DIR", dir);
+    }
+}
+
+/// Whether the dev engine serves downloads
+pub fn enabled() -> bool {
+    std::env::var("XET_ENGINE").is_ok_and(|engine| engine == "dev")
+}
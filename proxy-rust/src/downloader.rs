@@ -0,0 +1,502 @@
+//! Download backends
+//!
+//! [`Downloader`] is what the handlers use to list repositories and stream
+//! files. Two engines implement it, selected with `XET_ENGINE`:
+//!
+//! - `cli` (default) - spawns the Zig `xet-download` binary per request and
+//!   streams its stdout; failures are summarized from its stderr. Failures
+//!   before the first byte get a real HTTP status; later ones abort the
+//!   response so clients never see a truncated body as a success. With
+//!   `CLI_WORKERS` set, downloads go to persistent [`crate::workers`]
+//!   instead of a process each.
+//! - `native` - the in-process engine in [`crate::xet`], which reports
+//!   upstream errors with their real status before any byte is sent. It
+//!   does not upload.
+
+use crate::backoff::{UpstreamBackoff, RATE_LIMITED_SIGNATURE};
+use crate::listing::{self, ListPage, ListedFile};
+use crate::manifest::{self, ManifestChunk};
+use crate::range::ByteRange;
+use crate::repo::RepoRef;
+use crate::slots::Priority;
+use crate::subprocess::{self, Cli};
+use crate::transfer::CountingReader;
+use crate::upload::{self, UploadRequest, UploadResult};
+use crate::workers::{Job, JobFailure, WorkerPool};
+use crate::AppError;
+use async_trait::async_trait;
+use axum::body::Bytes;
+use futures_core::Stream;
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::AtomicU64;
+use std::sync::Arc;
+use std::task::{ready, Context, Poll};
+use std::time::SystemTime;
+use tokio::io::AsyncRead;
+use tokio::process::ChildStdout;
+use tokio::sync::oneshot;
+use tokio::time::Instant;
+use tokio_stream::StreamExt;
+use tokio_util::io::ReaderStream;
+use tracing::{error, info, Span};
+
+/// File bytes as they arrive from upstream
+pub type ByteStream = Pin<Box<dyn Stream<Item = io::Result<Bytes>> + Send>>;
+
+/// What to download
+#[derive(Clone, Copy)]
+pub struct DownloadRequest<'a> {
+    /// Repository the file belongs to; its CAS token authorizes the download
+    pub repo: &'a RepoRef,
+    pub hash: &'a str,
+    pub hf_token: &'a str,
+    pub range: Option<ByteRange>,
+    /// Expected body length, if known from a listing
+    pub length: Option<u64>,
+    /// Abort the transfer at this point in time
+    pub deadline: Option<Instant>,
+    /// Place in line where downloads wait for capacity
+    pub priority: Priority,
+    /// Download the whole file into the cache before returning, where there
+    /// is one (`X-Transfer-Mode: spool`)
+    pub spool: bool,
+}
+
+/// A started download
+pub struct Download {
+    pub body: ByteStream,
+    /// Length of the body, if known before streaming
+    pub length: Option<u64>,
+    /// Bytes received from upstream so far
+    pub upstream_bytes: Arc<AtomicU64>,
+    /// When the file was fetched into the cache, if served from it
+    pub cached: Option<SystemTime>,
+    /// Served from a copy another proxy sharing the cache fetched
+    pub peer: bool,
+}
+
+#[async_trait]
+pub trait Downloader: Send + Sync {
+    /// List the XET-enabled files of a repository
+    async fn list(&self, repo: &RepoRef, hf_token: &str) -> Result<Vec<ListedFile>, AppError>;
+
+    /// One page of [`Self::list`], from the `cursor` of the previous page;
+    /// engines that can't page list the whole repository as one
+    async fn list_page(
+        &self,
+        repo: &RepoRef,
+        hf_token: &str,
+        cursor: Option<&str>,
+    ) -> Result<ListPage, AppError> {
+        if cursor.is_some() {
+            return Err(AppError::BadRequest(
+                "Listing cursors require the native engine (XET_ENGINE=native)".to_string(),
+            ));
+        }
+        Ok(ListPage {
+            files: self.list(repo, hf_token).await?,
+            cursor: None,
+        })
+    }
+
+    /// Commit the revision of `repo` points at, if the engine can tell
+    async fn commit(&self, _repo: &RepoRef, _hf_token: &str) -> Result<Option<String>, AppError> {
+        Ok(None)
+    }
+
+    /// Start streaming a file by XET hash
+    async fn download(&self, request: DownloadRequest<'_>) -> Result<Download, AppError>;
+
+    /// Size of a file by XET hash, if the engine can tell without downloading it
+    async fn file_size(
+        &self,
+        repo: &RepoRef,
+        hash: &str,
+        hf_token: &str,
+    ) -> Result<Option<u64>, AppError>;
+
+    /// Ordered chunk hashes and lengths of a file by XET hash
+    async fn manifest(
+        &self,
+        _repo: &RepoRef,
+        _hash: &str,
+        _hf_token: &str,
+    ) -> Result<Vec<ManifestChunk>, AppError> {
+        Err(AppError::NotImplemented(
+            "Chunk manifests require the cli engine (XET_ENGINE=cli)".to_string(),
+        ))
+    }
+
+    /// Upload a file and commit it to the repository
+    async fn upload(&self, _request: UploadRequest<'_>) -> Result<UploadResult, AppError> {
+        Err(AppError::NotImplemented(
+            "Uploads require the cli engine (XET_ENGINE=cli)".to_string(),
+        ))
+    }
+
+    /// Engine failures so far, by signature
+    fn failure_counts(&self) -> BTreeMap<String, u64> {
+        BTreeMap::new()
+    }
+
+    /// Whether the engine is able to run, for `/readyz`
+    async fn check(&self) -> Result<String, String> {
+        Ok("built in".to_string())
+    }
+}
+
+/// Downloader shelling out to the Zig CLI
+pub struct CliDownloader {
+    cli: Cli,
+    backoff: UpstreamBackoff,
+    /// Persistent workers serving downloads, if `CLI_WORKERS` is set
+    workers: Option<WorkerPool>,
+}
+
+impl CliDownloader {
+    pub fn new(cli: Cli, backoff: UpstreamBackoff) -> Self {
+        let workers = WorkerPool::from_env(&cli);
+        Self {
+            cli,
+            backoff,
+            workers,
+        }
+    }
+
+    /// Spawn a CLI process for one download and supervise it in the
+    /// background, returning its stdout and a channel reporting how it ended
+    fn spawn_download(
+        &self,
+        request: &DownloadRequest<'_>,
+        repo: String,
+        cancelled: oneshot::Receiver<()>,
+    ) -> Result<(ChildStdout, oneshot::Receiver<Result<(), AppError>>), AppError> {
+        let mut command = self.cli.command();
+        command
+            .arg(request.repo.id()) // Repository for the CAS token
+            .arg(request.hash) // Pass hash as second argument
+            .env("HF_REPO_TYPE", request.repo.repo_type.as_str())
+            .env("HF_REVISION", request.repo.revision_segment());
+        if let Some(range) = request.range {
+            command.arg(range.cli_arg());
+        }
+
+        // Spawn the Zig CLI process to download the file
+        let mut child = command
+            .env("HF_TOKEN", request.hf_token)
+            .stdout(std::process::Stdio::piped())
+            .stderr(std::process::Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()
+            .map_err(|e| AppError::Internal(format!("Failed to spawn zig process: {}", e)))?;
+
+        let stdout = child
+            .stdout
+            .take()
+            .ok_or_else(|| AppError::Internal("Failed to capture stdout".to_string()))?;
+
+        let stderr = child
+            .stderr
+            .take()
+            .ok_or_else(|| AppError::Internal("Failed to capture stderr".to_string()))?;
+
+        // Supervise the child in the background: keep its stderr tail, enforce
+        // the request's time budget, kill it if the client goes away, and
+        // report how it exited
+        let cli = self.cli.clone();
+        let backoff = self.backoff.clone();
+        let label = request.hash.to_string();
+        let deadline = request.deadline;
+        let (exit_sender, exit) = oneshot::channel();
+        tokio::spawn(crate::upstream::inherit(async move {
+            let stderr_task = tokio::spawn(crate::upstream::inherit({
+                let label = label.clone();
+                async move { subprocess::collect_stderr_tail(stderr, &label, Span::current).await }
+            }));
+
+            let budget_exceeded = async {
+                match deadline {
+                    Some(deadline) => tokio::time::sleep_until(deadline).await,
+                    None => std::future::pending().await,
+                }
+            };
+            let (status, timed_out) = tokio::select! {
+                status = child.wait() => (status, false),
+                _ = budget_exceeded => {
+                    error!("Download of {} exceeded its time budget, killing zig process", label);
+                    let _ = child.kill().await;
+                    (child.wait().await, true)
+                }
+                Ok(()) = cancelled => {
+                    info!("Client went away, killing zig process for {}", label);
+                    let _ = child.kill().await;
+                    return;
+                }
+            };
+
+            let tail = stderr_task.await.unwrap_or_default();
+            let outcome = match status {
+                Ok(status) if status.success() => {
+                    backoff.record_success(&repo);
+                    Ok(())
+                }
+                Ok(status) => {
+                    let signature = cli.record_failure(&status, &tail);
+                    if signature == RATE_LIMITED_SIGNATURE {
+                        backoff.record_rate_limited(&repo);
+                    }
+                    Err(if timed_out {
+                        AppError::Timeout("Download exceeded its time budget".to_string())
+                    } else {
+                        signature_error(&signature, &repo)
+                    })
+                }
+                Err(e) => {
+                    error!("Failed to wait for zig process [{}]: {}", label, e);
+                    Err(AppError::Internal(format!(
+                        "Failed to wait for zig process: {}",
+                        e
+                    )))
+                }
+            };
+            let _ = exit_sender.send(outcome);
+        }));
+        Ok((stdout, exit))
+    }
+}
+
+/// Stream a download's output, holding the response until the first bytes
+/// arrive so that a failure up front is reported with a status instead of an
+/// empty 200
+async fn stream_output<R>(
+    output: R,
+    exit: oneshot::Receiver<Result<(), AppError>>,
+    mut guard: ChildGuard,
+    length: Option<u64>,
+) -> Result<Download, AppError>
+where
+    R: AsyncRead + Send + Unpin + 'static,
+{
+    let upstream = CountingReader::new(output);
+    let upstream_bytes = upstream.counter();
+    let mut output = ReaderStream::new(upstream);
+
+    let body: ByteStream = match output.next().await {
+        Some(Ok(chunk)) => {
+            let delivered = chunk.len() as u64;
+            if length == Some(delivered) {
+                guard.disarm();
+            }
+            Box::pin(tokio_stream::once(Ok(chunk)).chain(CliBody {
+                output,
+                exit: Some(exit),
+                guard,
+                length,
+                delivered,
+            }))
+        }
+        Some(Err(e)) => {
+            return Err(AppError::Internal(format!(
+                "Failed to read zig output: {}",
+                e
+            )))
+        }
+        None => {
+            guard.disarm();
+            exit_status(exit.await)?;
+            Box::pin(tokio_stream::empty())
+        }
+    };
+    Ok(Download {
+        body,
+        length,
+        upstream_bytes,
+        cached: None,
+        peer: false,
+    })
+}
+
+#[async_trait]
+impl Downloader for CliDownloader {
+    async fn list(&self, repo: &RepoRef, hf_token: &str) -> Result<Vec<ListedFile>, AppError> {
+        listing::list_repo(&self.cli, repo, hf_token).await
+    }
+
+    async fn download(&self, request: DownloadRequest<'_>) -> Result<Download, AppError> {
+        // CAS requests are rate limited per token repository
+        let repo = request.repo.to_string();
+        self.backoff.check(&repo)?;
+        if let Some(range) = request.range {
+            info!("Serving {} of {}", range.content_range(), request.hash);
+        }
+        let length = request.length.or(request.range.map(|range| range.len()));
+
+        let (cancel, cancelled) = oneshot::channel();
+        let guard = ChildGuard(Some(cancel));
+        match &self.workers {
+            Some(workers) => {
+                let backoff = self.backoff.clone();
+                let job = Job {
+                    repo: request.repo,
+                    hash: request.hash,
+                    hf_token: request.hf_token,
+                    range: request.range,
+                    priority: request.priority,
+                };
+                let (output, exit) = workers
+                    .start(
+                        job,
+                        request.deadline,
+                        cancelled,
+                        move |result| match result {
+                            Ok(()) => {
+                                backoff.record_success(&repo);
+                                Ok(())
+                            }
+                            Err(JobFailure::Failed(signature)) => {
+                                if signature == RATE_LIMITED_SIGNATURE {
+                                    backoff.record_rate_limited(&repo);
+                                }
+                                Err(signature_error(&signature, &repo))
+                            }
+                            Err(JobFailure::TimedOut) => Err(AppError::Timeout(
+                                "Download exceeded its time budget".to_string(),
+                            )),
+                        },
+                    )
+                    .await?;
+                stream_output(output, exit, guard, length).await
+            }
+            None => {
+                let (stdout, exit) = self.spawn_download(&request, repo, cancelled)?;
+                stream_output(stdout, exit, guard, length).await
+            }
+        }
+    }
+
+    async fn file_size(
+        &self,
+        _repo: &RepoRef,
+        _hash: &str,
+        _hf_token: &str,
+    ) -> Result<Option<u64>, AppError> {
+        // The CLI only reports sizes as part of a repository listing
+        Ok(None)
+    }
+
+    async fn manifest(
+        &self,
+        repo: &RepoRef,
+        hash: &str,
+        hf_token: &str,
+    ) -> Result<Vec<ManifestChunk>, AppError> {
+        manifest::chunk_manifest(&self.cli, repo, hash, hf_token).await
+    }
+
+    async fn upload(&self, request: UploadRequest<'_>) -> Result<UploadResult, AppError> {
+        upload::upload(&self.cli, request).await
+    }
+
+    fn failure_counts(&self) -> BTreeMap<String, u64> {
+        self.cli.failure_counts()
+    }
+
+    async fn check(&self) -> Result<String, String> {
+        self.cli.version().await
+    }
+}
+
+/// Map a CLI failure signature onto the error reported to the client
+pub fn signature_error(signature: &str, repo: &str) -> AppError {
+    let message = format!("Download failed ({})", signature);
+    match signature {
+        // The CAS token request for the repository was refused
+        "error: RepoAccessDenied" => AppError::Forbidden(format!(
+            "No access to '{}' (gated or restricted) to authorize the download",
+            repo
+        )),
+        "error: RepoNotFound" => AppError::NotFound(format!(
+            "Repository '{}' not found, or not visible with this token",
+            repo
+        )),
+        RATE_LIMITED_SIGNATURE => AppError::RateLimited {
+            message,
+            retry_after: None,
+        },
+        "error: Unauthorized" | "error: AuthenticationFailed" => AppError::Unauthorized(message),
+        "error: Forbidden" => AppError::Forbidden(message),
+        "error: NotFound" | "error: FileNotFound" => AppError::NotFound(message),
+        _ => AppError::Internal(message),
+    }
+}
+
+fn exit_status(
+    outcome: Result<Result<(), AppError>, oneshot::error::RecvError>,
+) -> Result<(), AppError> {
+    outcome.unwrap_or_else(|_| {
+        Err(AppError::Internal(
+            "zig process supervisor exited".to_string(),
+        ))
+    })
+}
+
+/// Kills the child when dropped, unless disarmed once its output is complete
+struct ChildGuard(Option<oneshot::Sender<()>>);
+
+impl ChildGuard {
+    fn disarm(&mut self) {
+        self.0 = None;
+    }
+}
+
+impl Drop for ChildGuard {
+    fn drop(&mut self) {
+        if let Some(cancel) = self.0.take() {
+            let _ = cancel.send(());
+        }
+    }
+}
+
+/// CLI output that ends with an error if the child exits unsuccessfully (or
+/// its worker job fails), and kills the child if dropped before the file was
+/// fully delivered
+struct CliBody<R> {
+    output: ReaderStream<CountingReader<R>>,
+    /// Exit outcome, awaited once stdout is exhausted
+    exit: Option<oneshot::Receiver<Result<(), AppError>>>,
+    guard: ChildGuard,
+    length: Option<u64>,
+    delivered: u64,
+}
+
+impl<R: AsyncRead + Unpin> Stream for CliBody<R> {
+    type Item = io::Result<Bytes>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if let Some(item) = ready!(Pin::new(&mut self.output).poll_next(cx)) {
+            if let Ok(chunk) = &item {
+                self.delivered += chunk.len() as u64;
+                // A body with a known length is dropped after its last byte
+                // rather than polled to its end
+                if self.length == Some(self.delivered) {
+                    self.guard.disarm();
+                }
+            }
+            return Poll::Ready(Some(item));
+        }
+        self.guard.disarm();
+        let Some(exit) = self.exit.as_mut() else {
+            return Poll::Ready(None);
+        };
+        let outcome = ready!(Pin::new(exit).poll(cx));
+        self.exit = None;
+        Poll::Ready(match exit_status(outcome) {
+            Ok(()) => None,
+            // Surfaces as an aborted response rather than a clean end
+            Err(e) => Some(Err(io::Error::other(e.message().to_string()))),
+        })
+    }
+}
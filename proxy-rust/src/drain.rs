@@ -0,0 +1,171 @@
+//! Downloads in flight, for deciding how to drain
+//!
+//! Every download streaming to a client registers here while it streams
+//! (see [`crate::transfer::TransferStream`]). `GET /admin/drain-status`
+//! lists them with whether the proxy is draining and how much of the drain
+//! time is left, together with the running prefetch jobs and archives, so
+//! an operator can tell whether waiting is worth it or the pod can be
+//! force-terminated:
+//!
+//! ```json
+//! {"draining": true, "drain_secs": 25, "remaining_secs": 12,
+//!  "transfers": [{"request_id": "...", "route": "/download/:owner/:repo/*file",
+//!    "hash": "...", "repo": "owner/repo", "path": "model.safetensors",
+//!    "client": "alice", "bytes_sent": 1073741824, "bytes_total": 4294967296,
+//!    "bytes_remaining": 3221225472, "elapsed_ms": 10922,
+//!    "throughput_bps": 98304000}],
+//!  "jobs": [...]}
+//! ```
+//!
+//! `client` is the authenticated identity, when there is one. A transfer's
+//! `bytes_total` and `bytes_remaining` are absent when its size isn't known
+//! up front.
+
+use crate::progress::JobProgress;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+/// One download as it streams
+struct Entry {
+    request_id: Option<String>,
+    route: &'static str,
+    hash: String,
+    repo: String,
+    path: Option<String>,
+    client: Option<String>,
+    started: Instant,
+    total: Option<u64>,
+    sent: Arc<AtomicU64>,
+}
+
+/// What is known about a download when it starts streaming
+pub struct Transfer {
+    pub route: &'static str,
+    pub hash: String,
+    pub repo: String,
+    pub path: Option<String>,
+    pub client: Option<String>,
+    pub started: Instant,
+    /// Bytes the response will carry, if known
+    pub total: Option<u64>,
+}
+
+/// The downloads streaming now
+#[derive(Clone, Default)]
+pub struct InFlight {
+    next: Arc<AtomicU64>,
+    transfers: Arc<Mutex<BTreeMap<u64, Entry>>>,
+}
+
+impl InFlight {
+    /// Register a download until the returned handle is dropped; it counts
+    /// the bytes sent in its [`Registration::sent`] counter
+    pub fn register(&self, transfer: Transfer) -> Registration {
+        let id = self.next.fetch_add(1, Ordering::Relaxed);
+        let sent = Arc::new(AtomicU64::new(0));
+        let entry = Entry {
+            request_id: crate::upstream::current_id(),
+            route: transfer.route,
+            hash: transfer.hash,
+            repo: transfer.repo,
+            path: transfer.path,
+            client: transfer.client,
+            started: transfer.started,
+            total: transfer.total,
+            sent: sent.clone(),
+        };
+        self.transfers.lock().unwrap().insert(id, entry);
+        Registration {
+            in_flight: self.clone(),
+            id,
+            sent,
+        }
+    }
+
+    /// The downloads streaming now, oldest first
+    pub fn list(&self) -> Vec<TransferStatus> {
+        let transfers = self.transfers.lock().unwrap();
+        transfers
+            .values()
+            .map(|entry| {
+                let sent = entry.sent.load(Ordering::Relaxed);
+                let elapsed = entry.started.elapsed();
+                TransferStatus {
+                    request_id: entry.request_id.clone(),
+                    route: entry.route,
+                    hash: entry.hash.clone(),
+                    repo: entry.repo.clone(),
+                    path: entry.path.clone(),
+                    client: entry.client.clone(),
+                    bytes_sent: sent,
+                    bytes_total: entry.total,
+                    bytes_remaining: entry.total.map(|total| total.saturating_sub(sent)),
+                    elapsed_ms: elapsed.as_millis() as u64,
+                    throughput_bps: if elapsed.is_zero() {
+                        0
+                    } else {
+                        (sent as f64 / elapsed.as_secs_f64()) as u64
+                    },
+                }
+            })
+            .collect()
+    }
+}
+
+/// A registered download, unregistered when dropped
+pub struct Registration {
+    in_flight: InFlight,
+    id: u64,
+    sent: Arc<AtomicU64>,
+}
+
+impl Registration {
+    /// Count `n` more bytes sent
+    pub fn sent(&self, n: u64) {
+        self.sent.fetch_add(n, Ordering::Relaxed);
+    }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        self.in_flight.transfers.lock().unwrap().remove(&self.id);
+    }
+}
+
+/// One download of `GET /admin/drain-status`
+#[derive(Serialize)]
+pub struct TransferStatus {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+    pub route: &'static str,
+    pub hash: String,
+    pub repo: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client: Option<String>,
+    pub bytes_sent: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bytes_total: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bytes_remaining: Option<u64>,
+    pub elapsed_ms: u64,
+    pub throughput_bps: u64,
+}
+
+/// Response of `GET /admin/drain-status`
+#[derive(Serialize)]
+pub struct DrainStatus {
+    pub draining: bool,
+    /// How long in-flight requests get to finish once draining
+    pub drain_secs: u64,
+    /// Drain time left, while draining
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub remaining_secs: Option<u64>,
+    pub transfers: Vec<TransferStatus>,
+    /// Running prefetch jobs and archives
+    pub jobs: Vec<JobProgress>,
+}
@@ -0,0 +1,110 @@
+//! Activity event bus
+//!
+//! Download handlers publish structured events here; `/events` fans them out
+//! to any number of Server-Sent Events subscribers. Publishing never blocks:
+//! when nobody is listening the event is simply dropped, and slow subscribers
+//! skip ahead instead of holding back downloads.
+
+use crate::shutdown::TakeUntil;
+use axum::response::sse::{Event, KeepAlive, Sse};
+use futures_core::Stream;
+use serde::Serialize;
+use std::convert::Infallible;
+use std::future::Future;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::sync::broadcast;
+use tokio_stream::wrappers::BroadcastStream;
+use tokio_stream::StreamExt;
+
+/// Number of events buffered per subscriber before it starts lagging
+const CHANNEL_CAPACITY: usize = 1024;
+
+/// A single activity event, serialized as one SSE `data:` payload
+#[derive(Clone, Debug, Serialize)]
+pub struct ProxyEvent {
+    /// Milliseconds since the Unix epoch
+    pub timestamp: u64,
+    #[serde(flatten)]
+    pub kind: EventKind,
+}
+
+// Variant names double as the wire `type`, so the shared prefix is intentional
+#[allow(clippy::enum_variant_names)]
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum EventKind {
+    DownloadStarted {
+        hash: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        repo: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        file: Option<String>,
+    },
+    DownloadFinished {
+        hash: String,
+        bytes: u64,
+        duration_ms: u64,
+    },
+    DownloadFailed {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        hash: Option<String>,
+        error: String,
+    },
+}
+
+impl EventKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            EventKind::DownloadStarted { .. } => "download_started",
+            EventKind::DownloadFinished { .. } => "download_finished",
+            EventKind::DownloadFailed { .. } => "download_failed",
+        }
+    }
+}
+
+/// Broadcast channel shared by all handlers
+#[derive(Clone)]
+pub struct EventBus {
+    sender: broadcast::Sender<ProxyEvent>,
+}
+
+impl EventBus {
+    pub fn new() -> Self {
+        let (sender, _) = broadcast::channel(CHANNEL_CAPACITY);
+        Self { sender }
+    }
+
+    /// Publish an event to all current subscribers
+    pub fn publish(&self, kind: EventKind) {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis() as u64)
+            .unwrap_or(0);
+        // An error only means there are no subscribers right now
+        let _ = self.sender.send(ProxyEvent { timestamp, kind });
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<ProxyEvent> {
+        self.sender.subscribe()
+    }
+
+    /// Build an SSE response streaming every future event, until `until`
+    /// resolves
+    pub fn sse(
+        &self,
+        until: impl Future<Output = ()> + Send + 'static,
+    ) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
+        let stream = BroadcastStream::new(self.subscribe()).filter_map(|event| {
+            // Lagged subscribers just miss the overwritten events
+            let event = event.ok()?;
+            Event::default()
+                .event(event.kind.name())
+                .json_data(&event)
+                .ok()
+                .map(Ok)
+        });
+        let stream = TakeUntil::new(stream, until);
+
+        Sse::new(stream).keep_alive(KeepAlive::default())
+    }
+}
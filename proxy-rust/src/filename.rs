@@ -0,0 +1,181 @@
+//! Content-Disposition filename templates
+//!
+//! Templates are plain strings with `{placeholder}` fields, e.g.
+//! `{repo}__{revision}__{basename}`. Supported placeholders:
+//!
+//! | Placeholder  | Value                                   |
+//! |--------------|-----------------------------------------|
+//! | `{owner}`    | Repository owner                        |
+//! | `{repo}`     | Repository name (without the owner)     |
+//! | `{revision}` | Git revision the file was resolved at   |
+//! | `{path}`     | File path, with `/` replaced by `_`     |
+//! | `{basename}` | Last path component                     |
+//! | `{hash}`     | Full XET hash                           |
+//! | `{hash8}`    | First 8 characters of the XET hash      |
+//!
+//! The default, `{basename}`, keeps the file's own name. Hash-only downloads
+//! of files the catalog doesn't know have no repository fields; when a
+//! template references one of them the file is named `{hash8}.bin` instead.
+//!
+//! Names are sent both as a quoted ASCII `filename` (other characters
+//! replaced by `_`) and as an RFC 5987 `filename*` carrying the UTF-8 name.
+
+use std::fmt;
+
+/// Template used when none is configured
+pub const DEFAULT_TEMPLATE: &str = "{basename}";
+
+const PLACEHOLDERS: &[&str] = &[
+    "owner", "repo", "revision", "path", "basename", "hash", "hash8",
+];
+
+/// What is known about the file being served
+pub struct FileContext<'a> {
+    pub owner: Option<&'a str>,
+    pub repo: Option<&'a str>,
+    pub revision: Option<&'a str>,
+    pub path: Option<&'a str>,
+    pub hash: &'a str,
+}
+
+impl FileContext<'_> {
+    fn value(&self, name: &str) -> Option<String> {
+        match name {
+            "owner" => self.owner.map(str::to_string),
+            "repo" => self.repo.map(str::to_string),
+            "revision" => self.revision.map(str::to_string),
+            "path" => self.path.map(|p| p.replace('/', "_")),
+            "basename" => self
+                .path
+                .map(|p| p.rsplit('/').next().unwrap_or(p).to_string()),
+            "hash" => Some(self.hash.to_string()),
+            "hash8" => Some(self.hash.chars().take(8).collect()),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct TemplateError(String);
+
+impl fmt::Display for TemplateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// A parsed filename template
+#[derive(Clone, Debug)]
+pub struct FilenameTemplate {
+    segments: Vec<Segment>,
+}
+
+#[derive(Clone, Debug)]
+enum Segment {
+    Literal(String),
+    Field(String),
+}
+
+impl FilenameTemplate {
+    pub fn parse(template: &str) -> Result<Self, TemplateError> {
+        let mut segments = Vec::new();
+        let mut rest = template;
+
+        while let Some(start) = rest.find('{') {
+            if start > 0 {
+                segments.push(Segment::Literal(rest[..start].to_string()));
+            }
+            let end = rest[start..].find('}').ok_or_else(|| {
+                TemplateError(format!("Unclosed '{{' in filename template '{}'", template))
+            })?;
+            let name = &rest[start + 1..start + end];
+            if !PLACEHOLDERS.contains(&name) {
+                return Err(TemplateError(format!(
+                    "Unknown placeholder '{{{}}}' in filename template (expected one of: {})",
+                    name,
+                    PLACEHOLDERS.join(", ")
+                )));
+            }
+            segments.push(Segment::Field(name.to_string()));
+            rest = &rest[start + end + 1..];
+        }
+        if !rest.is_empty() {
+            segments.push(Segment::Literal(rest.to_string()));
+        }
+
+        Ok(Self { segments })
+    }
+
+    /// Render the template, or `None` if a referenced field is unknown
+    fn try_render(&self, ctx: &FileContext) -> Option<String> {
+        let mut out = String::new();
+        for segment in &self.segments {
+            match segment {
+                Segment::Literal(text) => out.push_str(text),
+                Segment::Field(name) => out.push_str(&ctx.value(name)?),
+            }
+        }
+        Some(out)
+    }
+
+    /// Render a filename safe to place inside a quoted Content-Disposition value
+    pub fn render(&self, ctx: &FileContext) -> String {
+        let name = self.try_render(ctx).unwrap_or_else(|| {
+            let hash8: String = ctx.hash.chars().take(8).collect();
+            format!("{}.bin", hash8)
+        });
+        sanitize(&name)
+    }
+}
+
+impl Default for FilenameTemplate {
+    fn default() -> Self {
+        Self::parse(DEFAULT_TEMPLATE).expect("default template is valid")
+    }
+}
+
+/// `Content-Disposition` value offering `filename` for download
+pub fn content_disposition(filename: &str) -> String {
+    let ascii: String = filename
+        .chars()
+        .map(|c| if c.is_ascii() { c } else { '_' })
+        .collect();
+    if ascii == filename {
+        return format!("attachment; filename=\"{}\"", filename);
+    }
+    let mut encoded = String::new();
+    for byte in filename.bytes() {
+        match byte {
+            b'A'..=b'Z'
+            | b'a'..=b'z'
+            | b'0'..=b'9'
+            | b'!'
+            | b'#'
+            | b'$'
+            | b'&'
+            | b'+'
+            | b'-'
+            | b'.'
+            | b'^'
+            | b'_'
+            | b'`'
+            | b'|'
+            | b'~' => encoded.push(byte as char),
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    format!(
+        "attachment; filename=\"{}\"; filename*=UTF-8''{}",
+        ascii, encoded
+    )
+}
+
+fn sanitize(name: &str) -> String {
+    name.chars()
+        .map(|c| match c {
+            '/' | '\\' | '"' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect()
+}
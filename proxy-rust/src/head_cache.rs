@@ -0,0 +1,168 @@
+//! In-memory cache of file heads for redirect mode
+//!
+//! Clients asking for `X-Transfer-Mode: redirect` are normally sent to the Hub
+//! for every request, which makes metadata-heavy tools (reading GGUF or
+//! safetensors headers, index JSONs, configs) pay an upstream round trip per
+//! small read. With `HEAD_CACHE_MAX_BYTES` set, the first
+//! `HEAD_CACHE_PREFIX_BYTES` (default 4 MiB) of a file are kept in memory and
+//! requests falling entirely within them, including whole files no larger
+//! than the prefix, are served locally. Everything else is still redirected.
+//! Least recently used heads are evicted once the budget is exceeded.
+
+use crate::downloader::{DownloadRequest, Downloader};
+use crate::range::ByteRange;
+use crate::repo::RepoRef;
+use crate::slots::Priority;
+use crate::AppError;
+use axum::body::Bytes;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::time::Instant;
+use tokio_stream::StreamExt;
+use tracing::debug;
+
+struct Head {
+    bytes: Bytes,
+    last_used: Instant,
+}
+
+#[derive(Default)]
+struct Heads {
+    entries: HashMap<String, Head>,
+    total: u64,
+}
+
+#[derive(Clone)]
+pub struct HeadCache {
+    prefix_bytes: u64,
+    max_bytes: u64,
+    heads: Arc<Mutex<Heads>>,
+    hits: Arc<AtomicU64>,
+    misses: Arc<AtomicU64>,
+}
+
+impl HeadCache {
+    /// Load `HEAD_CACHE_MAX_BYTES` and `HEAD_CACHE_PREFIX_BYTES`; `None` if off
+    pub fn from_env() -> Option<Self> {
+        let max_bytes: u64 = crate::config::positive("HEAD_CACHE_MAX_BYTES")?;
+        let prefix_bytes = crate::config::positive("HEAD_CACHE_PREFIX_BYTES").unwrap_or(4 << 20);
+        assert!(
+            prefix_bytes <= max_bytes,
+            "HEAD_CACHE_PREFIX_BYTES must not exceed HEAD_CACHE_MAX_BYTES"
+        );
+        Some(Self {
+            prefix_bytes,
+            max_bytes,
+            heads: Arc::default(),
+            hits: Arc::default(),
+            misses: Arc::default(),
+        })
+    }
+
+    /// Whether `range` (the whole file if `None`) of a file of `size` bytes
+    /// lies within its head
+    pub fn covers(&self, size: u64, range: Option<ByteRange>) -> bool {
+        range.map_or(size, |r| r.end + 1) <= self.prefix_bytes
+    }
+
+    /// The head of a file, fetched from upstream on a miss
+    pub async fn get(
+        &self,
+        downloader: &dyn Downloader,
+        repo: &RepoRef,
+        hash: &str,
+        size: u64,
+        hf_token: &str,
+        deadline: Option<Instant>,
+    ) -> Result<Bytes, AppError> {
+        if let Some(head) = self.heads.lock().unwrap().entries.get_mut(hash) {
+            head.last_used = Instant::now();
+            self.hits.fetch_add(1, Ordering::Relaxed);
+            return Ok(head.bytes.clone());
+        }
+        self.misses.fetch_add(1, Ordering::Relaxed);
+
+        let length = size.min(self.prefix_bytes);
+        if length == 0 {
+            return Ok(Bytes::new());
+        }
+        let download = downloader
+            .download(DownloadRequest {
+                repo,
+                hash,
+                hf_token,
+                range: Some(ByteRange {
+                    start: 0,
+                    end: length - 1,
+                    size,
+                }),
+                length: Some(length),
+                deadline,
+                priority: Priority::Normal,
+                spool: false,
+            })
+            .await?;
+
+        let mut body = download.body;
+        let mut bytes = Vec::with_capacity(length as usize);
+        while let Some(chunk) = body.next().await {
+            let chunk =
+                chunk.map_err(|e| AppError::Internal(format!("Head fetch failed: {}", e)))?;
+            bytes.extend_from_slice(&chunk);
+        }
+        if bytes.len() as u64 != length {
+            return Err(AppError::Internal(format!(
+                "Head fetch of {} returned {} of {} bytes",
+                hash,
+                bytes.len(),
+                length
+            )));
+        }
+
+        let bytes = Bytes::from(bytes);
+        self.insert(hash, bytes.clone());
+        Ok(bytes)
+    }
+
+    fn insert(&self, hash: &str, bytes: Bytes) {
+        let mut heads = self.heads.lock().unwrap();
+        heads.total += bytes.len() as u64;
+        let replaced = heads.entries.insert(
+            hash.to_string(),
+            Head {
+                bytes,
+                last_used: Instant::now(),
+            },
+        );
+        if let Some(replaced) = replaced {
+            heads.total -= replaced.bytes.len() as u64;
+        }
+
+        while heads.total > self.max_bytes {
+            let Some(oldest) = heads
+                .entries
+                .iter()
+                .min_by_key(|(_, head)| head.last_used)
+                .map(|(hash, _)| hash.clone())
+            else {
+                break;
+            };
+            let evicted = heads.entries.remove(&oldest).unwrap();
+            heads.total -= evicted.bytes.len() as u64;
+            debug!("Evicted head of {} ({} bytes)", oldest, evicted.bytes.len());
+        }
+    }
+
+    pub fn hits(&self) -> u64 {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    pub fn misses(&self) -> u64 {
+        self.misses.load(Ordering::Relaxed)
+    }
+
+    pub fn used_bytes(&self) -> u64 {
+        self.heads.lock().unwrap().total
+    }
+}
@@ -0,0 +1,198 @@
+//! Operator request/response hooks written in Rhai
+//!
+//! `HOOK_SCRIPT` names a [Rhai](https://rhai.rs) script that may define
+//! either or both of:
+//!
+//! - `fn on_request(req)` - `req` is `#{method, path, query, headers}` with
+//!   lowercase header names. Return the (modified) map to rewrite the request
+//!   before routing, set `req.reject = "reason"` to refuse it with 403, or
+//!   return `()` to pass it through unchanged.
+//! - `fn on_response(req, resp)` - `resp` is `#{status, headers}`. Return the
+//!   modified map to set or remove response headers, or `()` to leave them.
+//!
+//! Hooks see the request as received, including credentials. A hook that
+//! errors fails the request rather than silently skipping a policy.
+
+use crate::AppError;
+use axum::{
+    extract::{Request, State},
+    http::{uri::PathAndQuery, HeaderMap, HeaderName, HeaderValue, Uri},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use rhai::{Dynamic, Engine, Map, Scope, AST};
+use std::sync::Arc;
+use tracing::{error, info};
+
+/// Upper bound on operations per hook call, so a runaway script can't hang requests
+const MAX_OPERATIONS: u64 = 1_000_000;
+
+/// Compiled hook script
+#[derive(Clone)]
+pub struct Hooks {
+    engine: Arc<Engine>,
+    ast: Arc<AST>,
+    on_request: bool,
+    on_response: bool,
+}
+
+impl Hooks {
+    /// Compile the script named by `HOOK_SCRIPT`, if set
+    pub fn from_env() -> Option<Self> {
+        let path = std::env::var("HOOK_SCRIPT").ok()?;
+        let mut engine = Engine::new();
+        engine.set_max_operations(MAX_OPERATIONS);
+        let ast = engine
+            .compile_file(path.clone().into())
+            .unwrap_or_else(|e| panic!("Failed to load HOOK_SCRIPT {}: {}", path, e));
+
+        let defines = |name: &str| ast.iter_functions().any(|f| f.name == name);
+        let hooks = Self {
+            on_request: defines("on_request"),
+            on_response: defines("on_response"),
+            engine: Arc::new(engine),
+            ast: Arc::new(ast),
+        };
+        info!(
+            "Loaded hooks from {} (on_request: {}, on_response: {})",
+            path, hooks.on_request, hooks.on_response
+        );
+        Some(hooks)
+    }
+
+    fn call(&self, name: &str, args: impl rhai::FuncArgs) -> Result<Option<Map>, AppError> {
+        let result: Dynamic = self
+            .engine
+            .call_fn(&mut Scope::new(), &self.ast, name, args)
+            .map_err(|e| hook_error(name, e))?;
+        if result.is_unit() {
+            return Ok(None);
+        }
+        result
+            .try_cast::<Map>()
+            .map(Some)
+            .ok_or_else(|| hook_error(name, "must return a map or ()"))
+    }
+}
+
+fn hook_error(name: &str, e: impl std::fmt::Display) -> AppError {
+    error!("Hook {} failed: {}", name, e);
+    AppError::Internal(format!("Hook {} failed", name))
+}
+
+/// Middleware running the hooks around the router
+pub async fn run(State(hooks): State<Hooks>, request: Request, next: Next) -> Response {
+    match run_hooks(&hooks, request, next).await {
+        Ok(response) => response,
+        Err(e) => e.into_response(),
+    }
+}
+
+async fn run_hooks(hooks: &Hooks, mut request: Request, next: Next) -> Result<Response, AppError> {
+    let view = request_view(&request);
+
+    if hooks.on_request {
+        if let Some(rewritten) = hooks.call("on_request", (view.clone(),))? {
+            if let Some(reason) = rewritten.get("reject") {
+                return Err(AppError::Forbidden(reason.to_string()));
+            }
+            apply_request(&mut request, &rewritten)?;
+        }
+    }
+
+    let mut response = next.run(request).await;
+
+    if hooks.on_response {
+        let mut resp = Map::new();
+        resp.insert("status".into(), (response.status().as_u16() as i64).into());
+        resp.insert("headers".into(), headers_view(response.headers()).into());
+        if let Some(annotated) = hooks.call("on_response", (view, resp))? {
+            if let Some(headers) = annotated.get("headers") {
+                let before = headers_view(response.headers());
+                apply_headers(response.headers_mut(), &before, headers, "on_response")?;
+            }
+        }
+    }
+
+    Ok(response)
+}
+
+fn request_view(request: &Request) -> Map {
+    let mut view = Map::new();
+    view.insert("method".into(), request.method().as_str().into());
+    view.insert("path".into(), request.uri().path().into());
+    view.insert(
+        "query".into(),
+        request.uri().query().unwrap_or_default().into(),
+    );
+    view.insert("headers".into(), headers_view(request.headers()).into());
+    view
+}
+
+fn headers_view(headers: &HeaderMap) -> Map {
+    headers
+        .iter()
+        .filter_map(|(name, value)| Some((name.as_str().into(), value.to_str().ok()?.into())))
+        .collect()
+}
+
+fn apply_request(request: &mut Request, view: &Map) -> Result<(), AppError> {
+    let text = |key: &str| view.get(key).and_then(|v| v.clone().into_string().ok());
+    let path = text("path").unwrap_or_else(|| request.uri().path().to_string());
+    let query = text("query").unwrap_or_default();
+
+    let path_and_query = if query.is_empty() {
+        path
+    } else {
+        format!("{}?{}", path, query)
+    };
+    let mut parts = request.uri().clone().into_parts();
+    parts.path_and_query = Some(
+        PathAndQuery::try_from(path_and_query)
+            .map_err(|e| hook_error("on_request", format!("invalid path: {}", e)))?,
+    );
+    *request.uri_mut() = Uri::from_parts(parts)
+        .map_err(|e| hook_error("on_request", format!("invalid URI: {}", e)))?;
+
+    if let Some(headers) = view.get("headers") {
+        let before = headers_view(request.headers());
+        apply_headers(request.headers_mut(), &before, headers, "on_request")?;
+    }
+    Ok(())
+}
+
+/// Apply the hook's edits to `headers`: set changed entries, drop removed ones.
+/// Untouched headers keep all their values.
+fn apply_headers(
+    headers: &mut HeaderMap,
+    before: &Map,
+    wanted: &Dynamic,
+    hook: &str,
+) -> Result<(), AppError> {
+    let wanted = wanted
+        .clone()
+        .try_cast::<Map>()
+        .ok_or_else(|| hook_error(hook, "headers must be a map"))?;
+
+    let removed: Vec<HeaderName> = headers
+        .keys()
+        .filter(|name| before.contains_key(name.as_str()) && !wanted.contains_key(name.as_str()))
+        .cloned()
+        .collect();
+    for name in removed {
+        headers.remove(name);
+    }
+
+    for (name, value) in wanted {
+        let value = value.to_string();
+        if before.get(&name).is_some_and(|v| v.to_string() == value) {
+            continue;
+        }
+        let name = HeaderName::try_from(name.as_str())
+            .map_err(|e| hook_error(hook, format!("invalid header name: {}", e)))?;
+        let value = HeaderValue::try_from(value)
+            .map_err(|e| hook_error(hook, format!("invalid header value: {}", e)))?;
+        headers.insert(name, value);
+    }
+    Ok(())
+}
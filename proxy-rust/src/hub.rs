@@ -0,0 +1,128 @@
+//! Hub endpoint, for mirrors and internal hubs
+//!
+//! `HF_ENDPOINT` (default `https://huggingface.co`) points the proxy at
+//! another hub, such as a regional mirror or one inside an air-gapped
+//! network: listings, revision lookups, CAS token requests and
+//! `X-Transfer-Mode: redirect` locations go there, and the CLI gets it as
+//! `HF_ENDPOINT` too. CAS is wherever the endpoint's token answers send it.
+//!
+//! With `HF_ENDPOINT_ALLOWLIST` (comma-separated URLs), a request can pick
+//! one of them with `X-HF-Endpoint`; any endpoint but those and
+//! `HF_ENDPOINT` is refused with 403. The choice covers the work the request
+//! spawns, and listings are cached per endpoint.
+
+use crate::AppError;
+use axum::extract::Request;
+use axum::middleware::Next;
+use axum::response::Response;
+use std::future::Future;
+use std::sync::{Arc, OnceLock};
+
+pub const DEFAULT_ENDPOINT: &str = "https://huggingface.co";
+pub const ENDPOINT_HEADER: &str = "x-hf-endpoint";
+
+tokio::task_local! {
+    static CURRENT: Arc<str>;
+}
+
+#[derive(Clone)]
+pub struct Hub {
+    default: Arc<str>,
+    allowed: Arc<[Arc<str>]>,
+}
+
+impl Hub {
+    /// Load `HF_ENDPOINT` and `HF_ENDPOINT_ALLOWLIST`
+    pub fn from_env() -> Self {
+        let default = std::env::var("HF_ENDPOINT").map_or_else(
+            |_| DEFAULT_ENDPOINT.into(),
+            |v| {
+                normalize(&v)
+                    .unwrap_or_else(|| panic!("HF_ENDPOINT must be an http(s) URL, got '{}'", v))
+            },
+        );
+        let allowed = std::env::var("HF_ENDPOINT_ALLOWLIST")
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|v| !v.is_empty())
+            .map(|v| {
+                normalize(v).unwrap_or_else(|| {
+                    panic!(
+                        "HF_ENDPOINT_ALLOWLIST entries must be http(s) URLs, got '{}'",
+                        v
+                    )
+                })
+            })
+            .collect();
+        Self { default, allowed }
+    }
+
+    /// `HF_ENDPOINT`, for the startup banner
+    pub fn default_endpoint(&self) -> &str {
+        &self.default
+    }
+
+    /// Endpoints requests may pick besides `HF_ENDPOINT`
+    pub fn allowed(&self) -> usize {
+        self.allowed.len()
+    }
+}
+
+/// `url` without its trailing slashes, if it is an http(s) URL
+fn normalize(url: &str) -> Option<Arc<str>> {
+    let parsed = reqwest::Url::parse(url).ok()?;
+    if !matches!(parsed.scheme(), "http" | "https") || parsed.host().is_none() {
+        return None;
+    }
+    Some(url.trim_end_matches('/').into())
+}
+
+/// The endpoints, loaded on first use
+pub fn configured() -> &'static Hub {
+    static HUB: OnceLock<Hub> = OnceLock::new();
+    HUB.get_or_init(Hub::from_env)
+}
+
+/// Endpoint of the request being served, or the configured one
+pub fn endpoint() -> Arc<str> {
+    CURRENT
+        .try_with(Arc::clone)
+        .unwrap_or_else(|_| configured().default.clone())
+}
+
+/// `future`, under the current request's endpoint; for work spawned on its
+/// behalf
+pub fn inherit<F: Future>(future: F) -> impl Future<Output = F::Output> {
+    let endpoint = CURRENT.try_with(Arc::clone).ok();
+    async move {
+        match endpoint {
+            Some(endpoint) => CURRENT.scope(endpoint, future).await,
+            None => future.await,
+        }
+    }
+}
+
+/// Middleware serving a request from the endpoint in its `X-HF-Endpoint`
+pub async fn select(request: Request, next: Next) -> Result<Response, AppError> {
+    let hub = configured();
+    let Some(value) = request.headers().get(ENDPOINT_HEADER) else {
+        return Ok(next.run(request).await);
+    };
+    let requested = value.to_str().ok().and_then(normalize).ok_or_else(|| {
+        AppError::BadRequest(format!("{} must be an http(s) URL", ENDPOINT_HEADER))
+    })?;
+    let endpoint = hub
+        .allowed
+        .iter()
+        .chain(std::iter::once(&hub.default))
+        .find(|allowed| **allowed == requested)
+        .cloned()
+        .ok_or_else(|| {
+            AppError::Forbidden(format!(
+                "Endpoint {} is not in HF_ENDPOINT_ALLOWLIST",
+                requested
+            ))
+        })?;
+    Ok(CURRENT.scope(endpoint, next.run(request)).await)
+}
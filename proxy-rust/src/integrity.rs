@@ -0,0 +1,645 @@
+//! Integrity verification of streamed files
+//!
+//! With `VERIFY_DOWNLOADS` set, a whole-file download is hashed as it
+//! passes through: the bytes are cut into chunks with XET's gear hash
+//! content-defined chunking, each chunk gets its keyed BLAKE3 hash, the
+//! chunk hashes are folded into the XET merkle tree, and the file hash
+//! derived from its root is compared with the hash the file was requested
+//! by. The last piece of the body is held back until the comparison is
+//! done, so that with `VERIFY_DOWNLOADS=abort` a mismatching download ends
+//! in an error before its last bytes and the client never sees a complete
+//! response. `VERIFY_DOWNLOADS=log` delivers the file anyway and only logs
+//! the mismatch. Either way the outcome is counted in
+//! `xet_proxy_verified_downloads_total` and
+//! `xet_proxy_integrity_failures_total`.
+//!
+//! Ranged downloads are not verified: the file hash covers the whole file.
+//! The algorithms mirror `chunking.zig` and `hashing.zig`.
+
+use crate::downloader::ByteStream;
+use crate::metrics::Metrics;
+use axum::body::Bytes;
+use futures_core::Stream;
+use std::fmt::Write;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tracing::{error, warn};
+
+/// What to do when a streamed file doesn't match its hash
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum VerifyMode {
+    Off,
+    /// Log and count the mismatch, deliver the file anyway
+    Log,
+    /// Fail the response before its last bytes
+    Abort,
+}
+
+impl VerifyMode {
+    /// Load `VERIFY_DOWNLOADS` (`off`, `log` or `abort`; default `off`)
+    pub fn from_env() -> Self {
+        match std::env::var("VERIFY_DOWNLOADS").as_deref() {
+            Err(_) | Ok("off") => Self::Off,
+            Ok("log") => Self::Log,
+            Ok("abort") => Self::Abort,
+            Ok(other) => panic!(
+                "VERIFY_DOWNLOADS must be 'off', 'log' or 'abort', got '{}'",
+                other
+            ),
+        }
+    }
+}
+
+const MIN_CHUNK_SIZE: u64 = 8192;
+const MAX_CHUNK_SIZE: u64 = 131072;
+const GEAR_HASH_MASK: u64 = 0xFFFF000000000000;
+/// Bytes of the first chunk that are not hashed, as no boundary can fall there
+const FIRST_CHUNK_SKIP: u64 = MIN_CHUNK_SIZE - 64 - 1;
+/// Largest number of nodes merged into one parent
+const MAX_MERGE: usize = 9;
+
+const DATA_KEY: [u8; 32] = [
+    102, 151, 245, 119, 91, 149, 80, 222, 49, 53, 203, 172, 165, 151, 24, 28, 157, 228, 33, 16,
+    155, 235, 43, 88, 180, 208, 176, 75, 147, 173, 242, 41,
+];
+const INTERNAL_NODE_KEY: [u8; 32] = [
+    1, 126, 197, 199, 165, 71, 41, 150, 253, 148, 102, 102, 180, 138, 2, 230, 93, 221, 83, 111, 55,
+    199, 109, 210, 248, 99, 82, 230, 74, 83, 113, 63,
+];
+const FILE_HASH_KEY: [u8; 32] = [0; 32];
+
+#[rustfmt::skip]
+const GEAR_HASH_TABLE: [u64; 256] = [
+    0xb088d3a9e840f559, 0x5652c7f739ed20d6, 0x45b28969898972ab, 0x6b0a89d5b68ec777,
+    0x368f573e8b7a31b7, 0x1dc636dce936d94b, 0x207a4c4e5554d5b6, 0xa474b34628239acb,
+    0x3b06a83e1ca3b912, 0x90e78d6c2f02baf7, 0xe1c92df7150d9a8a, 0x8e95053a1086d3ad,
+    0x5a2ef4f1b83a0722, 0xa50fac949f807fae, 0x0e7303eb80d8d681, 0x99b07edc1570ad0f,
+    0x689d2fb555fd3076, 0x00005082119ea468, 0xc4b08306a88fcc28, 0x3eb0678af6374afd,
+    0xf19f87ab86ad7436, 0xf2129fbfbe6bc736, 0x481149575c98a4ed, 0x0000010695477bc5,
+    0x1fba37801a9ceacc, 0x3bf06fd663a49b6d, 0x99687e9782e3874b, 0x79a10673aa50d8e3,
+    0xe4accf9e6211f420, 0x2520e71f87579071, 0x2bd5d3fd781a8a9b, 0x00de4dcddd11c873,
+    0xeaa9311c5a87392f, 0xdb748eb617bc40ff, 0xaf579a8df620bf6f, 0x86a6e5da1b09c2b1,
+    0xcc2fc30ac322a12e, 0x355e2afec1f74267, 0x2d99c8f4c021a47b, 0xbade4b4a9404cfc3,
+    0xf7b518721d707d69, 0x3286b6587bf32c20, 0x0000b68886af270c, 0xa115d6e4db8a9079,
+    0x484f7e9c97b2e199, 0xccca7bb75713e301, 0xbf2584a62bb0f160, 0xade7e813625dbcc8,
+    0x000070940d87955a, 0x8ae69108139e626f, 0xbd776ad72fde38a2, 0xfb6b001fc2fcc0cf,
+    0xc7a474b8e67bc427, 0xbaf6f11610eb5d58, 0x09cb1f5b6de770d1, 0xb0b219e6977d4c47,
+    0x00ccbc386ea7ad4a, 0xcc849d0adf973f01, 0x73a3ef7d016af770, 0xc807d2d386bdbdfe,
+    0x7f2ac9966c791730, 0xd037a86bc6c504da, 0xf3f17c661eaa609d, 0xaca626b04daae687,
+    0x755a99374f4a5b07, 0x90837ee65b2caede, 0x6ee8ad93fd560785, 0x0000d9e11053edd8,
+    0x9e063bb2d21cdbd7, 0x07ab77f12a01d2b2, 0xec550255e6641b44, 0x78fb94a8449c14c6,
+    0xc7510e1bc6c0f5f5, 0x0000320b36e4cae3, 0x827c33262c8b1a2d, 0x14675f0b48ea4144,
+    0x267bd3a6498deceb, 0xf1916ff982f5035e, 0x86221b7ff434fb88, 0x9dbecee7386f49d8,
+    0xea58f8cac80f8f4a, 0x008d198692fc64d8, 0x6d38704fbabf9a36, 0xe032cb07d1e7be4c,
+    0x228d21f6ad450890, 0x635cb1bfc02589a5, 0x4620a1739ca2ce71, 0xa7e7dfe3aae5fb58,
+    0x0c10ca932b3c0deb, 0x2727fee884afed7b, 0xa2df1c6df9e2ab1f, 0x4dcdd1ac0774f523,
+    0x000070ffad33e24e, 0xa2ace87bc5977816, 0x9892275ab4286049, 0xc2861181ddf18959,
+    0xbb9972a042483e19, 0xef70cd3766513078, 0x00000513abfc9864, 0xc058b61858c94083,
+    0x09e850859725e0de, 0x9197fb3bf83e7d94, 0x7e1e626d12b64bce, 0x520c54507f7b57d1,
+    0xbee1797174e22416, 0x6fd9ac3222e95587, 0x0023957c9adfbf3e, 0xa01c7d7e234bbe15,
+    0xaba2c758b8a38cbb, 0x0d1fa0ceec3e2b30, 0x0bb6a58b7e60b991, 0x4333dd5b9fa26635,
+    0xc2fd3b7d4001c1a3, 0xfb41802454731127, 0x65a56185a50d18cb, 0xf67a02bd8784b54f,
+    0x696f11dd67e65063, 0x00002022fca814ab, 0x8cd6be912db9d852, 0x695189b6e9ae8a57,
+    0xee9453b50ada0c28, 0xd8fc5ea91a78845e, 0xab86bf191a4aa767, 0x0000c6b5c86415e5,
+    0x267310178e08a22e, 0xed2d101b078bca25, 0x3b41ed84b226a8fb, 0x13e622120f28dc06,
+    0xa315f5ebfb706d26, 0x8816c34e3301bace, 0xe9395b9cbb71fdae, 0x002ce9202e721648,
+    0x4283db1d2bb3c91c, 0xd77d461ad2b1a6a5, 0xe2ec17e46eeb866b, 0xb8e0be4039fbc47c,
+    0xdea160c4d5299d04, 0x7eec86c8d28c3634, 0x2119ad129f98a399, 0xa6ccf46b61a283ef,
+    0x2c52cedef658c617, 0x2db4871169acdd83, 0x0000f0d6f39ecbe9, 0x3dd5d8c98d2f9489,
+    0x8a1872a22b01f584, 0xf282a4c40e7b3cf2, 0x8020ec2ccb1ba196, 0x6693b6e09e59e313,
+    0x0000ce19cc7c83eb, 0x20cb5735f6479c3b, 0x762ebf3759d75a5b, 0x207bfe823d693975,
+    0xd77dc112339cd9d5, 0x9ba7834284627d03, 0x217dc513e95f51e9, 0xb27b1a29fc5e7816,
+    0x00d5cd9831bb662d, 0x71e39b806d75734c, 0x7e572af006fb1a23, 0xa2734f2f6ae91f85,
+    0xbf82c6b5022cddf2, 0x5c3beac60761a0de, 0xcdc893bb47416998, 0x6d1085615c187e01,
+    0x77f8ae30ac277c5d, 0x917c6b81122a2c91, 0x5b75b699add16967, 0x0000cf6ae79a069b,
+    0xf3c40afa60de1104, 0x2063127aa59167c3, 0x621de62269d1894d, 0xd188ac1de62b4726,
+    0x107036e2154b673c, 0x0000b85f28553a1d, 0xf2ef4e4c18236f3d, 0xd9d6de6611b9f602,
+    0xa1fc7955fb47911c, 0xeb85fd032f298dbd, 0xbe27502fb3befae1, 0xe3034251c4cd661e,
+    0x441364d354071836, 0x0082b36c75f2983e, 0xb145910316fa66f0, 0x021c069c9847caf7,
+    0x2910dfc75a4b5221, 0x735b353e1c57a8b5, 0xce44312ce98ed96c, 0xbc942e4506bdfa65,
+    0xf05086a71257941b, 0xfec3b215d351cead, 0x00ae1055e0144202, 0xf54b40846f42e454,
+    0x00007fd9c8bcbcc8, 0xbfbd9ef317de9bfe, 0xa804302ff2854e12, 0x39ce4957a5e5d8d4,
+    0xffb9e2a45637ba84, 0x55b9ad1d9ea0818b, 0x00008acbf319178a, 0x48e2bfc8d0fbfb38,
+    0x8be39841e848b5e8, 0x0e2712160696a08b, 0xd51096e84b44242a, 0x1101ba176792e13a,
+    0xc22e770f4531689d, 0x1689eff272bbc56c, 0x00a92a197f5650ec, 0xbc765990bda1784e,
+    0xc61441e392fcb8ae, 0x07e13a2ced31e4a0, 0x92cbe984234e9d4d, 0x8f4ff572bb7d8ac5,
+    0x0b9670c00b963bd0, 0x62955a581a03eb01, 0x645f83e5ea000254, 0x41fce516cd88f299,
+    0xbbda9748da7a98cf, 0x0000aab2fe4845fa, 0x19761b069bf56555, 0x8b8f5e8343b6ad56,
+    0x3e5d1cfd144821d9, 0xec5c1e2ca2b0cd8f, 0xfaf7e0fea7fbb57f, 0x000000d3ba12961b,
+    0xda3f90178401b18e, 0x70ff906de33a5feb, 0x0527d5a7c06970e7, 0x22d8e773607c13e9,
+    0xc9ab70df643c3bac, 0xeda4c6dc8abe12e3, 0xecef1f410033e78a, 0x0024c2b274ac72cb,
+    0x06740d954fa900b4, 0x1d7a299b323d6304, 0xb3c37cb298cbead5, 0xc986e3c76178739b,
+    0x9fabea364b46f58a, 0x6da214c5af85cc56, 0x17a43ed8b7a38f84, 0x6eccec511d9adbeb,
+    0xf9cab30913335afb, 0x4a5e60c5f415eed2, 0x00006967503672b4, 0x9da51d121454bb87,
+    0x84321e13b9bbc816, 0xfb3d6fb6ab2fdd8d, 0x60305eed8e160a8d, 0xcbbf4b14e9946ce8,
+    0x00004f63381b10c3, 0x07d5b7816fcc4e10, 0xe5a536726a6a8155, 0x57afb23447a07fdd,
+    0x18f346f7abc9d394, 0x636dc655d61ad33d, 0xcc8bab4939f7f3f6, 0x63c7a906c1dd187b,
+];
+
+/// Hash as written by XET: four little-endian `u64`s in hex
+pub fn hash_to_hex(hash: &[u8; 32]) -> String {
+    let mut words = *hash;
+    for word in words.chunks_exact_mut(8) {
+        word.reverse();
+    }
+    hex::encode(words)
+}
+
+/// A chunk or internal node of the merkle tree
+#[derive(Clone, Copy)]
+struct Node {
+    hash: [u8; 32],
+    size: u64,
+}
+
+/// Nodes of one tree level that are not merged yet
+#[derive(Default)]
+struct Level {
+    pending: Vec<Node>,
+    /// Nodes this level has received in all
+    count: u64,
+}
+
+/// Incremental XET file hash
+pub struct FileHasher {
+    gear: u64,
+    /// Bytes of the current chunk seen so far
+    chunk_size: u64,
+    first_chunk: bool,
+    chunk: blake3::Hasher,
+    levels: Vec<Level>,
+}
+
+impl Default for FileHasher {
+    fn default() -> Self {
+        Self {
+            gear: 0,
+            chunk_size: 0,
+            first_chunk: true,
+            chunk: blake3::Hasher::new_keyed(&DATA_KEY),
+            levels: Vec::new(),
+        }
+    }
+}
+
+impl FileHasher {
+    pub fn update(&mut self, data: &[u8]) {
+        let mut start = 0;
+        for (i, &byte) in data.iter().enumerate() {
+            self.chunk_size += 1;
+            if self.first_chunk && self.chunk_size < FIRST_CHUNK_SKIP {
+                continue;
+            }
+            self.gear = self
+                .gear
+                .wrapping_add(self.gear.wrapping_add(GEAR_HASH_TABLE[byte as usize]));
+            let boundary = self.chunk_size >= MAX_CHUNK_SIZE
+                || (self.chunk_size >= MIN_CHUNK_SIZE && self.gear & GEAR_HASH_MASK == 0);
+            if boundary {
+                self.chunk.update(&data[start..=i]);
+                start = i + 1;
+                self.end_chunk();
+            }
+        }
+        self.chunk.update(&data[start..]);
+    }
+
+    /// Hash of everything passed to [`update`](Self::update), in hex
+    pub fn finish(mut self) -> String {
+        if self.chunk_size > 0 {
+            self.end_chunk();
+        }
+        let root = self.root();
+        hash_to_hex(blake3::keyed_hash(&FILE_HASH_KEY, &root).as_bytes())
+    }
+
+    /// Merkle root, merging whatever every level still holds
+    fn root(&mut self) -> [u8; 32] {
+        let mut depth = 0;
+        loop {
+            let Some(level) = self.levels.get_mut(depth) else {
+                // No chunks at all
+                return [0; 32];
+            };
+            if level.count == 1 {
+                return level.pending[0].hash;
+            }
+            let pending = std::mem::take(&mut level.pending);
+            if !pending.is_empty() {
+                self.push(depth + 1, merge(&pending));
+            }
+            depth += 1;
+        }
+    }
+
+    fn end_chunk(&mut self) {
+        let chunk = std::mem::replace(&mut self.chunk, blake3::Hasher::new_keyed(&DATA_KEY));
+        let node = Node {
+            hash: chunk.finalize().into(),
+            size: self.chunk_size,
+        };
+        self.gear = 0;
+        self.chunk_size = 0;
+        self.first_chunk = false;
+        self.push(0, node);
+    }
+
+    /// Add a node to level `depth`, merging its pending nodes into the next
+    /// level once their cut point is known
+    fn push(&mut self, depth: usize, node: Node) {
+        if self.levels.len() == depth {
+            self.levels.push(Level::default());
+        }
+        let level = &mut self.levels[depth];
+        level.pending.push(node);
+        level.count += 1;
+        // A group ends after its third node or later whose hash is 0 mod 4,
+        // or at `MAX_MERGE` nodes
+        let len = level.pending.len();
+        let tail = u64::from_le_bytes(node.hash[24..32].try_into().unwrap());
+        if (len > 2 && tail % 4 == 0) || len == MAX_MERGE {
+            let pending = std::mem::take(&mut level.pending);
+            self.push(depth + 1, merge(&pending));
+        }
+    }
+}
+
+/// Parent of `nodes`
+fn merge(nodes: &[Node]) -> Node {
+    let mut lines = String::new();
+    for node in nodes {
+        let _ = writeln!(lines, "{} : {}", hash_to_hex(&node.hash), node.size);
+    }
+    Node {
+        hash: blake3::keyed_hash(&INTERNAL_NODE_KEY, lines.as_bytes()).into(),
+        size: nodes.iter().map(|node| node.size).sum(),
+    }
+}
+
+/// Body that verifies the file it streams against `expected`, holding its
+/// last piece back until the outcome is known
+pub struct Verified {
+    inner: ByteStream,
+    expected: String,
+    mode: VerifyMode,
+    metrics: Metrics,
+    hasher: Option<FileHasher>,
+    held: Option<Bytes>,
+}
+
+impl Verified {
+    pub fn new(inner: ByteStream, expected: &str, mode: VerifyMode, metrics: Metrics) -> Self {
+        Self {
+            inner,
+            expected: expected.to_ascii_lowercase(),
+            mode,
+            metrics,
+            hasher: Some(FileHasher::default()),
+            held: None,
+        }
+    }
+
+    /// Compare the digest with the expected hash; `Err` if the body must fail
+    fn conclude(&mut self) -> io::Result<()> {
+        let Some(hasher) = self.hasher.take() else {
+            return Ok(());
+        };
+        let actual = hasher.finish();
+        let matches = actual == self.expected;
+        self.metrics.record_verification(matches);
+        if matches {
+            return Ok(());
+        }
+        let message = format!(
+            "Integrity check failed: streamed content of {} hashes to {}",
+            self.expected, actual
+        );
+        if self.mode == VerifyMode::Abort {
+            error!("{}, aborting the response", message);
+            self.held = None;
+            return Err(io::Error::new(io::ErrorKind::InvalidData, message));
+        }
+        warn!("{}", message);
+        Ok(())
+    }
+}
+
+impl Stream for Verified {
+    type Item = io::Result<Bytes>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        loop {
+            match self.inner.as_mut().poll_next(cx) {
+                Poll::Ready(Some(Ok(bytes))) => {
+                    if let Some(hasher) = self.hasher.as_mut() {
+                        hasher.update(&bytes);
+                    }
+                    if let Some(previous) = self.held.replace(bytes) {
+                        return Poll::Ready(Some(Ok(previous)));
+                    }
+                }
+                Poll::Ready(Some(Err(e))) => {
+                    // An incomplete transfer cannot be verified
+                    self.hasher = None;
+                    self.held = None;
+                    return Poll::Ready(Some(Err(e)));
+                }
+                Poll::Ready(None) => {
+                    if let Err(e) = self.conclude() {
+                        return Poll::Ready(Some(Err(e)));
+                    }
+                    return Poll::Ready(self.held.take().map(Ok));
+                }
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio_stream::StreamExt;
+
+    /// Inverse of [`hash_to_hex`]
+    fn hex_to_hash(hex: &str) -> [u8; 32] {
+        let mut hash: [u8; 32] = hex::decode(hex).unwrap().try_into().unwrap();
+        for word in hash.chunks_exact_mut(8) {
+            word.reverse();
+        }
+        hash
+    }
+
+    /// `createRandomData` of `verification_test.zig`
+    fn random_data(len: usize, seed: u64) -> Vec<u8> {
+        let mut state = seed;
+        let mut data = Vec::with_capacity(len + 8);
+        while data.len() < len {
+            state = state.wrapping_add(0x9E3779B97F4A7C15);
+            let mut z = state;
+            z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+            z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+            data.extend_from_slice(&(z ^ (z >> 31)).to_le_bytes());
+        }
+        data.truncate(len);
+        data
+    }
+
+    /// Chunk ends as the hasher cuts `data`, fed a byte at a time
+    fn chunk_ends(data: &[u8]) -> Vec<usize> {
+        let mut hasher = FileHasher::default();
+        let mut ends = Vec::new();
+        for (i, byte) in data.iter().enumerate() {
+            hasher.update(std::slice::from_ref(byte));
+            if hasher.chunk_size == 0 {
+                ends.push(i + 1);
+            }
+        }
+        if hasher.chunk_size > 0 {
+            ends.push(data.len());
+        }
+        ends
+    }
+
+    fn file_hash(data: &[u8]) -> String {
+        let mut hasher = FileHasher::default();
+        hasher.update(data);
+        hasher.finish()
+    }
+
+    /// Merkle root of `nodes` computed a level at a time, as
+    /// `buildMerkleTree` of `hashing.zig` does
+    fn batch_root(mut nodes: Vec<Node>) -> [u8; 32] {
+        if nodes.is_empty() {
+            return [0; 32];
+        }
+        while nodes.len() > 1 {
+            let mut parents = Vec::new();
+            let mut rest = &nodes[..];
+            while !rest.is_empty() {
+                let end = rest.len().min(MAX_MERGE);
+                let cut = if rest.len() <= 2 {
+                    rest.len()
+                } else {
+                    (2..end)
+                        .find(|&i| {
+                            u64::from_le_bytes(rest[i].hash[24..].try_into().unwrap()) % 4 == 0
+                        })
+                        .map_or(end, |i| i + 1)
+                };
+                parents.push(merge(&rest[..cut]));
+                rest = &rest[cut..];
+            }
+            nodes = parents;
+        }
+        nodes[0].hash
+    }
+
+    /// File hash of `data` cut at `ends`, computed in one go
+    fn batch_file_hash(data: &[u8], ends: &[usize]) -> String {
+        let mut start = 0;
+        let mut nodes = Vec::new();
+        for &end in ends {
+            nodes.push(Node {
+                hash: blake3::keyed_hash(&DATA_KEY, &data[start..end]).into(),
+                size: (end - start) as u64,
+            });
+            start = end;
+        }
+        let root = batch_root(nodes);
+        hash_to_hex(blake3::keyed_hash(&FILE_HASH_KEY, &root).as_bytes())
+    }
+
+    /// Merkle root as the hasher folds `nodes` in
+    fn streamed_root(nodes: &[(&str, u64)]) -> String {
+        let mut hasher = FileHasher::default();
+        for &(hash, size) in nodes {
+            let node = Node {
+                hash: hex_to_hash(hash),
+                size,
+            };
+            hasher.push(0, node);
+        }
+        hash_to_hex(&hasher.root())
+    }
+
+    #[test]
+    fn blake3_keyed_test_vectors() {
+        // From the BLAKE3 test vectors: input bytes `i % 251`, first 32
+        // bytes of the keyed hash
+        const KEY: &[u8; 32] = b"whats the Elvish word for friend";
+        let vectors = [
+            (
+                0,
+                "92b2b75604ed3c761f9d6f62392c8a9227ad0ea3f09573e783f1498a4ed60d26",
+            ),
+            (
+                1,
+                "6d7878dfff2f485635d39013278ae14f1454b8c0a3a2d34bc1ab38228a80c95b",
+            ),
+            (
+                1023,
+                "c951ecdf03288d0fcc96ee3413563d8a6d3589547f2c2fb36d9786470f1b9d6e",
+            ),
+            (
+                1024,
+                "75c46f6f3d9eb4f55ecaaee480db732e6c2105546f1e675003687c31719c7ba4",
+            ),
+            (
+                1025,
+                "357dc55de0c7e382c900fd6e320acc04146be01db6a8ce7210b7189bd664ea69",
+            ),
+            (
+                2048,
+                "879cf1fa2ea0e79126cb1063617a05b6ad9d0b696d0d757cf053439f60a99dd1",
+            ),
+            (
+                8193,
+                "954a2a75420c8d6547e3ba5b98d963e6fa6491addc8c023189cc519821b4a1f5",
+            ),
+            (
+                102400,
+                "1c35d1a5811083fd7119f5d5d1ba027b4d01c0c6c49fb6ff2cf75393ea5db4a7",
+            ),
+        ];
+        for (len, expected) in vectors {
+            let input: Vec<u8> = (0..len).map(|i| (i % 251) as u8).collect();
+            // Fed in uneven pieces, as chunks arrive from a stream
+            let mut hasher = blake3::Hasher::new_keyed(KEY);
+            for piece in input.chunks(100) {
+                hasher.update(piece);
+            }
+            assert_eq!(
+                hasher.finalize().to_hex().as_str(),
+                expected,
+                "{} bytes",
+                len
+            );
+        }
+    }
+
+    #[test]
+    fn chunk_boundaries_match_the_cli() {
+        // From `verification_test.zig`
+        let random = [
+            84493, 134421, 144853, 243318, 271793, 336457, 467529, 494581, 582000, 596735, 616815,
+            653164, 678202, 724510, 815591, 827760, 958832, 991092, 1000000,
+        ];
+        assert_eq!(chunk_ends(&random_data(1_000_000, 0)), random);
+        let constant: Vec<usize> = (1..=7).map(|i| i * 131072).chain([1000000]).collect();
+        assert_eq!(chunk_ends(&[59; 1_000_000]), constant);
+    }
+
+    #[test]
+    fn merkle_roots_match_the_cli() {
+        // From `hashing.zig`
+        const A: &str = "cfc5d07f6f03c29bbf424132963fe08d19a37d5757aaf520bf08119f05cd56d6";
+        const B: &str = "c3e67584b5c4fc2a89837ec39e40f2c8a6bb0b2987ac94cd4b31e5fbdd210a72";
+        const C: &str = "0d2beb91b9196929a5ddec9f6e306924ddf4a24268e3e59fd8464738d525af37";
+        const D: &str = "adf8773496a9b7319b2e50dc98093f344053b17d8ad37100b9c07d9805988784";
+        const ZERO: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+        assert_eq!(streamed_root(&[]), ZERO);
+        assert_eq!(streamed_root(&[(A, 100)]), A);
+        assert_eq!(
+            streamed_root(&[(A, 100), (B, 200), (C, 300)]),
+            "71ec1275fca074724e2dd666921b3277c7cee603e4d025bcab2d4050015be2bc"
+        );
+        assert_eq!(
+            streamed_root(&[(A, 100); 4]),
+            "89f2ada89ff8c96763c6b25010e6dd76a4c05b1466207633ea559acf2093211b"
+        );
+        assert_eq!(
+            streamed_root(&[(A, 100), (B, 200), (A, 100), (B, 200), (C, 300), (D, 400)]),
+            "52c826f99507aa05d0b45e9837fa1709e0485425cfbcb1e80db3905cf98b3ee9"
+        );
+        assert_eq!(
+            streamed_root(&[
+                (ZERO, 0),
+                (A, 100),
+                (B, 200),
+                (C, 300),
+                (D, 400),
+                (
+                    "4ac202caf347fc1e9c874b1ef6a1c5e619141eb775a6f43f0f0124ccd0060d9e",
+                    500
+                ),
+                (
+                    "b3b28636f65c149ea52eb1f94669466f70f033b54cea792824c696ba6ef3c389",
+                    600
+                ),
+                (
+                    "0e2c1a002aae913d2c0fc8ddfa4e9e14b7b311b3b0d458726d5d9f6a6318013c",
+                    700
+                ),
+            ]),
+            "f62abe77e3fb9c954fe52b0028027ddc90c064c45951a4fd2211d87e5c0011db"
+        );
+        // No hash of these is 0 mod 4, so every group is cut at 9 nodes
+        assert_eq!(
+            streamed_root(&[(A, 100); 32]),
+            "0a0123c1617921883b7e13902095fcb86676e77c49120c33b233003b0af0e0a6"
+        );
+    }
+
+    #[test]
+    fn file_hashes() {
+        // The file hash of an empty file is that of a zero merkle root
+        let empty = hash_to_hex(blake3::keyed_hash(&FILE_HASH_KEY, &[0; 32]).as_bytes());
+        assert_eq!(file_hash(b""), empty);
+
+        // One chunk: its own hash is the root
+        let small = b"hello world";
+        assert_eq!(file_hash(small), batch_file_hash(small, &[small.len()]));
+
+        // 19 chunks, cut where the CLI cuts them; 32 equal ones, merged 9
+        // at a time
+        let random = random_data(1_000_000, 0);
+        let ends = chunk_ends(&random);
+        assert_eq!(ends.len(), 19);
+        assert_eq!(file_hash(&random), batch_file_hash(&random, &ends));
+        let constant = vec![59; 32 * 131072];
+        let ends: Vec<usize> = (1..=32).map(|i| i * 131072).collect();
+        assert_eq!(file_hash(&constant), batch_file_hash(&constant, &ends));
+
+        // However the stream happens to be split
+        let mut hasher = FileHasher::default();
+        for piece in random.chunks(4093) {
+            hasher.update(piece);
+        }
+        assert_eq!(hasher.finish(), file_hash(&random));
+    }
+
+    async fn stream(data: &[u8], expected: &str, mode: VerifyMode) -> (Vec<u8>, Option<io::Error>) {
+        let pieces: Vec<_> = data.chunks(16384).map(Bytes::copy_from_slice).collect();
+        let inner: ByteStream = Box::pin(tokio_stream::iter(pieces.into_iter().map(Ok)));
+        let mut verified = Verified::new(inner, expected, mode, Metrics::default());
+        let mut delivered = Vec::new();
+        while let Some(piece) = verified.next().await {
+            match piece {
+                Ok(piece) => delivered.extend_from_slice(&piece),
+                Err(e) => return (delivered, Some(e)),
+            }
+        }
+        (delivered, None)
+    }
+
+    #[tokio::test]
+    async fn corrupted_stream_fails() {
+        let data = random_data(300_000, 1);
+        let expected = file_hash(&data);
+        let (delivered, error) = stream(&data, &expected, VerifyMode::Abort).await;
+        assert!(error.is_none());
+        assert_eq!(delivered, data);
+
+        let mut corrupted = data.clone();
+        corrupted[123_456] ^= 1;
+        let (delivered, error) = stream(&corrupted, &expected, VerifyMode::Abort).await;
+        assert_eq!(error.unwrap().kind(), io::ErrorKind::InvalidData);
+        assert!(delivered.len() < data.len());
+
+        let (delivered, error) = stream(&corrupted, &expected, VerifyMode::Log).await;
+        assert!(error.is_none());
+        assert_eq!(delivered, corrupted);
+    }
+}
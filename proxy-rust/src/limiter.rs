@@ -0,0 +1,143 @@
+//! Download concurrency limit
+//!
+//! Every streamed download holds a slot from the moment it is accepted
+//! until its body is done; `MAX_CONCURRENT_DOWNLOADS` sets how many there
+//! are (unset = no limit). A download finding all slots taken waits in a
+//! queue of `DOWNLOAD_QUEUE_SIZE` places (default 0), within its time
+//! budget, with resumed transfers first and nice background work last (see
+//! [`crate::slots`]). When the queue is full too it
+//! is refused with `503` and `Retry-After`, before any child process is
+//! spawned. An archive download holds a single slot.
+
+use crate::slots::{Priority, Slot, Slots};
+use crate::AppError;
+use futures_core::Stream;
+use serde::Serialize;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// Suggested client wait when the queue is full
+const RETRY_AFTER: Duration = Duration::from_secs(10);
+
+/// Slots for concurrent downloads, with a bounded queue
+#[derive(Clone)]
+pub struct DownloadLimiter {
+    max_concurrent: usize,
+    max_queued: usize,
+    slots: Slots,
+    queued: Arc<AtomicUsize>,
+}
+
+/// Limiter state reported by `/health`
+#[derive(Clone, Copy, Debug, Serialize, utoipa::ToSchema)]
+pub struct LimiterStatus {
+    pub active: usize,
+    pub max_concurrent: usize,
+    pub queued: usize,
+    pub max_queued: usize,
+}
+
+impl DownloadLimiter {
+    /// Load `MAX_CONCURRENT_DOWNLOADS` and `DOWNLOAD_QUEUE_SIZE`; `None` without a limit
+    pub fn from_env() -> Option<Self> {
+        let max_concurrent = crate::config::positive::<usize>("MAX_CONCURRENT_DOWNLOADS");
+        let max_queued = crate::config::number("DOWNLOAD_QUEUE_SIZE").unwrap_or(0);
+        let max_concurrent = max_concurrent?;
+        Some(Self {
+            max_concurrent,
+            max_queued,
+            slots: Slots::new(max_concurrent),
+            queued: Arc::default(),
+        })
+    }
+
+    /// Take a slot, queueing for one until `deadline` if the queue has room
+    pub async fn acquire(
+        &self,
+        priority: Priority,
+        deadline: Option<Instant>,
+    ) -> Result<Slot, AppError> {
+        if let Some(slot) = self.slots.try_acquire(priority) {
+            return Ok(slot);
+        }
+        if self.queued.fetch_add(1, Ordering::Relaxed) >= self.max_queued {
+            self.queued.fetch_sub(1, Ordering::Relaxed);
+            return Err(AppError::Unavailable {
+                message: "Too many concurrent downloads, retry later".to_string(),
+                retry_after: Some(RETRY_AFTER),
+            });
+        }
+        let _place = QueuePlace(&self.queued);
+        let slot = self.slots.acquire(priority);
+        match deadline {
+            Some(deadline) => tokio::time::timeout_at(deadline, slot).await.map_err(|_| {
+                AppError::Timeout("No download slot became free within the time budget".to_string())
+            }),
+            None => Ok(slot.await),
+        }
+    }
+
+    /// Wait for a slot as background work, which takes no place in the
+    /// queue of client downloads
+    pub async fn wait(&self, priority: Priority) -> Slot {
+        self.slots.acquire(priority).await
+    }
+
+    pub fn status(&self) -> LimiterStatus {
+        LimiterStatus {
+            active: self.max_concurrent - self.slots.available(),
+            max_concurrent: self.max_concurrent,
+            queued: self.queued.load(Ordering::Relaxed),
+            max_queued: self.max_queued,
+        }
+    }
+}
+
+/// A place in the queue, left when dropped
+struct QueuePlace<'a>(&'a AtomicUsize);
+
+impl Drop for QueuePlace<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Body holding a download slot until it is dropped
+pub struct Holding<S> {
+    inner: S,
+    _slot: Slot,
+}
+
+impl<S> Holding<S> {
+    pub fn new(inner: S, slot: Slot) -> Self {
+        Self { inner, _slot: slot }
+    }
+}
+
+impl<S: Stream + Unpin> Stream for Holding<S> {
+    type Item = S::Item;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        Pin::new(&mut self.inner).poll_next(cx)
+    }
+}
+
+impl<B: http_body::Body + Unpin> http_body::Body for Holding<B> {
+    type Data = B::Data;
+    type Error = B::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<http_body::Frame<B::Data>, B::Error>>> {
+        Pin::new(&mut self.inner).poll_frame(cx)
+    }
+
+    fn size_hint(&self) -> http_body::SizeHint {
+        self.inner.size_hint()
+    }
+}
@@ -0,0 +1,96 @@
+//! Repository file listing via the Zig CLI
+//!
+//! Running `xet-download --json <repo_id>` without a file argument prints a
+//! `file` message per XET-enabled file of the repository type and revision
+//! given in `HF_REPO_TYPE` and `HF_REVISION` (default `model` and `main`):
+//!
+//! ```text
+//! {"type":"file","path":"<path>","size":<size>,"xet_hash":"<hash>"}
+//! ```
+//!
+//! Files that are not stored with XET are omitted by the CLI.
+
+use crate::backoff::RATE_LIMITED_SIGNATURE;
+use crate::protocol::{self, Message};
+use crate::repo::RepoRef;
+use crate::subprocess::{self, Cli};
+use crate::AppError;
+use serde::Serialize;
+
+/// One XET-enabled file in a repository listing
+#[derive(Clone, Debug, Serialize)]
+pub struct ListedFile {
+    pub path: String,
+    pub size: u64,
+    pub xet_hash: String,
+}
+
+/// One page of a repository listing
+pub struct ListPage {
+    pub files: Vec<ListedFile>,
+    /// Where the next page starts, if there is one
+    pub cursor: Option<String>,
+}
+
+/// List the XET-enabled files of a repository
+pub async fn list_repo(
+    cli: &Cli,
+    repo: &RepoRef,
+    hf_token: &str,
+) -> Result<Vec<ListedFile>, AppError> {
+    let stdout = run_listing(cli, repo, hf_token).await?;
+    parse_listing(&stdout)
+}
+
+/// Run the CLI listing step and return its raw stdout
+pub async fn run_listing(cli: &Cli, repo: &RepoRef, hf_token: &str) -> Result<String, AppError> {
+    let output = cli
+        .command()
+        .arg(repo.id())
+        .env("HF_TOKEN", hf_token)
+        .env("HF_REPO_TYPE", repo.repo_type.as_str())
+        // Inserted into the Hub URL as is
+        .env("HF_REVISION", repo.revision_segment())
+        // Timeouts drop this future; don't leave the child running
+        .kill_on_drop(true)
+        .output()
+        .await
+        .map_err(|e| AppError::Internal(format!("Failed to execute zig binary: {}", e)))?;
+
+    if !output.status.success() {
+        let tail = subprocess::tail_lines(&output.stderr);
+        let signature = cli.record_failure(&output.status, &tail);
+        if signature == RATE_LIMITED_SIGNATURE {
+            return Err(AppError::RateLimited {
+                message: format!("Upstream rate limited the listing of '{}'", repo),
+                retry_after: None,
+            });
+        }
+        return Err(AppError::Internal(format!(
+            "Failed to list files ({}): {}",
+            signature,
+            tail.join("\n")
+        )));
+    }
+
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// Parse CLI listing output
+pub fn parse_listing(stdout: &str) -> Result<Vec<ListedFile>, AppError> {
+    protocol::parse_output(stdout)?
+        .into_iter()
+        .map(|message| match message {
+            Message::File {
+                path,
+                size,
+                xet_hash,
+            } => Ok(ListedFile {
+                path,
+                size,
+                xet_hash,
+            }),
+            other => Err(other.unexpected("listing")),
+        })
+        .collect()
+}
@@ -0,0 +1,282 @@
+//! Cache of repository listings
+//!
+//! Path downloads, `/list`, `/snapshot`, archives, `/select`, `/resolve` and
+//! `/exists` all start from a listing of the repository. Listings are kept
+//! for `LISTING_CACHE_TTL_SECS` (default 60, `0` disables the cache) per
+//! repository, revision and HuggingFace token, so one client's listing of a
+//! gated repository never answers another client, and at most
+//! `LISTING_CACHE_MAX_ENTRIES` (default 1000) are kept, the least recently
+//! used are dropped first.
+//!
+//! A branch or tag can move to another commit while its listing is cached.
+//! Where the engine can tell (`XET_ENGINE=native`), the revision is first
+//! looked up, one small request, and the listing is kept under the commit
+//! it points at: once the branch moves, its new commit misses the cache and
+//! is listed afresh, instead of serving the hashes of the old one until the
+//! TTL runs out. A revision that is a commit id is never looked up.
+//!
+//! `?refresh=true` on any of those requests lists the repository again and
+//! replaces the cached listing; `DELETE /cache/listing/:owner/:repo` drops
+//! the cached listings of a repository.
+
+use crate::listing::ListedFile;
+use crate::repo::{RepoRef, RepoType};
+use std::collections::{BTreeMap, HashMap};
+use std::hash::{BuildHasher, RandomState};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::info;
+
+const DEFAULT_TTL_SECS: u64 = 60;
+const DEFAULT_MAX_ENTRIES: usize = 1000;
+
+#[derive(Clone, PartialEq, Eq, Hash)]
+struct Key {
+    repo_type: RepoType,
+    owner: String,
+    name: String,
+    revision: String,
+    /// Commit the revision pointed at, if known
+    commit: Option<String>,
+    /// Hub the listing came from (see [`crate::hub`])
+    endpoint: Arc<str>,
+    /// Fingerprint of the token the listing was made with
+    token: u64,
+}
+
+struct Listing {
+    expires: Instant,
+    files: Arc<[ListedFile]>,
+    seq: u64,
+}
+
+#[derive(Default)]
+struct Entries {
+    by_key: HashMap<Key, Listing>,
+    /// Keys by the sequence number of their latest use
+    order: BTreeMap<u64, Key>,
+    next: u64,
+}
+
+#[derive(Clone)]
+pub struct ListingCache {
+    ttl: Duration,
+    max_entries: usize,
+    /// Fingerprints tokens, so they aren't kept in memory
+    hasher: RandomState,
+    entries: Arc<Mutex<Entries>>,
+    hits: Arc<AtomicU64>,
+    misses: Arc<AtomicU64>,
+}
+
+impl ListingCache {
+    /// Load `LISTING_CACHE_TTL_SECS` and `LISTING_CACHE_MAX_ENTRIES`; `None`
+    /// when disabled
+    pub fn from_env() -> Option<Self> {
+        let ttl = crate::config::number("LISTING_CACHE_TTL_SECS").unwrap_or(DEFAULT_TTL_SECS);
+        let max_entries =
+            crate::config::positive("LISTING_CACHE_MAX_ENTRIES").unwrap_or(DEFAULT_MAX_ENTRIES);
+        if ttl == 0 {
+            return None;
+        }
+        info!(
+            "Repository listings cached for {}s (at most {})",
+            ttl, max_entries
+        );
+        Some(Self {
+            ttl: Duration::from_secs(ttl),
+            max_entries,
+            hasher: RandomState::new(),
+            entries: Arc::default(),
+            hits: Arc::default(),
+            misses: Arc::default(),
+        })
+    }
+
+    /// The cached listing of `repo` at `commit` made with `hf_token`,
+    /// unless expired
+    pub fn get(
+        &self,
+        repo: &RepoRef,
+        commit: Option<&str>,
+        hf_token: &str,
+    ) -> Option<Vec<ListedFile>> {
+        let key = self.key(repo, commit, hf_token);
+        let mut entries = self.entries.lock().unwrap();
+        let seq = entries.next;
+        let found = match entries.by_key.get_mut(&key) {
+            Some(listing) if Instant::now() < listing.expires => {
+                let previous = std::mem::replace(&mut listing.seq, seq);
+                Some((previous, listing.files.to_vec()))
+            }
+            _ => None,
+        };
+        let (previous, files) = found?;
+        entries.next += 1;
+        entries.order.remove(&previous);
+        entries.order.insert(seq, key);
+        self.hits.fetch_add(1, Ordering::Relaxed);
+        Some(files)
+    }
+
+    /// Keep a fresh listing of `repo` at `commit` made with `hf_token`
+    pub fn put(&self, repo: &RepoRef, commit: Option<&str>, hf_token: &str, files: &[ListedFile]) {
+        self.misses.fetch_add(1, Ordering::Relaxed);
+        let key = self.key(repo, commit, hf_token);
+        let mut entries = self.entries.lock().unwrap();
+        let seq = entries.next;
+        entries.next += 1;
+        let listing = Listing {
+            expires: Instant::now() + self.ttl,
+            files: files.into(),
+            seq,
+        };
+        if let Some(previous) = entries.by_key.insert(key.clone(), listing) {
+            entries.order.remove(&previous.seq);
+        }
+        entries.order.insert(seq, key);
+        while entries.by_key.len() > self.max_entries {
+            let Some((_, oldest)) = entries.order.pop_first() else {
+                break;
+            };
+            entries.by_key.remove(&oldest);
+        }
+    }
+
+    /// Drop the listings of a repository, at every revision or only at
+    /// `revision`; returns how many were dropped
+    pub fn invalidate(
+        &self,
+        repo_type: RepoType,
+        owner: &str,
+        name: &str,
+        revision: Option<&str>,
+    ) -> usize {
+        let mut entries = self.entries.lock().unwrap();
+        let dropped: Vec<(u64, Key)> = entries
+            .by_key
+            .iter()
+            .filter(|(key, _)| {
+                key.repo_type == repo_type
+                    && key.owner == owner
+                    && key.name == name
+                    && revision.is_none_or(|revision| key.revision == revision)
+            })
+            .map(|(key, listing)| (listing.seq, key.clone()))
+            .collect();
+        for (seq, key) in &dropped {
+            entries.order.remove(seq);
+            entries.by_key.remove(key);
+        }
+        dropped.len()
+    }
+
+    /// Listings answered from the cache
+    pub fn hits(&self) -> u64 {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    /// Listings that went upstream
+    pub fn misses(&self) -> u64 {
+        self.misses.load(Ordering::Relaxed)
+    }
+
+    fn key(&self, repo: &RepoRef, commit: Option<&str>, hf_token: &str) -> Key {
+        Key {
+            repo_type: repo.repo_type,
+            owner: repo.owner.clone(),
+            name: repo.name.clone(),
+            revision: repo.revision.clone(),
+            commit: commit.map(str::to_string),
+            endpoint: crate::hub::endpoint(),
+            token: self.hasher.hash_one(hf_token),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cache(max_entries: usize) -> ListingCache {
+        ListingCache {
+            ttl: Duration::from_secs(60),
+            max_entries,
+            hasher: RandomState::new(),
+            entries: Arc::default(),
+            hits: Arc::default(),
+            misses: Arc::default(),
+        }
+    }
+
+    fn repo(revision: &str) -> RepoRef {
+        RepoRef::parse("org/x", Some(revision.to_string())).unwrap()
+    }
+
+    fn listing(hash: &str) -> Vec<ListedFile> {
+        vec![ListedFile {
+            path: "model.bin".to_string(),
+            xet_hash: hash.to_string(),
+            size: 1,
+        }]
+    }
+
+    fn hashes(files: Option<Vec<ListedFile>>) -> Option<Vec<String>> {
+        files.map(|files| files.into_iter().map(|f| f.xet_hash).collect())
+    }
+
+    #[test]
+    fn moved_branch_misses_the_listing_of_its_old_commit() {
+        let cache = cache(10);
+        cache.put(&repo("main"), Some("c1"), "token", &listing("old"));
+        assert_eq!(
+            hashes(cache.get(&repo("main"), Some("c1"), "token")),
+            Some(vec!["old".to_string()])
+        );
+        // `main` now points at c2
+        assert!(cache.get(&repo("main"), Some("c2"), "token").is_none());
+        cache.put(&repo("main"), Some("c2"), "token", &listing("new"));
+        assert_eq!(
+            hashes(cache.get(&repo("main"), Some("c2"), "token")),
+            Some(vec!["new".to_string()])
+        );
+        // Engines that can't tell the commit keep their own entry
+        assert!(cache.get(&repo("main"), None, "token").is_none());
+        assert_eq!((cache.hits(), cache.misses()), (2, 2));
+    }
+
+    #[test]
+    fn listings_are_kept_per_revision_and_token() {
+        let cache = cache(10);
+        cache.put(&repo("main"), None, "token", &listing("a"));
+        assert!(cache.get(&repo("v1"), None, "token").is_none());
+        assert!(cache.get(&repo("main"), None, "other").is_none());
+        assert!(cache.get(&repo("main"), None, "token").is_some());
+    }
+
+    #[test]
+    fn invalidation_drops_every_commit_of_a_revision() {
+        let cache = cache(10);
+        cache.put(&repo("main"), Some("c1"), "token", &listing("a"));
+        cache.put(&repo("main"), Some("c2"), "token", &listing("b"));
+        cache.put(&repo("v1"), None, "token", &listing("c"));
+        assert_eq!(
+            cache.invalidate(RepoType::Model, "org", "x", Some("main")),
+            2
+        );
+        assert!(cache.get(&repo("v1"), None, "token").is_some());
+    }
+
+    #[test]
+    fn least_recently_used_listing_is_dropped() {
+        let cache = cache(2);
+        cache.put(&repo("a"), None, "token", &listing("a"));
+        cache.put(&repo("b"), None, "token", &listing("b"));
+        assert!(cache.get(&repo("a"), None, "token").is_some());
+        cache.put(&repo("c"), None, "token", &listing("c"));
+        assert!(cache.get(&repo("b"), None, "token").is_none());
+        assert!(cache.get(&repo("a"), None, "token").is_some());
+        assert!(cache.get(&repo("c"), None, "token").is_some());
+    }
+}
@@ -0,0 +1,171 @@
+//! Log output
+//!
+//! The server logs to stdout, filtered by `RUST_LOG`. `LOG_FORMAT=json`
+//! writes one JSON object per line instead of text, for log aggregation:
+//! `timestamp`, `level`, `target`, the event's `fields` (`message` among
+//! them) and, in `span`, the request it happened in.
+//!
+//! Each request on the data-plane ports logs under a `request` span with
+//! its method, URI and id (see [`crate::upstream`]), the `X-Request-Id`
+//! echoed in its response. Work spawned for the request logs under the same
+//! span, zig's stderr lines included: those of a per-request process under
+//! the request that spawned it, those of a pooled worker under the request
+//! of the job it is running.
+//!
+//! An admin (see [`crate::auth`]) can troubleshoot one request without
+//! raising `RUST_LOG` for every other: with `X-Debug: true`, that request
+//! logs at debug level, work spawned for it included, and its response
+//! carries `X-Debug-Trace`, a one-line summary of the upstream calls made
+//! until the response started (see [`crate::upstream::summary`]). Other
+//! clients asking for it are refused with 403.
+
+use crate::auth::Grant;
+use crate::AppError;
+use axum::extract::Request;
+use axum::http::HeaderValue;
+use axum::middleware::Next;
+use axum::response::Response;
+use tracing::Span;
+use tracing_subscriber::fmt::writer::BoxMakeWriter;
+use tracing_subscriber::EnvFilter;
+
+pub const DEBUG_HEADER: &str = "x-debug";
+pub const DEBUG_TRACE_HEADER: &str = "x-debug-trace";
+/// Requests marked by [`debug`] log at debug level
+const DEBUG_DIRECTIVE: &str = "[request{debug=true}]=debug";
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Text,
+    Json,
+}
+
+/// Load `LOG_FORMAT` (`text`, the default, or `json`)
+pub fn format_from_env() -> Format {
+    match std::env::var("LOG_FORMAT").as_deref() {
+        Err(_) | Ok("text") => Format::Text,
+        Ok("json") => Format::Json,
+        Ok(other) => panic!("LOG_FORMAT must be 'text' or 'json', got '{}'", other),
+    }
+}
+
+/// Log to stdout, filtered by `RUST_LOG`, for the server
+pub fn init() {
+    let directive = DEBUG_DIRECTIVE.parse().expect("Invalid debug directive");
+    install(
+        EnvFilter::from_default_env().add_directive(directive),
+        BoxMakeWriter::new(std::io::stdout),
+    );
+}
+
+/// Log info and above to stderr, for commands whose output is on stdout
+pub fn init_stderr() {
+    install(EnvFilter::new("info"), BoxMakeWriter::new(std::io::stderr));
+}
+
+fn install(filter: EnvFilter, writer: BoxMakeWriter) {
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(writer);
+    match format_from_env() {
+        Format::Text => builder.init(),
+        Format::Json => builder
+            .json()
+            .with_current_span(true)
+            .with_span_list(false)
+            .init(),
+    }
+}
+
+/// Span a request is served in, for `TraceLayer`
+pub fn request_span<B>(request: &axum::http::Request<B>) -> Span {
+    tracing::info_span!(
+        "request",
+        method = %request.method(),
+        uri = %request.uri(),
+        request_id = crate::upstream::current_id().as_deref(),
+        debug = tracing::field::Empty,
+    )
+}
+
+/// Middleware serving `X-Debug: true`, once the client is authenticated
+pub async fn debug(request: Request, next: Next) -> Result<Response, AppError> {
+    let asked = request
+        .headers()
+        .get(DEBUG_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.trim().eq_ignore_ascii_case("true"));
+    if !asked {
+        return Ok(next.run(request).await);
+    }
+    match request.extensions().get::<Grant>() {
+        Some(grant) => grant.check_admin()?,
+        None => {
+            return Err(AppError::Forbidden(
+                "X-Debug is only for admins".to_string(),
+            ))
+        }
+    }
+    Span::current().record("debug", true);
+    let mut response = next.run(request).await;
+    if let Ok(value) = HeaderValue::from_str(&crate::upstream::summary()) {
+        response.headers_mut().insert(DEBUG_TRACE_HEADER, value);
+    }
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::Body;
+    use axum::http::StatusCode;
+    use axum::routing::get;
+    use axum::{Extension, Router};
+    use tower::ServiceExt;
+
+    fn app(grant: Option<Grant>) -> Router {
+        let app = Router::new()
+            .route("/", get(|| async {}))
+            .route_layer(axum::middleware::from_fn(debug));
+        match grant {
+            Some(grant) => app.layer(Extension(grant)),
+            None => app,
+        }
+    }
+
+    async fn get_with(app: Router, debug: Option<&str>) -> Response {
+        let mut request = Request::builder().uri("/");
+        if let Some(value) = debug {
+            request = request.header(DEBUG_HEADER, value);
+        }
+        app.oneshot(request.body(Body::empty()).unwrap())
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn admins_get_a_trace_summary() {
+        for value in ["true", " TRUE "] {
+            let response = get_with(app(Some(Grant::admin("ops"))), Some(value)).await;
+            assert_eq!(response.status(), StatusCode::OK);
+            assert_eq!(response.headers()[DEBUG_TRACE_HEADER], "no upstream calls");
+        }
+    }
+
+    #[tokio::test]
+    async fn debug_is_refused_without_an_admin_grant() {
+        let response = get_with(app(None), Some("true")).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn requests_not_asking_are_left_alone() {
+        for (grant, value) in [(None, None), (None, Some("false"))] {
+            let response = get_with(app(grant), value).await;
+            assert_eq!(response.status(), StatusCode::OK);
+            assert!(response.headers().get(DEBUG_TRACE_HEADER).is_none());
+        }
+        let response = get_with(app(Some(Grant::admin("ops"))), None).await;
+        assert!(response.headers().get(DEBUG_TRACE_HEADER).is_none());
+    }
+}
@@ -5,86 +5,1120 @@
 //! while delegating the actual XET protocol work to the Zig CLI.
 
 use axum::{
-    body::Body,
-    extract::{Path, State},
-    http::{header, HeaderMap, StatusCode},
+    body::{Body, Bytes},
+    extract::{rejection::JsonRejection, Form, Path, Query, State},
+    http::{header, response, HeaderMap, Method, StatusCode, Uri},
     response::{IntoResponse, Response},
-    routing::get,
-    Json, Router,
+    routing::{delete, get, head, post, put},
+    Extension, Json, Router,
 };
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::atomic::AtomicU64;
 use std::sync::Arc;
-use tokio::io::{AsyncBufReadExt, BufReader};
-use tokio::process::Command;
-use tokio_util::io::ReaderStream;
+use std::time::{Duration, SystemTime};
+use tokio_stream::StreamExt;
 use tower_http::trace::TraceLayer;
-use tracing::{error, info};
+use tracing::{debug, info, warn};
+use utoipa::{IntoParams, ToSchema};
+
+mod admin;
+mod aliases;
+mod amplification;
+mod archive;
+mod auth;
+mod backoff;
+mod cache;
+mod capabilities;
+mod catalog;
+mod conditional;
+mod config;
+mod config_check;
+mod dev;
+mod downloader;
+mod drain;
+mod events;
+mod filename;
+mod head_cache;
+#[cfg(feature = "hooks")]
+mod hooks;
+mod hub;
+mod integrity;
+mod limiter;
+mod listing;
+mod listing_cache;
+mod logging;
+mod manifest;
+mod metrics;
+#[cfg(feature = "nats")]
+mod nats;
+mod openapi;
+mod overrides;
+mod policy;
+mod ports;
+mod prefetch;
+mod progress;
+mod protocol;
+mod range;
+mod readiness;
+mod replay;
+mod repo;
+mod resume;
+mod retry;
+mod reverify;
+mod select;
+mod self_test;
+mod sessions;
+mod shedding;
+mod shutdown;
+mod slo;
+mod slots;
+mod subprocess;
+mod tenants;
+mod throttle;
+mod tls;
+mod token_scopes;
+mod transfer;
+mod trust;
+mod tus;
+mod upload;
+mod upstream;
+mod workers;
+mod xet;
+mod xet_hash;
+mod xorb;
+
+use admin::Admin;
+use aliases::{AliasTarget, Aliases};
+use amplification::Amplification;
+use auth::{Authenticator, Grant};
+use backoff::UpstreamBackoff;
+use cache::{Cache, CacheEntry, CacheMetadata, CacheReport, CachingDownloader};
+use catalog::{Catalog, CatalogEntry};
+use downloader::{CliDownloader, DownloadRequest, Downloader};
+use drain::{DrainStatus, InFlight};
+use events::{EventBus, EventKind};
+use filename::{FileContext, FilenameTemplate};
+use head_cache::HeadCache;
+use integrity::{Verified, VerifyMode};
+use limiter::{DownloadLimiter, Holding, LimiterStatus};
+use listing::ListedFile;
+use listing_cache::ListingCache;
+use manifest::ManifestChunk;
+use metrics::Metrics;
+use overrides::{OverrideLimits, RequestOptions, TransferMode, TRANSFER_MODE_HEADER};
+use policy::{Access, Policy};
+use ports::{Plane, PortMap};
+use prefetch::{JobStatus, PrefetchItem, Prefetcher};
+use progress::Progress;
+use range::{ByteRange, Multipart};
+use readiness::{Readiness, ReadyReport};
+use repo::{RepoRef, RepoType};
+use resume::{AbortedTransfers, ResumeClaims};
+use retry::RetryPolicy;
+use reverify::{Reverify, Verdict, REVERIFY_TIMEOUT};
+use select::{SelectionRules, Target};
+use sessions::{SessionStatus, Sessions, SESSION_HEADER};
+use shedding::{LoadShedder, LoadStatus, ShedLimits};
+use shutdown::Shutdown;
+use slo::{SloConfig, SloReport, SloTracker};
+use slots::{Priority, Slot};
+use subprocess::{Cli, ResourceLimits};
+use tenants::{TenantLimits, TenantStatus, Tenants};
+use throttle::Throttle;
+use token_scopes::TokenScopes;
+use transfer::{Source, TransferInfo, TransferObservers, TransferStream};
+use upload::{UploadRequest, UploadResult};
+use upstream::{TraceReport, Traces};
+use xet_hash::XetHash;
 
 const VERSION: &str = "0.1.0";
 
+const ROUTE_DOWNLOAD: &str = "/download/:owner/:repo/*file";
+const ROUTE_DOWNLOAD_HASH: &str = "/download-hash/:hash";
+const ROUTE_UPLOAD: &str = "/upload/:owner/:repo/*file";
+/// Files accepted in one `POST /resolve`
+const MAX_RESOLVE_FILES: usize = 1000;
+/// Hashes accepted in one `POST /exists`
+const MAX_EXISTS_HASHES: usize = 1000;
+/// Files accepted in one `POST /resolve-batch`
+const MAX_BATCH_FILES: usize = 1000;
+/// Listings one `POST /resolve-batch` makes at a time
+const BATCH_LISTINGS: usize = 8;
+
 #[derive(Clone)]
 struct AppState {
-    zig_bin_path: String,
+    downloader: Arc<dyn Downloader>,
+    events: EventBus,
+    filename_template: FilenameTemplate,
+    override_limits: OverrideLimits,
+    backoff: UpstreamBackoff,
+    /// What each upstream endpoint was found to support
+    capabilities: capabilities::Capabilities,
+    slo: SloTracker,
+    transfer_drift: Arc<AtomicU64>,
+    /// Interrupted transfers, whose resumes are prioritized
+    aborts: AbortedTransfers,
+    /// Downloads streaming now, for `/admin/drain-status`
+    in_flight: InFlight,
+    selection_rules: SelectionRules,
+    aliases: Aliases,
+    /// Files an archive download fetches at once
+    archive_parallelism: usize,
+    cache: Option<Cache>,
+    /// Background cache warming, when caching is on
+    prefetcher: Option<Prefetcher>,
+    /// Byte counts of prefetch jobs and archive downloads
+    progress: Progress,
+    /// Hashes seen in listings, to name and size hash downloads
+    catalog: Catalog,
+    /// Recent repository listings, unless disabled
+    listing_cache: Option<ListingCache>,
+    /// Shared listings and the per-client listing cap
+    amplification: Amplification,
+    /// Checks of old cached files against upstream, if on
+    reverify: Option<Reverify>,
+    /// File heads served locally in redirect mode
+    head_cache: Option<HeadCache>,
+    shedder: LoadShedder,
+    /// Connection draining on SIGTERM and SIGINT
+    shutdown: Shutdown,
+    readiness: Readiness,
+    /// Concurrent download limit, if configured
+    limiter: Option<DownloadLimiter>,
+    /// API keys required of clients, if configured
+    auth: Option<Authenticator>,
+    policy: Option<Policy>,
+    throttle: Option<Throttle>,
+    /// Which listener serves which endpoints
+    ports: PortMap,
+    /// Listener of the operational endpoints, if apart
+    admin: Option<Admin>,
+    sessions: Sessions,
+    /// Resumable uploads, when `UPLOAD_DIR` is set
+    uploads: Option<tus::Uploads>,
+    traces: Traces,
+    metrics: Metrics,
+    /// Whether whole-file downloads are checked against their hash
+    verify: VerifyMode,
+    /// Token used when a request carries none (opt-in)
+    fallback_token: Option<String>,
+    /// Scopes of `HF_TOKEN`, and whether uploads are refused
+    token_scopes: TokenScopes,
+    /// Repository authorizing hash downloads that name none
+    cas_token_repo: Option<RepoRef>,
 }
 
-#[derive(Serialize)]
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct SelectQuery {
+    /// Target descriptor, e.g. `gguf:q4_k_m` or `onnx:cpu`
+    target: String,
+    /// Bypass the listing cache
+    #[serde(default)]
+    refresh: bool,
+}
+
+/// Query parameters of the listing endpoints without others
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct RefreshQuery {
+    /// Bypass the listing cache
+    #[serde(default)]
+    refresh: bool,
+}
+
+/// Query parameters accepted by the download endpoints
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct DownloadQuery {
+    /// Per-request override of the Content-Disposition filename template
+    filename_template: Option<String>,
+    /// Repository a hash belongs to (`owner/repo` or `<type>s/owner/repo`),
+    /// whose CAS token authorizes a hash download
+    repo: Option<String>,
+    /// Revision of `repo` (default `main`)
+    revision: Option<String>,
+    /// Bypass the listing cache
+    #[serde(default)]
+    refresh: bool,
+}
+
+/// Query parameters accepted by the upload endpoint
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct UploadQuery {
+    /// Repository type (default `model`)
+    #[serde(default, rename = "type")]
+    repo_type: RepoType,
+    /// Branch to commit to (default `main`)
+    revision: Option<String>,
+    /// Commit title (default `Upload <file>`)
+    message: Option<String>,
+}
+
+/// A committed upload
+#[derive(Serialize, ToSchema)]
+struct UploadResponse {
+    repo: String,
+    path: String,
+    commit_sha: String,
+    xet_hash: String,
+    sha256: String,
+    size: u64,
+}
+
+#[derive(Serialize, ToSchema)]
 struct HealthResponse {
     status: &'static str,
     version: &'static str,
+    load: LoadStatus,
+    /// Concurrent and queued downloads, when limited
+    #[serde(skip_serializing_if = "Option::is_none")]
+    downloads: Option<LimiterStatus>,
+}
+
+/// Files of a bundle alias
+#[derive(Serialize, ToSchema)]
+struct BundleResponse {
+    alias: String,
+    repo_id: String,
+    revision: String,
+    files: Vec<BundleFile>,
+}
+
+#[derive(Serialize, ToSchema)]
+struct BundleFile {
+    path: String,
+    /// Proxy URL serving this file under the alias
+    url: String,
+}
+
+/// One entry of the `/list` response
+#[derive(Serialize, ToSchema)]
+struct ListEntry {
+    path: String,
+    size: u64,
+    xet_hash: String,
+    /// Strong validator for the file's content (its quoted XET hash)
+    etag: String,
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct ListQuery {
+    /// Only return paths starting with this prefix
+    prefix: Option<String>,
+    /// Bypass the listing cache
+    #[serde(default)]
+    refresh: bool,
+}
+
+/// Query parameters of `/download-archive`
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct ArchiveQuery {
+    /// Only pack paths starting with this prefix
+    prefix: Option<String>,
+    /// Bypass the listing cache
+    #[serde(default)]
+    refresh: bool,
+    /// What to do with a file whose download can't start: `fail` (abort),
+    /// `skip`, or `retry` then skip
+    #[serde(default)]
+    #[param(inline)]
+    on_error: archive::OnError,
+    /// Niceness of the archive's downloads, 0 (default) to 19; nicer
+    /// archives leave more of the download capacity to others
+    #[serde(default)]
+    nice: u8,
+}
+
+/// Query parameters of `/list` for partial listings
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct PageQuery {
+    /// Answer within this many milliseconds with the files listed so far
+    /// (at least one page), clamped to `PROXY_LIST_BUDGET_MS`
+    budget_ms: Option<u64>,
+    /// Continue a partial listing from its `X-Next-Cursor`
+    cursor: Option<String>,
+}
+
+/// Query parameters of `DELETE /cache/listing/:owner/:repo`
+#[derive(Deserialize)]
+struct ListingDeleteQuery {
+    /// Repository type (default `model`)
+    #[serde(default, rename = "type")]
+    repo_type: RepoType,
+    /// Only drop the listings of this revision
+    revision: Option<String>,
+}
+
+/// Response of `DELETE /cache/listing/:owner/:repo`
+#[derive(Serialize)]
+struct ListingPurgeResponse {
+    removed: usize,
+}
+
+/// Repository manifest in the shape `huggingface_hub.snapshot_download` works with
+#[derive(Serialize, ToSchema)]
+struct SnapshotResponse {
+    repo_id: String,
+    revision: String,
+    siblings: Vec<SnapshotFile>,
+}
+
+#[derive(Serialize, ToSchema)]
+struct SnapshotFile {
+    /// Path relative to the repository root
+    rfilename: String,
+    size: u64,
+    xet_hash: String,
+    /// Proxy URL serving this file's content
+    url: String,
+}
+
+/// Body of `POST /resolve`
+#[derive(Deserialize, ToSchema)]
+#[serde(deny_unknown_fields)]
+struct ResolveRequest {
+    /// `owner/repo` or `<type>s/owner/repo`
+    repo: String,
+    revision: Option<String>,
+    /// Paths within the repository
+    files: Vec<String>,
+}
+
+/// One file of a `POST /resolve` response
+#[derive(Serialize, ToSchema)]
+struct ResolvedHash {
+    xet_hash: String,
+    size: u64,
+}
+
+/// One file of a `POST /resolve-batch` request
+#[derive(Deserialize, ToSchema)]
+#[serde(deny_unknown_fields)]
+struct BatchFile {
+    /// `owner/repo` or `<type>s/owner/repo`
+    repo: String,
+    file: String,
+    revision: Option<String>,
+}
+
+/// One file of a `POST /resolve-batch` response, in the request's order
+#[derive(Serialize, ToSchema)]
+struct BatchResolved {
+    repo: String,
+    file: String,
+    /// Revision the file was resolved at (a session's pin, with `X-Session-Id`)
+    revision: Option<String>,
+    /// `null` when the file isn't listed or isn't stored with XET
+    xet_hash: Option<String>,
+    size: Option<u64>,
+    /// Whether the content is in the local cache
+    cached: bool,
+    /// Why the file couldn't be resolved
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+/// Body of `POST /exists`
+#[derive(Deserialize, ToSchema)]
+#[serde(deny_unknown_fields)]
+struct ExistsRequest {
+    hashes: Vec<String>,
+    /// Repository to list for hashes the catalog doesn't know
+    repo: Option<String>,
+    revision: Option<String>,
+}
+
+/// Response of `POST /exists`, each list in request order
+#[derive(Default, Serialize, ToSchema)]
+struct ExistsResponse {
+    /// In the local cache
+    cached: Vec<String>,
+    /// Not cached, but listed in a repository
+    upstream: Vec<String>,
+    unknown: Vec<String>,
+}
+
+/// Chunk manifest of a file, for verifying a download as it streams
+#[derive(Serialize, ToSchema)]
+struct ManifestResponse {
+    hash: String,
+    size: u64,
+    chunks: Vec<ManifestChunk>,
+}
+
+/// Query parameters of `POST /prefetch`
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct PrefetchQuery {
+    /// Niceness of the job's downloads, 0 (default) to 19; nicer jobs leave
+    /// more of the download capacity to others
+    #[serde(default)]
+    nice: u8,
+}
+
+/// Response of `POST /prefetch`
+#[derive(Serialize, ToSchema)]
+struct PrefetchResponse {
+    job_id: String,
+    /// Files named directly; scopes add theirs once the job runs
+    files: usize,
+    /// Where the job's progress is reported
+    status_url: String,
+}
+
+/// Response of `DELETE /cache`
+#[derive(Serialize)]
+struct PurgeResponse {
+    removed: usize,
+    freed_bytes: u64,
+    /// Seconds the removed files stay restorable, unless deleted right away
+    #[serde(skip_serializing_if = "Option::is_none")]
+    restorable_for: Option<u64>,
 }
 
+/// Response of `POST /cache/restore`
 #[derive(Serialize)]
+struct RestoreResponse {
+    restored: usize,
+    bytes: u64,
+}
+
+/// Query parameters of the cache deletion endpoints
+#[derive(Deserialize)]
+struct DeleteQuery {
+    /// Delete for good at once instead of after the grace period
+    #[serde(default)]
+    immediate: bool,
+}
+
+#[derive(Serialize, ToSchema)]
 struct ErrorResponse {
     error: String,
 }
 
-#[tokio::main]
-async fn main() {
-    // Initialize tracing
-    tracing_subscriber::fmt::init();
-
-    // Get configuration from environment
-    let port = std::env::var("PORT")
-        .unwrap_or_else(|_| "8080".to_string())
-        .parse::<u16>()
-        .expect("PORT must be a valid number");
-    let zig_bin_path = std::env::var("ZIG_BIN_PATH")
-        .unwrap_or_else(|_| "/usr/local/bin/xet-download".to_string());
-
-    let state = Arc::new(AppState {
-        zig_bin_path,
+fn zig_bin_path() -> String {
+    std::env::var("ZIG_BIN_PATH").unwrap_or_else(|_| "/usr/local/bin/xet-download".to_string())
+}
+
+fn filename_template_from_env() -> FilenameTemplate {
+    match std::env::var("FILENAME_TEMPLATE") {
+        Ok(template) => FilenameTemplate::parse(&template)
+            .unwrap_or_else(|e| panic!("FILENAME_TEMPLATE is invalid: {}", e)),
+        Err(_) => FilenameTemplate::default(),
+    }
+}
+
+/// Download engine selected by `XET_ENGINE`
+fn downloader_from_env(
+    backoff: UpstreamBackoff,
+    retry: RetryPolicy,
+    capabilities: capabilities::Capabilities,
+) -> Arc<dyn Downloader> {
+    match std::env::var("XET_ENGINE").as_deref() {
+        Err(_) | Ok("cli") => Arc::new(CliDownloader::new(
+            Cli::new(zig_bin_path(), ResourceLimits::from_env()),
+            backoff,
+        )),
+        Ok("native") => Arc::new(xet::NativeDownloader::new(backoff, retry, capabilities)),
+        Ok("dev") => Arc::new(dev::DevDownloader::from_env()),
+        Ok(other) => panic!(
+            "XET_ENGINE must be 'cli', 'native' or 'dev', got '{}'",
+            other
+        ),
+    }
+}
+
+/// Initialize every subsystem from the environment
+async fn app_state() -> Arc<AppState> {
+    let filename_template = filename_template_from_env();
+    let ports = PortMap::from_env();
+
+    let backoff = UpstreamBackoff::from_env();
+    let override_limits = OverrideLimits::from_env();
+    let capabilities = capabilities::Capabilities::default();
+    let downloader = downloader_from_env(
+        backoff.clone(),
+        override_limits.retry.clone(),
+        capabilities.clone(),
+    );
+    let cache = Cache::from_env();
+    if let Some(cache) = &cache {
+        cache.load().await;
+    }
+    let catalog = Catalog::from_env();
+    let progress = Progress::from_env();
+    let limiter = DownloadLimiter::from_env();
+    let prefetcher = cache.as_ref().map(|cache| {
+        Prefetcher::new(
+            downloader.clone(),
+            cache.clone(),
+            catalog.clone(),
+            backoff.clone(),
+            progress.clone(),
+            limiter.clone(),
+        )
     });
+    let downloader: Arc<dyn Downloader> = match &cache {
+        Some(cache) => Arc::new(CachingDownloader::new(downloader, cache.clone())),
+        None => downloader,
+    };
+
+    let events = EventBus::new();
+
+    #[cfg(feature = "nats")]
+    if let Ok(nats_url) = std::env::var("NATS_URL") {
+        let prefix =
+            std::env::var("NATS_SUBJECT_PREFIX").unwrap_or_else(|_| "xet-proxy".to_string());
+        nats::spawn_publisher(nats_url, prefix, events.clone()).await;
+    }
+
+    Arc::new(AppState {
+        downloader,
+        events,
+        filename_template,
+        override_limits,
+        backoff,
+        capabilities,
+        slo: SloTracker::new(SloConfig::from_env()),
+        transfer_drift: Arc::new(AtomicU64::new(0)),
+        aborts: AbortedTransfers::from_env(),
+        in_flight: InFlight::default(),
+        selection_rules: SelectionRules::from_env(),
+        aliases: Aliases::from_env(),
+        archive_parallelism: archive::parallelism_from_env(),
+        cache,
+        prefetcher,
+        progress,
+        catalog,
+        listing_cache: ListingCache::from_env(),
+        amplification: Amplification::from_env(),
+        reverify: Reverify::from_env(),
+        head_cache: HeadCache::from_env(),
+        shedder: LoadShedder::new(ShedLimits::from_env()),
+        shutdown: Shutdown::from_env(),
+        readiness: Readiness::from_env(),
+        limiter,
+        auth: Authenticator::from_env(),
+        policy: Policy::from_env(),
+        throttle: Throttle::from_env(),
+        admin: ports.admin.own().cloned().map(Admin::new),
+        ports,
+        sessions: Sessions::from_env(),
+        uploads: tus::Uploads::from_env(),
+        traces: Traces::from_env(),
+        metrics: Metrics::default(),
+        verify: VerifyMode::from_env(),
+        fallback_token: fallback_token_from_env(),
+        token_scopes: TokenScopes::from_env(read_only_from_env()),
+        cas_token_repo: cas_token_repo_from_env(),
+    })
+}
 
-    // Build router
+/// Routes of the proxy, with their middleware
+fn router(state: Arc<AppState>) -> Router {
     let app = Router::new()
         .route("/", get(root))
         .route("/health", get(health))
-        .route("/download/:owner/:repo/*file", get(download_by_path))
-        .route("/download-hash/:hash", get(download_by_hash))
-        .layer(TraceLayer::new_for_http())
+        .route("/healthz", get(liveness))
+        .route("/readyz", get(readiness))
+        .route("/version", get(version))
+        .route(ROUTE_DOWNLOAD, get(download_by_path))
+        .route(ROUTE_DOWNLOAD_HASH, get(download_by_hash))
+        .route("/manifest/:hash", get(chunk_manifest))
+        .route("/models/:alias", get(alias_download))
+        .route("/models/:alias/*file", get(alias_bundle_download))
+        .route("/download-archive/:owner/:repo", get(download_archive))
+        .route("/list/:owner/:repo", get(list_files))
+        .route("/snapshot/:owner/:repo", get(snapshot))
+        .route("/resolve", post(resolve_hashes))
+        .route("/resolve-batch", post(resolve_batch))
+        .route("/exists", post(hashes_exist))
+        .route("/select/:owner/:repo", get(select_artifact))
+        .route(ROUTE_UPLOAD, put(upload_file).post(tus_create))
+        .route(
+            "/uploads/:id",
+            head(tus_status).patch(tus_write).delete(tus_delete),
+        )
+        .route("/prefetch", post(prefetch_submit))
+        .route("/prefetch/:job_id", get(prefetch_status))
+        .route("/progress/:job_id", get(job_progress))
+        .route("/sessions", post(session_create))
+        .route("/sessions/:id", get(session_status).delete(session_end))
+        .route("/login", post(login))
+        .route("/logout", post(logout));
+    // Or on listeners of their own, or nowhere
+    let app = match state.ports.admin_shared() {
+        true => app.merge(operational_routes()),
+        false => app,
+    };
+    let app = match state.ports.admin_shared() && state.ports.metrics_with_admin() {
+        true => app.merge(metrics_routes()),
+        false => app,
+    };
+    // X-Debug needs the client's grant
+    let app = app.route_layer(axum::middleware::from_fn(logging::debug));
+    // Inside the metrics layer, so refused requests are counted; the
+    // client's limits are metered once it is authenticated
+    let app = match &state.auth {
+        Some(auth) => app
+            .route_layer(axum::middleware::from_fn_with_state(
+                auth.tenants().clone(),
+                tenants::meter,
+            ))
+            .route_layer(axum::middleware::from_fn_with_state(
+                auth.clone(),
+                auth::require_auth,
+            )),
+        None => app,
+    };
+    let app = app
+        .route_layer(axum::middleware::from_fn(amplification::scope))
+        .route_layer(axum::middleware::from_fn(hub::select));
+    // Ahead of authentication, so floods are turned away cheaply
+    let app = match &state.throttle {
+        Some(throttle) => app.route_layer(axum::middleware::from_fn_with_state(
+            throttle.clone(),
+            throttle::limit,
+        )),
+        None => app,
+    };
+    // Documentation is public: outside authentication and the rate limits
+    let app = app.merge(openapi::routes());
+    let app = app
+        .route_layer(axum::middleware::from_fn_with_state(
+            state.metrics.clone(),
+            metrics::track,
+        ))
+        .layer(TraceLayer::new_for_http().make_span_with(logging::request_span))
+        .layer(axum::middleware::from_fn_with_state(
+            state.traces.clone(),
+            upstream::track,
+        ))
         .with_state(state);
 
-    let addr = format!("0.0.0.0:{}", port);
-    let listener = tokio::net::TcpListener::bind(&addr)
-        .await
-        .expect("Failed to bind to address");
+    // Hooks wrap the router so request rewrites take effect before routing
+    #[cfg(feature = "hooks")]
+    let app = match hooks::Hooks::from_env() {
+        Some(hooks) => Router::new()
+            .fallback_service(app)
+            .layer(axum::middleware::from_fn_with_state(hooks, hooks::run)),
+        None => app,
+    };
+    app
+}
+
+/// Admin and debug endpoints
+fn operational_routes() -> Router<Arc<AppState>> {
+    Router::new()
+        .route("/events", get(event_stream))
+        .route("/cache", get(cache_status).delete(cache_purge))
+        .route("/cache/:hash", get(cache_entry).delete(cache_remove))
+        .route("/cache/restore", post(cache_restore_all))
+        .route("/cache/:hash/metadata", put(cache_set_metadata))
+        .route("/cache/:hash/restore", post(cache_restore))
+        .route("/cache/listing/:owner/:repo", delete(listing_cache_purge))
+        .route("/config", get(effective_config))
+        .route("/upstream/:request_id", get(upstream_trace))
+        .route("/admin/drain-status", get(drain_status))
+        .route("/admin/limits", get(admin_limits))
+        .route(
+            "/admin/limits/:client",
+            get(admin_limits_get)
+                .put(admin_limits_set)
+                .delete(admin_limits_remove),
+        )
+}
+
+/// Metrics endpoints
+fn metrics_routes() -> Router<Arc<AppState>> {
+    Router::new()
+        .route("/metrics", get(prometheus_metrics))
+        .route("/slo", get(slo_status))
+}
+
+/// Routes of the admin listener, behind its own authentication
+fn admin_router(state: Arc<AppState>, admin: Admin) -> Router {
+    let routes = match state.ports.metrics_with_admin() {
+        true => operational_routes().merge(metrics_routes()),
+        false => operational_routes(),
+    };
+    routes
+        .route_layer(axum::middleware::from_fn_with_state(
+            admin,
+            admin::require_key,
+        ))
+        .route("/health", get(health))
+        .route("/healthz", get(liveness))
+        .route("/readyz", get(readiness))
+        .route_layer(axum::middleware::from_fn_with_state(
+            state.metrics.clone(),
+            metrics::track,
+        ))
+        .layer(TraceLayer::new_for_http().make_span_with(logging::request_span))
+        .with_state(state)
+}
+
+/// Routes of the metrics listener, open for scrapers
+fn metrics_router(state: Arc<AppState>) -> Router {
+    metrics_routes()
+        .route("/health", get(health))
+        .route("/healthz", get(liveness))
+        .route("/readyz", get(readiness))
+        .route_layer(axum::middleware::from_fn_with_state(
+            state.metrics.clone(),
+            metrics::track,
+        ))
+        .layer(TraceLayer::new_for_http().make_span_with(logging::request_span))
+        .with_state(state)
+}
+
+#[tokio::main]
+async fn main() {
+    // Subcommands and flags
+    let mut dry_run = false;
+    let mut args: Vec<String> = std::env::args().skip(1).collect();
+    let config_file = match args.iter().position(|arg| arg == "--config") {
+        Some(i) if i + 1 < args.len() => {
+            args.remove(i);
+            Some(args.remove(i))
+        }
+        Some(_) => {
+            eprintln!("--config needs a file");
+            std::process::exit(2);
+        }
+        None => None,
+    };
+    // Before anything reads a setting; check-config reports a failure itself
+    let loaded = config::init(config_file);
+    match args
+        .iter()
+        .map(String::as_str)
+        .collect::<Vec<_>>()
+        .as_slice()
+    {
+        [] => {}
+        ["check-config"] => std::process::exit(config_check::run()),
+        ["config-schema"] => {
+            let schema = config::schema();
+            println!(
+                "{}",
+                serde_json::to_string_pretty(&schema).unwrap_or_default()
+            );
+            std::process::exit(0);
+        }
+        ["migrate-cache"] => {
+            logging::init_stderr();
+            std::process::exit(cache::migrate_command());
+        }
+        ["--dry-run"] => dry_run = true,
+        ["--dev"] => dev::enable(),
+        ["--self-test"] => {
+            logging::init_stderr();
+            std::process::exit(self_test::run().await);
+        }
+        ["replay", rest @ ..] => {
+            logging::init_stderr();
+            std::process::exit(replay::run(rest).await);
+        }
+        _ => {
+            eprintln!("Usage: xet-proxy [--config <file>] [check-config | config-schema | migrate-cache | replay <log file> [--download] | --dry-run | --dev | --self-test]");
+            std::process::exit(2);
+        }
+    }
+
+    if let Err(e) = loaded {
+        panic!("{}", e);
+    }
+
+    logging::init();
+
+    let state = app_state().await;
+    let ports = state.ports.clone();
+    let shedder = state.shedder.clone();
+    let auth = state.auth.clone();
+    let policy = state.policy.clone();
+    let cache = state.cache.clone();
+    let uploads = state.uploads.clone();
+    let verify = state.verify;
+    let traces = state.traces.capacity();
+    let resume_tokens = state.aborts.issues_tokens();
+    let shutdown = state.shutdown.clone();
+    let token_scopes = state.token_scopes.clone();
+    let metrics = state.metrics.clone();
+    let admin = state.admin.clone();
+    let admin_app = admin
+        .clone()
+        .map(|admin| admin_router(state.clone(), admin));
+    let metrics_app = ports.metrics.own().map(|_| metrics_router(state.clone()));
+    let prefetch_sync = prefetch_sync(&state);
+    let app = router(state);
+    let tls = tls::Tls::from_env();
+
+    let addr = match &tls {
+        Some(tls) if tls.only() => None,
+        _ => ports.data.map(|port| format!("0.0.0.0:{}", port)),
+    };
+    let tls_addr = tls.as_ref().map(|tls| format!("0.0.0.0:{}", tls.port()));
+    if addr.is_none() && tls_addr.is_none() {
+        warn!("PORT is off and TLS is not set up: no data-plane listener");
+    }
+    if dry_run {
+        info!("Dry run: configuration loaded and all subsystems initialized, not binding");
+        return;
+    }
+    let listener = match &addr {
+        Some(addr) => Some(
+            tokio::net::TcpListener::bind(addr)
+                .await
+                .expect("Failed to bind to address"),
+        ),
+        None => None,
+    };
+    let tls_listener = match &tls_addr {
+        Some(tls_addr) => Some(
+            tokio::net::TcpListener::bind(tls_addr)
+                .await
+                .expect("Failed to bind to the TLS_PORT address"),
+        ),
+        None => None,
+    };
+    let admin_listener = match &admin {
+        Some(admin) => Some(admin.bind().await),
+        None => None,
+    };
+    let metrics_listener = match ports.metrics.own() {
+        Some(bind) => Some(bind.listen("METRICS_ADDR").await),
+        None => None,
+    };
+    token_scopes.check_startup().await;
+    shedder.start();
+    shutdown.start();
+    token_scopes.start(shutdown.clone());
+    if let Some(cache) = &cache {
+        cache.start();
+    }
+    if let Some((prefetcher, schedule, targets)) = &prefetch_sync {
+        prefetcher.schedule(
+            targets.clone(),
+            schedule.token.clone(),
+            schedule.interval,
+            schedule.priority,
+        );
+    }
+    if let Some(auth) = &auth {
+        auth.start();
+    }
+    if let Some(uploads) = &uploads {
+        uploads.start();
+    }
 
     info!("========================================");
     info!("XET Proxy Server v{}", VERSION);
     info!("========================================");
-    info!("Listening on: http://{}", addr);
+    if let Some(addr) = &addr {
+        info!("Listening on: http://{}", addr);
+    }
+    if let (Some(tls), Some(tls_addr)) = (&tls, &tls_addr) {
+        info!("Listening on: https://{} ({})", tls_addr, tls.client_auth());
+    }
+    if let Some(file) = config::file() {
+        info!("Configuration file: {}", file);
+    }
+    if dev::enabled() {
+        info!(
+            "Developer mode: serving the sample files of {}, no token needed",
+            dev::REPO
+        );
+    }
+    let hub = hub::configured();
+    if hub.default_endpoint() != hub::DEFAULT_ENDPOINT || hub.allowed() > 0 {
+        info!(
+            "Hub endpoint: {} ({} more allowed in X-HF-Endpoint)",
+            hub.default_endpoint(),
+            hub.allowed()
+        );
+    }
+    if token_scopes.read_only() {
+        info!("Read-only: uploads are refused");
+    }
+    if let Some(trust) = trust::describe() {
+        info!("Upstream TLS trusts {}", trust);
+    }
     info!("");
     info!("Endpoints:");
     info!("  GET /health");
+    info!("  GET /healthz, GET /readyz");
+    info!("  GET /version");
     info!("  GET /download/:owner/:repo/*file");
-    info!("  GET /download-hash/:hash");
+    info!("  GET /download/:type/:owner/:repo/resolve/:revision/*file");
+    info!("  GET /download-hash/:hash?repo=...");
+    info!("  GET /manifest/:hash?repo=...");
+    info!("  GET /download-archive/:owner/:repo?prefix=...");
+    info!("  GET /models/:alias[/*file]");
+    info!("  GET /list/:owner/:repo?prefix=...&budget_ms=...&cursor=...");
+    info!("  GET /snapshot/:owner/:repo");
+    info!("  POST /resolve");
+    info!("  POST /resolve-batch");
+    info!("  POST /exists");
+    info!("  GET /select/:owner/:repo?target=...");
+    info!("  PUT /upload/:owner/:repo/*file");
+    match &uploads {
+        Some(uploads) => info!(
+            "  POST /upload/:owner/:repo/*file, HEAD|PATCH|DELETE /uploads/:id (resumable, in {})",
+            uploads.dir().display()
+        ),
+        None => info!("  POST /upload/:owner/:repo/*file, HEAD|PATCH|DELETE /uploads/:id (disabled, UPLOAD_DIR unset)"),
+    }
+    info!("  POST /prefetch, GET /prefetch/:job_id");
+    info!("  GET /progress/:job_id");
+    info!("  POST /sessions, GET|DELETE /sessions/:id");
+    info!("  GET /openapi.json, GET /docs");
+    if matches!(ports.admin, Plane::Off) {
+        info!("");
+        info!("Admin endpoints off (ADMIN_ADDR=off)");
+    } else {
+        if let Some(admin) = &admin {
+            let keys = if admin.authenticated() {
+                "ADMIN_API_KEYS required"
+            } else {
+                "open, ADMIN_API_KEYS unset"
+            };
+            info!("");
+            info!("Admin endpoints on {} ({}):", admin.describe(), keys);
+        }
+        info!("  GET /events");
+        if ports.metrics_with_admin() {
+            info!("  GET /metrics");
+            info!("  GET /slo");
+        }
+        info!("  GET|DELETE /cache, GET|DELETE /cache/:hash, PUT /cache/:hash/metadata");
+        info!("  POST /cache/restore, POST /cache/:hash/restore");
+        info!("  DELETE /cache/listing/:owner/:repo");
+        info!("  GET /config");
+        info!("  GET /upstream/:request_id");
+        info!("  GET /admin/drain-status");
+        if let Some(auth) = &auth {
+            match auth.tenants().file() {
+                Some(file) => info!(
+                    "  GET /admin/limits, GET|PUT|DELETE /admin/limits/:client (saved to {})",
+                    file
+                ),
+                None => {
+                    info!("  GET /admin/limits, GET|PUT|DELETE /admin/limits/:client (not saved)")
+                }
+            }
+        }
+    }
+    match &ports.metrics {
+        Plane::Own(bind) => {
+            info!("");
+            info!("Metrics endpoints on {} (open):", bind.describe());
+            info!("  GET /metrics");
+            info!("  GET /slo");
+        }
+        Plane::Off => info!("Metrics endpoints off (METRICS_ADDR=off)"),
+        Plane::Shared => {}
+    }
     info!("");
-    info!("Press Ctrl+C to stop");
+    if let Some(auth) = &auth {
+        info!(
+            "Clients authenticated by {}, SIGHUP reloads their files",
+            auth.providers().join(", ")
+        );
+        info!("");
+    }
+    if let Some(policy) = &policy {
+        info!(
+            "Served files checked against the policy at {}",
+            policy.url()
+        );
+        info!("");
+    }
+    if verify != VerifyMode::Off {
+        let on_mismatch = match verify {
+            VerifyMode::Abort => "aborted",
+            _ => "logged",
+        };
+        info!(
+            "Whole-file downloads verified against their XET hash, mismatches {}",
+            on_mismatch
+        );
+        info!("");
+    }
+    if resume_tokens {
+        info!(
+            "Resumption tokens issued, so any replica sharing RESUME_SECRET can resume a transfer"
+        );
+        info!("");
+    }
+    if traces > 0 {
+        info!(
+            "Upstream requests of the last {} requests kept for /upstream/:request_id",
+            traces
+        );
+        info!("");
+    }
+    if let Some((_, schedule, targets)) = &prefetch_sync {
+        info!(
+            "Prefetching the {} items of {} now and every {}s",
+            targets.len(),
+            schedule.path,
+            schedule.interval.as_secs()
+        );
+        info!("");
+    }
+    info!(
+        "Press Ctrl+C to stop; in-flight requests get {}s to finish",
+        shutdown.drain().as_secs()
+    );
     info!("========================================");
 
-    axum::serve(listener, app)
-        .await
-        .expect("Server failed to start");
+    let plain = async {
+        let Some(listener) = listener else {
+            return;
+        };
+        // Peer addresses tell clients apart for rate limits
+        let app = app
+            .clone()
+            .into_make_service_with_connect_info::<std::net::SocketAddr>();
+        axum::serve(listener, app)
+            .with_graceful_shutdown(shutdown.draining())
+            .await
+            .expect("Server failed to start");
+    };
+    let secure = async {
+        if let (Some(tls), Some(listener)) = (&tls, tls_listener) {
+            tls.serve(listener, app.clone(), shutdown.draining()).await;
+        }
+    };
+    let operational = async {
+        if let (Some(listener), Some(admin_app)) = (admin_listener, admin_app) {
+            ports::serve(listener, admin_app, shutdown.draining()).await;
+        }
+    };
+    let scraped = async {
+        if let (Some(listener), Some(metrics_app)) = (metrics_listener, metrics_app) {
+            ports::serve(listener, metrics_app, shutdown.draining()).await;
+        }
+    };
+    tokio::select! {
+        _ = async { tokio::join!(plain, secure, operational, scraped) } => {
+            info!("All requests finished, exiting");
+        }
+        _ = shutdown.drained() => {
+            warn!(
+                "Drain time of {}s is up, cutting off the requests in flight ({} downloads streaming)",
+                shutdown.drain().as_secs(),
+                metrics.active_downloads()
+            );
+        }
+    }
 }
 
 /// Root endpoint - returns usage instructions
@@ -113,19 +1147,186 @@ async fn root() -> Response {
         <code>GET /health</code>
         <p>Returns server health status</p>
     </div>
+
+    <div class="endpoint">
+        <h3>Liveness and Readiness</h3>
+        <code>GET /healthz</code>, <code>GET /readyz</code>
+        <p>Liveness, and readiness checking that the download engine runs, the Hub accepts <code>HF_TOKEN</code> and the cache is writable (503 with per-check results otherwise)</p>
+    </div>
+
+    <div class="endpoint">
+        <h3>Version and Upstream Capabilities</h3>
+        <code>GET /version</code>
+        <p>Proxy version, download engine and what each Hub, CAS and xorb storage endpoint was found to support (native engine), for debugging mixed-version environments</p>
+    </div>
     
     <div class="endpoint">
         <h3>Download by Repository and Path</h3>
         <code>GET /download/:owner/:repo/*file</code>
         <p>Download a file from HuggingFace by repository and file path</p>
         <pre>curl http://localhost:8080/download/jedisct1/MiMo-7B-RL-GGUF/MiMo-7B-RL-Q8_0.gguf -o model.gguf</pre>
+        <p>Datasets, spaces and pinned revisions: <code>GET /download/:type/:owner/:repo/resolve/:revision/*file</code></p>
+        <pre>curl http://localhost:8080/download/datasets/org/evals/resolve/v2/test.parquet -o test.parquet</pre>
     </div>
     
     <div class="endpoint">
         <h3>Download by XET Hash</h3>
         <code>GET /download-hash/:hash</code>
-        <p>Download a file directly by its XET hash (64 hex characters)</p>
-        <pre>curl http://localhost:8080/download-hash/ef62b750... -o model.safetensors</pre>
+        <p>Download a file directly by its XET hash (64 hex characters, optionally as <code>xet1:&lt;hex&gt;</code>); <code>?repo=</code> names the repository it belongs to</p>
+        <pre>curl "http://localhost:8080/download-hash/ef62b750...?repo=owner/repo" -o model.safetensors</pre>
+    </div>
+    
+    <div class="endpoint">
+        <h3>Chunk Manifest</h3>
+        <code>GET /manifest/:hash</code>
+        <p>Ordered chunk hashes, offsets and lengths of a file, to verify a download chunk by chunk as it streams</p>
+        <pre>curl "http://localhost:8080/manifest/ef62b750...?repo=owner/repo"</pre>
+    </div>
+    
+    <div class="endpoint">
+        <h3>Model Aliases</h3>
+        <code>GET /models/:alias</code>
+        <p>Download the file an operator-defined alias is pinned to, or list the files of a bundle alias (each served at <code>/models/:alias/*file</code>)</p>
+        <pre>curl http://localhost:8080/models/prod-chat-v3 -H "Authorization: Bearer hf_xxxxxxxxxxxxx" -o model.gguf</pre>
+    </div>
+    
+    <div class="endpoint">
+        <h3>List Repository Files</h3>
+        <code>GET /list/:owner/:repo?prefix=&budget_ms=&cursor=</code>
+        <p>JSON list of XET-enabled files with path, size, XET hash and ETag, optionally filtered by path prefix. With a time budget, a gigantic repository answers in time with the files listed so far and an <code>X-Next-Cursor</code> to continue from. Otherwise the listing is streamed page by page; <code>Accept: application/x-ndjson</code> gets one object per line</p>
+        <pre>curl http://localhost:8080/list/jedisct1/MiMo-7B-RL-GGUF?prefix=onnx/ -H "Authorization: Bearer hf_xxxxxxxxxxxxx"</pre>
+    </div>
+    
+    <div class="endpoint">
+        <h3>Repository Snapshot</h3>
+        <code>GET /snapshot/:owner/:repo</code>
+        <p>List every XET-enabled file with its relative path, size, hash and proxy download URL</p>
+        <pre>curl http://localhost:8080/snapshot/jedisct1/MiMo-7B-RL-GGUF -H "Authorization: Bearer hf_xxxxxxxxxxxxx"</pre>
+    </div>
+
+    <div class="endpoint">
+        <h3>Resolve Hashes</h3>
+        <code>POST /resolve</code>
+        <p>XET hashes and sizes of many files of a repository (<code>{{"repo", "revision", "files": [...]}}</code>) from one listing; unlisted files map to <code>null</code></p>
+    </div>
+
+    <div class="endpoint">
+        <h3>Batch Resolution</h3>
+        <code>POST /resolve-batch</code>
+        <p>Hashes, sizes and cache status of files across repositories (<code>[{{"repo", "file", "revision"}}, ...]</code>), one listing per repository revision; failures are reported per file</p>
+    </div>
+
+    <div class="endpoint">
+        <h3>Hash Existence</h3>
+        <code>POST /exists</code>
+        <p>Sort a list of hashes (<code>{{"hashes": [...], "repo"}}</code>) into those cached locally, those listed upstream and unknown ones, in one round trip</p>
+    </div>
+
+    <div class="endpoint">
+        <h3>Archive Download</h3>
+        <code>GET /download-archive/:owner/:repo?prefix=...</code>
+        <p>Stream every XET-enabled file under a prefix (e.g. all shards of a checkpoint) as one tar archive</p>
+        <pre>curl http://localhost:8080/download-archive/owner/repo?prefix=model- -H "Authorization: Bearer hf_xxxxxxxxxxxxx" | tar x</pre>
+    </div>
+    
+    <div class="endpoint">
+        <h3>Select Artifact by Target</h3>
+        <code>GET /select/:owner/:repo?target=gguf:q4_k_m</code>
+        <p>Pick the best-matching file for a target descriptor and redirect to its download</p>
+        <pre>curl -L http://localhost:8080/select/jedisct1/MiMo-7B-RL-GGUF?target=gguf:q8_0 -H "Authorization: Bearer hf_xxxxxxxxxxxxx" -o model.gguf</pre>
+    </div>
+    
+    <div class="endpoint">
+        <h3>Upload a File</h3>
+        <code>PUT /upload/:owner/:repo/*file?revision=&amp;message=&amp;type=</code>
+        <p>Stream the request body to the repository through XET and commit it; returns the commit SHA and XET hash (requires a write token)</p>
+        <pre>curl -T model.gguf http://localhost:8080/upload/owner/repo/model.gguf -H "Authorization: Bearer hf_xxxxxxxxxxxxx"</pre>
+    </div>
+    
+    <div class="endpoint">
+        <h3>Resumable Upload</h3>
+        <code>POST /upload/:owner/:repo/*file</code>, <code>HEAD|PATCH|DELETE /uploads/:id</code>
+        <p>Upload over the tus protocol: open with <code>Upload-Length</code>, send the bytes in <code>PATCH</code>es at <code>Upload-Offset</code>, and resume from the offset <code>HEAD</code> reports, even after a proxy restart; the last <code>PATCH</code> commits the file (requires <code>UPLOAD_DIR</code>)</p>
+        <pre>curl -i -X POST http://localhost:8080/upload/owner/repo/model.gguf -H "Upload-Length: 4096" -H "Tus-Resumable: 1.0.0" -H "Authorization: Bearer hf_xxxxxxxxxxxxx"</pre>
+    </div>
+    
+    <div class="endpoint">
+        <h3>Latency SLOs</h3>
+        <code>GET /slo</code>
+        <p>Time-to-first-byte and total transfer time distributions per route, with SLO burn rates</p>
+    </div>
+    
+    <div class="endpoint">
+        <h3>Prometheus Metrics</h3>
+        <code>GET /metrics</code>
+        <p>Request, transfer, CLI failure, cache and load metrics in the Prometheus text format</p>
+    </div>
+    
+    <div class="endpoint">
+        <h3>Activity Events</h3>
+        <code>GET /events</code>
+        <p>Server-Sent Events stream of download started/finished/failed events (no auth required)</p>
+        <pre>curl -N http://localhost:8080/events</pre>
+    </div>
+    
+    <div class="endpoint">
+        <h3>File Cache</h3>
+        <code>GET /cache</code>, <code>DELETE /cache</code>, <code>GET /cache/:hash</code>, <code>DELETE /cache/:hash</code>, <code>PUT /cache/:hash/metadata</code>, <code>POST /cache/restore</code>, <code>POST /cache/:hash/restore</code>
+        <p>Inspect the download cache (when <code>CACHE_DIR</code> or <code>CACHE_S3_BUCKET</code> is set), purge it, drop one file, tag a file with team, retention class and labels, or restore deleted files within the grace period</p>
+    </div>
+
+    <div class="endpoint">
+        <h3>Listing Cache</h3>
+        <code>DELETE /cache/listing/:owner/:repo?type=&amp;revision=</code>
+        <p>Drop the cached listings of a repository (kept <code>LISTING_CACHE_TTL_SECS</code>, default 60); <code>?refresh=true</code> on any listing request bypasses them too</p>
+    </div>
+
+    <div class="endpoint">
+        <h3>Drain Status</h3>
+        <code>GET /admin/drain-status</code>
+        <p>Downloads still streaming (hash, bytes remaining, client), running jobs and the drain time left, to decide whether to wait out a shutdown or force it</p>
+    </div>
+
+    <div class="endpoint">
+        <h3>Client Limits</h3>
+        <code>GET /admin/limits</code>, <code>GET|PUT|DELETE /admin/limits/:client</code>
+        <p>Admins change a client's <code>requests_per_minute</code>, <code>daily_bytes</code> and <code>bandwidth_bytes_per_sec</code> at runtime, saved to <code>TENANT_LIMITS_FILE</code></p>
+    </div>
+
+    <div class="endpoint">
+        <h3>Prefetch</h3>
+        <code>POST /prefetch</code>, <code>GET /prefetch/:job_id</code>
+        <p>Download files (by <code>{{repo, file}}</code> or hash), whole repositories or an organization's repositories (<code>{{"repo": "myorg/*", "files": [...]}}</code>) into the cache in the background and follow the job's progress; <code>PREFETCH_SYNC_FILE</code> re-runs a job on a schedule</p>
+    </div>
+
+    <div class="endpoint">
+        <h3>Job Progress</h3>
+        <code>GET /progress/:job_id</code>
+        <p>Server-Sent Events with bytes transferred, total size, throughput and state of a prefetch job or archive download (id from <code>X-Job-Id</code>)</p>
+    </div>
+
+    <div class="endpoint">
+        <h3>Sessions</h3>
+        <code>POST /sessions</code>, <code>GET|DELETE /sessions/:id</code>
+        <p>Requests sending the session's id in <code>X-Session-Id</code> all read the commit each repository was at when the session first used it</p>
+    </div>
+
+    <div class="endpoint">
+        <h3>API Specification</h3>
+        <code>GET /openapi.json</code>, <code>GET /docs</code>
+        <p>OpenAPI 3 description of the endpoints above, for generating clients, and Swagger UI to browse and try it</p>
+    </div>
+
+    <div class="endpoint">
+        <h3>Configuration</h3>
+        <code>GET /config</code>
+        <p>Effective configuration (config file layered under the environment), secrets redacted, with the scopes of <code>HF_TOKEN</code></p>
+    </div>
+
+    <div class="endpoint">
+        <h3>Upstream Traces</h3>
+        <code>GET /upstream/:request_id</code>
+        <p>Status, upstream request id and timing of the Hub and CAS requests made for a request (by its <code>X-Request-Id</code>), when <code>UPSTREAM_TRACES</code> is set</p>
     </div>
     
     <h2>Authentication</h2>
@@ -135,6 +1336,20 @@ curl http://localhost:8080/download/owner/repo/file \\
   -H "Authorization: Bearer hf_xxxxxxxxxxxxx" \\
   -o file.bin
     </pre>
+    <p>If the proxy is configured with API keys, also send one in <code>X-API-Key</code>.</p>
+    <p>In a browser, log in once with <code>POST /login</code> instead: a cookie then authenticates the downloads the browser makes for <code>LOGIN_TTL_SECS</code>, until <code>POST /logout</code>.</p>
+    <form method="post" action="/login">
+        <input type="password" name="api_key" placeholder="API key" autocomplete="off">
+        <button>Log in</button>
+    </form>
+    <form method="post" action="/logout"><button>Log out</button></form>
+    <p>With <code>POLICY_URL</code> set, every file served is checked against an external policy engine (OPA-style); denied files are answered with 403.</p>
+    <p>With <code>RATE_LIMITS_FILE</code> set, each route may cap the requests per minute of a client (by IP address or API key, 429 beyond) and the bandwidth of each response.</p>
+    <p>With <code>TLS_CERT_FILE</code> and <code>TLS_KEY_FILE</code> set, HTTPS is served on <code>TLS_PORT</code> too, optionally requiring client certificates from <code>TLS_CLIENT_CA_FILE</code>.</p>
+    <p>With <code>READ_ONLY=true</code>, uploads are refused, and an <code>HF_TOKEN</code> that can write is warned about (refused with <code>TOKEN_SCOPE_POLICY=refuse</code>).</p>
+    <p>Upstream connections also trust the CAs of <code>UPSTREAM_CA_FILE</code> (only them with <code>UPSTREAM_CA_ONLY=true</code>) and the certificates pinned in <code>UPSTREAM_PINNED_CERTS</code>, for TLS-intercepting proxies and internal hubs.</p>
+    <p>With <code>ADMIN_ADDR</code> set, the admin, metrics and debug endpoints are served there only, behind <code>ADMIN_API_KEYS</code>; <code>METRICS_ADDR</code> gives <code>/metrics</code> and <code>/slo</code> an open listener of their own. Either may be <code>off</code>, and so may <code>PORT</code>.</p>
+    <p>Started with <code>--dev</code>, the proxy serves the sample files of <code>xet-proxy/dev-samples</code> without tokens or network, for local development and CI.</p>
     
     <h2>Examples</h2>
     <pre>
@@ -144,7 +1359,7 @@ curl http://localhost:8080/download/jedisct1/MiMo-7B-RL-GGUF/MiMo-7B-RL-Q8_0.ggu
   -o model.gguf
 
 # Download by hash with Bearer token
-curl http://localhost:8080/download-hash/89dbfa4888600b29be17ddee8bdbf9c48999c81cb811964eee6b057d8467f927 \\
+curl "http://localhost:8080/download-hash/89dbfa4888600b29be17ddee8bdbf9c48999c81cb811964eee6b057d8467f927?repo=jedisct1/MiMo-7B-RL-GGUF" \\
   -H "Authorization: Bearer hf_xxxxxxxxxxxxx" \\
   -o model.safetensors
 
@@ -163,156 +1378,3287 @@ curl http://localhost:8080/health
         .unwrap()
 }
 
-/// Extract HF token from Authorization: Bearer header
-fn extract_token(headers: &HeaderMap) -> Result<String, AppError> {
-    if let Some(auth_header) = headers.get(header::AUTHORIZATION) {
-        if let Ok(auth_str) = auth_header.to_str() {
-            // Check if it starts with "Bearer "
-            if let Some(token) = auth_str.strip_prefix("Bearer ") {
-                if !token.is_empty() {
-                    return Ok(token.to_string());
-                }
-            }
-        }
+/// HuggingFace token for a request: `Authorization: Bearer`, then
+/// `X-HF-Token`, then the server-wide token if fallback is enabled
+fn extract_token(headers: &HeaderMap, fallback: Option<&str>) -> Result<String, AppError> {
+    let bearer = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|h| h.to_str().ok())
+        .and_then(|h| h.strip_prefix("Bearer "));
+    let explicit = headers.get("x-hf-token").and_then(|h| h.to_str().ok());
+    if let Some(token) = bearer.or(explicit).map(str::trim).filter(|t| !t.is_empty()) {
+        return Ok(token.to_string());
     }
-    
+    if let Some(token) = fallback {
+        return Ok(token.to_string());
+    }
+
     // No token provided
     Err(AppError::Unauthorized(
-        "Authorization header required. Use: Authorization: Bearer hf_xxxxxxxxxxxxx".to_string()
+        "Authorization header required. Use: Authorization: Bearer hf_xxxxxxxxxxxxx".to_string(),
     ))
 }
 
-/// Health check endpoint
-async fn health() -> Json<HealthResponse> {
-    Json(HealthResponse {
-        status: "ok",
-        version: VERSION,
-    })
+/// Server-wide `HF_TOKEN`, used only when `HF_TOKEN_FALLBACK=true` (or a
+/// placeholder in developer mode)
+fn fallback_token_from_env() -> Option<String> {
+    // The samples of developer mode need no token
+    if dev::enabled() {
+        return Some(dev::TOKEN.to_string());
+    }
+    if !config::flag("HF_TOKEN_FALLBACK").unwrap_or(false) {
+        return None;
+    }
+    let token = std::env::var("HF_TOKEN").expect("HF_TOKEN_FALLBACK requires HF_TOKEN");
+    assert!(!token.trim().is_empty(), "HF_TOKEN must not be empty");
+    Some(token)
 }
 
-/// Download file by repository path
-async fn download_by_path(
-    State(state): State<Arc<AppState>>,
-    headers: HeaderMap,
-    Path((owner, repo, file)): Path<(String, String, String)>,
-) -> Result<Response, AppError> {
-    let repo_id = format!("{}/{}", owner, repo);
-    info!("Download request: repo={}, file={}", repo_id, file);
+/// `READ_ONLY`: refuse uploads
+fn read_only_from_env() -> bool {
+    config::flag("READ_ONLY").unwrap_or(false)
+}
 
-    // Extract token from Authorization header
-    let hf_token = extract_token(&headers)?;
+/// Refuse writes in read-only mode
+fn writable(state: &AppState) -> Result<(), AppError> {
+    if state.token_scopes.read_only() {
+        return Err(AppError::Forbidden(
+            "Uploads are disabled: the proxy is read-only (READ_ONLY=true)".to_string(),
+        ));
+    }
+    Ok(())
+}
 
-    // First, list files to get the XET hash
-    let output = Command::new(&state.zig_bin_path)
-        .arg(&repo_id)
-        .env("HF_TOKEN", &hf_token)
-        .output()
-        .await
-        .map_err(|e| AppError::Internal(format!("Failed to execute zig binary: {}", e)))?;
+/// `PREFETCH_SYNC_FILE`, its items checked like a `POST /prefetch` body
+fn prefetch_sync(
+    state: &AppState,
+) -> Option<(Prefetcher, prefetch::Schedule, Vec<prefetch::Target>)> {
+    let mut schedule = prefetch::Schedule::from_env()?;
+    let targets = std::mem::take(&mut schedule.items)
+        .into_iter()
+        .map(|item| prefetch_target(state, item))
+        .collect::<Result<Vec<_>, _>>()
+        .unwrap_or_else(|e| {
+            panic!(
+                "Invalid PREFETCH_SYNC_FILE {}: {}",
+                schedule.path,
+                e.message()
+            )
+        });
+    // The schedule needs a cache, so there is a prefetcher
+    let prefetcher = state.prefetcher.clone()?;
+    Some((prefetcher, schedule, targets))
+}
 
-    if !output.status.success() {
-        let stderr = String::from_utf8_lossy(&output.stderr);
-        error!("Zig CLI failed: {}", stderr);
-        return Err(AppError::Internal(format!("Failed to list files: {}", stderr)));
-    }
+/// Default repository for hash downloads, from `CAS_TOKEN_REPO`
+fn cas_token_repo_from_env() -> Option<RepoRef> {
+    let spec = std::env::var("CAS_TOKEN_REPO").ok()?;
+    Some(RepoRef::parse(&spec, None).unwrap_or_else(|| {
+        panic!(
+            "CAS_TOKEN_REPO must be 'owner/repo' or '<type>s/owner/repo', got '{}'",
+            spec
+        )
+    }))
+}
 
-    // Parse output to find the file and get its XET hash
-    let stdout = String::from_utf8_lossy(&output.stdout);
-    
-    // Look for the file in the output
-    // Expected format: "filename - size bytes - xetHash: abc123..."
-    let mut xet_hash = None;
-    for line in stdout.lines() {
-        if line.contains(&file) && line.contains("xetHash:") {
-            if let Some(hash_part) = line.split("xetHash:").nth(1) {
-                xet_hash = Some(hash_part.trim().to_string());
-                break;
+/// Repository whose CAS token authorizes a hash download
+fn hash_repo(state: &AppState, query: &DownloadQuery) -> Result<RepoRef, AppError> {
+    match &query.repo {
+        Some(spec) => RepoRef::parse(spec, query.revision.clone()).ok_or_else(|| {
+            AppError::BadRequest("repo must be 'owner/repo' or '<type>s/owner/repo'".to_string())
+        }),
+        None => state.cas_token_repo.clone().ok_or_else(|| {
+            AppError::BadRequest(
+                "Hash downloads need ?repo=<owner>/<repo> naming the repository the file belongs to"
+                    .to_string(),
+            )
+        }),
+    }
+}
+
+/// `repo` as pinned by the request's session, if it names one
+async fn session_repo(
+    state: &AppState,
+    headers: &HeaderMap,
+    repo: RepoRef,
+    hf_token: &str,
+) -> Result<RepoRef, AppError> {
+    match headers.get(SESSION_HEADER).and_then(|h| h.to_str().ok()) {
+        Some(id) => state.sessions.pin(id.trim(), repo, hf_token).await,
+        None => Ok(repo),
+    }
+}
+
+/// Refuse a repository the request's client may not reach
+fn authorize(grant: &Option<Extension<Grant>>, repo: &RepoRef) -> Result<(), AppError> {
+    grant
+        .as_ref()
+        .map_or(Ok(()), |Extension(grant)| grant.check(repo))
+}
+
+/// Name of the authenticated client, for the policy endpoint
+fn identity(grant: &Option<Extension<Grant>>) -> Option<&str> {
+    grant.as_ref().map(|Extension(grant)| &*grant.name)
+}
+
+/// Refuse unless the policy endpoint, if any, allows serving the file
+async fn check_policy(state: &AppState, access: Access<'_>) -> Result<(), AppError> {
+    match &state.policy {
+        Some(policy) => policy.check(&access).await,
+        None => Ok(()),
+    }
+}
+
+/// Health check endpoint
+#[utoipa::path(
+    get,
+    path = "/health",
+    tag = "health",
+    responses((status = 200, body = HealthResponse)),
+)]
+async fn health(State(state): State<Arc<AppState>>) -> Json<HealthResponse> {
+    Json(HealthResponse {
+        status: "ok",
+        version: VERSION,
+        load: state.shedder.status(),
+        downloads: state.limiter.as_ref().map(DownloadLimiter::status),
+    })
+}
+
+/// Liveness: the process serves requests
+#[utoipa::path(
+    get,
+    path = "/healthz",
+    tag = "health",
+    responses((status = 200, description = "`status` and `version`", body = Object)),
+)]
+async fn liveness() -> Json<serde_json::Value> {
+    Json(serde_json::json!({ "status": "ok", "version": VERSION }))
+}
+
+/// Readiness: the proxy can serve downloads, with the result of each check
+#[utoipa::path(
+    get,
+    path = "/readyz",
+    tag = "health",
+    responses(
+        (status = 200, body = ReadyReport),
+        (status = 503, description = "A check failed", body = ReadyReport),
+    ),
+)]
+async fn readiness(State(state): State<Arc<AppState>>) -> (StatusCode, Json<ReadyReport>) {
+    let report = state
+        .readiness
+        .check(state.downloader.as_ref(), state.cache.as_ref())
+        .await;
+    let status = if report.ready() {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (status, Json(report))
+}
+
+/// Version, engine and detected upstream capabilities
+#[derive(Serialize, ToSchema)]
+struct VersionResponse {
+    version: &'static str,
+    /// `cli`, `native` or `dev`
+    engine: String,
+    /// The engine's own check, as for `/readyz`
+    engine_status: String,
+    hub_endpoint: String,
+    /// Capabilities by kind (`hub`, `cas` or `xorb_storage`) and endpoint
+    /// URL, as detected so far
+    endpoints: BTreeMap<&'static str, BTreeMap<String, capabilities::EndpointCapabilities>>,
+}
+
+/// Proxy version and what each upstream endpoint was found to support
+#[utoipa::path(
+    get,
+    path = "/version",
+    tag = "health",
+    responses((status = 200, body = VersionResponse)),
+)]
+async fn version(State(state): State<Arc<AppState>>) -> Json<VersionResponse> {
+    let engine_status = match state.downloader.check().await {
+        Ok(detail) => detail,
+        Err(e) => format!("failed: {}", e),
+    };
+    Json(VersionResponse {
+        version: VERSION,
+        engine: std::env::var("XET_ENGINE").unwrap_or_else(|_| "cli".to_string()),
+        engine_status,
+        hub_endpoint: crate::hub::endpoint().to_string(),
+        endpoints: state.capabilities.report(),
+    })
+}
+
+/// List a repository within the request's budget and the repository's
+/// backoff, unless a recent listing is cached
+async fn list_repo(
+    state: &AppState,
+    options: &RequestOptions,
+    repo: &RepoRef,
+    hf_token: &str,
+) -> Result<Vec<ListedFile>, AppError> {
+    list_repo_fresh(state, options, repo, hf_token)
+        .await
+        .map(|(files, _)| files)
+}
+
+/// [`list_repo`], telling whether the listing was fetched from the Hub
+async fn list_repo_fresh(
+    state: &AppState,
+    options: &RequestOptions,
+    repo: &RepoRef,
+    hf_token: &str,
+) -> Result<(Vec<ListedFile>, bool), AppError> {
+    let cache = state.listing_cache.as_ref();
+    let commit = listing_commit(state, options, repo, hf_token).await?;
+    let commit = commit.as_deref();
+    if let Some(files) = cache
+        .filter(|_| !options.refresh)
+        .and_then(|c| c.get(repo, commit, hf_token))
+    {
+        debug!(
+            "Listing of {}@{} served from the cache",
+            repo, repo.revision
+        );
+        state.amplification.cached();
+        return Ok((files, false));
+    }
+    let key = repo.to_string();
+    let files = state
+        .amplification
+        .coalesce(repo, hf_token, || {
+            let listing = options.run("Repository listing", || {
+                state.downloader.list(repo, hf_token)
+            });
+            state.backoff.guard(&key, listing)
+        })
+        .await?;
+    state.catalog.record_listing(repo, &files);
+    if let Some(cache) = cache {
+        cache.put(repo, commit, hf_token, &files);
+    }
+    Ok((files, true))
+}
+
+/// Commit the revision of `repo` points at, which its listing is cached
+/// under; `None` without a listing cache, for a commit id, or when the
+/// engine can't tell
+async fn listing_commit(
+    state: &AppState,
+    options: &RequestOptions,
+    repo: &RepoRef,
+    hf_token: &str,
+) -> Result<Option<String>, AppError> {
+    if state.listing_cache.is_none() || repo.pinned() {
+        return Ok(None);
+    }
+    let lookup = options.run("Revision lookup", || {
+        state.downloader.commit(repo, hf_token)
+    });
+    state.backoff.guard(&repo.to_string(), lookup).await
+}
+
+/// Files of a repository as JSON
+#[utoipa::path(
+    get,
+    path = "/list/{owner}/{repo}",
+    tag = "metadata",
+    params(("owner" = String, Path), ("repo" = String, Path), ListQuery, PageQuery),
+    responses((
+        status = 200,
+        body = Vec<ListEntry>,
+        description = "The files, streamed unless paged, one per line with `Accept: application/x-ndjson`; a partial listing has `X-Next-Cursor` and a `Link` to the rest",
+    )),
+    security((), ("hf_token" = [])),
+)]
+async fn list_files(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Path((owner, name)): Path<(String, String)>,
+    Query(query): Query<ListQuery>,
+    Query(page): Query<PageQuery>,
+) -> Result<Response, AppError> {
+    let repo = RepoRef::model(owner.clone(), name.clone());
+    info!("List request: repo={}, prefix={:?}", repo, query.prefix);
+
+    let hf_token = extract_token(&headers, state.fallback_token.as_deref())?;
+    let repo = session_repo(&state, &headers, repo, &hf_token).await?;
+    let mut options = RequestOptions::from_headers(&headers, &state.override_limits)?;
+    options.refresh = query.refresh;
+    let budget = page.budget_ms.map(Duration::from_millis);
+    let budget = match (budget, state.override_limits.list_budget) {
+        (Some(asked), Some(max)) => Some(asked.min(max)),
+        (asked, max) => asked.or(max),
+    };
+    let format = ListFormat::from_headers(&headers);
+    let prefix = query.prefix.unwrap_or_default();
+    // Only a whole listing is cached
+    let commit = match &page.cursor {
+        None => listing_commit(&state, &options, &repo, &hf_token).await?,
+        Some(_) => None,
+    };
+    let (files, cursor) = match (budget, page.cursor) {
+        (None, None) => {
+            match list_first_page(&state, &options, &repo, commit.as_deref(), &hf_token).await? {
+                (files, None) => (files, None),
+                (files, Some(cursor)) => {
+                    let body = list_streamed(
+                        state.clone(),
+                        options,
+                        repo,
+                        commit,
+                        hf_token,
+                        files,
+                        cursor,
+                        prefix,
+                        format,
+                    );
+                    return Ok(
+                        ([(header::CONTENT_TYPE, format.content_type())], body).into_response()
+                    );
+                }
+            }
+        }
+        (budget, cursor) => {
+            let commit = commit.as_deref();
+            list_within(&state, &options, &repo, commit, &hf_token, cursor, budget).await?
+        }
+    };
+
+    let mut response = (
+        [(header::CONTENT_TYPE, format.content_type())],
+        list_body(files, prefix.clone(), format),
+    )
+        .into_response();
+    if let Some(cursor) = cursor {
+        let mut next = format!(
+            "{}/list/{}/{}?cursor={}",
+            public_base_url(&headers),
+            encode_path(&owner),
+            encode_path(&name),
+            cursor
+        );
+        if !prefix.is_empty() {
+            next.push_str(&format!("&prefix={}", encode_path(&prefix)));
+        }
+        if let Some(budget_ms) = page.budget_ms {
+            next.push_str(&format!("&budget_ms={}", budget_ms));
+        }
+        let headers = response.headers_mut();
+        if let Ok(value) = header::HeaderValue::from_str(&cursor) {
+            headers.insert("x-next-cursor", value);
+        }
+        if let Ok(value) = header::HeaderValue::from_str(&format!("<{}>; rel=\"next\"", next)) {
+            headers.insert(header::LINK, value);
+        }
+    }
+    Ok(response)
+}
+
+/// Entries `/list` writes at once
+const LIST_CHUNK_ENTRIES: usize = 1000;
+
+/// The files under `prefix`, as `/list` reports them
+fn list_entries(files: impl IntoIterator<Item = ListedFile>, prefix: &str) -> Vec<ListEntry> {
+    files
+        .into_iter()
+        .filter(|f| f.path.starts_with(prefix))
+        .map(|f| ListEntry {
+            etag: conditional::etag(&f.xet_hash),
+            path: f.path,
+            size: f.size,
+            xet_hash: f.xet_hash,
+        })
+        .collect()
+}
+
+/// How `/list` writes its entries
+#[derive(Clone, Copy)]
+enum ListFormat {
+    /// One JSON array
+    Json,
+    /// One JSON object per line (`Accept: application/x-ndjson`)
+    Ndjson,
+}
+
+impl ListFormat {
+    fn from_headers(headers: &HeaderMap) -> Self {
+        let accept = headers.get(header::ACCEPT).and_then(|v| v.to_str().ok());
+        match accept.is_some_and(|accept| accept.contains("application/x-ndjson")) {
+            true => Self::Ndjson,
+            false => Self::Json,
+        }
+    }
+
+    fn content_type(self) -> &'static str {
+        match self {
+            Self::Json => "application/json",
+            Self::Ndjson => "application/x-ndjson",
+        }
+    }
+}
+
+/// Writes `/list` entries chunk by chunk
+struct ListWriter {
+    format: ListFormat,
+    /// Entries written so far
+    written: usize,
+}
+
+impl ListWriter {
+    fn new(format: ListFormat) -> Self {
+        Self { format, written: 0 }
+    }
+
+    /// The next chunk of the listing, the `last` one closing it
+    fn chunk(&mut self, entries: &[ListEntry], last: bool) -> Bytes {
+        let json = matches!(self.format, ListFormat::Json);
+        let mut out = Vec::new();
+        for entry in entries {
+            if json {
+                out.push(if self.written == 0 { b'[' } else { b',' });
+            }
+            serde_json::to_writer(&mut out, entry).expect("Listing entries serialize");
+            if !json {
+                out.push(b'\n');
+            }
+            self.written += 1;
+        }
+        if json && last {
+            out.extend_from_slice(if self.written == 0 { b"[]" } else { b"]" });
+        }
+        out.into()
+    }
+}
+
+/// A listing already at hand, written `LIST_CHUNK_ENTRIES` at a time as the
+/// client takes them
+fn list_body(mut files: Vec<ListedFile>, prefix: String, format: ListFormat) -> Body {
+    files.retain(|f| f.path.starts_with(&prefix));
+    let mut writer = ListWriter::new(format);
+    if files.len() <= LIST_CHUNK_ENTRIES {
+        return Body::from(writer.chunk(&list_entries(files, &prefix), true));
+    }
+    let mut files = files.into_iter().peekable();
+    let chunks = std::iter::from_fn(move || {
+        files.peek()?;
+        let entries = list_entries(files.by_ref().take(LIST_CHUNK_ENTRIES), &prefix);
+        Some(Ok::<_, std::io::Error>(
+            writer.chunk(&entries, files.peek().is_none()),
+        ))
+    });
+    Body::from_stream(tokio_stream::iter(chunks))
+}
+
+/// A cached listing, or the first page of a repository listing with the
+/// cursor of the next if there are more; a one-page listing is cached, at
+/// `commit` (see [`listing_commit`])
+async fn list_first_page(
+    state: &AppState,
+    options: &RequestOptions,
+    repo: &RepoRef,
+    commit: Option<&str>,
+    hf_token: &str,
+) -> Result<(Vec<ListedFile>, Option<String>), AppError> {
+    let cache = state.listing_cache.as_ref();
+    if let Some(files) = cache
+        .filter(|_| !options.refresh)
+        .and_then(|c| c.get(repo, commit, hf_token))
+    {
+        debug!(
+            "Listing of {}@{} served from the cache",
+            repo, repo.revision
+        );
+        state.amplification.cached();
+        return Ok((files, None));
+    }
+    state.amplification.admit()?;
+    let listing = options.run("Repository listing", || {
+        state.downloader.list_page(repo, hf_token, None)
+    });
+    let page = state.backoff.guard(&repo.to_string(), listing).await?;
+    state.catalog.record_listing(repo, &page.files);
+    if let (Some(cache), None) = (cache, &page.cursor) {
+        cache.put(repo, commit, hf_token, &page.files);
+    }
+    Ok((page.files, page.cursor))
+}
+
+/// A listing streamed page by page from its `first` page, the next fetched
+/// as the client takes the previous one. An upstream failure after the
+/// first page can only cut the response short. The pages are kept for the
+/// listing cache, if it is on, at `commit`.
+#[allow(clippy::too_many_arguments)]
+fn list_streamed(
+    state: Arc<AppState>,
+    options: RequestOptions,
+    repo: RepoRef,
+    commit: Option<String>,
+    hf_token: String,
+    first: Vec<ListedFile>,
+    cursor: String,
+    prefix: String,
+    format: ListFormat,
+) -> Body {
+    let (sender, receiver) = tokio::sync::mpsc::channel(2);
+    tokio::spawn(upstream::inherit(async move {
+        let key = repo.to_string();
+        let mut cached = state.listing_cache.as_ref().map(|_| first.clone());
+        let mut listed = first.len();
+        let mut writer = ListWriter::new(format);
+        let mut chunk = writer.chunk(&list_entries(first, &prefix), false);
+        let mut cursor = Some(cursor);
+        while let Some(next) = cursor.take() {
+            if sender.send(Ok(chunk)).await.is_err() {
+                return;
+            }
+            let listing = options.run("Repository listing", || {
+                state.downloader.list_page(&repo, &hf_token, Some(&next))
+            });
+            let page = match state.backoff.guard(&key, listing).await {
+                Ok(page) => page,
+                Err(e) => {
+                    warn!(
+                        "Listing of {} failed after {} files: {}",
+                        repo,
+                        listed,
+                        e.message()
+                    );
+                    let _ = sender
+                        .send(Err(std::io::Error::other(e.message().to_string())))
+                        .await;
+                    return;
+                }
+            };
+            state.catalog.record_listing(&repo, &page.files);
+            listed += page.files.len();
+            if let Some(cached) = &mut cached {
+                cached.extend(page.files.iter().cloned());
+            }
+            cursor = page.cursor;
+            chunk = writer.chunk(&list_entries(page.files, &prefix), cursor.is_none());
+        }
+        let _ = sender.send(Ok(chunk)).await;
+        info!("Listing of {} streamed, {} files", repo, listed);
+        if let (Some(cache), Some(files)) = (&state.listing_cache, cached) {
+            cache.put(&repo, commit.as_deref(), &hf_token, &files);
+        }
+    }));
+    Body::from_stream(tokio_stream::wrappers::ReceiverStream::new(receiver))
+}
+
+/// Pages of a repository listing from `cursor`, for as long as `budget`
+/// (and the request's deadline) allows but at least one, with the cursor
+/// to continue from if the listing isn't over; a whole listing is cached
+/// at `commit`
+#[allow(clippy::too_many_arguments)]
+async fn list_within(
+    state: &AppState,
+    options: &RequestOptions,
+    repo: &RepoRef,
+    commit: Option<&str>,
+    hf_token: &str,
+    mut cursor: Option<String>,
+    budget: Option<Duration>,
+) -> Result<(Vec<ListedFile>, Option<String>), AppError> {
+    let whole = cursor.is_none();
+    let cache = state.listing_cache.as_ref();
+    if let Some(files) = cache
+        .filter(|_| whole && !options.refresh)
+        .and_then(|c| c.get(repo, commit, hf_token))
+    {
+        debug!(
+            "Listing of {}@{} served from the cache",
+            repo, repo.revision
+        );
+        state.amplification.cached();
+        return Ok((files, None));
+    }
+    // Charged once per request, whatever the pages
+    state.amplification.admit()?;
+    let end = match (budget.map(|b| options.received_at + b), options.deadline) {
+        (Some(end), Some(deadline)) => Some(end.min(deadline)),
+        (end, deadline) => end.or(deadline),
+    };
+    let key = repo.to_string();
+    let mut files = Vec::new();
+    let mut first = true;
+    loop {
+        let listing = options.run("Repository listing", || {
+            state
+                .downloader
+                .list_page(repo, hf_token, cursor.as_deref())
+        });
+        let listing = state.backoff.guard(&key, listing);
+        let page = match end {
+            Some(end) if !first => match tokio::time::timeout_at(end, listing).await {
+                Ok(page) => page?,
+                // Out of time: this page is where the next answer starts
+                Err(_) => break,
+            },
+            _ => listing.await?,
+        };
+        first = false;
+        state.catalog.record_listing(repo, &page.files);
+        files.extend(page.files);
+        cursor = page.cursor;
+        if cursor.is_none() || end.is_some_and(|end| tokio::time::Instant::now() >= end) {
+            break;
+        }
+    }
+    if cursor.is_some() {
+        info!("Listing of {} partial after {} files", repo, files.len());
+    } else if whole {
+        if let Some(cache) = cache {
+            cache.put(repo, commit, hf_token, &files);
+        }
+    }
+    Ok((files, cursor))
+}
+
+/// Files of a repository under a prefix as one streamed tar archive
+#[utoipa::path(
+    get,
+    path = "/download-archive/{owner}/{repo}",
+    tag = "downloads",
+    params(("owner" = String, Path), ("repo" = String, Path), ArchiveQuery),
+    responses((status = 200, description = "Tar archive of the files", content_type = "application/x-tar")),
+    security((), ("hf_token" = [])),
+)]
+async fn download_archive(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    grant: Option<Extension<Grant>>,
+    Path((owner, repo)): Path<(String, String)>,
+    Query(query): Query<ArchiveQuery>,
+) -> Result<Response, AppError> {
+    let repo = RepoRef::model(owner, repo);
+    info!(
+        "Archive request: repo={}, prefix={:?}, on_error={:?}",
+        repo, query.prefix, query.on_error
+    );
+    state.shedder.check()?;
+
+    let hf_token = extract_token(&headers, state.fallback_token.as_deref())?;
+    let repo = session_repo(&state, &headers, repo, &hf_token).await?;
+    // Every listed file is served from this repository, so it is checked once
+    // against the grant, before anything is listed
+    authorize(&grant, &repo)?;
+    let mut options = RequestOptions::from_headers(&headers, &state.override_limits)?;
+    options.refresh = query.refresh;
+    let prefix = query.prefix.unwrap_or_default();
+    let files: Vec<_> = list_repo(&state, &options, &repo, &hf_token)
+        .await?
+        .into_iter()
+        .filter(|f| f.path.starts_with(&prefix))
+        .collect();
+    if files.is_empty() {
+        return Err(AppError::NotFound(format!(
+            "No XET-enabled files under '{}' in {}",
+            prefix, repo
+        )));
+    }
+    // One denied file refuses the whole archive
+    for file in &files {
+        check_policy(
+            &state,
+            Access {
+                identity: identity(&grant),
+                repo: Some(&repo),
+                file: Some(&file.path),
+                hash: &file.xet_hash,
+                size: Some(file.size),
+            },
+        )
+        .await?;
+    }
+
+    let size = archive::archive_size(&repo, &files);
+    let priority = nice_priority(query.nice)?;
+    let slot = download_slot(&state, priority, &options).await?;
+    let job_id = progress::job_id();
+    let job = state.progress.start(&job_id, "archive", files.len());
+    let body = archive::stream(
+        state.downloader.clone(),
+        repo.clone(),
+        files,
+        hf_token,
+        state.archive_parallelism,
+        options,
+        query.on_error,
+        priority,
+        job,
+    );
+    let mut response = Response::builder()
+        .header(header::CONTENT_TYPE, "application/x-tar")
+        .header("x-job-id", job_id)
+        .header(
+            header::CONTENT_DISPOSITION,
+            format!("attachment; filename=\"{}.tar\"", repo.name),
+        )
+        .header(
+            header::TRAILER,
+            match query.on_error {
+                archive::OnError::Fail => archive::SHA256_TRAILER.to_string(),
+                _ => format!("{}, {}", archive::SHA256_TRAILER, archive::SKIPPED_TRAILER),
+            },
+        );
+    // Trailers need a chunked response, which a length rules out, as does
+    // leaving files out
+    let wants_trailers = headers
+        .get(header::TE)
+        .and_then(|te| te.to_str().ok())
+        .is_some_and(|te| {
+            te.split(',')
+                .any(|t| t.trim().eq_ignore_ascii_case("trailers"))
+        });
+    if !wants_trailers && query.on_error == archive::OnError::Fail {
+        response = response.header(header::CONTENT_LENGTH, size);
+    }
+    match slot {
+        Some(slot) => response.body(Body::new(Holding::new(body, slot))),
+        None => response.body(Body::new(body)),
+    }
+    .map_err(|e| AppError::Internal(format!("Failed to build response: {}", e)))
+}
+
+/// Repository snapshot manifest
+#[utoipa::path(
+    get,
+    path = "/snapshot/{owner}/{repo}",
+    tag = "metadata",
+    params(("owner" = String, Path), ("repo" = String, Path), RefreshQuery),
+    responses((status = 200, body = SnapshotResponse)),
+    security((), ("hf_token" = [])),
+)]
+async fn snapshot(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Path((owner, repo)): Path<(String, String)>,
+    Query(query): Query<RefreshQuery>,
+) -> Result<Json<SnapshotResponse>, AppError> {
+    let repo = RepoRef::model(owner, repo);
+    info!("Snapshot request: repo={}", repo);
+
+    let hf_token = extract_token(&headers, state.fallback_token.as_deref())?;
+    let repo = session_repo(&state, &headers, repo, &hf_token).await?;
+    let mut options = RequestOptions::from_headers(&headers, &state.override_limits)?;
+    options.refresh = query.refresh;
+    let files = list_repo(&state, &options, &repo, &hf_token).await?;
+
+    let base_url = public_base_url(&headers);
+    let siblings = files
+        .into_iter()
+        .map(|f| SnapshotFile {
+            url: format!("{}/download-hash/{}?repo={}", base_url, f.xet_hash, repo),
+            rfilename: f.path,
+            size: f.size,
+            xet_hash: f.xet_hash,
+        })
+        .collect();
+
+    Ok(Json(SnapshotResponse {
+        repo_id: repo.id(),
+        revision: repo.revision.clone(),
+        siblings,
+    }))
+}
+
+/// XET hashes and sizes of several files of a repository, from one listing;
+/// files that aren't listed (or not XET-enabled) map to `null`
+#[utoipa::path(
+    post,
+    path = "/resolve",
+    tag = "metadata",
+    params(RefreshQuery),
+    request_body = ResolveRequest,
+    responses((status = 200, description = "Hash and size by path, `null` when not listed", body = BTreeMap<String, ResolvedHash>)),
+    security((), ("hf_token" = [])),
+)]
+async fn resolve_hashes(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    grant: Option<Extension<Grant>>,
+    Query(query): Query<RefreshQuery>,
+    request: Result<Json<ResolveRequest>, JsonRejection>,
+) -> Result<Json<BTreeMap<String, Option<ResolvedHash>>>, AppError> {
+    let Json(request) = request.map_err(|e| AppError::BadRequest(e.body_text()))?;
+    if request.files.is_empty() || request.files.len() > MAX_RESOLVE_FILES {
+        return Err(AppError::BadRequest(format!(
+            "Resolve takes 1 to {} files",
+            MAX_RESOLVE_FILES
+        )));
+    }
+    let repo = RepoRef::parse(&request.repo, request.revision).ok_or_else(|| {
+        AppError::BadRequest("repo must be 'owner/repo' or '<type>s/owner/repo'".to_string())
+    })?;
+    authorize(&grant, &repo)?;
+    info!(
+        "Resolve request: repo={}, {} files",
+        repo,
+        request.files.len()
+    );
+
+    let hf_token = extract_token(&headers, state.fallback_token.as_deref())?;
+    let repo = session_repo(&state, &headers, repo, &hf_token).await?;
+    let mut options = RequestOptions::from_headers(&headers, &state.override_limits)?;
+    options.refresh = query.refresh;
+    let listed: HashMap<String, ListedFile> = list_repo(&state, &options, &repo, &hf_token)
+        .await?
+        .into_iter()
+        .map(|f| (f.path.clone(), f))
+        .collect();
+    let resolved = request
+        .files
+        .into_iter()
+        .map(|path| {
+            let hash = listed.get(&path).map(|f| ResolvedHash {
+                xet_hash: f.xet_hash.clone(),
+                size: f.size,
+            });
+            (path, hash)
+        })
+        .collect();
+    Ok(Json(resolved))
+}
+
+/// XET hashes, sizes and cache status of files across repositories, from
+/// one listing per repository revision, several at a time
+#[utoipa::path(
+    post,
+    path = "/resolve-batch",
+    tag = "metadata",
+    params(RefreshQuery),
+    request_body = Vec<BatchFile>,
+    responses((status = 200, body = Vec<BatchResolved>)),
+    security((), ("hf_token" = [])),
+)]
+async fn resolve_batch(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    grant: Option<Extension<Grant>>,
+    Query(query): Query<RefreshQuery>,
+    request: Result<Json<Vec<BatchFile>>, JsonRejection>,
+) -> Result<Json<Vec<BatchResolved>>, AppError> {
+    let Json(files) = request.map_err(|e| AppError::BadRequest(e.body_text()))?;
+    if files.is_empty() || files.len() > MAX_BATCH_FILES {
+        return Err(AppError::BadRequest(format!(
+            "Batch resolve takes 1 to {} files",
+            MAX_BATCH_FILES
+        )));
+    }
+    let hf_token = extract_token(&headers, state.fallback_token.as_deref())?;
+    let mut options = RequestOptions::from_headers(&headers, &state.override_limits)?;
+    options.refresh = query.refresh;
+
+    // Files by the listing that resolves them
+    let mut groups: Vec<RepoRef> = Vec::new();
+    let mut group_of = HashMap::new();
+    let mut refused = HashMap::new();
+    let placed: Vec<Option<usize>> = files
+        .iter()
+        .enumerate()
+        .map(|(i, file)| {
+            let Some(repo) = RepoRef::parse(&file.repo, file.revision.clone()) else {
+                refused.insert(
+                    i,
+                    "repo must be 'owner/repo' or '<type>s/owner/repo'".to_string(),
+                );
+                return None;
+            };
+            if let Err(e) = authorize(&grant, &repo) {
+                refused.insert(i, e.message().to_string());
+                return None;
+            }
+            let key = (repo.to_string(), repo.revision.clone());
+            Some(*group_of.entry(key).or_insert_with(|| {
+                groups.push(repo);
+                groups.len() - 1
+            }))
+        })
+        .collect();
+    info!(
+        "Batch resolve request: {} files in {} repository revisions",
+        files.len(),
+        groups.len()
+    );
+
+    let permits = Arc::new(tokio::sync::Semaphore::new(BATCH_LISTINGS));
+    let mut listings = tokio::task::JoinSet::new();
+    for (index, repo) in groups.into_iter().enumerate() {
+        let (state, headers, hf_token, options) = (
+            state.clone(),
+            headers.clone(),
+            hf_token.clone(),
+            options.clone(),
+        );
+        let permits = permits.clone();
+        listings.spawn(upstream::inherit(async move {
+            let _permit = permits.acquire_owned().await;
+            let listed = async {
+                let repo = session_repo(&state, &headers, repo, &hf_token).await?;
+                let files: HashMap<String, ListedFile> =
+                    list_repo(&state, &options, &repo, &hf_token)
+                        .await?
+                        .into_iter()
+                        .map(|f| (f.path.clone(), f))
+                        .collect();
+                Ok::<_, AppError>((repo.revision, files))
+            };
+            (index, listed.await)
+        }));
+    }
+    let mut listed = HashMap::new();
+    while let Some(joined) = listings.join_next().await {
+        let (index, listing) =
+            joined.map_err(|e| AppError::Internal(format!("Batch listing failed: {}", e)))?;
+        listed.insert(index, listing);
+    }
+
+    let cached = |hash: &str| {
+        state
+            .cache
+            .as_ref()
+            .is_some_and(|cache| cache.size(hash).is_some())
+    };
+    let resolved = files
+        .into_iter()
+        .zip(placed)
+        .enumerate()
+        .map(|(i, (file, group))| {
+            let mut resolved = BatchResolved {
+                repo: file.repo,
+                file: file.file,
+                revision: file.revision,
+                xet_hash: None,
+                size: None,
+                cached: false,
+                error: refused.remove(&i),
+            };
+            match group.and_then(|group| listed.get(&group)) {
+                Some(Ok((revision, files))) => {
+                    resolved.revision = Some(revision.clone());
+                    if let Some(listed) = files.get(&resolved.file) {
+                        resolved.cached = cached(&listed.xet_hash);
+                        resolved.xet_hash = Some(listed.xet_hash.clone());
+                        resolved.size = Some(listed.size);
+                    }
+                }
+                Some(Err(e)) => resolved.error = Some(e.message().to_string()),
+                None => {}
+            }
+            resolved
+        })
+        .collect();
+    Ok(Json(resolved))
+}
+
+/// Which of a list of hashes are cached, listed upstream, or unknown
+#[utoipa::path(
+    post,
+    path = "/exists",
+    tag = "metadata",
+    params(RefreshQuery),
+    request_body = ExistsRequest,
+    responses((status = 200, body = ExistsResponse)),
+    security((), ("hf_token" = [])),
+)]
+async fn hashes_exist(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    grant: Option<Extension<Grant>>,
+    Query(query): Query<RefreshQuery>,
+    request: Result<Json<ExistsRequest>, JsonRejection>,
+) -> Result<Json<ExistsResponse>, AppError> {
+    let Json(mut request) = request.map_err(|e| AppError::BadRequest(e.body_text()))?;
+    if request.hashes.is_empty() || request.hashes.len() > MAX_EXISTS_HASHES {
+        return Err(AppError::BadRequest(format!(
+            "Exists takes 1 to {} hashes",
+            MAX_EXISTS_HASHES
+        )));
+    }
+    let hashes = request
+        .hashes
+        .iter()
+        .map(|hash| XetHash::parse(hash).map(XetHash::into_string));
+    request.hashes = hashes.collect::<Result<_, _>>()?;
+    let repo = match &request.repo {
+        Some(spec) => {
+            let repo = RepoRef::parse(spec, request.revision.clone()).ok_or_else(|| {
+                AppError::BadRequest(
+                    "repo must be 'owner/repo' or '<type>s/owner/repo'".to_string(),
+                )
+            })?;
+            authorize(&grant, &repo)?;
+            Some(repo)
+        }
+        None => None,
+    };
+
+    let cached = |hash: &str| {
+        state
+            .cache
+            .as_ref()
+            .is_some_and(|cache| cache.size(hash).is_some())
+    };
+    let cataloged = |hash: &str| {
+        state
+            .catalog
+            .get(hash)
+            .is_some_and(|known| authorize(&grant, &known.repo).is_ok())
+    };
+    // One listing settles the rest, for the repository the client named
+    let mut listed = HashSet::new();
+    let unsettled = request
+        .hashes
+        .iter()
+        .any(|hash| !cached(hash) && !cataloged(hash));
+    if let (Some(repo), true) = (repo, unsettled) {
+        let hf_token = extract_token(&headers, state.fallback_token.as_deref())?;
+        let repo = session_repo(&state, &headers, repo, &hf_token).await?;
+        let mut options = RequestOptions::from_headers(&headers, &state.override_limits)?;
+        options.refresh = query.refresh;
+        listed = list_repo(&state, &options, &repo, &hf_token)
+            .await?
+            .into_iter()
+            .map(|f| f.xet_hash)
+            .collect();
+    }
+
+    let mut response = ExistsResponse::default();
+    for hash in request.hashes {
+        if cached(&hash) {
+            response.cached.push(hash);
+        } else if listed.contains(&hash) || cataloged(&hash) {
+            response.upstream.push(hash);
+        } else {
+            response.unknown.push(hash);
+        }
+    }
+    info!(
+        "Exists request: {} cached, {} upstream, {} unknown",
+        response.cached.len(),
+        response.upstream.len(),
+        response.unknown.len()
+    );
+    Ok(Json(response))
+}
+
+/// Upload the request body and commit it to the repository
+#[utoipa::path(
+    put,
+    path = "/upload/{owner}/{repo}/{file}",
+    tag = "uploads",
+    params(("owner" = String, Path), ("repo" = String, Path), ("file" = String, Path), UploadQuery),
+    request_body(content = Vec<u8>, content_type = "application/octet-stream"),
+    responses((status = 200, body = UploadResponse)),
+    security(("hf_token" = [])),
+)]
+async fn upload_file(
+    State(state): State<Arc<AppState>>,
+    grant: Option<Extension<Grant>>,
+    headers: HeaderMap,
+    Path((owner, repo, file)): Path<(String, String, String)>,
+    Query(query): Query<UploadQuery>,
+    body: Body,
+) -> Result<Json<UploadResponse>, AppError> {
+    let repo = upload_repo(owner, repo, &query)?;
+    authorize(&grant, &repo)?;
+    info!("Upload request: repo={}, file={}", repo, file);
+    writable(&state)?;
+    state.shedder.check()?;
+
+    let hf_token = extract_token(&headers, state.fallback_token.as_deref())?;
+    let options = RequestOptions::from_headers(&headers, &state.override_limits)?;
+    let message = query.message.unwrap_or_else(|| format!("Upload {}", file));
+    let body = body
+        .into_data_stream()
+        .map(|chunk| chunk.map_err(std::io::Error::other));
+
+    let result = state
+        .downloader
+        .upload(UploadRequest {
+            repo: &repo,
+            path: &file,
+            hf_token: &hf_token,
+            message: &message,
+            body: Box::pin(body),
+            deadline: options.deadline,
+        })
+        .await?;
+    info!(
+        "Uploaded {} to {} ({} bytes, commit {})",
+        file, repo, result.size, result.commit_oid
+    );
+    state
+        .catalog
+        .record(&repo, &file, result.size, &result.xet_hash);
+
+    Ok(Json(UploadResponse::new(&repo, file, result)))
+}
+
+/// The repository and branch an upload commits to
+fn upload_repo(owner: String, repo: String, query: &UploadQuery) -> Result<RepoRef, AppError> {
+    let mut repo = RepoRef::model(owner, repo);
+    repo.repo_type = query.repo_type;
+    if let Some(revision) = &query.revision {
+        if revision.is_empty() {
+            return Err(AppError::BadRequest(
+                "revision must not be empty".to_string(),
+            ));
+        }
+        repo.revision = revision.clone();
+    }
+    Ok(repo)
+}
+
+impl UploadResponse {
+    fn new(repo: &RepoRef, path: String, result: UploadResult) -> Self {
+        Self {
+            repo: repo.to_string(),
+            path,
+            commit_sha: result.commit_oid,
+            xet_hash: result.xet_hash,
+            sha256: result.sha256,
+            size: result.size,
+        }
+    }
+}
+
+fn tus_uploads(state: &AppState) -> Result<&tus::Uploads, AppError> {
+    state.uploads.as_ref().ok_or_else(|| {
+        AppError::NotFound("Resumable uploads are disabled (UPLOAD_DIR is not set)".to_string())
+    })
+}
+
+/// Open a resumable upload of `Upload-Length` bytes (tus creation)
+#[utoipa::path(
+    post,
+    path = "/upload/{owner}/{repo}/{file}",
+    tag = "uploads",
+    params(
+        ("owner" = String, Path), ("repo" = String, Path), ("file" = String, Path), UploadQuery,
+        ("Upload-Length" = u64, Header, description = "Size of the file"),
+    ),
+    responses((status = 201, description = "Upload opened; its URL is in `Location`")),
+    security(("hf_token" = [])),
+)]
+async fn tus_create(
+    State(state): State<Arc<AppState>>,
+    grant: Option<Extension<Grant>>,
+    headers: HeaderMap,
+    Path((owner, repo, file)): Path<(String, String, String)>,
+    Query(query): Query<UploadQuery>,
+) -> Result<Response, AppError> {
+    writable(&state)?;
+    let uploads = tus_uploads(&state)?;
+    let repo = upload_repo(owner, repo, &query)?;
+    authorize(&grant, &repo)?;
+    info!("Resumable upload request: repo={}, file={}", repo, file);
+    state.shedder.check()?;
+
+    let hf_token = extract_token(&headers, state.fallback_token.as_deref())?;
+    let length = headers
+        .get("upload-length")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<u64>().ok())
+        .filter(|&length| length > 0)
+        .ok_or_else(|| {
+            AppError::BadRequest("Upload-Length must be a positive integer".to_string())
+        })?;
+    let message = query.message.unwrap_or_else(|| format!("Upload {}", file));
+    let id = uploads
+        .create(&repo, &file, message, length, &hf_token, identity(&grant))
+        .await?;
+
+    Response::builder()
+        .status(StatusCode::CREATED)
+        .header(
+            header::LOCATION,
+            format!("{}/uploads/{}", public_base_url(&headers), id),
+        )
+        .header("upload-offset", 0)
+        .header("tus-resumable", tus::VERSION)
+        .body(Body::empty())
+        .map_err(|e| AppError::Internal(format!("Failed to build response: {}", e)))
+}
+
+/// Where a resumable upload stands, in `Upload-Offset`
+#[utoipa::path(
+    head,
+    path = "/uploads/{id}",
+    tag = "uploads",
+    params(("id" = String, Path)),
+    responses((status = 200, description = "`Upload-Offset` and `Upload-Length` of the upload")),
+    security(("hf_token" = [])),
+)]
+async fn tus_status(
+    State(state): State<Arc<AppState>>,
+    grant: Option<Extension<Grant>>,
+    headers: HeaderMap,
+    Path(id): Path<String>,
+) -> Result<Response, AppError> {
+    let uploads = tus_uploads(&state)?;
+    let hf_token = extract_token(&headers, state.fallback_token.as_deref())?;
+    let status = uploads.status(&id, &hf_token, identity(&grant))?;
+    authorize(&grant, &status.repo)?;
+    head_response(
+        Response::builder()
+            .header("upload-offset", status.offset)
+            .header("upload-length", status.length)
+            .header(header::CACHE_CONTROL, "no-store")
+            .header("tus-resumable", tus::VERSION),
+    )
+}
+
+/// Append the body to a resumable upload at `Upload-Offset`; the part
+/// completing it commits the file
+#[utoipa::path(
+    patch,
+    path = "/uploads/{id}",
+    tag = "uploads",
+    params(("id" = String, Path), ("Upload-Offset" = u64, Header, description = "Where the body goes in the file")),
+    request_body(content = Vec<u8>, content_type = "application/offset+octet-stream"),
+    responses(
+        (status = 204, description = "Part received; the new offset is in `Upload-Offset`"),
+        (status = 200, body = UploadResponse, description = "Upload complete and committed"),
+        (status = 409, description = "Offset mismatch, or another request is writing"),
+    ),
+    security(("hf_token" = [])),
+)]
+async fn tus_write(
+    State(state): State<Arc<AppState>>,
+    grant: Option<Extension<Grant>>,
+    headers: HeaderMap,
+    Path(id): Path<String>,
+    body: Body,
+) -> Result<Response, AppError> {
+    writable(&state)?;
+    let uploads = tus_uploads(&state)?;
+    let hf_token = extract_token(&headers, state.fallback_token.as_deref())?;
+    let status = uploads.status(&id, &hf_token, identity(&grant))?;
+    authorize(&grant, &status.repo)?;
+    state.shedder.check()?;
+
+    let content_type = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok());
+    if content_type != Some(tus::PATCH_CONTENT_TYPE) {
+        return Err(AppError::BadRequest(format!(
+            "Content-Type must be {}",
+            tus::PATCH_CONTENT_TYPE
+        )));
+    }
+    let offset = headers
+        .get("upload-offset")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<u64>().ok())
+        .ok_or_else(|| AppError::BadRequest("Upload-Offset must be an integer".to_string()))?;
+    let options = RequestOptions::from_headers(&headers, &state.override_limits)?;
+    let body = body
+        .into_data_stream()
+        .map(|chunk| chunk.map_err(std::io::Error::other));
+
+    let response = Response::builder().header("tus-resumable", tus::VERSION);
+    let written = uploads
+        .write(
+            &id,
+            &hf_token,
+            identity(&grant),
+            offset,
+            body,
+            state.downloader.as_ref(),
+            options.deadline,
+        )
+        .await?;
+    let response = match written {
+        tus::Written::Partial(offset) => response
+            .status(StatusCode::NO_CONTENT)
+            .header("upload-offset", offset)
+            .body(Body::empty()),
+        tus::Written::Committed(result) => {
+            info!(
+                "Uploaded {} to {} ({} bytes, commit {}, resumable upload {})",
+                status.path, status.repo, result.size, result.commit_oid, id
+            );
+            state
+                .catalog
+                .record(&status.repo, &status.path, result.size, &result.xet_hash);
+            let upload = UploadResponse::new(&status.repo, status.path, result);
+            let json =
+                serde_json::to_vec(&upload).map_err(|e| AppError::Internal(e.to_string()))?;
+            response
+                .header("upload-offset", status.length)
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(Body::from(json))
+        }
+    };
+    response.map_err(|e| AppError::Internal(format!("Failed to build response: {}", e)))
+}
+
+/// Abandon a resumable upload
+#[utoipa::path(
+    delete,
+    path = "/uploads/{id}",
+    tag = "uploads",
+    params(("id" = String, Path)),
+    responses((status = 204, description = "Upload abandoned")),
+    security(("hf_token" = [])),
+)]
+async fn tus_delete(
+    State(state): State<Arc<AppState>>,
+    grant: Option<Extension<Grant>>,
+    headers: HeaderMap,
+    Path(id): Path<String>,
+) -> Result<Response, AppError> {
+    let uploads = tus_uploads(&state)?;
+    let hf_token = extract_token(&headers, state.fallback_token.as_deref())?;
+    let client = identity(&grant);
+    authorize(&grant, &uploads.status(&id, &hf_token, client)?.repo)?;
+    uploads.delete(&id, &hf_token, client).await?;
+    Response::builder()
+        .status(StatusCode::NO_CONTENT)
+        .header("tus-resumable", tus::VERSION)
+        .body(Body::empty())
+        .map_err(|e| AppError::Internal(format!("Failed to build response: {}", e)))
+}
+
+/// Redirect to the repository file best matching a target descriptor
+#[utoipa::path(
+    get,
+    path = "/select/{owner}/{repo}",
+    tag = "metadata",
+    params(("owner" = String, Path), ("repo" = String, Path), SelectQuery),
+    responses((status = 302, description = "Redirect to the download of the best match")),
+    security((), ("hf_token" = [])),
+)]
+async fn select_artifact(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Path((owner, repo)): Path<(String, String)>,
+    Query(query): Query<SelectQuery>,
+) -> Result<Response, AppError> {
+    let repo = RepoRef::model(owner, repo);
+    info!("Select request: repo={}, target={}", repo, query.target);
+
+    let target = Target::parse(&query.target).ok_or_else(|| {
+        AppError::BadRequest("Invalid target (expected <format>[:<variant>])".to_string())
+    })?;
+    let hf_token = extract_token(&headers, state.fallback_token.as_deref())?;
+    let repo = session_repo(&state, &headers, repo, &hf_token).await?;
+    let mut options = RequestOptions::from_headers(&headers, &state.override_limits)?;
+    options.refresh = query.refresh;
+    let files = list_repo(&state, &options, &repo, &hf_token).await?;
+
+    let selected = state
+        .selection_rules
+        .select(&target, &files)
+        .ok_or_else(|| {
+            AppError::NotFound(format!(
+                "No file in '{}' matches target '{}'",
+                repo, query.target
+            ))
+        })?;
+    info!("Selected {} for target {}", selected.path, query.target);
+
+    let location = format!("/download/{}/{}", repo, encode_path(&selected.path));
+    Ok(Response::builder()
+        .status(StatusCode::FOUND)
+        .header(header::LOCATION, location)
+        .header("x-selected-file", encode_path(&selected.path))
+        .body(Body::empty())
+        .unwrap())
+}
+
+/// Percent-encode a repository path for use in a URL, keeping `/`
+fn encode_path(path: &str) -> String {
+    let mut out = String::with_capacity(path.len());
+    for byte in path.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' | b'/' => {
+                out.push(byte as char)
+            }
+            _ => out.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    out
+}
+
+/// Externally visible base URL of this proxy, derived from the request
+fn public_base_url(headers: &HeaderMap) -> String {
+    let host = headers
+        .get(header::HOST)
+        .and_then(|h| h.to_str().ok())
+        .unwrap_or("localhost");
+    let scheme = headers
+        .get("x-forwarded-proto")
+        .and_then(|h| h.to_str().ok())
+        .unwrap_or("http");
+    format!("{}://{}", scheme, host)
+}
+
+/// Latency SLO status per download route
+async fn slo_status(State(state): State<Arc<AppState>>) -> Json<SloReport> {
+    Json(state.slo.report())
+}
+
+/// Effective configuration, secrets redacted, with the scopes of `HF_TOKEN`
+async fn effective_config(State(state): State<Arc<AppState>>) -> Json<serde_json::Value> {
+    let mut effective = config::effective();
+    if let Some(report) = state.token_scopes.report() {
+        effective["token"] = serde_json::to_value(report).unwrap_or_default();
+    }
+    Json(effective)
+}
+
+/// Prometheus metrics
+async fn prometheus_metrics(State(state): State<Arc<AppState>>) -> Response {
+    let body = state.metrics.render(|out| {
+        out.family(
+            "xet_proxy_cli_failures_total",
+            "counter",
+            "Failed download engine runs by signature",
+        );
+        for (signature, count) in state.downloader.failure_counts() {
+            out.sample(
+                "xet_proxy_cli_failures_total",
+                &[("signature", &signature)],
+                count,
+            );
+        }
+
+        out.family(
+            "xet_proxy_upstream_retries_total",
+            "counter",
+            "Retries of transient upstream failures by operation",
+        );
+        for (operation, count) in state.override_limits.retry.counts() {
+            out.sample(
+                "xet_proxy_upstream_retries_total",
+                &[("operation", &operation)],
+                count,
+            );
+        }
+
+        out.family(
+            "xet_proxy_transfer_drift_total",
+            "counter",
+            "Completed transfers whose byte counts disagreed",
+        );
+        out.sample(
+            "xet_proxy_transfer_drift_total",
+            &[],
+            state
+                .transfer_drift
+                .load(std::sync::atomic::Ordering::Relaxed),
+        );
+
+        if let Some(policy) = &state.policy {
+            let (allowed, denied, failed) = policy.counts();
+            out.family(
+                "xet_proxy_policy_decisions_total",
+                "counter",
+                "File access checks by policy outcome",
+            );
+            out.sample(
+                "xet_proxy_policy_decisions_total",
+                &[("decision", "allow")],
+                allowed,
+            );
+            out.sample(
+                "xet_proxy_policy_decisions_total",
+                &[("decision", "deny")],
+                denied,
+            );
+            out.sample(
+                "xet_proxy_policy_decisions_total",
+                &[("decision", "error")],
+                failed,
+            );
+        }
+
+        if let Some(throttle) = &state.throttle {
+            out.family(
+                "xet_proxy_throttled_requests_total",
+                "counter",
+                "Requests refused over a route's rate limit",
+            );
+            out.sample(
+                "xet_proxy_throttled_requests_total",
+                &[],
+                throttle.limited(),
+            );
+        }
+
+        if let Some(cache) = &state.cache {
+            let report = cache.report();
+            out.family(
+                "xet_proxy_cache_hits_total",
+                "counter",
+                "Downloads served from the cache",
+            );
+            out.sample("xet_proxy_cache_hits_total", &[], report.hits);
+            out.family(
+                "xet_proxy_cache_misses_total",
+                "counter",
+                "Downloads that went upstream",
+            );
+            out.sample("xet_proxy_cache_misses_total", &[], report.misses);
+            out.family(
+                "xet_proxy_cache_used_bytes",
+                "gauge",
+                "Bytes of cached files",
+            );
+            out.sample("xet_proxy_cache_used_bytes", &[], report.used_bytes);
+            out.family("xet_proxy_cache_max_bytes", "gauge", "Cache size limit");
+            out.sample("xet_proxy_cache_max_bytes", &[], report.max_bytes);
+
+            let mut team_bytes = std::collections::BTreeMap::new();
+            for entry in &report.entries {
+                if let Some(team) = entry.metadata.as_ref().and_then(|m| m.team.as_ref()) {
+                    *team_bytes.entry(team.as_str()).or_insert(0) += entry.size;
+                }
+            }
+            out.family(
+                "xet_proxy_cache_team_bytes",
+                "gauge",
+                "Bytes of cached files by owning team",
+            );
+            for (team, bytes) in team_bytes {
+                out.sample("xet_proxy_cache_team_bytes", &[("team", team)], bytes);
+            }
+        }
+
+        if let Some(listing_cache) = &state.listing_cache {
+            out.family(
+                "xet_proxy_listing_cache_hits_total",
+                "counter",
+                "Repository listings served from the cache",
+            );
+            out.sample(
+                "xet_proxy_listing_cache_hits_total",
+                &[],
+                listing_cache.hits(),
+            );
+            out.family(
+                "xet_proxy_listing_cache_misses_total",
+                "counter",
+                "Repository listings made upstream",
+            );
+            out.sample(
+                "xet_proxy_listing_cache_misses_total",
+                &[],
+                listing_cache.misses(),
+            );
+        }
+        out.family(
+            "xet_proxy_listing_requests_total",
+            "counter",
+            "Requests needing a repository listing, by how they got it",
+        );
+        for (outcome, count) in state.amplification.outcomes() {
+            out.sample(
+                "xet_proxy_listing_requests_total",
+                &[("outcome", outcome)],
+                count,
+            );
+        }
+        if let Some(factor) = state.amplification.factor() {
+            out.family(
+                "xet_proxy_listing_amplification",
+                "gauge",
+                "Upstream listings made per request needing one",
+            );
+            out.sample("xet_proxy_listing_amplification", &[], factor);
+        }
+
+        if let Some(reverify) = &state.reverify {
+            out.family(
+                "xet_proxy_cache_reverifications_total",
+                "counter",
+                "Checks of old cached files against upstream, by result",
+            );
+            for (result, count) in reverify.counts() {
+                out.sample(
+                    "xet_proxy_cache_reverifications_total",
+                    &[("result", result)],
+                    count,
+                );
+            }
+        }
+
+        if let Some(head_cache) = &state.head_cache {
+            out.family(
+                "xet_proxy_head_cache_hits_total",
+                "counter",
+                "Redirect-mode reads served from cached heads",
+            );
+            out.sample("xet_proxy_head_cache_hits_total", &[], head_cache.hits());
+            out.family(
+                "xet_proxy_head_cache_misses_total",
+                "counter",
+                "Head fetches from upstream",
+            );
+            out.sample(
+                "xet_proxy_head_cache_misses_total",
+                &[],
+                head_cache.misses(),
+            );
+            out.family(
+                "xet_proxy_head_cache_used_bytes",
+                "gauge",
+                "Bytes of cached heads",
+            );
+            out.sample(
+                "xet_proxy_head_cache_used_bytes",
+                &[],
+                head_cache.used_bytes(),
+            );
+        }
+
+        let load = state.shedder.status();
+        out.family(
+            "xet_proxy_shedding",
+            "gauge",
+            "1 while new downloads are shed",
+        );
+        out.sample("xet_proxy_shedding", &[], u8::from(load.shedding));
+        out.family(
+            "xet_proxy_resident_memory_bytes",
+            "gauge",
+            "Resident set size",
+        );
+        out.sample("xet_proxy_resident_memory_bytes", &[], load.rss_bytes);
+        out.family("xet_proxy_open_fds", "gauge", "Open file descriptors");
+        out.sample("xet_proxy_open_fds", &[], load.open_fds);
+    });
+
+    Response::builder()
+        .header(header::CONTENT_TYPE, "text/plain; version=0.0.4")
+        .body(Body::from(body))
+        .unwrap()
+}
+
+/// Activity event stream (Server-Sent Events)
+async fn event_stream(State(state): State<Arc<AppState>>) -> impl IntoResponse {
+    state.events.sse(state.shutdown.draining())
+}
+
+fn enabled_cache(state: &AppState) -> Result<&Cache, AppError> {
+    state.cache.as_ref().ok_or_else(|| {
+        AppError::NotFound(
+            "Caching is disabled (neither CACHE_DIR nor CACHE_S3_BUCKET is set)".to_string(),
+        )
+    })
+}
+
+/// Cache usage and entries
+async fn cache_status(State(state): State<Arc<AppState>>) -> Result<Json<CacheReport>, AppError> {
+    Ok(Json(enabled_cache(&state)?.report()))
+}
+
+/// Remove every cached file, restorable during the grace period
+async fn cache_purge(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<DeleteQuery>,
+) -> Result<Json<PurgeResponse>, AppError> {
+    let cache = enabled_cache(&state)?;
+    let (removed, freed_bytes) = cache.purge(query.immediate).await;
+    let restorable_for = Some(cache.grace().as_secs()).filter(|&secs| secs > 0 && !query.immediate);
+    match restorable_for {
+        Some(secs) => info!(
+            "Cache purged: {} files, {} bytes, restorable for {}s",
+            removed, freed_bytes, secs
+        ),
+        None => info!("Cache purged: {} files, {} bytes", removed, freed_bytes),
+    }
+    Ok(Json(PurgeResponse {
+        removed,
+        freed_bytes,
+        restorable_for,
+    }))
+}
+
+/// Bring back every file deleted within the grace period
+async fn cache_restore_all(
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<RestoreResponse>, AppError> {
+    let (restored, bytes) = enabled_cache(&state)?
+        .restore_all()
+        .await
+        .map_err(|e| AppError::Internal(format!("Failed to restore deleted files: {}", e)))?;
+    info!("Cache restored: {} files, {} bytes", restored, bytes);
+    Ok(Json(RestoreResponse { restored, bytes }))
+}
+
+/// Bring back one file deleted within the grace period
+async fn cache_restore(
+    State(state): State<Arc<AppState>>,
+    Path(hash): Path<String>,
+) -> Result<Json<CacheEntry>, AppError> {
+    let hash = XetHash::parse(&hash)?.into_string();
+    enabled_cache(&state)?
+        .restore(&hash)
+        .await
+        .map_err(|e| AppError::Internal(format!("Failed to restore {}: {}", hash, e)))?
+        .map(Json)
+        .ok_or_else(|| AppError::NotFound(format!("{} has no restorable deleted copy", hash)))
+}
+
+/// One cached file with its metadata
+async fn cache_entry(
+    State(state): State<Arc<AppState>>,
+    Path(hash): Path<String>,
+) -> Result<Json<CacheEntry>, AppError> {
+    let hash = XetHash::parse(&hash)?.into_string();
+    enabled_cache(&state)?
+        .entry(&hash)
+        .map(Json)
+        .ok_or_else(|| AppError::NotFound(format!("{} is not cached", hash)))
+}
+
+/// Replace the metadata of a cached file
+async fn cache_set_metadata(
+    State(state): State<Arc<AppState>>,
+    Path(hash): Path<String>,
+    metadata: Result<Json<CacheMetadata>, JsonRejection>,
+) -> Result<Json<CacheEntry>, AppError> {
+    let Json(metadata) = metadata.map_err(|e| AppError::BadRequest(e.body_text()))?;
+    let hash = XetHash::parse(&hash)?.into_string();
+    enabled_cache(&state)?
+        .set_metadata(&hash, metadata)
+        .await
+        .map_err(|e| AppError::Internal(format!("Failed to store metadata of {}: {}", hash, e)))?
+        .map(Json)
+        .ok_or_else(|| AppError::NotFound(format!("{} is not cached", hash)))
+}
+
+/// Remove one cached file, restorable during the grace period
+async fn cache_remove(
+    State(state): State<Arc<AppState>>,
+    Path(hash): Path<String>,
+    Query(query): Query<DeleteQuery>,
+) -> Result<StatusCode, AppError> {
+    let hash = XetHash::parse(&hash)?.into_string();
+    enabled_cache(&state)?
+        .delete(&hash, query.immediate)
+        .await
+        .map(|_| StatusCode::NO_CONTENT)
+        .ok_or_else(|| AppError::NotFound(format!("{} is not cached", hash)))
+}
+
+/// Drop the cached listings of a repository, so the next request lists it
+/// again
+async fn listing_cache_purge(
+    State(state): State<Arc<AppState>>,
+    Path((owner, repo)): Path<(String, String)>,
+    Query(query): Query<ListingDeleteQuery>,
+) -> Result<Json<ListingPurgeResponse>, AppError> {
+    let cache = state.listing_cache.as_ref().ok_or_else(|| {
+        AppError::NotFound("Listing cache is disabled (LISTING_CACHE_TTL_SECS=0)".to_string())
+    })?;
+    let removed = cache.invalidate(query.repo_type, &owner, &repo, query.revision.as_deref());
+    info!(
+        "Listing cache purged: {} listings of {}/{}",
+        removed, owner, repo
+    );
+    Ok(Json(ListingPurgeResponse { removed }))
+}
+
+/// Start downloading files into the cache in the background
+#[utoipa::path(
+    post,
+    path = "/prefetch",
+    tag = "prefetch",
+    params(PrefetchQuery),
+    request_body = Vec<PrefetchItem>,
+    responses((status = 202, body = PrefetchResponse)),
+    security((), ("hf_token" = [])),
+)]
+async fn prefetch_submit(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    grant: Option<Extension<Grant>>,
+    Query(query): Query<PrefetchQuery>,
+    items: Result<Json<Vec<PrefetchItem>>, JsonRejection>,
+) -> Result<(StatusCode, Json<PrefetchResponse>), AppError> {
+    let Json(items) = items.map_err(|e| AppError::BadRequest(e.body_text()))?;
+    let prefetcher = state.prefetcher.as_ref().ok_or_else(|| {
+        AppError::NotFound(
+            "Caching is disabled (neither CACHE_DIR nor CACHE_S3_BUCKET is set)".to_string(),
+        )
+    })?;
+    if items.is_empty() || items.len() > prefetch::MAX_ITEMS {
+        return Err(AppError::BadRequest(format!(
+            "Prefetch takes 1 to {} files",
+            prefetch::MAX_ITEMS
+        )));
+    }
+    state.shedder.check()?;
+
+    let hf_token = extract_token(&headers, state.fallback_token.as_deref())?;
+    let options = RequestOptions::from_headers(&headers, &state.override_limits)?;
+    let priority = nice_priority(query.nice)?;
+    let targets = items
+        .into_iter()
+        .map(|item| prefetch_target(&state, item))
+        .collect::<Result<Vec<_>, _>>()?;
+    for target in &targets {
+        let (prefetch::Target::Path { repo, .. }
+        | prefetch::Target::Hash { repo, .. }
+        | prefetch::Target::Scope(prefetch::Scope { repo, .. })) = target;
+        authorize(&grant, repo)?;
+    }
+    let files = targets
+        .iter()
+        .filter(|target| !matches!(target, prefetch::Target::Scope(_)))
+        .count();
+    let timeout = options
+        .deadline
+        .map(|deadline| deadline - options.received_at);
+    let job_id = prefetcher.submit(targets, hf_token, timeout, priority);
+
+    let status_url = format!("{}/prefetch/{}", public_base_url(&headers), job_id);
+    Ok((
+        StatusCode::ACCEPTED,
+        Json(PrefetchResponse {
+            job_id,
+            files,
+            status_url,
+        }),
+    ))
+}
+
+/// The priority of background work at niceness `nice`
+fn nice_priority(nice: u8) -> Result<Priority, AppError> {
+    Priority::from_nice(nice).ok_or_else(|| {
+        AppError::BadRequest(format!(
+            "nice must be 0 to {}, got {}",
+            slots::MAX_NICE,
+            nice
+        ))
+    })
+}
+
+/// Validate one prefetch item and find the repository authorizing it
+fn prefetch_target(state: &AppState, item: PrefetchItem) -> Result<prefetch::Target, AppError> {
+    let parse_repo = |spec: &str, revision: Option<String>| {
+        RepoRef::parse(spec, revision).ok_or_else(|| {
+            AppError::BadRequest(format!(
+                "repo must be 'owner/repo' or '<type>s/owner/repo', got '{}'",
+                spec
+            ))
+        })
+    };
+    let (hash, repo, revision) = match item {
+        PrefetchItem::Path {
+            repo,
+            file,
+            revision,
+        } => {
+            return Ok(prefetch::Target::Path {
+                repo: parse_repo(&repo, revision)?,
+                file,
+            })
+        }
+        PrefetchItem::Hash {
+            hash,
+            repo,
+            revision,
+        } => (hash, repo, revision),
+        PrefetchItem::Scope {
+            repo,
+            files,
+            revision,
+            max_file_size,
+            max_total_size,
+        } => {
+            let repo = parse_repo(&repo, revision)?;
+            if repo.owner.contains(['*', '?']) {
+                return Err(AppError::BadRequest(format!(
+                    "Wildcards are only allowed in the repository name, got '{}'",
+                    repo
+                )));
+            }
+            return Ok(prefetch::Target::Scope(prefetch::Scope {
+                repo,
+                files: files.unwrap_or_default(),
+                max_file_size,
+                max_total_size,
+            }));
+        }
+        PrefetchItem::BareHash(hash) => (hash, None, None),
+    };
+    let hash = XetHash::parse(&hash)?.into_string();
+    // Without a repository, the one the hash was listed in authorizes it
+    let repo = match repo {
+        Some(spec) => parse_repo(&spec, revision)?,
+        None => state
+            .catalog
+            .get(&hash)
+            .map(|entry| entry.repo)
+            .or_else(|| state.cas_token_repo.clone())
+            .ok_or_else(|| {
+                AppError::BadRequest(format!(
+                    "Hash {} needs a repo naming the repository it belongs to",
+                    hash
+                ))
+            })?,
+    };
+    Ok(prefetch::Target::Hash { repo, hash })
+}
+
+/// Progress of a prefetch job
+#[utoipa::path(
+    get,
+    path = "/prefetch/{job_id}",
+    tag = "prefetch",
+    params(("job_id" = String, Path)),
+    responses((status = 200, body = JobStatus)),
+)]
+async fn prefetch_status(
+    State(state): State<Arc<AppState>>,
+    Path(job_id): Path<String>,
+) -> Result<Json<JobStatus>, AppError> {
+    state
+        .prefetcher
+        .as_ref()
+        .and_then(|prefetcher| prefetcher.status(&job_id))
+        .map(Json)
+        .ok_or_else(|| AppError::NotFound(format!("Unknown prefetch job '{}'", job_id)))
+}
+
+/// Progress of a prefetch job or archive download (Server-Sent Events)
+#[utoipa::path(
+    get,
+    path = "/progress/{job_id}",
+    tag = "prefetch",
+    params(("job_id" = String, Path)),
+    responses((status = 200, description = "Progress events", content_type = "text/event-stream")),
+)]
+async fn job_progress(
+    State(state): State<Arc<AppState>>,
+    Path(job_id): Path<String>,
+) -> Result<impl IntoResponse, AppError> {
+    state.progress.sse(&job_id, state.shutdown.draining())
+}
+
+/// Start a download session pinning the repositories it reads
+#[utoipa::path(
+    post,
+    path = "/sessions",
+    tag = "sessions",
+    responses((status = 201, body = SessionStatus)),
+)]
+async fn session_create(
+    State(state): State<Arc<AppState>>,
+) -> Result<(StatusCode, Json<SessionStatus>), AppError> {
+    let id = state.sessions.create()?;
+    Ok((
+        StatusCode::CREATED,
+        Json(SessionStatus {
+            id,
+            expires_in: state.sessions.ttl().as_secs(),
+            pins: Vec::new(),
+        }),
+    ))
+}
+
+/// Commits a session has pinned
+#[utoipa::path(
+    get,
+    path = "/sessions/{id}",
+    tag = "sessions",
+    params(("id" = String, Path)),
+    responses((status = 200, body = SessionStatus)),
+)]
+async fn session_status(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+) -> Result<Json<SessionStatus>, AppError> {
+    state
+        .sessions
+        .status(&id)
+        .map(Json)
+        .ok_or_else(|| AppError::NotFound(format!("Unknown or expired session '{}'", id)))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/sessions/{id}",
+    tag = "sessions",
+    params(("id" = String, Path)),
+    responses((status = 204, description = "Session ended")),
+)]
+async fn session_end(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+) -> Result<StatusCode, AppError> {
+    if state.sessions.end(&id) {
+        Ok(StatusCode::NO_CONTENT)
+    } else {
+        Err(AppError::NotFound(format!(
+            "Unknown or expired session '{}'",
+            id
+        )))
+    }
+}
+
+/// Form of `POST /login`, from a browser
+#[derive(Deserialize, ToSchema)]
+struct LoginForm {
+    /// API key, which a browser can't send in `X-API-Key`
+    api_key: Option<String>,
+}
+
+/// Response of `POST /login`
+#[derive(Serialize, ToSchema)]
+struct LoginStatus {
+    /// Client the login stands for
+    client: String,
+    /// Seconds the login cookie lasts
+    expires_in: u64,
+}
+
+/// Whether the request was posted by an HTML form, answered by sending the
+/// browser back to `/`
+fn posted_by_form(headers: &HeaderMap) -> bool {
+    headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.starts_with("application/x-www-form-urlencoded"))
+}
+
+/// `response` setting the login cookie to `token` for `max_age`
+fn with_login_cookie(
+    mut response: Response,
+    headers: &HeaderMap,
+    token: &str,
+    max_age: Duration,
+) -> Response {
+    let secure = public_base_url(headers).starts_with("https:");
+    let cookie = auth::set_cookie(token, max_age, secure);
+    if let Ok(value) = header::HeaderValue::from_str(&cookie) {
+        response.headers_mut().insert(header::SET_COOKIE, value);
+    }
+    response
+}
+
+/// Log in with the request's credentials, for a cookie authenticating the
+/// browser's downloads
+#[utoipa::path(
+    post,
+    path = "/login",
+    tag = "login",
+    request_body(content = Option<LoginForm>, content_type = "application/x-www-form-urlencoded"),
+    responses(
+        (status = 200, body = LoginStatus, description = "Logged in, the cookie in `Set-Cookie`"),
+        (status = 303, description = "Logged in from a form, sent back to `/`"),
+    ),
+)]
+async fn login(
+    State(state): State<Arc<AppState>>,
+    identity: Option<Extension<tls::ClientIdentity>>,
+    method: Method,
+    uri: Uri,
+    mut headers: HeaderMap,
+    form: Option<Form<LoginForm>>,
+) -> Result<Response, AppError> {
+    let auth = state.auth.as_ref().ok_or_else(|| {
+        AppError::NotFound("Logins need authentication (AUTH_PROVIDERS)".to_string())
+    })?;
+    if let Some(key) = form.and_then(|Form(form)| form.api_key) {
+        let key = header::HeaderValue::from_str(key.trim())
+            .map_err(|_| AppError::BadRequest("Invalid API key".to_string()))?;
+        headers.insert(auth::API_KEY_HEADER, key);
+    }
+    let credentials = auth::Credentials {
+        method: &method,
+        uri: &uri,
+        headers: &headers,
+        client_cert: identity.as_ref().map(|Extension(identity)| &*identity.0),
+    };
+    let (token, grant) = auth
+        .login(&credentials)
+        .await
+        .inspect_err(|e| warn!("Refused a login: {}", e.message()))?;
+    let ttl = auth.login_ttl();
+    let response = match posted_by_form(&headers) {
+        true => (StatusCode::SEE_OTHER, [(header::LOCATION, "/")]).into_response(),
+        false => Json(LoginStatus {
+            client: grant.name.to_string(),
+            expires_in: ttl.as_secs(),
+        })
+        .into_response(),
+    };
+    Ok(with_login_cookie(response, &headers, &token, ttl))
+}
+
+/// End the login of the request's cookie
+#[utoipa::path(
+    post,
+    path = "/logout",
+    tag = "login",
+    responses(
+        (status = 204, description = "Logged out, the cookie cleared"),
+        (status = 303, description = "Logged out from a form, sent back to `/`"),
+    ),
+)]
+async fn logout(State(state): State<Arc<AppState>>, headers: HeaderMap) -> Response {
+    if let Some(auth) = &state.auth {
+        auth.logout(&headers);
+    }
+    let response = match posted_by_form(&headers) {
+        true => (StatusCode::SEE_OTHER, [(header::LOCATION, "/")]).into_response(),
+        false => StatusCode::NO_CONTENT.into_response(),
+    };
+    with_login_cookie(response, &headers, "", Duration::ZERO)
+}
+
+/// Upstream requests made for a client request
+async fn upstream_trace(
+    State(state): State<Arc<AppState>>,
+    Path(request_id): Path<String>,
+) -> Result<Json<TraceReport>, AppError> {
+    state.traces.get(&request_id).map(Json)
+}
+
+/// The runtime limits, if the request's client is an admin
+fn admin_tenants<'a>(
+    state: &'a AppState,
+    grant: &Option<Extension<Grant>>,
+) -> Result<&'a Tenants, AppError> {
+    let auth = state.auth.as_ref().ok_or_else(|| {
+        AppError::NotFound("Client limits need authentication (AUTH_PROVIDERS)".to_string())
+    })?;
+    match grant {
+        Some(Extension(grant)) => grant.check_admin()?,
+        None => {
+            return Err(AppError::Forbidden(
+                "Only admins may change client limits".to_string(),
+            ))
+        }
+    }
+    Ok(auth.tenants())
+}
+
+/// Downloads and jobs still running, and the drain time left
+async fn drain_status(State(state): State<Arc<AppState>>) -> Json<DrainStatus> {
+    let remaining = state.shutdown.remaining();
+    Json(DrainStatus {
+        draining: remaining.is_some(),
+        drain_secs: state.shutdown.drain().as_secs(),
+        remaining_secs: remaining.map(|r| r.as_secs()),
+        transfers: state.in_flight.list(),
+        jobs: state.progress.running(),
+    })
+}
+
+/// Every client with limits set at runtime
+async fn admin_limits(
+    State(state): State<Arc<AppState>>,
+    grant: Option<Extension<Grant>>,
+) -> Result<Json<BTreeMap<String, TenantStatus>>, AppError> {
+    Ok(Json(admin_tenants(&state, &grant)?.all()))
+}
+
+/// One client's limits set at runtime, and what it used today
+async fn admin_limits_get(
+    State(state): State<Arc<AppState>>,
+    grant: Option<Extension<Grant>>,
+    Path(client): Path<String>,
+) -> Result<Json<TenantStatus>, AppError> {
+    Ok(Json(admin_tenants(&state, &grant)?.status(&client)))
+}
+
+/// Replace a client's limits, effective on its next request
+async fn admin_limits_set(
+    State(state): State<Arc<AppState>>,
+    grant: Option<Extension<Grant>>,
+    Path(client): Path<String>,
+    limits: Result<Json<TenantLimits>, JsonRejection>,
+) -> Result<Json<TenantStatus>, AppError> {
+    let Json(limits) = limits.map_err(|e| AppError::BadRequest(e.body_text()))?;
+    let tenants = admin_tenants(&state, &grant)?;
+    tenants.set(&client, limits.clone())?;
+    info!(
+        "Limits of '{}' set by {}: {}",
+        client,
+        identity(&grant).unwrap_or("an unknown client"),
+        serde_json::to_string(&limits).unwrap_or_default()
+    );
+    Ok(Json(tenants.status(&client)))
+}
+
+/// Drop a client's runtime limits, back to those of its grant
+async fn admin_limits_remove(
+    State(state): State<Arc<AppState>>,
+    grant: Option<Extension<Grant>>,
+    Path(client): Path<String>,
+) -> Result<StatusCode, AppError> {
+    if !admin_tenants(&state, &grant)?.remove(&client)? {
+        return Err(AppError::NotFound(format!(
+            "'{}' has no limits set",
+            client
+        )));
+    }
+    info!(
+        "Limits of '{}' dropped by {}",
+        client,
+        identity(&grant).unwrap_or("an unknown client")
+    );
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Pick the filename template for a request: the client's override, if any
+fn request_template(state: &AppState, query: &DownloadQuery) -> Result<FilenameTemplate, AppError> {
+    match &query.filename_template {
+        Some(template) => {
+            FilenameTemplate::parse(template).map_err(|e| AppError::BadRequest(e.to_string()))
+        }
+        None => Ok(state.filename_template.clone()),
+    }
+}
+
+/// Download file by repository path, either `:owner/:repo/*file` (a model
+/// on `main`) or `:type/:owner/:repo/resolve/:revision/*file`
+#[utoipa::path(
+    get,
+    path = "/download/{owner}/{repo}/{file}",
+    tag = "downloads",
+    params(
+        ("owner" = String, Path),
+        ("repo" = String, Path),
+        ("file" = String, Path, description = "Path within the repository; `:type/:owner/:repo/resolve/:revision/*file` also works"),
+        ("Range" = Option<String>, Header, description = "Byte range, e.g. `bytes=0-1023`"),
+        ("X-Transfer-Mode" = Option<String>, Header, description = "`stream`, `redirect` or `spool`"),
+        ("X-Session-Id" = Option<String>, Header, description = "Session pinning the revision"),
+        DownloadQuery,
+    ),
+    responses(
+        (status = 200, description = "File content", content_type = "application/octet-stream"),
+        (status = 206, description = "Requested range", content_type = "application/octet-stream"),
+        (status = 307, description = "Redirect to the hub, with `X-Transfer-Mode: redirect`"),
+    ),
+    security((), ("hf_token" = [])),
+)]
+async fn download_by_path(
+    State(state): State<Arc<AppState>>,
+    method: Method,
+    headers: HeaderMap,
+    grant: Option<Extension<Grant>>,
+    Path((owner, repo, file)): Path<(String, String, String)>,
+    uri: Uri,
+    Query(query): Query<DownloadQuery>,
+) -> Result<Response, AppError> {
+    // The typed form is split from the still-encoded path so an encoded `/`
+    // in the revision stays part of it
+    let raw_rest = uri.path().splitn(4, '/').nth(3).unwrap_or_default();
+    let (repo, file) = match RepoRef::from_typed_path(&owner, raw_rest) {
+        Some(typed) => typed?,
+        None => (RepoRef::model(owner, repo), file),
+    };
+    serve_path(
+        state,
+        &method,
+        &headers,
+        identity(&grant),
+        repo,
+        file,
+        &query,
+    )
+    .await
+}
+
+/// Download the file an alias is pinned to, or list a bundle alias
+#[utoipa::path(
+    get,
+    path = "/models/{alias}",
+    tag = "downloads",
+    params(("alias" = String, Path), DownloadQuery),
+    responses((
+        status = 200,
+        description = "Content of the file the alias is pinned to, or the files of a bundle alias",
+        content(("application/octet-stream"), (BundleResponse = "application/json")),
+    )),
+    security((), ("hf_token" = [])),
+)]
+async fn alias_download(
+    State(state): State<Arc<AppState>>,
+    method: Method,
+    headers: HeaderMap,
+    grant: Option<Extension<Grant>>,
+    Path(name): Path<String>,
+    Query(query): Query<DownloadQuery>,
+) -> Result<Response, AppError> {
+    let alias = state
+        .aliases
+        .get(&name)
+        .cloned()
+        .ok_or_else(|| AppError::NotFound(format!("Unknown model alias '{}'", name)))?;
+    info!("Alias request: {} -> {}", name, alias.repo);
+    authorize(&grant, &alias.repo_ref())?;
+
+    match &alias.target {
+        AliasTarget::File { file } => {
+            serve_path(
+                state,
+                &method,
+                &headers,
+                identity(&grant),
+                alias.repo_ref(),
+                file.clone(),
+                &query,
+            )
+            .await
+        }
+        AliasTarget::Bundle { files } => {
+            let base_url = public_base_url(&headers);
+            let files = files
+                .iter()
+                .map(|path| BundleFile {
+                    url: format!("{}/models/{}/{}", base_url, name, encode_path(path)),
+                    path: path.clone(),
+                })
+                .collect();
+            Ok(Json(BundleResponse {
+                alias: name,
+                repo_id: alias.repo.clone(),
+                revision: alias.revision.clone(),
+                files,
+            })
+            .into_response())
+        }
+    }
+}
+
+/// Download one file of a bundle alias
+#[utoipa::path(
+    get,
+    path = "/models/{alias}/{file}",
+    tag = "downloads",
+    params(("alias" = String, Path), ("file" = String, Path), DownloadQuery),
+    responses((status = 200, description = "File content", content_type = "application/octet-stream")),
+    security((), ("hf_token" = [])),
+)]
+async fn alias_bundle_download(
+    State(state): State<Arc<AppState>>,
+    method: Method,
+    headers: HeaderMap,
+    grant: Option<Extension<Grant>>,
+    Path((name, file)): Path<(String, String)>,
+    Query(query): Query<DownloadQuery>,
+) -> Result<Response, AppError> {
+    let alias = state
+        .aliases
+        .get(&name)
+        .filter(|alias| alias.contains(&file))
+        .cloned()
+        .ok_or_else(|| {
+            AppError::NotFound(format!("'{}' is not part of model alias '{}'", file, name))
+        })?;
+    info!("Alias request: {}/{} -> {}", name, file, alias.repo);
+    authorize(&grant, &alias.repo_ref())?;
+
+    serve_path(
+        state,
+        &method,
+        &headers,
+        identity(&grant),
+        alias.repo_ref(),
+        file,
+        &query,
+    )
+    .await
+}
+
+/// Serve a repository file by path; `HEAD` resolves it without downloading
+async fn serve_path(
+    state: Arc<AppState>,
+    method: &Method,
+    headers: &HeaderMap,
+    identity: Option<&str>,
+    repo: RepoRef,
+    file: String,
+    query: &DownloadQuery,
+) -> Result<Response, AppError> {
+    info!(
+        "Download request: repo={}, revision={}, file={}",
+        repo, repo.revision, file
+    );
+    state.shedder.check()?;
+
+    // Extract token from Authorization header
+    let hf_token = extract_token(headers, state.fallback_token.as_deref())?;
+    let repo = session_repo(&state, headers, repo, &hf_token).await?;
+    let template = request_template(&state, query)?;
+    let mut options = RequestOptions::from_headers(headers, &state.override_limits)?;
+    options.refresh = query.refresh;
+
+    if options.mode == TransferMode::Redirect {
+        if let Some(head_cache) = &state.head_cache {
+            // Anything the head cache cannot serve is left to the upstream
+            let resolved = resolve_file(
+                &state, &hf_token, &repo, &file, &template, &options, identity,
+            )
+            .await;
+            match resolved {
+                Ok(resolved) if head_cache.covers(resolved.listed.size, resolved.range) => {
+                    return serve_head(
+                        &state, head_cache, method, &repo, &hf_token, resolved, &options,
+                    )
+                    .await;
+                }
+                Ok(_) => {}
+                // A file the policy denies isn't redirected to either
+                Err(e @ AppError::Forbidden(_)) => return Err(e),
+                Err(e) => debug!("Not serving {} from the head cache: {}", file, e.message()),
             }
         }
+        let location = repo.upstream_url(&encode_path(&file));
+        info!("Redirecting to upstream: {}", location);
+        return Ok(Response::builder()
+            .status(StatusCode::TEMPORARY_REDIRECT)
+            .header(header::LOCATION, location)
+            .header(TRANSFER_MODE_HEADER, TransferMode::Redirect.as_str())
+            .body(Body::empty())
+            .unwrap());
+    }
+
+    if method == Method::HEAD {
+        let resolved = resolve_file(
+            &state, &hf_token, &repo, &file, &template, &options, identity,
+        )
+        .await?;
+        let etag = conditional::etag(&resolved.listed.xet_hash);
+        if options.conditions.not_modified(method, &etag) {
+            return Ok(conditional::not_modified_response(&etag));
+        }
+        let length = resolved.range.map_or(resolved.listed.size, |r| r.len());
+        let response = file_response(&resolved.headers, &etag, Some(length), true, resolved.range);
+        return head_response(cached_head(&state, response, &resolved.headers).await);
+    }
+
+    let events = state.events.clone();
+    resolve_and_download(
+        state, method, hf_token, identity, repo, file, template, options,
+    )
+    .await
+    .inspect_err(|e| report_failure(&events, None, e))
+}
+
+/// Serve a request within a file's head from the head cache
+async fn serve_head(
+    state: &AppState,
+    head_cache: &HeadCache,
+    method: &Method,
+    repo: &RepoRef,
+    hf_token: &str,
+    resolved: ResolvedFile,
+    options: &RequestOptions,
+) -> Result<Response, AppError> {
+    let ResolvedFile {
+        listed,
+        range,
+        headers,
+    } = resolved;
+    let etag = conditional::etag(&listed.xet_hash);
+    if options.conditions.not_modified(method, &etag) {
+        return Ok(conditional::not_modified_response(&etag));
+    }
+    let length = range.map_or(listed.size, |r| r.len());
+    let response = file_response(&headers, &etag, Some(length), true, range)
+        .header("x-proxy-cache", "head")
+        .header(TRANSFER_MODE_HEADER, TransferMode::Stream.as_str());
+    if method == Method::HEAD {
+        return head_response(response);
     }
 
-    let hash = xet_hash.ok_or_else(|| {
+    let head = options
+        .run("Head fetch", || {
+            head_cache.get(
+                state.downloader.as_ref(),
+                repo,
+                &listed.xet_hash,
+                listed.size,
+                hf_token,
+                options.deadline,
+            )
+        })
+        .await?;
+    let body = match range {
+        Some(range) => head.slice(range.start as usize..=range.end as usize),
+        None => head,
+    };
+    response
+        .body(Body::from(body))
+        .map_err(|e| AppError::Internal(format!("Failed to build response: {}", e)))
+}
+
+/// A repository file resolved for serving
+struct ResolvedFile {
+    listed: ListedFile,
+    range: Option<ByteRange>,
+    headers: FileHeaders,
+}
+
+/// How a file response presents the file
+struct FileHeaders {
+    /// Content-Disposition filename
+    filename: String,
+    content_type: &'static str,
+    /// XET hash of the content, sent as `X-Xet-Hash`
+    hash: String,
+    /// Revision the file was resolved at, sent as `X-Repo-Revision` when known
+    revision: Option<String>,
+    /// The URL always names this content (hash URLs), so clients may keep it
+    immutable: bool,
+    /// Where the content was listed, if known, for resumption tokens
+    listing: Option<CatalogEntry>,
+    /// The listing naming the content was fetched from the Hub for this
+    /// request, so a cached copy counts as revalidated
+    revalidated: bool,
+}
+
+/// Look a repository path up in the listing, check it against the policy,
+/// and resolve its range and filename
+async fn resolve_file(
+    state: &AppState,
+    hf_token: &str,
+    repo: &RepoRef,
+    file: &str,
+    template: &FilenameTemplate,
+    options: &RequestOptions,
+    identity: Option<&str>,
+) -> Result<ResolvedFile, AppError> {
+    // First, list files to get the XET hash
+    let (files, revalidated) = list_repo_fresh(state, options, repo, hf_token).await?;
+
+    // Look for the file in the listing, by its exact path
+    let file = file.trim_start_matches('/');
+    let listed = files.into_iter().find(|f| f.path == file).ok_or_else(|| {
         AppError::NotFound(format!("File '{}' not found or not XET-enabled", file))
     })?;
 
-    info!("Found XET hash for {}: {}", file, hash);
+    info!("Found XET hash for {}: {}", file, listed.xet_hash);
+    check_policy(
+        state,
+        Access {
+            identity,
+            repo: Some(repo),
+            file: Some(&listed.path),
+            hash: &listed.xet_hash,
+            size: Some(listed.size),
+        },
+    )
+    .await?;
+
+    // A Range conditional on another version yields the whole file
+    let range = options
+        .range
+        .filter(|_| {
+            options
+                .conditions
+                .range_applies(&conditional::etag(&listed.xet_hash))
+        })
+        .map(|spec| spec.resolve(listed.size))
+        .transpose()?;
+
+    let filename = template.render(&FileContext {
+        owner: Some(&repo.owner),
+        repo: Some(&repo.name),
+        revision: Some(&repo.revision),
+        path: Some(file),
+        hash: &listed.xet_hash,
+    });
+
+    let headers = FileHeaders {
+        filename,
+        content_type: catalog::content_type(&listed.path),
+        hash: listed.xet_hash.clone(),
+        revision: Some(repo.revision.clone()),
+        immutable: false,
+        listing: Some(CatalogEntry {
+            repo: repo.clone(),
+            path: listed.path.clone(),
+            size: listed.size,
+        }),
+        revalidated,
+    };
+    Ok(ResolvedFile {
+        listed,
+        range,
+        headers,
+    })
+}
+
+/// Resolve a repository path to its XET hash, then stream it
+#[allow(clippy::too_many_arguments)]
+async fn resolve_and_download(
+    state: Arc<AppState>,
+    method: &Method,
+    hf_token: String,
+    identity: Option<&str>,
+    repo: RepoRef,
+    file: String,
+    template: FilenameTemplate,
+    options: RequestOptions,
+) -> Result<Response, AppError> {
+    let resolved = resolve_file(
+        &state, &hf_token, &repo, &file, &template, &options, identity,
+    )
+    .await?;
+    let ResolvedFile {
+        listed,
+        range,
+        headers,
+    } = resolved;
+    let hash = listed.xet_hash;
+    let etag = conditional::etag(&hash);
+    if options.conditions.not_modified(method, &etag) {
+        return Ok(conditional::not_modified_response(&etag));
+    }
+
+    state.events.publish(EventKind::DownloadStarted {
+        hash: hash.clone(),
+        repo: Some(repo.to_string()),
+        file: Some(file),
+    });
+
+    let info = TransferInfo {
+        route: ROUTE_DOWNLOAD,
+        hash,
+        repo: repo.clone(),
+        path: Some(listed.path),
+        started: options.received_at.into_std(),
+        expected_size: Some(range.map_or(listed.size, |r| r.len())),
+        client: state.aborts.client_key(&hf_token),
+        identity: identity.map(str::to_string),
+        offset: range.map_or(0, |r| r.start),
+        ranged: range.is_some(),
+    };
 
     // Now download by hash
-    download_by_hash_impl(state, hash, hf_token).await
+    download_by_hash_impl(state, &repo, info, hf_token, headers, options, range).await
+}
+
+/// Where a hash was listed: from the catalog, or else from the listing of
+/// the repository authorizing it, so a hash URL keeps its size (and with it
+/// `Range` support) across restarts
+async fn known_hash(
+    state: &AppState,
+    options: &RequestOptions,
+    repo: &RepoRef,
+    hash: &str,
+    hf_token: &str,
+) -> Option<CatalogEntry> {
+    if let Some(known) = state.catalog.get(hash) {
+        return Some(known);
+    }
+    match list_repo(state, options, repo, hf_token).await {
+        Ok(files) => files
+            .into_iter()
+            .find(|f| f.xet_hash == hash)
+            .map(|f| CatalogEntry {
+                repo: repo.clone(),
+                path: f.path,
+                size: f.size,
+            }),
+        Err(e) => {
+            debug!("Could not look {} up in {}: {}", hash, repo, e.message());
+            None
+        }
+    }
 }
 
 /// Download file by XET hash
+#[utoipa::path(
+    get,
+    path = "/download-hash/{hash}",
+    tag = "downloads",
+    params(
+        ("hash" = String, Path, description = "XET hash"),
+        ("Range" = Option<String>, Header, description = "Byte range, e.g. `bytes=0-1023`"),
+        ("X-Transfer-Mode" = Option<String>, Header, description = "`stream` or `spool`"),
+        DownloadQuery,
+    ),
+    responses(
+        (status = 200, description = "File content", content_type = "application/octet-stream"),
+        (status = 206, description = "Requested range", content_type = "application/octet-stream"),
+    ),
+    security((), ("hf_token" = [])),
+)]
 async fn download_by_hash(
     State(state): State<Arc<AppState>>,
+    method: Method,
     headers: HeaderMap,
+    grant: Option<Extension<Grant>>,
     Path(hash): Path<String>,
+    Query(query): Query<DownloadQuery>,
 ) -> Result<Response, AppError> {
     info!("Download by hash: {}", hash);
 
-    // Validate hash format (64 hex characters)
-    if hash.len() != 64 || !hash.chars().all(|c| c.is_ascii_hexdigit()) {
-        return Err(AppError::BadRequest(
-            "Invalid XET hash format (expected 64 hex characters)".to_string(),
-        ));
-    }
+    let hash = XetHash::parse(&hash)?.into_string();
+    state.shedder.check()?;
 
     // Extract token from Authorization header
-    let hf_token = extract_token(&headers)?;
+    let hf_token = extract_token(&headers, state.fallback_token.as_deref())?;
+    let repo = hash_repo(&state, &query)?;
+    authorize(&grant, &repo)?;
+    let mut options = RequestOptions::from_headers(&headers, &state.override_limits)?;
+    options.refresh = query.refresh;
+
+    // The hash is the ETag, so a cached copy is confirmed without a lookup
+    let etag = conditional::etag(&hash);
+    if options.conditions.not_modified(&method, &etag) {
+        return Ok(conditional::not_modified_response(&etag));
+    }
+
+    // A hash seen in a listing is presented like the file it was listed as;
+    // a resumption token from another replica stands in for its catalog
+    let resumed = state
+        .aborts
+        .verify(options.resume_token.as_deref(), &hf_token, &hash);
+    let known = match resumed.as_ref().and_then(ResumeClaims::listing) {
+        Some(listed) if state.catalog.get(&hash).is_none() => Some(listed),
+        _ => known_hash(&state, &options, &repo, &hash, &hf_token).await,
+    };
+    let filename = request_template(&state, &query)?.render(&FileContext {
+        owner: known.as_ref().map(|k| k.repo.owner.as_str()),
+        repo: known.as_ref().map(|k| k.repo.name.as_str()),
+        revision: known.as_ref().map(|k| k.repo.revision.as_str()),
+        path: known.as_ref().map(|k| k.path.as_str()),
+        hash: &hash,
+    });
+    let file_headers = FileHeaders {
+        filename,
+        content_type: known.as_ref().map_or("application/octet-stream", |k| {
+            catalog::content_type(&k.path)
+        }),
+        hash: hash.clone(),
+        revision: known.as_ref().map(|k| k.repo.revision.clone()),
+        immutable: true,
+        listing: known.clone(),
+        revalidated: false,
+    };
+
+    // Ranges need the size; without one the file is only served whole
+    let size = match (&known, &resumed) {
+        (Some(known), _) => Some(known.size),
+        (None, Some(resumed)) => Some(resumed.size),
+        (None, None) => {
+            options
+                .run("Size lookup", || {
+                    state.downloader.file_size(&repo, &hash, &hf_token)
+                })
+                .await?
+        }
+    };
+    check_policy(
+        &state,
+        Access {
+            identity: identity(&grant),
+            repo: Some(known.as_ref().map_or(&repo, |k| &k.repo)),
+            file: known.as_ref().map(|k| k.path.as_str()),
+            hash: &hash,
+            size,
+        },
+    )
+    .await?;
+    let range = match size {
+        Some(size) => options
+            .range
+            .filter(|_| options.conditions.range_applies(&etag))
+            .map(|spec| spec.resolve(size))
+            .transpose()?,
+        None => None,
+    };
+
+    if method == Method::HEAD {
+        let length = size.map(|size| range.map_or(size, |r| r.len()));
+        let response = file_response(&file_headers, &etag, length, size.is_some(), range);
+        return head_response(cached_head(&state, response, &file_headers).await);
+    }
+
+    state.events.publish(EventKind::DownloadStarted {
+        hash: hash.clone(),
+        repo: None,
+        file: None,
+    });
 
-    download_by_hash_impl(state, hash, hf_token).await
+    let events = state.events.clone();
+    let failed_hash = hash.clone();
+    let info = TransferInfo {
+        route: ROUTE_DOWNLOAD_HASH,
+        hash,
+        repo: repo.clone(),
+        path: None,
+        started: options.received_at.into_std(),
+        expected_size: size.map(|size| range.map_or(size, |r| r.len())),
+        client: state.aborts.client_key(&hf_token),
+        identity: identity(&grant).map(str::to_string),
+        offset: range.map_or(0, |r| r.start),
+        ranged: range.is_some(),
+    };
+    download_by_hash_impl(state, &repo, info, hf_token, file_headers, options, range)
+        .await
+        .inspect_err(|e| report_failure(&events, Some(failed_hash), e))
+}
+
+/// Chunk hashes and lengths of a file by XET hash
+#[utoipa::path(
+    get,
+    path = "/manifest/{hash}",
+    tag = "downloads",
+    params(("hash" = String, Path, description = "XET hash"), DownloadQuery),
+    responses((status = 200, body = ManifestResponse)),
+    security((), ("hf_token" = [])),
+)]
+async fn chunk_manifest(
+    State(state): State<Arc<AppState>>,
+    method: Method,
+    headers: HeaderMap,
+    grant: Option<Extension<Grant>>,
+    Path(hash): Path<String>,
+    Query(query): Query<DownloadQuery>,
+) -> Result<Response, AppError> {
+    info!("Manifest request: {}", hash);
+    let hash = XetHash::parse(&hash)?.into_string();
+    state.shedder.check()?;
+
+    let hf_token = extract_token(&headers, state.fallback_token.as_deref())?;
+    let repo = hash_repo(&state, &query)?;
+    authorize(&grant, &repo)?;
+    let options = RequestOptions::from_headers(&headers, &state.override_limits)?;
+
+    // A manifest changes only with the content, so it shares the file's ETag
+    let etag = conditional::etag(&hash);
+    if options.conditions.not_modified(&method, &etag) {
+        return Ok(conditional::not_modified_response(&etag));
+    }
+
+    let known = state.catalog.get(&hash);
+    check_policy(
+        &state,
+        Access {
+            identity: identity(&grant),
+            repo: Some(known.as_ref().map_or(&repo, |k| &k.repo)),
+            file: known.as_ref().map(|k| k.path.as_str()),
+            hash: &hash,
+            size: known.as_ref().map(|k| k.size),
+        },
+    )
+    .await?;
+
+    let manifest = options.run("Chunk manifest", || {
+        state.downloader.manifest(&repo, &hash, &hf_token)
+    });
+    let chunks = state.backoff.guard(&repo.to_string(), manifest).await?;
+    let size = chunks.last().map_or(0, |chunk| chunk.offset + chunk.length);
+    Ok((
+        [(header::ETAG, etag)],
+        Json(ManifestResponse { hash, size, chunks }),
+    )
+        .into_response())
+}
+
+/// Publish a `download_failed` event for an error returned before streaming
+fn report_failure(events: &EventBus, hash: Option<String>, err: &AppError) {
+    events.publish(EventKind::DownloadFailed {
+        hash,
+        error: err.message().to_string(),
+    });
 }
 
 /// Internal implementation of hash-based download
 async fn download_by_hash_impl(
     state: Arc<AppState>,
-    hash: String,
+    repo: &RepoRef,
+    info: TransferInfo,
     hf_token: String,
+    file_headers: FileHeaders,
+    options: RequestOptions,
+    range: Option<ByteRange>,
 ) -> Result<Response, AppError> {
-    // Spawn the Zig CLI process to download the file
-    // We'll use a temporary repo for the token, but download by hash directly
-    let mut child = Command::new(&state.zig_bin_path)
-        .arg("jedisct1/MiMo-7B-RL-GGUF") // Temporary repo for token
-        .arg(&hash) // Pass hash as second argument
-        .env("HF_TOKEN", &hf_token)
-        .stdout(std::process::Stdio::piped())
-        .stderr(std::process::Stdio::piped())
-        .spawn()
-        .map_err(|e| AppError::Internal(format!("Failed to spawn zig process: {}", e)))?;
-
-    let stdout = child
-        .stdout
-        .take()
-        .ok_or_else(|| AppError::Internal("Failed to capture stdout".to_string()))?;
-
-    let stderr = child
-        .stderr
-        .take()
-        .ok_or_else(|| AppError::Internal("Failed to capture stderr".to_string()))?;
-
-    // Log stderr in the background
-    tokio::spawn(async move {
-        let reader = BufReader::new(stderr);
-        let mut lines = reader.lines();
-        while let Ok(Some(line)) = lines.next_line().await {
-            info!("zig stderr: {}", line);
+    let mut info = info;
+    let resumed = state
+        .aborts
+        .verify(options.resume_token.as_deref(), &hf_token, &info.hash);
+    let priority = state
+        .aborts
+        .priority(info.client, &info.hash, range, resumed.as_ref());
+    if priority == Priority::Resumed {
+        info!(
+            "Resuming an interrupted transfer of {}, prioritized",
+            info.hash
+        );
+    }
+    // Several ranges are only served from the cache; otherwise, the whole file
+    let etag = conditional::etag(&info.hash);
+    let ranges = match info.expected_size.filter(|_| range.is_none()) {
+        Some(size) if !options.ranges.is_empty() && options.conditions.range_applies(&etag) => {
+            let ranges = range::resolve_all(&options.ranges, size)?;
+            let cached = match &state.cache {
+                Some(cache) => cache.lookup(&info.hash).await == Some(size),
+                None => false,
+            };
+            cached.then_some(ranges)
         }
-    });
+        _ => None,
+    };
+    let slot = download_slot(&state, priority, &options).await?;
+    if let Some(ranges) = ranges {
+        let parts = Parts {
+            ranges,
+            priority,
+            slot,
+        };
+        return serve_ranges(
+            &state,
+            repo,
+            info,
+            &hf_token,
+            &file_headers,
+            &options,
+            parts,
+        )
+        .await;
+    }
+    // Redirects are for path downloads, which don't get here
+    let mode = match options.mode {
+        TransferMode::Spool => TransferMode::Spool,
+        _ => TransferMode::Stream,
+    };
+    let spool = mode == TransferMode::Spool;
+    let download = options
+        .run("Download startup", || {
+            state.downloader.download(DownloadRequest {
+                repo,
+                hash: &info.hash,
+                hf_token: &hf_token,
+                range,
+                length: info.expected_size,
+                deadline: options.deadline,
+                priority,
+                spool,
+            })
+        })
+        .await?;
+    let source = Source::of(&download);
+    // An old cached copy is checked against its path upstream
+    let verdict = match (&state.reverify, download.cached, &file_headers.listing) {
+        (Some(reverify), Some(fetched), Some(listing))
+            if !file_headers.revalidated && reverify.due(fetched) =>
+        {
+            Some(reverify_cached(&state, reverify, &options, &hf_token, &info.hash, listing).await)
+        }
+        _ => None,
+    };
 
-    // Create streaming response from stdout
-    let stream = ReaderStream::new(stdout);
+    // Create streaming response from the upstream bytes
+    let observers = transfer_observers(&state);
+    // Ranges can only be resolved when the size is known from a listing
+    let accept_ranges = info.expected_size.is_some();
+    info.expected_size = info.expected_size.or(download.length);
+    let mut response = file_response(
+        &file_headers,
+        &etag,
+        info.expected_size,
+        accept_ranges,
+        range,
+    )
+    .header(TRANSFER_MODE_HEADER, mode.as_str());
+    if state.cache.is_some() {
+        let revalidated = file_headers.revalidated || verdict == Some(Verdict::Current);
+        response = cache_headers(response, download.cached, revalidated);
+    }
+    if let Some(verdict) = &verdict {
+        response = response.header("x-cache-upstream", verdict.as_str());
+        if let Verdict::Moved(hash) = verdict {
+            response = response.header("x-upstream-xet-hash", hash);
+        }
+    }
+    // Any replica can continue a transfer that supports ranges
+    let size = range.map_or(info.expected_size, |r| Some(r.size));
+    if let Some(size) = size.filter(|_| accept_ranges) {
+        let listing = file_headers.listing.as_ref();
+        let token = state.aborts.issue(
+            &hf_token,
+            &info.hash,
+            listing.map_or(repo, |l| &l.repo),
+            listing.map(|l| l.path.as_str()),
+            size,
+            info.offset,
+        );
+        if let Some(token) = token {
+            response = response.header("x-resume-token", token);
+        }
+    }
+    // The file hash covers the whole file: ranges can't be verified
+    let body = match (state.verify, range) {
+        (VerifyMode::Off, _) | (_, Some(_)) => download.body,
+        (mode, None) => Box::pin(Verified::new(
+            download.body,
+            &info.hash,
+            mode,
+            state.metrics.clone(),
+        )),
+    };
+    let body = match slot {
+        Some(slot) => Box::pin(Holding::new(body, slot)),
+        None => body,
+    };
+    let stream = TransferStream::new(body, observers, info, source, download.upstream_bytes);
     let body = Body::from_stream(stream);
 
-    let response = Response::builder()
+    let response = response
+        .body(body)
+        .map_err(|e| AppError::Internal(format!("Failed to build response: {}", e)))?;
+
+    Ok(response)
+}
+
+/// Where the outcome of transfers is reported
+fn transfer_observers(state: &AppState) -> TransferObservers {
+    TransferObservers {
+        events: state.events.clone(),
+        slo: state.slo.clone(),
+        drift_total: state.transfer_drift.clone(),
+        metrics: state.metrics.clone(),
+        aborts: state.aborts.clone(),
+        in_flight: state.in_flight.clone(),
+    }
+}
+
+/// Ranges of a cached file to serve together
+struct Parts {
+    ranges: Vec<ByteRange>,
+    priority: Priority,
+    slot: Option<Slot>,
+}
+
+/// Serve several ranges of a cached file as one `multipart/byteranges`
+/// response; every part is opened before answering, so they are all read
+/// from the same copy
+async fn serve_ranges(
+    state: &AppState,
+    repo: &RepoRef,
+    mut info: TransferInfo,
+    hf_token: &str,
+    file_headers: &FileHeaders,
+    options: &RequestOptions,
+    parts: Parts,
+) -> Result<Response, AppError> {
+    let multipart = Multipart::new(parts.ranges, file_headers.content_type);
+    let mut bodies = Vec::with_capacity(multipart.ranges().len());
+    let (mut fetched, mut source) = (None, None);
+    for range in multipart.ranges() {
+        let download = options
+            .run("Download startup", || {
+                state.downloader.download(DownloadRequest {
+                    repo,
+                    hash: &info.hash,
+                    hf_token,
+                    range: Some(*range),
+                    length: Some(range.len()),
+                    deadline: options.deadline,
+                    priority: parts.priority,
+                    spool: false,
+                })
+            })
+            .await?;
+        fetched = fetched.or(download.cached);
+        source = source.or(Some(Source::of(&download)));
+        bodies.push(download.body);
+    }
+    let length = multipart.len();
+    let sent = Arc::new(AtomicU64::new(0));
+    let body = multipart.body(bodies, sent.clone());
+    info.expected_size = Some(length);
+    info.ranged = true;
+
+    let etag = conditional::etag(&info.hash);
+    let mut response = file_response(file_headers, &etag, Some(length), true, None)
+        .status(StatusCode::PARTIAL_CONTENT)
+        .header(TRANSFER_MODE_HEADER, TransferMode::Stream.as_str());
+    // Each part carries the file's own type
+    let content_type = header::HeaderValue::from_str(&multipart.content_type());
+    if let (Some(headers), Ok(value)) = (response.headers_mut(), content_type) {
+        headers.insert(header::CONTENT_TYPE, value);
+    }
+    response = cache_headers(response, fetched, file_headers.revalidated);
+    let body = match parts.slot {
+        Some(slot) => Box::pin(Holding::new(body, slot)),
+        None => body,
+    };
+    let source = source.unwrap_or(Source::Cache);
+    let stream = TransferStream::new(body, transfer_observers(state), info, source, sent);
+    response
+        .body(Body::from_stream(stream))
+        .map_err(|e| AppError::Internal(format!("Failed to build response: {}", e)))
+}
+
+/// Slot for a streamed download, if downloads are limited
+async fn download_slot(
+    state: &AppState,
+    priority: Priority,
+    options: &RequestOptions,
+) -> Result<Option<Slot>, AppError> {
+    match &state.limiter {
+        Some(limiter) => Ok(Some(limiter.acquire(priority, options.deadline).await?)),
+        None => Ok(None),
+    }
+}
+
+/// Status and headers of a file response; shared by `GET` and `HEAD`
+fn file_response(
+    file_headers: &FileHeaders,
+    etag: &str,
+    content_length: Option<u64>,
+    accept_ranges: bool,
+    range: Option<ByteRange>,
+) -> response::Builder {
+    let mut response = Response::builder()
         .status(StatusCode::OK)
-        .header(header::CONTENT_TYPE, "application/octet-stream")
+        .header(header::CONTENT_TYPE, file_headers.content_type)
         .header(
             header::CONTENT_DISPOSITION,
-            format!("attachment; filename=\"{}.bin\"", &hash[..8]),
+            filename::content_disposition(&file_headers.filename),
         )
-        .body(body)
-        .map_err(|e| AppError::Internal(format!("Failed to build response: {}", e)))?;
+        .header(header::ETAG, etag)
+        .header("x-xet-hash", &file_headers.hash)
+        .header(
+            header::ACCEPT_RANGES,
+            if accept_ranges { "bytes" } else { "none" },
+        );
+    if let Some(revision) = &file_headers.revision {
+        response = response.header("x-repo-revision", revision);
+    }
+    if file_headers.immutable {
+        response = response.header(
+            header::CACHE_CONTROL,
+            "private, max-age=31536000, immutable",
+        );
+    }
+    if let Some(length) = content_length {
+        response = response.header(header::CONTENT_LENGTH, length);
+    }
+    if let Some(range) = range {
+        response = response
+            .status(StatusCode::PARTIAL_CONTENT)
+            .header(header::CONTENT_RANGE, range.content_range());
+    }
+    response
+}
 
-    Ok(response)
+/// Tell how a file response relates to the cache: `X-Cache` is `HIT` when
+/// served from it, `REVALIDATED` when also checked against a fresh listing,
+/// and `MISS` when fetched upstream; served copies carry their `Age` and
+/// the time they were fetched, `X-Cache-Fetched-At`
+fn cache_headers(
+    mut response: response::Builder,
+    fetched: Option<SystemTime>,
+    revalidated: bool,
+) -> response::Builder {
+    let status = match fetched {
+        Some(_) if revalidated => "REVALIDATED",
+        Some(_) => "HIT",
+        None => "MISS",
+    };
+    response = response.header("x-cache", status);
+    if let Some(fetched) = fetched {
+        let age = SystemTime::now()
+            .duration_since(fetched)
+            .unwrap_or_default();
+        response = response
+            .header(header::AGE, age.as_secs())
+            .header("x-cache-fetched-at", httpdate::fmt_http_date(fetched));
+    }
+    response
+}
+
+/// Whether `listing` still names the cached `hash` upstream, from a fresh
+/// listing unless it was checked lately; the Hub not answering in time
+/// leaves it unverified
+async fn reverify_cached(
+    state: &AppState,
+    reverify: &Reverify,
+    options: &RequestOptions,
+    hf_token: &str,
+    hash: &str,
+    listing: &CatalogEntry,
+) -> Verdict {
+    if let Some(verdict) = reverify.known(hash, listing) {
+        return verdict;
+    }
+    let mut fresh = options.clone();
+    fresh.refresh = true;
+    let listed = tokio::time::timeout(
+        REVERIFY_TIMEOUT,
+        list_repo(state, &fresh, &listing.repo, hf_token),
+    )
+    .await;
+    let verdict = match listed {
+        Ok(Ok(files)) => Verdict::of(&files, listing, hash),
+        Ok(Err(e)) => {
+            warn!(
+                "Could not re-verify {} against {}: {}",
+                hash,
+                listing.repo,
+                e.message()
+            );
+            Verdict::Unverified
+        }
+        Err(_) => {
+            warn!(
+                "Could not re-verify {} against {}: timed out",
+                hash, listing.repo
+            );
+            Verdict::Unverified
+        }
+    };
+    if let Verdict::Moved(_) | Verdict::Removed = verdict {
+        info!(
+            "Cached {} is {} upstream as {}@{}:{}",
+            hash,
+            verdict.as_str(),
+            listing.repo,
+            listing.repo.revision,
+            listing.path
+        );
+    }
+    reverify.record(hash, listing, verdict.clone());
+    verdict
+}
+
+/// [`cache_headers`] of a `HEAD` response, as a `GET` would be served now
+async fn cached_head(
+    state: &AppState,
+    response: response::Builder,
+    file_headers: &FileHeaders,
+) -> response::Builder {
+    match &state.cache {
+        Some(cache) => {
+            let fetched = cache.fetched(&file_headers.hash).await;
+            cache_headers(response, fetched, file_headers.revalidated)
+        }
+        None => response,
+    }
+}
+
+/// Finish a `HEAD` response. The body is empty but of unknown size, so an
+/// unknown file size is not reported as `Content-Length: 0`.
+fn head_response(response: response::Builder) -> Result<Response, AppError> {
+    response
+        .body(Body::from_stream(tokio_stream::empty::<
+            Result<Bytes, std::io::Error>,
+        >()))
+        .map_err(|e| AppError::Internal(format!("Failed to build response: {}", e)))
 }
 
 /// Application error types
@@ -321,20 +4667,98 @@ enum AppError {
     BadRequest(String),
     NotFound(String),
     Unauthorized(String),
+    Forbidden(String),
+    RateLimited {
+        message: String,
+        retry_after: Option<Duration>,
+    },
+    Timeout(String),
+    /// Temporarily refusing work, e.g. while shedding load
+    Unavailable {
+        message: String,
+        retry_after: Option<Duration>,
+    },
+    /// Requested range lies outside a file of `size` bytes
+    RangeNotSatisfiable {
+        size: u64,
+    },
+    /// The request conflicts with the current state upstream
+    Conflict(String),
+    /// Not supported by the configured engine
+    NotImplemented(String),
     Internal(String),
 }
 
+impl AppError {
+    fn message(&self) -> &str {
+        match self {
+            AppError::RangeNotSatisfiable { .. } => "Requested range not satisfiable",
+            AppError::BadRequest(msg)
+            | AppError::NotFound(msg)
+            | AppError::Unauthorized(msg)
+            | AppError::Forbidden(msg)
+            | AppError::Timeout(msg)
+            | AppError::Conflict(msg)
+            | AppError::NotImplemented(msg)
+            | AppError::Internal(msg) => msg,
+            AppError::RateLimited { message, .. } | AppError::Unavailable { message, .. } => {
+                message
+            }
+        }
+    }
+}
+
 impl IntoResponse for AppError {
     fn into_response(self) -> Response {
+        let mut retry_after = None;
+        let mut content_range = None;
         let (status, message) = match self {
             AppError::BadRequest(msg) => (StatusCode::BAD_REQUEST, msg),
             AppError::NotFound(msg) => (StatusCode::NOT_FOUND, msg),
             AppError::Unauthorized(msg) => (StatusCode::UNAUTHORIZED, msg),
+            AppError::Forbidden(msg) => (StatusCode::FORBIDDEN, msg),
+            AppError::RateLimited {
+                message,
+                retry_after: wait,
+            } => {
+                retry_after = wait;
+                (StatusCode::TOO_MANY_REQUESTS, message)
+            }
+            AppError::Timeout(msg) => (StatusCode::GATEWAY_TIMEOUT, msg),
+            AppError::Unavailable {
+                message,
+                retry_after: wait,
+            } => {
+                retry_after = wait;
+                (StatusCode::SERVICE_UNAVAILABLE, message)
+            }
+            AppError::RangeNotSatisfiable { size } => {
+                content_range = Some(format!("bytes */{}", size));
+                (
+                    StatusCode::RANGE_NOT_SATISFIABLE,
+                    "Requested range not satisfiable".to_string(),
+                )
+            }
+            AppError::Conflict(msg) => (StatusCode::CONFLICT, msg),
+            AppError::NotImplemented(msg) => (StatusCode::NOT_IMPLEMENTED, msg),
             AppError::Internal(msg) => (StatusCode::INTERNAL_SERVER_ERROR, msg),
         };
 
         let body = Json(ErrorResponse { error: message });
 
-        (status, body).into_response()
+        let mut response = (status, body).into_response();
+        if let Some(wait) = retry_after {
+            // Round up so clients never retry before the cooldown ends
+            let secs = wait.as_secs() + u64::from(wait.subsec_nanos() > 0);
+            response
+                .headers_mut()
+                .insert(header::RETRY_AFTER, secs.into());
+        }
+        if let Some(value) = content_range {
+            response
+                .headers_mut()
+                .insert(header::CONTENT_RANGE, value.parse().unwrap());
+        }
+        response
     }
 }
@@ -0,0 +1,86 @@
+//! Chunk manifests
+//!
+//! `xet-download --json manifest <repo_id> <hash>` reconstructs a file and
+//! prints a `chunk` message per chunk, in file order:
+//!
+//! ```text
+//! {"type":"chunk","hash":"<chunk_hash>","length":<length>}
+//! ```
+//!
+//! A chunk hash is the keyed BLAKE3 hash XET uses to address chunks, so
+//! a client can check each chunk of a streamed download as soon as its bytes
+//! have arrived. It can also tie the manifest itself to the file: the file
+//! hash is the XET merkle root over the same chunk hashes and lengths.
+
+use crate::backoff::RATE_LIMITED_SIGNATURE;
+use crate::downloader::signature_error;
+use crate::protocol::{self, Message};
+use crate::repo::RepoRef;
+use crate::subprocess::{self, Cli};
+use crate::AppError;
+use serde::Serialize;
+
+/// One chunk of a file
+#[derive(Debug, Serialize, utoipa::ToSchema)]
+pub struct ManifestChunk {
+    pub hash: String,
+    /// Position of the chunk's first byte in the file
+    pub offset: u64,
+    pub length: u64,
+}
+
+/// Compute the chunk manifest of a file by XET hash
+pub async fn chunk_manifest(
+    cli: &Cli,
+    repo: &RepoRef,
+    hash: &str,
+    hf_token: &str,
+) -> Result<Vec<ManifestChunk>, AppError> {
+    let output = cli
+        .command()
+        .arg("manifest")
+        .arg(repo.id()) // Repository for the CAS token
+        .arg(hash)
+        .env("HF_TOKEN", hf_token)
+        .env("HF_REPO_TYPE", repo.repo_type.as_str())
+        .env("HF_REVISION", repo.revision_segment())
+        // Timeouts drop this future; don't leave the child running
+        .kill_on_drop(true)
+        .output()
+        .await
+        .map_err(|e| AppError::Internal(format!("Failed to execute zig binary: {}", e)))?;
+
+    if !output.status.success() {
+        let tail = subprocess::tail_lines(&output.stderr);
+        let signature = cli.record_failure(&output.status, &tail);
+        if signature == RATE_LIMITED_SIGNATURE {
+            return Err(AppError::RateLimited {
+                message: format!("Upstream rate limited the manifest of {}", hash),
+                retry_after: None,
+            });
+        }
+        return Err(signature_error(&signature, &repo.to_string()));
+    }
+
+    parse_manifest(&String::from_utf8_lossy(&output.stdout))
+}
+
+/// Parse CLI manifest output, adding each chunk's offset
+fn parse_manifest(stdout: &str) -> Result<Vec<ManifestChunk>, AppError> {
+    let mut offset = 0;
+    protocol::parse_output(stdout)?
+        .into_iter()
+        .map(|message| match message {
+            Message::Chunk { hash, length } => {
+                let chunk = ManifestChunk {
+                    hash,
+                    offset,
+                    length,
+                };
+                offset += length;
+                Ok(chunk)
+            }
+            other => Err(other.unexpected("manifest")),
+        })
+        .collect()
+}
@@ -0,0 +1,266 @@
+//! Prometheus metrics
+//!
+//! [`Metrics`] holds the counters and histograms recorded on the request
+//! path: requests by route and status, bytes streamed, active downloads, and
+//! time-to-first-byte / total duration of downloads. `/metrics` renders them
+//! in the Prometheus text exposition format together with values owned by
+//! other subsystems (CLI failure signatures, cache, load sampling).
+
+use axum::extract::{MatchedPath, Request, State};
+use axum::middleware::Next;
+use axum::response::Response;
+use std::collections::BTreeMap;
+use std::fmt::{Display, Write};
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Upper bounds (seconds) of the time-to-first-byte histogram buckets
+const TTFB_BUCKETS: &[f64] = &[0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0];
+/// Upper bounds (seconds) of the download duration histogram buckets
+const DURATION_BUCKETS: &[f64] = &[
+    1.0, 5.0, 15.0, 30.0, 60.0, 300.0, 900.0, 1800.0, 3600.0, 7200.0,
+];
+
+#[derive(Clone)]
+struct Histogram {
+    bounds: &'static [f64],
+    /// Per-bucket (non-cumulative) counts; the last one is `+Inf`
+    counts: Vec<u64>,
+    sum: f64,
+}
+
+impl Histogram {
+    fn new(bounds: &'static [f64]) -> Self {
+        Self {
+            bounds,
+            counts: vec![0; bounds.len() + 1],
+            sum: 0.0,
+        }
+    }
+
+    fn observe(&mut self, value: Duration) {
+        let secs = value.as_secs_f64();
+        let bucket = self
+            .bounds
+            .iter()
+            .position(|&bound| secs <= bound)
+            .unwrap_or(self.bounds.len());
+        self.counts[bucket] += 1;
+        self.sum += secs;
+    }
+}
+
+#[derive(Default)]
+struct Inner {
+    requests: Mutex<BTreeMap<(String, u16), u64>>,
+    streamed_bytes: Mutex<BTreeMap<&'static str, Arc<AtomicU64>>>,
+    active_downloads: AtomicI64,
+    verified_downloads: AtomicU64,
+    integrity_failures: AtomicU64,
+    ttfb: Mutex<BTreeMap<&'static str, Histogram>>,
+    duration: Mutex<BTreeMap<&'static str, Histogram>>,
+}
+
+/// Metrics recorded on the request path
+#[derive(Clone, Default)]
+pub struct Metrics {
+    inner: Arc<Inner>,
+}
+
+impl Metrics {
+    pub fn record_request(&self, route: &str, status: u16) {
+        let mut requests = self.inner.requests.lock().unwrap();
+        *requests.entry((route.to_string(), status)).or_insert(0) += 1;
+    }
+
+    /// Counter of bytes sent to clients on `route`
+    pub fn streamed_bytes(&self, route: &'static str) -> Arc<AtomicU64> {
+        let mut streamed = self.inner.streamed_bytes.lock().unwrap();
+        streamed.entry(route).or_default().clone()
+    }
+
+    pub fn download_started(&self) {
+        self.inner.active_downloads.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn download_ended(&self) {
+        self.inner.active_downloads.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    /// Downloads currently streaming
+    pub fn active_downloads(&self) -> i64 {
+        self.inner.active_downloads.load(Ordering::Relaxed)
+    }
+
+    /// Count a download checked against its hash; `matched` is the outcome
+    pub fn record_verification(&self, matched: bool) {
+        self.inner
+            .verified_downloads
+            .fetch_add(1, Ordering::Relaxed);
+        if !matched {
+            self.inner
+                .integrity_failures
+                .fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn observe_ttfb(&self, route: &'static str, ttfb: Duration) {
+        let mut histograms = self.inner.ttfb.lock().unwrap();
+        histograms
+            .entry(route)
+            .or_insert_with(|| Histogram::new(TTFB_BUCKETS))
+            .observe(ttfb);
+    }
+
+    pub fn observe_duration(&self, route: &'static str, total: Duration) {
+        let mut histograms = self.inner.duration.lock().unwrap();
+        histograms
+            .entry(route)
+            .or_insert_with(|| Histogram::new(DURATION_BUCKETS))
+            .observe(total);
+    }
+
+    /// Render the request-path metrics; `extra` appends metrics owned elsewhere
+    pub fn render(&self, extra: impl FnOnce(&mut Exposition)) -> String {
+        let mut out = Exposition::default();
+
+        out.family(
+            "xet_proxy_http_requests_total",
+            "counter",
+            "HTTP requests by route and status",
+        );
+        for ((route, status), count) in self.inner.requests.lock().unwrap().iter() {
+            out.sample(
+                "xet_proxy_http_requests_total",
+                &[("route", route), ("status", &status.to_string())],
+                count,
+            );
+        }
+
+        out.family(
+            "xet_proxy_streamed_bytes_total",
+            "counter",
+            "Bytes of file content sent to clients",
+        );
+        for (route, bytes) in self.inner.streamed_bytes.lock().unwrap().iter() {
+            out.sample(
+                "xet_proxy_streamed_bytes_total",
+                &[("route", route)],
+                bytes.load(Ordering::Relaxed),
+            );
+        }
+
+        out.family(
+            "xet_proxy_active_downloads",
+            "gauge",
+            "Downloads currently streaming",
+        );
+        out.sample(
+            "xet_proxy_active_downloads",
+            &[],
+            self.inner.active_downloads.load(Ordering::Relaxed),
+        );
+
+        out.family(
+            "xet_proxy_verified_downloads_total",
+            "counter",
+            "Downloads whose content was checked against their hash",
+        );
+        out.sample(
+            "xet_proxy_verified_downloads_total",
+            &[],
+            self.inner.verified_downloads.load(Ordering::Relaxed),
+        );
+        out.family(
+            "xet_proxy_integrity_failures_total",
+            "counter",
+            "Verified downloads whose content did not match their hash",
+        );
+        out.sample(
+            "xet_proxy_integrity_failures_total",
+            &[],
+            self.inner.integrity_failures.load(Ordering::Relaxed),
+        );
+
+        out.histograms(
+            "xet_proxy_download_ttfb_seconds",
+            "Time from request to first byte of a download",
+            &self.inner.ttfb.lock().unwrap(),
+        );
+        out.histograms(
+            "xet_proxy_download_duration_seconds",
+            "Time from request to end of a completed download",
+            &self.inner.duration.lock().unwrap(),
+        );
+
+        extra(&mut out);
+        out.0
+    }
+}
+
+/// Prometheus text format writer
+#[derive(Default)]
+pub struct Exposition(String);
+
+impl Exposition {
+    pub fn family(&mut self, name: &str, kind: &str, help: &str) {
+        let _ = writeln!(self.0, "# HELP {} {}", name, help);
+        let _ = writeln!(self.0, "# TYPE {} {}", name, kind);
+    }
+
+    pub fn sample(&mut self, name: &str, labels: &[(&str, &str)], value: impl Display) {
+        self.0.push_str(name);
+        if !labels.is_empty() {
+            let labels: Vec<String> = labels
+                .iter()
+                .map(|(key, value)| format!("{}=\"{}\"", key, escape(value)))
+                .collect();
+            let _ = write!(self.0, "{{{}}}", labels.join(","));
+        }
+        let _ = writeln!(self.0, " {}", value);
+    }
+
+    fn histograms(
+        &mut self,
+        name: &str,
+        help: &str,
+        histograms: &BTreeMap<&'static str, Histogram>,
+    ) {
+        self.family(name, "histogram", help);
+        let bucket = format!("{}_bucket", name);
+        for (route, histogram) in histograms {
+            let mut cumulative = 0;
+            for (i, count) in histogram.counts.iter().enumerate() {
+                cumulative += count;
+                let le = histogram
+                    .bounds
+                    .get(i)
+                    .map_or("+Inf".to_string(), |bound| bound.to_string());
+                self.sample(&bucket, &[("route", route), ("le", &le)], cumulative);
+            }
+            self.sample(&format!("{}_sum", name), &[("route", route)], histogram.sum);
+            self.sample(&format!("{}_count", name), &[("route", route)], cumulative);
+        }
+    }
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Middleware counting responses by matched route and status
+pub async fn track(State(metrics): State<Metrics>, request: Request, next: Next) -> Response {
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|path| path.as_str().to_string());
+    let response = next.run(request).await;
+    if let Some(route) = route {
+        metrics.record_request(&route, response.status().as_u16());
+    }
+    response
+}
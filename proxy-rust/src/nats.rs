@@ -0,0 +1,51 @@
+//! NATS publisher for the activity event stream
+//!
+//! Mirrors every event from the in-process [`EventBus`] onto a NATS subject
+//! named `<prefix>.<event type>` (e.g. `xet-proxy.download_finished`), so
+//! platform teams can build accounting and lineage off proxy activity
+//! without holding an SSE connection open.
+
+use crate::events::EventBus;
+use tokio::sync::broadcast::error::RecvError;
+use tracing::{error, info, warn};
+
+/// Connect to `url` and forward events until the bus is closed
+pub async fn spawn_publisher(url: String, subject_prefix: String, events: EventBus) {
+    let client = match async_nats::connect(&url).await {
+        Ok(client) => client,
+        Err(e) => {
+            error!("Failed to connect to NATS at {}: {}", url, e);
+            return;
+        }
+    };
+    info!(
+        "Publishing events to NATS {} under '{}.*'",
+        url, subject_prefix
+    );
+
+    let mut receiver = events.subscribe();
+    tokio::spawn(async move {
+        loop {
+            let event = match receiver.recv().await {
+                Ok(event) => event,
+                Err(RecvError::Lagged(skipped)) => {
+                    warn!("NATS publisher lagged, dropped {} events", skipped);
+                    continue;
+                }
+                Err(RecvError::Closed) => break,
+            };
+
+            let payload = match serde_json::to_vec(&event) {
+                Ok(payload) => payload,
+                Err(e) => {
+                    error!("Failed to serialize event: {}", e);
+                    continue;
+                }
+            };
+            let subject = format!("{}.{}", subject_prefix, event.kind.name());
+            if let Err(e) = client.publish(subject, payload.into()).await {
+                warn!("Failed to publish event to NATS: {}", e);
+            }
+        }
+    });
+}
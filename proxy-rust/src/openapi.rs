@@ -0,0 +1,127 @@
+//! OpenAPI description of the data-plane API
+//!
+//! `/openapi.json` serves an OpenAPI 3 document generated from the handlers'
+//! annotations, for generating client SDKs, and `/docs` browses it with
+//! Swagger UI. Both answer without authentication.
+//!
+//! It covers the health probes and the download, metadata, upload, prefetch,
+//! session and login endpoints. The operational endpoints (`/cache`, `/metrics`,
+//! `/admin/limits`, ...), which may live on the admin listener instead, are
+//! left out. Errors answer with their status and an `ErrorResponse` body.
+
+use crate::ErrorResponse;
+use axum::Router;
+use utoipa::openapi::security::{HttpAuthScheme, HttpBuilder, SecurityScheme};
+use utoipa::openapi::{ContentBuilder, RefOr, ResponseBuilder};
+use utoipa::{Modify, OpenApi, ToSchema};
+use utoipa_swagger_ui::SwaggerUi;
+
+pub const SPEC_PATH: &str = "/openapi.json";
+pub const DOCS_PATH: &str = "/docs";
+
+#[derive(OpenApi)]
+#[openapi(
+    info(title = "XET Proxy", description = "HTTP access to files stored with XET on the HuggingFace Hub"),
+    paths(
+        crate::health,
+        crate::liveness,
+        crate::readiness,
+        crate::version,
+        crate::download_by_path,
+        crate::download_by_hash,
+        crate::chunk_manifest,
+        crate::alias_download,
+        crate::alias_bundle_download,
+        crate::download_archive,
+        crate::list_files,
+        crate::snapshot,
+        crate::resolve_hashes,
+        crate::resolve_batch,
+        crate::hashes_exist,
+        crate::select_artifact,
+        crate::upload_file,
+        crate::tus_create,
+        crate::tus_status,
+        crate::tus_write,
+        crate::tus_delete,
+        crate::prefetch_submit,
+        crate::prefetch_status,
+        crate::job_progress,
+        crate::session_create,
+        crate::session_status,
+        crate::session_end,
+        crate::login,
+        crate::logout,
+    ),
+    components(schemas(ErrorResponse)),
+    modifiers(&Conventions),
+    tags(
+        (name = "health", description = "Liveness and readiness probes"),
+        (name = "downloads", description = "File content, by path, hash or alias"),
+        (name = "metadata", description = "Listings and hash resolution"),
+        (name = "uploads", description = "Commits to a repository"),
+        (name = "prefetch", description = "Background downloads into the cache"),
+        (name = "sessions", description = "Revisions pinned across requests"),
+        (name = "login", description = "Cookies authenticating a browser"),
+    )
+)]
+struct ApiDoc;
+
+/// What the annotations leave to the whole API: the version, the token
+/// scheme and the error body
+struct Conventions;
+
+impl Modify for Conventions {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        openapi.info.version = crate::VERSION.to_string();
+        openapi.info.license = None;
+        let components = openapi.components.get_or_insert_with(Default::default);
+        components.add_security_scheme(
+            "hf_token",
+            SecurityScheme::Http(
+                HttpBuilder::new()
+                    .scheme(HttpAuthScheme::Bearer)
+                    .description(Some(
+                        "HuggingFace token, unless the proxy has a fallback token",
+                    ))
+                    .build(),
+            ),
+        );
+        let error = ResponseBuilder::new()
+            .description("Error, with its message")
+            .content(
+                "application/json",
+                ContentBuilder::new()
+                    .schema(Some(RefOr::Ref(utoipa::openapi::Ref::from_schema_name(
+                        ErrorResponse::name(),
+                    ))))
+                    .build(),
+            )
+            .build();
+        for item in openapi.paths.paths.values_mut() {
+            let operations = [
+                &mut item.get,
+                &mut item.put,
+                &mut item.post,
+                &mut item.delete,
+            ];
+            for operation in operations.into_iter().flatten() {
+                operation
+                    .responses
+                    .responses
+                    .entry("default".to_string())
+                    .or_insert_with(|| error.clone().into());
+            }
+        }
+    }
+}
+
+/// The generated document
+pub fn spec() -> utoipa::openapi::OpenApi {
+    ApiDoc::openapi()
+}
+
+/// `/openapi.json` and the Swagger UI at `/docs`
+pub fn routes<S: Clone + Send + Sync + 'static>() -> Router<S> {
+    SwaggerUi::new(DOCS_PATH).url(SPEC_PATH, spec()).into()
+}
@@ -0,0 +1,248 @@
+//! Request-scoped behavior overrides
+//!
+//! Clients can tune a single request with `X-Proxy-*` headers, always within
+//! the bounds the operator configured:
+//!
+//! - `X-Proxy-Timeout: <seconds>` - total time budget for the request,
+//!   including listing and streaming. Clamped to `PROXY_MAX_TIMEOUT_SECS`.
+//! - `X-Proxy-Retries: <n>` - retries of the upstream steps before the
+//!   response starts, on transient failures (see [`crate::retry`]). Defaults
+//!   to `PROXY_DEFAULT_RETRIES` (2), clamped to `PROXY_MAX_RETRIES` (3).
+//! - `X-Transfer-Mode: stream|redirect|spool` - how the file gets to the
+//!   client. `stream` (the default) proxies the bytes as they arrive.
+//!   `redirect` answers with a redirect to the upstream file instead; only
+//!   honored for path downloads and when `PROXY_ALLOW_REDIRECT=true`.
+//!   `spool` downloads the whole file into the cache before the response
+//!   starts, so a slow client doesn't hold the upstream transfer open; only
+//!   honored when `PROXY_ALLOW_SPOOL=true`, which needs a cache. A mode
+//!   the operator doesn't allow falls back to `stream`, and file responses
+//!   say which mode they got in `X-Transfer-Mode`. `X-Proxy-Prefer:
+//!   stream|redirect` is the older spelling of the first two.
+
+use crate::conditional::Conditions;
+use crate::range::RangeSpec;
+use crate::retry::RetryPolicy;
+use crate::AppError;
+use axum::http::HeaderMap;
+use std::future::Future;
+use std::time::Duration;
+use tokio::time::Instant;
+use tracing::{debug, info, warn};
+
+/// Header asking for a transfer mode, and telling the one used
+pub const TRANSFER_MODE_HEADER: &str = "x-transfer-mode";
+
+/// Operator-set defaults and bounds for request overrides
+#[derive(Clone, Debug)]
+pub struct OverrideLimits {
+    /// Timeout applied when the client doesn't send one (None = unlimited)
+    pub default_timeout: Option<Duration>,
+    /// Upper bound for client-requested timeouts (None = unbounded)
+    pub max_timeout: Option<Duration>,
+    pub default_retries: u32,
+    pub max_retries: u32,
+    pub allow_redirect: bool,
+    pub allow_spool: bool,
+    /// Longest `/list` answers wait before returning a partial listing
+    /// (None = until the listing is complete)
+    pub list_budget: Option<Duration>,
+    pub retry: RetryPolicy,
+}
+
+impl OverrideLimits {
+    /// Load limits from `PROXY_*` environment variables
+    pub fn from_env() -> Self {
+        let allow_spool = crate::config::flag("PROXY_ALLOW_SPOOL").unwrap_or(false);
+        assert!(
+            !allow_spool || crate::cache::configured(),
+            "PROXY_ALLOW_SPOOL spools into the cache and needs CACHE_DIR or CACHE_S3_BUCKET"
+        );
+        Self {
+            default_timeout: crate::config::number("PROXY_TIMEOUT_SECS").map(Duration::from_secs),
+            max_timeout: crate::config::number("PROXY_MAX_TIMEOUT_SECS").map(Duration::from_secs),
+            default_retries: crate::config::number("PROXY_DEFAULT_RETRIES").unwrap_or(2),
+            max_retries: crate::config::number("PROXY_MAX_RETRIES").unwrap_or(3),
+            allow_redirect: crate::config::flag("PROXY_ALLOW_REDIRECT").unwrap_or(false),
+            allow_spool,
+            list_budget: crate::config::number("PROXY_LIST_BUDGET_MS").map(Duration::from_millis),
+            retry: RetryPolicy::from_env(),
+        }
+    }
+}
+
+/// How a file gets to the client, per `X-Transfer-Mode`
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum TransferMode {
+    #[default]
+    Stream,
+    Redirect,
+    Spool,
+}
+
+impl TransferMode {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Stream => "stream",
+            Self::Redirect => "redirect",
+            Self::Spool => "spool",
+        }
+    }
+}
+
+/// Effective behavior for a single request
+#[derive(Clone, Debug)]
+pub struct RequestOptions {
+    /// When the request was received; budgets and latencies start here
+    pub received_at: Instant,
+    /// Point in time after which the request (and its child process) is aborted
+    pub deadline: Option<Instant>,
+    pub retries: u32,
+    pub retry: RetryPolicy,
+    /// Transfer mode, as asked for and allowed
+    pub mode: TransferMode,
+    /// `Range` header, resolved once the file size is known
+    pub range: Option<RangeSpec>,
+    /// `Range` header of several ranges, served from the cache only
+    pub ranges: Vec<RangeSpec>,
+    /// `If-None-Match` and `If-Range`, checked once the ETag is known
+    pub conditions: Conditions,
+    /// List the repository again instead of using a cached listing
+    /// (`?refresh=true`, see [`crate::listing_cache`])
+    pub refresh: bool,
+    /// `X-Resume-Token` from an earlier response (see [`crate::resume`])
+    pub resume_token: Option<String>,
+}
+
+impl RequestOptions {
+    pub fn from_headers(headers: &HeaderMap, limits: &OverrideLimits) -> Result<Self, AppError> {
+        let received_at = Instant::now();
+        let timeout = match header_u64(headers, "x-proxy-timeout")? {
+            Some(secs) => {
+                let requested = Duration::from_secs(secs);
+                Some(match limits.max_timeout {
+                    Some(max) if requested > max => {
+                        warn!("Clamping X-Proxy-Timeout {}s to {}s", secs, max.as_secs());
+                        max
+                    }
+                    _ => requested,
+                })
+            }
+            None => limits.default_timeout,
+        };
+
+        let retries = match header_u64(headers, "x-proxy-retries")? {
+            Some(n) => (n as u32).min(limits.max_retries),
+            None => limits.default_retries,
+        };
+
+        let requested = match headers.get(TRANSFER_MODE_HEADER).map(|v| v.to_str()) {
+            None => match headers.get("x-proxy-prefer").map(|v| v.to_str()) {
+                None | Some(Ok("stream")) => TransferMode::Stream,
+                Some(Ok("redirect")) => TransferMode::Redirect,
+                Some(_) => {
+                    return Err(AppError::BadRequest(
+                        "X-Proxy-Prefer must be 'stream' or 'redirect'".to_string(),
+                    ))
+                }
+            },
+            Some(Ok("stream")) => TransferMode::Stream,
+            Some(Ok("redirect")) => TransferMode::Redirect,
+            Some(Ok("spool")) => TransferMode::Spool,
+            Some(_) => {
+                return Err(AppError::BadRequest(
+                    "X-Transfer-Mode must be 'stream', 'redirect' or 'spool'".to_string(),
+                ))
+            }
+        };
+        let mode = match requested {
+            TransferMode::Redirect if !limits.allow_redirect => TransferMode::Stream,
+            TransferMode::Spool if !limits.allow_spool => TransferMode::Stream,
+            mode => mode,
+        };
+        if mode != requested {
+            debug!(
+                "Transfer mode {} is not allowed, streaming instead",
+                requested.as_str()
+            );
+        }
+
+        Ok(Self {
+            received_at,
+            deadline: timeout.map(|t| received_at + t),
+            retries,
+            retry: limits.retry.clone(),
+            mode,
+            range: RangeSpec::from_headers(headers),
+            ranges: RangeSpec::list_from_headers(headers).unwrap_or_default(),
+            conditions: Conditions::from_headers(headers),
+            refresh: false,
+            resume_token: headers
+                .get("x-resume-token")
+                .and_then(|v| v.to_str().ok())
+                .map(|v| v.trim().to_string()),
+        })
+    }
+
+    /// Run `op` with the request's retry count and deadline, retrying
+    /// transient failures while the budget allows
+    pub async fn run<T, F, Fut>(&self, what: &str, mut op: F) -> Result<T, AppError>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, AppError>>,
+    {
+        let mut attempt = 0;
+        loop {
+            let result = match self.deadline {
+                Some(deadline) => tokio::time::timeout_at(deadline, op())
+                    .await
+                    .unwrap_or_else(|_| Err(timeout_error(what))),
+                None => op().await,
+            };
+
+            let error = match result {
+                Err(error) if attempt < self.retries => error,
+                result => {
+                    if attempt > 0 && result.is_ok() {
+                        info!("{} succeeded after {} retries", what, attempt);
+                    }
+                    return result;
+                }
+            };
+            let delay = self.retry.delay(attempt + 1, &error);
+            match delay {
+                Some(delay) if self.deadline.is_none_or(|d| Instant::now() + delay < d) => {
+                    attempt += 1;
+                    warn!(
+                        "{} failed (attempt {}/{}), retrying in {}ms: {}",
+                        what,
+                        attempt,
+                        self.retries + 1,
+                        delay.as_millis(),
+                        error.message()
+                    );
+                    self.retry.record(what);
+                    tokio::time::sleep(delay).await;
+                }
+                _ => return Err(error),
+            }
+        }
+    }
+}
+
+fn timeout_error(what: &str) -> AppError {
+    AppError::Timeout(format!("{} exceeded the request time budget", what))
+}
+
+fn header_u64(headers: &HeaderMap, name: &str) -> Result<Option<u64>, AppError> {
+    headers
+        .get(name)
+        .map(|v| {
+            v.to_str()
+                .ok()
+                .and_then(|s| s.trim().parse().ok())
+                .ok_or_else(|| {
+                    AppError::BadRequest(format!("{} must be a non-negative integer", name))
+                })
+        })
+        .transpose()
+}
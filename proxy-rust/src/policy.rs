@@ -0,0 +1,225 @@
+//! File access decisions by an external policy engine
+//!
+//! With `POLICY_URL` set, every file the proxy is about to serve (path and
+//! hash downloads, `HEAD`s, chunk manifests and each file of an archive) is
+//! described to the policy endpoint in an OPA-style `POST`:
+//!
+//! ```json
+//! {"input": {"identity": "jwt:alice", "repo_type": "model", "repo": "owner/repo",
+//!            "revision": "main", "file": "model.gguf", "hash": "...", "size": 4368439584}}
+//! ```
+//!
+//! `identity` is the authenticated client (`null` without authentication);
+//! the repository, `file` and `size` are `null` when a hash download doesn't
+//! know them. The answer is OPA's: `{"result": true}`, or `{"result":
+//! {"allow": false, "reason": "..."}}`. A denial is answered with 403 and
+//! its reason; an undefined result denies too.
+//!
+//! Decisions are cached for `POLICY_CACHE_SECS` (default 30, 0 to ask
+//! every time) per distinct input. An endpoint that fails or doesn't answer
+//! within `POLICY_TIMEOUT_MS` (default 2000) fails the request with 503,
+//! unless `POLICY_FAIL_OPEN=true` lets it through.
+
+use crate::repo::RepoRef;
+use crate::AppError;
+use serde::Deserialize;
+use serde_json::json;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+/// Cached decisions beyond which expired ones are dropped
+const CACHE_PRUNE_AT: usize = 10_000;
+
+/// What a client is about to be served
+pub struct Access<'a> {
+    pub identity: Option<&'a str>,
+    pub repo: Option<&'a RepoRef>,
+    pub file: Option<&'a str>,
+    pub hash: &'a str,
+    pub size: Option<u64>,
+}
+
+#[derive(Deserialize)]
+struct Answer {
+    result: Option<Outcome>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Outcome {
+    Allow(bool),
+    Decision {
+        allow: bool,
+        #[serde(default)]
+        reason: Option<String>,
+    },
+}
+
+/// A decision: `Err` holds the denial's reason
+type Decision = Result<(), String>;
+
+/// Client of the policy endpoint, with its decision cache
+#[derive(Clone)]
+pub struct Policy {
+    url: Arc<str>,
+    http: reqwest::Client,
+    ttl: Duration,
+    fail_open: bool,
+    decisions: Arc<Mutex<HashMap<String, (Instant, Decision)>>>,
+    allowed: Arc<AtomicU64>,
+    denied: Arc<AtomicU64>,
+    failed: Arc<AtomicU64>,
+}
+
+impl Policy {
+    /// Load `POLICY_URL`, `POLICY_TIMEOUT_MS`, `POLICY_CACHE_SECS` and
+    /// `POLICY_FAIL_OPEN`; `None` without a URL
+    pub fn from_env() -> Option<Self> {
+        let url = std::env::var("POLICY_URL").ok()?;
+        assert!(
+            url.starts_with("http://") || url.starts_with("https://"),
+            "POLICY_URL must be an http:// or https:// URL"
+        );
+        let millis = crate::config::positive("POLICY_TIMEOUT_MS").unwrap_or(2000);
+        let ttl = crate::config::number("POLICY_CACHE_SECS").unwrap_or(30);
+        let fail_open = crate::config::flag("POLICY_FAIL_OPEN").unwrap_or(false);
+        let http = reqwest::Client::builder()
+            .timeout(Duration::from_millis(millis))
+            .user_agent(concat!("xet-proxy/", env!("CARGO_PKG_VERSION")))
+            .build()
+            .expect("Failed to build HTTP client");
+        info!(
+            "File access decided by {} (cached {}s, failing {})",
+            url,
+            ttl,
+            if fail_open { "open" } else { "closed" }
+        );
+        Some(Self {
+            url: url.into(),
+            http,
+            ttl: Duration::from_secs(ttl),
+            fail_open,
+            decisions: Arc::default(),
+            allowed: Arc::default(),
+            denied: Arc::default(),
+            failed: Arc::default(),
+        })
+    }
+
+    /// The policy endpoint
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// Refuse with 403 unless the policy allows `access`
+    pub async fn check(&self, access: &Access<'_>) -> Result<(), AppError> {
+        let input = json!({
+            "identity": access.identity,
+            "repo_type": access.repo.map(|repo| repo.repo_type.as_str()),
+            "repo": access.repo.map(RepoRef::id),
+            "revision": access.repo.map(|repo| repo.revision.as_str()),
+            "file": access.file,
+            "hash": access.hash,
+            "size": access.size,
+        });
+        let key = input.to_string();
+        let cached = self
+            .decisions
+            .lock()
+            .unwrap()
+            .get(&key)
+            .filter(|(expires, _)| Instant::now() < *expires)
+            .map(|(_, decision)| decision.clone());
+        let decision = match cached {
+            Some(decision) => decision,
+            None => match self.ask(&input).await {
+                Ok(decision) => {
+                    self.remember(key, decision.clone());
+                    decision
+                }
+                Err(e) if self.fail_open => {
+                    self.failed.fetch_add(1, Ordering::Relaxed);
+                    warn!(
+                        "Policy endpoint failed, allowing {}: {}",
+                        access.file.unwrap_or(access.hash),
+                        e
+                    );
+                    return Ok(());
+                }
+                Err(e) => {
+                    self.failed.fetch_add(1, Ordering::Relaxed);
+                    return Err(AppError::Unavailable {
+                        message: format!("Policy endpoint {}", e),
+                        retry_after: None,
+                    });
+                }
+            },
+        };
+        match decision {
+            Ok(()) => {
+                self.allowed.fetch_add(1, Ordering::Relaxed);
+                Ok(())
+            }
+            Err(reason) => {
+                self.denied.fetch_add(1, Ordering::Relaxed);
+                warn!(
+                    "Policy denied {} to {}: {}",
+                    access.file.unwrap_or(access.hash),
+                    access.identity.unwrap_or("an anonymous client"),
+                    reason
+                );
+                Err(AppError::Forbidden(format!("Denied by policy: {}", reason)))
+            }
+        }
+    }
+
+    /// Decisions made so far: allowed, denied, and failed to obtain
+    pub fn counts(&self) -> (u64, u64, u64) {
+        (
+            self.allowed.load(Ordering::Relaxed),
+            self.denied.load(Ordering::Relaxed),
+            self.failed.load(Ordering::Relaxed),
+        )
+    }
+
+    async fn ask(&self, input: &serde_json::Value) -> Result<Decision, String> {
+        let response = self
+            .http
+            .post(&*self.url)
+            .json(&json!({ "input": input }))
+            .send()
+            .await
+            .map_err(|e| format!("unreachable: {}", e))?;
+        let status = response.status();
+        if !status.is_success() {
+            return Err(format!("answered HTTP {}", status.as_u16()));
+        }
+        let answer: Answer = response
+            .json()
+            .await
+            .map_err(|e| format!("answered an invalid decision: {}", e))?;
+        Ok(match answer.result {
+            Some(Outcome::Allow(true)) | Some(Outcome::Decision { allow: true, .. }) => Ok(()),
+            Some(Outcome::Allow(false)) => Err("not allowed".to_string()),
+            Some(Outcome::Decision { reason, .. }) => {
+                Err(reason.unwrap_or_else(|| "not allowed".to_string()))
+            }
+            None => Err("no decision for this request".to_string()),
+        })
+    }
+
+    fn remember(&self, key: String, decision: Decision) {
+        if self.ttl.is_zero() {
+            return;
+        }
+        let now = Instant::now();
+        let mut decisions = self.decisions.lock().unwrap();
+        if decisions.len() >= CACHE_PRUNE_AT {
+            decisions.retain(|_, (expires, _)| now < *expires);
+        }
+        decisions.insert(key, (now + self.ttl, decision));
+    }
+}
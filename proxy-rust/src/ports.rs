@@ -0,0 +1,199 @@
+//! Which listener serves what
+//!
+//! The proxy's endpoints fall into three planes, each on the data-plane
+//! ports, on a listener of its own, or off:
+//!
+//! - data: downloads, listings, uploads and the other client endpoints, on
+//!   `PORT` (default 8080; `off` leaves only `TLS_PORT`, see [`crate::tls`]);
+//! - admin: the operational endpoints (`/cache`, `/config`, `/events`,
+//!   `/admin/...`), on `ADMIN_ADDR` behind its own keys (see
+//!   [`crate::admin`]);
+//! - metrics: `/metrics` and `/slo`, on `METRICS_ADDR`, open so Prometheus
+//!   scrapes it without a key.
+//!
+//! `ADMIN_ADDR` and `METRICS_ADDR` take an address (`127.0.0.1:9090`), the
+//! path of a Unix socket, or `off`. Unset, the admin plane shares the
+//! data-plane ports and the metrics plane goes wherever the admin plane
+//! does. The health probes answer on every listener.
+
+use axum::Router;
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use hyper_util::server::conn::auto;
+use hyper_util::server::graceful::GracefulShutdown;
+use hyper_util::service::TowerToHyperService;
+use std::future::{Future, IntoFuture};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::Duration;
+use tokio::net::{TcpListener, UnixListener};
+use tracing::{debug, warn};
+
+/// Where a listener of its own binds
+#[derive(Clone, Debug)]
+pub enum Bind {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl Bind {
+    /// Where the listener is, for the startup banner
+    pub fn describe(&self) -> String {
+        match self {
+            Bind::Tcp(addr) => format!("http://{}", addr),
+            Bind::Unix(path) => format!("unix:{}", path.display()),
+        }
+    }
+
+    /// Bind the listener configured by `var`
+    pub async fn listen(&self, var: &str) -> Listener {
+        match self {
+            Bind::Tcp(addr) => Listener::Tcp(TcpListener::bind(addr).await.unwrap_or_else(|e| {
+                panic!("Failed to bind to the {} address {}: {}", var, addr, e)
+            })),
+            Bind::Unix(path) => {
+                // Left over by a previous run, which would fail the bind
+                if std::fs::symlink_metadata(path)
+                    .is_ok_and(|m| std::os::unix::fs::FileTypeExt::is_socket(&m.file_type()))
+                {
+                    let _ = std::fs::remove_file(path);
+                }
+                Listener::Unix(UnixListener::bind(path).unwrap_or_else(|e| {
+                    panic!(
+                        "Failed to bind to the {} socket {}: {}",
+                        var,
+                        path.display(),
+                        e
+                    )
+                }))
+            }
+        }
+    }
+}
+
+/// Where a plane is served
+#[derive(Clone, Debug)]
+pub enum Plane {
+    /// With the endpoints it defaults to
+    Shared,
+    Own(Bind),
+    Off,
+}
+
+impl Plane {
+    /// Load an address, a socket path or `off` from `var`
+    fn from_env(var: &str) -> Self {
+        let Ok(addr) = std::env::var(var) else {
+            return Self::Shared;
+        };
+        if addr == "off" {
+            return Self::Off;
+        }
+        if addr.starts_with('/') {
+            return Self::Own(Bind::Unix(PathBuf::from(addr)));
+        }
+        Self::Own(Bind::Tcp(addr.parse().unwrap_or_else(|_| {
+            panic!(
+                "{} must be an address (127.0.0.1:9090), the path of a Unix socket or off, got '{}'",
+                var, addr
+            )
+        })))
+    }
+
+    pub fn own(&self) -> Option<&Bind> {
+        match self {
+            Self::Own(bind) => Some(bind),
+            _ => None,
+        }
+    }
+}
+
+/// Where each plane is served
+#[derive(Clone, Debug)]
+pub struct PortMap {
+    /// Plain HTTP port of the data plane, unless off
+    pub data: Option<u16>,
+    pub admin: Plane,
+    pub metrics: Plane,
+}
+
+impl PortMap {
+    /// Load `PORT`, `ADMIN_ADDR` and `METRICS_ADDR`
+    pub fn from_env() -> Self {
+        Self {
+            data: data_port(),
+            admin: Plane::from_env("ADMIN_ADDR"),
+            metrics: Plane::from_env("METRICS_ADDR"),
+        }
+    }
+
+    /// Whether the operational endpoints are on the data-plane ports
+    pub fn admin_shared(&self) -> bool {
+        matches!(self.admin, Plane::Shared)
+    }
+
+    /// Whether `/metrics` and `/slo` go with the operational endpoints
+    pub fn metrics_with_admin(&self) -> bool {
+        matches!(self.metrics, Plane::Shared)
+    }
+}
+
+/// Load `PORT` (default 8080), `None` when `off`
+pub fn data_port() -> Option<u16> {
+    match std::env::var("PORT").as_deref() {
+        Err(_) => Some(8080),
+        Ok("off") => None,
+        Ok(port) => Some(
+            port.parse::<u16>()
+                .unwrap_or_else(|_| panic!("PORT must be a valid number or off")),
+        ),
+    }
+}
+
+/// A bound listener of its own
+pub enum Listener {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+
+/// Serve `app` on `listener` until `draining` resolves, then wait for the
+/// connections to finish their requests
+pub async fn serve(
+    listener: Listener,
+    app: Router,
+    draining: impl Future<Output = ()> + Send + 'static,
+) {
+    let listener = match listener {
+        Listener::Tcp(listener) => {
+            let app = app.into_make_service_with_connect_info::<SocketAddr>();
+            let server = axum::serve(listener, app).with_graceful_shutdown(draining);
+            return server.into_future().await.expect("Server failed to start");
+        }
+        Listener::Unix(listener) => listener,
+    };
+    let graceful = GracefulShutdown::new();
+    tokio::pin!(draining);
+    loop {
+        let stream = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok((stream, _)) => stream,
+                Err(e) => {
+                    warn!("Failed to accept a connection: {}", e);
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                    continue;
+                }
+            },
+            _ = &mut draining => break,
+        };
+        let service = TowerToHyperService::new(app.clone());
+        let watcher = graceful.watcher();
+        tokio::spawn(async move {
+            let builder = auto::Builder::new(TokioExecutor::new());
+            let connection = builder.serve_connection(TokioIo::new(stream), service);
+            if let Err(e) = watcher.watch(connection).await {
+                debug!("Connection ended: {}", e);
+            }
+        });
+    }
+    drop(listener);
+    graceful.shutdown().await;
+}
@@ -0,0 +1,752 @@
+//! Background cache warming
+//!
+//! `POST /prefetch` takes a list of files, by repository path or by hash,
+//! and downloads them into the local cache in the background so that nodes
+//! hold the model weights before traffic reaches them. The request returns
+//! a job id at once; `GET /prefetch/:job_id` reports each file's progress.
+//!
+//! A job works through its files one at a time, with the token of the
+//! request that submitted it. Files are fetched from upstream straight into
+//! the cache, at the pace of the disk, and files already cached are skipped.
+//! One file failing doesn't stop the others. The last `MAX_FINISHED_JOBS`
+//! finished jobs stay queryable; `GET /progress/:job_id` (see
+//! [`crate::progress`]) streams a job's overall progress.
+//!
+//! An item naming only a repository covers all of its files, or those
+//! matching its `files` globs, and its name may be a wildcard: `myorg/*`
+//! covers every repository of `myorg`, as the Hub API lists them when the
+//! job runs. `max_file_size` and `max_total_size` cap what it brings in.
+//! `PREFETCH_SYNC_FILE` holds items the proxy runs as a job at startup and
+//! again every `PREFETCH_SYNC_INTERVAL_SECS`, so repositories published
+//! since the last run are mirrored too.
+//!
+//! `?nice=` (0 to 19; `PREFETCH_SYNC_NICE` for the scheduled jobs) makes a
+//! job yield to other downloads wherever they wait for capacity (see
+//! [`crate::slots`]). A nice job's downloads also hold a
+//! `MAX_CONCURRENT_DOWNLOADS` slot each, without taking a place in the
+//! client queue, so a mirror only downloads while clients leave room.
+
+use crate::backoff::UpstreamBackoff;
+use crate::cache::Cache;
+use crate::catalog::Catalog;
+use crate::downloader::{DownloadRequest, Downloader};
+use crate::limiter::DownloadLimiter;
+use crate::progress::{self, Job, Progress};
+use crate::repo::RepoRef;
+use crate::select::glob_match;
+use crate::slots::{Priority, MAX_NICE};
+use crate::transfer::Source;
+use crate::AppError;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::time::Instant;
+use tracing::{info, warn};
+
+/// Files accepted in one prefetch request
+pub const MAX_ITEMS: usize = 1000;
+const MAX_FINISHED_JOBS: usize = 1000;
+/// Repositories asked for per page of the Hub's repository search
+const SEARCH_PAGE_SIZE: &str = "1000";
+
+/// One file of a `POST /prefetch` body
+#[derive(Deserialize, utoipa::ToSchema)]
+#[serde(untagged, deny_unknown_fields)]
+pub enum PrefetchItem {
+    /// File by repository path (`owner/repo` or `<type>s/owner/repo`)
+    Path {
+        repo: String,
+        file: String,
+        revision: Option<String>,
+    },
+    /// File by XET hash, with the repository whose CAS token authorizes it
+    Hash {
+        hash: String,
+        repo: Option<String>,
+        revision: Option<String>,
+    },
+    /// Files of the repositories matching `repo`, whose name may hold `*`
+    /// and `?` wildcards (`myorg/*`)
+    Scope {
+        repo: String,
+        /// Globs of the files to fetch; all files without it
+        files: Option<Vec<String>>,
+        revision: Option<String>,
+        /// Skip files larger than this many bytes
+        max_file_size: Option<u64>,
+        /// Skip files once the scope's files would exceed this many bytes
+        max_total_size: Option<u64>,
+    },
+    /// Bare XET hash
+    BareHash(String),
+}
+
+/// A validated prefetch item
+#[derive(Clone)]
+pub enum Target {
+    Path { repo: RepoRef, file: String },
+    Hash { repo: RepoRef, hash: String },
+    Scope(Scope),
+}
+
+/// Repositories and files a scope item covers
+#[derive(Clone)]
+pub struct Scope {
+    /// Its name may hold wildcards, its owner may not
+    pub repo: RepoRef,
+    pub files: Vec<String>,
+    pub max_file_size: Option<u64>,
+    pub max_total_size: Option<u64>,
+}
+
+impl Scope {
+    fn is_wildcard(&self) -> bool {
+        self.repo.name.contains(['*', '?'])
+    }
+
+    fn covers(&self, path: &str) -> bool {
+        self.files.is_empty() || self.files.iter().any(|glob| glob_match(glob, path))
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, utoipa::ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum FileState {
+    Pending,
+    Downloading,
+    /// Fetched into the cache by this job
+    Done,
+    /// Already in the cache
+    Cached,
+    Failed,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, utoipa::ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum JobState {
+    Running,
+    /// Every file is in the cache
+    Completed,
+    /// Finished, but some files are not in the cache
+    Failed,
+}
+
+/// Progress of one file, as reported by `GET /prefetch/:job_id`
+#[derive(Clone, Serialize, utoipa::ToSchema)]
+pub struct FileStatus {
+    pub repo: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub file: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hash: Option<String>,
+    pub state: FileState,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub size: Option<u64>,
+    /// Bytes received from upstream so far
+    pub bytes: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    #[serde(skip)]
+    received: Option<Arc<AtomicU64>>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, utoipa::ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ScopeState {
+    Pending,
+    /// Its files were added to the job
+    Expanded,
+    Failed,
+}
+
+/// How a scope item expanded, as reported by `GET /prefetch/:job_id`
+#[derive(Clone, Serialize, utoipa::ToSchema)]
+pub struct ScopeStatus {
+    pub repo: String,
+    pub state: ScopeState,
+    /// Repositories it matched
+    pub repos: usize,
+    /// Files it added to the job
+    pub files: usize,
+    /// Matching files left out by `max_file_size` or `max_total_size`
+    pub skipped: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// A prefetch job, as reported by `GET /prefetch/:job_id`
+#[derive(Clone, Serialize, utoipa::ToSchema)]
+pub struct JobStatus {
+    pub id: String,
+    pub state: JobState,
+    /// Unix time the job was submitted
+    pub created_at: u64,
+    /// Niceness of its downloads
+    pub nice: u8,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub scopes: Vec<ScopeStatus>,
+    pub files: Vec<FileStatus>,
+}
+
+impl FileStatus {
+    fn pending(repo: &RepoRef, file: Option<String>, hash: Option<String>) -> Self {
+        Self {
+            repo: repo.to_string(),
+            file,
+            hash,
+            state: FileState::Pending,
+            size: None,
+            bytes: 0,
+            error: None,
+            received: None,
+        }
+    }
+}
+
+/// Files a scope expanded to
+struct Expansion {
+    repos: usize,
+    files: Vec<(RepoRef, String, String)>,
+    skipped: usize,
+    /// Repositories that couldn't be listed
+    failed: Vec<(RepoRef, AppError)>,
+}
+
+/// A repository in the Hub's search results
+#[derive(Deserialize)]
+struct SearchHit {
+    id: String,
+}
+
+/// Prefetch items run again and again, from `PREFETCH_SYNC_FILE`
+pub struct Schedule {
+    pub path: String,
+    pub items: Vec<PrefetchItem>,
+    pub interval: Duration,
+    /// `HF_TOKEN`, the jobs' token
+    pub token: String,
+    /// Of the jobs' downloads, from `PREFETCH_SYNC_NICE`
+    pub priority: Priority,
+}
+
+impl Schedule {
+    /// Load `PREFETCH_SYNC_FILE`, a `POST /prefetch` body,
+    /// `PREFETCH_SYNC_INTERVAL_SECS` (default 3600) and `PREFETCH_SYNC_NICE`
+    /// (default 0)
+    pub fn from_env() -> Option<Self> {
+        let path = std::env::var("PREFETCH_SYNC_FILE").ok()?;
+        let text = std::fs::read_to_string(&path)
+            .unwrap_or_else(|e| panic!("Failed to read PREFETCH_SYNC_FILE {}: {}", path, e));
+        let items: Vec<PrefetchItem> = serde_json::from_str(&text)
+            .unwrap_or_else(|e| panic!("Invalid PREFETCH_SYNC_FILE {}: {}", path, e));
+        assert!(
+            !items.is_empty() && items.len() <= MAX_ITEMS,
+            "PREFETCH_SYNC_FILE must list 1 to {} items",
+            MAX_ITEMS
+        );
+        assert!(
+            crate::cache::configured(),
+            "PREFETCH_SYNC_FILE prefetches into the cache and needs CACHE_DIR or CACHE_S3_BUCKET"
+        );
+        let secs = crate::config::positive("PREFETCH_SYNC_INTERVAL_SECS").unwrap_or(3600);
+        let priority = crate::config::number("PREFETCH_SYNC_NICE").map_or(Priority::Normal, |n| {
+            Priority::from_nice(n)
+                .unwrap_or_else(|| panic!("PREFETCH_SYNC_NICE must be 0 to {}", MAX_NICE))
+        });
+        let token = match crate::dev::enabled() {
+            true => crate::dev::TOKEN.to_string(),
+            false => std::env::var("HF_TOKEN")
+                .ok()
+                .filter(|token| !token.trim().is_empty())
+                .expect("PREFETCH_SYNC_FILE requires HF_TOKEN"),
+        };
+        Some(Self {
+            path,
+            items,
+            interval: Duration::from_secs(secs),
+            token,
+            priority,
+        })
+    }
+}
+
+/// Submits and tracks prefetch jobs
+#[derive(Clone)]
+pub struct Prefetcher {
+    /// Downloader without the cache in front of it
+    upstream: Arc<dyn Downloader>,
+    cache: Cache,
+    catalog: Catalog,
+    backoff: UpstreamBackoff,
+    progress: Progress,
+    /// Capacity nice jobs share with client downloads
+    limiter: Option<DownloadLimiter>,
+    /// For the Hub's repository search
+    http: reqwest::Client,
+    jobs: Arc<Mutex<Jobs>>,
+}
+
+#[derive(Default)]
+struct Jobs {
+    by_id: HashMap<String, JobStatus>,
+    /// Finished jobs, oldest first
+    finished: VecDeque<String>,
+}
+
+impl Prefetcher {
+    pub fn new(
+        upstream: Arc<dyn Downloader>,
+        cache: Cache,
+        catalog: Catalog,
+        backoff: UpstreamBackoff,
+        progress: Progress,
+        limiter: Option<DownloadLimiter>,
+    ) -> Self {
+        let http = crate::trust::apply(reqwest::Client::builder())
+            .connect_timeout(Duration::from_secs(30))
+            .user_agent(concat!("xet-proxy/", env!("CARGO_PKG_VERSION")))
+            .build()
+            .expect("Failed to build HTTP client");
+        Self {
+            upstream,
+            cache,
+            catalog,
+            backoff,
+            progress,
+            limiter,
+            http,
+            jobs: Arc::default(),
+        }
+    }
+
+    /// Start a job fetching `targets` with `hf_token` at `priority`;
+    /// returns its id. `timeout` bounds each file, not the whole job.
+    pub fn submit(
+        &self,
+        targets: Vec<Target>,
+        hf_token: String,
+        timeout: Option<Duration>,
+        priority: Priority,
+    ) -> String {
+        let (id, job) = self.begin(&targets, priority);
+        let prefetcher = self.clone();
+        let job_id = id.clone();
+        tokio::spawn(async move {
+            prefetcher
+                .run(&job_id, &job, targets, &hf_token, timeout, priority)
+                .await
+        });
+        id
+    }
+
+    /// Run a job fetching `targets` now and every `interval` after, each
+    /// run starting once the previous one is over
+    pub fn schedule(
+        &self,
+        targets: Vec<Target>,
+        hf_token: String,
+        interval: Duration,
+        priority: Priority,
+    ) {
+        let prefetcher = self.clone();
+        tokio::spawn(async move {
+            let mut ticks = tokio::time::interval(interval);
+            ticks.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+            loop {
+                ticks.tick().await;
+                let (id, job) = prefetcher.begin(&targets, priority);
+                prefetcher
+                    .run(&id, &job, targets.clone(), &hf_token, None, priority)
+                    .await;
+            }
+        });
+    }
+
+    /// Register a job for `targets`
+    fn begin(&self, targets: &[Target], priority: Priority) -> (String, Arc<Job>) {
+        let id = progress::job_id();
+        let mut files = Vec::new();
+        let mut scopes = Vec::new();
+        for target in targets {
+            match target {
+                Target::Path { repo, file } => {
+                    files.push(FileStatus::pending(repo, Some(file.clone()), None))
+                }
+                Target::Hash { repo, hash } => {
+                    files.push(FileStatus::pending(repo, None, Some(hash.clone())))
+                }
+                Target::Scope(scope) => scopes.push(ScopeStatus {
+                    repo: scope.repo.to_string(),
+                    state: ScopeState::Pending,
+                    repos: 0,
+                    files: 0,
+                    skipped: 0,
+                    error: None,
+                }),
+            }
+        }
+        let job = self.progress.start(&id, "prefetch", files.len());
+        if scopes.is_empty() {
+            info!("Prefetch job {} started with {} files", id, files.len());
+        } else {
+            info!(
+                "Prefetch job {} started with {} files and {} scopes",
+                id,
+                files.len(),
+                scopes.len()
+            );
+        }
+        let status = JobStatus {
+            id: id.clone(),
+            state: JobState::Running,
+            created_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_secs()),
+            nice: match priority {
+                Priority::Nice(nice) => nice,
+                _ => 0,
+            },
+            scopes,
+            files,
+        };
+        self.jobs.lock().unwrap().by_id.insert(id.clone(), status);
+        (id, job)
+    }
+
+    /// Status of a job, unless unknown or long finished
+    pub fn status(&self, id: &str) -> Option<JobStatus> {
+        let mut status = self.jobs.lock().unwrap().by_id.get(id)?.clone();
+        for file in &mut status.files {
+            if let Some(received) = &file.received {
+                file.bytes = received.load(Ordering::Relaxed);
+            }
+        }
+        Some(status)
+    }
+
+    async fn run(
+        &self,
+        id: &str,
+        job: &Job,
+        targets: Vec<Target>,
+        hf_token: &str,
+        timeout: Option<Duration>,
+        priority: Priority,
+    ) {
+        let mut failed = 0;
+        let mut fetches = Vec::new();
+        let mut scopes = Vec::new();
+        for target in targets {
+            match target {
+                Target::Scope(scope) => scopes.push(scope),
+                target => fetches.push(Some(target)),
+            }
+        }
+        for (index, scope) in scopes.iter().enumerate() {
+            let deadline = timeout.map(|timeout| Instant::now() + timeout);
+            let expansion = self.within(deadline, self.expand(scope, hf_token)).await;
+            failed += self.add_expansion(id, job, index, expansion, &mut fetches);
+        }
+
+        for (index, target) in fetches.into_iter().enumerate() {
+            let Some(target) = target else {
+                continue;
+            };
+            let deadline = timeout.map(|timeout| Instant::now() + timeout);
+            let fetch = self.fetch(id, job, index, target, hf_token, deadline, priority);
+            let result = crate::retry::counted(job.retries(), fetch).await;
+            job.file_done();
+            self.update(id, index, |file| match result {
+                Ok(state) => file.state = state,
+                Err(e) => {
+                    failed += 1;
+                    file.state = FileState::Failed;
+                    file.error = Some(e.message().to_string());
+                }
+            });
+        }
+
+        info!("Prefetch job {} finished, {} files failed", id, failed);
+        job.finish(match failed {
+            0 => Ok(()),
+            n => Err(format!("{} files failed", n)),
+        });
+        let mut jobs = self.jobs.lock().unwrap();
+        if let Some(job) = jobs.by_id.get_mut(id) {
+            job.state = if failed == 0 {
+                JobState::Completed
+            } else {
+                JobState::Failed
+            };
+        }
+        jobs.finished.push_back(id.to_string());
+        while jobs.finished.len() > MAX_FINISHED_JOBS {
+            if let Some(oldest) = jobs.finished.pop_front() {
+                jobs.by_id.remove(&oldest);
+            }
+        }
+    }
+
+    /// Bring one file into the cache
+    #[allow(clippy::too_many_arguments)]
+    async fn fetch(
+        &self,
+        id: &str,
+        job: &Job,
+        index: usize,
+        target: Target,
+        hf_token: &str,
+        deadline: Option<Instant>,
+        priority: Priority,
+    ) -> Result<FileState, AppError> {
+        let (repo, hash, size) = match target {
+            Target::Path { repo, file } => {
+                let listing = self.upstream.list(&repo, hf_token);
+                let files = self
+                    .within(deadline, self.backoff.guard(&repo.to_string(), listing))
+                    .await?;
+                self.catalog.record_listing(&repo, &files);
+                let listed = files.into_iter().find(|f| f.path == file).ok_or_else(|| {
+                    AppError::NotFound(format!("File '{}' not found or not XET-enabled", file))
+                })?;
+                (repo, listed.xet_hash, Some(listed.size))
+            }
+            Target::Hash { repo, hash } => {
+                let size = self.catalog.get(&hash).map(|entry| entry.size);
+                (repo, hash, size)
+            }
+            Target::Scope(scope) => {
+                return Err(AppError::Internal(format!("{} is not a file", scope.repo)));
+            }
+        };
+        self.update(id, index, |file| {
+            file.hash = Some(hash.clone());
+            file.size = size;
+        });
+        if let Some(size) = size {
+            job.add_total(size);
+        }
+        if let Some(cached) = self.cache.lookup(&hash).await {
+            if size.is_none() {
+                job.add_total(cached);
+            }
+            job.track(Source::Cache, Arc::new(AtomicU64::new(cached)));
+            self.update(id, index, |file| {
+                file.size = Some(cached);
+                file.bytes = cached;
+            });
+            return Ok(FileState::Cached);
+        }
+
+        // A nice job's download holds a slot like a client's, yielding
+        let _slot = match (&self.limiter, priority) {
+            (Some(limiter), Priority::Nice(_)) => Some(
+                self.within(deadline, async { Ok(limiter.wait(priority).await) })
+                    .await?,
+            ),
+            _ => None,
+        };
+        let download = self.upstream.download(DownloadRequest {
+            repo: &repo,
+            hash: &hash,
+            hf_token,
+            range: None,
+            length: size,
+            deadline,
+            priority,
+            spool: false,
+        });
+        let download = self
+            .within(deadline, self.backoff.guard(&repo.to_string(), download))
+            .await?;
+        self.update(id, index, |file| {
+            file.state = FileState::Downloading;
+            file.size = file.size.or(download.length);
+            file.received = Some(download.upstream_bytes.clone());
+        });
+        let announced = size.or(download.length);
+        if let (None, Some(length)) = (size, announced) {
+            job.add_total(length);
+        }
+        let received = download.upstream_bytes.clone();
+        job.track(Source::Upstream, received.clone());
+        let stored = self.cache.store(&hash, download).await;
+        // A size nobody announced is known once the file is in
+        if announced.is_none() && stored.is_ok() {
+            job.add_total(received.load(Ordering::Relaxed));
+        }
+        self.update(id, index, |file| {
+            if let Some(received) = file.received.take() {
+                file.bytes = received.load(Ordering::Relaxed);
+            }
+        });
+        match stored {
+            Ok(_) => Ok(FileState::Done),
+            Err(e) => {
+                warn!("Prefetch job {}: failed to cache {}: {}", id, hash, e);
+                Err(AppError::Internal(format!(
+                    "Failed to cache {}: {}",
+                    hash, e
+                )))
+            }
+        }
+    }
+
+    /// The repositories a scope matches and their files within its caps
+    async fn expand(&self, scope: &Scope, hf_token: &str) -> Result<Expansion, AppError> {
+        let repos = match scope.is_wildcard() {
+            true => self.search(&scope.repo, hf_token).await?,
+            false => vec![scope.repo.clone()],
+        };
+        let mut expansion = Expansion {
+            repos: repos.len(),
+            files: Vec::new(),
+            skipped: 0,
+            failed: Vec::new(),
+        };
+        let mut total = 0;
+        for repo in repos {
+            let listing = self.upstream.list(&repo, hf_token);
+            let files = match self.backoff.guard(&repo.to_string(), listing).await {
+                Ok(files) => files,
+                Err(e) => {
+                    expansion.failed.push((repo, e));
+                    continue;
+                }
+            };
+            self.catalog.record_listing(&repo, &files);
+            for file in files.into_iter().filter(|file| scope.covers(&file.path)) {
+                let too_large = scope.max_file_size.is_some_and(|max| file.size > max)
+                    || scope
+                        .max_total_size
+                        .is_some_and(|max| total + file.size > max);
+                if too_large {
+                    expansion.skipped += 1;
+                    continue;
+                }
+                total += file.size;
+                expansion
+                    .files
+                    .push((repo.clone(), file.path, file.xet_hash));
+            }
+        }
+        Ok(expansion)
+    }
+
+    /// Repositories of the scope's owner whose name matches its wildcard,
+    /// from the Hub's repository search
+    async fn search(&self, pattern: &RepoRef, hf_token: &str) -> Result<Vec<RepoRef>, AppError> {
+        let url = format!(
+            "{}/api/{}",
+            crate::hub::endpoint(),
+            pattern.repo_type.plural()
+        );
+        let query = [
+            ("author", pattern.owner.as_str()),
+            ("limit", SEARCH_PAGE_SIZE),
+        ];
+        let mut request = Some(self.http.get(&url).query(&query));
+        let mut repos = Vec::new();
+        // Paginated like the tree API
+        while let Some(page) = request.take() {
+            let response =
+                crate::xet::send(page.bearer_auth(hf_token), "Repository search").await?;
+            request = crate::xet::next_page(response.headers()).map(|next| self.http.get(next));
+            let hits: Vec<SearchHit> = response.json().await.map_err(|e| {
+                AppError::Internal(format!("Invalid repository search response: {}", e))
+            })?;
+            repos.extend(hits.into_iter().filter_map(|hit| {
+                let (owner, name) = hit.id.split_once('/')?;
+                (owner == pattern.owner && glob_match(&pattern.name, name)).then(|| RepoRef {
+                    repo_type: pattern.repo_type,
+                    owner: owner.to_string(),
+                    name: name.to_string(),
+                    revision: pattern.revision.clone(),
+                })
+            }));
+        }
+        Ok(repos)
+    }
+
+    /// Record how the `index`th scope expanded and queue its files; returns
+    /// how many of its repositories failed
+    fn add_expansion(
+        &self,
+        id: &str,
+        job: &Job,
+        index: usize,
+        expansion: Result<Expansion, AppError>,
+        fetches: &mut Vec<Option<Target>>,
+    ) -> usize {
+        let mut jobs = self.jobs.lock().unwrap();
+        let Some(status) = jobs.by_id.get_mut(id) else {
+            return 0;
+        };
+        let scope = &mut status.scopes[index];
+        let expansion = match expansion {
+            Ok(expansion) => expansion,
+            Err(e) => {
+                warn!(
+                    "Prefetch job {}: failed to expand {}: {}",
+                    id,
+                    scope.repo,
+                    e.message()
+                );
+                scope.state = ScopeState::Failed;
+                scope.error = Some(e.message().to_string());
+                return 1;
+            }
+        };
+        scope.state = ScopeState::Expanded;
+        scope.repos = expansion.repos;
+        scope.files = expansion.files.len();
+        scope.skipped = expansion.skipped;
+        info!(
+            "Prefetch job {}: {} expanded to {} repositories, {} files ({} skipped)",
+            id, scope.repo, expansion.repos, scope.files, expansion.skipped
+        );
+        let failed = expansion.failed.len();
+        job.add_files(expansion.files.len());
+        for (repo, e) in expansion.failed {
+            let mut file = FileStatus::pending(&repo, None, None);
+            file.state = FileState::Failed;
+            file.error = Some(e.message().to_string());
+            status.files.push(file);
+            fetches.push(None);
+        }
+        for (repo, path, hash) in expansion.files {
+            status
+                .files
+                .push(FileStatus::pending(&repo, Some(path), Some(hash.clone())));
+            fetches.push(Some(Target::Hash { repo, hash }));
+        }
+        failed
+    }
+
+    /// Await `op`, giving up at `deadline`
+    async fn within<T>(
+        &self,
+        deadline: Option<Instant>,
+        op: impl std::future::Future<Output = Result<T, AppError>>,
+    ) -> Result<T, AppError> {
+        match deadline {
+            Some(deadline) => tokio::time::timeout_at(deadline, op)
+                .await
+                .map_err(|_| AppError::Timeout("Prefetch exceeded the time budget".to_string()))?,
+            None => op.await,
+        }
+    }
+
+    fn update(&self, id: &str, index: usize, change: impl FnOnce(&mut FileStatus)) {
+        let mut jobs = self.jobs.lock().unwrap();
+        if let Some(file) = jobs
+            .by_id
+            .get_mut(id)
+            .and_then(|job| job.files.get_mut(index))
+        {
+            change(file);
+        }
+    }
+}
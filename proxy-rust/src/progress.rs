@@ -0,0 +1,352 @@
+//! Progress of long-running jobs
+//!
+//! Prefetch jobs and archive downloads register here under their job id
+//! (returned by `POST /prefetch`, and in the archive response's `X-Job-Id`
+//! header) and update their byte counts as they go. `GET
+//! /progress/:job_id` streams a job's progress as Server-Sent Events, one
+//! `progress` event every `PROGRESS_INTERVAL_MS` (default 1000), until an
+//! event reporting it `completed` or `failed` ends the stream:
+//!
+//! ```json
+//! {"job_id": "...", "kind": "archive", "state": "running", "bytes": 1073741824,
+//!  "total": 4294968320, "files": 4, "files_done": 1, "throughput_bps": 98304000,
+//!  "elapsed_ms": 10922, "cache_bytes": 268435456, "peer_bytes": 0,
+//!  "upstream_bytes": 805306368, "retries": 1}
+//! ```
+//!
+//! `total` counts the files whose size is known so far (`null` before the
+//! first): a prefetch job learns its files' sizes one at a time, so its
+//! total grows as it goes. Throughput is
+//! measured over the last interval; the final event gives the job's average.
+//! A completed archive also reports the `sha256` of what it sent.
+//!
+//! Events also break the job's bytes down by where they came from
+//! (`cache_bytes`, `peer_bytes` for a shared cache another proxy filled,
+//! `upstream_bytes`) and count the `retries` it needed; a finished job logs
+//! that breakdown with its average throughput.
+//! The last `MAX_FINISHED_JOBS` finished jobs stay queryable.
+
+use crate::shutdown::TakeUntil;
+use crate::transfer::{Breakdown, Source};
+use crate::AppError;
+use axum::response::sse::{Event, KeepAlive, Sse};
+use futures_core::Stream;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::future::Future;
+use std::hash::{BuildHasher, RandomState};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use tracing::info;
+
+const MAX_FINISHED_JOBS: usize = 1000;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum JobState {
+    Running,
+    Completed,
+    Failed,
+}
+
+/// One job's counters, updated by the handler running it
+pub struct Job {
+    id: String,
+    kind: &'static str,
+    started: Instant,
+    files: AtomicUsize,
+    files_done: AtomicUsize,
+    /// Bytes counted directly
+    bytes: AtomicU64,
+    /// Counters of downloads in flight, read when reporting, with where
+    /// they read from and whether they count towards `bytes`
+    sources: Mutex<Vec<(Source, Arc<AtomicU64>, bool)>>,
+    /// Retries of the job's upstream requests
+    retries: Arc<AtomicU64>,
+    total: Mutex<Option<u64>>,
+    /// Digest of what the job produced, for archives
+    sha256: Mutex<Option<String>>,
+    outcome: Mutex<Option<Outcome>>,
+}
+
+struct Outcome {
+    at: Instant,
+    error: Option<String>,
+}
+
+/// A job's progress, as sent in each event
+#[derive(Serialize)]
+pub struct JobProgress {
+    pub job_id: String,
+    pub kind: &'static str,
+    pub state: JobState,
+    pub bytes: u64,
+    pub total: Option<u64>,
+    pub files: usize,
+    pub files_done: usize,
+    pub throughput_bps: u64,
+    pub elapsed_ms: u64,
+    #[serde(flatten)]
+    pub breakdown: Breakdown,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sha256: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl Job {
+    /// Count `n` bytes transferred
+    pub fn add_bytes(&self, n: u64) {
+        self.bytes.fetch_add(n, Ordering::Relaxed);
+    }
+
+    /// Count the bytes of a download from `source` as its `counter` grows
+    pub fn track(&self, source: Source, counter: Arc<AtomicU64>) {
+        self.sources.lock().unwrap().push((source, counter, true));
+    }
+
+    /// Attribute the bytes of a download to `source` as its `counter`
+    /// grows, without counting them: for bytes counted with
+    /// [`Self::add_bytes`] as they are sent
+    pub fn attribute(&self, source: Source, counter: Arc<AtomicU64>) {
+        self.sources.lock().unwrap().push((source, counter, false));
+    }
+
+    /// Counter of the job's retries, for [`crate::retry::counted`]
+    pub fn retries(&self) -> Arc<AtomicU64> {
+        self.retries.clone()
+    }
+
+    /// Add `n` bytes to the expected total
+    pub fn add_total(&self, n: u64) {
+        let mut total = self.total.lock().unwrap();
+        *total = Some(total.unwrap_or(0) + n);
+    }
+
+    /// Take `n` bytes off the expected total, for work left out
+    pub fn sub_total(&self, n: u64) {
+        let mut total = self.total.lock().unwrap();
+        *total = total.map(|t| t.saturating_sub(n));
+    }
+
+    /// Count `n` more files, found once the job runs
+    pub fn add_files(&self, n: usize) {
+        self.files.fetch_add(n, Ordering::Relaxed);
+    }
+
+    /// Count one of the job's files as finished, successfully or not
+    pub fn file_done(&self) {
+        self.files_done.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Record the SHA-256 (hex) of what the job produced
+    pub fn set_sha256(&self, sha256: String) {
+        *self.sha256.lock().unwrap() = Some(sha256);
+    }
+
+    /// Record the job's outcome and log its breakdown; later calls are
+    /// ignored
+    pub fn finish(&self, result: Result<(), String>) {
+        {
+            let mut outcome = self.outcome.lock().unwrap();
+            if outcome.is_some() {
+                return;
+            }
+            *outcome = Some(Outcome {
+                at: Instant::now(),
+                error: result.err(),
+            });
+        }
+        let progress = self.progress(None);
+        let breakdown = progress.breakdown;
+        info!(
+            target: "transfer",
+            job_id = %self.id,
+            kind = self.kind,
+            state = ?progress.state,
+            bytes = progress.bytes,
+            cache_bytes = breakdown.cache_bytes,
+            peer_bytes = breakdown.peer_bytes,
+            upstream_bytes = breakdown.upstream_bytes,
+            duration_ms = progress.elapsed_ms,
+            throughput_bps = progress.throughput_bps,
+            retries = breakdown.retries,
+            "Job {} finished",
+            self.id
+        );
+    }
+
+    fn bytes(&self) -> u64 {
+        let sources = self.sources.lock().unwrap();
+        self.bytes.load(Ordering::Relaxed)
+            + sources
+                .iter()
+                .filter(|(_, _, counted)| *counted)
+                .map(|(_, counter, _)| counter.load(Ordering::Relaxed))
+                .sum::<u64>()
+    }
+
+    fn breakdown(&self) -> Breakdown {
+        let mut breakdown = Breakdown {
+            retries: self.retries.load(Ordering::Relaxed),
+            ..Breakdown::default()
+        };
+        for (source, counter, _) in self.sources.lock().unwrap().iter() {
+            breakdown.add(*source, counter.load(Ordering::Relaxed));
+        }
+        breakdown
+    }
+
+    fn finished_at(&self) -> Option<Instant> {
+        self.outcome
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map(|outcome| outcome.at)
+    }
+
+    /// Progress now, with throughput over the `interval` that moved
+    /// `recent` bytes (`None` for the average since the start)
+    fn progress(&self, recent: Option<(u64, Duration)>) -> JobProgress {
+        let bytes = self.bytes();
+        let outcome = self.outcome.lock().unwrap();
+        let (state, error, end) = match &*outcome {
+            None => (JobState::Running, None, Instant::now()),
+            Some(Outcome { at, error: None }) => (JobState::Completed, None, *at),
+            Some(Outcome { at, error }) => (JobState::Failed, error.clone(), *at),
+        };
+        let elapsed = end.duration_since(self.started);
+        let (moved, over) = match recent {
+            Some(recent) if state == JobState::Running => recent,
+            _ => (bytes, elapsed),
+        };
+        JobProgress {
+            job_id: self.id.clone(),
+            kind: self.kind,
+            state,
+            bytes,
+            total: *self.total.lock().unwrap(),
+            files: self.files.load(Ordering::Relaxed),
+            files_done: self.files_done.load(Ordering::Relaxed),
+            throughput_bps: if over.is_zero() {
+                0
+            } else {
+                (moved as f64 / over.as_secs_f64()) as u64
+            },
+            elapsed_ms: elapsed.as_millis() as u64,
+            breakdown: self.breakdown(),
+            sha256: self.sha256.lock().unwrap().clone(),
+            error,
+        }
+    }
+}
+
+/// The jobs, by id
+#[derive(Clone)]
+pub struct Progress {
+    interval: Duration,
+    jobs: Arc<Mutex<HashMap<String, Arc<Job>>>>,
+}
+
+impl Progress {
+    /// Load `PROGRESS_INTERVAL_MS` (default 1000)
+    pub fn from_env() -> Self {
+        let millis = crate::config::positive("PROGRESS_INTERVAL_MS").unwrap_or(1000);
+        Self {
+            interval: Duration::from_millis(millis),
+            jobs: Arc::default(),
+        }
+    }
+
+    /// Register a job of `files` files under `id`
+    pub fn start(&self, id: &str, kind: &'static str, files: usize) -> Arc<Job> {
+        let job = Arc::new(Job {
+            id: id.to_string(),
+            kind,
+            started: Instant::now(),
+            files: AtomicUsize::new(files),
+            files_done: AtomicUsize::new(0),
+            bytes: AtomicU64::new(0),
+            sources: Mutex::default(),
+            retries: Arc::default(),
+            total: Mutex::default(),
+            sha256: Mutex::default(),
+            outcome: Mutex::default(),
+        });
+        let mut jobs = self.jobs.lock().unwrap();
+        jobs.insert(id.to_string(), job.clone());
+        let mut finished: Vec<(Instant, String)> = jobs
+            .values()
+            .filter_map(|job| Some((job.finished_at()?, job.id.clone())))
+            .collect();
+        if finished.len() > MAX_FINISHED_JOBS {
+            finished.sort();
+            for (_, id) in &finished[..finished.len() - MAX_FINISHED_JOBS] {
+                jobs.remove(id);
+            }
+        }
+        job
+    }
+
+    /// Progress of the jobs still running
+    pub fn running(&self) -> Vec<JobProgress> {
+        let jobs = self.jobs.lock().unwrap();
+        jobs.values()
+            .map(|job| job.progress(None))
+            .filter(|progress| progress.state == JobState::Running)
+            .collect()
+    }
+
+    /// Server-Sent Events reporting the job's progress until it finishes,
+    /// or `until` resolves
+    pub fn sse(
+        &self,
+        id: &str,
+        until: impl Future<Output = ()> + Send + 'static,
+    ) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, AppError> {
+        let job = self
+            .jobs
+            .lock()
+            .unwrap()
+            .get(id)
+            .cloned()
+            .ok_or_else(|| AppError::NotFound(format!("Unknown job '{}'", id)))?;
+        let (events, receiver) = mpsc::channel(1);
+        let interval = self.interval;
+        tokio::spawn(async move {
+            let mut last: Option<(u64, Instant)> = None;
+            loop {
+                let now = Instant::now();
+                let recent = last.map(|(bytes, at)| (job.bytes().saturating_sub(bytes), now - at));
+                let progress = job.progress(recent);
+                last = Some((progress.bytes, now));
+                let finished = progress.state != JobState::Running;
+                let Ok(event) = Event::default().event("progress").json_data(&progress) else {
+                    return;
+                };
+                // The subscriber went away
+                if events.send(Ok(event)).await.is_err() || finished {
+                    return;
+                }
+                tokio::time::sleep(interval).await;
+            }
+        });
+        let stream = TakeUntil::new(ReceiverStream::new(receiver), until);
+        Ok(Sse::new(stream).keep_alive(KeepAlive::default()))
+    }
+}
+
+/// Unguessable job id, so one client can't watch another's jobs
+pub fn job_id() -> String {
+    static SEQUENCE: AtomicU64 = AtomicU64::new(0);
+    let state = RandomState::new();
+    format!(
+        "{:016x}{:016x}",
+        state.hash_one(SEQUENCE.fetch_add(1, Ordering::Relaxed)),
+        state.hash_one(std::process::id())
+    )
+}
@@ -0,0 +1,92 @@
+//! Machine-readable CLI output
+//!
+//! Every CLI invocation passes `--json` (see [`crate::subprocess::Cli`]),
+//! which makes `xet-download` print one JSON object per line, tagged with
+//! its `type`, instead of text meant for people. File contents are still
+//! written raw to stdout; `progress` and `error` messages go to stderr.
+//!
+//! Output that doesn't parse is an error, never skipped: a listing missing
+//! lines would turn into a `404` for a file that exists.
+
+use crate::upload::UploadResult;
+use crate::AppError;
+use serde::Deserialize;
+
+/// One line of CLI output
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Message {
+    /// XET-enabled file of a repository listing
+    File {
+        path: String,
+        size: u64,
+        xet_hash: String,
+    },
+    /// Chunk of a file's manifest, in file order
+    Chunk { hash: String, length: u64 },
+    /// Committed upload
+    Uploaded(UploadResult),
+    /// Bytes of a download written so far
+    Progress { bytes: u64 },
+    /// Worker job finished successfully
+    Done,
+    /// The command (or worker job) failed with this Zig error name
+    Error { error: String },
+}
+
+impl Message {
+    fn kind(&self) -> &'static str {
+        match self {
+            Message::File { .. } => "file",
+            Message::Chunk { .. } => "chunk",
+            Message::Uploaded(_) => "uploaded",
+            Message::Progress { .. } => "progress",
+            Message::Done => "done",
+            Message::Error { .. } => "error",
+        }
+    }
+
+    /// Error for a message that is valid but not expected in `context`
+    pub fn unexpected(&self, context: &str) -> AppError {
+        AppError::Internal(format!(
+            "Unexpected '{}' message in CLI {} output",
+            self.kind(),
+            context
+        ))
+    }
+}
+
+/// Parse one line of CLI output
+pub fn parse_line(line: &str) -> Result<Message, AppError> {
+    serde_json::from_str(line.trim())
+        .map_err(|e| AppError::Internal(format!("Unparseable CLI output ({}): {}", e, line)))
+}
+
+/// Parse all of a command's stdout, skipping blank lines
+pub fn parse_output(stdout: &str) -> Result<Vec<Message>, AppError> {
+    stdout
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(parse_line)
+        .collect()
+}
+
+/// Failure signature (`error: <Name>`) of an `error` message on stderr
+pub fn error_signature(line: &str) -> Option<String> {
+    match parse_line(line) {
+        Ok(Message::Error { error }) => Some(format!("error: {}", error)),
+        _ => None,
+    }
+}
+
+/// Bytes reported by a `progress` message on stderr
+pub fn progress_bytes(line: &str) -> Option<u64> {
+    // Cheap pre-check: progress arrives after every term of a download
+    if !line.contains("\"progress\"") {
+        return None;
+    }
+    match parse_line(line) {
+        Ok(Message::Progress { bytes }) => Some(bytes),
+        _ => None,
+    }
+}
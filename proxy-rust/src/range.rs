@@ -0,0 +1,383 @@
+//! HTTP byte range requests
+//!
+//! Single ranges (`bytes=a-b`, `bytes=a-` and `bytes=-n`) are served with
+//! `206 Partial Content`. A range is resolved against the listed file size
+//! and handed to the CLI, which asks CAS for the reconstruction terms
+//! overlapping it, so resuming a large download only fetches the XET chunks
+//! it still needs.
+//!
+//! Several ranges (`bytes=0-99,-100`, up to [`MAX_RANGES`]) of a cached file
+//! are served as one `multipart/byteranges` body (see [`Multipart`]), each
+//! part read from the cache. Ranges none of which is satisfiable get `416`,
+//! like a single one. An uncached file, like a malformed header, gets the
+//! whole file with `200`, as RFC 9110 allows: fetching the ranges one by
+//! one upstream would cost more than the file.
+
+use crate::downloader::ByteStream;
+use crate::AppError;
+use axum::body::Bytes;
+use axum::http::{header, HeaderMap};
+use std::hash::{BuildHasher, RandomState};
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use tokio_stream::StreamExt;
+
+/// Most ranges served from one request; more get the whole file
+pub const MAX_RANGES: usize = 16;
+
+/// A syntactically valid `Range` header, not yet checked against a size
+#[derive(Clone, Copy, Debug)]
+pub enum RangeSpec {
+    /// `bytes=<start>-[<end>]`
+    FromTo(u64, Option<u64>),
+    /// `bytes=-<length>`: the last `length` bytes
+    Suffix(u64),
+}
+
+impl RangeSpec {
+    /// Parse the request's `Range` header, if any and a single range
+    pub fn from_headers(headers: &HeaderMap) -> Option<Self> {
+        let spec = Self::header(headers)?;
+        if spec.contains(',') {
+            return None;
+        }
+        Self::parse(spec)
+    }
+
+    /// Parse a `Range` header of several ranges, if all of them are valid
+    pub fn list_from_headers(headers: &HeaderMap) -> Option<Vec<Self>> {
+        let specs = Self::header(headers)?.split(',');
+        let specs = specs.map(Self::parse).collect::<Option<Vec<_>>>()?;
+        (2..=MAX_RANGES).contains(&specs.len()).then_some(specs)
+    }
+
+    /// Value of the `Range` header, after its `bytes=` unit
+    fn header(headers: &HeaderMap) -> Option<&str> {
+        let value = headers.get(header::RANGE)?.to_str().ok()?;
+        value.trim().strip_prefix("bytes=")
+    }
+
+    /// One `<start>-[<end>]` or `-<length>` range
+    fn parse(spec: &str) -> Option<Self> {
+        let (start, end) = spec.split_once('-')?;
+        let (start, end) = (start.trim(), end.trim());
+        if start.is_empty() {
+            return end.parse().ok().map(RangeSpec::Suffix);
+        }
+        let start = start.parse().ok()?;
+        let end = match end {
+            "" => None,
+            end => Some(end.parse().ok()?),
+        };
+        if end.is_some_and(|end| end < start) {
+            return None;
+        }
+        Some(RangeSpec::FromTo(start, end))
+    }
+
+    /// Resolve against a file of `size` bytes
+    pub fn resolve(self, size: u64) -> Result<ByteRange, AppError> {
+        let last = size.checked_sub(1);
+        let (start, end) = match (self, last) {
+            (_, None) | (RangeSpec::Suffix(0), _) => {
+                return Err(AppError::RangeNotSatisfiable { size })
+            }
+            (RangeSpec::FromTo(start, end), Some(last)) => (start, end.unwrap_or(last).min(last)),
+            (RangeSpec::Suffix(length), Some(last)) => (size.saturating_sub(length), last),
+        };
+        if start > end {
+            return Err(AppError::RangeNotSatisfiable { size });
+        }
+        Ok(ByteRange { start, end, size })
+    }
+}
+
+/// Resolve several ranges against a file of `size` bytes, leaving out those
+/// past its end; `416` if that leaves none
+pub fn resolve_all(specs: &[RangeSpec], size: u64) -> Result<Vec<ByteRange>, AppError> {
+    let ranges: Vec<_> = specs
+        .iter()
+        .filter_map(|spec| spec.resolve(size).ok())
+        .collect();
+    if ranges.is_empty() {
+        return Err(AppError::RangeNotSatisfiable { size });
+    }
+    Ok(ranges)
+}
+
+/// A satisfiable range of a file (`end` inclusive, as in HTTP)
+#[derive(Clone, Copy, Debug)]
+pub struct ByteRange {
+    pub start: u64,
+    pub end: u64,
+    /// Size of the whole file
+    pub size: u64,
+}
+
+impl ByteRange {
+    pub fn len(&self) -> u64 {
+        self.end - self.start + 1
+    }
+
+    /// `Content-Range` header value
+    pub fn content_range(&self) -> String {
+        format!("bytes {}-{}/{}", self.start, self.end, self.size)
+    }
+
+    /// Range argument understood by the CLI
+    pub fn cli_arg(&self) -> String {
+        format!("{}-{}", self.start, self.end)
+    }
+}
+
+/// Layout of a `multipart/byteranges` body: each range is preceded by a
+/// header naming it, and the body ends with the closing boundary
+pub struct Multipart {
+    boundary: String,
+    content_type: &'static str,
+    ranges: Vec<ByteRange>,
+}
+
+impl Multipart {
+    /// Parts of a file of type `content_type`, in the order requested
+    pub fn new(ranges: Vec<ByteRange>, content_type: &'static str) -> Self {
+        static SEQUENCE: AtomicU64 = AtomicU64::new(0);
+        let boundary = format!(
+            "{:016x}",
+            RandomState::new().hash_one(SEQUENCE.fetch_add(1, Ordering::Relaxed))
+        );
+        Self {
+            boundary,
+            content_type,
+            ranges,
+        }
+    }
+
+    pub fn ranges(&self) -> &[ByteRange] {
+        &self.ranges
+    }
+
+    /// `Content-Type` header value of the response
+    pub fn content_type(&self) -> String {
+        format!("multipart/byteranges; boundary={}", self.boundary)
+    }
+
+    /// Length of the whole body
+    pub fn len(&self) -> u64 {
+        let parts = self.ranges.iter().enumerate();
+        let parts: u64 = parts
+            .map(|(i, range)| self.part_header(i).len() as u64 + range.len())
+            .sum();
+        parts + self.trailer().len() as u64
+    }
+
+    /// Body from the streams of each range, counting the bytes sent in
+    /// `sent`
+    pub fn body(&self, parts: Vec<ByteStream>, sent: Arc<AtomicU64>) -> ByteStream {
+        let mut body: ByteStream = Box::pin(tokio_stream::empty());
+        for (i, part) in parts.into_iter().enumerate() {
+            let header = tokio_stream::once(Ok(Bytes::from(self.part_header(i))));
+            body = Box::pin(body.chain(header).chain(part));
+        }
+        let trailer = tokio_stream::once(Ok(Bytes::from(self.trailer())));
+        Box::pin(body.chain(trailer).map(move |chunk: io::Result<Bytes>| {
+            if let Ok(chunk) = &chunk {
+                sent.fetch_add(chunk.len() as u64, Ordering::Relaxed);
+            }
+            chunk
+        }))
+    }
+
+    /// Boundary and headers before the `i`th range
+    fn part_header(&self, i: usize) -> String {
+        format!(
+            "{}--{}\r\nContent-Type: {}\r\nContent-Range: {}\r\n\r\n",
+            if i == 0 { "" } else { "\r\n" },
+            self.boundary,
+            self.content_type,
+            self.ranges[i].content_range()
+        )
+    }
+
+    fn trailer(&self) -> String {
+        format!("\r\n--{}--\r\n", self.boundary)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn spec(value: &str) -> Option<RangeSpec> {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::RANGE, value.parse().unwrap());
+        RangeSpec::from_headers(&headers)
+    }
+
+    /// `(start, end)` of the range served for `value` on a file of `size` bytes
+    fn resolved(value: &str, size: u64) -> Option<(u64, u64)> {
+        let range = spec(value)
+            .expect("range should parse")
+            .resolve(size)
+            .ok()?;
+        Some((range.start, range.end))
+    }
+
+    #[test]
+    fn bounded_and_open_ended_ranges() {
+        assert_eq!(resolved("bytes=0-0", 10), Some((0, 0)));
+        assert_eq!(resolved("bytes=2-5", 10), Some((2, 5)));
+        assert_eq!(resolved("bytes=5-", 10), Some((5, 9)));
+        assert_eq!(resolved(" bytes= 3 - 4 ", 10), Some((3, 4)));
+
+        let range = spec("bytes=2-5").unwrap().resolve(10).ok().unwrap();
+        assert_eq!(range.len(), 4);
+        assert_eq!(range.content_range(), "bytes 2-5/10");
+        assert_eq!(range.cli_arg(), "2-5");
+    }
+
+    #[test]
+    fn end_is_clamped_to_the_file() {
+        assert_eq!(resolved("bytes=5-100", 10), Some((5, 9)));
+        assert_eq!(resolved("bytes=9-9", 10), Some((9, 9)));
+        assert_eq!(resolved("bytes=0-18446744073709551615", 10), Some((0, 9)));
+    }
+
+    #[test]
+    fn suffix_ranges_take_the_end_of_the_file() {
+        assert_eq!(resolved("bytes=-3", 10), Some((7, 9)));
+        assert_eq!(resolved("bytes=-10", 10), Some((0, 9)));
+        // A suffix longer than the file is the whole file
+        assert_eq!(resolved("bytes=-100", 10), Some((0, 9)));
+    }
+
+    #[test]
+    fn unsatisfiable_ranges_are_416() {
+        for (value, size) in [
+            ("bytes=10-", 10),
+            ("bytes=10-20", 10),
+            ("bytes=11-", 10),
+            ("bytes=-0", 10),
+            ("bytes=0-", 0),
+            ("bytes=-5", 0),
+        ] {
+            let result = spec(value).unwrap().resolve(size);
+            assert!(
+                matches!(result, Err(AppError::RangeNotSatisfiable { size: s }) if s == size),
+                "{value} on {size} bytes"
+            );
+        }
+    }
+
+    fn specs(value: &str) -> Option<Vec<RangeSpec>> {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::RANGE, value.parse().unwrap());
+        RangeSpec::list_from_headers(&headers)
+    }
+
+    #[test]
+    fn several_ranges_are_listed_in_order() {
+        let ranges = resolve_all(&specs("bytes=5-6, 0-1,-2").unwrap(), 10).unwrap();
+        let ranges: Vec<_> = ranges.iter().map(|r| (r.start, r.end)).collect();
+        assert_eq!(ranges, [(5, 6), (0, 1), (8, 9)]);
+
+        for value in [
+            "bytes=0-1",
+            "bytes=0-1,x",
+            "bytes=0-1,",
+            "bytes=0-1,5-2",
+            "items=0-1,2-3",
+        ] {
+            assert!(specs(value).is_none(), "{value}");
+        }
+        let most = vec!["0-0"; MAX_RANGES].join(",");
+        assert_eq!(specs(&format!("bytes={most}")).unwrap().len(), MAX_RANGES);
+        assert!(specs(&format!("bytes={most},1-1")).is_none());
+    }
+
+    #[test]
+    fn ranges_past_the_end_are_left_out() {
+        let ranges = resolve_all(&specs("bytes=20-30,2-3,-0").unwrap(), 10).unwrap();
+        assert_eq!(ranges.len(), 1);
+        assert_eq!((ranges[0].start, ranges[0].end), (2, 3));
+
+        let result = resolve_all(&specs("bytes=20-30,10-").unwrap(), 10);
+        assert!(matches!(
+            result,
+            Err(AppError::RangeNotSatisfiable { size: 10 })
+        ));
+    }
+
+    #[tokio::test]
+    async fn multipart_body_frames_every_range() {
+        let file = b"0123456789";
+        let ranges = resolve_all(&specs("bytes=1-2,-3").unwrap(), 10).unwrap();
+        let multipart = Multipart::new(ranges, "text/plain");
+        let boundary = multipart
+            .content_type()
+            .strip_prefix("multipart/byteranges; boundary=")
+            .unwrap()
+            .to_string();
+        let parts = multipart
+            .ranges()
+            .iter()
+            .map(|r| {
+                let piece = Bytes::copy_from_slice(&file[r.start as usize..=r.end as usize]);
+                Box::pin(tokio_stream::iter([Ok(piece)])) as ByteStream
+            })
+            .collect();
+        let sent = Arc::new(AtomicU64::new(0));
+        let mut body = multipart.body(parts, sent.clone());
+        let mut text = Vec::new();
+        while let Some(chunk) = body.next().await {
+            text.extend_from_slice(&chunk.unwrap());
+        }
+
+        let expected = format!(
+            "--{b}\r\nContent-Type: text/plain\r\nContent-Range: bytes 1-2/10\r\n\r\n12\r\n\
+             --{b}\r\nContent-Type: text/plain\r\nContent-Range: bytes 7-9/10\r\n\r\n789\r\n\
+             --{b}--\r\n",
+            b = boundary
+        );
+        assert_eq!(String::from_utf8(text).unwrap(), expected);
+        assert_eq!(multipart.len(), expected.len() as u64);
+        assert_eq!(sent.load(Ordering::Relaxed), expected.len() as u64);
+        assert_ne!(
+            Multipart::new(vec![], "text/plain").content_type(),
+            multipart.content_type()
+        );
+    }
+
+    #[tokio::test]
+    async fn failing_part_fails_the_body() {
+        let range = spec("bytes=0-3").unwrap().resolve(10).unwrap();
+        let multipart = Multipart::new(vec![range], "text/plain");
+        let part: ByteStream = Box::pin(tokio_stream::iter([Err(io::Error::other("gone"))]));
+        let mut body = multipart.body(vec![part], Arc::new(AtomicU64::new(0)));
+        assert!(body.next().await.unwrap().is_ok());
+        assert!(body.next().await.unwrap().is_err());
+    }
+
+    #[test]
+    fn multi_range_and_malformed_headers_are_ignored() {
+        for value in [
+            "bytes=0-1,5-6",
+            "bytes=0-1, -2",
+            "items=0-1",
+            "bytes 0-1",
+            "bytes=",
+            "bytes=5",
+            "bytes=-",
+            "bytes=a-b",
+            "bytes=1-x",
+            "bytes=--1",
+            "bytes=5-2",
+            "bytes=-1-2",
+            "bytes=18446744073709551616-",
+        ] {
+            assert!(spec(value).is_none(), "{value}");
+        }
+        assert!(RangeSpec::from_headers(&HeaderMap::new()).is_none());
+    }
+}
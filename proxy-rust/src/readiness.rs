@@ -0,0 +1,193 @@
+//! Liveness and readiness probes
+//!
+//! `/healthz` answers as long as the process serves requests, for liveness
+//! probes. `/readyz` answers 200 only when the proxy can actually serve
+//! downloads, and 503 otherwise, with the outcome of each check:
+//!
+//! - `downloader`: the download engine runs (the CLI answers `--version`);
+//! - `hub_token`: the Hub accepts `HF_TOKEN` (skipped without it, when
+//!   clients bring their own tokens);
+//! - `cache`: the cache directory or bucket is writable (skipped without
+//!   one).
+//!
+//! ```json
+//! {"status": "not_ready", "checks": {
+//!   "cache": {"status": "ok", "detail": "/var/cache/xet is writable", "duration_ms": 0},
+//!   "downloader": {"status": "ok", "detail": "zig-out/bin/xet runs: 0.3.1", "duration_ms": 4},
+//!   "hub_token": {"status": "failed", "detail": "rejected by the Hub (401)", "duration_ms": 212}}}
+//! ```
+//!
+//! Each check has `READINESS_TIMEOUT_MS` (default 2000) to pass. Results are
+//! reused for `READINESS_CACHE_SECS` (default 10, 0 to check every time), so
+//! frequent probes don't turn into as many Hub requests.
+
+use crate::cache::Cache;
+use crate::downloader::Downloader;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+use tracing::warn;
+
+#[derive(Clone, Copy, PartialEq, Eq, Serialize, utoipa::ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum CheckStatus {
+    Ok,
+    Failed,
+    Skipped,
+}
+
+/// Outcome of one check
+#[derive(Clone, Serialize, utoipa::ToSchema)]
+pub struct Check {
+    pub status: CheckStatus,
+    pub detail: String,
+    pub duration_ms: u64,
+}
+
+#[derive(Clone, Serialize, utoipa::ToSchema)]
+pub struct ReadyReport {
+    pub status: &'static str,
+    pub checks: BTreeMap<&'static str, Check>,
+}
+
+impl ReadyReport {
+    pub fn ready(&self) -> bool {
+        self.checks
+            .values()
+            .all(|check| check.status != CheckStatus::Failed)
+    }
+}
+
+#[derive(Clone)]
+pub struct Readiness {
+    hf_token: Option<Arc<str>>,
+    http: reqwest::Client,
+    timeout: Duration,
+    ttl: Duration,
+    /// The latest report; locked while checking, so concurrent probes share
+    /// one round of checks
+    last: Arc<Mutex<Option<(Instant, ReadyReport)>>>,
+}
+
+impl Readiness {
+    /// Load `READINESS_TIMEOUT_MS` and `READINESS_CACHE_SECS`
+    pub fn from_env() -> Self {
+        let millis = crate::config::positive("READINESS_TIMEOUT_MS").unwrap_or(2000);
+        let ttl = crate::config::number("READINESS_CACHE_SECS").unwrap_or(10);
+        let timeout = Duration::from_millis(millis);
+        let http = crate::trust::apply(reqwest::Client::builder())
+            .timeout(timeout)
+            .user_agent(concat!("xet-proxy/", env!("CARGO_PKG_VERSION")))
+            .build()
+            .expect("Failed to build HTTP client");
+        Self {
+            hf_token: std::env::var("HF_TOKEN").ok().map(Into::into),
+            http,
+            timeout,
+            ttl: Duration::from_secs(ttl),
+            last: Arc::default(),
+        }
+    }
+
+    /// Run the checks, unless a recent report can be reused
+    pub async fn check(&self, downloader: &dyn Downloader, cache: Option<&Cache>) -> ReadyReport {
+        let mut last = self.last.lock().await;
+        if let Some((at, report)) = &*last {
+            if at.elapsed() < self.ttl {
+                return report.clone();
+            }
+        }
+        let (engine, token, writable) = tokio::join!(
+            self.timed(async { Some(downloader.check().await) }),
+            self.timed(async {
+                let token = self.hf_token.as_deref()?;
+                Some(whoami(&self.http, token).await)
+            }),
+            self.timed(async { Some(cache?.check_writable().await) }),
+        );
+        let checks = BTreeMap::from([
+            ("downloader", engine.into_check("no download engine")),
+            (
+                "hub_token",
+                token.into_check("HF_TOKEN is not set; clients send their own"),
+            ),
+            ("cache", writable.into_check("no cache is configured")),
+        ]);
+        let mut report = ReadyReport {
+            status: "ready",
+            checks,
+        };
+        if !report.ready() {
+            report.status = "not_ready";
+            for (name, check) in &report.checks {
+                if check.status == CheckStatus::Failed {
+                    warn!("Not ready: {} check failed: {}", name, check.detail);
+                }
+            }
+        }
+        *last = Some((Instant::now(), report.clone()));
+        report
+    }
+
+    /// Run one check within the timeout; `None` from it means skipped
+    async fn timed(&self, check: impl Future<Output = Option<Result<String, String>>>) -> Timed {
+        let started = Instant::now();
+        let outcome = tokio::time::timeout(self.timeout, check)
+            .await
+            .unwrap_or_else(|_| Some(Err(format!("no result within {:?}", self.timeout))));
+        Timed {
+            outcome,
+            duration_ms: started.elapsed().as_millis() as u64,
+        }
+    }
+}
+
+struct Timed {
+    outcome: Option<Result<String, String>>,
+    duration_ms: u64,
+}
+
+impl Timed {
+    /// The check's outcome, `skipped` saying why it didn't apply
+    fn into_check(self, skipped: &str) -> Check {
+        let (status, detail) = match self.outcome {
+            Some(Ok(detail)) => (CheckStatus::Ok, detail),
+            Some(Err(e)) => (CheckStatus::Failed, e),
+            None => (CheckStatus::Skipped, skipped.to_string()),
+        };
+        Check {
+            status,
+            detail,
+            duration_ms: self.duration_ms,
+        }
+    }
+}
+
+/// Whether the Hub accepts `token`, and for which account
+pub async fn whoami(http: &reqwest::Client, token: &str) -> Result<String, String> {
+    let url = format!("{}/api/whoami-v2", crate::hub::endpoint());
+    let response = http
+        .get(&url)
+        .bearer_auth(token)
+        .send()
+        .await
+        .map_err(|e| format!("Hub unreachable: {}", e))?;
+    match response.status() {
+        status if status.is_success() => {
+            let name = response
+                .json::<serde_json::Value>()
+                .await
+                .ok()
+                .and_then(|user| user["name"].as_str().map(str::to_string));
+            Ok(format!(
+                "valid, for {}",
+                name.as_deref().unwrap_or("an unnamed account")
+            ))
+        }
+        reqwest::StatusCode::UNAUTHORIZED => Err("rejected by the Hub (401)".to_string()),
+        status => Err(format!("Hub answered {}", status)),
+    }
+}
@@ -0,0 +1,227 @@
+//! `xet-proxy replay <log file> [--download]`
+//!
+//! Replays the downloads recorded in a JSON log (`LOG_FORMAT=json`) against
+//! the backend configured now, for regression hunting after an upgrade: each
+//! completed `transfer` record names the repository, revision and path (or
+//! hash) a file was requested as, and the hash and bytes it was served
+//! with. A file requested by path is resolved again through a fresh listing,
+//! a file requested by hash is sized again; with `--download` it is also
+//! downloaded and hashed (see [`crate::integrity`]). Any difference with the
+//! record is reported, and the command exits non-zero if there was one.
+//!
+//! The same file recorded many times is replayed once. Ranged and
+//! interrupted transfers are skipped: their records don't describe a whole
+//! file. The cache is bypassed, and requests use `HF_TOKEN`.
+
+use crate::backoff::UpstreamBackoff;
+use crate::downloader::{DownloadRequest, Downloader};
+use crate::integrity::FileHasher;
+use crate::repo::RepoRef;
+use crate::retry::RetryPolicy;
+use crate::AppError;
+use serde::Deserialize;
+use std::collections::HashSet;
+use std::io::BufRead;
+use tokio_stream::StreamExt;
+
+/// The fields of a `transfer` record replaying needs
+#[derive(Deserialize)]
+struct Fields {
+    outcome: String,
+    hash: String,
+    repo: Option<String>,
+    revision: Option<String>,
+    path: Option<String>,
+    #[serde(default)]
+    ranged: bool,
+    client_bytes: u64,
+}
+
+#[derive(Deserialize)]
+struct Line {
+    target: String,
+    fields: Fields,
+}
+
+/// A whole-file transfer as recorded
+struct Recorded {
+    repo: String,
+    revision: String,
+    path: Option<String>,
+    hash: String,
+    bytes: u64,
+}
+
+impl Recorded {
+    /// The completed whole-file transfer `line` records, if it does
+    fn parse(line: &str) -> Option<Self> {
+        let line: Line = serde_json::from_str(line).ok()?;
+        let fields = line.fields;
+        if line.target != "transfer" || fields.outcome != "completed" || fields.ranged {
+            return None;
+        }
+        Some(Self {
+            repo: fields.repo?,
+            revision: fields.revision?,
+            path: fields.path,
+            hash: fields.hash,
+            bytes: fields.client_bytes,
+        })
+    }
+
+    fn describe(&self) -> String {
+        let file = self.path.as_deref().unwrap_or(&self.hash);
+        format!("{}@{} {}", self.repo, self.revision, file)
+    }
+}
+
+/// Hash and size of a file now, as far as they could be learned
+struct Replayed {
+    hash: String,
+    size: Option<u64>,
+}
+
+/// Run the subcommand; the process exit code
+pub async fn run(args: &[&str]) -> i32 {
+    let (log, download) = match args {
+        [log] => (*log, false),
+        [log, "--download"] | ["--download", log] => (*log, true),
+        _ => {
+            eprintln!("Usage: xet-proxy replay <log file> [--download]");
+            return 2;
+        }
+    };
+    let file = match std::fs::File::open(log) {
+        Ok(file) => file,
+        Err(e) => {
+            eprintln!("{}: {}", log, e);
+            return 2;
+        }
+    };
+
+    let mut seen = HashSet::new();
+    let mut records = Vec::new();
+    let mut skipped = 0;
+    for line in std::io::BufReader::new(file).lines() {
+        let line = match line {
+            Ok(line) => line,
+            Err(e) => {
+                eprintln!("{}: {}", log, e);
+                return 2;
+            }
+        };
+        if !line.contains("\"transfer\"") {
+            continue;
+        }
+        match Recorded::parse(&line) {
+            Some(record) if seen.insert(record.describe() + record.hash.as_str()) => {
+                records.push(record)
+            }
+            Some(_) => {}
+            None => skipped += 1,
+        }
+    }
+
+    let downloader = crate::downloader_from_env(
+        UpstreamBackoff::from_env(),
+        RetryPolicy::from_env(),
+        crate::capabilities::Capabilities::default(),
+    );
+    let token = std::env::var("HF_TOKEN").unwrap_or_default();
+    let mut differences = 0;
+    for record in &records {
+        let outcome = match replay(downloader.as_ref(), &token, record, download).await {
+            Ok(replayed) => compare(record, &replayed),
+            Err(e) => Some(e.message().to_string()),
+        };
+        match outcome {
+            None => println!(
+                "ok        {} ({}, {} bytes)",
+                record.describe(),
+                record.hash,
+                record.bytes
+            ),
+            Some(difference) => {
+                differences += 1;
+                println!("DIFFERS   {}: {}", record.describe(), difference);
+            }
+        }
+    }
+    println!(
+        "{} replayed, {} differ, {} records skipped (ranged, interrupted or without a repository)",
+        records.len(),
+        differences,
+        skipped
+    );
+    i32::from(differences > 0)
+}
+
+/// Resolve, size or download `record`'s file through `downloader`
+async fn replay(
+    downloader: &dyn Downloader,
+    token: &str,
+    record: &Recorded,
+    download: bool,
+) -> Result<Replayed, AppError> {
+    let repo = RepoRef::parse(&record.repo, Some(record.revision.clone()))
+        .ok_or_else(|| AppError::BadRequest(format!("Invalid repository '{}'", record.repo)))?;
+    let mut replayed = match &record.path {
+        Some(path) => {
+            let files = downloader.list(&repo, token).await?;
+            let listed = files
+                .into_iter()
+                .find(|f| &f.path == path)
+                .ok_or_else(|| AppError::NotFound(format!("'{}' is no longer listed", path)))?;
+            Replayed {
+                hash: listed.xet_hash,
+                size: Some(listed.size),
+            }
+        }
+        None => Replayed {
+            hash: record.hash.clone(),
+            size: downloader.file_size(&repo, &record.hash, token).await?,
+        },
+    };
+    if !download {
+        return Ok(replayed);
+    }
+
+    let started = downloader
+        .download(DownloadRequest {
+            repo: &repo,
+            hash: &replayed.hash,
+            hf_token: token,
+            range: None,
+            length: replayed.size,
+            deadline: None,
+            priority: Default::default(),
+            spool: false,
+        })
+        .await?;
+    let mut body = started.body;
+    let mut hasher = FileHasher::default();
+    let mut bytes = 0;
+    while let Some(chunk) = body.next().await {
+        let chunk = chunk.map_err(|e| AppError::Internal(format!("Download failed: {}", e)))?;
+        hasher.update(&chunk);
+        bytes += chunk.len() as u64;
+    }
+    replayed.hash = hasher.finish();
+    replayed.size = Some(bytes);
+    Ok(replayed)
+}
+
+/// What differs between the record and the replay, if anything
+fn compare(record: &Recorded, replayed: &Replayed) -> Option<String> {
+    let mut differences = Vec::new();
+    if replayed.hash != record.hash {
+        differences.push(format!("hash {} -> {}", record.hash, replayed.hash));
+    }
+    match replayed.size {
+        Some(size) if size != record.bytes => {
+            differences.push(format!("size {} -> {} bytes", record.bytes, size))
+        }
+        _ => {}
+    }
+    (!differences.is_empty()).then(|| differences.join(", "))
+}
@@ -0,0 +1,212 @@
+//! Repository references
+//!
+//! A [`RepoRef`] names a repository on the Hub together with its type and
+//! the revision to read. Besides the default `/download/:owner/:repo/*file`
+//! (a model on `main`), download routes accept the Hub's own URL scheme:
+//!
+//! ```text
+//! /download/{models|datasets|spaces}/:owner/:repo/resolve/:revision/*file
+//! ```
+//!
+//! The Hub reserves `models`, `datasets` and `spaces` as account names, so
+//! the two forms never collide. Revisions may be a branch, tag or commit;
+//! a revision containing `/` (e.g. `refs/pr/1`) is sent URL-encoded.
+
+use crate::AppError;
+use serde::Deserialize;
+use std::fmt;
+
+pub const DEFAULT_REVISION: &str = "main";
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, Deserialize, utoipa::ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum RepoType {
+    #[default]
+    Model,
+    Dataset,
+    Space,
+}
+
+impl RepoType {
+    /// Parse the plural form used in Hub URLs (`models`, `datasets`, `spaces`)
+    pub fn from_plural(s: &str) -> Option<Self> {
+        match s {
+            "models" => Some(RepoType::Model),
+            "datasets" => Some(RepoType::Dataset),
+            "spaces" => Some(RepoType::Space),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            RepoType::Model => "model",
+            RepoType::Dataset => "dataset",
+            RepoType::Space => "space",
+        }
+    }
+
+    /// Path segment of the Hub API (`/api/<plural>/...`)
+    pub fn plural(self) -> &'static str {
+        match self {
+            RepoType::Model => "models",
+            RepoType::Dataset => "datasets",
+            RepoType::Space => "spaces",
+        }
+    }
+}
+
+/// A repository at a revision
+#[derive(Clone, Debug)]
+pub struct RepoRef {
+    pub repo_type: RepoType,
+    pub owner: String,
+    pub name: String,
+    pub revision: String,
+}
+
+impl RepoRef {
+    /// A model repository on the default branch
+    pub fn model(owner: String, name: String) -> Self {
+        Self {
+            repo_type: RepoType::Model,
+            owner,
+            name,
+            revision: DEFAULT_REVISION.to_string(),
+        }
+    }
+
+    /// Parse `owner/name` (a model) or `<type>s/owner/name`, the form
+    /// [`Display`](fmt::Display) produces
+    pub fn parse(spec: &str, revision: Option<String>) -> Option<Self> {
+        let parts: Vec<&str> = spec.split('/').collect();
+        let (repo_type, owner, name) = match parts[..] {
+            [owner, name] => (RepoType::Model, owner, name),
+            [repo_type, owner, name] => (RepoType::from_plural(repo_type)?, owner, name),
+            _ => return None,
+        };
+        let revision = revision.unwrap_or_else(|| DEFAULT_REVISION.to_string());
+        if owner.is_empty() || name.is_empty() || revision.is_empty() {
+            return None;
+        }
+        Some(Self {
+            repo_type,
+            owner: owner.to_string(),
+            name: name.to_string(),
+            revision,
+        })
+    }
+
+    /// `owner/name`, as the Hub APIs take it
+    pub fn id(&self) -> String {
+        format!("{}/{}", self.owner, self.name)
+    }
+
+    /// Whether the revision is a full commit id, which never moves
+    pub fn pinned(&self) -> bool {
+        self.revision.len() == 40 && self.revision.bytes().all(|b| b.is_ascii_hexdigit())
+    }
+
+    /// The revision as a single URL path segment
+    pub fn revision_segment(&self) -> String {
+        encode_segment(&self.revision)
+    }
+
+    /// URL of `file` on the Hub website (see [`crate::hub`])
+    pub fn upstream_url(&self, file: &str) -> String {
+        let prefix = match self.repo_type {
+            RepoType::Model => String::new(),
+            other => format!("{}/", other.plural()),
+        };
+        format!(
+            "{}/{}{}/resolve/{}/{}",
+            crate::hub::endpoint(),
+            prefix,
+            self.id(),
+            self.revision_segment(),
+            file
+        )
+    }
+
+    /// Parse a typed download path: `repo_type` is the plural type segment
+    /// and `raw_rest` the remaining `<owner>/<repo>/resolve/<revision>/<file>`,
+    /// still percent-encoded. Returns `None` if `repo_type` is not a type.
+    pub fn from_typed_path(
+        repo_type: &str,
+        raw_rest: &str,
+    ) -> Option<Result<(Self, String), AppError>> {
+        let repo_type = RepoType::from_plural(repo_type)?;
+        let mut parts = raw_rest.splitn(5, '/');
+        let parsed = (|| {
+            let owner = percent_decode(parts.next()?)?;
+            let name = percent_decode(parts.next()?)?;
+            if parts.next()? != "resolve" {
+                return None;
+            }
+            let revision = percent_decode(parts.next()?)?;
+            let file = percent_decode(parts.next()?)?;
+            if [&owner, &name, &revision, &file]
+                .iter()
+                .any(|s| s.is_empty())
+            {
+                return None;
+            }
+            Some((
+                Self {
+                    repo_type,
+                    owner,
+                    name,
+                    revision,
+                },
+                file,
+            ))
+        })();
+        Some(parsed.ok_or_else(|| {
+            AppError::BadRequest(format!(
+                "Expected /download/{}/<owner>/<repo>/resolve/<revision>/<file>",
+                repo_type.plural()
+            ))
+        }))
+    }
+}
+
+/// `owner/name` for models, `<type>s/owner/name` otherwise, as in Hub URLs
+impl fmt::Display for RepoRef {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.repo_type {
+            RepoType::Model => write!(f, "{}/{}", self.owner, self.name),
+            other => write!(f, "{}/{}/{}", other.plural(), self.owner, self.name),
+        }
+    }
+}
+
+/// Percent-encode everything but unreserved characters
+fn encode_segment(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for byte in s.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                out.push(byte as char)
+            }
+            _ => out.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    out
+}
+
+fn percent_decode(s: &str) -> Option<String> {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = std::str::from_utf8(bytes.get(i + 1..i + 3)?).ok()?;
+            out.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            out.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(out).ok()
+}
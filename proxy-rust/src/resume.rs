@@ -0,0 +1,417 @@
+//! Priority for resumed transfers
+//!
+//! A download the client abandoned part way is remembered for
+//! `RESUME_PRIORITY_SECS` (default 600, `0` disables), keyed by client and
+//! hash, with how far it got. A `Range` request by the same client for the
+//! same hash that starts within the delivered bytes continues that transfer
+//! and gets [`Priority::Resumed`]: where downloads wait for capacity, it goes
+//! ahead of fresh ones, so an interrupted user finishes quickly instead of
+//! rejoining the back of the queue.
+//!
+//! Clients are told apart by their token, which is only kept as a keyed
+//! hash.
+//!
+//! Behind a load balancer the resume may reach another replica, which knows
+//! nothing of the first transfer. With `RESUME_SECRET` set (the same on
+//! every replica), each file response that supports ranges carries an
+//! `X-Resume-Token`: a token signed with the secret naming the hash, the
+//! repository, revision and path it was listed as, the file size and the
+//! offset the response starts at, bound to the client's HuggingFace token
+//! and valid for `RESUME_PRIORITY_SECS`. Sent back with a `Range` starting
+//! past that offset, it makes the request a prioritized resume on any
+//! replica, once per token and replica, and tells a replica that never
+//! listed the file its size, so a hash download resumes as a range there
+//! too.
+
+use crate::catalog::CatalogEntry;
+use crate::range::ByteRange;
+use crate::repo::RepoRef;
+use crate::slots::Priority;
+use jsonwebtoken::{Algorithm, DecodingKey, EncodingKey, Header, Validation};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::hash::{BuildHasher, RandomState};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tracing::debug;
+
+/// Entries kept at most; expired ones are dropped first
+const MAX_ENTRIES: usize = 10_000;
+
+/// Client identity for matching resumes
+pub type ClientKey = u64;
+
+/// When a transfer was abandoned, and how many bytes of the file had been
+/// delivered by then
+#[derive(Clone, Copy)]
+struct Aborted {
+    at: Instant,
+    delivered: u64,
+}
+
+/// What a resumption token vouches for
+#[derive(Serialize, Deserialize)]
+pub struct ResumeClaims {
+    pub hash: String,
+    /// Repository the file was served from, `owner/repo` or
+    /// `<type>s/owner/repo`
+    repo: String,
+    revision: String,
+    /// Path the content was listed as, if known
+    path: Option<String>,
+    /// Size of the whole file
+    pub size: u64,
+    /// First byte of the response the token came with
+    pub offset: u64,
+    /// Keyed hash of the client's HuggingFace token
+    client: String,
+    exp: u64,
+}
+
+impl ResumeClaims {
+    /// Where the content was listed, if the token names its path
+    pub fn listing(&self) -> Option<CatalogEntry> {
+        Some(CatalogEntry {
+            repo: RepoRef::parse(&self.repo, Some(self.revision.clone()))?,
+            path: self.path.clone()?,
+            size: self.size,
+        })
+    }
+}
+
+/// Signing and checking of resumption tokens
+#[derive(Clone)]
+struct ResumeTokens {
+    encoding: EncodingKey,
+    decoding: DecodingKey,
+    validation: Validation,
+}
+
+/// Recently aborted transfers
+#[derive(Clone)]
+pub struct AbortedTransfers {
+    window: Duration,
+    keys: RandomState,
+    aborted: Arc<Mutex<HashMap<(ClientKey, String), Aborted>>>,
+    tokens: Option<ResumeTokens>,
+    /// Tokens that already earned their prioritized resume here, until
+    /// they expire
+    redeemed: Arc<Mutex<HashMap<String, Instant>>>,
+}
+
+impl AbortedTransfers {
+    /// Load `RESUME_PRIORITY_SECS` and `RESUME_SECRET`
+    pub fn from_env() -> Self {
+        let secs = crate::config::number("RESUME_PRIORITY_SECS").unwrap_or(600);
+        let secret = std::env::var("RESUME_SECRET").ok();
+        if secret.as_deref() == Some("") {
+            panic!("RESUME_SECRET must not be empty");
+        }
+        Self::new(Duration::from_secs(secs), secret.as_deref())
+    }
+
+    /// Transfers remembered for `window`, with tokens signed by `secret`;
+    /// a zero window disables both
+    fn new(window: Duration, secret: Option<&str>) -> Self {
+        let tokens = secret.filter(|_| !window.is_zero()).map(|secret| {
+            let mut validation = Validation::new(Algorithm::HS256);
+            validation.leeway = 0;
+            ResumeTokens {
+                encoding: EncodingKey::from_secret(secret.as_bytes()),
+                decoding: DecodingKey::from_secret(secret.as_bytes()),
+                validation,
+            }
+        });
+        Self {
+            window,
+            keys: RandomState::new(),
+            aborted: Arc::default(),
+            tokens,
+            redeemed: Arc::default(),
+        }
+    }
+
+    /// Whether responses carry resumption tokens
+    pub fn issues_tokens(&self) -> bool {
+        self.tokens.is_some()
+    }
+
+    /// Key identifying the client holding `hf_token`
+    pub fn client_key(&self, hf_token: &str) -> ClientKey {
+        self.keys.hash_one(hf_token)
+    }
+
+    /// Remember that `client` abandoned `hash` with its first `delivered`
+    /// bytes received
+    pub fn record(&self, client: ClientKey, hash: &str, delivered: u64) {
+        if self.window.is_zero() || delivered == 0 {
+            return;
+        }
+        let mut aborted = self.aborted.lock().unwrap();
+        if aborted.len() >= MAX_ENTRIES {
+            let window = self.window;
+            aborted.retain(|_, aborted| aborted.at.elapsed() < window);
+            if aborted.len() >= MAX_ENTRIES {
+                return;
+            }
+        }
+        let at = Instant::now();
+        aborted.insert((client, hash.to_string()), Aborted { at, delivered });
+    }
+
+    /// Priority of a download of `hash` by `client`, who may hold a
+    /// verified token from an earlier transfer; a resume is only
+    /// prioritized once
+    pub fn priority(
+        &self,
+        client: ClientKey,
+        hash: &str,
+        range: Option<ByteRange>,
+        resumed: Option<&ResumeClaims>,
+    ) -> Priority {
+        let Some(range) = range.filter(|range| range.start > 0 && !self.window.is_zero()) else {
+            return Priority::Normal;
+        };
+        if resumed.is_some_and(|claims| self.redeem(claims, range.start)) {
+            return Priority::Resumed;
+        }
+        let mut aborted = self.aborted.lock().unwrap();
+        let key = (client, hash.to_string());
+        match aborted.get(&key) {
+            Some(previous)
+                if previous.at.elapsed() < self.window && range.start <= previous.delivered =>
+            {
+                aborted.remove(&key);
+                Priority::Resumed
+            }
+            _ => Priority::Normal,
+        }
+    }
+
+    /// A token for a response of `hash` to the client holding `hf_token`,
+    /// starting at `offset` of its `size` bytes; `None` without
+    /// `RESUME_SECRET`
+    pub fn issue(
+        &self,
+        hf_token: &str,
+        hash: &str,
+        repo: &RepoRef,
+        path: Option<&str>,
+        size: u64,
+        offset: u64,
+    ) -> Option<String> {
+        let tokens = self.tokens.as_ref()?;
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).ok()?;
+        let claims = ResumeClaims {
+            hash: hash.to_string(),
+            repo: repo.to_string(),
+            revision: repo.revision.clone(),
+            path: path.map(str::to_string),
+            size,
+            offset,
+            client: tokens.client(hf_token)?,
+            exp: (now + self.window).as_secs(),
+        };
+        jsonwebtoken::encode(&Header::new(Algorithm::HS256), &claims, &tokens.encoding)
+            .inspect_err(|e| debug!("Failed to sign a resumption token: {}", e))
+            .ok()
+    }
+
+    /// The claims of `token` if it is valid, unexpired, and was issued to
+    /// the client holding `hf_token` for `hash`
+    pub fn verify(&self, token: Option<&str>, hf_token: &str, hash: &str) -> Option<ResumeClaims> {
+        let tokens = self.tokens.as_ref()?;
+        let claims =
+            jsonwebtoken::decode::<ResumeClaims>(token?, &tokens.decoding, &tokens.validation)
+                .inspect_err(|e| debug!("Ignoring an invalid resumption token: {}", e))
+                .ok()?
+                .claims;
+        if claims.hash != hash || Some(&claims.client) != tokens.client(hf_token).as_ref() {
+            debug!("Ignoring a resumption token issued for another client or file");
+            return None;
+        }
+        Some(claims)
+    }
+
+    /// Whether a token earns a prioritized resume from `start`, which it
+    /// does once
+    fn redeem(&self, claims: &ResumeClaims, start: u64) -> bool {
+        if start <= claims.offset || start >= claims.size {
+            return false;
+        }
+        let token = format!(
+            "{}:{}:{}:{}",
+            claims.client, claims.hash, claims.offset, claims.exp
+        );
+        let mut redeemed = self.redeemed.lock().unwrap();
+        if redeemed.len() >= MAX_ENTRIES {
+            let window = self.window;
+            redeemed.retain(|_, at| at.elapsed() < window);
+        }
+        redeemed.insert(token, Instant::now()).is_none()
+    }
+}
+
+impl ResumeTokens {
+    /// Keyed hash of a HuggingFace token, the same on every replica
+    fn client(&self, hf_token: &str) -> Option<String> {
+        jsonwebtoken::crypto::sign(hf_token.as_bytes(), &self.encoding, Algorithm::HS256).ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HASH: &str = "aa00000000000000000000000000000000000000000000000000000000000000";
+    const OTHER_HASH: &str = "bb00000000000000000000000000000000000000000000000000000000000000";
+    const WINDOW: Duration = Duration::from_secs(600);
+
+    fn repo() -> RepoRef {
+        RepoRef::parse("org/model", None).unwrap()
+    }
+
+    fn range(start: u64, size: u64) -> Option<ByteRange> {
+        Some(ByteRange {
+            start,
+            end: size - 1,
+            size,
+        })
+    }
+
+    /// A token for bytes 100.. of a 1000-byte file, to the holder of `hf_a`
+    fn issued(transfers: &AbortedTransfers) -> String {
+        transfers
+            .issue("hf_a", HASH, &repo(), Some("f.bin"), 1000, 100)
+            .unwrap()
+    }
+
+    #[test]
+    fn token_is_bound_to_client_and_hash() {
+        let transfers = AbortedTransfers::new(WINDOW, Some("secret"));
+        let token = issued(&transfers);
+
+        let claims = transfers.verify(Some(&token), "hf_a", HASH).unwrap();
+        assert_eq!((claims.size, claims.offset), (1000, 100));
+        assert_eq!(claims.listing().unwrap().path, "f.bin");
+        // Another replica with the same secret takes it
+        let replica = AbortedTransfers::new(WINDOW, Some("secret"));
+        assert!(replica.verify(Some(&token), "hf_a", HASH).is_some());
+
+        assert!(transfers.verify(Some(&token), "hf_b", HASH).is_none());
+        assert!(transfers.verify(Some(&token), "hf_a", OTHER_HASH).is_none());
+        let stranger = AbortedTransfers::new(WINDOW, Some("other secret"));
+        assert!(stranger.verify(Some(&token), "hf_a", HASH).is_none());
+        // A first signature byte other than the one signed
+        let signature = token.rfind('.').unwrap() + 1;
+        let mut tampered = token.into_bytes();
+        tampered[signature] = if tampered[signature] == b'A' {
+            b'B'
+        } else {
+            b'A'
+        };
+        let tampered = String::from_utf8(tampered).unwrap();
+        assert!(transfers.verify(Some(&tampered), "hf_a", HASH).is_none());
+    }
+
+    #[test]
+    fn expired_token_is_rejected() {
+        let transfers = AbortedTransfers::new(WINDOW, Some("secret"));
+        let tokens = transfers.tokens.as_ref().unwrap();
+        assert_eq!(tokens.validation.leeway, 0);
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let token = |exp: u64| {
+            let claims = ResumeClaims {
+                hash: HASH.to_string(),
+                repo: "org/model".to_string(),
+                revision: "main".to_string(),
+                path: None,
+                size: 1000,
+                offset: 100,
+                client: tokens.client("hf_a").unwrap(),
+                exp,
+            };
+            jsonwebtoken::encode(&Header::new(Algorithm::HS256), &claims, &tokens.encoding).unwrap()
+        };
+        assert!(transfers
+            .verify(Some(&token(now + 60)), "hf_a", HASH)
+            .is_some());
+        assert!(transfers
+            .verify(Some(&token(now - 1)), "hf_a", HASH)
+            .is_none());
+    }
+
+    #[test]
+    fn token_earns_one_resume_past_its_offset() {
+        let transfers = AbortedTransfers::new(WINDOW, Some("secret"));
+        let client = transfers.client_key("hf_a");
+        let claims = transfers
+            .verify(Some(&issued(&transfers)), "hf_a", HASH)
+            .unwrap();
+        let priority = |start| transfers.priority(client, HASH, range(start, 1000), Some(&claims));
+
+        // Not past the offset the token came with, nor inside the file
+        assert_eq!(priority(100), Priority::Normal);
+        assert_eq!(priority(50), Priority::Normal);
+        assert_eq!(priority(1000), Priority::Normal);
+        assert_eq!(priority(500), Priority::Resumed);
+        // Redeemed: the same token resumes at normal priority from now on
+        assert_eq!(priority(500), Priority::Normal);
+        assert_eq!(priority(600), Priority::Normal);
+        assert_eq!(
+            transfers.priority(client, HASH, None, Some(&claims)),
+            Priority::Normal
+        );
+    }
+
+    #[test]
+    fn abandoned_transfer_earns_one_resume() {
+        let transfers = AbortedTransfers::new(WINDOW, None);
+        let client = transfers.client_key("hf_a");
+        transfers.record(client, HASH, 500);
+
+        let other = transfers.client_key("hf_b");
+        assert_eq!(
+            transfers.priority(other, HASH, range(400, 1000), None),
+            Priority::Normal
+        );
+        assert_eq!(
+            transfers.priority(client, HASH, range(600, 1000), None),
+            Priority::Normal
+        );
+        assert_eq!(
+            transfers.priority(client, HASH, range(400, 1000), None),
+            Priority::Resumed
+        );
+        assert_eq!(
+            transfers.priority(client, HASH, range(400, 1000), None),
+            Priority::Normal
+        );
+    }
+
+    #[test]
+    fn zero_window_never_prioritizes() {
+        let transfers = AbortedTransfers::new(Duration::ZERO, Some("secret"));
+        assert!(!transfers.issues_tokens());
+        assert!(transfers
+            .issue("hf_a", HASH, &repo(), None, 1000, 100)
+            .is_none());
+
+        let client = transfers.client_key("hf_a");
+        transfers.record(client, HASH, 500);
+        assert_eq!(
+            transfers.priority(client, HASH, range(400, 1000), None),
+            Priority::Normal
+        );
+        // Even claims vouched for elsewhere
+        let signer = AbortedTransfers::new(WINDOW, Some("secret"));
+        let claims = signer.verify(Some(&issued(&signer)), "hf_a", HASH).unwrap();
+        assert_eq!(
+            transfers.priority(client, HASH, range(500, 1000), Some(&claims)),
+            Priority::Normal
+        );
+    }
+}
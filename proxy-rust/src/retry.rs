@@ -0,0 +1,135 @@
+//! Retries of transient upstream failures
+//!
+//! The upstream steps before a response starts (listings, size lookups,
+//! download startup, head fetches, chunk manifests) are retried when they
+//! fail transiently, up to the request's retry count (`X-Proxy-Retries`,
+//! see [`crate::overrides`]). Transient failures are upstream 5xx and
+//! connection errors, and 429s.
+//!
+//! Attempt `n` waits `PROXY_RETRY_BASE_MS` (default 500) doubled `n - 1`
+//! times, at most `PROXY_RETRY_MAX_MS` (default 10000), less a random share
+//! of up to `PROXY_RETRY_JITTER` (default 0.5) of it so clients that failed
+//! together don't retry together. After a 429 the repository's cooldown
+//! (see [`crate::backoff`]) is waited out instead, unless it is longer than
+//! the maximum delay.
+//!
+//! The native engine also retries each xorb fetch mid-stream, up to
+//! `PROXY_CHUNK_RETRIES` (default 3) times. Retries are logged and counted
+//! in `xet_proxy_upstream_retries_total{operation}`, and per request (or
+//! prefetched file) in [`counted`] scopes, for transfer reports.
+
+use crate::AppError;
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::hash::{BuildHasher, RandomState};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+tokio::task_local! {
+    static COUNTER: Arc<AtomicU64>;
+}
+
+/// Retry schedule, with the retries made so far
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    base: Duration,
+    max: Duration,
+    jitter: f64,
+    /// Retries of each xorb fetch of a native download
+    pub chunk_retries: u32,
+    counts: Arc<Mutex<BTreeMap<String, u64>>>,
+}
+
+impl RetryPolicy {
+    /// Load `PROXY_RETRY_BASE_MS`, `PROXY_RETRY_MAX_MS`, `PROXY_RETRY_JITTER`
+    /// and `PROXY_CHUNK_RETRIES`
+    pub fn from_env() -> Self {
+        let number = |name: &str, default| crate::config::number(name).unwrap_or(default);
+        let base = Duration::from_millis(number("PROXY_RETRY_BASE_MS", 500));
+        let max = Duration::from_millis(number("PROXY_RETRY_MAX_MS", 10_000));
+        assert!(
+            base <= max,
+            "PROXY_RETRY_BASE_MS must not exceed PROXY_RETRY_MAX_MS"
+        );
+        let jitter = std::env::var("PROXY_RETRY_JITTER").map_or(0.5, |v| {
+            v.parse::<f64>()
+                .ok()
+                .filter(|j| (0.0..=1.0).contains(j))
+                .unwrap_or_else(|| panic!("PROXY_RETRY_JITTER must be a number between 0 and 1"))
+        });
+        Self {
+            base,
+            max,
+            jitter,
+            chunk_retries: number("PROXY_CHUNK_RETRIES", 3) as u32,
+            counts: Arc::default(),
+        }
+    }
+
+    /// Delay before retry `attempt` (from 1), with jitter
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let delay = self
+            .base
+            .saturating_mul(1 << attempt.saturating_sub(1).min(16))
+            .min(self.max);
+        delay.mul_f64(1.0 - self.jitter * random_fraction())
+    }
+
+    /// Delay before retrying after `error`, or `None` if it isn't worth
+    /// retrying
+    pub fn delay(&self, attempt: u32, error: &AppError) -> Option<Duration> {
+        match error {
+            AppError::Internal(_) => Some(self.backoff(attempt)),
+            AppError::RateLimited {
+                retry_after: Some(wait),
+                ..
+            } => (*wait <= self.max).then_some(*wait),
+            AppError::RateLimited { .. } => Some(self.backoff(attempt)),
+            _ => None,
+        }
+    }
+
+    /// Count a retry of `operation`
+    pub fn record(&self, operation: &str) {
+        let mut counts = self.counts.lock().unwrap();
+        *counts.entry(operation.to_string()).or_insert(0) += 1;
+        let _ = COUNTER.try_with(|counter| counter.fetch_add(1, Ordering::Relaxed));
+    }
+
+    /// Retries made so far, by operation
+    pub fn counts(&self) -> Vec<(String, u64)> {
+        let counts = self.counts.lock().unwrap();
+        counts
+            .iter()
+            .map(|(operation, &n)| (operation.clone(), n))
+            .collect()
+    }
+}
+
+/// `future`, counting its retries in `counter`
+pub async fn counted<F: Future>(counter: Arc<AtomicU64>, future: F) -> F::Output {
+    COUNTER.scope(counter, future).await
+}
+
+/// Counter of the current [`counted`] scope, if any
+pub fn counter() -> Option<Arc<AtomicU64>> {
+    COUNTER.try_with(Arc::clone).ok()
+}
+
+/// `future`, counting its retries in the current scope's counter; for work
+/// spawned on its behalf
+pub fn inherit<F: Future>(future: F) -> impl Future<Output = F::Output> {
+    let counter = counter();
+    async move {
+        match counter {
+            Some(counter) => COUNTER.scope(counter, future).await,
+            None => future.await,
+        }
+    }
+}
+
+/// Uniform-ish number in `[0, 1)`, for jitter
+fn random_fraction() -> f64 {
+    (RandomState::new().hash_one(std::time::Instant::now()) >> 11) as f64 / (1u64 << 53) as f64
+}
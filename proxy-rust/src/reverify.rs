@@ -0,0 +1,159 @@
+//! Re-verification of old cached files against upstream
+//!
+//! A hash names its content forever, but the path it was downloaded as may
+//! since have moved on to other content, or gone. With
+//! `CACHE_REVERIFY_AFTER_SECS` set, a download served from a cached copy
+//! fetched longer ago than that, whose repository path is known, first
+//! lists the repository again (at the same revision) and checks that the
+//! path still names the hash. The answer is kept for as long again, so a
+//! busy file is checked once per period, not per download. The download is
+//! served from the cache whatever the outcome, annotated with
+//! `X-Cache-Upstream`:
+//!
+//! - `current`: the path still names the hash (`X-Cache` is then
+//!   `REVALIDATED`);
+//! - `moved`: the path names other content now, whose hash is in
+//!   `X-Upstream-Xet-Hash`;
+//! - `removed`: the path is gone from the listing;
+//! - `unverified`: the listing failed or took over 5 seconds; the check is
+//!   tried again after a minute, and meanwhile an offline proxy keeps
+//!   serving its cache.
+//!
+//! `xet_proxy_cache_reverifications_total{result}` counts the checks.
+
+use crate::catalog::CatalogEntry;
+use crate::listing::ListedFile;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+use tracing::info;
+
+/// How long a check may hold up a download
+pub const REVERIFY_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long before an unanswered check is tried again
+const RETRY_UNVERIFIED: Duration = Duration::from_secs(60);
+/// Answers kept beyond which expired ones are dropped
+const PRUNE_AT: usize = 10_000;
+
+/// What upstream says about a cached file's path
+#[derive(Clone, Debug, PartialEq)]
+pub enum Verdict {
+    Current,
+    /// The path names this hash now
+    Moved(String),
+    Removed,
+    Unverified,
+}
+
+impl Verdict {
+    /// The verdict on `hash` listed as `listing`, from the fresh `files`
+    pub fn of(files: &[ListedFile], listing: &CatalogEntry, hash: &str) -> Self {
+        match files.iter().find(|f| f.path == listing.path) {
+            Some(f) if f.xet_hash == hash => Self::Current,
+            Some(f) => Self::Moved(f.xet_hash.clone()),
+            None => Self::Removed,
+        }
+    }
+
+    /// Value of `X-Cache-Upstream`
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Current => "current",
+            Self::Moved(_) => "moved",
+            Self::Removed => "removed",
+            Self::Unverified => "unverified",
+        }
+    }
+
+    fn index(&self) -> usize {
+        match self {
+            Self::Current => 0,
+            Self::Moved(_) => 1,
+            Self::Removed => 2,
+            Self::Unverified => 3,
+        }
+    }
+}
+
+/// Hash, repository and path of a cached file
+type Key = (String, String, String);
+
+/// A verdict and until when it stands
+struct Answer {
+    verdict: Verdict,
+    until: Instant,
+}
+
+#[derive(Clone)]
+pub struct Reverify {
+    after: Duration,
+    answers: Arc<Mutex<HashMap<Key, Answer>>>,
+    counts: Arc<[AtomicU64; 4]>,
+}
+
+impl Reverify {
+    /// Load `CACHE_REVERIFY_AFTER_SECS`; `None` if off
+    pub fn from_env() -> Option<Self> {
+        let secs: u64 = crate::config::positive("CACHE_REVERIFY_AFTER_SECS")?;
+        info!(
+            "Cached files older than {}s are checked against upstream",
+            secs
+        );
+        Some(Self {
+            after: Duration::from_secs(secs),
+            answers: Arc::default(),
+            counts: Arc::default(),
+        })
+    }
+
+    /// Whether a copy fetched at `fetched` is old enough to be checked
+    pub fn due(&self, fetched: SystemTime) -> bool {
+        SystemTime::now()
+            .duration_since(fetched)
+            .is_ok_and(|age| age >= self.after)
+    }
+
+    /// The standing verdict on `hash` listed as `listing`, if any
+    pub fn known(&self, hash: &str, listing: &CatalogEntry) -> Option<Verdict> {
+        let answers = self.answers.lock().unwrap();
+        answers
+            .get(&key(hash, listing))
+            .filter(|answer| answer.until > Instant::now())
+            .map(|answer| answer.verdict.clone())
+    }
+
+    /// Record and count a fresh verdict
+    pub fn record(&self, hash: &str, listing: &CatalogEntry, verdict: Verdict) {
+        self.counts[verdict.index()].fetch_add(1, Ordering::Relaxed);
+        let now = Instant::now();
+        let until = match verdict {
+            Verdict::Unverified => now + RETRY_UNVERIFIED,
+            _ => now + self.after,
+        };
+        let mut answers = self.answers.lock().unwrap();
+        if answers.len() >= PRUNE_AT {
+            answers.retain(|_, answer| answer.until > now);
+        }
+        answers.insert(key(hash, listing), Answer { verdict, until });
+    }
+
+    /// `(result, count)` of the checks made
+    pub fn counts(&self) -> [(&'static str, u64); 4] {
+        let count = |i: usize| self.counts[i].load(Ordering::Relaxed);
+        [
+            ("current", count(0)),
+            ("moved", count(1)),
+            ("removed", count(2)),
+            ("unverified", count(3)),
+        ]
+    }
+}
+
+fn key(hash: &str, listing: &CatalogEntry) -> Key {
+    (
+        hash.to_string(),
+        format!("{}@{}", listing.repo, listing.repo.revision),
+        listing.path.clone(),
+    )
+}
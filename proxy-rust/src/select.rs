@@ -0,0 +1,170 @@
+//! Artifact selection by target descriptor
+//!
+//! A descriptor is `<format>[:<variant>]`, e.g. `gguf:q4_k_m`, `onnx:cpu` or
+//! just `safetensors`. Operator rules (loaded from the JSON file named by
+//! `ARTIFACT_RULES_FILE`) map a descriptor to glob patterns in priority
+//! order and always win:
+//!
+//! ```json
+//! { "onnx:cpu": ["onnx/model_quantized.onnx", "onnx/*.onnx"] }
+//! ```
+//!
+//! Without a matching rule, naming heuristics apply: the file extension must
+//! match the format, and the variant must appear in the file name once
+//! separators are ignored (`q4_k_m` matches `Model-Q4_K_M.gguf` and
+//! `model.q4km.gguf`). Ties prefer the first shard of sharded files, then
+//! files closer to the repository root.
+
+use crate::listing::ListedFile;
+use std::collections::HashMap;
+
+/// Variant keywords implying a hardware target rather than a name fragment
+const CPU_HINTS: &[&str] = &["quantized", "int8", "q8", "q4", "cpu"];
+const GPU_HINTS: &[&str] = &["fp16", "gpu", "cuda"];
+
+/// A parsed `<format>[:<variant>]` descriptor
+pub struct Target {
+    raw: String,
+    format: String,
+    variant: Option<String>,
+}
+
+impl Target {
+    pub fn parse(descriptor: &str) -> Option<Self> {
+        let descriptor = descriptor.trim().to_ascii_lowercase();
+        let (format, variant) = match descriptor.split_once(':') {
+            Some((format, variant)) => (format.to_string(), Some(variant.to_string())),
+            None => (descriptor.clone(), None),
+        };
+        if format.is_empty() || variant.as_deref() == Some("") {
+            return None;
+        }
+        Some(Self {
+            raw: descriptor,
+            format,
+            variant,
+        })
+    }
+}
+
+/// Operator-defined descriptor rules
+#[derive(Clone, Default)]
+pub struct SelectionRules {
+    rules: HashMap<String, Vec<String>>,
+}
+
+impl SelectionRules {
+    /// Load rules from `ARTIFACT_RULES_FILE`, if set
+    pub fn from_env() -> Self {
+        let Ok(path) = std::env::var("ARTIFACT_RULES_FILE") else {
+            return Self::default();
+        };
+        let text = std::fs::read_to_string(&path)
+            .unwrap_or_else(|e| panic!("Failed to read ARTIFACT_RULES_FILE {}: {}", path, e));
+        let rules: HashMap<String, Vec<String>> = serde_json::from_str(&text)
+            .unwrap_or_else(|e| panic!("Invalid ARTIFACT_RULES_FILE {}: {}", path, e));
+        Self {
+            rules: rules
+                .into_iter()
+                .map(|(k, v)| (k.to_ascii_lowercase(), v))
+                .collect(),
+        }
+    }
+
+    /// Pick the best file for `target`
+    pub fn select<'a>(&self, target: &Target, files: &'a [ListedFile]) -> Option<&'a ListedFile> {
+        if let Some(patterns) = self.rules.get(&target.raw) {
+            // First pattern with a match wins; ties within a pattern by path order
+            return patterns.iter().find_map(|pattern| {
+                files
+                    .iter()
+                    .filter(|f| glob_match(pattern, &f.path))
+                    .min_by(|a, b| a.path.cmp(&b.path))
+            });
+        }
+
+        files
+            .iter()
+            .filter_map(|f| heuristic_score(target, &f.path).map(|score| (score, f)))
+            .max_by(|(sa, a), (sb, b)| sa.cmp(sb).then_with(|| b.path.cmp(&a.path)))
+            .map(|(_, f)| f)
+    }
+}
+
+/// Score a path for `target`; `None` means it doesn't qualify
+fn heuristic_score(target: &Target, path: &str) -> Option<i64> {
+    let lower = path.to_ascii_lowercase();
+    let basename = lower.rsplit('/').next().unwrap_or(&lower);
+    if !basename.ends_with(&format!(".{}", target.format)) {
+        return None;
+    }
+
+    let mut score = 0;
+    if let Some(variant) = &target.variant {
+        let name = normalize(basename);
+        if name.contains(&normalize(variant)) {
+            score += 100;
+        } else {
+            let hints = match variant.as_str() {
+                "cpu" => CPU_HINTS,
+                "gpu" => GPU_HINTS,
+                _ => return None,
+            };
+            // Hardware targets still accept untagged files, just ranked lower
+            if hints.iter().any(|h| name.contains(h)) {
+                score += 50;
+            }
+        }
+    }
+
+    // Prefer the first shard of "-00001-of-00005" style files
+    if let Some(shard) = shard_index(basename) {
+        score -= if shard <= 1 { 1 } else { 10 };
+    }
+    // Prefer files closer to the repository root
+    score -= lower.matches('/').count() as i64;
+    Some(score)
+}
+
+/// Lowercase alphanumerics only, so separators don't affect matching
+fn normalize(s: &str) -> String {
+    s.chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .map(|c| c.to_ascii_lowercase())
+        .collect()
+}
+
+/// Shard number from names like `model-00002-of-00005.safetensors`
+fn shard_index(basename: &str) -> Option<u32> {
+    let of = basename.find("-of-")?;
+    let start = basename[..of].rfind('-')? + 1;
+    basename[start..of].parse().ok()
+}
+
+/// Minimal glob matching supporting `*` and `?` (`*` also matches `/`)
+pub fn glob_match(pattern: &str, text: &str) -> bool {
+    let p: Vec<char> = pattern.chars().collect();
+    let t: Vec<char> = text.chars().collect();
+    let (mut pi, mut ti) = (0, 0);
+    let mut star: Option<(usize, usize)> = None;
+
+    while ti < t.len() {
+        if pi < p.len() && (p[pi] == '?' || p[pi] == t[ti]) {
+            pi += 1;
+            ti += 1;
+        } else if pi < p.len() && p[pi] == '*' {
+            star = Some((pi, ti));
+            pi += 1;
+        } else if let Some((sp, st)) = star {
+            pi = sp + 1;
+            ti = st + 1;
+            star = Some((sp, st + 1));
+        } else {
+            return false;
+        }
+    }
+    while pi < p.len() && p[pi] == '*' {
+        pi += 1;
+    }
+    pi == p.len()
+}
@@ -0,0 +1,268 @@
+//! `xet-proxy --self-test`
+//!
+//! Exercises each configured subsystem end to end and exits non-zero if any
+//! step failed, for deployment pipelines that want more than
+//! `check-config`. After the configuration checks, the server is built as
+//! for a normal start (without binding a port) and driven with in-process
+//! requests:
+//!
+//! - `/health` answers;
+//! - `HF_TOKEN` is accepted by the Hub;
+//! - a known public file resolves and its first KiB downloads through the
+//!   configured backend (`SELF_TEST_REPO`, default `jedisct1/MiMo-7B-RL-GGUF`;
+//!   `SELF_TEST_FILE`, default the smallest listed file);
+//! - the cache writes, reads back and evicts a scratch entry;
+//! - `/metrics` reports the requests just made.
+//!
+//! Steps needing a token are skipped without `HF_TOKEN`, and the cache step
+//! without a cache. With API keys configured, requests present
+//! `SELF_TEST_API_KEY`. Each step is bounded by `SELF_TEST_TIMEOUT_SECS`
+//! (default 60).
+
+use axum::body::{Body, Bytes};
+use axum::http::{header, Request, StatusCode};
+use axum::Router;
+use std::future::Future;
+use std::time::{Duration, Instant};
+use tower::ServiceExt;
+
+const DEFAULT_REPO: &str = "jedisct1/MiMo-7B-RL-GGUF";
+/// Bytes downloaded from the test file
+const PROBE_BYTES: u64 = 1024;
+
+/// Load `SELF_TEST_TIMEOUT_SECS` (default 60)
+pub fn timeout_from_env() -> Duration {
+    Duration::from_secs(crate::config::positive("SELF_TEST_TIMEOUT_SECS").unwrap_or(60))
+}
+
+/// Outcome of one step
+enum Outcome {
+    Ok(String),
+    Skipped(String),
+    Failed(String),
+}
+
+#[derive(Default)]
+struct Report {
+    failures: usize,
+}
+
+impl Report {
+    /// Run one step within `timeout` and print its outcome and duration
+    async fn step(&mut self, name: &str, timeout: Duration, step: impl Future<Output = Outcome>) {
+        let started = Instant::now();
+        let outcome = tokio::time::timeout(timeout, step)
+            .await
+            .unwrap_or_else(|_| Outcome::Failed(format!("no result within {:?}", timeout)));
+        let took = started.elapsed().as_secs_f64();
+        match outcome {
+            Outcome::Ok(detail) => println!("ok    {} ({:.2}s): {}", name, took, detail),
+            Outcome::Skipped(reason) => println!("skip  {}: {}", name, reason),
+            Outcome::Failed(e) => {
+                self.failures += 1;
+                println!("FAIL  {} ({:.2}s): {}", name, took, e);
+            }
+        }
+    }
+}
+
+/// Run the self-test and return the process exit code
+pub async fn run() -> i32 {
+    println!("Configuration:");
+    let failures = crate::config_check::check();
+    if failures > 0 {
+        println!(
+            "{} configuration problem(s) found, not testing subsystems",
+            failures
+        );
+        return 1;
+    }
+    let timeout = timeout_from_env();
+    let repo = std::env::var("SELF_TEST_REPO").unwrap_or_else(|_| DEFAULT_REPO.to_string());
+    let file = std::env::var("SELF_TEST_FILE").ok();
+    let token = std::env::var("HF_TOKEN").ok();
+
+    println!();
+    println!("Subsystems:");
+    let state = crate::app_state().await;
+    let cache = state.cache.clone();
+    let app = crate::router(state);
+    let mut report = Report::default();
+
+    report
+        .step("health", timeout, async {
+            match get(&app, "/health", None, None).await {
+                Ok((StatusCode::OK, _)) => Outcome::Ok("200".to_string()),
+                Ok((status, body)) => Outcome::Failed(unexpected(status, &body)),
+                Err(e) => Outcome::Failed(e),
+            }
+        })
+        .await;
+
+    report
+        .step("token", timeout, async {
+            match &token {
+                Some(token) => check_token(token).await,
+                None => Outcome::Skipped("HF_TOKEN is not set".to_string()),
+            }
+        })
+        .await;
+
+    report
+        .step("backend", timeout, async {
+            match &token {
+                Some(token) => check_backend(&app, &repo, file.as_deref(), token).await,
+                None => Outcome::Skipped("HF_TOKEN is not set".to_string()),
+            }
+        })
+        .await;
+
+    report
+        .step("cache", timeout, async {
+            match &cache {
+                Some(cache) => match cache.self_test().await {
+                    Ok(detail) => Outcome::Ok(detail),
+                    Err(e) => Outcome::Failed(e),
+                },
+                None => Outcome::Skipped("no cache is configured".to_string()),
+            }
+        })
+        .await;
+
+    report
+        .step("metrics", timeout, async {
+            match get(&app, "/metrics", None, None).await {
+                Ok((StatusCode::OK, body)) => {
+                    let sample = r#"xet_proxy_http_requests_total{route="/health",status="200"}"#;
+                    if String::from_utf8_lossy(&body).contains(sample) {
+                        Outcome::Ok("request metrics recorded".to_string())
+                    } else {
+                        Outcome::Failed("the /health request was not recorded".to_string())
+                    }
+                }
+                Ok((status, body)) => Outcome::Failed(unexpected(status, &body)),
+                Err(e) => Outcome::Failed(e),
+            }
+        })
+        .await;
+
+    println!();
+    if report.failures == 0 {
+        println!("Self-test passed");
+        0
+    } else {
+        println!("{} step(s) failed", report.failures);
+        1
+    }
+}
+
+/// Whether the Hub accepts `token`
+async fn check_token(token: &str) -> Outcome {
+    match crate::readiness::whoami(&crate::trust::client(), token).await {
+        Ok(detail) => Outcome::Ok(detail),
+        Err(e) => Outcome::Failed(e),
+    }
+}
+
+/// Resolve a file of `repo` and download its first bytes
+async fn check_backend(app: &Router, repo: &str, file: Option<&str>, token: &str) -> Outcome {
+    let (status, body) = match get(app, &format!("/list/{}", repo), Some(token), None).await {
+        Ok(response) => response,
+        Err(e) => return Outcome::Failed(e),
+    };
+    if status != StatusCode::OK {
+        return Outcome::Failed(format!("listing {}: {}", repo, unexpected(status, &body)));
+    }
+    let listed: Vec<serde_json::Value> = match serde_json::from_slice(&body) {
+        Ok(listed) => listed,
+        Err(e) => return Outcome::Failed(format!("listing {}: invalid response: {}", repo, e)),
+    };
+    let entry = match file {
+        Some(file) => listed.iter().find(|entry| entry["path"] == file),
+        None => listed
+            .iter()
+            .min_by_key(|entry| entry["size"].as_u64().unwrap_or(u64::MAX)),
+    };
+    let Some((path, size)) =
+        entry.and_then(|entry| Some((entry["path"].as_str()?, entry["size"].as_u64()?)))
+    else {
+        return Outcome::Failed(match file {
+            Some(file) => format!("{} is not a XET file of {}", file, repo),
+            None => format!("{} has no XET files", repo),
+        });
+    };
+
+    let expected = size.min(PROBE_BYTES);
+    if expected == 0 {
+        return Outcome::Ok(format!("resolved {}/{} (empty)", repo, path));
+    }
+    let range = format!("bytes=0-{}", expected - 1);
+    let url = format!("/download/{}/{}", repo, path);
+    match get(app, &url, Some(token), Some(&range)).await {
+        Ok((StatusCode::OK | StatusCode::PARTIAL_CONTENT, body))
+            if body.len() as u64 == expected =>
+        {
+            Outcome::Ok(format!(
+                "resolved {}/{} and downloaded {} bytes",
+                repo, path, expected
+            ))
+        }
+        Ok((StatusCode::OK | StatusCode::PARTIAL_CONTENT, body)) => Outcome::Failed(format!(
+            "downloading {}/{}: got {} of {} bytes",
+            repo,
+            path,
+            body.len(),
+            expected
+        )),
+        Ok((status, body)) => Outcome::Failed(format!(
+            "downloading {}/{}: {}",
+            repo,
+            path,
+            unexpected(status, &body)
+        )),
+        Err(e) => Outcome::Failed(e),
+    }
+}
+
+/// Send a `GET` through the router and collect the response body
+async fn get(
+    app: &Router,
+    uri: &str,
+    token: Option<&str>,
+    range: Option<&str>,
+) -> Result<(StatusCode, Bytes), String> {
+    let mut request = Request::get(uri);
+    if let Some(token) = token {
+        request = request.header(header::AUTHORIZATION, format!("Bearer {}", token));
+    }
+    if let Some(range) = range {
+        request = request.header(header::RANGE, range);
+    }
+    if let Ok(key) = std::env::var("SELF_TEST_API_KEY") {
+        request = request.header(crate::auth::API_KEY_HEADER, key);
+    }
+    let request = request
+        .body(Body::empty())
+        .map_err(|e| format!("{}: {}", uri, e))?;
+    let response = app
+        .clone()
+        .oneshot(request)
+        .await
+        .map_err(|e| format!("{}: {}", uri, e))?;
+    let status = response.status();
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .map_err(|e| format!("{}: reading the body: {}", uri, e))?;
+    Ok((status, body))
+}
+
+/// Status and error message of an unexpected response
+fn unexpected(status: StatusCode, body: &[u8]) -> String {
+    let message = serde_json::from_slice::<serde_json::Value>(body)
+        .ok()
+        .and_then(|error| error["error"].as_str().map(str::to_string));
+    match message {
+        Some(message) => format!("{} ({})", status, message),
+        None => status.to_string(),
+    }
+}
@@ -0,0 +1,234 @@
+//! Download sessions pinning repositories to a commit
+//!
+//! A multi-file pull (config, tokenizer, shards) resolves every file
+//! against a branch separately, so a push landing mid-pull can mix files of
+//! two commits. `POST /sessions` starts a session; requests carrying its id
+//! in `X-Session-Id` have each repository revision they name resolved to a
+//! commit the first time the session sees it, and every later request of
+//! the session reads that same commit, whatever the branch does meanwhile.
+//! Revisions that already are commit hashes are used as is.
+//!
+//! Sessions expire after `SESSION_TTL_SECS` (default 3600) without use and
+//! can be ended early with `DELETE /sessions/:id`. At most `MAX_SESSIONS`
+//! live at once.
+
+use crate::repo::{RepoRef, RepoType};
+use crate::AppError;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::hash::{BuildHasher, RandomState};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::info;
+
+pub const SESSION_HEADER: &str = "x-session-id";
+const MAX_SESSIONS: usize = 10_000;
+/// Repository revisions one session pins at most
+const MAX_PINS: usize = 1000;
+
+/// Pinned revision of one repository
+#[derive(Clone, Serialize, utoipa::ToSchema)]
+pub struct Pin {
+    pub repo: String,
+    pub revision: String,
+    pub commit: String,
+}
+
+/// A session, as reported by `GET /sessions/:id`
+#[derive(Serialize, utoipa::ToSchema)]
+pub struct SessionStatus {
+    pub id: String,
+    /// Seconds left before the session expires unless used
+    pub expires_in: u64,
+    pub pins: Vec<Pin>,
+}
+
+struct Session {
+    last_used: Instant,
+    /// Commits by repository and requested revision
+    pins: HashMap<(RepoType, String, String), Pin>,
+}
+
+#[derive(Deserialize)]
+struct RevisionInfo {
+    sha: String,
+}
+
+/// Live sessions
+#[derive(Clone)]
+pub struct Sessions {
+    ttl: Duration,
+    http: reqwest::Client,
+    sessions: Arc<Mutex<HashMap<String, Session>>>,
+}
+
+impl Sessions {
+    /// Load `SESSION_TTL_SECS`
+    pub fn from_env() -> Self {
+        let secs = crate::config::positive("SESSION_TTL_SECS").unwrap_or(3600);
+        let http = crate::trust::apply(reqwest::Client::builder())
+            .connect_timeout(Duration::from_secs(30))
+            .user_agent(concat!("xet-proxy/", env!("CARGO_PKG_VERSION")))
+            .build()
+            .expect("Failed to build HTTP client");
+        Self {
+            ttl: Duration::from_secs(secs),
+            http,
+            sessions: Arc::default(),
+        }
+    }
+
+    pub fn ttl(&self) -> Duration {
+        self.ttl
+    }
+
+    /// Start a session; returns its id
+    pub fn create(&self) -> Result<String, AppError> {
+        let mut sessions = self.sessions.lock().unwrap();
+        if sessions.len() >= MAX_SESSIONS {
+            let ttl = self.ttl;
+            sessions.retain(|_, session| session.last_used.elapsed() < ttl);
+            if sessions.len() >= MAX_SESSIONS {
+                return Err(AppError::Unavailable {
+                    message: "Too many open sessions, retry later".to_string(),
+                    retry_after: Some(Duration::from_secs(60)),
+                });
+            }
+        }
+        let id = session_id();
+        sessions.insert(
+            id.clone(),
+            Session {
+                last_used: Instant::now(),
+                pins: HashMap::new(),
+            },
+        );
+        info!("Session {} started", id);
+        Ok(id)
+    }
+
+    pub fn status(&self, id: &str) -> Option<SessionStatus> {
+        let sessions = self.sessions.lock().unwrap();
+        let session = sessions
+            .get(id)
+            .filter(|s| s.last_used.elapsed() < self.ttl)?;
+        let mut pins: Vec<Pin> = session.pins.values().cloned().collect();
+        pins.sort_by(|a, b| (&a.repo, &a.revision).cmp(&(&b.repo, &b.revision)));
+        Some(SessionStatus {
+            id: id.to_string(),
+            expires_in: self
+                .ttl
+                .saturating_sub(session.last_used.elapsed())
+                .as_secs(),
+            pins,
+        })
+    }
+
+    /// End a session; `false` if it didn't exist
+    pub fn end(&self, id: &str) -> bool {
+        self.sessions.lock().unwrap().remove(id).is_some()
+    }
+
+    /// `repo` with its revision replaced by the commit the session pinned it
+    /// to, resolving and pinning it now if the session hasn't seen it yet
+    pub async fn pin(&self, id: &str, repo: RepoRef, hf_token: &str) -> Result<RepoRef, AppError> {
+        let key = (repo.repo_type, repo.id(), repo.revision.clone());
+        {
+            let mut sessions = self.sessions.lock().unwrap();
+            let session = self.live(&mut sessions, id)?;
+            if let Some(pin) = session.pins.get(&key) {
+                return Ok(RepoRef {
+                    revision: pin.commit.clone(),
+                    ..repo
+                });
+            }
+            if session.pins.len() >= MAX_PINS {
+                return Err(AppError::BadRequest(format!(
+                    "A session pins at most {} repository revisions",
+                    MAX_PINS
+                )));
+            }
+        }
+
+        let commit = if is_commit(&repo.revision) {
+            repo.revision.clone()
+        } else {
+            self.resolve(&repo, hf_token).await?
+        };
+        let mut sessions = self.sessions.lock().unwrap();
+        let session = self.live(&mut sessions, id)?;
+        // A concurrent request may have pinned it first; the first pin wins
+        let pin = session.pins.entry(key).or_insert_with(|| {
+            info!(
+                "Session {} pinned {}@{} to {}",
+                id, repo, repo.revision, commit
+            );
+            Pin {
+                repo: repo.to_string(),
+                revision: repo.revision.clone(),
+                commit,
+            }
+        });
+        Ok(RepoRef {
+            revision: pin.commit.clone(),
+            ..repo
+        })
+    }
+
+    /// The session `id`, refreshed, unless unknown or expired
+    fn live<'a>(
+        &self,
+        sessions: &'a mut HashMap<String, Session>,
+        id: &str,
+    ) -> Result<&'a mut Session, AppError> {
+        let expired = sessions
+            .get(id)
+            .is_some_and(|s| s.last_used.elapsed() >= self.ttl);
+        if expired {
+            sessions.remove(id);
+        }
+        let session = sessions
+            .get_mut(id)
+            .ok_or_else(|| AppError::NotFound(format!("Unknown or expired session '{}'", id)))?;
+        session.last_used = Instant::now();
+        Ok(session)
+    }
+
+    /// Commit `repo`'s revision currently points at
+    async fn resolve(&self, repo: &RepoRef, hf_token: &str) -> Result<String, AppError> {
+        let url = format!(
+            "{}/api/{}/{}/revision/{}",
+            crate::hub::endpoint(),
+            repo.repo_type.plural(),
+            repo.id(),
+            repo.revision_segment()
+        );
+        let request = self.http.get(&url).bearer_auth(hf_token);
+        let info: RevisionInfo = crate::xet::send(request, "Revision lookup")
+            .await?
+            .json()
+            .await
+            .map_err(|e| AppError::Internal(format!("Invalid revision response: {}", e)))?;
+        Ok(info.sha)
+    }
+}
+
+/// Whether `revision` is a full commit hash
+fn is_commit(revision: &str) -> bool {
+    revision.len() == 40
+        && revision
+            .bytes()
+            .all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+}
+
+/// Unguessable session id
+fn session_id() -> String {
+    static SEQUENCE: AtomicU64 = AtomicU64::new(0);
+    let state = RandomState::new();
+    format!(
+        "{:016x}{:016x}",
+        state.hash_one(SEQUENCE.fetch_add(1, Ordering::Relaxed)),
+        state.hash_one(std::process::id())
+    )
+}
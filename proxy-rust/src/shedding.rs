@@ -0,0 +1,146 @@
+//! Load shedding under resource pressure
+//!
+//! A background task samples the process's resident memory and open file
+//! descriptors once a second. When either exceeds its threshold
+//! (`SHED_MAX_RSS_MB`, `SHED_MAX_FDS`), new downloads are refused with 503
+//! while transfers already streaming continue, so the process can drain
+//! instead of falling over. Shedding stops once both are back under 90% of
+//! their thresholds, which keeps it from flapping around the limit.
+
+use crate::AppError;
+use serde::Serialize;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{info, warn};
+
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(1);
+/// Suggested client wait while shedding
+const RETRY_AFTER: Duration = Duration::from_secs(5);
+/// Fraction of a threshold usage must fall under to stop shedding
+const RECOVERY_RATIO: f64 = 0.9;
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ShedLimits {
+    pub max_rss_bytes: Option<u64>,
+    pub max_fds: Option<u64>,
+}
+
+impl ShedLimits {
+    /// Load `SHED_MAX_RSS_MB` and `SHED_MAX_FDS` (unset = no limit)
+    pub fn from_env() -> Self {
+        Self {
+            max_rss_bytes: crate::config::positive::<u64>("SHED_MAX_RSS_MB")
+                .map(|mb| mb * 1024 * 1024),
+            max_fds: crate::config::positive("SHED_MAX_FDS"),
+        }
+    }
+}
+
+/// Latest resource sample and shedding state
+#[derive(Clone, Copy, Debug, Serialize, utoipa::ToSchema)]
+pub struct LoadStatus {
+    pub shedding: bool,
+    pub rss_bytes: u64,
+    pub open_fds: u64,
+}
+
+#[derive(Default)]
+struct Shared {
+    shedding: AtomicBool,
+    rss_bytes: AtomicU64,
+    open_fds: AtomicU64,
+}
+
+#[derive(Clone)]
+pub struct LoadShedder {
+    limits: ShedLimits,
+    shared: Arc<Shared>,
+}
+
+impl LoadShedder {
+    pub fn new(limits: ShedLimits) -> Self {
+        Self {
+            limits,
+            shared: Arc::new(Shared::default()),
+        }
+    }
+
+    /// Start sampling in the background
+    pub fn start(&self) {
+        let shedder = self.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(SAMPLE_INTERVAL);
+            loop {
+                interval.tick().await;
+                shedder.sample();
+            }
+        });
+    }
+
+    fn sample(&self) {
+        let rss = resident_bytes().unwrap_or(0);
+        let fds = open_fds().unwrap_or(0);
+        self.shared.rss_bytes.store(rss, Ordering::Relaxed);
+        self.shared.open_fds.store(fds, Ordering::Relaxed);
+
+        let over = |value: u64, limit: Option<u64>, ratio: f64| {
+            limit.is_some_and(|limit| value as f64 > limit as f64 * ratio)
+        };
+        let was_shedding = self.shared.shedding.load(Ordering::Relaxed);
+        let shedding = if was_shedding {
+            over(rss, self.limits.max_rss_bytes, RECOVERY_RATIO)
+                || over(fds, self.limits.max_fds, RECOVERY_RATIO)
+        } else {
+            over(rss, self.limits.max_rss_bytes, 1.0) || over(fds, self.limits.max_fds, 1.0)
+        };
+
+        if shedding != was_shedding {
+            self.shared.shedding.store(shedding, Ordering::Relaxed);
+            if shedding {
+                warn!(
+                    rss_bytes = rss,
+                    open_fds = fds,
+                    "Resource pressure: shedding new downloads"
+                );
+            } else {
+                info!(
+                    rss_bytes = rss,
+                    open_fds = fds,
+                    "Resource pressure relieved"
+                );
+            }
+        }
+    }
+
+    /// Refuse a new download while shedding
+    pub fn check(&self) -> Result<(), AppError> {
+        if self.shared.shedding.load(Ordering::Relaxed) {
+            return Err(AppError::Unavailable {
+                message: "Server is under resource pressure, retry later".to_string(),
+                retry_after: Some(RETRY_AFTER),
+            });
+        }
+        Ok(())
+    }
+
+    pub fn status(&self) -> LoadStatus {
+        LoadStatus {
+            shedding: self.shared.shedding.load(Ordering::Relaxed),
+            rss_bytes: self.shared.rss_bytes.load(Ordering::Relaxed),
+            open_fds: self.shared.open_fds.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Resident set size from `/proc/self/statm`
+fn resident_bytes() -> Option<u64> {
+    let statm = std::fs::read_to_string("/proc/self/statm").ok()?;
+    let pages: u64 = statm.split_whitespace().nth(1)?.parse().ok()?;
+    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) };
+    Some(pages * u64::try_from(page_size).ok()?)
+}
+
+fn open_fds() -> Option<u64> {
+    Some(std::fs::read_dir("/proc/self/fd").ok()?.count() as u64)
+}
@@ -0,0 +1,126 @@
+//! Graceful shutdown
+//!
+//! On `SIGTERM` (what Kubernetes sends) or `SIGINT` (`Ctrl+C`) the proxy
+//! stops accepting connections and lets in-flight requests finish for up to
+//! `SHUTDOWN_DRAIN_SECS` (default 25, under Kubernetes' default 30s grace
+//! period). Event streams (`/events`, `/progress/:job_id`) end right away,
+//! since they never finish on their own. Downloads still streaming when the
+//! drain time is up are cut off and their CLI processes killed as the
+//! process exits; so are background prefetch jobs. `GET /admin/drain-status`
+//! (see [`crate::drain`]) lists what is still running and the drain time
+//! left.
+
+use futures_core::Stream;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
+use tracing::{error, info};
+
+#[derive(Clone)]
+pub struct Shutdown {
+    drain: Duration,
+    /// When draining started
+    draining: watch::Sender<Option<Instant>>,
+}
+
+impl Shutdown {
+    /// Load `SHUTDOWN_DRAIN_SECS`
+    pub fn from_env() -> Self {
+        let secs = crate::config::number("SHUTDOWN_DRAIN_SECS").unwrap_or(25);
+        Self {
+            drain: Duration::from_secs(secs),
+            draining: watch::Sender::new(None),
+        }
+    }
+
+    /// How long in-flight requests may take to finish
+    pub fn drain(&self) -> Duration {
+        self.drain
+    }
+
+    /// Start draining on the first `SIGTERM` or `SIGINT`
+    pub fn start(&self) {
+        let draining = self.draining.clone();
+        tokio::spawn(async move {
+            let mut terminate =
+                match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+                    Ok(terminate) => terminate,
+                    Err(e) => {
+                        error!(
+                            "Cannot watch SIGTERM, only Ctrl+C shuts down gracefully: {}",
+                            e
+                        );
+                        let _ = tokio::signal::ctrl_c().await;
+                        draining.send_replace(Some(Instant::now()));
+                        return;
+                    }
+                };
+            let signal = tokio::select! {
+                _ = terminate.recv() => "SIGTERM",
+                _ = tokio::signal::ctrl_c() => "SIGINT",
+            };
+            info!("{} received, no longer accepting connections", signal);
+            draining.send_replace(Some(Instant::now()));
+        });
+    }
+
+    /// Start draining now, as on a signal
+    pub fn begin(&self) {
+        self.draining.send_replace(Some(Instant::now()));
+    }
+
+    /// Drain time left, once draining has started
+    pub fn remaining(&self) -> Option<Duration> {
+        let since = (*self.draining.borrow())?;
+        Some(self.drain.saturating_sub(since.elapsed()))
+    }
+
+    /// Resolves once draining has started
+    pub fn draining(&self) -> impl Future<Output = ()> + Send + 'static {
+        let mut draining = self.draining.subscribe();
+        async move {
+            // An error means the sender is gone, and nothing will drain
+            if draining.wait_for(Option::is_some).await.is_err() {
+                std::future::pending::<()>().await;
+            }
+        }
+    }
+
+    /// Resolves once the drain time is up
+    pub fn drained(&self) -> impl Future<Output = ()> + Send + 'static {
+        let draining = self.draining();
+        let drain = self.drain;
+        async move {
+            draining.await;
+            tokio::time::sleep(drain).await;
+        }
+    }
+}
+
+/// A stream that ends once a future resolves
+pub struct TakeUntil<S> {
+    stream: Pin<Box<S>>,
+    until: Pin<Box<dyn Future<Output = ()> + Send>>,
+}
+
+impl<S> TakeUntil<S> {
+    pub fn new(stream: S, until: impl Future<Output = ()> + Send + 'static) -> Self {
+        Self {
+            stream: Box::pin(stream),
+            until: Box::pin(until),
+        }
+    }
+}
+
+impl<S: Stream> Stream for TakeUntil<S> {
+    type Item = S::Item;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<S::Item>> {
+        if self.until.as_mut().poll(cx).is_ready() {
+            return Poll::Ready(None);
+        }
+        self.stream.as_mut().poll_next(cx)
+    }
+}
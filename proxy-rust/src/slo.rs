@@ -0,0 +1,203 @@
+//! Download latency SLO tracking
+//!
+//! Time-to-first-byte and total transfer time are recorded per route in a
+//! sliding window. Each is compared against a configured threshold; the
+//! share of requests above it, divided by the error budget (`1 - target`),
+//! gives the burn rate. A burn rate above 1 means the budget for the window
+//! is being consumed faster than the SLO allows. `/slo` reports the
+//! distributions and burn rates for alerting integrations.
+
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Short window used for fast-burn detection
+const SHORT_WINDOW: Duration = Duration::from_secs(300);
+/// Upper bound on stored samples per route
+const MAX_SAMPLES: usize = 10_000;
+
+#[derive(Clone, Debug)]
+pub struct SloConfig {
+    pub ttfb_threshold: Duration,
+    pub total_threshold: Duration,
+    /// Fraction of requests that must meet each threshold (e.g. 0.99)
+    pub target: f64,
+    pub window: Duration,
+}
+
+impl SloConfig {
+    /// Load `SLO_TTFB_MS`, `SLO_TOTAL_SECS`, `SLO_TARGET` and `SLO_WINDOW_SECS`
+    pub fn from_env() -> Self {
+        let target = std::env::var("SLO_TARGET").map_or(0.99, |v| {
+            v.parse::<f64>()
+                .unwrap_or_else(|_| panic!("SLO_TARGET must be a number"))
+        });
+        assert!(
+            target > 0.0 && target < 1.0,
+            "SLO_TARGET must be between 0 and 1 (exclusive)"
+        );
+        let number = |name: &str, default| crate::config::number(name).unwrap_or(default);
+        Self {
+            ttfb_threshold: Duration::from_millis(number("SLO_TTFB_MS", 2000)),
+            total_threshold: Duration::from_secs(number("SLO_TOTAL_SECS", 1800)),
+            target,
+            window: Duration::from_secs(number("SLO_WINDOW_SECS", 3600)),
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Sample {
+    at: Instant,
+    ttfb: Option<Duration>,
+    /// None when the transfer failed before completing
+    total: Option<Duration>,
+}
+
+/// Shared SLO recorder
+#[derive(Clone)]
+pub struct SloTracker {
+    config: SloConfig,
+    routes: Arc<Mutex<HashMap<&'static str, VecDeque<Sample>>>>,
+}
+
+#[derive(Serialize)]
+pub struct SloReport {
+    pub window_secs: u64,
+    pub target: f64,
+    pub routes: BTreeMap<&'static str, RouteReport>,
+}
+
+#[derive(Serialize)]
+pub struct RouteReport {
+    pub requests: usize,
+    pub ttfb: Objective,
+    pub total: Objective,
+}
+
+#[derive(Serialize)]
+pub struct Objective {
+    pub threshold_ms: u64,
+    pub p50_ms: Option<u64>,
+    pub p90_ms: Option<u64>,
+    pub p99_ms: Option<u64>,
+    /// Fraction of requests in the window meeting the threshold
+    pub good_ratio: Option<f64>,
+    pub burn_rate: Option<f64>,
+    /// Burn rate over the last five minutes
+    pub burn_rate_short: Option<f64>,
+    /// "ok", "burning" (burn rate above 1) or "no_data"
+    pub status: &'static str,
+}
+
+impl SloTracker {
+    pub fn new(config: SloConfig) -> Self {
+        Self {
+            config,
+            routes: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Record one transfer on `route`
+    pub fn record(&self, route: &'static str, ttfb: Option<Duration>, total: Option<Duration>) {
+        let now = Instant::now();
+        let mut routes = self.routes.lock().unwrap();
+        let samples = routes.entry(route).or_default();
+        samples.push_back(Sample {
+            at: now,
+            ttfb,
+            total,
+        });
+        prune(samples, now, self.config.window);
+    }
+
+    pub fn report(&self) -> SloReport {
+        let now = Instant::now();
+        let mut routes = self.routes.lock().unwrap();
+        let report = routes
+            .iter_mut()
+            .map(|(route, samples)| {
+                prune(samples, now, self.config.window);
+                let recent = |s: &&Sample| now.duration_since(s.at) <= SHORT_WINDOW;
+
+                let ttfb: Vec<Option<Duration>> = samples.iter().map(|s| s.ttfb).collect();
+                let ttfb_short: Vec<Option<Duration>> =
+                    samples.iter().filter(recent).map(|s| s.ttfb).collect();
+                let total: Vec<Option<Duration>> = samples.iter().map(|s| s.total).collect();
+                let total_short: Vec<Option<Duration>> =
+                    samples.iter().filter(recent).map(|s| s.total).collect();
+
+                let route_report = RouteReport {
+                    requests: samples.len(),
+                    ttfb: self.objective(self.config.ttfb_threshold, &ttfb, &ttfb_short),
+                    total: self.objective(self.config.total_threshold, &total, &total_short),
+                };
+                (*route, route_report)
+            })
+            .collect();
+
+        SloReport {
+            window_secs: self.config.window.as_secs(),
+            target: self.config.target,
+            routes: report,
+        }
+    }
+
+    /// Evaluate one objective; a missing measurement counts against it
+    fn objective(
+        &self,
+        threshold: Duration,
+        samples: &[Option<Duration>],
+        short: &[Option<Duration>],
+    ) -> Objective {
+        let mut measured: Vec<Duration> = samples.iter().flatten().copied().collect();
+        measured.sort();
+
+        let burn = |samples: &[Option<Duration>]| {
+            if samples.is_empty() {
+                return None;
+            }
+            let good = samples
+                .iter()
+                .filter(|s| s.is_some_and(|d| d <= threshold))
+                .count();
+            let good_ratio = good as f64 / samples.len() as f64;
+            Some((good_ratio, (1.0 - good_ratio) / (1.0 - self.config.target)))
+        };
+        let long = burn(samples);
+        let burn_rate_short = burn(short).map(|(_, rate)| rate);
+
+        Objective {
+            threshold_ms: threshold.as_millis() as u64,
+            p50_ms: percentile(&measured, 0.50),
+            p90_ms: percentile(&measured, 0.90),
+            p99_ms: percentile(&measured, 0.99),
+            good_ratio: long.map(|(ratio, _)| ratio),
+            burn_rate: long.map(|(_, rate)| rate),
+            burn_rate_short,
+            status: match long {
+                None => "no_data",
+                Some((_, rate)) if rate > 1.0 => "burning",
+                Some(_) => "ok",
+            },
+        }
+    }
+}
+
+fn prune(samples: &mut VecDeque<Sample>, now: Instant, window: Duration) {
+    while samples
+        .front()
+        .is_some_and(|s| now.duration_since(s.at) > window || samples.len() > MAX_SAMPLES)
+    {
+        samples.pop_front();
+    }
+}
+
+fn percentile(sorted: &[Duration], q: f64) -> Option<u64> {
+    if sorted.is_empty() {
+        return None;
+    }
+    let index = ((sorted.len() - 1) as f64 * q).round() as usize;
+    Some(sorted[index].as_millis() as u64)
+}
@@ -0,0 +1,167 @@
+//! Slots handed out by priority
+//!
+//! A counting semaphore whose waiters are served by priority first and
+//! arrival second, so a download that resumes an interrupted transfer
+//! overtakes fresh downloads waiting for the same capacity.
+//!
+//! Background work (prefetch jobs and archives asking for it) may run at a
+//! niceness of 1 to 19, as processes do. It is served after everything
+//! less nice, and only takes a slot while more than `nice / 20` of all the
+//! slots stay free for others: at 10, half the capacity is kept for other
+//! work; at 19, 95%. A mirror running all day at a high niceness so only
+//! uses capacity nobody else wants, and gives it back as its downloads end.
+
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use tokio::sync::oneshot;
+
+/// Highest niceness
+pub const MAX_NICE: u8 = 19;
+
+/// Scheduling priority of a download
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Priority {
+    #[default]
+    Normal,
+    /// Continues a recently interrupted transfer
+    Resumed,
+    /// Background work at a niceness of 1 to [`MAX_NICE`]
+    Nice(u8),
+}
+
+impl Priority {
+    /// The priority of work at niceness `nice`, 0 being normal; `None`
+    /// above [`MAX_NICE`]
+    pub fn from_nice(nice: u8) -> Option<Self> {
+        match nice {
+            0 => Some(Self::Normal),
+            n if n <= MAX_NICE => Some(Self::Nice(n)),
+            _ => None,
+        }
+    }
+
+    /// Waiters of a lower rank are served first
+    fn rank(self) -> u8 {
+        match self {
+            Self::Resumed => 0,
+            Self::Normal => 1,
+            Self::Nice(n) => 1 + n,
+        }
+    }
+}
+
+/// A fixed number of slots
+#[derive(Clone)]
+pub struct Slots {
+    state: Arc<Mutex<State>>,
+}
+
+struct State {
+    size: usize,
+    available: usize,
+    /// Waiters by rank, each rank served in arrival order
+    waiting: BTreeMap<u8, VecDeque<oneshot::Sender<Slot>>>,
+}
+
+impl State {
+    /// Slots work of `rank` must leave free for others
+    fn reserve(&self, rank: u8) -> usize {
+        let nice = rank.saturating_sub(Priority::Normal.rank());
+        self.size * usize::from(nice) / 20
+    }
+
+    /// Take a slot for work of `rank`, if it may have one now
+    fn take(&mut self, rank: u8) -> bool {
+        if self.available > self.reserve(rank) {
+            self.available -= 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// The first waiter, if it may have a slot now; it is taken for it
+    fn next(&mut self) -> Option<oneshot::Sender<Slot>> {
+        let (&rank, _) = self.waiting.iter().find(|(_, queue)| !queue.is_empty())?;
+        // Those behind are nicer still, so they would have to wait too
+        if !self.take(rank) {
+            return None;
+        }
+        let queue = self.waiting.get_mut(&rank)?;
+        let next = queue.pop_front();
+        if queue.is_empty() {
+            self.waiting.remove(&rank);
+        }
+        next
+    }
+}
+
+/// A held slot, given back when dropped
+pub struct Slot {
+    slots: Slots,
+}
+
+impl Slots {
+    pub fn new(size: usize) -> Self {
+        Self {
+            state: Arc::new(Mutex::new(State {
+                size,
+                available: size,
+                waiting: BTreeMap::new(),
+            })),
+        }
+    }
+
+    /// A slot, if one is free right away for work of `priority`
+    pub fn try_acquire(&self, priority: Priority) -> Option<Slot> {
+        let mut state = self.state.lock().unwrap();
+        state.take(priority.rank()).then(|| Slot {
+            slots: self.clone(),
+        })
+    }
+
+    /// Slots not currently held
+    pub fn available(&self) -> usize {
+        self.state.lock().unwrap().available
+    }
+
+    /// Wait for a slot; dropping the future gives up the place in line
+    pub async fn acquire(&self, priority: Priority) -> Slot {
+        let waiting = {
+            let mut state = self.state.lock().unwrap();
+            let rank = priority.rank();
+            // A waiter that could have this slot would have it already
+            if state.take(rank) {
+                return Slot {
+                    slots: self.clone(),
+                };
+            }
+            let (sender, receiver) = oneshot::channel();
+            state.waiting.entry(rank).or_default().push_back(sender);
+            receiver
+        };
+        // Senders are only dropped after handing over a slot
+        waiting.await.expect("slot waiter dropped")
+    }
+
+    fn release(&self) {
+        let next = {
+            let mut state = self.state.lock().unwrap();
+            state.available += 1;
+            match state.next() {
+                Some(next) => next,
+                None => return,
+            }
+        };
+        // A waiter that gave up hands the slot straight back (on drop)
+        let _ = next.send(Slot {
+            slots: self.clone(),
+        });
+    }
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        self.slots.release();
+    }
+}
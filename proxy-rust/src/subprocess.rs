@@ -0,0 +1,222 @@
+//! Zig CLI subprocess management
+//!
+//! Every child is started through [`Cli::command`], which selects the JSON
+//! [`crate::protocol`] and applies the configured resource limits before
+//! `exec`. Failures are summarized into a
+//! short signature (e.g. `error: AuthenticationFailed`, `signal: SIGXCPU`)
+//! that is counted per distinct value and logged together with the tail of
+//! the child's stderr, instead of being lost in per-line log output.
+
+use crate::protocol;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::os::unix::process::ExitStatusExt;
+use std::process::ExitStatus;
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncBufReadExt, AsyncRead, BufReader};
+use tokio::process::Command;
+use tracing::{debug, error, trace, warn, Span};
+
+/// Number of stderr lines kept for diagnostics
+const STDERR_TAIL_LINES: usize = 20;
+
+/// Per-child resource limits (unset = inherit from the proxy)
+#[derive(Clone, Debug, Default)]
+pub struct ResourceLimits {
+    /// RLIMIT_CPU: CPU seconds before the child receives SIGXCPU
+    pub cpu_secs: Option<u64>,
+    /// RLIMIT_AS: maximum address space in bytes
+    pub memory_bytes: Option<u64>,
+}
+
+impl ResourceLimits {
+    /// Load limits from `CLI_RLIMIT_CPU_SECS` and `CLI_RLIMIT_MEMORY_MB`
+    pub fn from_env() -> Self {
+        Self {
+            cpu_secs: crate::config::number("CLI_RLIMIT_CPU_SECS"),
+            memory_bytes: crate::config::number::<u64>("CLI_RLIMIT_MEMORY_MB")
+                .map(|mb| mb * 1024 * 1024),
+        }
+    }
+}
+
+/// Handle to the Zig CLI backend
+#[derive(Clone)]
+pub struct Cli {
+    bin_path: String,
+    limits: ResourceLimits,
+    failures: Arc<Mutex<HashMap<String, u64>>>,
+}
+
+impl Cli {
+    pub fn new(bin_path: String, limits: ResourceLimits) -> Self {
+        Self {
+            bin_path,
+            limits,
+            failures: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Build a command for the CLI with resource limits applied
+    pub fn command(&self) -> Command {
+        let mut command = Command::new(&self.bin_path);
+        command.arg("--json");
+        // The current request's hub, see crate::hub
+        command.env("HF_ENDPOINT", &*crate::hub::endpoint());
+        crate::trust::apply_to_cli(&mut command);
+        let limits = self.limits.clone();
+        if limits.cpu_secs.is_some() || limits.memory_bytes.is_some() {
+            // SAFETY: the closure only calls setrlimit, which is async-signal-safe
+            unsafe {
+                command.pre_exec(move || {
+                    if let Some(secs) = limits.cpu_secs {
+                        set_rlimit(libc::RLIMIT_CPU, secs)?;
+                    }
+                    if let Some(bytes) = limits.memory_bytes {
+                        set_rlimit(libc::RLIMIT_AS, bytes)?;
+                    }
+                    Ok(())
+                });
+            }
+        }
+        command
+    }
+
+    /// Run `--version`, to tell whether the binary runs at all; its first
+    /// line of output if so
+    pub async fn version(&self) -> Result<String, String> {
+        let output = Command::new(&self.bin_path)
+            .arg("--version")
+            .kill_on_drop(true)
+            .output()
+            .await
+            .map_err(|e| format!("{} cannot be run: {}", self.bin_path, e))?;
+        if !output.status.success() {
+            return Err(format!(
+                "{} --version failed ({})",
+                self.bin_path, output.status
+            ));
+        }
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        let version = stdout.lines().next().unwrap_or("").trim();
+        Ok(format!("{} runs: {}", self.bin_path, version))
+    }
+
+    /// Failed children so far, by signature
+    pub fn failure_counts(&self) -> BTreeMap<String, u64> {
+        let failures = self.failures.lock().unwrap();
+        failures.iter().map(|(k, v)| (k.clone(), *v)).collect()
+    }
+
+    /// Record a failed child and return its signature
+    pub fn record_failure(&self, status: &ExitStatus, stderr_tail: &[String]) -> String {
+        let signature = failure_signature(status, stderr_tail);
+        self.record_signature(&signature, &status.to_string(), stderr_tail);
+        signature
+    }
+
+    /// Record a failure whose signature is already known, such as a worker's
+    /// `error: <Name>` reply
+    pub fn record_signature(&self, signature: &str, context: &str, stderr_tail: &[String]) {
+        let count = {
+            let mut failures = self.failures.lock().unwrap();
+            let count = failures.entry(signature.to_string()).or_insert(0);
+            *count += 1;
+            *count
+        };
+        error!(
+            signature = %signature,
+            count,
+            "Zig CLI failed ({}); stderr tail:\n{}",
+            context,
+            stderr_tail.join("\n")
+        );
+    }
+}
+
+#[cfg(all(target_os = "linux", target_env = "gnu"))]
+type RlimitResource = libc::__rlimit_resource_t;
+#[cfg(not(all(target_os = "linux", target_env = "gnu")))]
+type RlimitResource = libc::c_int;
+
+fn set_rlimit(resource: RlimitResource, value: u64) -> std::io::Result<()> {
+    let limit = libc::rlimit {
+        rlim_cur: value as libc::rlim_t,
+        rlim_max: value as libc::rlim_t,
+    };
+    if unsafe { libc::setrlimit(resource, &limit) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Reduce a failure to a stable, low-cardinality signature
+fn failure_signature(status: &ExitStatus, stderr_tail: &[String]) -> String {
+    if let Some(signal) = status.signal() {
+        return format!("signal: {}", signal_name(signal));
+    }
+    // The CLI reports failures as an `error` message; Zig itself prints
+    // "error: <ErrorName>" for errors escaping main
+    let zig_error = stderr_tail.iter().rev().find_map(|line| {
+        protocol::error_signature(line).or_else(|| {
+            let name = line.trim().strip_prefix("error: ")?;
+            Some(format!(
+                "error: {}",
+                name.split_whitespace().next().unwrap_or(name)
+            ))
+        })
+    });
+    zig_error.unwrap_or_else(|| format!("exit: {}", status.code().unwrap_or(-1)))
+}
+
+fn signal_name(signal: i32) -> String {
+    match signal {
+        libc::SIGKILL => "SIGKILL".to_string(),
+        libc::SIGSEGV => "SIGSEGV".to_string(),
+        libc::SIGABRT => "SIGABRT".to_string(),
+        libc::SIGXCPU => "SIGXCPU".to_string(),
+        libc::SIGTERM => "SIGTERM".to_string(),
+        libc::SIGPIPE => "SIGPIPE".to_string(),
+        other => other.to_string(),
+    }
+}
+
+/// Read a child's stderr to the end, keeping only the last lines; each is
+/// logged in the span `span` returns at the time, that of the request the
+/// child is working for
+pub async fn collect_stderr_tail<R: AsyncRead + Unpin>(
+    stderr: R,
+    label: &str,
+    span: impl Fn() -> Span,
+) -> Vec<String> {
+    let mut tail = VecDeque::with_capacity(STDERR_TAIL_LINES);
+    let mut lines = BufReader::new(stderr).lines();
+    loop {
+        match lines.next_line().await {
+            Ok(Some(line)) => {
+                if let Some(bytes) = protocol::progress_bytes(&line) {
+                    trace!("zig progress [{}]: {} bytes", label, bytes);
+                    continue;
+                }
+                span().in_scope(|| debug!("zig stderr [{}]: {}", label, line));
+                if tail.len() == STDERR_TAIL_LINES {
+                    tail.pop_front();
+                }
+                tail.push_back(line);
+            }
+            Ok(None) => break,
+            Err(e) => {
+                warn!("Failed to read zig stderr [{}]: {}", label, e);
+                break;
+            }
+        }
+    }
+    tail.into()
+}
+
+/// Last lines of captured stderr output
+pub fn tail_lines(stderr: &[u8]) -> Vec<String> {
+    let text = String::from_utf8_lossy(stderr);
+    let lines: Vec<&str> = text.lines().collect();
+    let start = lines.len().saturating_sub(STDERR_TAIL_LINES);
+    lines[start..].iter().map(|l| l.to_string()).collect()
+}
@@ -0,0 +1,360 @@
+//! Per-client limits, adjustable at runtime
+//!
+//! Admins (clients whose grant has `admin`, and `API_KEYS` keys) set limits
+//! for any authenticated client by its name through `/admin/limits`:
+//!
+//! - `requests_per_minute` replaces the one of the client's grant;
+//! - `daily_bytes` caps the bytes served to the client per UTC day: once
+//!   reached, its requests get 429 until midnight (a download already
+//!   streaming finishes);
+//! - `bandwidth_bytes_per_sec` caps the rate of everything served to the
+//!   client, all its downloads together.
+//!
+//! Changes apply to the next request, and to the next chunk of downloads
+//! already streaming. With `TENANT_LIMITS_FILE` they are written back to
+//! that JSON file, loaded at startup and reread on `SIGHUP`; without it they
+//! last until the proxy restarts. Bytes served are counted in memory.
+
+use crate::auth::Grant;
+use crate::AppError;
+use axum::body::{Body, Bytes};
+use axum::extract::{Request, State};
+use axum::middleware::Next;
+use axum::response::Response;
+use http_body::{Frame, SizeHint};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, RwLock};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tracing::{error, info, warn};
+
+const DAY_SECS: u64 = 86_400;
+
+/// Limits of one client; unset ones don't apply
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TenantLimits {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub requests_per_minute: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub daily_bytes: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bandwidth_bytes_per_sec: Option<u64>,
+}
+
+impl TenantLimits {
+    fn validate(&self) -> Result<(), String> {
+        if self.requests_per_minute == Some(0) {
+            return Err("requests_per_minute must be positive".to_string());
+        }
+        if self.daily_bytes == Some(0) {
+            return Err("daily_bytes must be positive".to_string());
+        }
+        if self.bandwidth_bytes_per_sec == Some(0) {
+            return Err("bandwidth_bytes_per_sec must be positive".to_string());
+        }
+        Ok(())
+    }
+}
+
+/// A client's limits with what it used today, as reported by the admin
+/// endpoints
+#[derive(Serialize)]
+pub struct TenantStatus {
+    #[serde(flatten)]
+    pub limits: TenantLimits,
+    pub used_bytes_today: u64,
+}
+
+/// Bytes served to a client on one UTC day
+struct Usage {
+    day: u64,
+    bytes: u64,
+}
+
+/// Token bucket of a client's bandwidth, which may go into debt
+struct Bandwidth {
+    tokens: f64,
+    updated: Instant,
+}
+
+#[derive(Clone)]
+pub struct Tenants {
+    file: Option<Arc<str>>,
+    limits: Arc<RwLock<BTreeMap<String, TenantLimits>>>,
+    usage: Arc<Mutex<HashMap<String, Usage>>>,
+    bandwidth: Arc<Mutex<HashMap<String, Bandwidth>>>,
+}
+
+impl Tenants {
+    /// Load `TENANT_LIMITS_FILE`, if it exists
+    pub fn from_env() -> Self {
+        let file = std::env::var("TENANT_LIMITS_FILE").ok();
+        let limits = match &file {
+            Some(path) if std::path::Path::new(path).exists() => {
+                load(path).unwrap_or_else(|e| panic!("{}", e))
+            }
+            _ => BTreeMap::new(),
+        };
+        if let Some(path) = &file {
+            info!("Loaded limits of {} clients from {}", limits.len(), path);
+        }
+        Self {
+            file: file.map(Into::into),
+            limits: Arc::new(RwLock::new(limits)),
+            usage: Arc::default(),
+            bandwidth: Arc::default(),
+        }
+    }
+
+    /// Where changes are persisted, if anywhere
+    pub fn file(&self) -> Option<&str> {
+        self.file.as_deref()
+    }
+
+    /// Every client with limits set
+    pub fn all(&self) -> BTreeMap<String, TenantStatus> {
+        let limits = self.limits.read().unwrap().clone();
+        limits
+            .into_iter()
+            .map(|(name, limits)| {
+                let status = TenantStatus {
+                    used_bytes_today: self.used_today(&name),
+                    limits,
+                };
+                (name, status)
+            })
+            .collect()
+    }
+
+    /// One client's limits and usage
+    pub fn status(&self, name: &str) -> TenantStatus {
+        TenantStatus {
+            limits: self.get(name).unwrap_or_default(),
+            used_bytes_today: self.used_today(name),
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Option<TenantLimits> {
+        self.limits.read().unwrap().get(name).cloned()
+    }
+
+    /// Replace a client's limits, and persist them
+    pub fn set(&self, name: &str, limits: TenantLimits) -> Result<(), AppError> {
+        limits.validate().map_err(AppError::BadRequest)?;
+        let mut all = self.limits.write().unwrap();
+        let previous = all.insert(name.to_string(), limits);
+        if let Err(e) = self.persist(&all) {
+            match previous {
+                Some(previous) => all.insert(name.to_string(), previous),
+                None => all.remove(name),
+            };
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    /// Drop a client's limits, and persist that; false if it had none
+    pub fn remove(&self, name: &str) -> Result<bool, AppError> {
+        let mut all = self.limits.write().unwrap();
+        let Some(previous) = all.remove(name) else {
+            return Ok(false);
+        };
+        if let Err(e) = self.persist(&all) {
+            all.insert(name.to_string(), previous);
+            return Err(e);
+        }
+        Ok(true)
+    }
+
+    /// Reread `TENANT_LIMITS_FILE`, on `SIGHUP`
+    pub fn reload(&self) {
+        let Some(path) = &self.file else {
+            return;
+        };
+        match load(path) {
+            Ok(limits) => {
+                info!("Reloaded limits of {} clients", limits.len());
+                *self.limits.write().unwrap() = limits;
+            }
+            Err(e) => error!("Keeping the previous client limits: {}", e),
+        }
+    }
+
+    /// Bytes served to a client today
+    pub fn used_today(&self, name: &str) -> u64 {
+        let usage = self.usage.lock().unwrap();
+        usage
+            .get(name)
+            .filter(|usage| usage.day == today())
+            .map_or(0, |usage| usage.bytes)
+    }
+
+    /// Refuse with 429 a client that used up its daily bytes
+    fn check_quota(&self, name: &str) -> Result<(), AppError> {
+        let Some(quota) = self.get(name).and_then(|limits| limits.daily_bytes) else {
+            return Ok(());
+        };
+        if self.used_today(name) < quota {
+            return Ok(());
+        }
+        let now = unix_secs();
+        Err(AppError::RateLimited {
+            message: format!("'{}' used up its {} bytes for today", name, quota),
+            retry_after: Some(Duration::from_secs(DAY_SECS - now % DAY_SECS)),
+        })
+    }
+
+    /// Count `n` bytes served to a client; returns how long to hold them
+    /// back to keep within its bandwidth
+    fn consume(&self, name: &str, n: u64) -> Option<Duration> {
+        let day = today();
+        {
+            let mut usage = self.usage.lock().unwrap();
+            let usage = usage
+                .entry(name.to_string())
+                .or_insert(Usage { day, bytes: 0 });
+            if usage.day != day {
+                *usage = Usage { day, bytes: 0 };
+            }
+            usage.bytes += n;
+        }
+        let rate = self.get(name)?.bandwidth_bytes_per_sec? as f64;
+        let mut buckets = self.bandwidth.lock().unwrap();
+        let now = Instant::now();
+        let bucket = buckets.entry(name.to_string()).or_insert(Bandwidth {
+            tokens: rate,
+            updated: now,
+        });
+        let refill = now.duration_since(bucket.updated).as_secs_f64() * rate;
+        bucket.tokens = (bucket.tokens + refill).min(rate) - n as f64;
+        bucket.updated = now;
+        (bucket.tokens < 0.0).then(|| Duration::from_secs_f64(-bucket.tokens / rate))
+    }
+
+    /// Whether anything is counted or shaped for a client
+    fn meters(&self, name: &str) -> bool {
+        self.get(name).is_some_and(|limits| {
+            limits.daily_bytes.is_some() || limits.bandwidth_bytes_per_sec.is_some()
+        })
+    }
+
+    fn persist(&self, limits: &BTreeMap<String, TenantLimits>) -> Result<(), AppError> {
+        let Some(path) = &self.file else {
+            return Ok(());
+        };
+        let failed = |e: std::io::Error| {
+            AppError::Internal(format!(
+                "Failed to write TENANT_LIMITS_FILE {}: {}",
+                path, e
+            ))
+        };
+        let json = serde_json::to_vec_pretty(limits)
+            .map_err(|e| AppError::Internal(format!("Failed to serialize limits: {}", e)))?;
+        // Written aside and renamed, so a crash never leaves half a file
+        let temp = format!("{}.tmp", path);
+        std::fs::write(&temp, json).map_err(failed)?;
+        std::fs::rename(&temp, &**path).map_err(failed)?;
+        info!("Saved limits of {} clients to {}", limits.len(), path);
+        Ok(())
+    }
+}
+
+fn load(path: &str) -> Result<BTreeMap<String, TenantLimits>, String> {
+    let text = std::fs::read_to_string(path)
+        .map_err(|e| format!("Failed to read TENANT_LIMITS_FILE {}: {}", path, e))?;
+    let limits: BTreeMap<String, TenantLimits> = serde_json::from_str(&text)
+        .map_err(|e| format!("Invalid TENANT_LIMITS_FILE {}: {}", path, e))?;
+    for (name, limits) in &limits {
+        limits
+            .validate()
+            .map_err(|e| format!("TENANT_LIMITS_FILE {}: client '{}': {}", path, name, e))?;
+    }
+    Ok(limits)
+}
+
+fn unix_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+fn today() -> u64 {
+    unix_secs() / DAY_SECS
+}
+
+/// Middleware enforcing the daily bytes of the authenticated client, and
+/// counting and shaping what it is served
+pub async fn meter(
+    State(tenants): State<Tenants>,
+    request: Request,
+    next: Next,
+) -> Result<Response, AppError> {
+    let Some(name) = request
+        .extensions()
+        .get::<Grant>()
+        .map(|grant| grant.name.clone())
+    else {
+        return Ok(next.run(request).await);
+    };
+    tenants
+        .check_quota(&name)
+        .inspect_err(|e| warn!("Refused {}: {}", request.uri().path(), e.message()))?;
+    let response = next.run(request).await;
+    if !tenants.meters(&name) {
+        return Ok(response);
+    }
+    Ok(response.map(|body| {
+        Body::new(Metered {
+            inner: body,
+            tenants,
+            name,
+            delay: None,
+        })
+    }))
+}
+
+/// Response body counted against a client's limits and held back to its
+/// bandwidth
+struct Metered {
+    inner: Body,
+    tenants: Tenants,
+    name: Arc<str>,
+    delay: Option<Pin<Box<tokio::time::Sleep>>>,
+}
+
+impl http_body::Body for Metered {
+    type Data = Bytes;
+    type Error = axum::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, axum::Error>>> {
+        if let Some(delay) = self.delay.as_mut() {
+            if delay.as_mut().poll(cx).is_pending() {
+                return Poll::Pending;
+            }
+            self.delay = None;
+        }
+        let item = Pin::new(&mut self.inner).poll_frame(cx);
+        let chunk = match &item {
+            Poll::Ready(Some(Ok(frame))) => frame.data_ref(),
+            _ => None,
+        };
+        if let Some(chunk) = chunk {
+            // The chunk goes out now; the wait holds back the next one
+            if let Some(wait) = self.tenants.consume(&self.name, chunk.len() as u64) {
+                self.delay = Some(Box::pin(tokio::time::sleep(wait)));
+            }
+        }
+        item
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
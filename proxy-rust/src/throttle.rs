@@ -0,0 +1,291 @@
+//! Per-client rate limiting and bandwidth shaping, by route
+//!
+//! `RATE_LIMITS_FILE` names a JSON file of limits per route, as routes are
+//! written in the router (and in the `route` label of the metrics); `*`
+//! applies to every route without its own entry, except the health probes:
+//!
+//! ```json
+//! {
+//!   "client": "api_key",
+//!   "routes": {
+//!     "/download/:owner/:repo/*file": { "requests_per_minute": 120, "bytes_per_sec": 52428800 },
+//!     "/download-archive/:owner/:repo": { "requests_per_minute": 6, "bytes_per_sec": 20971520 },
+//!     "*": { "requests_per_minute": 600 }
+//!   }
+//! }
+//! ```
+//!
+//! Clients are told apart by IP address (`"client": "ip"`, the default), or
+//! by the key they send in `X-API-Key`, falling back to their address
+//! (`"api_key"`). Behind a load balancer, `"trust_forwarded_for": true`
+//! takes the address from the first `X-Forwarded-For` entry.
+//!
+//! A client over a route's `requests_per_minute` gets 429 with
+//! `Retry-After`; the allowance refills evenly over the minute, and every
+//! route has its own, `*` ones too. Each
+//! response of a route with `bytes_per_sec` streams no faster than that,
+//! whatever else the client downloads at the same time.
+
+use crate::AppError;
+use axum::body::{Body, Bytes};
+use axum::extract::{ConnectInfo, MatchedPath, Request, State};
+use axum::http::HeaderMap;
+use axum::middleware::Next;
+use axum::response::Response;
+use http_body::{Frame, SizeHint};
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap};
+use std::future::Future;
+use std::hash::{BuildHasher, RandomState};
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+/// Request buckets beyond which idle ones are dropped
+const PRUNE_AT: usize = 10_000;
+/// Routes `*` doesn't cover, so health probes aren't turned away
+const PROBES: [&str; 3] = ["/health", "/healthz", "/readyz"];
+
+#[derive(Clone, Copy, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+enum ClientKey {
+    #[default]
+    Ip,
+    ApiKey,
+}
+
+/// Limits of one route
+#[derive(Clone, Copy, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RouteLimits {
+    requests_per_minute: Option<u32>,
+    bytes_per_sec: Option<u64>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RulesFile {
+    #[serde(default)]
+    client: ClientKey,
+    #[serde(default)]
+    trust_forwarded_for: bool,
+    routes: BTreeMap<String, RouteLimits>,
+}
+
+/// Token bucket refilling `requests_per_minute` a minute
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+#[derive(Clone)]
+pub struct Throttle {
+    client: ClientKey,
+    trust_forwarded_for: bool,
+    routes: Arc<BTreeMap<String, RouteLimits>>,
+    /// Fingerprints API keys, so they aren't kept in memory
+    hasher: RandomState,
+    buckets: Arc<Mutex<HashMap<(String, String), Bucket>>>,
+    limited: Arc<AtomicU64>,
+}
+
+impl Throttle {
+    /// Load `RATE_LIMITS_FILE`; `None` if unset
+    pub fn from_env() -> Option<Self> {
+        let path = std::env::var("RATE_LIMITS_FILE").ok()?;
+        let text = std::fs::read_to_string(&path)
+            .unwrap_or_else(|e| panic!("Failed to read RATE_LIMITS_FILE {}: {}", path, e));
+        let rules: RulesFile = serde_json::from_str(&text)
+            .unwrap_or_else(|e| panic!("Invalid RATE_LIMITS_FILE {}: {}", path, e));
+        for (route, limits) in &rules.routes {
+            assert!(
+                route == "*" || route.starts_with('/'),
+                "RATE_LIMITS_FILE {}: route '{}' must start with / (or be *)",
+                path,
+                route
+            );
+            assert!(
+                limits.requests_per_minute != Some(0) && limits.bytes_per_sec != Some(0),
+                "RATE_LIMITS_FILE {}: limits of '{}' must be positive",
+                path,
+                route
+            );
+        }
+        info!(
+            "Rate limits of {} routes loaded from {} (clients by {})",
+            rules.routes.len(),
+            path,
+            match rules.client {
+                ClientKey::Ip => "IP address",
+                ClientKey::ApiKey => "API key",
+            }
+        );
+        Some(Self {
+            client: rules.client,
+            trust_forwarded_for: rules.trust_forwarded_for,
+            routes: Arc::new(rules.routes),
+            hasher: RandomState::new(),
+            buckets: Arc::default(),
+            limited: Arc::default(),
+        })
+    }
+
+    /// Requests refused over a route's rate so far
+    pub fn limited(&self) -> u64 {
+        self.limited.load(Ordering::Relaxed)
+    }
+
+    /// The limits applying to `route`
+    fn rule(&self, route: &str) -> Option<RouteLimits> {
+        self.routes
+            .get(route)
+            .or_else(|| {
+                let probe = PROBES.contains(&route);
+                self.routes.get("*").filter(|_| !probe)
+            })
+            .copied()
+    }
+
+    /// Who is asking, as this throttle tells clients apart
+    fn client(&self, headers: &HeaderMap, peer: Option<SocketAddr>) -> String {
+        if self.client == ClientKey::ApiKey {
+            if let Some(key) = headers.get(crate::auth::API_KEY_HEADER) {
+                return format!("key:{:016x}", self.hasher.hash_one(key.as_bytes()));
+            }
+        }
+        let forwarded = self
+            .trust_forwarded_for
+            .then(|| headers.get("x-forwarded-for")?.to_str().ok())
+            .flatten()
+            .and_then(|list| list.split(',').next())
+            .map(str::trim)
+            .filter(|ip| !ip.is_empty());
+        match (forwarded, peer) {
+            (Some(ip), _) => format!("ip:{}", ip),
+            (None, Some(peer)) => format!("ip:{}", peer.ip()),
+            (None, None) => "ip:unknown".to_string(),
+        }
+    }
+
+    /// Take one request from the client's allowance on `route`
+    fn charge(&self, route: &str, client: String, limit: u32) -> Result<(), AppError> {
+        let limit = f64::from(limit);
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() >= PRUNE_AT {
+            // Idle a minute means full again, as good as new
+            buckets
+                .retain(|_, bucket| now.duration_since(bucket.updated) < Duration::from_secs(60));
+        }
+        let bucket = buckets
+            .entry((route.to_string(), client))
+            .or_insert(Bucket {
+                tokens: limit,
+                updated: now,
+            });
+        let refill = now.duration_since(bucket.updated).as_secs_f64() * limit / 60.0;
+        bucket.tokens = (bucket.tokens + refill).min(limit);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            return Ok(());
+        }
+        self.limited.fetch_add(1, Ordering::Relaxed);
+        let wait = Duration::from_secs_f64((1.0 - bucket.tokens) * 60.0 / limit);
+        Err(AppError::RateLimited {
+            message: format!("Over {} requests per minute on {}", limit, route),
+            retry_after: Some(wait),
+        })
+    }
+}
+
+/// Middleware applying the limits of the matched route
+pub async fn limit(
+    State(throttle): State<Throttle>,
+    request: Request,
+    next: Next,
+) -> Result<Response, AppError> {
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map_or("", MatchedPath::as_str);
+    let Some(limits) = throttle.rule(route) else {
+        return Ok(next.run(request).await);
+    };
+    let route = route.to_string();
+    let peer = request
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(peer)| *peer);
+    let client = throttle.client(request.headers(), peer);
+    if let Some(limit) = limits.requests_per_minute {
+        throttle
+            .charge(&route, client.clone(), limit)
+            .inspect_err(|e| warn!("Throttled {}: {}", client, e.message()))?;
+    }
+    let response = next.run(request).await;
+    let Some(rate) = limits.bytes_per_sec else {
+        return Ok(response);
+    };
+    Ok(response.map(|body| {
+        Body::new(Shaped {
+            inner: body,
+            rate: rate as f64,
+            tokens: rate as f64,
+            updated: Instant::now(),
+            delay: None,
+        })
+    }))
+}
+
+/// Response body held back to `rate` bytes per second, with bursts of up to
+/// a second's worth
+struct Shaped {
+    inner: Body,
+    rate: f64,
+    tokens: f64,
+    updated: Instant,
+    delay: Option<Pin<Box<tokio::time::Sleep>>>,
+}
+
+impl http_body::Body for Shaped {
+    type Data = Bytes;
+    type Error = axum::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, axum::Error>>> {
+        if let Some(delay) = self.delay.as_mut() {
+            if delay.as_mut().poll(cx).is_pending() {
+                return Poll::Pending;
+            }
+            self.delay = None;
+        }
+        let item = Pin::new(&mut self.inner).poll_frame(cx);
+        let chunk = match &item {
+            Poll::Ready(Some(Ok(frame))) => frame.data_ref(),
+            _ => None,
+        };
+        if let Some(chunk) = chunk {
+            // The chunk goes out now; the wait holds back the next one
+            let now = Instant::now();
+            let refill = now.duration_since(self.updated).as_secs_f64() * self.rate;
+            self.tokens = (self.tokens + refill).min(self.rate) - chunk.len() as f64;
+            self.updated = now;
+            if self.tokens < 0.0 {
+                let wait = Duration::from_secs_f64(-self.tokens / self.rate);
+                self.delay = Some(Box::pin(tokio::time::sleep(wait)));
+            }
+        }
+        item
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
@@ -0,0 +1,230 @@
+//! Native HTTPS, with optional client certificates (mTLS)
+//!
+//! With `TLS_CERT_FILE` and `TLS_KEY_FILE` (PEM; the certificate file may
+//! hold the whole chain) the proxy also serves HTTPS, on `TLS_PORT`
+//! (default 8443), next to plain HTTP on `PORT`. `TLS_ONLY=true` drops the
+//! plain listener. Both serve the same routes; HTTPS speaks HTTP/2 to
+//! clients that offer it.
+//!
+//! `TLS_CLIENT_CA_FILE` (PEM) asks clients for a certificate issued by one
+//! of its CAs, and refuses the handshake of any other.
+//! `TLS_CLIENT_AUTH=optional` also lets clients without a certificate in,
+//! leaving them to the other authentication providers. The subject of a
+//! verified certificate, its names in the certificate's order (e.g.
+//! `O=Example, CN=ci-runner`), is the client's identity for the `mtls`
+//! provider (see [`crate::auth`]).
+//!
+//! Files are read at startup; a renewed certificate takes a restart.
+
+use axum::extract::ConnectInfo;
+use axum::Router;
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use hyper_util::server::conn::auto;
+use hyper_util::server::graceful::GracefulShutdown;
+use hyper_util::service::TowerToHyperService;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::server::WebPkiClientVerifier;
+use rustls::{RootCertStore, ServerConfig};
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tokio_rustls::TlsAcceptor;
+use tower::ServiceExt;
+use tracing::{debug, info, warn};
+
+/// Time a client has to complete the handshake
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Subject of a client's verified certificate, in the extensions of its
+/// requests
+#[derive(Clone)]
+pub struct ClientIdentity(pub Arc<str>);
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum ClientAuth {
+    Required,
+    Optional,
+}
+
+pub struct Tls {
+    port: u16,
+    only: bool,
+    client_auth: Option<ClientAuth>,
+    acceptor: TlsAcceptor,
+}
+
+impl Tls {
+    /// Load `TLS_*`; `None` without `TLS_CERT_FILE`
+    pub fn from_env() -> Option<Self> {
+        let cert_file = std::env::var("TLS_CERT_FILE").ok();
+        let key_file = std::env::var("TLS_KEY_FILE").ok();
+        let (cert_file, key_file) = match (cert_file, key_file) {
+            (Some(cert_file), Some(key_file)) => (cert_file, key_file),
+            (None, None) => return None,
+            _ => panic!("TLS_CERT_FILE and TLS_KEY_FILE go together"),
+        };
+        let port = crate::config::number("TLS_PORT").unwrap_or(8443);
+        let only = crate::config::flag("TLS_ONLY").unwrap_or(false);
+        assert!(
+            only || Some(port) != crate::ports::data_port(),
+            "TLS_PORT must differ from PORT, unless TLS_ONLY is set"
+        );
+
+        let chain = CertificateDer::pem_file_iter(&cert_file)
+            .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+            .unwrap_or_else(|e| panic!("Failed to read TLS_CERT_FILE {}: {}", cert_file, e));
+        assert!(
+            !chain.is_empty(),
+            "TLS_CERT_FILE {} holds no certificate",
+            cert_file
+        );
+        let key = PrivateKeyDer::from_pem_file(&key_file)
+            .unwrap_or_else(|e| panic!("Failed to read TLS_KEY_FILE {}: {}", key_file, e));
+
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let client_auth = match std::env::var("TLS_CLIENT_AUTH").as_deref() {
+            Err(_) | Ok("required") => ClientAuth::Required,
+            Ok("optional") => ClientAuth::Optional,
+            Ok(other) => panic!(
+                "TLS_CLIENT_AUTH must be 'required' or 'optional', got '{}'",
+                other
+            ),
+        };
+        let client_auth = std::env::var("TLS_CLIENT_CA_FILE")
+            .ok()
+            .map(|ca_file| (client_auth, ca_file));
+        let builder = ServerConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()
+            .expect("TLS protocol versions");
+        let builder = match &client_auth {
+            None => builder.with_no_client_auth(),
+            Some((client_auth, ca_file)) => {
+                let mut roots = RootCertStore::empty();
+                let cas = CertificateDer::pem_file_iter(ca_file)
+                    .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+                    .unwrap_or_else(|e| {
+                        panic!("Failed to read TLS_CLIENT_CA_FILE {}: {}", ca_file, e)
+                    });
+                for ca in cas {
+                    roots.add(ca).unwrap_or_else(|e| {
+                        panic!("Invalid CA in TLS_CLIENT_CA_FILE {}: {}", ca_file, e)
+                    });
+                }
+                assert!(
+                    !roots.is_empty(),
+                    "TLS_CLIENT_CA_FILE {} holds no certificate",
+                    ca_file
+                );
+                let verifier =
+                    WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider);
+                let verifier = match client_auth {
+                    ClientAuth::Required => verifier,
+                    ClientAuth::Optional => verifier.allow_unauthenticated(),
+                };
+                let verifier = verifier
+                    .build()
+                    .unwrap_or_else(|e| panic!("Invalid TLS_CLIENT_CA_FILE {}: {}", ca_file, e));
+                builder.with_client_cert_verifier(verifier)
+            }
+        };
+        let mut config = builder.with_single_cert(chain, key).unwrap_or_else(|e| {
+            panic!(
+                "TLS_KEY_FILE {} doesn't go with TLS_CERT_FILE {}: {}",
+                key_file, cert_file, e
+            )
+        });
+        config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+        info!("HTTPS certificate loaded from {}", cert_file);
+        Some(Self {
+            port,
+            only,
+            client_auth: client_auth.map(|(client_auth, _)| client_auth),
+            acceptor: TlsAcceptor::from(Arc::new(config)),
+        })
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    /// Whether plain HTTP is off
+    pub fn only(&self) -> bool {
+        self.only
+    }
+
+    /// Whether client certificates are verified, for the startup banner
+    pub fn client_auth(&self) -> &'static str {
+        match self.client_auth {
+            None => "no client certificates",
+            Some(ClientAuth::Required) => "client certificates required",
+            Some(ClientAuth::Optional) => "client certificates verified when sent",
+        }
+    }
+
+    /// Serve `app` over HTTPS until `draining` resolves, then wait for the
+    /// connections to finish their requests
+    pub async fn serve(
+        &self,
+        listener: TcpListener,
+        app: Router,
+        draining: impl Future<Output = ()>,
+    ) {
+        let graceful = GracefulShutdown::new();
+        tokio::pin!(draining);
+        loop {
+            let (stream, peer) = tokio::select! {
+                accepted = listener.accept() => match accepted {
+                    Ok(accepted) => accepted,
+                    Err(e) => {
+                        // Out of descriptors, typically: give it a moment
+                        warn!("Failed to accept an HTTPS connection: {}", e);
+                        tokio::time::sleep(Duration::from_millis(100)).await;
+                        continue;
+                    }
+                },
+                _ = &mut draining => break,
+            };
+            let acceptor = self.acceptor.clone();
+            let app = app.clone();
+            let watcher = graceful.watcher();
+            tokio::spawn(async move {
+                let stream =
+                    match tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                        Ok(Ok(stream)) => stream,
+                        Ok(Err(e)) => return debug!("TLS handshake with {} failed: {}", peer, e),
+                        Err(_) => return debug!("TLS handshake with {} timed out", peer),
+                    };
+                let identity = stream
+                    .get_ref()
+                    .1
+                    .peer_certificates()
+                    .and_then(|chain| chain.first())
+                    .and_then(|cert| subject(cert));
+                // What axum::serve gives plain connections, and the identity
+                let service = app.map_request(move |mut request: axum::extract::Request<_>| {
+                    request.extensions_mut().insert(ConnectInfo(peer));
+                    if let Some(identity) = &identity {
+                        request.extensions_mut().insert(identity.clone());
+                    }
+                    request
+                });
+                let builder = auto::Builder::new(TokioExecutor::new());
+                let connection = builder
+                    .serve_connection(TokioIo::new(stream), TowerToHyperService::new(service));
+                if let Err(e) = watcher.watch(connection).await {
+                    debug!("HTTPS connection from {} ended: {}", peer, e);
+                }
+            });
+        }
+        drop(listener);
+        graceful.shutdown().await;
+    }
+}
+
+/// Subject of a client certificate
+fn subject(cert: &CertificateDer<'_>) -> Option<ClientIdentity> {
+    let (_, cert) = x509_parser::parse_x509_certificate(cert).ok()?;
+    Some(ClientIdentity(cert.subject().to_string().into()))
+}
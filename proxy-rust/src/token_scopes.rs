@@ -0,0 +1,287 @@
+//! Least privilege of `HF_TOKEN`
+//!
+//! With `READ_ONLY=true` the proxy refuses uploads, and only ever reads with
+//! `HF_TOKEN`: a token that can also write is more than it needs, and more
+//! than a leak should give away. At startup, then every
+//! `TOKEN_SCOPE_CHECK_SECS` (default 3600, 0 for startup only), the token's
+//! scopes are looked up with the Hub's `whoami-v2`. Write scopes in
+//! read-only mode are warned about, or with `TOKEN_SCOPE_POLICY=refuse`
+//! refused: the proxy doesn't start, or shuts down gracefully if the token
+//! gained them since. A Hub that can't be asked is only warned about.
+//!
+//! Scopes are the role of a classic token (`read`, `write`) and the
+//! permissions of a fine-grained one, global (`discussion.write`) or on an
+//! entity (`repo.content.read on user alice`); any `write` one can write.
+//! `GET /config` reports the latest check under `token`.
+
+use crate::shutdown::Shutdown;
+use serde::{Deserialize, Serialize};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::{error, info, warn};
+
+/// What to do about write scopes in read-only mode
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Policy {
+    Warn,
+    Refuse,
+}
+
+/// The token's scopes, as last checked
+#[derive(Clone, Serialize)]
+pub struct ScopeReport {
+    /// Account the token belongs to
+    pub account: Option<String>,
+    /// `read`, `write` or `fineGrained`
+    pub role: Option<String>,
+    pub scopes: Vec<String>,
+    /// Whether any scope can write
+    pub write: bool,
+    pub read_only: bool,
+    /// Why the scopes are unknown, if they are
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// Unix time of the check
+    pub checked_at: u64,
+}
+
+impl ScopeReport {
+    /// Whether the token has more privileges than the proxy needs
+    fn excessive(&self) -> bool {
+        self.read_only && self.write
+    }
+}
+
+#[derive(Clone)]
+pub struct TokenScopes {
+    token: Option<Arc<str>>,
+    read_only: bool,
+    policy: Policy,
+    interval: Option<Duration>,
+    http: reqwest::Client,
+    last: Arc<Mutex<Option<ScopeReport>>>,
+}
+
+impl TokenScopes {
+    /// Load `TOKEN_SCOPE_POLICY` and `TOKEN_SCOPE_CHECK_SECS`, for a proxy
+    /// that is `read_only` or not
+    pub fn from_env(read_only: bool) -> Self {
+        let policy = match std::env::var("TOKEN_SCOPE_POLICY").as_deref() {
+            Err(_) | Ok("warn") => Policy::Warn,
+            Ok("refuse") => Policy::Refuse,
+            Ok(other) => panic!(
+                "TOKEN_SCOPE_POLICY must be 'warn' or 'refuse', got '{}'",
+                other
+            ),
+        };
+        let secs = crate::config::number("TOKEN_SCOPE_CHECK_SECS").unwrap_or(3600);
+        let http = crate::trust::apply(reqwest::Client::builder())
+            .timeout(Duration::from_secs(10))
+            .user_agent(concat!("xet-proxy/", env!("CARGO_PKG_VERSION")))
+            .build()
+            .expect("Failed to build HTTP client");
+        Self {
+            // The samples of developer mode need no token
+            token: std::env::var("HF_TOKEN")
+                .ok()
+                .filter(|_| !crate::dev::enabled())
+                .map(Into::into),
+            read_only,
+            policy,
+            interval: (secs > 0).then(|| Duration::from_secs(secs)),
+            http,
+            last: Arc::default(),
+        }
+    }
+
+    /// Whether uploads are refused
+    pub fn read_only(&self) -> bool {
+        self.read_only
+    }
+
+    /// The latest check, if `HF_TOKEN` is set
+    pub fn report(&self) -> Option<ScopeReport> {
+        self.last.lock().unwrap().clone()
+    }
+
+    /// Check the token before serving; panics if it must be refused
+    pub async fn check_startup(&self) {
+        let Some(report) = self.check().await else {
+            return;
+        };
+        if let Some(e) = &report.error {
+            warn!("Could not check the scopes of HF_TOKEN: {}", e);
+            return;
+        }
+        info!(
+            "HF_TOKEN of {}: {}",
+            report.account.as_deref().unwrap_or("an unnamed account"),
+            describe(&report)
+        );
+        if report.excessive() {
+            if self.policy == Policy::Refuse {
+                panic!(
+                    "HF_TOKEN can write ({}) but the proxy is read-only; use a read token, or TOKEN_SCOPE_POLICY=warn",
+                    describe(&report)
+                );
+            }
+            warn!(
+                "HF_TOKEN can write ({}) but the proxy is read-only; a read token would do",
+                describe(&report)
+            );
+        }
+    }
+
+    /// Check the token every `TOKEN_SCOPE_CHECK_SECS`, shutting down if it
+    /// must be refused
+    pub fn start(&self, shutdown: Shutdown) {
+        let (Some(interval), Some(_)) = (self.interval, &self.token) else {
+            return;
+        };
+        let scopes = self.clone();
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(interval).await;
+                let Some(report) = scopes.check().await else {
+                    return;
+                };
+                if let Some(e) = &report.error {
+                    warn!("Could not check the scopes of HF_TOKEN: {}", e);
+                } else if report.excessive() && scopes.policy == Policy::Refuse {
+                    error!(
+                        "HF_TOKEN can write ({}) but the proxy is read-only, shutting down",
+                        describe(&report)
+                    );
+                    shutdown.begin();
+                    return;
+                } else if report.excessive() {
+                    warn!(
+                        "HF_TOKEN can write ({}) but the proxy is read-only; a read token would do",
+                        describe(&report)
+                    );
+                }
+            }
+        });
+    }
+
+    /// Look the token's scopes up and record them; `None` without a token
+    async fn check(&self) -> Option<ScopeReport> {
+        let token = self.token.as_deref()?;
+        let mut report = ScopeReport {
+            account: None,
+            role: None,
+            scopes: Vec::new(),
+            write: false,
+            read_only: self.read_only,
+            error: None,
+            checked_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_secs()),
+        };
+        match whoami(&self.http, token).await {
+            Ok(whoami) => {
+                let token = whoami.auth.access_token;
+                report.scopes = token.scopes();
+                report.write = report.scopes.iter().any(|scope| can_write(scope));
+                report.account = whoami.name;
+                report.role = token.role;
+            }
+            Err(e) => report.error = Some(e),
+        }
+        *self.last.lock().unwrap() = Some(report.clone());
+        Some(report)
+    }
+}
+
+/// The scopes, for logs
+fn describe(report: &ScopeReport) -> String {
+    match report.scopes.is_empty() {
+        true => "no scopes".to_string(),
+        false => report.scopes.join(", "),
+    }
+}
+
+/// Whether a scope, as reported, grants writing
+fn can_write(scope: &str) -> bool {
+    let permission = scope.split(' ').next().unwrap_or(scope);
+    permission == "write" || permission == "admin" || permission.ends_with(".write")
+}
+
+#[derive(Deserialize)]
+struct Whoami {
+    name: Option<String>,
+    #[serde(default)]
+    auth: Auth,
+}
+
+#[derive(Default, Deserialize)]
+struct Auth {
+    #[serde(rename = "accessToken", default)]
+    access_token: AccessToken,
+}
+
+#[derive(Default, Deserialize)]
+struct AccessToken {
+    role: Option<String>,
+    #[serde(rename = "fineGrained")]
+    fine_grained: Option<FineGrained>,
+}
+
+#[derive(Deserialize)]
+struct FineGrained {
+    #[serde(default)]
+    global: Vec<String>,
+    #[serde(default)]
+    scoped: Vec<Scoped>,
+}
+
+#[derive(Deserialize)]
+struct Scoped {
+    entity: Entity,
+    #[serde(default)]
+    permissions: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct Entity {
+    #[serde(rename = "type")]
+    kind: String,
+    name: String,
+}
+
+impl AccessToken {
+    /// The role of a classic token, the permissions of a fine-grained one
+    fn scopes(&self) -> Vec<String> {
+        let Some(fine_grained) = &self.fine_grained else {
+            return self.role.iter().cloned().collect();
+        };
+        let scoped = fine_grained.scoped.iter().flat_map(|scoped| {
+            scoped.permissions.iter().map(|permission| {
+                format!(
+                    "{} on {} {}",
+                    permission, scoped.entity.kind, scoped.entity.name
+                )
+            })
+        });
+        fine_grained.global.iter().cloned().chain(scoped).collect()
+    }
+}
+
+/// The Hub's account and token details for `token`
+async fn whoami(http: &reqwest::Client, token: &str) -> Result<Whoami, String> {
+    let url = format!("{}/api/whoami-v2", crate::hub::endpoint());
+    let response = http
+        .get(&url)
+        .bearer_auth(token)
+        .send()
+        .await
+        .map_err(|e| format!("Hub unreachable: {}", e))?;
+    match response.status() {
+        status if status.is_success() => response
+            .json()
+            .await
+            .map_err(|e| format!("unexpected whoami-v2 answer: {}", e)),
+        reqwest::StatusCode::UNAUTHORIZED => Err("rejected by the Hub (401)".to_string()),
+        status => Err(format!("Hub answered {}", status)),
+    }
+}
@@ -0,0 +1,353 @@
+//! Response body instrumentation
+//!
+//! [`TransferStream`] wraps a download body and reports its outcome: events
+//! on the activity bus, latency samples for SLO tracking, and one structured
+//! `transfer` log record per download with its byte accounting.
+//!
+//! Three byte counts are compared for every completed transfer: the size the
+//! listing announced (when known), the bytes read from the source (the CLI's
+//! stdout, or the cache) and the bytes handed to the client. A completed
+//! transfer where they disagree is logged as drift and counted, since it
+//! means something in the pipeline truncated or padded the file without
+//! failing.
+//!
+//! The record also says where the bytes came from (`cache_bytes` for the
+//! local cache, `peer_bytes` for a shared cache another proxy filled,
+//! `upstream_bytes` for CAS), the effective `throughput_bps` and the
+//! `retries` the request needed, to tell why a pull was fast or slow.
+//!
+//! With the repository, `revision` and `path` the file was requested as,
+//! the records are an audit log `xet-proxy replay` can check the current
+//! backend against (see [`crate::replay`]).
+
+use crate::downloader::Download;
+use crate::drain::{InFlight, Registration, Transfer};
+use crate::events::{EventBus, EventKind};
+use crate::metrics::Metrics;
+use crate::repo::RepoRef;
+use crate::resume::{AbortedTransfers, ClientKey};
+use crate::slo::SloTracker;
+use futures_core::Stream;
+use serde::Serialize;
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, ReadBuf};
+use tracing::{info, warn};
+
+/// Where a transfer's outcome is reported
+#[derive(Clone)]
+pub struct TransferObservers {
+    pub events: EventBus,
+    pub slo: SloTracker,
+    /// Completed transfers whose byte counts disagreed
+    pub drift_total: Arc<AtomicU64>,
+    pub metrics: Metrics,
+    /// Where interrupted transfers are remembered for resuming
+    pub aborts: AbortedTransfers,
+    /// Where transfers are listed while they stream
+    pub in_flight: InFlight,
+}
+
+/// Where a download's bytes come from
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Source {
+    Cache,
+    /// A shared cache, filled by another proxy
+    Peer,
+    Upstream,
+}
+
+impl Source {
+    pub fn of(download: &Download) -> Self {
+        match download.cached {
+            Some(_) if download.peer => Self::Peer,
+            Some(_) => Self::Cache,
+            None => Self::Upstream,
+        }
+    }
+}
+
+/// Bytes by where they came from, with the retries made getting them
+#[derive(Clone, Copy, Debug, Default, Serialize)]
+pub struct Breakdown {
+    pub cache_bytes: u64,
+    pub peer_bytes: u64,
+    pub upstream_bytes: u64,
+    pub retries: u64,
+}
+
+impl Breakdown {
+    pub fn add(&mut self, source: Source, bytes: u64) {
+        match source {
+            Source::Cache => self.cache_bytes += bytes,
+            Source::Peer => self.peer_bytes += bytes,
+            Source::Upstream => self.upstream_bytes += bytes,
+        }
+    }
+}
+
+/// What is known about a transfer before it starts
+pub struct TransferInfo {
+    pub route: &'static str,
+    pub hash: String,
+    /// Repository whose CAS token authorized the download
+    pub repo: RepoRef,
+    /// Path the file was requested as, unless requested by hash
+    pub path: Option<String>,
+    /// When the request was received
+    pub started: Instant,
+    /// Size announced by the listing, if the file came from one
+    pub expected_size: Option<u64>,
+    pub client: ClientKey,
+    /// Authenticated identity of the client, if any
+    pub identity: Option<String>,
+    /// Offset of the first byte sent, for a range
+    pub offset: u64,
+    pub ranged: bool,
+}
+
+/// Reader wrapper counting the bytes read from upstream
+pub struct CountingReader<R> {
+    inner: R,
+    count: Arc<AtomicU64>,
+}
+
+impl<R> CountingReader<R> {
+    pub fn new(inner: R) -> Self {
+        Self {
+            inner,
+            count: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    pub fn counter(&self) -> Arc<AtomicU64> {
+        self.count.clone()
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for CountingReader<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let before = buf.filled().len();
+        let poll = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(())) = poll {
+            let read = (buf.filled().len() - before) as u64;
+            self.count.fetch_add(read, Ordering::Relaxed);
+        }
+        poll
+    }
+}
+
+/// Body stream wrapper that reports download completion
+///
+/// Emits `download_finished` when the inner stream ends cleanly or the
+/// expected size has been delivered, and `download_failed` on a read error
+/// or when the response is dropped early (typically a client disconnect).
+/// Time to first byte and total duration are measured from `started`, the
+/// moment the request was received.
+pub struct TransferStream<S> {
+    inner: S,
+    observers: TransferObservers,
+    info: TransferInfo,
+    source: Source,
+    /// Bytes read from the source
+    source_bytes: Arc<AtomicU64>,
+    /// Retries of the request, if counted
+    retries: Option<Arc<AtomicU64>>,
+    /// Route-wide count of bytes sent to clients
+    streamed_bytes: Arc<AtomicU64>,
+    bytes: u64,
+    first_byte: Option<Duration>,
+    done: bool,
+    /// Listing in [`InFlight`], until done
+    registration: Option<Registration>,
+}
+
+impl<S> TransferStream<S> {
+    /// `source_bytes` is the counter of the download feeding `inner` (see
+    /// [`CountingReader`]); its retries are those counted in the current
+    /// scope (see [`crate::retry::counted`])
+    pub fn new(
+        inner: S,
+        observers: TransferObservers,
+        info: TransferInfo,
+        source: Source,
+        source_bytes: Arc<AtomicU64>,
+    ) -> Self {
+        observers.metrics.download_started();
+        let streamed_bytes = observers.metrics.streamed_bytes(info.route);
+        let registration = observers.in_flight.register(Transfer {
+            route: info.route,
+            hash: info.hash.clone(),
+            repo: info.repo.to_string(),
+            path: info.path.clone(),
+            client: info.identity.clone(),
+            started: info.started,
+            total: info.expected_size,
+        });
+        Self {
+            inner,
+            observers,
+            info,
+            source,
+            source_bytes,
+            retries: crate::retry::counter(),
+            streamed_bytes,
+            bytes: 0,
+            first_byte: None,
+            done: false,
+            registration: Some(registration),
+        }
+    }
+
+    fn finish(&mut self) {
+        self.done = true;
+        self.registration = None;
+        let total = self.info.started.elapsed();
+        self.log_record("completed", total);
+        self.observers
+            .slo
+            .record(self.info.route, self.first_byte, Some(total));
+        self.observers
+            .metrics
+            .observe_duration(self.info.route, total);
+        self.observers.metrics.download_ended();
+        self.observers.events.publish(EventKind::DownloadFinished {
+            hash: self.info.hash.clone(),
+            bytes: self.bytes,
+            duration_ms: total.as_millis() as u64,
+        });
+    }
+
+    fn fail(&mut self, error: String, count_against_slo: bool) {
+        self.done = true;
+        self.registration = None;
+        let outcome = if count_against_slo {
+            "failed"
+        } else {
+            "aborted"
+        };
+        self.log_record(outcome, self.info.started.elapsed());
+        self.observers.metrics.download_ended();
+        self.observers.aborts.record(
+            self.info.client,
+            &self.info.hash,
+            self.info.offset + self.bytes,
+        );
+        if count_against_slo {
+            self.observers
+                .slo
+                .record(self.info.route, self.first_byte, None);
+        }
+        self.observers.events.publish(EventKind::DownloadFailed {
+            hash: Some(self.info.hash.clone()),
+            error,
+        });
+    }
+
+    /// Emit the per-download record; flag drift on completed transfers
+    fn log_record(&self, outcome: &'static str, duration: Duration) {
+        let read = self.source_bytes.load(Ordering::Relaxed);
+        let expected = self.info.expected_size;
+        let mut breakdown = Breakdown {
+            retries: self
+                .retries
+                .as_ref()
+                .map_or(0, |r| r.load(Ordering::Relaxed)),
+            ..Breakdown::default()
+        };
+        breakdown.add(self.source, read);
+        let throughput = if duration.is_zero() {
+            0
+        } else {
+            (self.bytes as f64 / duration.as_secs_f64()) as u64
+        };
+        info!(
+            target: "transfer",
+            route = self.info.route,
+            hash = %self.info.hash,
+            repo = %self.info.repo,
+            revision = %self.info.repo.revision,
+            path = self.info.path.as_deref(),
+            ranged = self.info.ranged,
+            outcome,
+            expected_bytes = expected,
+            cache_bytes = breakdown.cache_bytes,
+            peer_bytes = breakdown.peer_bytes,
+            upstream_bytes = breakdown.upstream_bytes,
+            client_bytes = self.bytes,
+            duration_ms = duration.as_millis() as u64,
+            throughput_bps = throughput,
+            retries = breakdown.retries,
+            "Transfer {}",
+            outcome
+        );
+
+        let drifted = read != self.bytes || expected.is_some_and(|e| e != read);
+        if outcome == "completed" && drifted {
+            self.observers.drift_total.fetch_add(1, Ordering::Relaxed);
+            warn!(
+                target: "transfer",
+                hash = %self.info.hash,
+                expected_bytes = expected,
+                source_bytes = read,
+                client_bytes = self.bytes,
+                "Byte accounting drift on completed transfer"
+            );
+        }
+    }
+}
+
+impl<S, B> Stream for TransferStream<S>
+where
+    S: Stream<Item = std::io::Result<B>> + Unpin,
+    B: AsRef<[u8]>,
+{
+    type Item = std::io::Result<B>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = &mut *self;
+        let poll = Pin::new(&mut this.inner).poll_next(cx);
+        match &poll {
+            Poll::Ready(Some(Ok(chunk))) => {
+                if this.first_byte.is_none() {
+                    let ttfb = this.info.started.elapsed();
+                    this.first_byte = Some(ttfb);
+                    this.observers.metrics.observe_ttfb(this.info.route, ttfb);
+                }
+                let len = chunk.as_ref().len() as u64;
+                this.bytes += len;
+                this.streamed_bytes.fetch_add(len, Ordering::Relaxed);
+                if let Some(registration) = &this.registration {
+                    registration.sent(len);
+                }
+                // With a Content-Length the body is dropped as soon as the
+                // last byte is written, without being polled to its end
+                if !this.done && this.info.expected_size == Some(this.bytes) {
+                    this.finish();
+                }
+            }
+            Poll::Ready(Some(Err(e))) if !this.done => this.fail(e.to_string(), true),
+            Poll::Ready(None) if !this.done => this.finish(),
+            _ => {}
+        }
+        poll
+    }
+}
+
+impl<S> Drop for TransferStream<S> {
+    fn drop(&mut self) {
+        if !self.done {
+            // Client-side aborts don't count against the SLO
+            let error = format!("client disconnected after {} bytes", self.bytes);
+            self.fail(error, false);
+        }
+    }
+}
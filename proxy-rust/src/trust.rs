@@ -0,0 +1,217 @@
+//! Trust of TLS connections to the Hub and CAS
+//!
+//! Behind a TLS-intercepting corporate proxy, upstream certificates are
+//! issued by a CA the built-in roots don't know. `UPSTREAM_CA_FILE` adds
+//! the PEM certificates of a file to the roots, or replaces them with
+//! `UPSTREAM_CA_ONLY=true`. `UPSTREAM_PINNED_CERTS` lists SHA-256
+//! fingerprints of server certificates (of their DER encoding, hex, colons
+//! allowed) trusted as presented, whoever issued them; the server must
+//! still prove it holds the certificate's key.
+//!
+//! Every client talking to the Hub, CAS or xorb storage is built with
+//! [`apply`]: the native engine, readiness, prefetch and sessions. The CLI
+//! backend is started with `SSL_CERT_FILE` set to `UPSTREAM_CA_FILE`;
+//! pinned certificates only apply in-process.
+
+use ring::digest;
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::client::WebPkiServerVerifier;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use rustls::{ClientConfig, DigitallySignedStruct, RootCertStore, SignatureScheme};
+use std::sync::{Arc, OnceLock};
+use tokio::process::Command;
+
+/// Upstream trust settings, as loaded from the environment
+pub struct Trust {
+    ca_file: Option<String>,
+    /// Extra roots in `ca_file`
+    cas: usize,
+    only: bool,
+    pins: usize,
+    /// TLS configuration of upstream clients, unless the defaults apply
+    config: Option<ClientConfig>,
+}
+
+impl Trust {
+    /// Load `UPSTREAM_CA_FILE`, `UPSTREAM_CA_ONLY` and
+    /// `UPSTREAM_PINNED_CERTS`
+    pub fn from_env() -> Self {
+        let ca_file = std::env::var("UPSTREAM_CA_FILE").ok();
+        let only = crate::config::flag("UPSTREAM_CA_ONLY").unwrap_or(false);
+        let pins: Vec<Vec<u8>> = std::env::var("UPSTREAM_PINNED_CERTS")
+            .map(|v| {
+                v.split(',')
+                    .map(str::trim)
+                    .filter(|pin| !pin.is_empty())
+                    .map(|pin| {
+                        hex::decode(pin.replace(':', ""))
+                            .ok()
+                            .filter(|bytes| bytes.len() == 32)
+                            .unwrap_or_else(|| {
+                                panic!(
+                                    "UPSTREAM_PINNED_CERTS must list SHA-256 fingerprints (64 hex characters), got '{}'",
+                                    pin
+                                )
+                            })
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+        assert!(
+            !only || ca_file.is_some(),
+            "UPSTREAM_CA_ONLY needs UPSTREAM_CA_FILE"
+        );
+        if ca_file.is_none() && pins.is_empty() {
+            return Self {
+                ca_file,
+                cas: 0,
+                only,
+                pins: 0,
+                config: None,
+            };
+        }
+
+        let mut roots = RootCertStore::empty();
+        if !only {
+            roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+        }
+        let mut cas = 0;
+        if let Some(ca_file) = &ca_file {
+            let certs = CertificateDer::pem_file_iter(ca_file)
+                .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+                .unwrap_or_else(|e| panic!("Failed to read UPSTREAM_CA_FILE {}: {}", ca_file, e));
+            for cert in certs {
+                roots.add(cert).unwrap_or_else(|e| {
+                    panic!("Invalid CA in UPSTREAM_CA_FILE {}: {}", ca_file, e)
+                });
+                cas += 1;
+            }
+            assert!(cas > 0, "UPSTREAM_CA_FILE {} holds no certificate", ca_file);
+        }
+
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let builder = ClientConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()
+            .expect("TLS protocol versions");
+        let config = if pins.is_empty() {
+            builder.with_root_certificates(roots)
+        } else {
+            let verifier = WebPkiServerVerifier::builder_with_provider(Arc::new(roots), provider)
+                .build()
+                .unwrap_or_else(|e| panic!("Invalid upstream trust roots: {}", e));
+            builder
+                .dangerous()
+                .with_custom_certificate_verifier(Arc::new(Pinned {
+                    pins: pins.clone(),
+                    verifier,
+                }))
+        }
+        .with_no_client_auth();
+
+        Self {
+            ca_file,
+            cas,
+            only,
+            pins: pins.len(),
+            config: Some(config),
+        }
+    }
+
+    /// What is trusted beyond the defaults, for the startup banner
+    pub fn describe(&self) -> Option<String> {
+        self.config.as_ref()?;
+        let mut parts = Vec::new();
+        if let Some(ca_file) = &self.ca_file {
+            let scope = if self.only { "only" } else { "also" };
+            parts.push(format!(
+                "{} the {} CA certificates in {}",
+                scope, self.cas, ca_file
+            ));
+        }
+        if self.pins > 0 {
+            parts.push(format!("{} pinned certificates", self.pins));
+        }
+        Some(parts.join(", "))
+    }
+}
+
+fn current() -> &'static Trust {
+    static TRUST: OnceLock<Trust> = OnceLock::new();
+    TRUST.get_or_init(Trust::from_env)
+}
+
+/// Startup summary of the upstream trust settings, if any apply
+pub fn describe() -> Option<String> {
+    current().describe()
+}
+
+/// `builder` with the upstream trust settings applied
+pub fn apply(builder: reqwest::ClientBuilder) -> reqwest::ClientBuilder {
+    match &current().config {
+        Some(config) => builder.use_preconfigured_tls(config.clone()),
+        None => builder,
+    }
+}
+
+/// A default client with the upstream trust settings applied
+pub fn client() -> reqwest::Client {
+    apply(reqwest::Client::builder())
+        .build()
+        .expect("Failed to build HTTP client")
+}
+
+/// Pass the extra roots on to a CLI process
+pub fn apply_to_cli(command: &mut Command) {
+    if let Some(ca_file) = &current().ca_file {
+        command.env("SSL_CERT_FILE", ca_file);
+    }
+}
+
+/// Verifier accepting pinned certificates as presented, and others with a
+/// chain to the roots
+#[derive(Debug)]
+struct Pinned {
+    pins: Vec<Vec<u8>>,
+    verifier: Arc<WebPkiServerVerifier>,
+}
+
+impl ServerCertVerifier for Pinned {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let fingerprint = digest::digest(&digest::SHA256, end_entity.as_ref());
+        if self.pins.iter().any(|pin| pin[..] == *fingerprint.as_ref()) {
+            return Ok(ServerCertVerified::assertion());
+        }
+        self.verifier
+            .verify_server_cert(end_entity, intermediates, server_name, ocsp_response, now)
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.verifier.verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.verifier.verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.verifier.supported_verify_schemes()
+    }
+}
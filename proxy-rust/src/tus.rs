@@ -0,0 +1,635 @@
+//! Resumable uploads, over the tus protocol
+//!
+//! `POST /upload/:owner/:repo/*file` with an `Upload-Length` header opens an
+//! upload and answers `201` with its URL, `/uploads/:id`, in `Location`.
+//! Each `PATCH` to it appends its body at `Upload-Offset`, which must be
+//! where the upload stands, and `HEAD` tells where that is, so a client that
+//! lost its connection carries on from there. The `PATCH` completing the
+//! upload commits the file like `PUT /upload` (see [`crate::upload`]) and
+//! answers with the commit; a bodiless `PATCH` at the end retries a failed
+//! commit.
+//!
+//! Received bytes are spooled to `UPLOAD_DIR`, next to a JSON record of the
+//! upload: its target, offset and the SHA-256 of each part (a `PATCH`
+//! body), written once the part is on disk. Uploads recorded there are
+//! picked up again at startup, so clients resume after a restart too:
+//! bytes received after the last record are dropped, and the parts are
+//! checked against their hashes before the commit. An upload belongs to the
+//! token and the authenticated client that opened it, and is forgotten
+//! `UPLOAD_EXPIRY_SECS` (default 86400) after its last change.
+
+use crate::downloader::Downloader;
+use crate::repo::RepoRef;
+use crate::upload::{UploadRequest, UploadResult};
+use crate::AppError;
+use axum::body::Bytes;
+use ring::digest;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::hash::{BuildHasher, RandomState};
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::io::AsyncWriteExt;
+use tokio::time::Instant;
+use tokio_stream::{Stream, StreamExt};
+use tracing::{info, warn};
+
+/// The protocol version spoken, sent as `Tus-Resumable`
+pub const VERSION: &str = "1.0.0";
+/// `Content-Type` of `PATCH` bodies
+pub const PATCH_CONTENT_TYPE: &str = "application/offset+octet-stream";
+const RECORD_SUFFIX: &str = ".json";
+const DATA_SUFFIX: &str = ".data";
+/// Read size when checking parts against their hashes
+const CHECK_BUFFER: usize = 1 << 20;
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// What the upload dir records of an upload
+#[derive(Clone, Serialize, Deserialize)]
+struct Record {
+    /// Repository, as `RepoRef` displays it
+    repo: String,
+    revision: String,
+    path: String,
+    message: String,
+    length: u64,
+    offset: u64,
+    /// SHA-256 (hex) of the token that opened the upload
+    token: String,
+    /// Authenticated client that opened the upload, if the proxy
+    /// authenticates clients
+    #[serde(default)]
+    client: Option<String>,
+    /// Unix time of the last change
+    updated_at: u64,
+    parts: Vec<Part>,
+    /// The commit, kept for a client asking again after losing the answer
+    committed: Option<UploadResult>,
+}
+
+/// Bytes received by one `PATCH`
+#[derive(Clone, Serialize, Deserialize)]
+struct Part {
+    offset: u64,
+    length: u64,
+    /// SHA-256 (hex) of the bytes
+    sha256: String,
+}
+
+impl Record {
+    fn repo(&self) -> Option<RepoRef> {
+        RepoRef::parse(&self.repo, Some(self.revision.clone()))
+    }
+}
+
+/// Where an upload stands, for `HEAD`
+pub struct Status {
+    pub repo: RepoRef,
+    pub path: String,
+    pub offset: u64,
+    pub length: u64,
+}
+
+/// Outcome of a `PATCH`
+pub enum Written {
+    /// More is expected, from this offset
+    Partial(u64),
+    Committed(UploadResult),
+}
+
+struct Upload {
+    record: Mutex<Record>,
+    /// Held by the `PATCH` writing to the upload
+    writing: tokio::sync::Mutex<()>,
+}
+
+/// The open uploads, spooled to `UPLOAD_DIR`
+#[derive(Clone)]
+pub struct Uploads {
+    dir: PathBuf,
+    expiry: Duration,
+    uploads: Arc<Mutex<HashMap<String, Arc<Upload>>>>,
+}
+
+impl Uploads {
+    /// Load `UPLOAD_DIR` and `UPLOAD_EXPIRY_SECS`, with the uploads
+    /// recorded in the directory; `None` without `UPLOAD_DIR`
+    pub fn from_env() -> Option<Self> {
+        let dir = PathBuf::from(std::env::var("UPLOAD_DIR").ok()?);
+        let secs = crate::config::positive("UPLOAD_EXPIRY_SECS").unwrap_or(86400);
+        std::fs::create_dir_all(&dir)
+            .unwrap_or_else(|e| panic!("Failed to create UPLOAD_DIR {}: {}", dir.display(), e));
+        let uploads = Self {
+            dir,
+            expiry: Duration::from_secs(secs),
+            uploads: Arc::default(),
+        };
+        let loaded = uploads.load().unwrap_or_else(|e| {
+            panic!("Failed to read UPLOAD_DIR {}: {}", uploads.dir.display(), e)
+        });
+        info!(
+            "Resumable uploads in {}: {} picked up",
+            uploads.dir.display(),
+            loaded
+        );
+        Some(uploads)
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Open an upload of `length` bytes to `path` in `repo`; returns its id
+    pub async fn create(
+        &self,
+        repo: &RepoRef,
+        path: &str,
+        message: String,
+        length: u64,
+        hf_token: &str,
+        client: Option<&str>,
+    ) -> Result<String, AppError> {
+        let id = upload_id();
+        let record = Record {
+            repo: repo.to_string(),
+            revision: repo.revision.clone(),
+            path: path.to_string(),
+            message,
+            length,
+            offset: 0,
+            token: sha256_hex(hf_token.as_bytes()),
+            client: client.map(String::from),
+            updated_at: unix_now(),
+            parts: Vec::new(),
+            committed: None,
+        };
+        tokio::fs::File::create(self.data_path(&id))
+            .await
+            .map_err(|e| storage_error(&e))?;
+        self.save(&id, &record).await?;
+        let upload = Upload {
+            record: Mutex::new(record),
+            writing: tokio::sync::Mutex::new(()),
+        };
+        self.uploads
+            .lock()
+            .unwrap()
+            .insert(id.clone(), Arc::new(upload));
+        info!(
+            "Upload {} opened: {} bytes to {} in {}",
+            id, length, path, repo
+        );
+        Ok(id)
+    }
+
+    pub fn status(
+        &self,
+        id: &str,
+        hf_token: &str,
+        client: Option<&str>,
+    ) -> Result<Status, AppError> {
+        let upload = self.get(id, hf_token, client)?;
+        let record = upload.record.lock().unwrap();
+        Ok(Status {
+            repo: record_repo(&record)?,
+            path: record.path.clone(),
+            offset: record.offset,
+            length: record.length,
+        })
+    }
+
+    /// Append `body` at `offset`, and commit the file with `downloader` once
+    /// it is complete
+    #[allow(clippy::too_many_arguments)]
+    pub async fn write(
+        &self,
+        id: &str,
+        hf_token: &str,
+        client: Option<&str>,
+        offset: u64,
+        mut body: impl Stream<Item = Result<Bytes, io::Error>> + Unpin,
+        downloader: &dyn Downloader,
+        deadline: Option<Instant>,
+    ) -> Result<Written, AppError> {
+        let upload = self.get(id, hf_token, client)?;
+        let Ok(_writing) = upload.writing.try_lock() else {
+            return Err(AppError::Conflict(format!(
+                "Upload {} is being written by another request",
+                id
+            )));
+        };
+        let mut record = upload.record.lock().unwrap().clone();
+        if let Some(result) = &record.committed {
+            if offset == record.length {
+                return Ok(Written::Committed(result.clone()));
+            }
+        }
+        if offset != record.offset {
+            return Err(AppError::Conflict(format!(
+                "Upload {} is at offset {}, not {}",
+                id, record.offset, offset
+            )));
+        }
+
+        let data = self.data_path(id);
+        let file = tokio::fs::OpenOptions::new()
+            .write(true)
+            .open(&data)
+            .await
+            .map_err(|e| storage_error(&e))?;
+        // Drop whatever a request cut short left past the recorded offset
+        file.set_len(offset).await.map_err(|e| storage_error(&e))?;
+        let mut file = tokio::io::BufWriter::new(file);
+        tokio::io::AsyncSeekExt::seek(&mut file, io::SeekFrom::Start(offset))
+            .await
+            .map_err(|e| storage_error(&e))?;
+        let mut digest = digest::Context::new(&digest::SHA256);
+        let mut received = 0u64;
+        let mut failure = None;
+        while let Some(chunk) = body.next().await {
+            let chunk = match chunk {
+                Ok(chunk) => chunk,
+                // Keep what arrived, for the client to resume after it
+                Err(e) => {
+                    failure = Some(AppError::BadRequest(format!("Upload body failed: {}", e)));
+                    break;
+                }
+            };
+            if offset + received + chunk.len() as u64 > record.length {
+                let message = format!("The body runs past Upload-Length ({} bytes)", record.length);
+                file.into_inner()
+                    .set_len(offset)
+                    .await
+                    .map_err(|e| storage_error(&e))?;
+                return Err(AppError::BadRequest(message));
+            }
+            file.write_all(&chunk)
+                .await
+                .map_err(|e| storage_error(&e))?;
+            digest.update(&chunk);
+            received += chunk.len() as u64;
+        }
+
+        if received > 0 {
+            file.flush().await.map_err(|e| storage_error(&e))?;
+            file.get_ref()
+                .sync_all()
+                .await
+                .map_err(|e| storage_error(&e))?;
+            record.parts.push(Part {
+                offset,
+                length: received,
+                sha256: hex::encode(digest.finish()),
+            });
+            record.offset += received;
+            self.update(id, &upload, &mut record).await?;
+        }
+        drop(file);
+        if let Some(failure) = failure {
+            return Err(failure);
+        }
+        if record.offset < record.length {
+            return Ok(Written::Partial(record.offset));
+        }
+        self.commit(id, &upload, record, hf_token, downloader, deadline)
+            .await
+            .map(Written::Committed)
+    }
+
+    /// Check a complete upload's parts and commit it
+    async fn commit(
+        &self,
+        id: &str,
+        upload: &Upload,
+        mut record: Record,
+        hf_token: &str,
+        downloader: &dyn Downloader,
+        deadline: Option<Instant>,
+    ) -> Result<UploadResult, AppError> {
+        let data = self.data_path(id);
+        let parts = record.parts.clone();
+        let damaged = tokio::task::spawn_blocking(move || first_damaged(&data, &parts))
+            .await
+            .map_err(|e| AppError::Internal(format!("Part check failed: {}", e)))?
+            .map_err(|e| storage_error(&e))?;
+        if let Some(index) = damaged {
+            let part = record.parts[index].clone();
+            warn!(
+                "Upload {}: the part at offset {} is damaged, rolling back to it",
+                id, part.offset
+            );
+            record.parts.truncate(index);
+            record.offset = part.offset;
+            self.update(id, upload, &mut record).await?;
+            return Err(AppError::Conflict(format!(
+                "Upload {} lost the bytes from offset {}; resume from there",
+                id, part.offset
+            )));
+        }
+
+        let repo = record_repo(&record)?;
+        let file = tokio::fs::File::open(self.data_path(id))
+            .await
+            .map_err(|e| storage_error(&e))?;
+        let result = downloader
+            .upload(UploadRequest {
+                repo: &repo,
+                path: &record.path,
+                hf_token,
+                message: &record.message,
+                body: Box::pin(tokio_util::io::ReaderStream::new(file)),
+                deadline,
+            })
+            .await?;
+        record.committed = Some(result.clone());
+        self.update(id, upload, &mut record).await?;
+        let _ = tokio::fs::remove_file(self.data_path(id)).await;
+        Ok(result)
+    }
+
+    /// Abandon an upload, with its bytes
+    pub async fn delete(
+        &self,
+        id: &str,
+        hf_token: &str,
+        client: Option<&str>,
+    ) -> Result<(), AppError> {
+        self.get(id, hf_token, client)?;
+        self.uploads.lock().unwrap().remove(id);
+        self.remove_files(id).await;
+        info!("Upload {} deleted", id);
+        Ok(())
+    }
+
+    /// Forget expired uploads in the background
+    pub fn start(&self) {
+        let uploads = self.clone();
+        tokio::spawn(async move {
+            let mut ticks = tokio::time::interval(SWEEP_INTERVAL.min(uploads.expiry));
+            loop {
+                ticks.tick().await;
+                uploads.sweep().await;
+            }
+        });
+    }
+
+    async fn sweep(&self) {
+        let cutoff = unix_now().saturating_sub(self.expiry.as_secs());
+        let expired: Vec<String> = {
+            let mut uploads = self.uploads.lock().unwrap();
+            let expired = uploads
+                .iter()
+                .filter(|(_, upload)| upload.record.lock().unwrap().updated_at < cutoff)
+                .map(|(id, _)| id.clone())
+                .collect::<Vec<_>>();
+            for id in &expired {
+                uploads.remove(id);
+            }
+            expired
+        };
+        for id in expired {
+            self.remove_files(&id).await;
+            info!("Upload {} expired", id);
+        }
+    }
+
+    /// The upload `id`, if `hf_token` and `client` opened it
+    fn get(&self, id: &str, hf_token: &str, client: Option<&str>) -> Result<Arc<Upload>, AppError> {
+        let upload = self.uploads.lock().unwrap().get(id).cloned();
+        upload
+            .filter(|upload| {
+                let record = upload.record.lock().unwrap();
+                record.token == sha256_hex(hf_token.as_bytes())
+                    && record.client.as_deref() == client
+            })
+            .ok_or_else(|| AppError::NotFound(format!("Unknown or expired upload '{}'", id)))
+    }
+
+    /// Record a change in memory and in the upload dir
+    async fn update(&self, id: &str, upload: &Upload, record: &mut Record) -> Result<(), AppError> {
+        record.updated_at = unix_now();
+        self.save(id, record).await?;
+        *upload.record.lock().unwrap() = record.clone();
+        Ok(())
+    }
+
+    async fn save(&self, id: &str, record: &Record) -> Result<(), AppError> {
+        let json = serde_json::to_vec(record).map_err(|e| AppError::Internal(e.to_string()))?;
+        // Written aside and renamed, so a crash never leaves half a record
+        let partial = self.dir.join(format!("{}{}.partial", id, RECORD_SUFFIX));
+        async {
+            tokio::fs::write(&partial, json).await?;
+            tokio::fs::rename(&partial, self.record_path(id)).await
+        }
+        .await
+        .map_err(|e| storage_error(&e))
+    }
+
+    async fn remove_files(&self, id: &str) {
+        let _ = tokio::fs::remove_file(self.record_path(id)).await;
+        let _ = tokio::fs::remove_file(self.data_path(id)).await;
+    }
+
+    /// Pick up the recorded uploads, trimming their data to the records;
+    /// returns how many
+    fn load(&self) -> io::Result<usize> {
+        let mut uploads = HashMap::new();
+        for dir_entry in std::fs::read_dir(&self.dir)?.flatten() {
+            let name = dir_entry.file_name().to_string_lossy().into_owned();
+            let Some(id) = name
+                .strip_suffix(RECORD_SUFFIX)
+                .filter(|id| is_upload_id(id))
+            else {
+                continue;
+            };
+            let record = std::fs::read(dir_entry.path())
+                .map_err(|e| e.to_string())
+                .and_then(|bytes| {
+                    serde_json::from_slice::<Record>(&bytes).map_err(|e| e.to_string())
+                })
+                .and_then(|record| match record.repo() {
+                    Some(_) => Ok(record),
+                    None => Err(format!("invalid repository '{}'", record.repo)),
+                });
+            let mut record = match record {
+                Ok(record) => record,
+                Err(e) => {
+                    warn!("Dropping unreadable upload record {}: {}", name, e);
+                    let _ = std::fs::remove_file(dir_entry.path());
+                    let _ = std::fs::remove_file(self.data_path(id));
+                    continue;
+                }
+            };
+            if record.committed.is_none() && !self.trim(id, &mut record)? {
+                std::fs::write(self.record_path(id), serde_json::to_vec(&record)?)?;
+            }
+            let upload = Upload {
+                record: Mutex::new(record),
+                writing: tokio::sync::Mutex::new(()),
+            };
+            uploads.insert(id.to_string(), Arc::new(upload));
+        }
+        // Leftovers of uploads whose record never made it, or was dropped
+        for dir_entry in std::fs::read_dir(&self.dir)?.flatten() {
+            let name = dir_entry.file_name().to_string_lossy().into_owned();
+            let known = name
+                .strip_suffix(DATA_SUFFIX)
+                .is_some_and(|id| uploads.contains_key(id));
+            if !known && (name.ends_with(DATA_SUFFIX) || name.ends_with(".partial")) {
+                let _ = std::fs::remove_file(dir_entry.path());
+            }
+        }
+        let loaded = uploads.len();
+        *self.uploads.lock().unwrap() = uploads;
+        Ok(loaded)
+    }
+
+    /// Fit the data of an upload to its record, and the record to the data
+    /// if bytes went missing; returns whether the record stands as it was
+    fn trim(&self, id: &str, record: &mut Record) -> io::Result<bool> {
+        let data = self.data_path(id);
+        let file = match std::fs::OpenOptions::new().write(true).open(&data) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => std::fs::File::create(&data)?,
+            Err(e) => return Err(e),
+        };
+        let size = file.metadata()?.len();
+        if size >= record.offset {
+            file.set_len(record.offset)?;
+            return Ok(true);
+        }
+        let kept = record
+            .parts
+            .iter()
+            .take_while(|part| part.offset + part.length <= size)
+            .count();
+        record.parts.truncate(kept);
+        record.offset = record
+            .parts
+            .last()
+            .map_or(0, |part| part.offset + part.length);
+        file.set_len(record.offset)?;
+        warn!(
+            "Upload {} lost bytes on disk, resuming from offset {}",
+            id, record.offset
+        );
+        Ok(false)
+    }
+
+    fn record_path(&self, id: &str) -> PathBuf {
+        self.dir.join(format!("{}{}", id, RECORD_SUFFIX))
+    }
+
+    fn data_path(&self, id: &str) -> PathBuf {
+        self.dir.join(format!("{}{}", id, DATA_SUFFIX))
+    }
+}
+
+fn record_repo(record: &Record) -> Result<RepoRef, AppError> {
+    // Validated when the record was made or loaded
+    record.repo().ok_or_else(|| {
+        AppError::Internal(format!(
+            "Upload record names an invalid repository '{}'",
+            record.repo
+        ))
+    })
+}
+
+/// Index of the first part whose bytes don't match its hash
+fn first_damaged(data: &Path, parts: &[Part]) -> io::Result<Option<usize>> {
+    let mut file = std::fs::File::open(data)?;
+    let mut buffer = vec![0; CHECK_BUFFER];
+    for (index, part) in parts.iter().enumerate() {
+        let mut digest = digest::Context::new(&digest::SHA256);
+        let mut left = part.length;
+        while left > 0 {
+            let n = file.read(&mut buffer[..left.min(CHECK_BUFFER as u64) as usize])?;
+            if n == 0 {
+                return Ok(Some(index));
+            }
+            digest.update(&buffer[..n]);
+            left -= n as u64;
+        }
+        if hex::encode(digest.finish()) != part.sha256 {
+            return Ok(Some(index));
+        }
+    }
+    Ok(None)
+}
+
+fn storage_error(e: &io::Error) -> AppError {
+    AppError::Internal(format!("Upload storage failed: {}", e))
+}
+
+fn sha256_hex(data: &[u8]) -> String {
+    hex::encode(digest::digest(&digest::SHA256, data))
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+/// Unguessable upload id, also its file name in the upload dir
+fn upload_id() -> String {
+    static SEQUENCE: AtomicU64 = AtomicU64::new(0);
+    let state = RandomState::new();
+    format!(
+        "{:016x}{:016x}",
+        state.hash_one(SEQUENCE.fetch_add(1, Ordering::Relaxed)),
+        state.hash_one(std::process::id())
+    )
+}
+
+fn is_upload_id(s: &str) -> bool {
+    s.len() == 32 && s.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn uploads(dir: &tempfile::TempDir) -> Uploads {
+        Uploads {
+            dir: dir.path().to_path_buf(),
+            expiry: Duration::from_secs(60),
+            uploads: Arc::default(),
+        }
+    }
+
+    #[tokio::test]
+    async fn upload_belongs_to_the_client_that_opened_it() {
+        let dir = tempfile::tempdir().unwrap();
+        let uploads = uploads(&dir);
+        let repo = RepoRef::parse("datasets/org/x", None).unwrap();
+        let id = uploads
+            .create(&repo, "f.bin", "Upload".into(), 4, "hf_a", Some("team-a"))
+            .await
+            .unwrap();
+
+        let status = uploads.status(&id, "hf_a", Some("team-a")).unwrap();
+        assert_eq!(status.repo.to_string(), "datasets/org/x");
+        // Same token, other client or none: as if there were no such upload
+        for client in [Some("team-b"), None] {
+            assert!(matches!(
+                uploads.status(&id, "hf_a", client),
+                Err(AppError::NotFound(_))
+            ));
+            assert!(matches!(
+                uploads.delete(&id, "hf_a", client).await,
+                Err(AppError::NotFound(_))
+            ));
+        }
+        assert!(uploads.status(&id, "hf_b", Some("team-a")).is_err());
+
+        // The client is recorded, so it still holds after a restart
+        let restarted = self::uploads(&dir);
+        assert_eq!(restarted.load().unwrap(), 1);
+        assert!(restarted.status(&id, "hf_a", Some("team-b")).is_err());
+        assert!(restarted.status(&id, "hf_a", Some("team-a")).is_ok());
+    }
+}
@@ -0,0 +1,158 @@
+//! Uploads through the Zig CLI
+//!
+//! `PUT /upload/:owner/:repo/*file` streams the request body into
+//! `xet-download upload <repo_id> <path>`, which chunks it into xorbs,
+//! uploads them and the file's shard to CAS, and commits the file to the
+//! repository. The CLI reports the commit and the file's hashes as a single
+//! `uploaded` message on stdout. Nothing is buffered here beyond the pipe, so upload
+//! size is not limited by the proxy's memory.
+
+use crate::backoff::RATE_LIMITED_SIGNATURE;
+use crate::downloader::ByteStream;
+use crate::protocol::{self, Message};
+use crate::repo::RepoRef;
+use crate::subprocess::{self, Cli};
+use crate::AppError;
+use serde::{Deserialize, Serialize};
+use std::process::Stdio;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::time::Instant;
+use tokio_stream::StreamExt;
+use tracing::{error, Span};
+
+/// What to upload
+pub struct UploadRequest<'a> {
+    /// Repository and branch to commit to
+    pub repo: &'a RepoRef,
+    /// Path of the file in the repository
+    pub path: &'a str,
+    pub hf_token: &'a str,
+    /// Commit title
+    pub message: &'a str,
+    pub body: ByteStream,
+    /// Abort the upload at this point in time
+    pub deadline: Option<Instant>,
+}
+
+/// A committed upload, as reported by the CLI
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct UploadResult {
+    pub commit_oid: String,
+    pub xet_hash: String,
+    pub sha256: String,
+    pub size: u64,
+}
+
+/// Run the CLI upload, feeding it the request body
+pub async fn upload(cli: &Cli, request: UploadRequest<'_>) -> Result<UploadResult, AppError> {
+    let repo = request.repo.to_string();
+    let mut child = cli
+        .command()
+        .arg("upload")
+        .arg(request.repo.id())
+        .arg(request.path)
+        .env("HF_TOKEN", request.hf_token)
+        .env("HF_REPO_TYPE", request.repo.repo_type.as_str())
+        .env("HF_REVISION", request.repo.revision_segment())
+        .env("HF_COMMIT_MESSAGE", request.message)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        // Timeouts and client disconnects drop this future; stop the upload
+        .kill_on_drop(true)
+        .spawn()
+        .map_err(|e| AppError::Internal(format!("Failed to spawn zig process: {}", e)))?;
+
+    let (Some(mut stdin), Some(mut stdout), Some(stderr)) =
+        (child.stdin.take(), child.stdout.take(), child.stderr.take())
+    else {
+        return Err(AppError::Internal(
+            "Failed to capture zig process pipes".to_string(),
+        ));
+    };
+    let label = format!("upload {}/{}", repo, request.path);
+    let stderr_task = tokio::spawn(crate::upstream::inherit(async move {
+        subprocess::collect_stderr_tail(stderr, &label, Span::current).await
+    }));
+
+    let mut body = request.body;
+    let feed = async move {
+        while let Some(chunk) = body.next().await {
+            let chunk =
+                chunk.map_err(|e| AppError::BadRequest(format!("Upload body failed: {}", e)))?;
+            if stdin.write_all(&chunk).await.is_err() {
+                // The child stopped reading; its exit status tells why
+                break;
+            }
+        }
+        // Closing stdin marks the end of the file
+        drop(stdin);
+        Ok(())
+    };
+    let collect = async {
+        let mut output = String::new();
+        let read = stdout.read_to_string(&mut output).await;
+        (read, output)
+    };
+
+    let run = async {
+        let (fed, (read, output)) = tokio::join!(feed, collect);
+        fed?;
+        let status = child
+            .wait()
+            .await
+            .map_err(|e| AppError::Internal(format!("Failed to wait for zig process: {}", e)))?;
+        Ok::<_, AppError>((status, read, output))
+    };
+    let (status, read, output) = match request.deadline {
+        Some(deadline) => tokio::time::timeout_at(deadline, run)
+            .await
+            .unwrap_or_else(|_| {
+                Err(AppError::Timeout(
+                    "Upload exceeded its time budget".to_string(),
+                ))
+            })?,
+        None => run.await?,
+    };
+
+    let tail = stderr_task.await.unwrap_or_default();
+    if !status.success() {
+        let signature = cli.record_failure(&status, &tail);
+        return Err(signature_error(&signature, &repo));
+    }
+    if let Err(e) = read {
+        error!("Failed to read zig upload output: {}", e);
+    }
+    match protocol::parse_output(&output)?.pop() {
+        Some(Message::Uploaded(result)) => Ok(result),
+        Some(other) => Err(other.unexpected("upload")),
+        None => Err(AppError::Internal(
+            "CLI upload reported no result".to_string(),
+        )),
+    }
+}
+
+/// Map a CLI upload failure signature onto the error reported to the client
+fn signature_error(signature: &str, repo: &str) -> AppError {
+    match signature {
+        "error: EmptyUpload" => AppError::BadRequest("Upload body is empty".to_string()),
+        // The write token or the commit was refused
+        "error: RepoAccessDenied" => AppError::Forbidden(format!("No write access to '{}'", repo)),
+        "error: RepoNotFound" => AppError::NotFound(format!(
+            "Repository '{}' not found, or not visible with this token",
+            repo
+        )),
+        "error: CommitConflict" => AppError::Conflict(format!(
+            "The branch of '{}' moved or the path conflicts with an existing entry",
+            repo
+        )),
+        RATE_LIMITED_SIGNATURE => AppError::RateLimited {
+            message: format!("Upload failed ({})", signature),
+            retry_after: None,
+        },
+        "error: AuthenticationFailed" => {
+            AppError::Unauthorized(format!("Upload failed ({})", signature))
+        }
+        _ => AppError::Internal(format!("Upload failed ({})", signature)),
+    }
+}
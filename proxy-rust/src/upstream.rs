@@ -0,0 +1,318 @@
+//! Upstream request traces
+//!
+//! Every request gets an id, the client's `X-Request-Id` (up to 128 visible
+//! ASCII characters) or a generated one, echoed in the response. The
+//! requests the proxy makes to the Hub and CAS on its behalf (listings,
+//! revision lookups, CAS token and reconstruction queries, xorb fetches)
+//! are logged at debug level under that id, with their status, the id the
+//! upstream gave them and how long their response headers took, so a
+//! problem on the Hub side can be reported with evidence. With
+//! `UPSTREAM_TRACES` set, the traces of that many recent requests are also
+//! kept for `GET /upstream/:request_id`. The request's own log lines carry
+//! the id as well (see [`crate::logging`]).
+//!
+//! URLs are recorded without their query string, which carries the
+//! signature of presigned URLs. The cli engine talks to CAS from the CLI,
+//! so with it only the proxy's own Hub requests are traced.
+
+use crate::AppError;
+use axum::extract::{Request, State};
+use axum::http::HeaderValue;
+use axum::middleware::Next;
+use axum::response::Response;
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::future::Future;
+use std::hash::{BuildHasher, RandomState};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use tracing::{debug, Instrument, Span};
+
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+const MAX_REQUEST_ID_LEN: usize = 128;
+/// Upstream calls recorded per request; a long download's xorb fetches
+/// beyond it are only logged
+const MAX_CALLS: usize = 1000;
+/// Calls named in [`summary`]
+const SUMMARY_CALLS: usize = 10;
+/// Response headers carrying the upstream's id for a request, by preference
+const UPSTREAM_ID_HEADERS: [&str; 3] = ["x-request-id", "x-amz-request-id", "x-amz-cf-id"];
+
+tokio::task_local! {
+    static CURRENT: Arc<Trace>;
+}
+
+/// One request made upstream
+#[derive(Clone, Serialize)]
+pub struct Call {
+    /// What the request was for, e.g. `Reconstruction query`
+    pub what: String,
+    pub method: String,
+    pub url: String,
+    /// Milliseconds from the client request's arrival to this request
+    pub started_ms: u64,
+    /// Milliseconds until the response headers (or the failure)
+    pub duration_ms: u64,
+    pub status: Option<u16>,
+    pub upstream_request_id: Option<String>,
+    pub error: Option<String>,
+}
+
+/// Upstream requests made for one client request, as reported by
+/// `GET /upstream/:request_id`
+#[derive(Serialize)]
+pub struct TraceReport {
+    pub request_id: String,
+    pub method: String,
+    pub path: String,
+    /// Unix time the request arrived at
+    pub received_at: u64,
+    pub calls: Vec<Call>,
+    /// Calls made beyond those recorded
+    pub dropped: u64,
+}
+
+struct Trace {
+    request_id: String,
+    method: String,
+    path: String,
+    received: Instant,
+    received_at: u64,
+    calls: Mutex<Vec<Call>>,
+    dropped: AtomicU64,
+    traces: Traces,
+}
+
+impl Trace {
+    fn record(self: &Arc<Self>, call: Call) {
+        debug!(
+            "Request {}: {} {} {} answered {} in {}ms (upstream id {}){}",
+            self.request_id,
+            call.what,
+            call.method,
+            call.url,
+            call.status.map_or("nothing".to_string(), |s| s.to_string()),
+            call.duration_ms,
+            call.upstream_request_id.as_deref().unwrap_or("none"),
+            call.error
+                .as_ref()
+                .map_or(String::new(), |e| format!(": {}", e))
+        );
+        let first = {
+            let mut calls = self.calls.lock().unwrap();
+            if calls.len() >= MAX_CALLS {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+                return;
+            }
+            calls.push(call);
+            calls.len() == 1
+        };
+        // Only requests that went upstream take a place among the recent ones
+        if first {
+            self.traces.keep(self.clone());
+        }
+    }
+
+    fn report(&self) -> TraceReport {
+        TraceReport {
+            request_id: self.request_id.clone(),
+            method: self.method.clone(),
+            path: self.path.clone(),
+            received_at: self.received_at,
+            calls: self.calls.lock().unwrap().clone(),
+            dropped: self.dropped.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Traces of recent requests
+#[derive(Clone)]
+pub struct Traces {
+    capacity: usize,
+    recent: Arc<Mutex<VecDeque<Arc<Trace>>>>,
+}
+
+impl Traces {
+    /// Load `UPSTREAM_TRACES` (default 0: traces are only logged)
+    pub fn from_env() -> Self {
+        let capacity = crate::config::number("UPSTREAM_TRACES").unwrap_or(0);
+        Self {
+            capacity,
+            recent: Arc::default(),
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    fn keep(&self, trace: Arc<Trace>) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut recent = self.recent.lock().unwrap();
+        if recent.len() >= self.capacity {
+            recent.pop_front();
+        }
+        recent.push_back(trace);
+    }
+
+    /// The latest request with id `request_id`, if still kept
+    pub fn get(&self, request_id: &str) -> Result<TraceReport, AppError> {
+        if self.capacity == 0 {
+            return Err(AppError::NotFound(
+                "Upstream traces are not kept (UPSTREAM_TRACES is not set)".to_string(),
+            ));
+        }
+        let recent = self.recent.lock().unwrap();
+        recent
+            .iter()
+            .rev()
+            .find(|trace| trace.request_id == request_id)
+            .map(|trace| trace.report())
+            .ok_or_else(|| {
+                AppError::NotFound(format!(
+                    "No upstream requests recorded for request '{}'",
+                    request_id
+                ))
+            })
+    }
+}
+
+/// Middleware giving each request an id and tracing its upstream requests
+pub async fn track(State(traces): State<Traces>, request: Request, next: Next) -> Response {
+    let request_id = request
+        .headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|h| h.to_str().ok())
+        .map(str::trim)
+        .filter(|id| {
+            !id.is_empty()
+                && id.len() <= MAX_REQUEST_ID_LEN
+                && id.bytes().all(|b| b.is_ascii_graphic())
+        })
+        .map_or_else(generate_id, str::to_string);
+    let trace = Arc::new(Trace {
+        request_id: request_id.clone(),
+        method: request.method().to_string(),
+        path: request.uri().path().to_string(),
+        received: Instant::now(),
+        received_at: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs()),
+        calls: Mutex::default(),
+        dropped: AtomicU64::new(0),
+        traces,
+    });
+    let response = crate::retry::counted(Arc::default(), next.run(request));
+    let mut response = CURRENT.scope(trace, response).await;
+    if let Ok(value) = HeaderValue::from_str(&request_id) {
+        response.headers_mut().insert(REQUEST_ID_HEADER, value);
+    }
+    response
+}
+
+/// Id of the request being served, if any
+pub fn current_id() -> Option<String> {
+    CURRENT.try_with(|trace| trace.request_id.clone()).ok()
+}
+
+/// One line on the upstream calls of the request being served so far, e.g.
+/// `2 calls in 57ms: Repository listing 200 45ms, CAS token request 200 12ms`
+pub fn summary() -> String {
+    CURRENT
+        .try_with(|trace| {
+            let calls = trace.calls.lock().unwrap();
+            let total = calls.len() as u64 + trace.dropped.load(Ordering::Relaxed);
+            let millis: u64 = calls.iter().map(|call| call.duration_ms).sum();
+            let mut listed: Vec<String> = calls
+                .iter()
+                .take(SUMMARY_CALLS)
+                .map(|call| {
+                    let outcome = match (call.status, &call.error) {
+                        (Some(status), _) => status.to_string(),
+                        (None, Some(_)) => "failed".to_string(),
+                        (None, None) => "nothing".to_string(),
+                    };
+                    format!("{} {} {}ms", call.what, outcome, call.duration_ms)
+                })
+                .collect();
+            if total > SUMMARY_CALLS as u64 {
+                listed.push(format!("{} more", total - SUMMARY_CALLS as u64));
+            }
+            match total {
+                0 => "no upstream calls".to_string(),
+                total => format!("{} calls in {}ms: {}", total, millis, listed.join(", ")),
+            }
+        })
+        .unwrap_or_else(|_| "no upstream calls".to_string())
+}
+
+/// `future`, traced and logged under the current request, served by its
+/// hub (see [`crate::hub`]) and counting its retries (see
+/// [`crate::retry::counted`]); for work spawned on its behalf
+pub fn inherit<F: Future>(future: F) -> impl Future<Output = F::Output> {
+    let trace = CURRENT.try_with(Arc::clone).ok();
+    let future = crate::retry::inherit(crate::hub::inherit(future.instrument(Span::current())));
+    async move {
+        match trace {
+            Some(trace) => CURRENT.scope(trace, future).await,
+            None => future.await,
+        }
+    }
+}
+
+/// Send `request`, recording it in the current request's trace
+pub async fn send(
+    request: reqwest::RequestBuilder,
+    what: &str,
+) -> reqwest::Result<reqwest::Response> {
+    let (client, request) = request.build_split();
+    let request = request?;
+    let method = request.method().to_string();
+    let full_url = request.url().to_string();
+    let mut url = request.url().clone();
+    url.set_query(None);
+    let started = Instant::now();
+    let result = client.execute(request).await;
+    let _ = CURRENT.try_with(|trace| {
+        let (status, upstream_request_id, error) = match &result {
+            Ok(response) => (
+                Some(response.status().as_u16()),
+                UPSTREAM_ID_HEADERS.iter().find_map(|name| {
+                    let value = response.headers().get(*name)?.to_str().ok()?;
+                    Some(value.to_string())
+                }),
+                None,
+            ),
+            Err(e) => (
+                None,
+                None,
+                Some(e.to_string().replace(&full_url, url.as_str())),
+            ),
+        };
+        trace.record(Call {
+            what: what.to_string(),
+            method,
+            url: url.to_string(),
+            started_ms: started.duration_since(trace.received).as_millis() as u64,
+            duration_ms: started.elapsed().as_millis() as u64,
+            status,
+            upstream_request_id,
+            error,
+        });
+    });
+    result
+}
+
+/// Id for a request that came without one
+fn generate_id() -> String {
+    static SEQUENCE: AtomicU64 = AtomicU64::new(0);
+    let state = RandomState::new();
+    format!(
+        "{:016x}{:016x}",
+        state.hash_one(SEQUENCE.fetch_add(1, Ordering::Relaxed)),
+        state.hash_one(std::process::id())
+    )
+}
@@ -0,0 +1,354 @@
+//! Persistent CLI workers
+//!
+//! Spawning `xet-download` per download pays for process start-up and a CAS
+//! token request every time. With `CLI_WORKERS=<n>` the CLI engine instead
+//! keeps up to n `xet-download worker` processes, each serving one download
+//! at a time and reusing CAS tokens until shortly before they expire.
+//! Downloads beyond n wait for a free worker (within their time budget),
+//! resumed transfers first (see [`crate::resume`]).
+//!
+//! A job is one tab-separated line on the worker's stdin:
+//! `<fifo> <repo_type> <repo_id> <revision> <hash> <start-end|-> <hub> <hf_token>`,
+//! `<hub>` being the request's endpoint (see [`crate::hub`]).
+//! The worker writes the file into the named pipe `<fifo>` and then answers
+//! with a `done` or `error` [`crate::protocol`] message on stdout. The proxy holds a write end of the pipe itself until
+//! the answer arrives, so the body ends exactly when the job does, even if
+//! the worker never got to open the pipe.
+//!
+//! A worker whose job failed, ran out of time or lost its client is killed,
+//! and a fresh one is spawned on demand, so a worker in a bad state never
+//! serves twice.
+
+use crate::protocol::{self, Message};
+use crate::range::ByteRange;
+use crate::repo::RepoRef;
+use crate::slots::{Priority, Slots};
+use crate::subprocess::{self, Cli};
+use crate::AppError;
+use std::ffi::CString;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader, Lines};
+use tokio::net::unix::pipe;
+use tokio::process::{Child, ChildStdin, ChildStdout};
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+use tracing::{error, info, warn, Span};
+
+/// Load the pool size from `CLI_WORKERS` (unset or 0 = a process per download)
+pub fn pool_size_from_env() -> usize {
+    crate::config::number("CLI_WORKERS").unwrap_or(0)
+}
+
+/// How a job ended, if not successfully
+pub enum JobFailure {
+    /// Failure signature, as recorded by [`Cli::record_failure`]
+    Failed(String),
+    TimedOut,
+}
+
+/// A download for a worker
+pub struct Job<'a> {
+    pub repo: &'a RepoRef,
+    pub hash: &'a str,
+    pub hf_token: &'a str,
+    pub range: Option<ByteRange>,
+    pub priority: Priority,
+}
+
+impl Job<'_> {
+    fn line(&self, fifo: &Path) -> Result<String, AppError> {
+        // Fields are tab separated and the job ends at a newline
+        if self.hf_token.chars().any(|c| c.is_control()) {
+            return Err(AppError::BadRequest(
+                "Token must not contain control characters".to_string(),
+            ));
+        }
+        let range = self
+            .range
+            .map_or_else(|| "-".to_string(), |range| range.cli_arg());
+        Ok(format!(
+            "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\n",
+            fifo.display(),
+            self.repo.repo_type.as_str(),
+            self.repo.id(),
+            self.repo.revision_segment(),
+            self.hash,
+            range,
+            crate::hub::endpoint(),
+            self.hf_token
+        ))
+    }
+}
+
+/// Pool of `xet-download worker` processes
+#[derive(Clone)]
+pub struct WorkerPool {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    cli: Cli,
+    idle: Mutex<Vec<Worker>>,
+    /// One slot per worker
+    slots: Slots,
+    fifo_dir: PathBuf,
+    next_job: AtomicU64,
+}
+
+impl WorkerPool {
+    /// Pool of `size` workers, if `CLI_WORKERS` asks for one
+    pub fn from_env(cli: &Cli) -> Option<Self> {
+        let size = pool_size_from_env();
+        if size == 0 {
+            return None;
+        }
+        let fifo_dir = std::env::temp_dir().join(format!("xet-proxy-{}", std::process::id()));
+        std::fs::create_dir_all(&fifo_dir)
+            .unwrap_or_else(|e| panic!("Failed to create {}: {}", fifo_dir.display(), e));
+        info!("Serving CLI downloads from a pool of {} workers", size);
+        Some(Self {
+            inner: Arc::new(Inner {
+                cli: cli.clone(),
+                idle: Mutex::new(Vec::new()),
+                slots: Slots::new(size),
+                fifo_dir,
+                next_job: AtomicU64::new(0),
+            }),
+        })
+    }
+
+    /// Hand a job to a worker and return the pipe its output arrives on
+    ///
+    /// `settle` maps the job's end onto the outcome sent on the returned
+    /// channel once the output is complete. If `cancelled` fires first, the
+    /// worker is killed and nothing is sent.
+    pub async fn start<F>(
+        &self,
+        job: Job<'_>,
+        deadline: Option<Instant>,
+        cancelled: oneshot::Receiver<()>,
+        settle: F,
+    ) -> Result<(pipe::Receiver, oneshot::Receiver<Result<(), AppError>>), AppError>
+    where
+        F: FnOnce(Result<(), JobFailure>) -> Result<(), AppError> + Send + 'static,
+    {
+        let slot = self.inner.slots.acquire(job.priority);
+        let slot = match deadline {
+            Some(deadline) => tokio::time::timeout_at(deadline, slot).await.map_err(|_| {
+                AppError::Timeout("No CLI worker became free within the time budget".to_string())
+            })?,
+            None => slot.await,
+        };
+
+        let id = self.inner.next_job.fetch_add(1, Ordering::Relaxed);
+        let fifo = Fifo::create(self.inner.fifo_dir.join(format!("job-{}", id)))
+            .map_err(|e| AppError::Internal(format!("Failed to create job pipe: {}", e)))?;
+        let line = job.line(&fifo.0)?;
+        let open = |e: io::Error| AppError::Internal(format!("Failed to open job pipe: {}", e));
+        let output = pipe::OpenOptions::new()
+            .open_receiver(&fifo.0)
+            .map_err(open)?;
+        // Keeps the output open until the worker has answered
+        let keeper = pipe::OpenOptions::new()
+            .open_sender(&fifo.0)
+            .map_err(open)?;
+
+        let worker = self.dispatch(&line).await?;
+        *worker.job_span.lock().unwrap() = Span::current();
+        let (outcome_sender, outcome) = oneshot::channel();
+        let inner = self.inner.clone();
+        tokio::spawn(crate::upstream::inherit(async move {
+            let mut worker = worker;
+            let budget_exceeded = async {
+                match deadline {
+                    Some(deadline) => tokio::time::sleep_until(deadline).await,
+                    None => std::future::pending().await,
+                }
+            };
+            let reply = tokio::select! {
+                reply = worker.replies.next_line() => reply,
+                _ = budget_exceeded => {
+                    error!("Job {} exceeded its time budget, killing zig worker", id);
+                    inner.cli.record_failure(&worker.kill().await, &worker.stderr_tail().await);
+                    drop(keeper);
+                    let _ = outcome_sender.send(settle(Err(JobFailure::TimedOut)));
+                    return;
+                }
+                Ok(()) = cancelled => {
+                    info!("Client went away, killing zig worker for job {}", id);
+                    worker.kill().await;
+                    return;
+                }
+            };
+            // The output ends once the worker's write end is closed too
+            drop(keeper);
+            drop(fifo);
+
+            let result = match reply.map(|reply| reply.map(|line| protocol::parse_line(&line))) {
+                Ok(Some(Ok(Message::Done))) => {
+                    *worker.job_span.lock().unwrap() = Span::none();
+                    inner.idle.lock().unwrap().push(worker);
+                    Ok(())
+                }
+                Ok(Some(reply)) => {
+                    let status = worker.kill().await;
+                    let tail = worker.stderr_tail().await;
+                    let signature = match reply {
+                        Ok(Message::Error { error }) => format!("error: {}", error),
+                        Ok(other) => {
+                            warn!("{}", other.unexpected("worker").message());
+                            "protocol: unexpected reply".to_string()
+                        }
+                        Err(e) => {
+                            warn!("{}", e.message());
+                            "protocol: unexpected reply".to_string()
+                        }
+                    };
+                    inner
+                        .cli
+                        .record_signature(&signature, &status.to_string(), &tail);
+                    Err(JobFailure::Failed(signature))
+                }
+                Ok(None) | Err(_) => {
+                    // The worker exited (or crashed) mid-job
+                    let status = worker.wait().await;
+                    let tail = worker.stderr_tail().await;
+                    Err(JobFailure::Failed(inner.cli.record_failure(&status, &tail)))
+                }
+            };
+            drop(slot);
+            let _ = outcome_sender.send(settle(result));
+        }));
+        Ok((output, outcome))
+    }
+
+    /// Send a job line to an idle worker, or a new one if none is left
+    async fn dispatch(&self, line: &str) -> Result<Worker, AppError> {
+        let idle = self.inner.idle.lock().unwrap().pop();
+        if let Some(mut worker) = idle {
+            match worker.send(line).await {
+                Ok(()) => return Ok(worker),
+                Err(e) => {
+                    // Died while idle, e.g. of a resource limit
+                    warn!("Idle zig worker is gone ({}), replacing it", e);
+                    let status = worker.wait().await;
+                    self.inner
+                        .cli
+                        .record_failure(&status, &worker.stderr_tail().await);
+                }
+            }
+        }
+        let mut worker = self.spawn()?;
+        worker
+            .send(line)
+            .await
+            .map_err(|e| AppError::Internal(format!("Failed to send job to zig worker: {}", e)))?;
+        Ok(worker)
+    }
+
+    fn spawn(&self) -> Result<Worker, AppError> {
+        let mut child = self
+            .inner
+            .cli
+            .command()
+            .arg("worker")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()
+            .map_err(|e| AppError::Internal(format!("Failed to spawn zig worker: {}", e)))?;
+        let (Some(stdin), Some(stdout), Some(stderr)) =
+            (child.stdin.take(), child.stdout.take(), child.stderr.take())
+        else {
+            return Err(AppError::Internal(
+                "Failed to capture zig worker pipes".to_string(),
+            ));
+        };
+        let label = format!("worker {}", child.id().unwrap_or_default());
+        info!("Spawned zig {}", label);
+        let job_span = Arc::new(Mutex::new(Span::none()));
+        let span = job_span.clone();
+        Ok(Worker {
+            child,
+            stdin,
+            replies: BufReader::new(stdout).lines(),
+            stderr: Some(tokio::spawn(async move {
+                subprocess::collect_stderr_tail(stderr, &label, || span.lock().unwrap().clone())
+                    .await
+            })),
+            job_span,
+        })
+    }
+}
+
+impl Drop for Inner {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.fifo_dir);
+    }
+}
+
+struct Worker {
+    child: Child,
+    stdin: ChildStdin,
+    replies: Lines<BufReader<ChildStdout>>,
+    /// Collects the stderr tail until the worker exits
+    stderr: Option<JoinHandle<Vec<String>>>,
+    /// Span of the request whose job the worker is running, for its stderr
+    job_span: Arc<Mutex<Span>>,
+}
+
+impl Worker {
+    async fn send(&mut self, line: &str) -> io::Result<()> {
+        if let Some(status) = self.child.try_wait()? {
+            return Err(io::Error::other(format!("exited with {}", status)));
+        }
+        self.stdin.write_all(line.as_bytes()).await?;
+        self.stdin.flush().await
+    }
+
+    async fn kill(&mut self) -> std::process::ExitStatus {
+        let _ = self.child.kill().await;
+        self.wait().await
+    }
+
+    async fn wait(&mut self) -> std::process::ExitStatus {
+        self.child.wait().await.unwrap_or_else(|e| {
+            error!("Failed to wait for zig worker: {}", e);
+            std::os::unix::process::ExitStatusExt::from_raw(-1)
+        })
+    }
+
+    /// Last stderr lines, once the worker has exited
+    async fn stderr_tail(&mut self) -> Vec<String> {
+        match self.stderr.take() {
+            Some(task) => task.await.unwrap_or_default(),
+            None => Vec::new(),
+        }
+    }
+}
+
+/// Named pipe a job's output is written to, removed when dropped
+struct Fifo(PathBuf);
+
+impl Fifo {
+    fn create(path: PathBuf) -> io::Result<Self> {
+        let c_path = CString::new(path.as_os_str().as_bytes()).map_err(io::Error::other)?;
+        if unsafe { libc::mkfifo(c_path.as_ptr(), 0o600) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Self(path))
+    }
+}
+
+impl Drop for Fifo {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
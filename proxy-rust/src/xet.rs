@@ -0,0 +1,578 @@
+//! Native XET download engine
+//!
+//! Implements the read side of the XET protocol in-process, mirroring the
+//! Zig CLI: the Hub tree API lists files, the Hub issues a CAS read token,
+//! CAS returns the reconstruction terms of a file (optionally for a byte
+//! range), and each term's xorb range is fetched from its presigned URL and
+//! decoded. Terms are fetched one at a time and pushed into a small bounded
+//! channel, so a slow client slows the upstream fetches instead of
+//! buffering the file in memory. A xorb fetch failing transiently is
+//! retried in place (see [`crate::retry`]), so one hiccup mid-stream
+//! doesn't abort the download. What the Hub, CAS and xorb storage turn out
+//! to support is recorded as it's used (see [`crate::capabilities`]).
+
+use crate::backoff::UpstreamBackoff;
+use crate::capabilities::{self, Capabilities};
+use crate::downloader::{Download, DownloadRequest, Downloader};
+use crate::listing::{ListPage, ListedFile};
+use crate::repo::RepoRef;
+use crate::retry::RetryPolicy;
+use crate::{xorb, AppError};
+use async_trait::async_trait;
+use axum::body::Bytes;
+use reqwest::{header, StatusCode};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use tracing::{debug, error, warn};
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+/// Decoded terms buffered ahead of the client
+const TERM_BUFFER: usize = 4;
+
+/// Downloader talking to the Hub and CAS directly
+pub struct NativeDownloader {
+    http: reqwest::Client,
+    backoff: UpstreamBackoff,
+    retry: RetryPolicy,
+    capabilities: Capabilities,
+}
+
+#[derive(Deserialize)]
+struct TreeEntry {
+    #[serde(rename = "type")]
+    kind: String,
+    path: String,
+    #[serde(default)]
+    size: u64,
+    #[serde(rename = "xetHash")]
+    xet_hash: Option<String>,
+}
+
+/// The part of the Hub's repository info naming the commit
+#[derive(Deserialize)]
+struct RevisionInfo {
+    sha: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct CasToken {
+    access_token: String,
+    cas_url: String,
+}
+
+#[derive(Deserialize)]
+struct Reconstruction {
+    #[serde(default)]
+    offset_into_first_range: u64,
+    terms: Vec<Term>,
+    #[serde(default)]
+    fetch_info: HashMap<String, Vec<FetchInfo>>,
+}
+
+impl Reconstruction {
+    fn unpacked_length(&self) -> u64 {
+        self.terms.iter().map(|t| t.unpacked_length).sum()
+    }
+}
+
+#[derive(Deserialize)]
+struct Term {
+    hash: String,
+    unpacked_length: u64,
+    range: ChunkRange,
+}
+
+#[derive(Clone, Copy, Deserialize)]
+struct ChunkRange {
+    start: u32,
+    end: u32,
+}
+
+#[derive(Clone, Deserialize)]
+struct FetchInfo {
+    range: ChunkRange,
+    url: String,
+    /// Byte range of the presigned URL (inclusive end)
+    url_range: UrlRange,
+}
+
+#[derive(Clone, Copy, Deserialize)]
+struct UrlRange {
+    start: u64,
+    end: u64,
+}
+
+impl NativeDownloader {
+    pub fn new(backoff: UpstreamBackoff, retry: RetryPolicy, capabilities: Capabilities) -> Self {
+        let http = crate::trust::apply(reqwest::Client::builder())
+            .connect_timeout(CONNECT_TIMEOUT)
+            .user_agent(concat!("xet-proxy/", env!("CARGO_PKG_VERSION")))
+            .build()
+            .expect("Failed to build HTTP client");
+        Self {
+            http,
+            backoff,
+            retry,
+            capabilities,
+        }
+    }
+
+    async fn cas_token(&self, repo: &RepoRef, hf_token: &str) -> Result<CasToken, AppError> {
+        let url = format!(
+            "{}/api/{}/{}/xet-read-token/{}",
+            crate::hub::endpoint(),
+            repo.repo_type.plural(),
+            repo.id(),
+            repo.revision_segment()
+        );
+        let response = self
+            .get(&url, hf_token, "CAS token request")
+            .await
+            .map_err(|e| match e {
+                AppError::Forbidden(_) => AppError::Forbidden(format!(
+                    "No access to '{}' (gated or restricted) to authorize the download",
+                    repo
+                )),
+                AppError::NotFound(_) => AppError::NotFound(format!(
+                    "Repository '{}' not found, or not visible with this token",
+                    repo
+                )),
+                e => e,
+            })?;
+        response
+            .json()
+            .await
+            .map_err(|e| AppError::Internal(format!("Invalid CAS token response: {}", e)))
+    }
+
+    async fn reconstruction(
+        &self,
+        token: &CasToken,
+        hash: &str,
+        range: Option<(u64, u64)>,
+    ) -> Result<Reconstruction, AppError> {
+        let url = format!("{}/reconstructions/{}", token.cas_url, hash);
+        let mut request = self.http.get(&url).bearer_auth(&token.access_token);
+        if let Some((start, end)) = range {
+            request = request.header(header::RANGE, format!("bytes={}-{}", start, end));
+        }
+        let response = send(request, "Reconstruction query").await?;
+        let recon: Reconstruction = response
+            .json()
+            .await
+            .map_err(|e| AppError::Internal(format!("Invalid reconstruction response: {}", e)))?;
+        if recon.terms.is_empty() {
+            return Ok(recon);
+        }
+        let supported = !recon.fetch_info.is_empty();
+        self.capabilities
+            .record(capabilities::CAS, &token.cas_url, |c| {
+                c.reconstruction_api = Some(if supported { "v1" } else { "unsupported" })
+            });
+        if !supported {
+            return Err(AppError::Internal(format!(
+                "CAS at {} answered reconstructions without fetch_info, an unsupported API version",
+                token.cas_url
+            )));
+        }
+        Ok(recon)
+    }
+
+    /// Reconstruction of a file or range. The first query for a range not
+    /// starting at 0 is also made for the whole file to tell whether the CAS
+    /// honors `Range`; one that doesn't gets whole-file queries, with the
+    /// range start as the offset to skip.
+    async fn range_reconstruction(
+        &self,
+        token: &CasToken,
+        hash: &str,
+        range: Option<(u64, u64)>,
+    ) -> Result<Reconstruction, AppError> {
+        let Some((start, _)) = range else {
+            return self.reconstruction(token, hash, None).await;
+        };
+        let whole = match self
+            .capabilities
+            .get(capabilities::CAS, &token.cas_url)
+            .ranged_reconstructions
+        {
+            Some(false) => self.reconstruction(token, hash, None).await?,
+            None if start > 0 => {
+                let ranged = self.reconstruction(token, hash, range).await?;
+                let whole = self.reconstruction(token, hash, None).await?;
+                let ignored = ranged.offset_into_first_range == 0
+                    && ranged.unpacked_length() == whole.unpacked_length();
+                self.capabilities
+                    .record(capabilities::CAS, &token.cas_url, |c| {
+                        c.ranged_reconstructions = Some(!ignored)
+                    });
+                if !ignored {
+                    return Ok(ranged);
+                }
+                whole
+            }
+            _ => return self.reconstruction(token, hash, range).await,
+        };
+        Ok(Reconstruction {
+            offset_into_first_range: start,
+            ..whole
+        })
+    }
+
+    async fn get(
+        &self,
+        url: &str,
+        hf_token: &str,
+        what: &str,
+    ) -> Result<reqwest::Response, AppError> {
+        send(self.http.get(url).bearer_auth(hf_token), what).await
+    }
+}
+
+/// Send a request, mapping failures onto proxy errors
+pub async fn send(
+    request: reqwest::RequestBuilder,
+    what: &str,
+) -> Result<reqwest::Response, AppError> {
+    let response = crate::upstream::send(request, what)
+        .await
+        .map_err(|e| AppError::Internal(format!("{} failed: {}", what, e)))?;
+    let status = response.status();
+    if status.is_success() {
+        return Ok(response);
+    }
+
+    let message = format!("{} failed with HTTP {}", what, status.as_u16());
+    Err(match status {
+        StatusCode::UNAUTHORIZED => AppError::Unauthorized(message),
+        StatusCode::FORBIDDEN => AppError::Forbidden(message),
+        StatusCode::NOT_FOUND => AppError::NotFound(message),
+        StatusCode::TOO_MANY_REQUESTS => AppError::RateLimited {
+            message,
+            retry_after: None,
+        },
+        _ => AppError::Internal(message),
+    })
+}
+
+#[async_trait]
+impl Downloader for NativeDownloader {
+    async fn list(&self, repo: &RepoRef, hf_token: &str) -> Result<Vec<ListedFile>, AppError> {
+        let mut files = Vec::new();
+        let mut cursor = None;
+        loop {
+            let page = self.list_page(repo, hf_token, cursor.as_deref()).await?;
+            files.extend(page.files);
+            cursor = page.cursor;
+            if cursor.is_none() {
+                return Ok(files);
+            }
+        }
+    }
+
+    /// The cursor is the query string of the tree API's next page, hex
+    /// encoded: it can only ever lead to the same endpoint
+    async fn list_page(
+        &self,
+        repo: &RepoRef,
+        hf_token: &str,
+        cursor: Option<&str>,
+    ) -> Result<ListPage, AppError> {
+        let tree = format!(
+            "{}/api/{}/{}/tree/{}",
+            crate::hub::endpoint(),
+            repo.repo_type.plural(),
+            repo.id(),
+            repo.revision_segment()
+        );
+        let url = match cursor {
+            Some(cursor) => {
+                let query = hex::decode(cursor)
+                    .ok()
+                    .and_then(|query| String::from_utf8(query).ok())
+                    .ok_or_else(|| AppError::BadRequest(format!("Invalid cursor '{}'", cursor)))?;
+                format!("{}?{}", tree, query)
+            }
+            None => tree,
+        };
+        let response = self.get(&url, hf_token, "Repository listing").await?;
+        // The tree API paginates with `Link: <...>; rel="next"`
+        let cursor = next_page(response.headers())
+            .and_then(|next| Some(hex::encode(next.split_once('?')?.1)));
+        let entries: Vec<TreeEntry> = response
+            .json()
+            .await
+            .map_err(|e| AppError::Internal(format!("Invalid listing response: {}", e)))?;
+        // A page with files but no hash only says so much: the repo may
+        // just not use XET
+        let has_files = entries.iter().any(|entry| entry.kind == "file");
+        let hashed = entries.iter().any(|entry| entry.xet_hash.is_some());
+        if has_files {
+            self.capabilities
+                .record(capabilities::HUB, &crate::hub::endpoint(), |c| {
+                    c.xet_hashes = Some(hashed || c.xet_hashes == Some(true))
+                });
+        }
+        let files = entries
+            .into_iter()
+            .filter_map(|entry| {
+                Some(ListedFile {
+                    xet_hash: entry.xet_hash.filter(|_| entry.kind == "file")?,
+                    path: entry.path,
+                    size: entry.size,
+                })
+            })
+            .collect();
+        Ok(ListPage { files, cursor })
+    }
+
+    async fn commit(&self, repo: &RepoRef, hf_token: &str) -> Result<Option<String>, AppError> {
+        let url = format!(
+            "{}/api/{}/{}/revision/{}",
+            crate::hub::endpoint(),
+            repo.repo_type.plural(),
+            repo.id(),
+            repo.revision_segment()
+        );
+        let response = self.get(&url, hf_token, "Revision lookup").await?;
+        let info: RevisionInfo = response
+            .json()
+            .await
+            .map_err(|e| AppError::Internal(format!("Invalid revision response: {}", e)))?;
+        Ok(Some(info.sha))
+    }
+
+    async fn download(&self, request: DownloadRequest<'_>) -> Result<Download, AppError> {
+        let range = request.range.map(|r| (r.start, r.end));
+        let recon = self
+            .backoff
+            .guard(&request.repo.to_string(), async {
+                let token = self.cas_token(request.repo, request.hf_token).await?;
+                self.range_reconstruction(&token, request.hash, range).await
+            })
+            .await?;
+
+        let length = match request.range {
+            Some(range) => range.len(),
+            None => recon.unpacked_length(),
+        };
+        debug!(
+            "Reconstructing {} from {} terms ({} bytes)",
+            request.hash,
+            recon.terms.len(),
+            length
+        );
+
+        let upstream_bytes = Arc::new(AtomicU64::new(0));
+        let (sender, receiver) = mpsc::channel(TERM_BUFFER);
+        let fetch = stream_terms(
+            self.http.clone(),
+            self.retry.clone(),
+            self.capabilities.clone(),
+            recon,
+            length,
+            sender.clone(),
+            upstream_bytes.clone(),
+        );
+        let deadline = request.deadline;
+        let hash = request.hash.to_string();
+        tokio::spawn(crate::upstream::inherit(async move {
+            let result = match deadline {
+                Some(deadline) => tokio::time::timeout_at(deadline, fetch)
+                    .await
+                    .unwrap_or_else(|_| Err("request time budget exceeded".to_string())),
+                None => fetch.await,
+            };
+            if let Err(e) = result {
+                error!("Native download of {} failed: {}", hash, e);
+                let _ = sender.send(Err(io::Error::other(e))).await;
+            }
+        }));
+
+        Ok(Download {
+            body: Box::pin(ReceiverStream::new(receiver)),
+            length: Some(length),
+            upstream_bytes,
+            cached: None,
+            peer: false,
+        })
+    }
+
+    async fn file_size(
+        &self,
+        repo: &RepoRef,
+        hash: &str,
+        hf_token: &str,
+    ) -> Result<Option<u64>, AppError> {
+        let recon = self
+            .backoff
+            .guard(&repo.to_string(), async {
+                let token = self.cas_token(repo, hf_token).await?;
+                self.reconstruction(&token, hash, None).await
+            })
+            .await?;
+        Ok(Some(recon.unpacked_length()))
+    }
+}
+
+/// Fetch and decode each term in order, forwarding `length` bytes after the
+/// range offset. Stops quietly if the client went away.
+async fn stream_terms(
+    http: reqwest::Client,
+    retry: RetryPolicy,
+    capabilities: Capabilities,
+    recon: Reconstruction,
+    length: u64,
+    sender: mpsc::Sender<io::Result<Bytes>>,
+    upstream_bytes: Arc<AtomicU64>,
+) -> Result<(), String> {
+    let mut skip = recon.offset_into_first_range;
+    let mut remaining = length;
+
+    for term in &recon.terms {
+        if remaining == 0 {
+            break;
+        }
+        let data = fetch_term(&http, &retry, &capabilities, term, &recon.fetch_info).await?;
+
+        let mut slice = &data[..];
+        let skipped = skip.min(slice.len() as u64) as usize;
+        slice = &slice[skipped..];
+        skip -= skipped as u64;
+        let take = remaining.min(slice.len() as u64) as usize;
+        remaining -= take as u64;
+        if take == 0 {
+            continue;
+        }
+        // Upstream bytes are the bytes of the file (or range) produced
+        upstream_bytes.fetch_add(take as u64, Ordering::Relaxed);
+
+        if sender
+            .send(Ok(Bytes::copy_from_slice(&slice[..take])))
+            .await
+            .is_err()
+        {
+            return Ok(());
+        }
+    }
+
+    if remaining != 0 {
+        return Err(format!("reconstruction ended {} bytes short", remaining));
+    }
+    Ok(())
+}
+
+async fn fetch_term(
+    http: &reqwest::Client,
+    retry: &RetryPolicy,
+    capabilities: &Capabilities,
+    term: &Term,
+    fetch_info: &HashMap<String, Vec<FetchInfo>>,
+) -> Result<Vec<u8>, String> {
+    let info = fetch_info
+        .get(&term.hash)
+        .and_then(|infos| {
+            infos
+                .iter()
+                .find(|i| i.range.start <= term.range.start && i.range.end >= term.range.end)
+        })
+        .ok_or_else(|| format!("no fetch info covering xorb {}", term.hash))?;
+
+    let mut attempt = 0;
+    let body = loop {
+        match fetch_xorb(http, capabilities, info).await {
+            Ok(body) => break body,
+            Err(e) if attempt < retry.chunk_retries && transient(&e) => {
+                attempt += 1;
+                let delay = retry.backoff(attempt);
+                warn!(
+                    "Xorb fetch of {} failed (attempt {}/{}), retrying in {}ms: {}",
+                    term.hash,
+                    attempt,
+                    retry.chunk_retries + 1,
+                    delay.as_millis(),
+                    e
+                );
+                retry.record("Xorb fetch");
+                tokio::time::sleep(delay).await;
+            }
+            Err(e) => return Err(format!("xorb fetch failed: {}", e)),
+        }
+    };
+
+    let chunks = xorb::extract_chunk_range(
+        &body,
+        term.range.start - info.range.start,
+        term.range.end - info.range.start,
+    )
+    .map_err(|e| e.message().to_string())?;
+    if chunks.len() as u64 != term.unpacked_length {
+        return Err(format!(
+            "xorb {} decoded to {} bytes, expected {}",
+            term.hash,
+            chunks.len(),
+            term.unpacked_length
+        ));
+    }
+    Ok(chunks)
+}
+
+/// Bytes of a xorb range, cut out of the whole object if the store
+/// ignores `Range`
+async fn fetch_xorb(
+    http: &reqwest::Client,
+    capabilities: &Capabilities,
+    info: &FetchInfo,
+) -> reqwest::Result<Bytes> {
+    let request = http.get(&info.url).header(
+        header::RANGE,
+        format!("bytes={}-{}", info.url_range.start, info.url_range.end),
+    );
+    let response = crate::upstream::send(request, "Xorb fetch")
+        .await?
+        .error_for_status()?;
+    let ranged = response.status() == StatusCode::PARTIAL_CONTENT;
+    capabilities.record(
+        capabilities::XORB_STORAGE,
+        capabilities::origin(&info.url),
+        |c| c.ranged_fetches = Some(ranged),
+    );
+    let body = response.bytes().await?;
+    if ranged {
+        return Ok(body);
+    }
+    let end = (info.url_range.end as usize)
+        .saturating_add(1)
+        .min(body.len());
+    let start = (info.url_range.start as usize).min(end);
+    Ok(body.slice(start..end))
+}
+
+/// Whether a failed fetch may succeed if tried again: connection and body
+/// errors, 429 and 5xx
+fn transient(error: &reqwest::Error) -> bool {
+    error
+        .status()
+        .is_none_or(|status| status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error())
+}
+
+/// URL of the next page of a Hub API answer (`Link: <...>; rel="next"`)
+pub fn next_page(headers: &header::HeaderMap) -> Option<String> {
+    let link = headers.get(header::LINK)?.to_str().ok()?;
+    link.split(',').find_map(|part| {
+        let (url, params) = part.split_once(';')?;
+        params.contains("rel=\"next\"").then(|| {
+            url.trim()
+                .trim_start_matches('<')
+                .trim_end_matches('>')
+                .to_string()
+        })
+    })
+}
@@ -0,0 +1,91 @@
+//! XET hash formats
+//!
+//! Hashes arrive in URLs and request bodies as text; handlers parse them
+//! once into an [`XetHash`] and key everything else (CAS queries, the
+//! cache, the catalog, listings) on its canonical text. Today's only format
+//! is a 32-byte MerkleHash written as 64 hex characters, either bare or
+//! prefixed with its version as `xet1:<hex>`; both parse to the same hash,
+//! whose canonical text is the bare hex the Hub lists. A new hash or
+//! merkle-root format is one more [`Format`], with its prefix, check and
+//! canonical spelling, here.
+
+use crate::AppError;
+
+/// Versions of the XET hash format
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    /// 32-byte MerkleHash, as 64 hex characters
+    V1,
+}
+
+impl Format {
+    const ALL: [Self; 1] = [Self::V1];
+
+    /// Format of a hash written without a prefix
+    const BARE: Self = Self::V1;
+
+    /// Prefix naming the format in `<prefix>:<hash>`
+    fn prefix(self) -> &'static str {
+        match self {
+            Self::V1 => "xet1",
+        }
+    }
+
+    /// What a hash of this format looks like, for errors
+    fn expected(self) -> &'static str {
+        match self {
+            Self::V1 => "64 hex characters",
+        }
+    }
+
+    fn accepts(self, digest: &str) -> bool {
+        match self {
+            Self::V1 => digest.len() == 64 && digest.bytes().all(|b| b.is_ascii_hexdigit()),
+        }
+    }
+
+    /// Canonical text of an accepted `digest`
+    fn canonical(self, digest: &str) -> String {
+        match self {
+            Self::V1 => digest.to_string(),
+        }
+    }
+}
+
+/// A hash in one of the supported formats, by its canonical text
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct XetHash(String);
+
+impl XetHash {
+    /// Parse a bare hash or a `<prefix>:<hash>`
+    pub fn parse(text: &str) -> Result<Self, AppError> {
+        let (format, digest) = match text.split_once(':') {
+            Some((prefix, digest)) => {
+                let format = Format::ALL
+                    .into_iter()
+                    .find(|format| format.prefix() == prefix)
+                    .ok_or_else(|| {
+                        AppError::BadRequest(format!(
+                            "Unsupported XET hash format '{}' in '{}'",
+                            prefix, text
+                        ))
+                    })?;
+                (format, digest)
+            }
+            None => (Format::BARE, text),
+        };
+        if !format.accepts(digest) {
+            return Err(AppError::BadRequest(format!(
+                "Invalid XET hash '{}' (expected {})",
+                text,
+                format.expected()
+            )));
+        }
+        Ok(Self(format.canonical(digest)))
+    }
+
+    /// Canonical text, as the rest of the proxy keys on it
+    pub fn into_string(self) -> String {
+        self.0
+    }
+}
@@ -0,0 +1,115 @@
+//! Xorb chunk decoding
+//!
+//! A xorb is a sequence of chunks, each stored as an 8-byte header followed
+//! by its (possibly compressed) bytes:
+//!
+//! ```text
+//! version (1) | compressed size (3, LE) | compression type (1) | uncompressed size (3, LE)
+//! ```
+//!
+//! Compression types are 0 (none), 1 (LZ4 frame), 2 (byte grouping + LZ4)
+//! and 3 (full bitslice + LZ4), mirroring the Zig implementation.
+
+use crate::AppError;
+use std::io::Read;
+
+const CHUNK_HEADER_SIZE: usize = 8;
+const XORB_VERSION: u8 = 0;
+
+/// Decode chunks `[start, end)` of a xorb byte range whose first chunk has index 0
+pub fn extract_chunk_range(data: &[u8], start: u32, end: u32) -> Result<Vec<u8>, AppError> {
+    if start >= end {
+        return Err(invalid("empty chunk range"));
+    }
+
+    let mut output = Vec::new();
+    let mut position = 0;
+    let mut index = 0;
+    while index < end {
+        let header = data
+            .get(position..position + CHUNK_HEADER_SIZE)
+            .ok_or_else(|| invalid("truncated chunk header"))?;
+        if header[0] != XORB_VERSION {
+            return Err(invalid(&format!("unsupported chunk version {}", header[0])));
+        }
+        let compressed_size = u24(&header[1..4]);
+        let uncompressed_size = u24(&header[5..8]);
+        position += CHUNK_HEADER_SIZE;
+
+        let compressed = data
+            .get(position..position + compressed_size)
+            .ok_or_else(|| invalid("truncated chunk data"))?;
+        position += compressed_size;
+
+        if index >= start {
+            output.extend_from_slice(&decompress(compressed, header[4], uncompressed_size)?);
+        }
+        index += 1;
+    }
+    Ok(output)
+}
+
+fn u24(bytes: &[u8]) -> usize {
+    bytes[0] as usize | (bytes[1] as usize) << 8 | (bytes[2] as usize) << 16
+}
+
+fn invalid(reason: &str) -> AppError {
+    AppError::Internal(format!("Invalid xorb data: {}", reason))
+}
+
+fn decompress(
+    data: &[u8],
+    compression_type: u8,
+    uncompressed_size: usize,
+) -> Result<Vec<u8>, AppError> {
+    match compression_type {
+        0 if data.len() == uncompressed_size => Ok(data.to_vec()),
+        0 => Err(invalid("uncompressed chunk size mismatch")),
+        1 => lz4_frame(data, uncompressed_size),
+        2 => Ok(reverse_byte_grouping(&lz4_frame(data, uncompressed_size)?)),
+        3 => Ok(reverse_full_bitslice(&lz4_frame(data, uncompressed_size)?)),
+        other => Err(invalid(&format!("unknown compression type {}", other))),
+    }
+}
+
+fn lz4_frame(data: &[u8], uncompressed_size: usize) -> Result<Vec<u8>, AppError> {
+    let mut output = Vec::with_capacity(uncompressed_size);
+    lz4_flex::frame::FrameDecoder::new(data)
+        .read_to_end(&mut output)
+        .map_err(|e| invalid(&format!("LZ4 decompression failed: {}", e)))?;
+    if output.len() != uncompressed_size {
+        return Err(invalid("decompressed chunk size mismatch"));
+    }
+    Ok(output)
+}
+
+/// Undo byte grouping: byte `i` of each 4-byte group was moved to group `i`
+fn reverse_byte_grouping(data: &[u8]) -> Vec<u8> {
+    let n = data.len();
+    let (split, rem) = (n / 4, n % 4);
+    let mut offsets = [0; 4];
+    for group in 1..4 {
+        let size = split + usize::from(rem > group - 1);
+        offsets[group] = offsets[group - 1] + size;
+    }
+
+    let mut output = vec![0; n];
+    for (i, byte) in output.iter_mut().enumerate() {
+        *byte = data[offsets[i % 4] + i / 4];
+    }
+    output
+}
+
+/// Undo full bitslicing: bit `k` of the input holds bit `k / n` of byte `k % n`
+fn reverse_full_bitslice(data: &[u8]) -> Vec<u8> {
+    let n = data.len();
+    let mut output = vec![0u8; n];
+    for (in_byte, &value) in data.iter().enumerate() {
+        for in_bit in 0..8 {
+            let k = in_byte * 8 + in_bit;
+            let bit = (value >> in_bit) & 1;
+            output[k % n] |= bit << (k / n);
+        }
+    }
+    output
+}
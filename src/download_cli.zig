@@ -4,6 +4,18 @@
 const std = @import("std");
 const xet = @import("xet");
 
+/// Output format: human-readable text, or JSON lines for the proxy
+///
+/// With `--json` every message is one JSON object per line with a `type`:
+/// `file` (listing entry), `chunk` (manifest entry), `uploaded` (commit),
+/// `done` (worker job finished) on stdout, and `progress` (bytes written so
+/// far) and `error` (the command failed) on stderr. File contents are still
+/// written raw.
+const Format = enum { text, json };
+
+/// Reported by `--version`
+const version = "0.1.0";
+
 pub fn main(init: std.process.Init) !void {
     const allocator = init.gpa;
     const io = init.io;
@@ -17,32 +29,132 @@ pub fn main(init: std.process.Init) !void {
         try args.append(allocator, arg);
     }
 
-    // Usage: download_cli <repo_id> [filename_or_hash]
-    if (args.items.len < 2) {
+    const format: Format = if (args.items.len > 1 and std.mem.eql(u8, args.items[1], "--json")) .json else .text;
+    if (format == .json) _ = args.orderedRemove(1);
+
+    run(allocator, io, environ, args.items, format) catch |err| {
+        if (format == .text) return err;
+        // Reported as a protocol message instead of Zig's "error: <Name>"
+        reportError(io, err);
+        std.process.exit(1);
+    };
+}
+
+fn reportError(io: std.Io, err: anyerror) void {
+    var stderr_buffer: [128]u8 = undefined;
+    var stderr_writer = std.Io.File.stderr().writer(io, &stderr_buffer);
+    stderr_writer.interface.print("{{\"type\":\"error\",\"error\":\"{s}\"}}\n", .{@errorName(err)}) catch return;
+    stderr_writer.interface.flush() catch {};
+}
+
+fn run(
+    allocator: std.mem.Allocator,
+    io: std.Io,
+    environ: std.process.Environ,
+    args: []const []const u8,
+    format: Format,
+) !void {
+    // Usage: download_cli [--json] <repo_id> [filename_or_hash] [<start>-<end>]
+    //        download_cli upload <repo_id> <path_in_repo>
+    //        download_cli manifest <repo_id> <hash>
+    //        download_cli worker
+    //        download_cli --version
+    // HF_REPO_TYPE (default "model") and HF_REVISION (default "main",
+    // URL-encoded) select the repository type and revision, HF_ENDPOINT
+    // (default https://huggingface.co) the Hub. A hash download
+    // uses the repository's CAS token, so <repo_id> must grant access to it.
+    // An upload reads the file from stdin and commits it with the title in
+    // HF_COMMIT_MESSAGE. A manifest lists the chunk hashes and lengths of a
+    // file by hash. A worker serves download jobs from stdin until it
+    // is closed (see serveJobs).
+    if (args.len == 2 and std.mem.eql(u8, args[1], "--version")) {
+        // Lets the proxy's readiness probe check the binary runs
+        var stdout_buffer: [64]u8 = undefined;
+        var stdout_writer = std.Io.File.stdout().writer(io, &stdout_buffer);
+        try stdout_writer.interface.writeAll("xet-download " ++ version ++ "\n");
+        try stdout_writer.interface.flush();
+        return;
+    }
+    const endpoint_env = std.process.Environ.getAlloc(environ, allocator, "HF_ENDPOINT") catch null;
+    defer if (endpoint_env) |v| allocator.free(v);
+    if (endpoint_env) |endpoint| {
+        xet.model_download.hub_endpoint = std.mem.trimEnd(u8, endpoint, "/");
+    }
+    if (args.len == 2 and std.mem.eql(u8, args[1], "worker")) {
+        try serveJobs(allocator, io, format);
+        return;
+    }
+    const is_upload = args.len > 1 and std.mem.eql(u8, args[1], "upload");
+    const is_manifest = args.len > 1 and std.mem.eql(u8, args[1], "manifest");
+    if (args.len < 2 or ((is_upload or is_manifest) and args.len != 4)) {
         var stderr_buffer: [256]u8 = undefined;
         var stderr_writer = std.Io.File.stderr().writer(io, &stderr_buffer);
-        try stderr_writer.interface.writeAll("Usage: download_cli <repo_id> [filename_or_hash]\n");
+        try stderr_writer.interface.writeAll(
+            \\Usage: download_cli [--json] <repo_id> [filename_or_hash] [<start>-<end>]
+            \\       download_cli [--json] upload <repo_id> <path_in_repo>
+            \\       download_cli [--json] manifest <repo_id> <hash>
+            \\       download_cli [--json] worker
+            \\       download_cli --version
+            \\
+        );
         try stderr_writer.interface.flush();
         return error.InvalidArgs;
     }
 
-    const repo_id = args.items[1];
-    const file_or_hash = if (args.items.len > 2) args.items[2] else null;
+    const is_subcommand = is_upload or is_manifest;
+    const repo_id = if (is_subcommand) args[2] else args[1];
+    const file_or_hash = if (!is_subcommand and args.len > 2) args[2] else null;
+    // Optional inclusive byte range, e.g. "1048576-2097151"
+    const byte_range = if (!is_subcommand and args.len > 3) try parseByteRange(args[3]) else null;
 
     // Get HF token
     const hf_token = try std.process.Environ.getAlloc(environ, allocator, "HF_TOKEN");
     defer allocator.free(hf_token);
 
+    const repo_type_env = std.process.Environ.getAlloc(environ, allocator, "HF_REPO_TYPE") catch null;
+    defer if (repo_type_env) |v| allocator.free(v);
+    const revision_env = std.process.Environ.getAlloc(environ, allocator, "HF_REVISION") catch null;
+    defer if (revision_env) |v| allocator.free(v);
+    const repo = Repo{
+        .id = repo_id,
+        .repo_type = repo_type_env orelse "model",
+        .revision = revision_env orelse "main",
+    };
+
+    if (is_upload) {
+        try uploadFile(allocator, io, environ, repo, args[3], hf_token, format);
+        return;
+    }
+    if (is_manifest) {
+        try printManifest(allocator, io, environ, repo, args[3], hf_token, format);
+        return;
+    }
+
     // If file_or_hash looks like a hash (64 hex chars), download by hash
     if (file_or_hash) |foh| {
         if (foh.len == 64 and isHex(foh)) {
-            try downloadByHash(allocator, io, environ, foh, hf_token);
+            try downloadByHash(allocator, io, environ, repo, foh, hf_token, byte_range, format);
             return;
         }
     }
 
     // Otherwise, list files
-    try listFiles(allocator, io, environ, repo_id, hf_token, file_or_hash);
+    try listFiles(allocator, io, environ, repo, hf_token, file_or_hash, format);
+}
+
+/// Repository the CLI operates on
+const Repo = struct {
+    id: []const u8,
+    repo_type: []const u8,
+    revision: []const u8,
+};
+
+fn parseByteRange(s: []const u8) !xet.model_download.ByteRange {
+    const sep = std.mem.indexOfScalar(u8, s, '-') orelse return error.InvalidRange;
+    const start = std.fmt.parseInt(u64, s[0..sep], 10) catch return error.InvalidRange;
+    const end = std.fmt.parseInt(u64, s[sep + 1 ..], 10) catch return error.InvalidRange;
+    if (end < start) return error.InvalidRange;
+    return .{ .start = start, .end = end };
 }
 
 fn isHex(s: []const u8) bool {
@@ -56,17 +168,18 @@ fn listFiles(
     allocator: std.mem.Allocator,
     io: std.Io,
     environ: std.process.Environ,
-    repo_id: []const u8,
+    repo: Repo,
     hf_token: []const u8,
     filename: ?[]const u8,
+    format: Format,
 ) !void {
     var file_list = try xet.model_download.listFiles(
         allocator,
         io,
         environ,
-        repo_id,
-        "model",
-        "main",
+        repo.id,
+        repo.repo_type,
+        repo.revision,
         hf_token,
     );
     defer file_list.deinit();
@@ -79,28 +192,37 @@ fn listFiles(
     // If filename specified, find and download it
     if (filename) |name| {
         const file_info = file_list.findFile(name) orelse {
-            try stdout.writeAll("File not found\n");
+            if (format == .text) try stdout.writeAll("File not found\n");
             return error.FileNotFound;
         };
 
         if (file_info.xet_hash == null) {
-            try stdout.writeAll("File is not XET-enabled\n");
+            if (format == .text) try stdout.writeAll("File is not XET-enabled\n");
             return error.NotXetFile;
         }
 
-        try downloadByHash(allocator, io, environ, file_info.xet_hash.?, hf_token);
+        try downloadByHash(allocator, io, environ, repo, file_info.xet_hash.?, hf_token, null, format);
         return;
     }
 
     // Otherwise just list files with XET hashes
     for (file_list.files) |file| {
-        if (file.xet_hash) |hash| {
-            const size_mb = @as(f64, @floatFromInt(file.size)) / (1024.0 * 1024.0);
-            try stdout.print("{s} - {d:.2} MB - xetHash: {s}\n", .{
+        const hash = file.xet_hash orelse continue;
+        switch (format) {
+            .text => try stdout.print("{s} - {d} bytes - xetHash: {s}\n", .{
                 file.path,
-                size_mb,
+                file.size,
                 hash,
-            });
+            }),
+            .json => {
+                const path = try xet.model_upload.jsonString(allocator, file.path);
+                defer allocator.free(path);
+                try stdout.print("{{\"type\":\"file\",\"path\":{s},\"size\":{d},\"xet_hash\":\"{s}\"}}\n", .{
+                    path,
+                    file.size,
+                    hash,
+                });
+            },
         }
     }
     try stdout.flush();
@@ -110,17 +232,24 @@ fn downloadByHash(
     allocator: std.mem.Allocator,
     io: std.Io,
     environ: std.process.Environ,
+    repo: Repo,
     hash_hex: []const u8,
     hf_token: []const u8,
+    byte_range: ?xet.model_download.ByteRange,
+    format: Format,
 ) !void {
     _ = try xet.cas_client.apiHexToHash(hash_hex);
 
+    var reporter = ProgressReporter{ .io = io };
+
     const config = xet.model_download.DownloadConfig{
-        .repo_id = "jedisct1/MiMo-7B-RL-GGUF", // Temporary repo for token
-        .repo_type = "model",
-        .revision = "main",
+        .repo_id = repo.id,
+        .repo_type = repo.repo_type,
+        .revision = repo.revision,
         .file_hash_hex = hash_hex,
         .hf_token = hf_token,
+        .byte_range = byte_range,
+        .progress = if (format == .json) reporter.progress() else null,
     };
 
     // Stream to stdout
@@ -136,3 +265,248 @@ fn downloadByHash(
         &stdout_writer.interface,
     );
 }
+
+/// Writes `progress` messages to stderr
+const ProgressReporter = struct {
+    io: std.Io,
+
+    fn progress(self: *ProgressReporter) xet.reconstruction.Progress {
+        return .{ .context = self, .report = report };
+    }
+
+    fn report(context: *anyopaque, bytes: u64) void {
+        const self: *ProgressReporter = @ptrCast(@alignCast(context));
+        var stderr_buffer: [64]u8 = undefined;
+        var stderr_writer = std.Io.File.stderr().writer(self.io, &stderr_buffer);
+        stderr_writer.interface.print("{{\"type\":\"progress\",\"bytes\":{d}}}\n", .{bytes}) catch return;
+        stderr_writer.interface.flush() catch {};
+    }
+};
+
+fn printManifest(
+    allocator: std.mem.Allocator,
+    io: std.Io,
+    environ: std.process.Environ,
+    repo: Repo,
+    hash_hex: []const u8,
+    hf_token: []const u8,
+    format: Format,
+) !void {
+    const config = xet.model_download.DownloadConfig{
+        .repo_id = repo.id,
+        .repo_type = repo.repo_type,
+        .revision = repo.revision,
+        .file_hash_hex = hash_hex,
+        .hf_token = hf_token,
+    };
+
+    var stdout_buffer: [4096]u8 = undefined;
+    var stdout_writer = std.Io.File.stdout().writer(io, &stdout_buffer);
+    const stdout = &stdout_writer.interface;
+    switch (format) {
+        .text => try xet.model_download.writeChunkManifest(allocator, io, environ, config, stdout, "{s} {d}\n"),
+        .json => try xet.model_download.writeChunkManifest(
+            allocator,
+            io,
+            environ,
+            config,
+            stdout,
+            "{{\"type\":\"chunk\",\"hash\":\"{s}\",\"length\":{d}}}\n",
+        ),
+    }
+    try stdout.flush();
+}
+
+/// Serve download jobs, one per line on stdin, until stdin is closed
+///
+/// A job is `<fifo>\t<repo_type>\t<repo_id>\t<revision>\t<hash>\t<start-end|->\t<hub>\t<hf_token>`,
+/// `<hub>` being the Hub endpoint to request the CAS token from.
+/// The file is written into the named pipe `<fifo>`, then the job is answered
+/// with a line on stdout: `ok` or `error: <Name>` (`done` or `error` messages
+/// with --json). The proxy replaces a worker after a failed job, so errors are
+/// not retried here.
+fn serveJobs(allocator: std.mem.Allocator, io: std.Io, format: Format) !void {
+    var tokens = TokenCache{ .allocator = allocator };
+    defer tokens.deinit();
+
+    var stdin_buffer: [4096]u8 = undefined;
+    var stdin_reader = std.Io.File.Reader.init(std.Io.File.stdin(), io, &stdin_buffer);
+    var stdout_buffer: [256]u8 = undefined;
+    var stdout_writer = std.Io.File.stdout().writer(io, &stdout_buffer);
+    const stdout = &stdout_writer.interface;
+
+    while (try stdin_reader.interface.takeDelimiter('\n')) |line| {
+        if (serveJob(allocator, io, &tokens, line)) {
+            try stdout.writeAll(switch (format) {
+                .text => "ok\n",
+                .json => "{\"type\":\"done\"}\n",
+            });
+        } else |err| switch (format) {
+            .text => try stdout.print("error: {s}\n", .{@errorName(err)}),
+            .json => try stdout.print("{{\"type\":\"error\",\"error\":\"{s}\"}}\n", .{@errorName(err)}),
+        }
+        try stdout.flush();
+    }
+}
+
+fn serveJob(allocator: std.mem.Allocator, io: std.Io, tokens: *TokenCache, line: []const u8) !void {
+    var fields = std.mem.splitScalar(u8, line, '\t');
+    const fifo_path = fields.next() orelse return error.InvalidJob;
+    const repo = Repo{
+        .repo_type = fields.next() orelse return error.InvalidJob,
+        .id = fields.next() orelse return error.InvalidJob,
+        .revision = fields.next() orelse return error.InvalidJob,
+    };
+    const hash_hex = fields.next() orelse return error.InvalidJob;
+    const range_arg = fields.next() orelse return error.InvalidJob;
+    const endpoint = fields.next() orelse return error.InvalidJob;
+    const hf_token = fields.next() orelse return error.InvalidJob;
+    if (fields.next() != null) return error.InvalidJob;
+
+    // The job's Hub, for its token request
+    const worker_endpoint = xet.model_download.hub_endpoint;
+    xet.model_download.hub_endpoint = endpoint;
+    defer xet.model_download.hub_endpoint = worker_endpoint;
+    const byte_range = if (std.mem.eql(u8, range_arg, "-")) null else try parseByteRange(range_arg);
+
+    // Token failures are answered before anything is written
+    const xet_token = try tokens.get(io, repo, hf_token);
+
+    var output = try std.Io.Dir.cwd().openFile(io, fifo_path, .{ .mode = .write_only });
+    defer output.close(io);
+    var output_buffer: [8192]u8 = undefined;
+    var output_writer = output.writer(io, &output_buffer);
+
+    try xet.model_download.downloadWithToken(
+        allocator,
+        io,
+        xet_token,
+        hash_hex,
+        byte_range,
+        null,
+        &output_writer.interface,
+    );
+    try output_writer.interface.flush();
+}
+
+/// CAS tokens of a worker, reused until shortly before they expire
+const TokenCache = struct {
+    allocator: std.mem.Allocator,
+    /// Keyed by Hub, repository, revision and HF token
+    entries: std.StringHashMapUnmanaged(xet.model_download.XetTokenResult) = .empty,
+
+    /// Seconds before expiry at which a token is no longer used
+    const expiry_margin = 60;
+    /// Tokens kept before the cache starts over
+    const max_entries = 64;
+
+    fn deinit(self: *TokenCache) void {
+        self.clear();
+        self.entries.deinit(self.allocator);
+    }
+
+    fn clear(self: *TokenCache) void {
+        var it = self.entries.iterator();
+        while (it.next()) |entry| {
+            self.allocator.free(entry.key_ptr.*);
+            entry.value_ptr.deinit();
+        }
+        self.entries.clearRetainingCapacity();
+    }
+
+    /// Token for a repository, requested only if none is cached or it is about to expire
+    fn get(
+        self: *TokenCache,
+        io: std.Io,
+        repo: Repo,
+        hf_token: []const u8,
+    ) !*const xet.model_download.XetTokenResult {
+        const key = try std.fmt.allocPrint(self.allocator, "{s}\t{s}\t{s}\t{s}\t{s}", .{
+            xet.model_download.hub_endpoint,
+            repo.repo_type,
+            repo.id,
+            repo.revision,
+            hf_token,
+        });
+        const now = std.Io.Clock.real.now(io).toSeconds();
+        if (self.entries.getEntry(key)) |entry| {
+            if (entry.value_ptr.exp - expiry_margin > now) {
+                self.allocator.free(key);
+                return entry.value_ptr;
+            }
+            const stale_key = entry.key_ptr.*;
+            entry.value_ptr.deinit();
+            self.entries.removeByPtr(entry.key_ptr);
+            self.allocator.free(stale_key);
+        }
+        errdefer self.allocator.free(key);
+
+        var token = try xet.model_download.requestXetToken(
+            self.allocator,
+            io,
+            repo.id,
+            repo.repo_type,
+            repo.revision,
+            .read,
+            hf_token,
+        );
+        errdefer token.deinit();
+        if (self.entries.count() >= max_entries) self.clear();
+        try self.entries.put(self.allocator, key, token);
+        return self.entries.getPtr(key).?;
+    }
+};
+
+fn uploadFile(
+    allocator: std.mem.Allocator,
+    io: std.Io,
+    environ: std.process.Environ,
+    repo: Repo,
+    path_in_repo: []const u8,
+    hf_token: []const u8,
+    format: Format,
+) !void {
+    const message_env = std.process.Environ.getAlloc(environ, allocator, "HF_COMMIT_MESSAGE") catch null;
+    defer if (message_env) |v| allocator.free(v);
+    const default_message = try std.fmt.allocPrint(allocator, "Upload {s}", .{path_in_repo});
+    defer allocator.free(default_message);
+
+    const config = xet.model_upload.UploadConfig{
+        .repo_id = repo.id,
+        .repo_type = repo.repo_type,
+        .revision = repo.revision,
+        .path_in_repo = path_in_repo,
+        .hf_token = hf_token,
+        .commit_message = message_env orelse default_message,
+    };
+
+    var stdin_buffer: [64 * 1024]u8 = undefined;
+    var stdin_reader = std.Io.File.Reader.init(std.Io.File.stdin(), io, &stdin_buffer);
+
+    var result = try xet.model_upload.uploadFromReader(allocator, io, config, &stdin_reader.interface);
+    defer result.deinit();
+
+    const xet_hash = try xet.cas_client.hashToApiHex(result.file_hash, allocator);
+    defer allocator.free(xet_hash);
+    const sha256 = std.fmt.bytesToHex(result.sha256, .lower);
+
+    var stdout_buffer: [512]u8 = undefined;
+    var stdout_writer = std.Io.File.stdout().writer(io, &stdout_buffer);
+    const stdout = &stdout_writer.interface;
+    switch (format) {
+        .text => try stdout.print("Committed {s} in {s} ({d} bytes, xetHash: {s}, sha256: {s})\n", .{
+            path_in_repo,
+            result.commit_oid,
+            result.size,
+            xet_hash,
+            &sha256,
+        }),
+        .json => try stdout.print("{{\"type\":\"uploaded\",\"commit_oid\":\"{s}\",\"xet_hash\":\"{s}\",\"sha256\":\"{s}\",\"size\":{d}}}\n", .{
+            result.commit_oid,
+            xet_hash,
+            &sha256,
+            result.size,
+        }),
+    }
+    try stdout.flush();
+}
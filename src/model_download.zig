@@ -3,6 +3,14 @@ const Allocator = std.mem.Allocator;
 const cas_client = @import("cas_client.zig");
 const reconstruction = @import("reconstruction.zig");
 
+/// The public Hugging Face Hub
+pub const default_hub_endpoint = "https://huggingface.co";
+
+/// Base URL of the Hub API requests go to, without a trailing slash; point
+/// it at a mirror or an internal hub before making requests (the CLI sets
+/// it from HF_ENDPOINT)
+pub var hub_endpoint: []const u8 = default_hub_endpoint;
+
 const OwnedToken = struct {
     value: []const u8,
     allocator: ?Allocator,
@@ -22,6 +30,12 @@ const OwnedToken = struct {
     }
 };
 
+/// Byte range of a file, both ends inclusive (as in an HTTP Range header)
+pub const ByteRange = struct {
+    start: u64,
+    end: u64,
+};
+
 /// Configuration for downloading a model from Hugging Face
 pub const DownloadConfig = struct {
     /// Repository ID (e.g., "jedisct1/MiMo-7B-RL-GGUF")
@@ -34,6 +48,10 @@ pub const DownloadConfig = struct {
     file_hash_hex: []const u8,
     /// Hugging Face API token (if null, reads from HF_TOKEN environment variable)
     hf_token: ?[]const u8 = null,
+    /// Only download this part of the file (if null, downloads the whole file)
+    byte_range: ?ByteRange = null,
+    /// Receives the number of bytes written as the download proceeds
+    progress: ?reconstruction.Progress = null,
 };
 
 /// Information about a file in a HuggingFace repository
@@ -92,8 +110,8 @@ pub fn listFiles(
 
     const tree_url = try std.fmt.allocPrint(
         allocator,
-        "https://huggingface.co/api/{s}s/{s}/tree/{s}",
-        .{ repo_type, repo_id, revision },
+        "{s}/api/{s}s/{s}/tree/{s}",
+        .{ hub_endpoint, repo_type, repo_id, revision },
     );
     defer allocator.free(tree_url);
 
@@ -116,6 +134,9 @@ pub fn listFiles(
     try req.sendBodiless();
     var response = try req.receiveHead(&.{});
 
+    if (response.head.status == .too_many_requests) {
+        return error.TooManyRequests;
+    }
     if (response.head.status != .ok) {
         return error.ApiRequestFailed;
     }
@@ -185,8 +206,8 @@ pub fn getFileXetHash(
 
     const resolve_url = try std.fmt.allocPrint(
         allocator,
-        "https://huggingface.co/{s}/resolve/{s}/{s}",
-        .{ repo_id, revision, filepath },
+        "{s}/{s}/resolve/{s}/{s}",
+        .{ hub_endpoint, repo_id, revision, filepath },
     );
     defer allocator.free(resolve_url);
 
@@ -218,7 +239,7 @@ pub fn getFileXetHash(
 }
 
 /// Result from XET token exchange with Hugging Face Hub
-const XetTokenResult = struct {
+pub const XetTokenResult = struct {
     access_token: []const u8,
     cas_url: []const u8,
     exp: i64,
@@ -230,18 +251,24 @@ const XetTokenResult = struct {
     }
 };
 
+/// Access requested with an XET token
+pub const XetAccess = enum { read, write };
+
 /// Request XET access token from Hugging Face Hub
-fn requestXetToken(
+pub fn requestXetToken(
     allocator: Allocator,
     io: std.Io,
-    config: DownloadConfig,
+    repo_id: []const u8,
+    repo_type: []const u8,
+    revision: []const u8,
+    access: XetAccess,
     hf_token: []const u8,
 ) !XetTokenResult {
     // Build token URL
     const token_url = try std.fmt.allocPrint(
         allocator,
-        "https://huggingface.co/api/{s}s/{s}/xet-read-token/{s}",
-        .{ config.repo_type, config.repo_id, config.revision },
+        "{s}/api/{s}s/{s}/xet-{s}-token/{s}",
+        .{ hub_endpoint, repo_type, repo_id, @tagName(access), revision },
     );
     defer allocator.free(token_url);
 
@@ -267,8 +294,13 @@ fn requestXetToken(
     try req.sendBodiless();
     var response = try req.receiveHead(&.{});
 
-    if (response.head.status != .ok) {
-        return error.AuthenticationFailed;
+    switch (response.head.status) {
+        .ok => {},
+        .too_many_requests => return error.TooManyRequests,
+        // Gated or restricted repository the token may not read
+        .forbidden => return error.RepoAccessDenied,
+        .not_found => return error.RepoNotFound,
+        else => return error.AuthenticationFailed,
     }
 
     // Parse JSON response
@@ -367,11 +399,35 @@ pub fn downloadModelToWriter(
     const hf_token = try OwnedToken.init(allocator, environ, config.hf_token);
     defer hf_token.deinit();
 
-    var xet_token = try requestXetToken(allocator, io, config, hf_token.value);
+    var xet_token = try requestXetToken(
+        allocator,
+        io,
+        config.repo_id,
+        config.repo_type,
+        config.revision,
+        .read,
+        hf_token.value,
+    );
     defer xet_token.deinit();
 
+    try downloadWithToken(allocator, io, &xet_token, config.file_hash_hex, config.byte_range, config.progress, writer);
+}
+
+/// Download a file by XET hash with a token obtained beforehand
+///
+/// Lets long-lived callers (such as the CLI worker mode) reuse a token from
+/// requestXetToken() for several downloads until it expires.
+pub fn downloadWithToken(
+    allocator: Allocator,
+    io: std.Io,
+    xet_token: *const XetTokenResult,
+    file_hash_hex: []const u8,
+    byte_range: ?ByteRange,
+    progress: ?reconstruction.Progress,
+    writer: *std.Io.Writer,
+) !void {
     // Convert file hash from API hex format to binary
-    const file_hash = try cas_client.apiHexToHash(config.file_hash_hex);
+    const file_hash = try cas_client.apiHexToHash(file_hash_hex);
 
     // Initialize CAS client
     var cas = try cas_client.CasClient.init(
@@ -384,7 +440,49 @@ pub fn downloadModelToWriter(
 
     // Reconstruct file using stream API
     var reconstructor = reconstruction.FileReconstructor.init(allocator, &cas);
-    try reconstructor.reconstructStream(file_hash, writer);
+    reconstructor.progress = progress;
+    if (byte_range) |range| {
+        try reconstructor.reconstructRangeStream(file_hash, range.start, range.end + 1, writer);
+    } else {
+        try reconstructor.reconstructStream(file_hash, writer);
+    }
+}
+
+/// Write the chunk manifest of a file (see FileReconstructor.writeChunkManifest)
+pub fn writeChunkManifest(
+    allocator: Allocator,
+    io: std.Io,
+    environ: std.process.Environ,
+    config: DownloadConfig,
+    writer: *std.Io.Writer,
+    comptime line_format: []const u8,
+) !void {
+    const hf_token = try OwnedToken.init(allocator, environ, config.hf_token);
+    defer hf_token.deinit();
+
+    var xet_token = try requestXetToken(
+        allocator,
+        io,
+        config.repo_id,
+        config.repo_type,
+        config.revision,
+        .read,
+        hf_token.value,
+    );
+    defer xet_token.deinit();
+
+    const file_hash = try cas_client.apiHexToHash(config.file_hash_hex);
+
+    var cas = try cas_client.CasClient.init(
+        allocator,
+        io,
+        xet_token.cas_url,
+        xet_token.access_token,
+    );
+    defer cas.deinit();
+
+    var reconstructor = reconstruction.FileReconstructor.init(allocator, &cas);
+    try reconstructor.writeChunkManifest(file_hash, writer, line_format);
 }
 
 /// Download a model from Hugging Face and write it to a writer using parallel fetching
@@ -409,7 +507,15 @@ pub fn downloadModelToWriterParallel(
     const hf_token = try OwnedToken.init(allocator, environ, config.hf_token);
     defer hf_token.deinit();
 
-    var xet_token = try requestXetToken(allocator, io, config, hf_token.value);
+    var xet_token = try requestXetToken(
+        allocator,
+        io,
+        config.repo_id,
+        config.repo_type,
+        config.revision,
+        .read,
+        hf_token.value,
+    );
     defer xet_token.deinit();
 
     const file_hash = try cas_client.apiHexToHash(config.file_hash_hex);
@@ -487,7 +593,15 @@ pub fn downloadModel(
     const hf_token = try OwnedToken.init(allocator, environ, config.hf_token);
     defer hf_token.deinit();
 
-    var xet_token = try requestXetToken(allocator, io, config, hf_token.value);
+    var xet_token = try requestXetToken(
+        allocator,
+        io,
+        config.repo_id,
+        config.repo_type,
+        config.revision,
+        .read,
+        hf_token.value,
+    );
     defer xet_token.deinit();
 
     // Convert file hash from API hex format to binary
@@ -0,0 +1,437 @@
+//! High-level API for uploading files to Hugging Face through XET
+//!
+//! The content is read as a stream and split into content-defined chunks,
+//! which are packed into xorbs and uploaded to CAS as each one fills, so at
+//! most one xorb is held in memory. Chunks repeated within the file are
+//! stored once. A shard describing the file is then registered with CAS and
+//! the file is committed to the repository as an LFS pointer (SHA-256 and
+//! size), which the Hub resolves to the XET file registered by the shard.
+
+const std = @import("std");
+const Allocator = std.mem.Allocator;
+const cas_client = @import("cas_client.zig");
+const chunking = @import("chunking.zig");
+const constants = @import("constants.zig");
+const hashing = @import("hashing.zig");
+const model_download = @import("model_download.zig");
+const shard = @import("shard.zig");
+const xorb = @import("xorb.zig");
+
+/// Compression applied to uploaded xorbs
+const upload_compression: constants.CompressionType = .LZ4;
+
+/// Configuration for uploading a file to Hugging Face
+pub const UploadConfig = struct {
+    /// Repository ID (e.g., "jedisct1/MiMo-7B-RL-GGUF")
+    repo_id: []const u8,
+    /// Repository type ("model", "dataset", or "space")
+    repo_type: []const u8 = "model",
+    /// Branch to commit to (URL-encoded)
+    revision: []const u8 = "main",
+    /// Path of the file in the repository
+    path_in_repo: []const u8,
+    /// Hugging Face API token with write access to the repository
+    hf_token: []const u8,
+    /// Commit title
+    commit_message: []const u8,
+};
+
+/// Result of an upload
+pub const UploadResult = struct {
+    /// Commit created on the Hub
+    commit_oid: []const u8,
+    /// XET hash of the uploaded file
+    file_hash: hashing.Hash,
+    /// SHA-256 of the uploaded file
+    sha256: [32]u8,
+    /// Size in bytes
+    size: u64,
+    allocator: Allocator,
+
+    pub fn deinit(self: *UploadResult) void {
+        self.allocator.free(self.commit_oid);
+    }
+};
+
+/// Where a chunk is stored: its xorb (by upload order) and index within it
+const ChunkLocation = struct {
+    xorb_index: u32,
+    chunk_index: u32,
+};
+
+/// A run of consecutive chunks of one xorb within the file
+const Term = struct {
+    xorb_index: u32,
+    chunk_start: u32,
+    chunk_end: u32,
+    size: u32,
+};
+
+/// Streaming chunker that uploads xorbs as they fill
+const Uploader = struct {
+    allocator: Allocator,
+    cas: *cas_client.CasClient,
+    builder: xorb.XorbBuilder,
+    builder_size: usize,
+    chunk_copies: std.ArrayList([]u8),
+    chunk_dedup: std.AutoHashMap(hashing.Hash, ChunkLocation),
+    merkle_nodes: std.ArrayList(hashing.MerkleNode),
+    terms: std.ArrayList(Term),
+    xorb_hashes: std.ArrayList(hashing.Hash),
+    /// Chunk hashes of each uploaded xorb, for verification entries
+    xorb_chunk_hashes: std.ArrayList([]hashing.Hash),
+    shard_builder: shard.ShardBuilder,
+
+    fn init(allocator: Allocator, cas: *cas_client.CasClient) Uploader {
+        return .{
+            .allocator = allocator,
+            .cas = cas,
+            .builder = xorb.XorbBuilder.init(allocator),
+            .builder_size = 0,
+            .chunk_copies = .empty,
+            .chunk_dedup = std.AutoHashMap(hashing.Hash, ChunkLocation).init(allocator),
+            .merkle_nodes = .empty,
+            .terms = .empty,
+            .xorb_hashes = .empty,
+            .xorb_chunk_hashes = .empty,
+            .shard_builder = shard.ShardBuilder.init(allocator),
+        };
+    }
+
+    fn deinit(self: *Uploader) void {
+        self.builder.deinit();
+        self.freeChunkCopies();
+        self.chunk_copies.deinit(self.allocator);
+        self.chunk_dedup.deinit();
+        self.merkle_nodes.deinit(self.allocator);
+        self.terms.deinit(self.allocator);
+        self.xorb_hashes.deinit(self.allocator);
+        for (self.xorb_chunk_hashes.items) |hashes| {
+            self.allocator.free(hashes);
+        }
+        self.xorb_chunk_hashes.deinit(self.allocator);
+        self.shard_builder.deinit();
+    }
+
+    fn freeChunkCopies(self: *Uploader) void {
+        for (self.chunk_copies.items) |chunk_copy| {
+            self.allocator.free(chunk_copy);
+        }
+        self.chunk_copies.clearRetainingCapacity();
+    }
+
+    fn addChunk(self: *Uploader, chunk_data: []const u8) !void {
+        const chunk_hash = hashing.computeDataHash(chunk_data);
+        try self.merkle_nodes.append(self.allocator, .{
+            .hash = chunk_hash,
+            .size = @intCast(chunk_data.len),
+        });
+
+        const location = self.chunk_dedup.get(chunk_hash) orelse blk: {
+            const estimated_chunk_size = constants.XorbChunkHeaderSize + chunk_data.len;
+            if (self.builder_size + estimated_chunk_size > constants.MaxXorbSize) {
+                try self.flushXorb();
+            }
+
+            const chunk_copy = try self.allocator.dupe(u8, chunk_data);
+            errdefer self.allocator.free(chunk_copy);
+            try self.chunk_copies.append(self.allocator, chunk_copy);
+            _ = try self.builder.addChunk(chunk_copy);
+            self.builder_size += estimated_chunk_size;
+
+            const location = ChunkLocation{
+                .xorb_index = @intCast(self.xorb_hashes.items.len),
+                .chunk_index = @intCast(self.builder.chunks.items.len - 1),
+            };
+            try self.chunk_dedup.put(chunk_hash, location);
+            break :blk location;
+        };
+
+        if (self.terms.items.len > 0) {
+            const last = &self.terms.items[self.terms.items.len - 1];
+            if (last.xorb_index == location.xorb_index and last.chunk_end == location.chunk_index) {
+                last.chunk_end += 1;
+                last.size += @intCast(chunk_data.len);
+                return;
+            }
+        }
+        try self.terms.append(self.allocator, .{
+            .xorb_index = location.xorb_index,
+            .chunk_start = location.chunk_index,
+            .chunk_end = location.chunk_index + 1,
+            .size = @intCast(chunk_data.len),
+        });
+    }
+
+    /// Upload the xorb being built and record it in the shard
+    fn flushXorb(self: *Uploader) !void {
+        const chunks = self.builder.chunks.items;
+        if (chunks.len == 0) return;
+
+        const serialized = try self.builder.serialize(upload_compression);
+        defer self.allocator.free(serialized);
+        const xorb_hash = try self.builder.computeHash();
+        _ = try self.cas.uploadXorb(xorb_hash, serialized);
+
+        const entries = try self.allocator.alloc(shard.CASChunkSequenceEntry, chunks.len);
+        defer self.allocator.free(entries);
+        const chunk_hashes = try self.allocator.alloc(hashing.Hash, chunks.len);
+        errdefer self.allocator.free(chunk_hashes);
+
+        var raw_offset: u32 = 0;
+        for (chunks, 0..) |chunk, i| {
+            entries[i] = .{
+                .chunk_hash = chunk.hash,
+                .byte_range_start = raw_offset,
+                .unpacked_segment_size = @intCast(chunk.data.len),
+                .reserved = @splat(0),
+            };
+            chunk_hashes[i] = chunk.hash;
+            raw_offset += @intCast(chunk.data.len);
+        }
+        try self.shard_builder.addCASInfo(xorb_hash, entries, raw_offset, @intCast(serialized.len));
+
+        try self.xorb_hashes.append(self.allocator, xorb_hash);
+        try self.xorb_chunk_hashes.append(self.allocator, chunk_hashes);
+
+        self.builder.deinit();
+        self.builder = xorb.XorbBuilder.init(self.allocator);
+        self.builder_size = 0;
+        self.freeChunkCopies();
+    }
+
+    /// Upload the last xorb and the shard; returns the file hash
+    fn finish(self: *Uploader, sha256: [32]u8) !hashing.Hash {
+        try self.flushXorb();
+
+        const merkle_root = try hashing.buildMerkleTree(self.allocator, self.merkle_nodes.items);
+        const file_hash = hashing.computeFileHash(merkle_root);
+
+        const entries = try self.allocator.alloc(shard.FileDataSequenceEntry, self.terms.items.len);
+        defer self.allocator.free(entries);
+        const verification = try self.allocator.alloc(shard.FileVerificationEntry, self.terms.items.len);
+        defer self.allocator.free(verification);
+
+        for (self.terms.items, 0..) |term, i| {
+            entries[i] = .{
+                .xorb_hash = self.xorb_hashes.items[term.xorb_index],
+                .cas_flags = 0,
+                .unpacked_segment_size = term.size,
+                .chunk_index_start = term.chunk_start,
+                .chunk_index_end = term.chunk_end,
+            };
+            // The range hash covers the chunk hashes of the term
+            const range = self.xorb_chunk_hashes.items[term.xorb_index][term.chunk_start..term.chunk_end];
+            verification[i] = .{
+                .range_hash = hashing.computeVerificationHash(std.mem.sliceAsBytes(range)),
+                .reserved = @splat(0),
+            };
+        }
+        try self.shard_builder.addVerifiedFileInfo(file_hash, entries, verification, sha256);
+
+        const shard_data = try self.shard_builder.serialize();
+        defer self.allocator.free(shard_data);
+        _ = try self.cas.uploadShard(shard_data);
+
+        return file_hash;
+    }
+};
+
+/// Upload the content of `reader` to the repository and commit it
+///
+/// This function handles the complete upload flow:
+/// 1. Requests an XET write token and CAS URL from the Hub
+/// 2. Chunks the content and uploads its xorbs to CAS
+/// 3. Registers the file with CAS by uploading a shard
+/// 4. Commits the file to the repository
+pub fn uploadFromReader(
+    allocator: Allocator,
+    io: std.Io,
+    config: UploadConfig,
+    reader: *std.Io.Reader,
+) !UploadResult {
+    var xet_token = try model_download.requestXetToken(
+        allocator,
+        io,
+        config.repo_id,
+        config.repo_type,
+        config.revision,
+        .write,
+        config.hf_token,
+    );
+    defer xet_token.deinit();
+
+    var cas = try cas_client.CasClient.init(allocator, io, xet_token.cas_url, xet_token.access_token);
+    defer cas.deinit();
+
+    var uploader = Uploader.init(allocator, &cas);
+    defer uploader.deinit();
+
+    var sha256 = std.crypto.hash.sha2.Sha256.init(.{});
+    var chunker = chunking.Chunker.init();
+    var size: u64 = 0;
+
+    const read_buffer = try allocator.alloc(u8, 1024 * 1024);
+    defer allocator.free(read_buffer);
+
+    var accumulated: std.ArrayList(u8) = .empty;
+    defer accumulated.deinit(allocator);
+    var local_offset: usize = 0;
+
+    while (true) {
+        const bytes_read = reader.readSliceShort(read_buffer) catch |err| {
+            if (err == error.EndOfStream) break;
+            return err;
+        };
+        if (bytes_read == 0) break;
+
+        size += bytes_read;
+        sha256.update(read_buffer[0..bytes_read]);
+        try accumulated.appendSlice(allocator, read_buffer[0..bytes_read]);
+
+        while (chunker.findNextChunk(accumulated.items[local_offset..])) |boundary| {
+            try uploader.addChunk(accumulated.items[boundary.start..boundary.end]);
+            local_offset = boundary.end;
+        }
+
+        // Drop consumed bytes; boundaries are relative to the buffer start
+        if (local_offset > 0 and accumulated.items.len > 2 * constants.MaxChunkSize) {
+            const remaining = accumulated.items.len - local_offset;
+            if (remaining > 0) {
+                std.mem.copyForwards(u8, accumulated.items[0..remaining], accumulated.items[local_offset..]);
+            }
+            accumulated.shrinkRetainingCapacity(remaining);
+            chunker.position -= local_offset;
+            chunker.chunk_start -= local_offset;
+            local_offset = 0;
+        }
+    }
+
+    if (accumulated.items.len > local_offset) {
+        try uploader.addChunk(accumulated.items[local_offset..]);
+    }
+    if (size == 0) return error.EmptyUpload;
+
+    var sha256_hash: [32]u8 = undefined;
+    sha256.final(&sha256_hash);
+
+    const file_hash = try uploader.finish(sha256_hash);
+    const commit_oid = try commitFile(allocator, io, config, sha256_hash, size);
+
+    return .{
+        .commit_oid = commit_oid,
+        .file_hash = file_hash,
+        .sha256 = sha256_hash,
+        .size = size,
+        .allocator = allocator,
+    };
+}
+
+/// Commit the uploaded file as an LFS pointer; returns the commit ID
+fn commitFile(
+    allocator: Allocator,
+    io: std.Io,
+    config: UploadConfig,
+    sha256_hash: [32]u8,
+    size: u64,
+) ![]const u8 {
+    const commit_url = try std.fmt.allocPrint(
+        allocator,
+        "{s}/api/{s}s/{s}/commit/{s}",
+        .{ model_download.hub_endpoint, config.repo_type, config.repo_id, config.revision },
+    );
+    defer allocator.free(commit_url);
+
+    const summary = try jsonString(allocator, config.commit_message);
+    defer allocator.free(summary);
+    const path = try jsonString(allocator, config.path_in_repo);
+    defer allocator.free(path);
+    const oid = std.fmt.bytesToHex(sha256_hash, .lower);
+
+    // One JSON object per line: the commit header, then the operations
+    const body = try std.fmt.allocPrint(
+        allocator,
+        "{{\"key\":\"header\",\"value\":{{\"summary\":{s},\"description\":\"\"}}}}\n" ++
+            "{{\"key\":\"lfsFile\",\"value\":{{\"path\":{s},\"algo\":\"sha256\",\"oid\":\"{s}\",\"size\":{d}}}}}\n",
+        .{ summary, path, &oid, size },
+    );
+    defer allocator.free(body);
+
+    var http_client = std.http.Client{ .allocator = allocator, .io = io };
+    defer http_client.deinit();
+
+    const auth_header = try std.fmt.allocPrint(allocator, "Bearer {s}", .{config.hf_token});
+    defer allocator.free(auth_header);
+
+    const extra_headers = [_]std.http.Header{
+        .{ .name = "Authorization", .value = auth_header },
+    };
+
+    const uri = try std.Uri.parse(commit_url);
+    var req = try http_client.request(.POST, uri, .{
+        .extra_headers = &extra_headers,
+        .headers = .{
+            .content_type = .{ .override = "application/x-ndjson" },
+        },
+    });
+    defer req.deinit();
+
+    req.transfer_encoding = .{ .content_length = body.len };
+    var req_body = try req.sendBodyUnflushed(&.{});
+    try req_body.writer.writeAll(body);
+    try req_body.end();
+    try req.connection.?.flush();
+    var response = try req.receiveHead(&.{});
+
+    switch (response.head.status) {
+        .ok => {},
+        .too_many_requests => return error.TooManyRequests,
+        .unauthorized => return error.AuthenticationFailed,
+        .forbidden => return error.RepoAccessDenied,
+        .not_found => return error.RepoNotFound,
+        // The branch moved or the path conflicts with an existing entry
+        .conflict, .precondition_failed => return error.CommitConflict,
+        else => return error.CommitFailed,
+    }
+
+    var reader = response.reader(&.{});
+    const response_body = try reader.allocRemaining(allocator, @enumFromInt(64 * 1024));
+    defer allocator.free(response_body);
+
+    const parsed = try std.json.parseFromSlice(
+        std.json.Value,
+        allocator,
+        response_body,
+        .{},
+    );
+    defer parsed.deinit();
+
+    const commit_oid = parsed.value.object.get("commitOid") orelse return error.CommitFailed;
+    return try allocator.dupe(u8, commit_oid.string);
+}
+
+/// Quote `s` as a JSON string
+pub fn jsonString(allocator: Allocator, s: []const u8) ![]u8 {
+    var out: std.ArrayList(u8) = .empty;
+    errdefer out.deinit(allocator);
+
+    try out.append(allocator, '"');
+    for (s) |c| {
+        switch (c) {
+            '"' => try out.appendSlice(allocator, "\\\""),
+            '\\' => try out.appendSlice(allocator, "\\\\"),
+            '\n' => try out.appendSlice(allocator, "\\n"),
+            '\r' => try out.appendSlice(allocator, "\\r"),
+            '\t' => try out.appendSlice(allocator, "\\t"),
+            0...8, 11, 12, 14...0x1f => {
+                var escape: [6]u8 = undefined;
+                _ = try std.fmt.bufPrint(&escape, "\\u{x:0>4}", .{c});
+                try out.appendSlice(allocator, &escape);
+            },
+            else => try out.append(allocator, c),
+        }
+    }
+    try out.append(allocator, '"');
+
+    return out.toOwnedSlice(allocator);
+}
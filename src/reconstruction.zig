@@ -2,6 +2,7 @@ const std = @import("std");
 const builtin = @import("builtin");
 const Allocator = std.mem.Allocator;
 const xorb = @import("xorb.zig");
+const hashing = @import("hashing.zig");
 const shard = @import("shard.zig");
 
 // Network-dependent imports only for non-WASM targets
@@ -22,6 +23,13 @@ const parallel_fetcher = if (has_network) @import("parallel_fetcher.zig") else s
 ///
 /// Note: FileReconstructor is only available on platforms with network support (not WASM)
 
+/// Progress callback of a streaming reconstruction: receives the number of
+/// bytes written so far, after each term
+pub const Progress = struct {
+    context: *anyopaque,
+    report: *const fn (context: *anyopaque, bytes: u64) void,
+};
+
 // Helper type for xorb data (only needed when network support is available)
 const XorbData = if (has_network) struct {
     data: []u8,
@@ -33,6 +41,8 @@ const XorbData = if (has_network) struct {
 pub const FileReconstructor = if (has_network) struct {
     allocator: Allocator,
     cas: *cas_client.CasClient,
+    /// Reported to by the streaming functions, if set
+    progress: ?Progress = null,
 
     pub fn init(allocator: Allocator, cas: *cas_client.CasClient) FileReconstructor {
         return FileReconstructor{
@@ -164,6 +174,7 @@ pub const FileReconstructor = if (has_network) struct {
             mut_recon.deinit();
         }
 
+        var written: u64 = 0;
         for (recon.terms) |term| {
             const xorb_info = try self.fetchXorbForTerm(term, recon.fetch_info);
             defer self.allocator.free(xorb_info.data);
@@ -173,6 +184,87 @@ pub const FileReconstructor = if (has_network) struct {
             defer self.allocator.free(chunk_data);
 
             try writer.writeAll(chunk_data);
+            written += chunk_data.len;
+            self.reportProgress(written);
+        }
+    }
+
+    fn reportProgress(self: *FileReconstructor, written: u64) void {
+        if (self.progress) |progress| progress.report(progress.context, written);
+    }
+
+    /// Stream a range of bytes from a file to a writer
+    /// Writes the byte range [start, end) (end is exclusive); only the terms
+    /// overlapping the range are fetched from CAS
+    pub fn reconstructRangeStream(
+        self: *FileReconstructor,
+        file_hash: [32]u8,
+        start: u64,
+        end: u64,
+        writer: *std.Io.Writer,
+    ) !void {
+        if (start >= end) return error.InvalidRange;
+
+        const recon = try self.cas.getReconstruction(
+            file_hash,
+            .{ .start = start, .end = end - 1 },
+        );
+        defer {
+            var mut_recon = recon;
+            mut_recon.deinit();
+        }
+
+        var pending_skip = recon.offset_into_first_range;
+        var remaining = end - start;
+        var written: u64 = 0;
+
+        for (recon.terms) |term| {
+            const xorb_info = try self.fetchXorbForTerm(term, recon.fetch_info);
+            defer self.allocator.free(xorb_info.data);
+
+            var xorb_reader = xorb.XorbReader.init(self.allocator, xorb_info.data);
+            const chunk_data = try xorb_reader.extractChunkRange(xorb_info.local_start, xorb_info.local_end);
+            defer self.allocator.free(chunk_data);
+
+            const slice = termSliceInRange(chunk_data, &pending_skip, &remaining);
+            try writer.writeAll(slice);
+            written += slice.len;
+            self.reportProgress(written);
+            if (remaining == 0) break;
+        }
+
+        if (remaining != 0) return error.SizeMismatch;
+    }
+
+    /// Write the chunk manifest of a file: one line per chunk, in file order,
+    /// formatted by `line_format` from the chunk hash (hex) and length.
+    /// Fetches every term, like a full reconstruction.
+    pub fn writeChunkManifest(
+        self: *FileReconstructor,
+        file_hash: [32]u8,
+        writer: *std.Io.Writer,
+        comptime line_format: []const u8,
+    ) !void {
+        const recon = try self.cas.getReconstruction(file_hash, null);
+        defer {
+            var mut_recon = recon;
+            mut_recon.deinit();
+        }
+
+        for (recon.terms) |term| {
+            const xorb_info = try self.fetchXorbForTerm(term, recon.fetch_info);
+            defer self.allocator.free(xorb_info.data);
+
+            var xorb_reader = xorb.XorbReader.init(self.allocator, xorb_info.data);
+            var index: u32 = 0;
+            while (index < xorb_info.local_end) : (index += 1) {
+                const chunk = try xorb_reader.nextChunk() orelse return error.RangeOutOfBounds;
+                defer self.allocator.free(chunk);
+                if (index < xorb_info.local_start) continue;
+
+                const hex = hashing.hashToHex(hashing.computeDataHash(chunk));
+                try writer.print(line_format, .{ &hex, chunk.len });
+            }
         }
     }
 
@@ -202,6 +294,21 @@ pub const FileReconstructor = if (has_network) struct {
 } else struct {};
 
 // Helper function for tests - exposed regardless of network support
+/// The part of a term's data that falls in a streamed range, after skipping
+/// what is left of the first term's offset and stopping at the range's end
+fn termSliceInRange(term_data: []const u8, pending_skip: *u64, remaining: *u64) []const u8 {
+    var slice = term_data;
+    if (pending_skip.* != 0) {
+        const skip: usize = @intCast(@min(pending_skip.*, slice.len));
+        slice = slice[skip..];
+        pending_skip.* -= skip;
+    }
+
+    const len: usize = @intCast(@min(slice.len, remaining.*));
+    remaining.* -= len;
+    return slice[0..len];
+}
+
 fn copyTermIntoRange(
     result: []u8,
     term_data: []const u8,
@@ -332,3 +439,54 @@ test "copyTermIntoRange applies skip and truncation" {
     try std.testing.expectEqual(@as(usize, 0), remaining);
     try std.testing.expectEqualSlices(u8, "defgh", result[0..result_offset]);
 }
+
+test "copyTermIntoRange carries a skip longer than a term" {
+    var buffer: [4]u8 = undefined;
+    var pending_skip: usize = 7;
+    var result_offset: usize = 0;
+    var remaining: usize = buffer.len;
+
+    // The first term is skipped entirely, the rest of the skip lands in the second
+    copyTermIntoRange(&buffer, "abcde", &pending_skip, &result_offset, &remaining);
+    try std.testing.expectEqual(@as(usize, 2), pending_skip);
+    try std.testing.expectEqual(@as(usize, 0), result_offset);
+    try std.testing.expectEqual(@as(usize, 4), remaining);
+
+    copyTermIntoRange(&buffer, "fghij", &pending_skip, &result_offset, &remaining);
+    try std.testing.expectEqual(@as(usize, 0), pending_skip);
+    try std.testing.expectEqualSlices(u8, "hij", buffer[0..result_offset]);
+
+    copyTermIntoRange(&buffer, "klmno", &pending_skip, &result_offset, &remaining);
+    try std.testing.expectEqual(@as(usize, 0), remaining);
+    try std.testing.expectEqualSlices(u8, "hijk", &buffer);
+
+    // Once the range is full, later terms are ignored
+    copyTermIntoRange(&buffer, "pqrst", &pending_skip, &result_offset, &remaining);
+    try std.testing.expectEqualSlices(u8, "hijk", &buffer);
+}
+
+test "termSliceInRange streams the range across terms" {
+    const terms = [_][]const u8{ "0123", "4567", "89ab", "cdef" };
+    var out: [16]u8 = undefined;
+    var writer = std.Io.Writer.fixed(&out);
+
+    // Bytes [6, 11) of "0123456789abcdef", with the reconstruction starting at term 0
+    var pending_skip: u64 = 6;
+    var remaining: u64 = 5;
+    for (terms) |term| {
+        try writer.writeAll(termSliceInRange(term, &pending_skip, &remaining));
+        if (remaining == 0) break;
+    }
+    try std.testing.expectEqual(@as(u64, 0), remaining);
+    try std.testing.expectEqualSlices(u8, "6789a", writer.buffered());
+}
+
+test "termSliceInRange stops short when the terms run out" {
+    var pending_skip: u64 = 2;
+    var remaining: u64 = 10;
+
+    try std.testing.expectEqualSlices(u8, "", termSliceInRange("ab", &pending_skip, &remaining));
+    try std.testing.expectEqualSlices(u8, "cd", termSliceInRange("cd", &pending_skip, &remaining));
+    // The caller reports the shortfall as a size mismatch
+    try std.testing.expectEqual(@as(u64, 8), remaining);
+}
@@ -13,6 +13,7 @@
 //! - cas_client: HTTP CAS API client (not available on WASM)
 //! - reconstruction: File reconstruction from terms (limited on WASM - no parallel operations)
 //! - model_download: High-level API for downloading models from Hugging Face (not available on WASM)
+//! - model_upload: High-level API for uploading files to Hugging Face (not available on WASM)
 
 const std = @import("std");
 const builtin = @import("builtin");
@@ -34,6 +35,7 @@ pub const has_network_support = builtin.target.os.tag != .wasi;
 
 pub const cas_client = if (has_network_support) @import("cas_client.zig") else struct {};
 pub const model_download = if (has_network_support) @import("model_download.zig") else struct {};
+pub const model_upload = if (has_network_support) @import("model_upload.zig") else struct {};
 pub const parallel_fetcher = if (has_network_support) @import("parallel_fetcher.zig") else struct {};
 
 test {
@@ -69,6 +69,11 @@ pub const ShardFooter = extern struct {
     }
 };
 
+/// File flag: each data sequence entry is followed by a verification entry
+pub const FileFlagWithVerification: u32 = 1 << 31;
+/// File flag: the file info ends with a metadata extension
+pub const FileFlagWithMetadataExt: u32 = 1 << 30;
+
 pub const FileDataSequenceHeader = extern struct {
     /// File hash (32 bytes)
     file_hash: [32]u8,
@@ -214,6 +219,39 @@ pub const ShardBuilder = struct {
         }
     }
 
+    /// Add file info with a verification entry per data sequence entry and
+    /// the file's SHA-256, as required for files committed to the Hub
+    pub fn addVerifiedFileInfo(
+        self: *ShardBuilder,
+        file_hash: hashing.Hash,
+        entries: []const FileDataSequenceEntry,
+        verification: []const FileVerificationEntry,
+        sha256_hash: [32]u8,
+    ) !void {
+        if (verification.len != entries.len) return error.VerificationEntryMismatch;
+
+        const header = FileDataSequenceHeader{
+            .file_hash = file_hash,
+            .file_flags = FileFlagWithVerification | FileFlagWithMetadataExt,
+            .entry_count = @intCast(entries.len),
+            .reserved = @splat(0),
+        };
+        try self.file_info.appendSlice(self.allocator, std.mem.asBytes(&header));
+
+        for (entries) |entry| {
+            try self.file_info.appendSlice(self.allocator, std.mem.asBytes(&entry));
+        }
+        for (verification) |entry| {
+            try self.file_info.appendSlice(self.allocator, std.mem.asBytes(&entry));
+        }
+
+        const metadata_ext = FileMetadataExt{
+            .sha256_hash = sha256_hash,
+            .reserved = @splat(0),
+        };
+        try self.file_info.appendSlice(self.allocator, std.mem.asBytes(&metadata_ext));
+    }
+
     pub fn addCASInfo(
         self: *ShardBuilder,
         xorb_hash: hashing.Hash,